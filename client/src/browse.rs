@@ -0,0 +1,482 @@
+//! Terminal Browser
+//!
+//! `browse` lets people who only have a terminal look through their albums and manage them. The
+//! list on the left goes from the user's albums, to the sections of an album as its top fragment
+//! lists them, to the files of a section, and the pane on the right shows what is known about
+//! whatever is selected. Details of a file are fetched the first time that it is selected.
+//!
+//! Files of a section can be marked, and are then downloaded, removed from the album, or added to
+//! another album together. Without any marks, those act on the selected file alone. Errors are
+//! shown on the status line instead of leaving the browser, so that one failed request doesn't
+//! lose the user's place.
+
+use crate::error::Result;
+use crate::{section_label, time_ago, Client};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use tokio::task::block_in_place;
+use wire::{AlbumInfo, FileInfo, ListedAlbum, Role, SectionEntry, TopEntry};
+
+const HIGHLIGHT: Style = Style::new().add_modifier(Modifier::REVERSED);
+const LABEL: Style = Style::new().add_modifier(Modifier::DIM);
+
+/// What the list on the left shows.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum View {
+    Albums,
+    Sections,
+    Files,
+    /// Albums that the marked files can be added to.
+    Targets,
+}
+
+/// The album that is open, along with the sections that its top fragment lists.
+struct OpenAlbum {
+    id: String,
+    info: AlbumInfo<'static>,
+    sections: Vec<TopEntry>,
+}
+
+struct Browser {
+    view: View,
+    albums: Vec<ListedAlbum<'static>>,
+    album: Option<OpenAlbum>,
+    files: Vec<SectionEntry>,
+    /// Files that the next operation acts on, by id.
+    marked: HashSet<String>,
+    details: HashMap<String, FileInfo<'static>>,
+    /// Albums that the marked files can be added to, by their index in `albums`.
+    targets: Vec<usize>,
+    /// Where each view was left, so that going back returns there.
+    lists: HashMap<View, ListState>,
+    status: String,
+}
+
+impl Browser {
+    fn list(&mut self, view: View) -> &mut ListState {
+        self.lists.entry(view).or_default()
+    }
+
+    fn selected(&mut self, view: View) -> Option<usize> {
+        self.list(view).selected()
+    }
+
+    fn len(&self, view: View) -> usize {
+        match view {
+            View::Albums => self.albums.len(),
+            View::Sections => self.album.as_ref().map_or(0, |album| album.sections.len()),
+            View::Files => self.files.len(),
+            View::Targets => self.targets.len(),
+        }
+    }
+
+    /// Move the selection of `view` by `by`, staying inside the list.
+    fn step(&mut self, view: View, by: isize) {
+        let len = self.len(view);
+        if len == 0 {
+            return;
+        }
+
+        let list = self.list(view);
+        let next = match list.selected() {
+            Some(selected) => (selected as isize + by).clamp(0, len as isize - 1) as usize,
+            None => 0,
+        };
+        list.select(Some(next));
+    }
+
+    /// Ids that an operation acts on, which are the marked files or else the selected one.
+    fn chosen(&mut self) -> Vec<String> {
+        if !self.marked.is_empty() {
+            return self
+                .files
+                .iter()
+                .filter(|file| self.marked.contains(&file.file_id))
+                .map(|file| file.file_id.clone())
+                .collect();
+        }
+
+        match self.selected(View::Files) {
+            Some(selected) => vec![self.files[selected].file_id.clone()],
+            None => vec![],
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let rows = Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).split(frame.area());
+        let panes = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).split(rows[0]);
+
+        let (title, items) = self.items();
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(HIGHLIGHT);
+        let view = self.view;
+        frame.render_stateful_widget(list, panes[0], self.list(view));
+
+        let details = Paragraph::new(self.details())
+            .block(Block::bordered().title("Details"))
+            .wrap(Wrap { trim: true });
+        frame.render_widget(details, panes[1]);
+
+        let status = if self.status.is_empty() { self.help().to_string() } else { self.status.clone() };
+        frame.render_widget(Paragraph::new(status).style(LABEL), rows[1]);
+    }
+
+    fn help(&self) -> &'static str {
+        match self.view {
+            View::Albums => "enter open, q quit",
+            View::Sections => "enter open, esc back, q quit",
+            View::Files => "space mark, d download, r remove, a add to, n/p other sections, esc back, q quit",
+            View::Targets => "enter add, esc back",
+        }
+    }
+
+    /// The title and items of the list of the current view.
+    fn items(&self) -> (String, Vec<ListItem<'static>>) {
+        match self.view {
+            View::Albums => {
+                let items = self.albums.iter().map(album_item).collect();
+                (String::from("Albums"), items)
+            }
+            View::Sections => {
+                let album = self.album.as_ref().unwrap();
+                let items = album
+                    .sections
+                    .iter()
+                    .map(|entry| {
+                        let label = section_label(&album.info.album, entry.section);
+                        let label = if entry.part > 0 { format!("{} (part {})", label, entry.part + 1) } else { label };
+                        ListItem::new(Line::from(vec![
+                            Span::raw(label),
+                            Span::styled(format!("  {} files", entry.length), LABEL),
+                        ]))
+                    })
+                    .collect();
+                (album.info.album.description.name.to_string(), items)
+            }
+            View::Files => {
+                let items = self
+                    .files
+                    .iter()
+                    .map(|file| {
+                        let mark = if self.marked.contains(&file.file_id) { "[x] " } else { "[ ] " };
+                        let name = match self.details.get(&file.file_id) {
+                            Some(info) => info.metadata.name.to_string(),
+                            None => file.file_id.clone(),
+                        };
+                        ListItem::new(Line::from(vec![Span::raw(mark), Span::raw(name)]))
+                    })
+                    .collect();
+                let title = match self.marked.len() {
+                    0 => String::from("Files"),
+                    marked => format!("Files ({} marked)", marked),
+                };
+                (title, items)
+            }
+            View::Targets => {
+                let items = self.targets.iter().map(|&index| album_item(&self.albums[index])).collect();
+                (String::from("Add to album"), items)
+            }
+        }
+    }
+
+    /// What is known about the selection of the current view.
+    fn details(&mut self) -> Vec<Line<'static>> {
+        let view = self.view;
+        let selected = match self.selected(view) {
+            Some(selected) => selected,
+            None => return vec![],
+        };
+
+        match view {
+            View::Albums => album_details(&self.albums[selected].info),
+            View::Targets => album_details(&self.albums[self.targets[selected]].info),
+            View::Sections => {
+                let album = self.album.as_ref().unwrap();
+                let entry = &album.sections[selected];
+                let mut lines = album_details(&album.info);
+                lines.push(Line::from(""));
+                lines.push(field("Section", section_label(&album.info.album, entry.section)));
+                lines.push(field("Files", entry.length.to_string()));
+                lines
+            }
+            View::Files => {
+                let entry = &self.files[selected];
+                let mut lines = vec![];
+                if let Some(info) = self.details.get(&entry.file_id) {
+                    lines.push(Line::styled(info.metadata.name.to_string(), Style::new().add_modifier(Modifier::BOLD)));
+                    lines.push(field("Type", info.detected_mime.to_string()));
+                    lines.push(field("Uploaded", time_ago(info.uploaded)));
+                    if let Some(size) = info.size {
+                        lines.push(field("Size", format!("{:.1} MB", size as f64 / 1e6)));
+                    }
+                    if let Some(camera) = &info.camera {
+                        lines.push(field("Camera", camera.to_string()));
+                    }
+                    if let Some(location) = info.location {
+                        lines.push(field("Location", format!("{:.5}, {:.5}", location.latitude, location.longitude)));
+                    }
+                    if let Some(video) = &info.video {
+                        lines.push(field("Codec", video.codec.to_string()));
+                    }
+                    if !info.labels.tags.is_empty() {
+                        lines.push(field("Tags", info.labels.tags.join(", ")));
+                    }
+                    if info.encrypted {
+                        lines.push(Line::styled("Encrypted", Style::new().fg(Color::Yellow)));
+                    }
+                }
+                lines.push(field("Id", entry.file_id.clone()));
+                lines.push(field("Dimensions", format!("{}x{}", entry.width, entry.height)));
+                if let Some(caption) = &entry.caption {
+                    lines.push(Line::from(""));
+                    lines.push(Line::from(caption.clone()));
+                }
+                lines
+            }
+        }
+    }
+}
+
+fn field(label: &str, value: String) -> Line<'static> {
+    Line::from(vec![Span::styled(format!("{}: ", label), LABEL), Span::raw(value)])
+}
+
+fn role_style(role: Role) -> Style {
+    match role {
+        Role::Owner => Style::new().fg(Color::Yellow),
+        Role::Editor => Style::new().fg(Color::Green),
+        Role::Contributor => Style::new().fg(Color::Cyan),
+        Role::Reader => LABEL,
+    }
+}
+
+fn album_item(listed: &ListedAlbum) -> ListItem<'static> {
+    let album = &listed.info.album;
+    ListItem::new(Line::from(vec![
+        Span::raw(album.description.name.to_string()),
+        Span::styled(format!("  {} files", album.length), LABEL),
+    ]))
+}
+
+fn album_details(info: &AlbumInfo) -> Vec<Line<'static>> {
+    let album = &info.album;
+    let mut lines = vec![
+        Line::styled(album.description.name.to_string(), Style::new().add_modifier(Modifier::BOLD)),
+        Line::from(vec![
+            Span::styled("Role: ", LABEL),
+            Span::styled(format!("{:?}", info.role), role_style(info.role)),
+        ]),
+        field("Files", album.length.to_string()),
+        field("Updated", time_ago(album.last_update)),
+    ];
+    if info.rebuilding {
+        lines.push(Line::styled("Rearranging for new settings", LABEL));
+    }
+    if !album.description.description.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from(album.description.description.to_string()));
+    }
+
+    lines
+}
+
+impl Client {
+    /// Run the browser until the user quits.
+    pub async fn browse(&self) -> Result<()> {
+        // Prompts can't be answered once the terminal belongs to the browser.
+        self.get_prompt_key().await;
+        self.cipher()?;
+
+        let mut browser = Browser {
+            view: View::Albums,
+            albums: self.browse_albums(None, "last_update").await?.albums,
+            album: None,
+            files: vec![],
+            marked: HashSet::new(),
+            details: HashMap::new(),
+            targets: vec![],
+            lists: HashMap::new(),
+            status: String::new(),
+        };
+        browser.step(View::Albums, 0);
+
+        let mut terminal = ratatui::init();
+        let result = self.browse_loop(&mut terminal, &mut browser).await;
+        ratatui::restore();
+
+        result
+    }
+
+    async fn browse_loop(&self, terminal: &mut DefaultTerminal, browser: &mut Browser) -> Result<()> {
+        loop {
+            terminal.draw(|frame| browser.draw(frame))?;
+
+            let key = match block_in_place(event::read)? {
+                Event::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }) => code,
+                _ => continue,
+            };
+            if key == KeyCode::Char('q') {
+                return Ok(());
+            }
+
+            browser.status.clear();
+            if let Err(error) = self.browse_key(terminal, browser, key).await {
+                browser.status = error.to_string();
+            }
+        }
+    }
+
+    async fn browse_key(&self, terminal: &mut DefaultTerminal, browser: &mut Browser, key: KeyCode) -> Result<()> {
+        match (browser.view, key) {
+            (view, KeyCode::Up | KeyCode::Char('k')) => browser.step(view, -1),
+            (view, KeyCode::Down | KeyCode::Char('j')) => browser.step(view, 1),
+            (view, KeyCode::PageUp) => browser.step(view, -10),
+            (view, KeyCode::PageDown) => browser.step(view, 10),
+
+            (View::Albums, KeyCode::Enter | KeyCode::Right | KeyCode::Char('l')) => {
+                if let Some(selected) = browser.selected(View::Albums) {
+                    let id = browser.albums[selected].id.to_string();
+                    browser.list(View::Sections).select(None);
+                    self.open_album(browser, id).await?;
+
+                    browser.step(View::Sections, 0);
+                    browser.view = View::Sections;
+                }
+            }
+            (View::Sections, KeyCode::Enter | KeyCode::Right | KeyCode::Char('l')) => {
+                if browser.selected(View::Sections).is_some() {
+                    self.open_section(browser).await?;
+                    browser.view = View::Files;
+                }
+            }
+            (View::Sections, KeyCode::Esc | KeyCode::Left | KeyCode::Char('h') | KeyCode::Backspace) => {
+                browser.view = View::Albums;
+            }
+
+            (View::Files, KeyCode::Esc | KeyCode::Left | KeyCode::Char('h') | KeyCode::Backspace) => {
+                browser.marked.clear();
+                browser.view = View::Sections;
+            }
+            (View::Files, KeyCode::Char(' ')) => {
+                if let Some(selected) = browser.selected(View::Files) {
+                    let file_id = &browser.files[selected].file_id;
+                    if !browser.marked.remove(file_id) {
+                        browser.marked.insert(file_id.clone());
+                    }
+                    browser.step(View::Files, 1);
+                }
+            }
+            (View::Files, KeyCode::Char('n')) => self.page(browser, 1).await?,
+            (View::Files, KeyCode::Char('p')) => self.page(browser, -1).await?,
+            (View::Files, KeyCode::Char('d')) => {
+                let file_ids = browser.chosen();
+                let mut saved = 0;
+
+                for file_id in &file_ids {
+                    browser.status = format!("Downloading {} of {}...", saved + 1, file_ids.len());
+                    terminal.draw(|frame| browser.draw(frame))?;
+
+                    self.download(file_id, None).await?;
+                    saved += 1;
+                }
+                browser.status = format!("Downloaded {} files", saved);
+                browser.marked.clear();
+            }
+            (View::Files, KeyCode::Char('r')) => {
+                let file_ids = browser.chosen();
+                let album_id = browser.album.as_ref().unwrap().id.clone();
+
+                self.remove_from_album(&album_id, &file_ids).await?;
+                browser.marked.clear();
+
+                // Removing files changes the sections, so the album is read again.
+                self.open_album(browser, album_id).await?;
+                self.open_section(browser).await?;
+                browser.status = format!("Removed {} files", file_ids.len());
+            }
+            (View::Files, KeyCode::Char('a')) => {
+                let open_id = browser.album.as_ref().unwrap().id.clone();
+                browser.targets = (0..browser.albums.len())
+                    .filter(|&index| {
+                        let listed = &browser.albums[index];
+                        listed.id != open_id && listed.info.role.can_contribute()
+                    })
+                    .collect();
+
+                browser.list(View::Targets).select(None);
+                browser.step(View::Targets, 0);
+                browser.view = View::Targets;
+            }
+
+            (View::Targets, KeyCode::Enter) => {
+                if let Some(selected) = browser.selected(View::Targets) {
+                    let target = &browser.albums[browser.targets[selected]];
+                    let (target_id, name) = (target.id.to_string(), target.info.album.description.name.to_string());
+                    let file_ids = browser.chosen();
+
+                    self.add_to_album(&target_id, &file_ids).await?;
+                    browser.marked.clear();
+                    browser.view = View::Files;
+                    browser.status = format!("Added {} files to {}", file_ids.len(), name);
+                }
+            }
+            (View::Targets, KeyCode::Esc | KeyCode::Left | KeyCode::Backspace) => browser.view = View::Files,
+
+            _ => return Ok(()),
+        }
+
+        // Details are fetched once a file is selected, by moving or by opening a section.
+        if browser.view == View::Files {
+            if let Some(selected) = browser.selected(View::Files) {
+                let file_id = browser.files[selected].file_id.clone();
+                if let Entry::Vacant(entry) = browser.details.entry(file_id) {
+                    let info = self.file_info(entry.key()).await?;
+                    entry.insert(info);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the metadata and sections of an album, which keeps the selected section where it is.
+    async fn open_album(&self, browser: &mut Browser, album_id: String) -> Result<()> {
+        let info = self.album_metadata(&album_id).await?;
+        let sections = self.album_top(&album_id, info.album.fragment_head).await?;
+
+        browser.album = Some(OpenAlbum { id: album_id, info, sections });
+        if let Some(selected) = browser.selected(View::Sections) {
+            let last = browser.len(View::Sections).checked_sub(1);
+            browser.list(View::Sections).select(last.map(|last| selected.min(last)));
+        }
+
+        Ok(())
+    }
+
+    /// Read the files of the selected section.
+    async fn open_section(&self, browser: &mut Browser) -> Result<()> {
+        let album = browser.album.as_ref().unwrap();
+        browser.files = match browser.lists.get(&View::Sections).and_then(ListState::selected) {
+            Some(selected) => self.album_section(&album.id, album.sections[selected].fragment_id).await?,
+            None => vec![],
+        };
+
+        browser.list(View::Files).select(None);
+        browser.step(View::Files, 0);
+        Ok(())
+    }
+
+    /// Go to the section `by` sections after the open one.
+    async fn page(&self, browser: &mut Browser, by: isize) -> Result<()> {
+        browser.marked.clear();
+        browser.step(View::Sections, by);
+        self.open_section(browser).await
+    }
+}
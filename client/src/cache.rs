@@ -0,0 +1,177 @@
+//! Thumbnail Cache
+//!
+//! Renditions that the client fetches are kept in its database, so that showing the same files
+//! again doesn't download them again. Entries are keyed by file id and rendition, and remember
+//! the revision of the file that they were fetched at along with the entity tag that the server
+//! sent. Renditions only change when their file is edited, which bumps its revision, so an entry
+//! at the current revision is used as it is. One at an older revision is sent back with
+//! `If-None-Match`, which costs a request but not the download if the rendition stayed the same.
+//!
+//! Each profile has its own cache of up to `cache_limit` bytes, 256 MiB by default. Once it grows
+//! past that, the entries that were used longest ago are dropped until it fits again.
+
+use crate::error::{Error, Result};
+use crate::Client;
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use wire::endpoint;
+
+pub const DEFAULT_LIMIT: u64 = 256 << 20;
+
+/// What is known about a cached rendition, whose bytes are kept in another tree.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    revision: u32,
+    etag: String,
+    mime: String,
+    size: u64,
+    /// Ids from the database only grow, so this orders entries by when they were last used.
+    used: u64,
+}
+
+/// A rendition as it was cached.
+pub struct Cached {
+    pub revision: u32,
+    pub etag: String,
+    pub mime: String,
+    pub bytes: Vec<u8>,
+}
+
+pub struct ThumbnailCache {
+    db: sled::Db,
+    entries: sled::Tree,
+    data: sled::Tree,
+    limit: u64,
+}
+
+impl ThumbnailCache {
+    pub fn open(db: &sled::Db, profile: &str, limit: u64) -> Result<Self> {
+        Ok(ThumbnailCache {
+            db: db.clone(),
+            entries: db.open_tree(format!("thumbnails/{}", profile))?,
+            data: db.open_tree(format!("thumbnail_data/{}", profile))?,
+            limit,
+        })
+    }
+
+    fn key(file_id: &str, quality: &str) -> String {
+        format!("{}/{}", file_id, quality)
+    }
+
+    /// The cached `quality` rendition of a file at whichever revision it was fetched, which counts
+    /// as a use of it.
+    pub fn get(&self, file_id: &str, quality: &str) -> Result<Option<Cached>> {
+        let key = Self::key(file_id, quality);
+
+        let mut entry: Entry = match self.entries.get(&key)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => return Ok(None),
+        };
+        // The entry may have been dropped while the bytes were still being written.
+        let bytes = match self.data.get(&key)? {
+            Some(bytes) => bytes.to_vec(),
+            None => return Ok(None),
+        };
+
+        entry.used = self.db.generate_id()?;
+        self.entries.insert(&key, serde_json::to_vec(&entry)?)?;
+
+        Ok(Some(Cached {
+            revision: entry.revision,
+            etag: entry.etag,
+            mime: entry.mime,
+            bytes,
+        }))
+    }
+
+    /// Keep the `quality` rendition of a file at `revision`, dropping others if the cache is full.
+    pub fn insert(&self, file_id: &str, quality: &str, cached: &Cached) -> Result<()> {
+        let key = Self::key(file_id, quality);
+        let entry = Entry {
+            revision: cached.revision,
+            etag: cached.etag.clone(),
+            mime: cached.mime.clone(),
+            size: cached.bytes.len() as u64,
+            used: self.db.generate_id()?,
+        };
+
+        self.data.insert(&key, cached.bytes.as_slice())?;
+        self.entries.insert(&key, serde_json::to_vec(&entry)?)?;
+
+        self.evict()
+    }
+
+    /// Drop the entries that were used longest ago until the cache fits in its limit.
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        for item in self.entries.iter() {
+            let (key, bytes) = item?;
+            let entry: Entry = serde_json::from_slice(&bytes)?;
+            entries.push((key, entry.size, entry.used));
+        }
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        if total <= self.limit {
+            return Ok(());
+        }
+
+        entries.sort_by_key(|(_, _, used)| *used);
+        for (key, size, _) in entries {
+            if total <= self.limit {
+                break;
+            }
+
+            self.entries.remove(&key)?;
+            self.data.remove(&key)?;
+            total -= size;
+        }
+
+        Ok(())
+    }
+}
+
+impl Client {
+    pub fn thumbnail_cache(&self) -> Result<ThumbnailCache> {
+        ThumbnailCache::open(&self.db, &self.profile, self.get_cache_limit())
+    }
+
+    /// The `quality` rendition of a file at `revision` along with its mime type, from the cache
+    /// when it is current there.
+    pub async fn rendition(&self, file_id: &str, quality: &str, revision: u32) -> Result<(String, Vec<u8>)> {
+        let cache = self.thumbnail_cache()?;
+        let cached = match cache.get(file_id, quality)? {
+            Some(cached) if cached.revision == revision => return Ok((cached.mime, cached.bytes)),
+            cached => cached,
+        };
+
+        let mut request = self.auth_request::<endpoint::ServeFile>(&[quality, file_id]).await;
+        if let Some(cached) = &cached {
+            request = request.header(header::IF_NONE_MATCH, &cached.etag);
+        }
+
+        let mut fresh = match self.send_retry(request).await {
+            Ok(response) => {
+                let header = |name: header::HeaderName| {
+                    response.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from)
+                };
+                let etag = header(header::ETAG);
+                let mime = header(header::CONTENT_TYPE).unwrap_or_else(|| String::from("application/octet-stream"));
+                let bytes = response.bytes().await?.to_vec();
+
+                match etag {
+                    Some(etag) => Cached { revision, etag, mime, bytes },
+                    // Without an entity tag there would be nothing to check the entry with later.
+                    None => return Ok((mime, bytes)),
+                }
+            }
+            Err(Error::Remote { status_code, .. }) if status_code == StatusCode::NOT_MODIFIED => {
+                cached.expect("Only cached renditions are checked")
+            }
+            Err(error) => return Err(error),
+        };
+
+        fresh.revision = revision;
+        cache.insert(file_id, quality, &fresh)?;
+        Ok((fresh.mime, fresh.bytes))
+    }
+}
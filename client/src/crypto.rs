@@ -0,0 +1,105 @@
+//! End-to-End Encryption
+//!
+//! A profile can hold a key that its uploads are encrypted with before they leave the machine, so
+//! that the server only ever stores ciphertext. `keygen` makes the key at random and keeps it in
+//! the profile wrapped by a key that is derived from a passphrase with Argon2id, which is asked for
+//! the first time that the key is needed. Losing either the profile or the passphrase means losing
+//! the files, since the server has no copy of the key.
+//!
+//! Each file is sealed as a whole with XChaCha20-Poly1305 under a random nonce, and stored as a
+//! version byte, the nonce and the ciphertext. The server can't render encrypted files, so they only
+//! have an original, which `download` decrypts.
+
+use crate::error::{Error, Result};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+const VERSION: u8 = 1;
+const NONCE_BYTES: usize = 24;
+const SALT_BYTES: usize = 16;
+/// Bytes that sealing adds to a file, for the version, the nonce and the tag.
+pub const OVERHEAD: u64 = 1 + NONCE_BYTES as u64 + 16;
+
+fn nonce(bytes: &[u8]) -> Option<XNonce> {
+    <[u8; NONCE_BYTES]>::try_from(bytes).ok().map(XNonce::from)
+}
+
+/// Derives the wrapping key. Stronger than the Argon2 defaults, since it stands between anyone who
+/// copies the profile and the files.
+fn wrapping_key(passphrase: &str, salt: &[u8]) -> XChaCha20Poly1305 {
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        mem_cost: 64 * 1024,
+        time_cost: 3,
+        ..argon2::Config::default()
+    };
+    let key = argon2::hash_raw(passphrase.as_bytes(), salt, &config).unwrap();
+
+    XChaCha20Poly1305::new_from_slice(&key).unwrap()
+}
+
+/// The key of a profile, sealed with a key derived from its passphrase.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WrappedKey {
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl WrappedKey {
+    /// Make a new key, wrapped with `passphrase`.
+    pub fn generate(passphrase: &str) -> Self {
+        let mut salt = vec![0; SALT_BYTES];
+        OsRng.fill_bytes(&mut salt);
+
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = wrapping_key(passphrase, &salt).encrypt(&nonce, &key[..]).unwrap();
+
+        WrappedKey {
+            salt,
+            nonce: nonce.to_vec(),
+            key: sealed,
+        }
+    }
+
+    /// Unwrap the key, which fails if `passphrase` isn't the one that it was wrapped with.
+    pub fn open(&self, passphrase: &str) -> Result<Cipher> {
+        let nonce = nonce(&self.nonce).ok_or(Error::Decrypt("the key of this profile"))?;
+        let key = wrapping_key(passphrase, &self.salt)
+            .decrypt(&nonce, self.key.as_slice())
+            .map_err(|_| Error::Decrypt("the key of this profile"))?;
+
+        Ok(Cipher(XChaCha20Poly1305::new_from_slice(&key).unwrap()))
+    }
+}
+
+pub struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let mut sealed = vec![VERSION];
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(self.0.encrypt(&nonce, plaintext).unwrap());
+        sealed
+    }
+
+    /// Open a file from `encrypt`, which fails if it was encrypted with another key or changed
+    /// since.
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        match sealed.split_first() {
+            Some((&VERSION, rest)) if rest.len() >= NONCE_BYTES => {
+                let (nonce_bytes, ciphertext) = rest.split_at(NONCE_BYTES);
+                self.0
+                    .decrypt(&nonce(nonce_bytes).unwrap(), ciphertext)
+                    .map_err(|_| Error::Decrypt("the file"))
+            }
+            _ => Err(Error::Decrypt("the file")),
+        }
+    }
+}
@@ -2,25 +2,197 @@ use tokio::io;
 use std::fmt;
 use async_trait::async_trait;
 use reqwest::{Url, Response};
+use std::path::PathBuf;
+use wire::{ErrorCode, ErrorResponse, IntoOwned};
 
 #[derive(Debug)]
 pub enum Error {
     Remote {
         status_code: reqwest::StatusCode,
         url: Url,
+        /// Boxed to keep every `Result` of the client small.
+        error: Box<ErrorResponse<'static>>,
+    },
+    /// A bulk change stopped after `done` of `total` files were committed.
+    Incomplete {
+        done: usize,
+        total: usize,
         details: String,
     },
+    /// Some files of an upload couldn't be stored, although the rest were.
+    PartialUpload {
+        uploaded: Vec<String>,
+        failed: Vec<PathBuf>,
+    },
+    /// The server stored a file with a different hash than the contents that were sent.
+    Mismatch {
+        name: String,
+        file_id: String,
+    },
+    /// `verify` found files that are missing from the server or differ from what it has.
+    Unverified {
+        missing: Vec<String>,
+        mismatched: Vec<String>,
+    },
+    /// What is named couldn't be decrypted, because the passphrase or key is wrong or the data was
+    /// changed.
+    Decrypt(&'static str),
+    /// An encrypted file was asked for, but the profile has no key.
+    NoKey,
     Reqwest(reqwest::Error),
     IO(io::Error),
     Json(serde_json::Error),
     Sled(sled::Error),
+    Zip(zip::result::ZipError),
+    Watch(notify::Error),
 }
 
 impl std::error::Error for Error {}
 
+/// Broad kinds of failure, each with its own exit code so that scripts can tell them apart.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Other,
+    Auth,
+    NotFound,
+    Network,
+    Server,
+    PartialUpload,
+    /// The server no longer supports the API version of this client.
+    Outdated,
+    /// What the server has isn't what was uploaded.
+    Mismatch,
+}
+
+impl Kind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Kind::Other => 1,
+            Kind::Auth => 2,
+            Kind::NotFound => 3,
+            Kind::Network => 4,
+            Kind::Server => 5,
+            Kind::PartialUpload => 6,
+            Kind::Outdated => 7,
+            Kind::Mismatch => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Other => "other",
+            Kind::Auth => "auth",
+            Kind::NotFound => "not_found",
+            Kind::Network => "network",
+            Kind::Server => "server",
+            Kind::PartialUpload => "partial_upload",
+            Kind::Outdated => "outdated",
+            Kind::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Error::Remote { status_code, error, .. } => match error.code {
+                ErrorCode::Unauthorized | ErrorCode::AccountLocked | ErrorCode::ElevationRequired => Kind::Auth,
+                ErrorCode::NotFound => Kind::NotFound,
+                ErrorCode::Timeout => Kind::Network,
+                ErrorCode::TooManyRequests | ErrorCode::Busy | ErrorCode::Internal => Kind::Server,
+                ErrorCode::UnsupportedVersion => Kind::Outdated,
+                // Proxies in front of the server answer with their own bodies.
+                ErrorCode::Unknown if status_code.is_server_error() => Kind::Server,
+                _ => Kind::Other,
+            },
+            Error::Incomplete { .. } => Kind::Server,
+            Error::PartialUpload { .. } => Kind::PartialUpload,
+            Error::Mismatch { .. } | Error::Unverified { .. } => Kind::Mismatch,
+            Error::Reqwest(error) if error.is_timeout() || error.is_connect() || error.is_request() => {
+                Kind::Network
+            }
+            _ => Kind::Other,
+        }
+    }
+
+    /// Whether the same request could succeed if it were sent again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Reqwest(error) => error.is_timeout() || error.is_connect() || error.is_request(),
+            Error::Remote { status_code, .. } => {
+                status_code.is_server_error()
+                    || *status_code == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || *status_code == reqwest::StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::Remote { status_code, url, error } => match error.code {
+                ErrorCode::Unauthorized => write!(
+                    f,
+                    "Not authorized for {}. If your session expired, run `{} login`",
+                    url.path(),
+                    env!("CARGO_PKG_NAME")
+                ),
+                ErrorCode::AccountLocked => write!(f, "The account is locked after too many failed logins, try again later"),
+                ErrorCode::NotFound => write!(f, "{} wasn't found", url.path()),
+                ErrorCode::TooManyRequests => write!(f, "The server is rate limiting requests, try again later"),
+                ErrorCode::Busy => write!(f, "The server is busy processing other images, try again later"),
+                ErrorCode::UnsupportedVersion => match error.unsupported_version() {
+                    Some(version) => write!(
+                        f,
+                        "The server needs API version {} or newer, but this client speaks {}. Update {}",
+                        version.minimum,
+                        version.client,
+                        env!("CARGO_PKG_NAME")
+                    ),
+                    None => write!(f, "The server doesn't support this client anymore, update it"),
+                },
+                _ if status_code.is_server_error() => {
+                    write!(f, "The server failed with {}: {}", status_code, error.message)
+                }
+                _ => write!(f, "The server rejected {} with {}: {}", url.path(), status_code, error.message),
+            },
+            Error::Incomplete { done, total, details } => write!(
+                f,
+                "Only {} of {} files were changed before the server failed: {}",
+                done, total, details
+            ),
+            Error::PartialUpload { uploaded, failed } => write!(
+                f,
+                "Uploaded {} files, but {} couldn't be uploaded",
+                uploaded.len(),
+                failed.len()
+            ),
+            Error::Mismatch { name, file_id } => write!(
+                f,
+                "The server stored {} as {} with a different hash than what was sent",
+                name, file_id
+            ),
+            Error::Unverified { missing, mismatched } => write!(
+                f,
+                "{} files are missing from the server and {} don't match it",
+                missing.len(),
+                mismatched.len()
+            ),
+            Error::Decrypt(what) => write!(f, "Couldn't decrypt {}, the passphrase or key is wrong", what),
+            Error::NoKey => write!(f, "The file is encrypted, but this profile has no key to decrypt it with"),
+            Error::Reqwest(error) if error.is_timeout() => write!(f, "The server took too long to respond"),
+            Error::Reqwest(error) if error.is_connect() => {
+                write!(f, "Couldn't connect to the server, check the url with `{} config list`", env!("CARGO_PKG_NAME"))
+            }
+            Error::Reqwest(error) => write!(f, "Request failed: {}", error),
+            Error::IO(error) => write!(f, "{}", error),
+            Error::Json(error) => write!(f, "Unexpected response: {}", error),
+            Error::Sled(error) => write!(f, "Couldn't use the local database: {}", error),
+            Error::Zip(error) => write!(f, "Couldn't read the archive: {}", error),
+            Error::Watch(error) => write!(f, "Couldn't watch for changes: {}", error),
+        }
     }
 }
 
@@ -48,6 +220,18 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<zip::result::ZipError> for Error {
+    fn from(error: zip::result::ZipError) -> Self {
+        Error::Zip(error)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(error: notify::Error) -> Self {
+        Error::Watch(error)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[async_trait]
@@ -59,10 +243,23 @@ pub trait ResponseErrorExt: Sized {
 impl ResponseErrorExt for Response {
     async fn check_status(self) -> Result<Self> {
         if !self.status().is_success() {
+            let status_code = self.status();
+            let url = self.url().clone();
+            let bytes = self.bytes().await?;
+
+            let error = match serde_json::from_slice::<ErrorResponse>(&bytes) {
+                Ok(error) => error.into_owned(),
+                Err(_) => ErrorResponse {
+                    code: ErrorCode::Unknown,
+                    message: String::from_utf8_lossy(&bytes).into_owned().into(),
+                    details: None,
+                },
+            };
+
             Err(Error::Remote {
-                status_code: self.status(),
-                url: self.url().clone(),
-                details: self.text().await?,
+                status_code,
+                url,
+                error: Box::new(error),
             })
         } else {
             Ok(self)
@@ -1,20 +1,46 @@
+mod browse;
+mod cache;
+mod crypto;
 mod error;
-
-use crate::error::{Result, ResponseErrorExt};
-use reqwest::{Url, Body};
+mod output;
+mod progress;
+mod queue;
+mod retry;
+mod takeout;
+mod time_zone;
+mod verify;
+mod watch;
+mod xmp;
+
+use crate::crypto::{Cipher, WrappedKey};
+use crate::error::{Error, Kind, Result, ResponseErrorExt};
+use crate::output::Output;
+use crate::progress::{Tracked, Transfer};
+use crate::queue::{Operation, Queue};
+use crate::takeout::Sidecar;
+use crate::watch::WatchRule;
+use reqwest::multipart::{Form, Part};
+use reqwest::{header, Body, Method, RequestBuilder, Response, StatusCode, Url};
 use std::time::UNIX_EPOCH;
 use std::path::{Path, PathBuf};
 use tokio::fs;
-use tokio::io::{self, AsyncReadExt};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use bytes::{Bytes, BytesMut};
 use async_stream::try_stream;
-use futures::stream::Stream;
+use futures::stream::{self, Stream, StreamExt};
+use std::future::Future;
 use wire::*;
+use wire::endpoint::{self, Endpoint};
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
 use std::borrow::Cow;
-use clap::{Arg, App, SubCommand, crate_version, crate_name};
+use clap::{Arg, App, ArgMatches, SubCommand, crate_version, crate_name};
 use std::io::Write;
 use console::style;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+use chrono::TimeZone;
 
 fn file_stream(mut file: fs::File, chunk_size: usize) -> impl Stream<Item = io::Result<Bytes>> {
     try_stream! {
@@ -31,7 +57,71 @@ fn file_stream(mut file: fs::File, chunk_size: usize) -> impl Stream<Item = io::
     }
 }
 
-const UPLOAD_METADATA: &'static str = "upload-metadata";
+const UPLOAD_METADATA: &str = "upload-metadata";
+/// Files up to this size are uploaded in batches.
+const BATCH_FILE_BYTES: u64 = 1 << 20;
+/// Size at which a batch is sent without waiting for more files.
+const BATCH_BYTES: u64 = 16 << 20;
+/// Files that are listed per request.
+const LIST_PAGE: usize = 1000;
+
+/// What became of a file that was to be uploaded.
+enum Outcome {
+    Stored(String),
+    /// The server couldn't be reached, so the upload can be queued for later.
+    Unreachable,
+    Failed,
+}
+
+impl Outcome {
+    fn failed(error: &Error) -> Self {
+        if error.is_transient() {
+            Outcome::Unreachable
+        } else {
+            Outcome::Failed
+        }
+    }
+}
+
+/// Format a time stamp as a date in the album's time zone.
+fn album_date(album: &Album, time: i64) -> String {
+    album.description.time_zone.timestamp_opt(time, 0).unwrap().format("%Y-%m-%d").to_string()
+}
+
+fn styled_role(role: Role) -> console::StyledObject<String> {
+    let name = format!("{:?}", role);
+    match role {
+        Role::Owner => style(name).yellow(),
+        Role::Editor => style(name).green(),
+        Role::Contributor => style(name).cyan(),
+        Role::Reader => style(name).dim(),
+    }
+}
+
+/// Describe the files that a section of an album holds.
+fn section_label(album: &Album, section: i64) -> String {
+    match album.description.sort {
+        SortMode::CaptureDate | SortMode::UploadDate => album_date(album, section),
+        SortMode::Name => match std::char::from_u32(section as u32) {
+            Some(initial) => format!("\"{}\"", initial),
+            None => String::from("(no name)"),
+        },
+        SortMode::Manual => format!("from position {}", section),
+    }
+}
+
+/// Roughly how long ago a unix time was, like "2h ago".
+fn time_ago(time: i64) -> String {
+    let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+    let seconds = (now - time).max(0);
+
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
 /*
 impl Context {
 
@@ -67,9 +157,54 @@ impl Context {
 }
 */
 
+fn http_method(method: endpoint::Method) -> Method {
+    match method {
+        endpoint::Method::Get => Method::GET,
+        endpoint::Method::Post => Method::POST,
+        endpoint::Method::Put => Method::PUT,
+        endpoint::Method::Patch => Method::PATCH,
+        endpoint::Method::Delete => Method::DELETE,
+    }
+}
+
+/// Read the JSON body of a response from `E`.
+async fn decode<E: Endpoint>(response: Response) -> Result<E::Response<'static>>
+where
+    for<'a> E::Response<'a>: Deserialize<'a> + IntoOwned<Owned = E::Response<'static>>,
+{
+    let bytes = response.bytes().await?;
+    let json: E::Response<'_> = serde_json::from_slice(&bytes)?;
+    Ok(json.into_owned())
+}
+
+/// Follow the progress of a bulk album change until it finishes.
+async fn wait_bulk(mut response: reqwest::Response) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+
+            match serde_json::from_slice(&line)? {
+                BulkProgress::Committed { done, total, .. } => {
+                    eprintln!("Committed {} of {} files", done, total);
+                }
+                BulkProgress::Failed { done, total, error } => {
+                    return Err(Error::Incomplete { done, total, details: error });
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn prompt_line(prompt: &str) -> String {
-    print!("{}", prompt);
-    std::io::stdout().flush().unwrap();
+    // Prompts go to stderr so that they don't mix with `--json` output.
+    eprint!("{}", prompt);
+    std::io::stderr().flush().unwrap();
 
     let mut string = String::new();
     std::io::stdin().read_line(&mut string).unwrap();
@@ -77,28 +212,141 @@ fn prompt_line(prompt: &str) -> String {
     string.trim().to_string()
 }
 
+/// Ask before doing something that can't easily be undone, unless `yes` already answered.
+fn confirm(question: &str, yes: bool) -> bool {
+    yes || prompt_line(&format!("{} [y/N] ", question)).eq_ignore_ascii_case("y")
+}
+
+/// Open `url` with whatever the system opens links with.
+async fn open_browser(url: &Url) -> Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        tokio::process::Command::new("open")
+    } else if cfg!(windows) {
+        let mut command = tokio::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        tokio::process::Command::new("xdg-open")
+    };
+
+    let status = command.arg(url.as_str()).status().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("Couldn't open {} in a browser", url)).into());
+    }
+
+    Ok(())
+}
+
+/// What `flush` did with the queued operations.
+#[derive(Serialize)]
+struct Flushed {
+    done: usize,
+    failed: usize,
+    kept: usize,
+}
+
 pub struct Client {
     pub client: reqwest::Client,
     pub db: sled::Db,
+    /// Name of the profile whose url and key are in use.
+    pub profile: String,
+    settings: sled::Tree,
+    /// The key of the profile once it has been unwrapped.
+    cipher: OnceLock<Cipher>,
+    /// Progress and rate limit of uploads.
+    transfer: Transfer,
 }
 
+const DEFAULT_PROFILE: &str = "default";
+const PROFILE_PREFIX: &str = "profile/";
+/// Setting that holds the `WrappedKey` of a profile that encrypts its uploads.
+const WRAPPED_KEY: &[u8] = b"wrapped_key";
+/// Setting that holds where the frontend of the profile's server is, which links are made to.
+const FRONTEND_URL: &[u8] = b"frontend_url";
+/// Setting that holds how many bytes of renditions the profile keeps in its thumbnail cache.
+const CACHE_LIMIT: &[u8] = b"cache_limit";
+
 impl Client {
-    fn new(db_path: &str) -> Self {
-        Self {
-            client: reqwest::Client::new(),
-            db: sled::open(db_path).unwrap(),
-        }
+    fn new(db_path: &str, profile: Option<&str>) -> Self {
+        Self::open(sled::open(db_path).unwrap(), profile)
+    }
+
+    fn temp(profile: Option<&str>) -> Self {
+        Self::open(sled::Config::new().temporary(true).open().unwrap(), profile)
     }
 
-    fn temp() -> Self {
+    /// Open `profile`, or the profile picked by `config use` when there isn't one.
+    fn open(db: sled::Db, profile: Option<&str>) -> Self {
+        let profile = match profile {
+            Some(profile) => profile.to_owned(),
+            None => match db.get(b"profile").unwrap() {
+                Some(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+                None => DEFAULT_PROFILE.to_owned(),
+            },
+        };
+
+        let settings = db.open_tree(Self::profile_tree(&profile)).unwrap();
+
+        // Databases from before profiles kept a single url and key at the top level.
+        let legacy = db.open_tree(Self::profile_tree(DEFAULT_PROFILE)).unwrap();
+        for name in [&b"url"[..], &b"key"[..]] {
+            if let Some(value) = db.remove(name).unwrap() {
+                legacy.insert(name, value).unwrap();
+            }
+        }
+        if db.tree_names().iter().any(|name| name == b"queue") {
+            let queue = db.open_tree(b"queue").unwrap();
+            let moved = db.open_tree(format!("queue/{}", DEFAULT_PROFILE)).unwrap();
+            for entry in queue.iter() {
+                let (key, value) = entry.unwrap();
+                moved.insert(key, value).unwrap();
+            }
+            db.drop_tree(b"queue").unwrap();
+        }
+
         Self {
             client: reqwest::Client::new(),
-            db: sled::Config::new().temporary(true).open().unwrap(),
+            db,
+            profile,
+            settings,
+            cipher: OnceLock::new(),
+            transfer: Transfer::default(),
         }
     }
 
+    fn profile_tree(name: &str) -> String {
+        format!("{}{}", PROFILE_PREFIX, name)
+    }
+
+    /// Every profile in the database along with its url and whether it's logged in.
+    fn profiles(&self) -> Vec<(String, Option<Url>, bool)> {
+        self.db
+            .tree_names()
+            .iter()
+            .filter_map(|name| {
+                let name = std::str::from_utf8(name).ok()?.strip_prefix(PROFILE_PREFIX)?;
+                let tree = self.db.open_tree(Self::profile_tree(name)).unwrap();
+                let url = tree
+                    .get(b"url")
+                    .unwrap()
+                    .map(|bytes| Url::parse(std::str::from_utf8(&bytes).unwrap()).unwrap());
+                let logged_in = tree.contains_key(b"key").unwrap();
+                Some((name.to_owned(), url, logged_in))
+            })
+            .collect()
+    }
+
+    /// Make `profile` the one used when `--profile` isn't given.
+    fn use_profile(&self, profile: &str) {
+        self.db.insert(b"profile", profile.as_bytes()).unwrap();
+    }
+
+    fn queue(&self) -> Result<Queue> {
+        Queue::open(&self.db, &self.profile)
+    }
+
     fn get_key(&self) -> Option<String> {
-        if let Some(bytes) = self.db.get(b"key").unwrap() {
+        if let Some(bytes) = self.settings.get(b"key").unwrap() {
             let string = std::str::from_utf8(&bytes).unwrap();
             Some(string.to_owned())
         } else {
@@ -107,11 +355,38 @@ impl Client {
     }
 
     fn set_key(&self, key: &str) {
-        self.db.insert(b"key", key.as_bytes()).unwrap();
+        self.settings.insert(b"key", key.as_bytes()).unwrap();
+    }
+
+    fn has_key(&self) -> bool {
+        self.settings.contains_key(WRAPPED_KEY).unwrap()
+    }
+
+    /// Give the profile a new key, wrapped with `passphrase`, which its uploads are encrypted with
+    /// from then on.
+    fn keygen(&self, passphrase: &str) {
+        let wrapped = WrappedKey::generate(passphrase);
+        self.settings.insert(WRAPPED_KEY, serde_json::to_vec(&wrapped).unwrap()).unwrap();
+    }
+
+    /// The key of the profile, if it has one, asking for its passphrase the first time.
+    fn cipher(&self) -> Result<Option<&Cipher>> {
+        if let Some(cipher) = self.cipher.get() {
+            return Ok(Some(cipher));
+        }
+
+        let wrapped: WrappedKey = match self.settings.get(WRAPPED_KEY)? {
+            Some(bytes) => serde_json::from_slice(&bytes)?,
+            None => return Ok(None),
+        };
+        let passphrase = rpassword::prompt_password_stderr("passphrase: ").unwrap();
+        let cipher = wrapped.open(&passphrase)?;
+
+        Ok(Some(self.cipher.get_or_init(|| cipher)))
     }
 
     fn get_url(&self) -> Option<Url> {
-        if let Some(bytes) = self.db.get(b"url").unwrap() {
+        if let Some(bytes) = self.settings.get(b"url").unwrap() {
             let string = std::str::from_utf8(&bytes).unwrap();
             Some(Url::parse(string).unwrap())
         } else {
@@ -120,7 +395,49 @@ impl Client {
     }
 
     fn set_url(&self, url: &Url) {
-        self.db.insert(b"url", url.to_string().as_bytes()).unwrap();
+        self.settings.insert(b"url", url.to_string().as_bytes()).unwrap();
+    }
+
+    fn get_frontend_url(&self) -> Option<Url> {
+        if let Some(bytes) = self.settings.get(FRONTEND_URL).unwrap() {
+            let string = std::str::from_utf8(&bytes).unwrap();
+            Some(Url::parse(string).unwrap())
+        } else {
+            None
+        }
+    }
+
+    fn set_frontend_url(&self, url: &Url) {
+        self.settings.insert(FRONTEND_URL, url.to_string().as_bytes()).unwrap();
+    }
+
+    fn get_cache_limit(&self) -> u64 {
+        match self.settings.get(CACHE_LIMIT).unwrap() {
+            Some(bytes) => std::str::from_utf8(&bytes).unwrap().parse().unwrap(),
+            None => cache::DEFAULT_LIMIT,
+        }
+    }
+
+    fn set_cache_limit(&self, limit: u64) {
+        self.settings.insert(CACHE_LIMIT, limit.to_string().as_bytes()).unwrap();
+    }
+
+    fn get_prompt_frontend_url(&self) -> Url {
+        if let Some(url) = self.get_frontend_url() {
+            return url;
+        }
+
+        loop {
+            let string = prompt_line("frontend url: ");
+
+            match Url::parse(&string) {
+                Ok(url) => {
+                    self.set_frontend_url(&url);
+                    return url;
+                },
+                Err(err) => eprintln!("{:?}", err),
+            };
+        }
     }
 
     fn get_prompt_url(&self) -> Url {
@@ -157,27 +474,101 @@ impl Client {
         }
 
         let user = self.prompt_user_details();
-        let key = self.login(&user).await.unwrap();
+        let key = self.login(&user, None).await.unwrap();
 
         key.key.into_owned()
     }
+
+    /// Whether the server turned the stored key away, rather than the request that was made with
+    /// it. Both are answered with Unauthorized.
+    async fn session_expired(&self) -> Result<bool> {
+        let key = match self.get_key() {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+
+        let response = self.request::<endpoint::ListSessions>(&[])
+            .bearer_auth(key)
+            .send().await?;
+        Ok(response.status() == StatusCode::UNAUTHORIZED)
+    }
+
+    /// Send a request from `auth_request` once. If the session has expired, the stored key is
+    /// forgotten and the user logs in again, after which the request is sent one more time.
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let again = request.try_clone();
+        let result = request.send().await?.check_status().await;
+        self.relogin_on_expiry(result, again, |request| async move {
+            request.send().await?.check_status().await
+        }).await
+    }
+
+    /// Like `send`, but for requests that can safely be repeated, which are also retried after
+    /// transient failures.
+    async fn send_retry(&self, request: RequestBuilder) -> Result<Response> {
+        let again = request.try_clone();
+        let result = retry::send(request).await;
+        self.relogin_on_expiry(result, again, retry::send).await
+    }
+
+    async fn relogin_on_expiry<F, Fut>(
+        &self,
+        result: Result<Response>,
+        again: Option<RequestBuilder>,
+        send: F,
+    ) -> Result<Response>
+    where
+        F: FnOnce(RequestBuilder) -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        let (error, again) = match (result, again) {
+            (Err(error @ Error::Remote { status_code: StatusCode::UNAUTHORIZED, .. }), Some(again)) => (error, again),
+            (result, _) => return result,
+        };
+
+        if !self.session_expired().await? {
+            return Err(error);
+        }
+
+        eprintln!("The session has expired, log in again");
+        self.settings.remove(b"key")?;
+        let key = self.get_prompt_key().await;
+
+        // Requests keep every header that they were given, so the old key has to be replaced
+        // rather than joined by the new one.
+        let (client, request) = again.build_split();
+        let mut request = request?;
+        request.headers_mut().insert(header::AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+        send(RequestBuilder::from_parts(client, request)).await
+    }
     
     fn build_url(&self, path: &str) -> Url {
         self.get_prompt_url().join(path).unwrap()
     }
     
-    async fn build_auth_url(&self, path: &str) -> Url {
-        let mut url = self.build_url(path);
+    /// Start a request to `E`, with `params` filled into its path.
+    fn request<E: Endpoint>(&self, params: &[&str]) -> RequestBuilder {
+        self.client
+            .request(http_method(E::METHOD), self.build_url(&E::path(params)[1..]))
+            .header(API_VERSION_HEADER, ApiVersion::CURRENT.to_string())
+    }
 
+    /// Start a request to `E` that carries the session key in the `Authorization` header.
+    async fn auth_request<E: Endpoint>(&self, params: &[&str]) -> RequestBuilder {
         let key = self.get_prompt_key().await;
-        url.query_pairs_mut().append_pair("key", &key);
+        self.request::<E>(params).bearer_auth(key)
+    }
 
-        url
+    /// Like `auth_request`, with `body` sent as JSON.
+    async fn auth_json<'a, E: Endpoint>(&self, params: &[&str], body: &E::Request<'a>) -> RequestBuilder
+    where
+        E::Request<'a>: Serialize,
+    {
+        self.auth_request::<E>(params).await.json(body)
     }
 
-    async fn create_user<'a, 'b>(&self, user: &UserDetails<'a, 'b>) -> Result<()> {
-        self.client
-            .post(self.build_url("user/create"))
+    async fn create_user<'a>(&self, user: &UserDetails<'a, 'a>) -> Result<()> {
+        self.request::<endpoint::CreateUser>(&[])
             .json(user)
             .send().await?
             .check_status().await?;
@@ -185,28 +576,26 @@ impl Client {
         Ok(())
     }
 
-    async fn login<'a, 'b>(&self, user: &UserDetails<'a, 'b>) -> Result<Key<'static>> {
-        let bytes = self.client
-            .post(self.build_url("user/login"))
-            .json(user)
+    async fn login<'a>(&self, user: &UserDetails<'a, 'a>, label: Option<&str>) -> Result<Key<'static>> {
+        let mut request = self.request::<endpoint::Login>(&[])
+            .json(user);
+        if let Some(label) = label {
+            request = request.query(&[("label", label)]);
+        }
+
+        let response = request
             .send().await?
-            .check_status().await?
-            .bytes().await?;
-        let json: Key = serde_json::from_slice(&bytes)?;
+            .check_status().await?;
+        let json = decode::<endpoint::Login>(response).await?;
 
         self.set_key(&json.key);
 
-        Ok(json.into_owned())
+        Ok(json)
     }
 
-    async fn sessions(&self) -> Result<SessionList<'static>> {
-        let bytes = self.client
-            .get(self.build_auth_url("user/sessions").await)
-            .send().await?
-            .check_status().await?
-            .bytes().await?;
-        let json: SessionList = serde_json::from_slice(&bytes)?;
-        Ok(json.into_owned())
+    async fn sessions(&self) -> Result<SessionList<'static, 'static, 'static>> {
+        let response = self.send_retry(self.auth_request::<endpoint::ListSessions>(&[]).await).await?;
+        decode::<endpoint::ListSessions>(response).await
     }
 
     async fn logout(&self, prefix: Option<&str>) -> Result<()> {
@@ -217,16 +606,15 @@ impl Client {
             (Some(k), None) => k,
         };
 
-        self.client
-            .delete(self.build_auth_url("user/logout").await)
-            .json(&Key { key: Cow::from(key) })
-            .send().await?
-            .check_status().await?;
+        // A key that the server no longer accepts is already logged out, so there is no point in
+        // logging in again.
+        let request = self.auth_json::<endpoint::Logout>(&[], &Key { key: Cow::from(key) }).await;
+        retry::send(request).await?;
 
         if let Some(current_key) = &self.get_key() {
             if let (Some((_,ck)), Some((_,k))) = (current_key.split_once('.'), key.split_once('.')) {
                 if ck.starts_with(k) {
-                    self.db.remove(b"key").unwrap();
+                    self.settings.remove(b"key").unwrap();
                 }
             }
         }
@@ -234,25 +622,51 @@ impl Client {
         Ok(())
     }
 
+    /// End every session except this one.
+    async fn logout_others(&self) -> Result<()> {
+        self.send_retry(self.auth_request::<endpoint::LogoutOthers>(&[]).await).await?;
+
+        Ok(())
+    }
+
     async fn file_list<'a>(&self, req: &ListRequest<'a>) -> Result<FileList<'static, 'static>> {
-        let bytes = self.client
-            .get(self.build_auth_url("file/list").await)
-            .json(req)
-            .send().await?
-            .check_status().await?
-            .bytes().await?;
-        let json: FileList = serde_json::from_slice(&bytes)?;
-        Ok(json.into_owned())
+        let response = self.send_retry(self.auth_json::<endpoint::ListFiles>(&[], req).await).await?;
+        decode::<endpoint::ListFiles>(response).await
     }
 
-    async fn upload(&self, path: &Path, json: Option<&Path>) -> Result<NewResource<'static>> {
+    /// Names and ids of the files whose names start with `prefix`, up to `length` of them, read a
+    /// page at a time.
+    async fn list_files(
+        &self,
+        prefix: Option<&str>,
+        skip: Option<usize>,
+        length: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let mut files = vec![];
+        let mut token: Option<String> = None;
+
+        loop {
+            let request = ListRequest {
+                prefix: prefix.map(Cow::from),
+                // Only the first page is counted from the start of the listing.
+                skip: if token.is_none() { skip } else { None },
+                length: Some(length.map_or(LIST_PAGE, |length| (length - files.len()).min(LIST_PAGE))),
+                token: token.take().map(Cow::from),
+            };
+            let page = self.file_list(&request).await?;
+            files.extend(page.files.into_iter().map(|(name, id)| (name.into_owned(), id.into_owned())));
+
+            match page.next {
+                Some(next) if length.is_none_or(|length| files.len() < length) => token = Some(next.into_owned()),
+                _ => return Ok(files),
+            }
+        }
+    }
+
+    async fn file_metadata(&self, path: &Path, sidecar: Option<&Sidecar>) -> Result<FileMetadata<'static, 'static>> {
         let mime = mime_guess::from_path(path).first_or_octet_stream();
 
-        let o_time_stamp = json.map(|json_path| {
-            let file = std::fs::File::open(json_path).ok()?;
-            let value: serde_json::Value = serde_json::from_reader(file).ok()?;
-            value.get("creationTime")?.get("timestamp")?.as_str()?.parse::<i64>().ok()
-        }).flatten();
+        let o_time_stamp = sidecar.and_then(|sidecar| sidecar.time_stamp());
 
         let time_stamp = if let Some(ts) = o_time_stamp {
             ts
@@ -266,33 +680,191 @@ impl Client {
         let name = path.file_name().unwrap().to_str()
             .expect("Only support unicode file names");
 
-        let metadata = serde_json::to_string(&FileMetadata {
+        Ok(FileMetadata {
             last_modified: time_stamp,
-            name: Cow::from(name),
-            mime: Cow::from(mime.essence_str()),
-        }).unwrap();
+            name: Cow::from(name.to_string()),
+            mime: Cow::from(mime.essence_str().to_string()),
+        })
+    }
+
+    /// Upload a file, encrypting it first if the profile has a key.
+    async fn upload(&self, path: &Path, sidecar: Option<&Sidecar>) -> Result<StoredFile<'static>> {
+        self.upload_with(path, sidecar, ConflictPolicy::default()).await
+    }
+
+    /// Upload a file, doing what `conflict` says if the user has a file with the same name already.
+    /// Fails with `Error::Mismatch` if what the server stored isn't what was sent.
+    async fn upload_with(
+        &self,
+        path: &Path,
+        sidecar: Option<&Sidecar>,
+        conflict: ConflictPolicy,
+    ) -> Result<StoredFile<'static>> {
+        let metadata = serde_json::to_string(&self.file_metadata(path, sidecar).await?).unwrap();
         let metadata_header = base64::encode_config(metadata.as_bytes(), base64::URL_SAFE);
 
-        let file = fs::File::open(path).await.unwrap();
-        let body = Body::wrap_stream(file_stream(file, 1024 * 8));
+        let mut request = self.auth_request::<endpoint::Upload>(&[]).await
+            .header(UPLOAD_METADATA, metadata_header);
+        if conflict != ConflictPolicy::default() {
+            request = request.query(&[("conflict", conflict)]);
+        }
 
-        let bytes = self.client
-            .post(self.build_auth_url("file/upload").await)
-            .header(UPLOAD_METADATA, metadata_header)
-            .body(body)
-            .send().await?
-            .check_status().await?
-            .bytes().await?;
-        let json: NewResource = serde_json::from_slice(&bytes)?;
+        let name = path.file_name().unwrap().to_string_lossy();
+        // Files are sealed as a whole, so encrypted ones can't be read as they are sent.
+        let (body, tracked, sent) = match self.cipher()? {
+            Some(cipher) => {
+                let sealed = cipher.encrypt(&fs::read(path).await?);
+                let hash = verify::hash_bytes(&sealed);
+                request = request.query(&[("encrypted", "true")]);
+                let (body, tracked) = self.transfer.track(Some(&name), sealed.len() as u64, progress::chunks(sealed));
+                (Body::wrap_stream(body), tracked, hash)
+            }
+            None => {
+                let hash = wire::hash_hex(&verify::hash_file(path).await?);
+                let file = fs::File::open(path).await.unwrap();
+                let len = file.metadata().await?.len();
+                let (body, tracked) = self.transfer.track(Some(&name), len, file_stream(file, 1024 * 8));
+                (Body::wrap_stream(body), tracked, hash)
+            }
+        };
 
-        Ok(json.into_owned())
+        let result = async {
+            let response = self.send(request.body(body)).await?;
+            let stored = decode::<endpoint::Upload>(response).await?;
+
+            if !verify::matches(stored.hash.as_deref(), &sent) {
+                return Err(Error::Mismatch {
+                    name: stored.name.into_owned(),
+                    file_id: stored.id.into_owned(),
+                });
+            }
+            Ok(stored)
+        }
+        .await;
+
+        // Only files that were stored stay counted as sent.
+        if result.is_err() {
+            tracked.rewind();
+        }
+        result
     }
 
-    async fn upload_dir(&self, dir: &Path) -> Result<Vec<String>> {
+    /// Upload several small files in one request, returning a result for each of them. Files that
+    /// the server stored differently from how they were sent are given an error instead of an id.
+    async fn upload_batch(&self, files: &[(&PathBuf, Option<&Sidecar>)]) -> Result<Vec<UploadResult<'static, 'static, 'static>>> {
+        let mut form = Form::new();
+        let cipher = self.cipher()?;
+        let mut sent = vec![];
+        let mut tracked = vec![];
+
+        for (path, sidecar) in files {
+            let metadata = self.file_metadata(path, *sidecar).await?;
+            let mut contents = fs::read(path).await?;
+            if let Some(cipher) = cipher {
+                contents = cipher.encrypt(&contents);
+            }
+            sent.push(verify::hash_bytes(&contents));
+
+            let len = contents.len() as u64;
+            let (body, part_tracked) = self.transfer.track(None, len, progress::chunks(contents));
+            tracked.push(part_tracked);
+            let part = Part::stream_with_length(Body::wrap_stream(body), len);
+
+            form = form
+                .text("metadata", serde_json::to_string(&metadata).unwrap())
+                .part("file", part.file_name(metadata.name.into_owned()));
+        }
+
+        let mut request = self.auth_request::<endpoint::UploadBatch>(&[]).await
+            .multipart(form);
+        if cipher.is_some() {
+            request = request.query(&[("encrypted", "true")]);
+        }
+        let response = match self.send(request).await {
+            Ok(response) => response,
+            Err(error) => {
+                tracked.iter().for_each(Tracked::rewind);
+                return Err(error);
+            }
+        };
+        let mut results = decode::<endpoint::UploadBatch>(response).await?;
+
+        for (result, sent) in results.iter_mut().zip(sent.iter()) {
+            if result.id.is_some() && !verify::matches(result.hash.as_deref(), sent) {
+                let mismatch = Error::Mismatch {
+                    name: result.name.to_string(),
+                    file_id: result.id.take().unwrap().into_owned(),
+                };
+                eprintln!("{}", mismatch);
+                result.error = Some(Cow::from(mismatch.to_string()));
+            }
+        }
+        for (result, tracked) in results.iter().zip(tracked.iter()) {
+            if result.id.is_none() {
+                tracked.rewind();
+            }
+        }
+        Ok(results)
+    }
+
+    async fn limits(&self) -> Result<Limits> {
+        let response = retry::send(self.request::<endpoint::GetLimits>(&[])).await?;
+        decode::<endpoint::GetLimits>(response).await
+    }
+
+    /// Whether the server would turn `path` away for being too large.
+    async fn too_large(&self, path: &Path, limits: &Limits) -> Result<bool> {
+        let size = fs::metadata(path).await?.len();
+
+        if size > limits.max_upload_bytes {
+            eprintln!(
+                "Skipping {:?}: {} bytes is over the {} byte limit",
+                path, size, limits.max_upload_bytes
+            );
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Upload a file on its own, or a batch of small files, returning what became of each file.
+    /// Requests that fail for a transient reason are tried again.
+    async fn upload_unit(&self, unit: &[(&PathBuf, Option<&Sidecar>)]) -> Vec<(PathBuf, Outcome)> {
+        if let [(path, sidecar)] = unit {
+            let outcome = match retry::with_backoff(|| self.upload(path, *sidecar)).await {
+                Ok(new) => Outcome::Stored(new.id.into_owned()),
+                Err(error) => Outcome::failed(&error),
+            };
+            return vec![(path.to_path_buf(), outcome)];
+        }
+
+        match retry::with_backoff(|| self.upload_batch(unit)).await {
+            Ok(results) => results
+                .into_iter()
+                .zip(unit.iter())
+                .map(|(result, (path, _))| {
+                    let outcome = match result.id {
+                        Some(id) => Outcome::Stored(id.into_owned()),
+                        None => Outcome::Failed,
+                    };
+                    (path.to_path_buf(), outcome)
+                })
+                .collect(),
+            Err(error) => {
+                let outcome = || Outcome::failed(&error);
+                unit.iter().map(|(path, _)| (path.to_path_buf(), outcome())).collect()
+            }
+        }
+    }
+
+    /// Upload every file in `dir`, running up to `jobs` requests at once. Files that couldn't
+    /// reach the server are queued to be uploaded and added to `album` by `flush`. Returns the ids
+    /// of the stored files along with the files that the server turned away.
+    async fn upload_dir(&self, dir: &Path, jobs: usize, album: Option<&str>) -> Result<(Vec<String>, Vec<PathBuf>)> {
         let mut iter = fs::read_dir(dir).await?;
         let mut file_paths = HashSet::new();
 
-        println!("Uploading {:?}...", dir);
+        eprintln!("Uploading {:?}...", dir);
 
         while let Some(entry) = iter.next_entry().await? {
             if entry.file_type().await?.is_file() {
@@ -302,75 +874,394 @@ impl Client {
 
         let extended: Vec<_> = file_paths
             .iter()
-            .filter(|p| !p.to_str().unwrap().ends_with(".json"))
+            .filter(|p| !p.to_str().unwrap().ends_with(".json") && !xmp::is_xmp(p))
             .map(|p| {
-                let mut os_string = p.clone().into_os_string();
-                os_string.push(".json");
-                let json = PathBuf::from(os_string);
-                if file_paths.contains(&json) {
-                    (p, Some(json))
-                } else {
-                    (p, None)
-                }
+                let sidecar = takeout::sidecar_path(p, &file_paths).and_then(|json| Sidecar::read(&json));
+                (p, sidecar)
             })
             .collect();
+        let xmps: HashMap<&PathBuf, PathBuf> = extended
+            .iter()
+            .filter_map(|(p, _)| Some((*p, xmp::xmp_path(p, &file_paths)?)))
+            .collect();
+
+        // Log in before anything runs concurrently, so that only one prompt is shown.
+        self.get_prompt_key().await;
+        self.cipher()?;
+        let limits = self.limits().await?;
+
+        // Small files are sent together, since they would spend most of their time on overhead.
+        let mut units = vec![];
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+
+        for (path, sidecar) in extended.iter() {
+            if self.too_large(path, &limits).await? {
+                continue;
+            }
 
-        let bar = indicatif::ProgressBar::new(extended.len() as u64);
+            let size = fs::metadata(path).await?.len();
+            if size > BATCH_FILE_BYTES || limits.max_batch_files <= 1 {
+                units.push((vec![(*path, sidecar.as_ref())], size));
+                continue;
+            }
+
+            batch.push((*path, sidecar.as_ref()));
+            batch_bytes += size;
+
+            if batch.len() >= limits.max_batch_files || batch_bytes >= BATCH_BYTES {
+                units.push((std::mem::take(&mut batch), batch_bytes));
+                batch_bytes = 0;
+            }
+        }
+        if !batch.is_empty() {
+            units.push((batch, batch_bytes));
+        }
+
+        // Sealing makes each file a little larger than it is on disk.
+        let overhead = if self.cipher()?.is_some() { crypto::OVERHEAD } else { 0 };
+        let total = units.iter().map(|(unit, bytes)| bytes + overhead * unit.len() as u64).sum();
+        let bar = self.transfer.show(Some(total)).unwrap();
+
+        let mut uploads = stream::iter(units.iter())
+            .map(|(unit, _)| {
+                let (bar, xmps) = (&bar, &xmps);
+                async move {
+                    let results = self.upload_unit(unit).await;
+                    for (path, outcome) in results.iter() {
+                        match (outcome, xmps.get(&path)) {
+                            (Outcome::Stored(id), Some(xmp)) => {
+                                if let Err(error) = self.set_xmp(id, xmp).await {
+                                    bar.println(format!("Couldn't send the sidecar of {:?}: {}", path, error));
+                                }
+                            }
+                            (Outcome::Stored(_), None) => {}
+                            // What was sent of files that weren't stored was taken off the bar, so
+                            // they are left out of its total as well.
+                            _ => {
+                                let size = fs::metadata(path).await.map_or(0, |metadata| metadata.len());
+                                bar.dec_length(size + overhead);
+                            }
+                        }
+                    }
+                    results
+                }
+            })
+            .buffer_unordered(jobs.max(1));
+
+        let queue = self.queue()?;
         let mut file_ids = vec![];
         let mut errors = vec![];
-
-        for (path, json) in extended.iter() {
-            match self.upload(&path, json.as_ref().map(|p| p.as_path())).await {
-                Ok(new) => {
-                    file_ids.push(new.id.into_owned());
-                },
-                Err(_) => {
-                    errors.push(path);
-                },
+        let mut queued = 0;
+
+        while let Some(results) = uploads.next().await {
+            for (path, outcome) in results {
+                match outcome {
+                    Outcome::Stored(id) => file_ids.push(id),
+                    Outcome::Unreachable => {
+                        queue.push(&Operation::Upload { path, album: album.map(String::from) })?;
+                        queued += 1;
+                    }
+                    Outcome::Failed => errors.push(path),
+                }
             }
-            bar.inc(1);
         }
-        bar.finish();
+        self.transfer.hide();
+
+        if queued > 0 {
+            eprintln!("Couldn't reach the server for {} files, which `flush` will upload", queued);
+        }
+
+        Ok((file_ids, errors))
+    }
+
+    /// Try every queued operation again, keeping the ones that still can't reach the server.
+    async fn flush(&self) -> Result<Flushed> {
+        let queue = self.queue()?;
+        let (mut done, mut failed, mut kept) = (0, 0, 0);
+
+        for (key, operation) in queue.entries()? {
+            let result = match &operation {
+                Operation::Upload { path, album } => {
+                    match retry::with_backoff(|| self.upload(path, None)).await {
+                        Ok(new) => match album {
+                            Some(album) => self.add_or_queue(album, &[new.id.into_owned()]).await,
+                            None => Ok(()),
+                        },
+                        Err(error) => Err(error),
+                    }
+                }
+                Operation::Add { album, ids } => self.add_to_album(album, ids).await,
+            };
 
-        for path in errors.iter()  {
-            eprintln!("Couldn't upload: {:?}", path);
+            match result {
+                Ok(()) => done += 1,
+                Err(error) if error.is_transient() => {
+                    kept += 1;
+                    continue;
+                }
+                Err(error) => {
+                    eprintln!("Giving up on {:?}: {}", operation, error);
+                    failed += 1;
+                }
+            }
+
+            queue.remove(&key)?;
         }
 
-        Ok(file_ids)
+        Ok(Flushed { done, failed, kept })
+    }
+
+    async fn rename_file(&self, file_id: &str, name: &str) -> Result<()> {
+        let request = self.auth_json::<endpoint::RenameFile>(&[file_id], &Rename { name: Cow::from(name) }).await;
+        self.send_retry(request).await?;
+        Ok(())
     }
 
     async fn create_album<'a>(&self, settings: &AlbumSettings<'a>) -> Result<String> {
-        let bytes = self.client
-            .post(self.build_auth_url("album/create").await)
-            .json(settings)
-            .send().await?
-            .check_status().await?
+        let request = self.auth_json::<endpoint::CreateAlbum>(&[], settings).await;
+        let response = self.send(request).await?;
+        Ok(decode::<endpoint::CreateAlbum>(response).await?.id.into_owned())
+    }
+
+    async fn add_to_album(&self, album_id: &str, file_ids: &[String]) -> Result<()> {
+        // Adding a file that is already in the album changes nothing, so this can be repeated.
+        let ids = IdList { ids: file_ids.iter().map(Cow::from).collect() };
+        let request = self.auth_json::<endpoint::AddFiles>(&[album_id], &ids).await;
+        let response = self.send_retry(request).await?;
+        wait_bulk(response).await
+    }
+
+    /// Add files to an album, or queue the change if the server can't be reached.
+    async fn add_or_queue(&self, album_id: &str, file_ids: &[String]) -> Result<()> {
+        match self.add_to_album(album_id, file_ids).await {
+            Err(error) if error.is_transient() => {
+                self.queue()?.push(&Operation::Add {
+                    album: album_id.to_string(),
+                    ids: file_ids.to_vec(),
+                })?;
+                eprintln!("Couldn't reach the server, so the files will be added by `flush`");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn remove_from_album(&self, album_id: &str, file_ids: &[String]) -> Result<()> {
+        let ids = IdList { ids: file_ids.iter().map(Cow::from).collect() };
+        let request = self.auth_json::<endpoint::RemoveFiles>(&[album_id], &ids).await;
+        let response = self.send_retry(request).await?;
+        wait_bulk(response).await
+    }
+
+    /// Lay an album out by days in `time_zone`, keeping the rest of its settings.
+    async fn set_album_time_zone(&self, album_id: &str, time_zone: chrono_tz::Tz) -> Result<()> {
+        let mut settings = self.album_metadata(album_id).await?.album.description;
+        settings.time_zone = time_zone;
+
+        let request = self.auth_json::<endpoint::UpdateAlbum>(&[album_id], &settings).await;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    async fn reorder_album(&self, album_id: &str, file_ids: &[String]) -> Result<()> {
+        let ids = IdList { ids: file_ids.iter().map(Cow::from).collect() };
+        let request = self.auth_json::<endpoint::ReorderAlbum>(&[album_id], &ids).await;
+        self.send_retry(request).await?;
+        Ok(())
+    }
+
+    async fn move_files(&self, from_id: &str, to_id: &str, file_ids: &[String]) -> Result<()> {
+        let ids = IdList { ids: file_ids.iter().map(Cow::from).collect() };
+        let request = self.auth_json::<endpoint::MoveFiles>(&[from_id, to_id], &ids).await;
+        self.send_retry(request).await?;
+        Ok(())
+    }
+
+    async fn album_list(&self) -> Result<HashMap<String, AlbumInfo<'static>>> {
+        let response = self.send_retry(self.auth_request::<endpoint::ListAlbums>(&[]).await).await?;
+        decode::<endpoint::ListAlbums>(response).await
+    }
+
+    async fn browse_albums(&self, role: Option<&str>, sort: &str) -> Result<AlbumListing<'static>> {
+        let mut request = self.auth_request::<endpoint::BrowseAlbums>(&[]).await.query(&[("sort", sort)]);
+        if let Some(role) = role {
+            request = request.query(&[("role", role)]);
+        }
+        let response = self.send_retry(request).await?;
+        decode::<endpoint::BrowseAlbums>(response).await
+    }
+
+    async fn album_metadata(&self, album_id: &str) -> Result<AlbumInfo<'static>> {
+        let request = self.auth_request::<endpoint::ServeAlbum>(&[album_id, "metadata"]).await;
+        let bytes = self.send_retry(request).await?
             .bytes().await?;
-        let json: NewResource = serde_json::from_slice(&bytes)?;
-        Ok(json.id.to_string())
+        let json: AlbumInfo = serde_json::from_slice(&bytes)?;
+        Ok(json.into_owned())
     }
 
-    async fn add_to_album(&self, album_id: &str, file_ids: &Vec<String>) -> Result<()> {
-        self.client
-            .post(self.build_auth_url(&format!("album/add/{}", album_id)).await)
-            .json(&IdList { ids: file_ids.iter().map(|e| Cow::from(e)).collect() })
-            .send().await?
-            .check_status().await?;
+    /// The sections listed by the album's top fragment, which has the id of the fragment head.
+    async fn album_top(&self, album_id: &str, fragment_head: u64) -> Result<Vec<TopEntry>> {
+        let fragment_head = fragment_head.to_string();
+        let request = self.auth_request::<endpoint::ServeAlbum>(&[album_id, &fragment_head]).await;
+        let bytes = self.send_retry(request).await?
+            .bytes().await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// The files of a section fragment of an album, whose id is listed by the top fragment.
+    async fn album_section(&self, album_id: &str, fragment_id: u64) -> Result<Vec<SectionEntry>> {
+        let fragment_id = fragment_id.to_string();
+        let request = self.auth_request::<endpoint::ServeAlbum>(&[album_id, &fragment_id]).await;
+        let bytes = self.send_retry(request).await?
+            .bytes().await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    async fn share_album(&self, album_id: &str, email: &str, role: Role) -> Result<()> {
+        let pair = PermissionPair { email: Cow::from(email), user_id: None, role };
+        let request = self.auth_json::<endpoint::ShareAlbum>(&[album_id], &pair).await;
+        self.send(request).await?;
         Ok(())
     }
 
-    async fn remove_from_album(&self, album_id: &str, file_ids: &Vec<String>) -> Result<()> {
-        self.client
-            .delete(self.build_auth_url(&format!("album/remove/{}", album_id)).await)
-            .json(&IdList { ids: file_ids.iter().map(|e| Cow::from(e)).collect() })
-            .send().await?
-            .check_status().await?;
+    async fn unshare_album(&self, album_id: &str, email: &str) -> Result<()> {
+        let request = self.auth_json::<endpoint::UnshareAlbum>(&[album_id], &Key { key: Cow::from(email) }).await;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    async fn album_members(&self, album_id: &str) -> Result<Vec<PermissionPair<'static, 'static>>> {
+        let response = self.send_retry(self.auth_request::<endpoint::ListMembers>(&[album_id]).await).await?;
+        decode::<endpoint::ListMembers>(response).await
+    }
+
+    async fn file_info(&self, file_id: &str) -> Result<FileInfo<'static>> {
+        let response = self.send_retry(self.auth_request::<endpoint::GetFile>(&[file_id]).await).await?;
+        decode::<endpoint::GetFile>(response).await
+    }
+
+    /// Where the frontend shows the file or album with `id`. Unless `kind` says which of the two it
+    /// is, the server is asked.
+    async fn frontend_link(&self, id: &str, kind: Option<&'static str>) -> Result<(&'static str, Url)> {
+        let kind = match kind {
+            Some(kind) => kind,
+            None => match self.file_info(id).await {
+                Ok(_) => "file",
+                Err(error) if error.kind() == Kind::NotFound => {
+                    self.album_metadata(id).await?;
+                    "album"
+                }
+                Err(error) => return Err(error),
+            },
+        };
+
+        let mut url = self.get_prompt_frontend_url();
+        url.path_segments_mut()
+            .expect("Invalid frontend url")
+            .pop_if_empty()
+            .extend([kind, id]);
+
+        Ok((kind, url))
+    }
+
+    /// Save the original of a file to `output`, or under its name in the current directory,
+    /// decrypting it if it was encrypted.
+    async fn download(&self, file_id: &str, output: Option<&Path>) -> Result<PathBuf> {
+        let info = self.file_info(file_id).await?;
+
+        let path = match output {
+            Some(output) => output.to_path_buf(),
+            None => PathBuf::from(Path::new(info.metadata.name.as_ref()).file_name().unwrap_or(file_id.as_ref())),
+        };
+
+        self.save_original(file_id, &info, &path).await?;
+        Ok(path)
+    }
+
+    /// Save the `quality` rendition of a file to `output`, or under its name with the extension of
+    /// the rendition in the current directory. Renditions come through the thumbnail cache.
+    async fn download_rendition(&self, file_id: &str, quality: &str, output: Option<&Path>) -> Result<PathBuf> {
+        let info = self.file_info(file_id).await?;
+        let (mime, bytes) = self.rendition(file_id, quality, info.revision).await?;
+
+        let path = match output {
+            Some(output) => output.to_path_buf(),
+            None => {
+                let name = Path::new(info.metadata.name.as_ref()).file_name().unwrap_or(file_id.as_ref());
+                let mut path = PathBuf::from(name);
+                if let Some(extension) = mime_guess::get_mime_extensions_str(&mime).and_then(|found| found.first()) {
+                    path.set_extension(extension);
+                }
+                path
+            }
+        };
+
+        fs::write(&path, bytes).await?;
+        Ok(path)
+    }
+
+    /// Save the original of the file with `info` to `path`.
+    async fn save_original(&self, file_id: &str, info: &FileInfo<'_>, path: &Path) -> Result<()> {
+        let mut response = self.send_retry(self.auth_request::<endpoint::ServeFile>(&["large", file_id]).await).await?;
+        let mut file = fs::File::create(path).await?;
+
+        if info.encrypted {
+            let cipher = self.cipher()?.ok_or(Error::NoKey)?;
+            file.write_all(&cipher.decrypt(&response.bytes().await?)?).await?;
+        } else {
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+            }
+        }
+
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn delete_file(&self, file_id: &str) -> Result<()> {
+        self.send(self.auth_request::<endpoint::DeleteFile>(&[file_id]).await).await?;
+        Ok(())
+    }
+
+    async fn delete_album(&self, album_id: &str) -> Result<()> {
+        self.send(self.auth_request::<endpoint::DeleteAlbum>(&[album_id]).await).await?;
+        Ok(())
+    }
+
+    /// Files that were taken on `date`, `YYYY-MM-DD`, in earlier years, latest year first.
+    async fn memories(&self, date: &str) -> Result<Vec<(i32, Vec<SectionEntry>)>> {
+        let request = self.auth_request::<endpoint::Memories>(&[]).await.query(&[("date", date)]);
+        let response = self.send_retry(request).await?;
+        decode::<endpoint::Memories>(response).await
+    }
+
+    async fn trash_list(&self) -> Result<Vec<TrashEntry<'static, 'static>>> {
+        let response = self.send_retry(self.auth_request::<endpoint::ListTrash>(&[]).await).await?;
+        decode::<endpoint::ListTrash>(response).await
+    }
+
+    async fn trash_restore(&self, kind: TrashKind, id: &str) -> Result<()> {
+        let request = self.auth_json::<endpoint::RestoreTrash>(&[], &TrashRestore { kind, id: Cow::from(id) }).await;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    async fn empty_trash(&self) -> Result<()> {
+        self.send(self.auth_request::<endpoint::EmptyTrash>(&[]).await).await?;
+        Ok(())
+    }
+
+    async fn transfer_album(&self, album_id: &str, email: &str) -> Result<()> {
+        let transfer = TransferOwnership { email: Cow::from(email) };
+        let request = self.auth_json::<endpoint::TransferAlbum>(&[album_id], &transfer).await;
+        self.send(request).await?;
         Ok(())
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let matches = App::new(crate_name!())
         .version(crate_version!())
         .arg(Arg::with_name("database")
@@ -379,23 +1270,96 @@ async fn main() -> Result<()> {
         .arg(Arg::with_name("temp")
             .short("t")
             .long("temp"))
+        .arg(Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .takes_value(true))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Print the result as JSON"))
         .arg(Arg::with_name("url")
             .takes_value(true))
+        .arg(Arg::with_name("limit-rate")
+            .long("limit-rate")
+            .takes_value(true)
+            .help("Bytes per second that uploads are held to, with an optional k, m or g suffix"))
+        .subcommand(SubCommand::with_name("config")
+            .subcommand(SubCommand::with_name("set-url")
+                .arg(Arg::with_name("url")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("set-frontend")
+                .about("Set where the frontend is, which `url` makes links to")
+                .arg(Arg::with_name("url")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("set-cache-limit")
+                .about("Set how many megabytes of renditions the thumbnail cache keeps")
+                .arg(Arg::with_name("megabytes")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("list"))
+            .subcommand(SubCommand::with_name("use")
+                .arg(Arg::with_name("name")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))))
         .subcommand(SubCommand::with_name("create"))
-        .subcommand(SubCommand::with_name("login"))
+        .subcommand(SubCommand::with_name("login")
+            .arg(Arg::with_name("label")
+                .short("l")
+                .long("label")
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("sessions"))
+        .subcommand(SubCommand::with_name("keygen")
+            .about("Make a key that this profile encrypts uploads with")
+            .arg(Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Don't ask for confirmation")))
+        .subcommand(SubCommand::with_name("flush")
+            .about("Retry uploads and album changes that couldn't reach the server"))
         .subcommand(SubCommand::with_name("logout")
             .arg(Arg::with_name("prefix")
                 .index(1)
-                .takes_value(true)))
+                .takes_value(true))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .conflicts_with("prefix")
+                .help("Log out every other session")))
         .subcommand(SubCommand::with_name("upload")
             .arg(Arg::with_name("add")
                 .short("a")
                 .long("add")
                 .takes_value(true))
+            .arg(Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .help("Uploads to run at the same time, 4 by default"))
             .arg(Arg::with_name("path")
                 .required(true)
                 .index(1)))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Upload photos and videos as they show up in directories, until interrupted")
+            .arg(Arg::with_name("add")
+                .short("a")
+                .long("add")
+                .takes_value(true)
+                .help("Album to add files to, unless their directory is given as <dir>=<album>"))
+            .arg(Arg::with_name("dirs")
+                .required(true)
+                .multiple(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Check that the files of a directory or album are intact on the server")
+            .arg(Arg::with_name("target")
+                .required(true)
+                .index(1)
+                .help("Directory whose files should be in the library, or the id of an album")))
         .subcommand(SubCommand::with_name("list")
             .arg(Arg::with_name("prefix")
                 .index(1)
@@ -414,6 +1378,95 @@ async fn main() -> Result<()> {
             .arg(Arg::with_name("length")
                 .short("l")
                 .takes_value(true)))
+        .subcommand(SubCommand::with_name("download")
+            .about("Save the original of a file, decrypting it if it was encrypted")
+            .arg(Arg::with_name("id")
+                .index(1)
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("thumbnails")
+                .long("thumbnails")
+                .help("Save a rendition instead, keeping it in the thumbnail cache"))
+            .arg(Arg::with_name("quality")
+                .short("q")
+                .long("quality")
+                .takes_value(true)
+                .requires("thumbnails")
+                .help("Rendition to save with --thumbnails, medium by default"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("browse")
+            .about("Look through albums and manage their files in the terminal"))
+        .subcommand(SubCommand::with_name("url")
+            .about("Print where the frontend shows a file or album")
+            .arg(Arg::with_name("id")
+                .index(1)
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .conflicts_with("album")
+                .help("The id is of a file, so the server isn't asked"))
+            .arg(Arg::with_name("album")
+                .long("album")
+                .help("The id is of an album, so the server isn't asked"))
+            .arg(Arg::with_name("open")
+                .long("open")
+                .help("Open the link in a browser as well")))
+        .subcommand(SubCommand::with_name("rename")
+            .arg(Arg::with_name("id")
+                .index(1)
+                .required(true)
+                .takes_value(true))
+            .arg(Arg::with_name("name")
+                .index(2)
+                .required(true)
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("rm")
+            .about("Move files to the trash")
+            .arg(Arg::with_name("ids")
+                .index(1)
+                .required(true)
+                .multiple(true))
+            .arg(Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("Don't ask for confirmation")))
+        .subcommand(SubCommand::with_name("trash")
+            .subcommand(SubCommand::with_name("list"))
+            .subcommand(SubCommand::with_name("restore")
+                .arg(Arg::with_name("ids")
+                    .index(1)
+                    .required(true)
+                    .multiple(true)))
+            .subcommand(SubCommand::with_name("empty")
+                .about("Delete everything in the trash for good")
+                .arg(Arg::with_name("yes")
+                    .short("y")
+                    .long("yes")
+                    .help("Don't ask for confirmation"))))
+        .subcommand(SubCommand::with_name("memories")
+            .about("List files that were taken on this day in earlier years")
+            .arg(Arg::with_name("date")
+                .long("date")
+                .takes_value(true)
+                .help("Day to look back from as YYYY-MM-DD, today by default")))
+        .subcommand(SubCommand::with_name("import-takeout")
+            .arg(Arg::with_name("timezone")
+                .long("timezone")
+                .takes_value(true)
+                .validator(time_zone::validate)
+                .help("Time zone of the albums that are made, the one of this system by default"))
+            .arg(Arg::with_name("path")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("export-takeout")
+            .about("Save every original into a .tgz archive, with XMP sidecars of their labels")
+            .arg(Arg::with_name("path")
+                .required(true)
+                .index(1)))
         .subcommand(SubCommand::with_name("album")
             .subcommand(SubCommand::with_name("create")
                 .arg(Arg::with_name("name")
@@ -422,94 +1475,610 @@ async fn main() -> Result<()> {
                     .takes_value(true))
                 .arg(Arg::with_name("timezone")
                     .short("tz")
+                    .long("timezone")
+                    .takes_value(true)
+                    .validator(time_zone::validate)
+                    .help("IANA name of the time zone that days are counted in, the one of this system by default"))
+                .arg(Arg::with_name("sort")
+                    .long("sort")
+                    .possible_values(&["capture", "upload", "name", "manual"])
+                    .takes_value(true))
+                .arg(Arg::with_name("description")
+                    .long("description")
+                    .takes_value(true))
+                .arg(Arg::with_name("year")
+                    .long("year")
+                    .takes_value(true)
+                    .help("Add files taken in this year as they are uploaded or tagged"))
+                .arg(Arg::with_name("tag")
+                    .long("tag")
+                    .takes_value(true)
+                    .help("Add files with this tag as they are uploaded or tagged"))
+                .arg(Arg::with_name("camera")
+                    .long("camera")
+                    .takes_value(true)
+                    .help("Add files taken with this camera model as they are uploaded or tagged")))
+            .subcommand(SubCommand::with_name("list")
+                .about("List the albums that are shared with you")
+                .arg(Arg::with_name("role")
+                    .long("role")
+                    .possible_values(&["owner", "shared"])
+                    .takes_value(true)
+                    .help("Only list the albums that you own, or those that others share with you"))
+                .arg(Arg::with_name("sort")
+                    .long("sort")
+                    .possible_values(&["name", "last_update", "length"])
+                    .default_value("name")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("show")
+                .about("Show the sections of an album")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Move an album to the trash")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("yes")
+                    .short("y")
+                    .long("yes")
+                    .help("Don't ask for confirmation")))
+            .subcommand(SubCommand::with_name("set-tz")
+                .about("Change the time zone that an album counts days in")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("timezone")
+                    .index(2)
+                    .takes_value(true)
+                    .validator(time_zone::validate)
+                    .help("IANA name of the time zone, the one of this system by default")))
+            .subcommand(SubCommand::with_name("share")
+                .about("Give someone access to an album, or change their role")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("email")
+                    .index(2)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("role")
+                    .short("r")
+                    .long("role")
+                    .possible_values(&["editor", "contributor", "reader"])
+                    .default_value("reader")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("unshare")
+                .about("Take away someone's access to an album")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("email")
+                    .index(2)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("members")
+                .about("List who an album is shared with")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("reorder")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("ids")
+                    .index(2)
+                    .required(true)
+                    .multiple(true)))
+            .subcommand(SubCommand::with_name("move")
+                .about("Move files from one album to another")
+                .arg(Arg::with_name("from")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("to")
+                    .index(2)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("ids")
+                    .index(3)
+                    .required(true)
+                    .multiple(true)))
+            .subcommand(SubCommand::with_name("transfer")
+                .about("Make another member the owner of an album")
+                .arg(Arg::with_name("album")
+                    .index(1)
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("email")
+                    .index(2)
+                    .required(true)
                     .takes_value(true))))
         .get_matches();
 
     let client = if matches.value_of("temp").is_none() {
         let db_path = matches.value_of("database").unwrap_or(".sync");
-        Client::new(db_path)
+        Client::new(db_path, matches.value_of("profile"))
     } else {
-        Client::temp()
+        Client::temp(matches.value_of("profile"))
     };
 
     if let Some(url) = matches.value_of("url") {
         client.set_url(&Url::parse(url).unwrap());
     }
+    if let Some(rate) = matches.value_of("limit-rate") {
+        client.transfer.limit_rate(progress::parse_rate(rate).expect("--limit-rate takes a rate like 500k"));
+    }
+
+    let output = Output::new(matches.is_present("json"));
+
+    if let Err(error) = run(&matches, &client, &output).await {
+        output.error(&error);
+        std::process::exit(error.kind().exit_code());
+    }
+}
 
-    if let Some(_) = matches.subcommand_matches("create") {
+async fn run(matches: &ArgMatches<'_>, client: &Client, output: &Output) -> Result<()> {
+    if let Some(matches) = matches.subcommand_matches("config") {
+        if let Some(matches) = matches.subcommand_matches("set-url") {
+            let url = Url::parse(matches.value_of("url").unwrap()).expect("Invalid url");
+            client.set_url(&url);
+            output.emit(json!({ "profile": client.profile, "url": url.as_str() }), || {});
+        } else if let Some(matches) = matches.subcommand_matches("set-frontend") {
+            let url = Url::parse(matches.value_of("url").unwrap()).expect("Invalid url");
+            client.set_frontend_url(&url);
+            output.emit(json!({ "profile": client.profile, "frontend_url": url.as_str() }), || {});
+        } else if let Some(matches) = matches.subcommand_matches("set-cache-limit") {
+            let megabytes: u64 = matches.value_of("megabytes").unwrap().parse().expect("Invalid number of megabytes");
+            client.set_cache_limit(megabytes << 20);
+            output.emit(json!({ "profile": client.profile, "cache_limit": megabytes << 20 }), || {});
+        } else if let Some(matches) = matches.subcommand_matches("use") {
+            let name = matches.value_of("name").unwrap();
+            client.use_profile(name);
+            output.emit(json!({ "profile": name }), || {});
+        } else {
+            let profiles = client.profiles();
+            let json: Vec<_> = profiles
+                .iter()
+                .map(|(name, url, logged_in)| json!({
+                    "name": name,
+                    "url": url.as_ref().map(|url| url.as_str()),
+                    "logged_in": logged_in,
+                    "current": *name == client.profile,
+                }))
+                .collect();
+
+            output.emit(json, || {
+                for (name, url, logged_in) in profiles {
+                    let marker = if name == client.profile { "*" } else { " " };
+                    let url = url.map_or(String::from("(no url)"), |url| url.to_string());
+                    let state = if logged_in { "logged in" } else { "logged out" };
+                    println!("{} {}\t{}\t{}", marker, style(name).bold(), url, style(state).dim());
+                }
+            });
+        }
+    } else if matches.subcommand_matches("create").is_some() {
         let user = client.prompt_user_details();
         client.create_user(&user).await?;
-        println!("Created user");
-        client.login(&user).await?;
-        println!("Logged in");
-    } else if let Some(_) = matches.subcommand_matches("login") {
+        client.login(&user, None).await?;
+        output.emit(json!({ "email": user.email }), || println!("Created user and logged in"));
+    } else if let Some(matches) = matches.subcommand_matches("login") {
         let user = client.prompt_user_details();
-        client.login(&user).await?;
-        println!("Logged in");
-    } else if let Some(_) = matches.subcommand_matches("sessions") {
-        for (i, key) in client.sessions().await?.key_prefixes.iter().enumerate() {
-            let (start, end) = key.split_once('.').unwrap();
-            print!("{}\t{}.{}", style(i).bold().dim(), style(start).dim(), end);
-
-            if let Some(my_key) = client.get_key() {
-                let key: &str = &key;
-                if my_key.starts_with(key) {
-                    println!(" [*]");
-                    continue;
+        client.login(&user, matches.value_of("label")).await?;
+        output.emit(json!({ "email": user.email }), || println!("Logged in"));
+    } else if let Some(matches) = matches.subcommand_matches("keygen") {
+        let replace = "Replace the key of this profile? Files encrypted with it can't be decrypted anymore";
+        if !client.has_key() || confirm(replace, matches.is_present("yes")) {
+            let passphrase = loop {
+                let passphrase = rpassword::prompt_password_stderr("passphrase: ").unwrap();
+                if passphrase == rpassword::prompt_password_stderr("repeat passphrase: ").unwrap() {
+                    break passphrase;
                 }
-            }
-            println!("");
+                eprintln!("The passphrases don't match");
+            };
+
+            client.keygen(&passphrase);
+            output.emit(json!({ "profile": client.profile }), || {
+                println!("Uploads from {} are encrypted from now on", style(&client.profile).bold())
+            });
         }
+    } else if matches.subcommand_matches("flush").is_some() {
+        let flushed = client.flush().await?;
+        output.emit(&flushed, || {
+            println!(
+                "Flushed {} operations, {} failed, {} still queued",
+                flushed.done, flushed.failed, flushed.kept
+            )
+        });
+    } else if matches.subcommand_matches("sessions").is_some() {
+        let list = client.sessions().await?;
+        output.emit(&list.sessions, || {
+            for (i, session) in list.sessions.iter().enumerate() {
+                let (start, end) = session.key_prefix.split_once('.').unwrap();
+                print!("{}\t{}.{}", style(i).bold().dim(), style(start).dim(), end);
+
+                let name = session.label.as_ref().or(session.user_agent.as_ref());
+                if let Some(name) = name {
+                    print!("\t{} —", name);
+                }
+                print!(" last used {}", time_ago(session.last_used));
+
+                if session.current {
+                    print!(" [*]");
+                }
+                println!();
+            }
+        });
     } else if let Some(matches) = matches.subcommand_matches("logout") {
-        client.logout(matches.value_of("prefix")).await?;
+        if matches.is_present("all") {
+            client.logout_others().await?;
+            output.emit(json!({}), || println!("Logged out every other session"));
+        } else {
+            client.logout(matches.value_of("prefix")).await?;
+            output.emit(json!({}), || {});
+        }
     } else if let Some(matches) = matches.subcommand_matches("upload") {
         let path = Path::new(matches.value_of("path").unwrap());
         
-        let file_ids = if path.is_file() {
-            vec![client.upload(path, None).await?.id.to_string()]
+        let jobs = matches.value_of("jobs").map_or(4, |jobs| jobs.parse().expect("--jobs takes a number"));
+
+        let album = matches.value_of("add");
+
+        let (file_ids, failed) = if path.is_file() {
+            client.transfer.show(None);
+            let uploaded = retry::with_backoff(|| client.upload(path, None)).await;
+            client.transfer.hide();
+
+            match uploaded {
+                Ok(new) => (vec![new.id.into_owned()], vec![]),
+                Err(error) if error.is_transient() => {
+                    client.queue()?.push(&Operation::Upload {
+                        path: path.to_path_buf(),
+                        album: album.map(String::from),
+                    })?;
+                    eprintln!("Couldn't reach the server, so the file will be uploaded by `flush`");
+                    (vec![], vec![])
+                }
+                Err(error) => return Err(error),
+            }
         } else {
-            client.upload_dir(path).await?
+            client.upload_dir(path, jobs, album).await?
         };
 
+        if let Some(album) = album {
+            if !file_ids.is_empty() {
+                client.add_or_queue(album, &file_ids).await?;
+            }
+        }
 
-        if let Some(album) = matches.value_of("add") {
-            client.add_to_album(&album, &file_ids).await?;
+        if !failed.is_empty() {
+            return Err(Error::PartialUpload { uploaded: file_ids, failed });
         }
-    } else if let Some(matches) = matches.subcommand_matches("list") {
-        let request = ListRequest {
-            prefix: matches.value_of("prefix").map(|e| Cow::from(e)),
-            skip: matches.value_of("skip").map(|e| e.parse().ok()).flatten(),
-            length: matches.value_of("length").map(|e| e.parse().ok()).flatten(),
+
+        output.emit(json!({ "ids": file_ids }), || println!("Uploaded {} files", file_ids.len()));
+    } else if let Some(matches) = matches.subcommand_matches("watch") {
+        let album = matches.value_of("add");
+        let rules = matches.values_of("dirs").unwrap().map(|rule| WatchRule::parse(rule, album)).collect();
+
+        let file_ids = client.watch(rules).await?;
+        output.emit(json!({ "ids": file_ids }), || println!("Uploaded {} files", file_ids.len()));
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        let target = matches.value_of("target").unwrap();
+
+        let report = if Path::new(target).is_dir() {
+            client.verify_dir(Path::new(target)).await?
+        } else {
+            client.verify_album(target).await?
         };
+        if !report.unhashed.is_empty() {
+            eprintln!("The server has no hash of {} files, so they weren't checked", report.unhashed.len());
+        }
 
-        let json = client.file_list(&request).await?;
+        let report = report.into_result()?;
+        output.emit(&report, || println!("Verified {} files", report.checked - report.unhashed.len()));
+    } else if let Some(matches) = matches.subcommand_matches("list") {
+        let skip = matches.value_of("skip").and_then(|e| e.parse().ok());
+        let length = matches.value_of("length").and_then(|e| e.parse().ok());
 
-        let mut file_ids = vec![];
+        let listed = client.list_files(matches.value_of("prefix"), skip, length).await?;
 
-        for (i, (name, id)) in json.files.iter().enumerate() {
-            let i = i + request.skip.unwrap_or(0);
-            print!("{}", style(i).bold().dim());
-            println!("\t{: <40} {}", name, style(id).dim());
+        let file_ids: Vec<String> = listed.iter().map(|(_, id)| id.to_string()).collect();
 
-            file_ids.push(id.to_string());
-        }
+        let files: Vec<_> = listed.iter().map(|(name, id)| json!({ "name": name, "id": id })).collect();
+        output.emit(files, || {
+            for (i, (name, id)) in listed.iter().enumerate() {
+                let i = i + skip.unwrap_or(0);
+                print!("{}", style(i).bold().dim());
+                println!("\t{: <40} {}", name, style(id).dim());
+            }
+        });
 
         if let Some(album) = matches.value_of("add") {
-            client.add_to_album(&album, &file_ids).await?;
+            client.add_to_album(album, &file_ids).await?;
         }
 
         if let Some(album) = matches.value_of("remove") {
-            client.remove_from_album(&album, &file_ids).await?;
+            client.remove_from_album(album, &file_ids).await?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("download") {
+        let id = matches.value_of("id").unwrap();
+        let output_path = matches.value_of("output").map(Path::new);
+
+        let path = if matches.is_present("thumbnails") {
+            let quality = matches.value_of("quality").unwrap_or("medium");
+            client.download_rendition(id, quality, output_path).await?
+        } else {
+            client.download(id, output_path).await?
+        };
+        output.emit(json!({ "id": id, "path": path }), || println!("Saved {} to {:?}", style(id).dim(), path));
+    } else if matches.subcommand_matches("browse").is_some() {
+        client.browse().await?;
+    } else if let Some(matches) = matches.subcommand_matches("url") {
+        let id = matches.value_of("id").unwrap();
+        let kind = if matches.is_present("file") {
+            Some("file")
+        } else if matches.is_present("album") {
+            Some("album")
+        } else {
+            None
+        };
+
+        let (kind, url) = client.frontend_link(id, kind).await?;
+        if matches.is_present("open") {
+            open_browser(&url).await?;
+        }
+        output.emit(json!({ "id": id, "kind": kind, "url": url.as_str() }), || println!("{}", url));
+    } else if let Some(matches) = matches.subcommand_matches("rename") {
+        let id = matches.value_of("id").unwrap();
+        let name = matches.value_of("name").unwrap();
+
+        client.rename_file(id, name).await?;
+        output.emit(json!({ "id": id, "name": name }), || println!("Renamed {} to {}", style(id).dim(), name));
+    } else if let Some(matches) = matches.subcommand_matches("memories") {
+        // The server dates the library in UTC, but today is the day where the user is.
+        let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+        let date = matches.value_of("date").unwrap_or(&today);
+        let memories = client.memories(date).await?;
+
+        let years: Vec<_> = memories
+            .iter()
+            .map(|(year, entries)| {
+                let ids: Vec<_> = entries.iter().map(|entry| &entry.file_id).collect();
+                json!({ "year": year, "ids": ids })
+            })
+            .collect();
+        output.emit(years, || {
+            if memories.is_empty() {
+                println!("Nothing was taken on this day in earlier years");
+            }
+            for (year, entries) in &memories {
+                println!("{} ({} files)", style(year).bold(), entries.len());
+                for entry in entries {
+                    let time = entry.order.as_i64().and_then(|ts| chrono::DateTime::from_timestamp(ts, 0));
+                    let time = time.map(|time| time.format("%H:%M").to_string()).unwrap_or_default();
+                    println!("\t{}\t{}", time, style(&entry.file_id).dim());
+                }
+            }
+        });
+    } else if let Some(matches) = matches.subcommand_matches("import-takeout") {
+        let path = Path::new(matches.value_of("path").unwrap());
+        let time_zone = time_zone::or_local(matches.value_of("timezone"));
+
+        client.import_takeout(path, time_zone).await?;
+        output.emit(json!({ "path": path }), || println!("Imported {:?}", path));
+    } else if let Some(matches) = matches.subcommand_matches("export-takeout") {
+        let path = Path::new(matches.value_of("path").unwrap());
+
+        let count = client.export_takeout(path).await?;
+        output.emit(json!({ "path": path, "files": count }), || println!("Exported {} files to {:?}", count, path));
+    } else if let Some(matches) = matches.subcommand_matches("rm") {
+        let file_ids: Vec<&str> = matches.values_of("ids").unwrap().collect();
+
+        if confirm(&format!("Move {} files to the trash?", file_ids.len()), matches.is_present("yes")) {
+            for file_id in &file_ids {
+                client.delete_file(file_id).await?;
+            }
+            output.emit(json!({ "ids": file_ids }), || println!("Moved files to the trash"));
+        }
+    } else if let Some(matches) = matches.subcommand_matches("trash") {
+        if let Some(matches) = matches.subcommand_matches("restore") {
+            let entries = client.trash_list().await?;
+            let mut restored = vec![];
+
+            for id in matches.values_of("ids").unwrap() {
+                match entries.iter().find(|entry| entry.id == id) {
+                    Some(entry) => {
+                        client.trash_restore(entry.kind, id).await?;
+                        restored.push(entry);
+                    }
+                    None => eprintln!("{} isn't in the trash", id),
+                }
+            }
+
+            output.emit(&restored, || {
+                for entry in &restored {
+                    println!("Restored {}", entry.name);
+                }
+            });
+        } else if let Some(matches) = matches.subcommand_matches("empty") {
+            if confirm("Delete everything in the trash for good?", matches.is_present("yes")) {
+                client.empty_trash().await?;
+                output.emit(json!({}), || println!("Emptied the trash"));
+            }
+        } else {
+            let now = UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+            let entries = client.trash_list().await?;
+
+            output.emit(&entries, || {
+                for entry in &entries {
+                    let kind = match entry.kind {
+                        TrashKind::File => "file",
+                        TrashKind::Album => "album",
+                    };
+                    let days_left = (entry.purge_after - now).max(0) / 86400;
+                    println!(
+                        "{}\t{}\t{}\tdeleted {}, purged in {} days",
+                        style(&entry.id).dim(),
+                        kind,
+                        style(&entry.name).bold(),
+                        time_ago(entry.deleted),
+                        days_left,
+                    );
+                }
+            });
         }
     } else if let Some(matches) = matches.subcommand_matches("album") {
         if let Some(matches) = matches.subcommand_matches("create") {
+            // The conditions that are given make up a single rule, which files have to meet all of.
+            let rule = AlbumRule {
+                year: matches.value_of("year").map(|year| year.parse().expect("--year takes a number")),
+                tag: matches.value_of("tag").map(Cow::from),
+                camera: matches.value_of("camera").map(Cow::from),
+            };
             let settings = AlbumSettings {
                 name: Cow::from(matches.value_of("name").unwrap()),
-                time_zone: matches.value_of("timezone").unwrap_or("EST").parse().unwrap(),
+                time_zone: time_zone::or_local(matches.value_of("timezone")),
+                sort: match matches.value_of("sort") {
+                    Some("upload") => SortMode::UploadDate,
+                    Some("name") => SortMode::Name,
+                    Some("manual") => SortMode::Manual,
+                    _ => SortMode::CaptureDate,
+                },
+                description: Cow::from(matches.value_of("description").unwrap_or("")),
+                rules: if rule.is_empty() { vec![] } else { vec![rule] },
             };
 
             let id = client.create_album(&settings).await?;
-            println!("Created album id={}", id);
+            output.emit(json!({ "id": id }), || println!("Created album id={}", id));
+        } else if let Some(matches) = matches.subcommand_matches("list") {
+            let listing = client.browse_albums(matches.value_of("role"), matches.value_of("sort").unwrap()).await?;
+
+            output.emit(&listing, || {
+                for ListedAlbum { id, info: AlbumInfo { album, role, .. } } in &listing.albums {
+                    print!(
+                        "{}\t{}\t{}\t{} files",
+                        style(id).dim(),
+                        style(&album.description.name).bold(),
+                        styled_role(*role),
+                        album.length,
+                    );
+                    if let Some((start, end)) = album.date_range {
+                        print!("\t{} – {}", album_date(album, start), album_date(album, end));
+                    }
+                    println!();
+                }
+                println!("{} owned, {} shared with you", listing.owned, listing.shared);
+            });
+        } else if let Some(matches) = matches.subcommand_matches("show") {
+            let album_id = matches.value_of("album").unwrap();
+            let info = client.album_metadata(album_id).await?;
+            let top = client.album_top(album_id, info.album.fragment_head).await?;
+
+            let sections: Vec<_> = top
+                .iter()
+                .map(|entry| json!({ "section": entry.section, "part": entry.part, "length": entry.length }))
+                .collect();
+
+            output.emit(json!({ "album": info, "sections": sections }), || {
+                let AlbumInfo { ref album, role, rebuilding, .. } = info;
+
+                println!("{} ({})", style(&album.description.name).bold(), styled_role(role));
+                if rebuilding {
+                    println!("{}", style("Rearranging for new settings, shown as before until done").dim());
+                }
+                if !album.description.description.is_empty() {
+                    println!("{}", album.description.description);
+                }
+                println!(
+                    "{} files in {} sections, updated {}",
+                    album.length,
+                    top.iter().filter(|entry| entry.part == 0).count(),
+                    time_ago(album.last_update),
+                );
+
+                for entry in &top {
+                    let label = section_label(album, entry.section);
+                    if entry.part > 0 {
+                        println!("  {} (part {})\t{} files", label, entry.part + 1, entry.length);
+                    } else {
+                        println!("  {}\t{} files", label, entry.length);
+                    }
+                }
+            });
+        } else if let Some(matches) = matches.subcommand_matches("delete") {
+            let album = matches.value_of("album").unwrap();
+            let name = client.album_metadata(album).await?.album.description.name;
+
+            if confirm(&format!("Move album \"{}\" to the trash?", name), matches.is_present("yes")) {
+                client.delete_album(album).await?;
+                output.emit(json!({ "id": album }), || println!("Moved album to the trash"));
+            }
+        } else if let Some(matches) = matches.subcommand_matches("set-tz") {
+            let album = matches.value_of("album").unwrap();
+            let time_zone = time_zone::or_local(matches.value_of("timezone"));
+
+            client.set_album_time_zone(album, time_zone).await?;
+            output.emit(json!({ "id": album, "time_zone": time_zone.name() }), || {
+                println!("Album {} now counts days in {}", style(album).dim(), time_zone.name())
+            });
+        } else if let Some(matches) = matches.subcommand_matches("share") {
+            let album = matches.value_of("album").unwrap();
+            let email = matches.value_of("email").unwrap();
+            let role = match matches.value_of("role").unwrap() {
+                "editor" => Role::Editor,
+                "contributor" => Role::Contributor,
+                _ => Role::Reader,
+            };
+
+            client.share_album(album, email, role).await?;
+            output.emit(json!({ "email": email, "role": role }), || {
+                println!("Shared album with {} as {}", email, styled_role(role))
+            });
+        } else if let Some(matches) = matches.subcommand_matches("unshare") {
+            let album = matches.value_of("album").unwrap();
+            let email = matches.value_of("email").unwrap();
+
+            client.unshare_album(album, email).await?;
+            output.emit(json!({ "email": email }), || println!("Unshared album with {}", email));
+        } else if let Some(matches) = matches.subcommand_matches("members") {
+            let album = matches.value_of("album").unwrap();
+
+            let members = client.album_members(album).await?;
+
+            output.emit(&members, || {
+                for member in &members {
+                    println!("{}\t{}", styled_role(member.role), member.email);
+                }
+            });
+        } else if let Some(matches) = matches.subcommand_matches("reorder") {
+            let album = matches.value_of("album").unwrap();
+            let file_ids: Vec<String> = matches.values_of("ids").unwrap().map(|e| e.to_string()).collect();
+
+            client.reorder_album(album, &file_ids).await?;
+            output.emit(json!({ "ids": file_ids }), || println!("Reordered album"));
+        } else if let Some(matches) = matches.subcommand_matches("move") {
+            let from = matches.value_of("from").unwrap();
+            let to = matches.value_of("to").unwrap();
+            let file_ids: Vec<String> = matches.values_of("ids").unwrap().map(|e| e.to_string()).collect();
+
+            client.move_files(from, to, &file_ids).await?;
+            output.emit(json!({ "from": from, "to": to, "ids": file_ids }), || {
+                println!("Moved files to {}", to)
+            });
+        } else if let Some(matches) = matches.subcommand_matches("transfer") {
+            let album = matches.value_of("album").unwrap();
+            let email = matches.value_of("email").unwrap();
+
+            client.transfer_album(album, email).await?;
+            output.emit(json!({ "email": email }), || println!("Transferred album to {}", email));
         }
     }
     
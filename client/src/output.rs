@@ -0,0 +1,78 @@
+//! Output
+//!
+//! Commands print for people by default. With `--json`, each command prints a single JSON value to
+//! stdout instead, and failures print an object whose `error` field names the kind of failure.
+//! Anything that isn't the result of the command, like prompts and progress, goes to stderr
+//! either way so that stdout can be parsed.
+
+use crate::error::Error;
+use console::style;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Output { json }
+    }
+
+    /// Print `value` when the output is JSON, or call `human` to print for people otherwise.
+    pub fn emit<T: Serialize, F: FnOnce()>(&self, value: T, human: F) {
+        if self.json {
+            println!("{}", serde_json::to_string(&value).unwrap());
+        } else {
+            human();
+        }
+    }
+
+    /// Report a command that failed. People get the error on stderr.
+    pub fn error(&self, error: &Error) {
+        if self.json {
+            println!("{}", error_json(error));
+        } else {
+            eprintln!("{}", style(error).red());
+            if let Error::PartialUpload { failed, .. } = error {
+                for path in failed {
+                    eprintln!("  {:?}", path);
+                }
+            }
+            if let Error::Unverified { missing, mismatched } = error {
+                for name in missing {
+                    eprintln!("  missing    {}", name);
+                }
+                for name in mismatched {
+                    eprintln!("  mismatched {}", name);
+                }
+            }
+        }
+    }
+}
+
+fn error_json(error: &Error) -> Value {
+    let mut json = json!({
+        "error": error.kind().name(),
+        "message": error.to_string(),
+    });
+
+    let details = match error {
+        Error::Remote { status_code, url, error } => json!({
+            "status": status_code.as_u16(),
+            "url": url.as_str(),
+            "code": error.code,
+            "details": error.details,
+        }),
+        Error::Incomplete { done, total, .. } => json!({ "done": done, "total": total }),
+        Error::PartialUpload { uploaded, failed } => json!({ "ids": uploaded, "failed": failed }),
+        Error::Mismatch { name, file_id } => json!({ "name": name, "id": file_id }),
+        Error::Unverified { missing, mismatched } => json!({ "missing": missing, "mismatched": mismatched }),
+        _ => json!({}),
+    };
+    if let (Value::Object(json), Value::Object(details)) = (&mut json, details) {
+        json.extend(details);
+    }
+
+    json
+}
@@ -0,0 +1,196 @@
+//! Upload Progress
+//!
+//! Upload bodies are streamed through `Transfer::track`, which reports the bytes that were sent to
+//! whichever bars the command is showing and holds them back to the rate given with
+//! `--limit-rate`. A file that is sent on its own gets a bar with its transfer rate, and directory
+//! uploads show the bar of the whole directory above those, with the time that is left.
+//!
+//! The rate limit is shared by every upload of the process, so running several at once doesn't
+//! get around it. Bytes of a request that failed are taken off the bars again, so that retries
+//! aren't counted twice.
+
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::stream::Stream;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io;
+use tokio::time::{self, Instant};
+
+/// Size of the pieces that bodies which are already in memory are sent in.
+const CHUNK_BYTES: usize = 1 << 16;
+
+/// Parse a rate in bytes per second like `curl --limit-rate` does, where a `k`, `m` or `g` suffix
+/// multiplies by a power of 1024.
+pub fn parse_rate(rate: &str) -> Option<u64> {
+    let (number, shift) = match rate.chars().last()?.to_ascii_lowercase() {
+        'k' => (&rate[..rate.len() - 1], 10),
+        'm' => (&rate[..rate.len() - 1], 20),
+        'g' => (&rate[..rate.len() - 1], 30),
+        _ => (rate, 0),
+    };
+
+    let number: u64 = number.parse().ok()?;
+    (number > 0).then(|| number << shift)
+}
+
+/// A bar of `len` bytes that shows how fast they are sent.
+pub fn bar(len: u64, name: Option<&str>) -> ProgressBar {
+    let template = match name {
+        Some(_) => "{msg:20!} {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} ({eta})",
+        None => "{wide_bar} {bytes}/{total_bytes} {bytes_per_sec} ({eta})",
+    };
+
+    let bar = ProgressBar::new(len);
+    bar.set_style(ProgressStyle::default_bar().template(template).unwrap());
+    if let Some(name) = name {
+        bar.set_message(name.to_string());
+    }
+    bar
+}
+
+/// Send `bytes` a piece at a time, so that their progress can be followed.
+pub fn chunks(bytes: Vec<u8>) -> impl Stream<Item = io::Result<Bytes>> {
+    let bytes = Bytes::from(bytes);
+
+    try_stream! {
+        let mut start = 0;
+        while start < bytes.len() {
+            let end = bytes.len().min(start + CHUNK_BYTES);
+            yield bytes.slice(start..end);
+            start = end;
+        }
+    }
+}
+
+/// Keeps the bytes that are sent to `rate` per second on average.
+struct Throttle {
+    rate: u64,
+    /// When the bytes that count against the limit started being sent, and how many there are.
+    sent: Mutex<(Instant, u64)>,
+}
+
+impl Throttle {
+    /// Wait until `bytes` more can be sent.
+    async fn wait(&self, bytes: usize) {
+        let until = {
+            let mut sent = self.sent.lock().unwrap();
+            let now = Instant::now();
+            // Time that nothing was sent in doesn't allow a burst later.
+            if sent.0 + Duration::from_secs_f64(sent.1 as f64 / self.rate as f64) < now {
+                *sent = (now, 0);
+            }
+
+            sent.1 += bytes as u64;
+            sent.0 + Duration::from_secs_f64(sent.1 as f64 / self.rate as f64)
+        };
+
+        time::sleep_until(until).await;
+    }
+}
+
+#[derive(Clone)]
+struct Bars {
+    multi: MultiProgress,
+    total: Option<ProgressBar>,
+}
+
+/// Bars that uploads report to and the limit that they are held to, which the client keeps for
+/// every request.
+#[derive(Default)]
+pub struct Transfer {
+    bars: Mutex<Option<Bars>>,
+    throttle: OnceLock<Arc<Throttle>>,
+}
+
+/// What a request has sent so far, whose bar is cleared when it is dropped.
+pub struct Tracked {
+    file: Option<ProgressBar>,
+    total: Option<ProgressBar>,
+    sent: Arc<AtomicU64>,
+}
+
+impl Tracked {
+    /// Take what was sent back off the bar of the total, because the request failed.
+    pub fn rewind(&self) {
+        if let Some(total) = &self.total {
+            total.dec(self.sent.swap(0, Ordering::Relaxed));
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            file.finish_and_clear();
+        }
+    }
+}
+
+impl Transfer {
+    /// Hold uploads to `rate` bytes per second.
+    pub fn limit_rate(&self, rate: u64) {
+        let throttle = Throttle {
+            rate,
+            sent: Mutex::new((Instant::now(), 0)),
+        };
+        let _ = self.throttle.set(Arc::new(throttle));
+    }
+
+    /// Show bars for the uploads that follow, under a bar of `total` bytes if it is given, which
+    /// is returned so that messages can be printed above it.
+    pub fn show(&self, total: Option<u64>) -> Option<ProgressBar> {
+        let multi = MultiProgress::new();
+        let total = total.map(|total| multi.add(bar(total, None)));
+
+        *self.bars.lock().unwrap() = Some(Bars { multi, total: total.clone() });
+        total
+    }
+
+    /// Stop showing bars, leaving the bar of the total where it is.
+    pub fn hide(&self) {
+        if let Some(Bars { total: Some(total), .. }) = self.bars.lock().unwrap().take() {
+            total.finish();
+        }
+    }
+
+    /// Report the bytes of `stream` as they are sent, and hold them back to the rate limit. The
+    /// stream gets a bar of its own when it is a single file of `len` bytes, which is `name`d.
+    pub fn track<S>(&self, name: Option<&str>, len: u64, stream: S) -> (impl Stream<Item = io::Result<Bytes>>, Tracked)
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        let bars = self.bars.lock().unwrap().clone();
+        let tracked = Tracked {
+            file: bars
+                .as_ref()
+                .zip(name)
+                .map(|(bars, name)| bars.multi.add(bar(len, Some(name)))),
+            total: bars.and_then(|bars| bars.total),
+            sent: Arc::new(AtomicU64::new(0)),
+        };
+
+        let throttle = self.throttle.get().cloned();
+        let bars: Vec<ProgressBar> = tracked.file.iter().chain(tracked.total.iter()).cloned().collect();
+        let sent = tracked.sent.clone();
+
+        let stream = try_stream! {
+            for await chunk in stream {
+                let chunk: Bytes = chunk?;
+                if let Some(throttle) = &throttle {
+                    throttle.wait(chunk.len()).await;
+                }
+
+                for bar in &bars {
+                    bar.inc(chunk.len() as u64);
+                }
+                sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                yield chunk;
+            }
+        };
+
+        (stream, tracked)
+    }
+}
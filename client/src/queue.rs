@@ -0,0 +1,54 @@
+//! Offline Queue
+//!
+//! Uploads and album additions that fail because the server can't be reached are kept in the
+//! client's database instead of being lost, and the `flush` command sends them again later.
+
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Operation {
+    /// Upload a file, and then add it to `album` if there is one.
+    Upload { path: PathBuf, album: Option<String> },
+    Add { album: String, ids: Vec<String> },
+}
+
+pub struct Queue {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+impl Queue {
+    /// Each profile has its own queue, since the operations belong to its server and account.
+    pub fn open(db: &sled::Db, profile: &str) -> Result<Self> {
+        Ok(Queue {
+            db: db.clone(),
+            tree: db.open_tree(format!("queue/{}", profile))?,
+        })
+    }
+
+    pub fn push(&self, operation: &Operation) -> Result<()> {
+        // Ids from the database only grow, so operations are kept in the order that they failed.
+        let id = self.db.generate_id()?;
+        self.tree.insert(id.to_be_bytes(), serde_json::to_vec(operation)?)?;
+        Ok(())
+    }
+
+    /// Every queued operation along with the key that removes it.
+    pub fn entries(&self) -> Result<Vec<(sled::IVec, Operation)>> {
+        self.tree
+            .iter()
+            .map(|entry| {
+                let (key, bytes) = entry?;
+                Ok((key, serde_json::from_slice(&bytes)?))
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        self.tree.remove(key)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! Retrying requests that failed for reasons that may pass, like a dropped connection or an
+//! overloaded server.
+
+use crate::error::{ResponseErrorExt, Result};
+use reqwest::{RequestBuilder, Response};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time;
+
+/// Attempts that are made before an error is returned.
+const ATTEMPTS: u32 = 4;
+/// Wait before the first retry, which doubles with every attempt.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Run `f` until it succeeds, fails with an error that isn't transient, or runs out of attempts.
+pub async fn with_backoff<T, F, Fut>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut delay = BASE_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match f().await {
+            Err(error) if error.is_transient() && attempt < ATTEMPTS => {
+                time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Send a request that can safely be repeated, trying again after transient failures.
+pub async fn send(request: RequestBuilder) -> Result<Response> {
+    with_backoff(|| {
+        let request = request
+            .try_clone()
+            .expect("Only requests with buffered bodies can be retried");
+        async move { request.send().await?.check_status().await }
+    })
+    .await
+}
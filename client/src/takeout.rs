@@ -0,0 +1,306 @@
+//! Google Takeout Import
+//!
+//! A Takeout export of Google Photos has a folder for every album, plus a `Photos from <year>`
+//! folder for every year. Each photo comes with a `.json` sidecar holding its metadata, and
+//! album folders have a `metadata.json` with the album's title and description. A photo shows up
+//! in its year folder as well as in every album that it is part of. File names are unique on the
+//! server, so the copies are matched up by name and only the first one is uploaded.
+//!
+//! Photos that come with an XMP sidecar as well, like those of `export-takeout`, have it sent
+//! along too. The server only reads locations from EXIF data, so `geoData` is not imported. Descriptions
+//! become captions, which belong to an album, so descriptions of photos that aren't in any album
+//! are dropped.
+
+use crate::error::{Error, Result};
+use crate::xmp;
+use crate::Client;
+use chrono_tz::Tz;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use wire::{endpoint, AlbumSettings, Caption, SortMode};
+
+/// Google shortens sidecar names, including the `.json`, to this many characters.
+const MAX_SIDECAR_NAME: usize = 51;
+const ALBUM_METADATA: &str = "metadata.json";
+
+/// Metadata that Google keeps next to a photo or an album.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Sidecar {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub creation_time: Option<TimeStamp>,
+    pub photo_taken_time: Option<TimeStamp>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TimeStamp {
+    timestamp: String,
+}
+
+impl Sidecar {
+    pub fn read(path: &Path) -> Option<Sidecar> {
+        let file = std::fs::File::open(path).ok()?;
+        serde_json::from_reader(file).ok()
+    }
+
+    /// When the photo was taken, or else when it was added to Google Photos.
+    pub fn time_stamp(&self) -> Option<i64> {
+        self.photo_taken_time
+            .as_ref()
+            .or(self.creation_time.as_ref())?
+            .timestamp
+            .parse()
+            .ok()
+    }
+}
+
+/// Find the sidecar of `path` among the `.json` files in its folder.
+pub fn sidecar_path(path: &Path, jsons: &HashSet<PathBuf>) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let dir = path.parent()?;
+
+    let mut candidates = vec![format!("{}.json", name)];
+
+    // Copies with the same name are numbered `IMG_0001(1).jpg`, but their sidecar is named
+    // `IMG_0001.jpg(1).json`.
+    if let Some((stem, extension)) = name.rsplit_once('.') {
+        if let Some((base, number)) = stem.rsplit_once('(') {
+            candidates.push(format!("{}.{}({}.json", base, extension, number));
+        }
+    }
+
+    for candidate in candidates.iter() {
+        let shortened = if candidate.chars().count() > MAX_SIDECAR_NAME {
+            let keep = MAX_SIDECAR_NAME - ".json".len();
+            let start: String = candidate.chars().take(keep).collect();
+            format!("{}.json", start)
+        } else {
+            candidate.clone()
+        };
+
+        let json = dir.join(shortened);
+        if jsons.contains(&json) {
+            return Some(json);
+        }
+    }
+
+    None
+}
+
+struct Folder {
+    path: PathBuf,
+    /// Present when the folder is an album rather than a year.
+    album: Option<Sidecar>,
+    /// Each photo with its JSON sidecar and the path of its XMP sidecar.
+    media: Vec<(PathBuf, Option<Sidecar>, Option<PathBuf>)>,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("json"))
+}
+
+/// Collect every folder below `dir` that has photos in it.
+fn find_folders(dir: &Path, folders: &mut Vec<Folder>) -> Result<()> {
+    let mut files = vec![];
+    let mut jsons = HashSet::new();
+    let mut xmps = HashSet::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            find_folders(&path, folders)?;
+        } else if is_json(&path) {
+            jsons.insert(path);
+        } else if xmp::is_xmp(&path) {
+            xmps.insert(path);
+        } else {
+            files.push(path);
+        }
+    }
+
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    files.sort();
+
+    let media = files
+        .into_iter()
+        .map(|path| {
+            let sidecar = sidecar_path(&path, &jsons).and_then(|json| Sidecar::read(&json));
+            let xmp = xmp::xmp_path(&path, &xmps);
+            (path, sidecar, xmp)
+        })
+        .collect();
+
+    folders.push(Folder {
+        path: dir.to_path_buf(),
+        album: Sidecar::read(&dir.join(ALBUM_METADATA)),
+        media,
+    });
+
+    Ok(())
+}
+
+/// Unpack a `.zip` or `.tgz` archive into a temporary folder.
+fn extract(archive: &Path) -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("takeout-{}", std::process::id()));
+    let file = std::fs::File::open(archive)?;
+    let name = archive.to_string_lossy();
+
+    eprintln!("Extracting {:?}...", archive);
+
+    if name.ends_with(".zip") {
+        zip::ZipArchive::new(file)?.extract(&dir)?;
+    } else if name.ends_with(".tgz") || name.ends_with(".tar.gz") {
+        tar::Archive::new(GzDecoder::new(file)).unpack(&dir)?;
+    } else {
+        return Err(Error::IO(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Takeout archives must be .zip, .tgz, or .tar.gz",
+        )));
+    }
+
+    Ok(dir)
+}
+
+impl Client {
+    pub async fn import_takeout(&self, path: &Path, time_zone: Tz) -> Result<()> {
+        let extracted = if path.is_file() {
+            Some(extract(path)?)
+        } else {
+            None
+        };
+        let root = extracted.as_deref().unwrap_or(path);
+
+        let mut folders = vec![];
+        find_folders(root, &mut folders)?;
+
+        let mut known: HashMap<String, String> = self.list_files(None, None, None).await?.into_iter().collect();
+
+        let mut albums = self.album_names().await?;
+        let limits = self.limits().await?;
+
+        let total = folders.iter().map(|f| f.media.len()).sum::<usize>();
+        let bar = indicatif::ProgressBar::new(total as u64);
+        let mut uploaded = vec![];
+        let mut errors = vec![];
+
+        for folder in folders {
+            let mut file_ids = vec![];
+            let mut captions = vec![];
+
+            for (path, sidecar, xmp) in folder.media.iter() {
+                bar.inc(1);
+
+                let name = path
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .expect("Only support unicode file names");
+
+                let file_id = match known.get(name) {
+                    Some(file_id) => file_id.clone(),
+                    None if self.too_large(path, &limits).await? => continue,
+                    None => match self.upload(path, sidecar.as_ref()).await {
+                        Ok(new) => {
+                            let file_id = new.id.into_owned();
+                            if let Some(xmp) = xmp {
+                                if let Err(error) = self.set_xmp(&file_id, xmp).await {
+                                    bar.println(format!("Couldn't send the sidecar of {:?}: {}", path, error));
+                                }
+                            }
+                            known.insert(name.to_string(), file_id.clone());
+                            uploaded.push(file_id.clone());
+                            file_id
+                        }
+                        Err(_) => {
+                            errors.push(path.clone());
+                            continue;
+                        }
+                    },
+                };
+
+                if let Some(sidecar) = sidecar {
+                    if !sidecar.description.is_empty() {
+                        captions.push((file_id.clone(), sidecar.description.clone()));
+                    }
+                }
+
+                file_ids.push(file_id);
+            }
+
+            let album = match folder.album {
+                Some(album) => album,
+                None => continue,
+            };
+
+            let name = if album.title.is_empty() {
+                folder.path.file_name().unwrap().to_string_lossy().into_owned()
+            } else {
+                album.title
+            };
+
+            let album_id = match albums.get(&name) {
+                Some(album_id) => album_id.clone(),
+                None => {
+                    let settings = AlbumSettings {
+                        name: Cow::from(&name),
+                        time_zone,
+                        sort: SortMode::CaptureDate,
+                        description: Cow::from(album.description),
+                        rules: vec![],
+                    };
+
+                    let album_id = self.create_album(&settings).await?;
+                    albums.insert(name.clone(), album_id.clone());
+                    album_id
+                }
+            };
+
+            bar.println(format!("Adding {} files to {}", file_ids.len(), name));
+            self.add_to_album(&album_id, &file_ids).await?;
+
+            for (file_id, text) in captions {
+                self.set_caption(&album_id, &file_id, &text).await?;
+            }
+        }
+        bar.finish();
+
+        if let Some(dir) = extracted {
+            std::fs::remove_dir_all(dir)?;
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PartialUpload { uploaded, failed: errors })
+        }
+    }
+
+    /// Map from album name to id for every album that the user can see.
+    async fn album_names(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .album_list()
+            .await?
+            .into_iter()
+            .map(|(album_id, info)| (info.album.description.name.into_owned(), album_id))
+            .collect())
+    }
+
+    async fn set_caption(&self, album_id: &str, file_id: &str, text: &str) -> Result<()> {
+        let caption = Caption { text: Some(Cow::from(text)) };
+        let request = self.auth_json::<endpoint::SetCaption>(&[album_id, file_id], &caption).await;
+        self.send_retry(request).await?;
+        Ok(())
+    }
+}
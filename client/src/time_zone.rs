@@ -0,0 +1,62 @@
+//! Time Zones
+//!
+//! Albums are laid out by days in their time zone, which defaults to the one that the system of
+//! the client is set to. Names are the IANA ones that `chrono_tz` knows, and are matched without
+//! regard to case or to spaces in place of underscores. Names that don't match any come with
+//! the closest ones as suggestions.
+
+use chrono_tz::{Tz, TZ_VARIANTS};
+
+/// Suggestions that are given for a name that doesn't match.
+const SUGGESTIONS: usize = 3;
+
+/// The time zone of the system, or UTC if it can't be told.
+pub fn local() -> Tz {
+    iana_time_zone::get_timezone()
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().replace(' ', "_").to_lowercase()
+}
+
+/// Parse the name of a time zone, explaining what was meant if it's misspelled.
+pub fn parse(name: &str) -> Result<Tz, String> {
+    let normalized = normalize(name);
+    if let Some(tz) = TZ_VARIANTS.iter().find(|tz| tz.name().to_lowercase() == normalized) {
+        return Ok(*tz);
+    }
+
+    // Names are compared both in full and by their last part, which is usually a city.
+    let distance = |tz: &Tz| {
+        let full = tz.name().to_lowercase();
+        let city = full.rsplit('/').next().unwrap().to_string();
+        strsim::levenshtein(&normalized, &full).min(strsim::levenshtein(&normalized, &city))
+    };
+    let mut close: Vec<(usize, &str)> = TZ_VARIANTS
+        .iter()
+        .map(|tz| (distance(tz), tz.name()))
+        .filter(|(distance, _)| *distance <= (normalized.len() / 3).max(2))
+        .collect();
+    close.sort();
+
+    let suggestions: Vec<&str> = close.into_iter().take(SUGGESTIONS).map(|(_, name)| name).collect();
+    if suggestions.is_empty() {
+        Err(format!("{} isn't a time zone, use a name like America/New_York", name))
+    } else {
+        Err(format!("{} isn't a time zone, did you mean {}?", name, suggestions.join(", ")))
+    }
+}
+
+/// Check the name of a time zone as it is given on the command line.
+pub fn validate(name: String) -> Result<(), String> {
+    parse(&name).map(|_| ())
+}
+
+/// The time zone that was named on the command line, which was validated there, or the one of the
+/// system if none was.
+pub fn or_local(name: Option<&str>) -> Tz {
+    name.map_or_else(local, |name| parse(name).unwrap())
+}
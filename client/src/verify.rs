@@ -0,0 +1,185 @@
+//! Verifying Uploads
+//!
+//! The server reports the SHA-256 of what it stored for every upload, which the client compares
+//! with what it sent, so that a file that was damaged on the way is reported instead of being
+//! taken as backed up. Encrypted files are sealed with a fresh nonce each time, so what is
+//! compared for them is the ciphertext that was sent.
+//!
+//! `verify` audits after the fact. For a directory, every file in it has to be in the library
+//! under the same name, with the same contents. For an album, every file in it has to be served
+//! with the contents that the server recorded when it was uploaded. Contents are compared by
+//! hash, and files that the server has no hash of, or that were encrypted, are downloaded to be
+//! compared.
+
+use crate::error::{Error, Kind, Result};
+use crate::{xmp, Client};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use wire::endpoint;
+
+/// SHA-256 of the file at `path`, read a piece at a time.
+pub async fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Lowercase hex SHA-256 of `bytes`, like the server reports.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    wire::hash_hex(&Sha256::digest(bytes).into())
+}
+
+/// Whether the hash that the server reported for an upload matches what was sent. Servers that
+/// don't report hashes can't be checked.
+pub fn matches(reported: Option<&str>, sent: &str) -> bool {
+    reported.is_none_or(|reported| reported.eq_ignore_ascii_case(sent))
+}
+
+/// What `verify` found.
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub checked: usize,
+    pub missing: Vec<String>,
+    pub mismatched: Vec<String>,
+    /// Files that had nothing to be compared with, because the server has no hash of them.
+    pub unhashed: Vec<String>,
+}
+
+impl Report {
+    pub fn into_result(self) -> Result<Self> {
+        if self.missing.is_empty() && self.mismatched.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::Unverified {
+                missing: self.missing,
+                mismatched: self.mismatched,
+            })
+        }
+    }
+}
+
+impl Client {
+    /// SHA-256 of the original of a file as it is served, decrypted if `decrypt` is set and the
+    /// file was encrypted.
+    async fn remote_hash(&self, file_id: &str, info: &wire::FileInfo<'_>, decrypt: bool) -> Result<String> {
+        let request = self.auth_request::<endpoint::ServeFile>(&["large", file_id]).await;
+        let mut response = self.send_retry(request).await?;
+
+        if info.encrypted && decrypt {
+            let cipher = self.cipher()?.ok_or(Error::NoKey)?;
+            return Ok(hash_bytes(&cipher.decrypt(&response.bytes().await?)?));
+        }
+
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = response.chunk().await? {
+            hasher.update(&chunk);
+        }
+        Ok(wire::hash_hex(&hasher.finalize().into()))
+    }
+
+    /// Check that every file in `dir` was uploaded under its name and that the library has the
+    /// same contents for it.
+    pub async fn verify_dir(&self, dir: &Path) -> Result<Report> {
+        let mut paths = vec![];
+        let mut iter = fs::read_dir(dir).await?;
+        while let Some(entry) = iter.next_entry().await? {
+            let path = entry.path();
+            // Sidecars are sent along with their files rather than being stored themselves.
+            let sidecar = path.to_str().unwrap().ends_with(".json") || xmp::is_xmp(&path);
+            if entry.file_type().await?.is_file() && !sidecar {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let library: HashMap<String, String> = self.list_files(None, None, None).await?.into_iter().collect();
+        let mut report = Report::default();
+
+        for path in paths {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            report.checked += 1;
+
+            let file_id = match library.get(&name) {
+                Some(file_id) => file_id,
+                None => {
+                    report.missing.push(name);
+                    continue;
+                }
+            };
+
+            let info = self.file_info(file_id).await?;
+            let local = wire::hash_hex(&hash_file(&path).await?);
+            let remote = match &info.hash {
+                Some(hash) if !info.encrypted => hash.to_string(),
+                _ => self.remote_hash(file_id, &info, true).await?,
+            };
+
+            if !local.eq_ignore_ascii_case(&remote) {
+                eprintln!("{:?} doesn't match {}", path, file_id);
+                report.mismatched.push(name);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Check that every file in an album is served with the contents that the server recorded
+    /// when it was uploaded.
+    pub async fn verify_album(&self, album_id: &str) -> Result<Report> {
+        let album = self.album_metadata(album_id).await?;
+        let mut file_ids = vec![];
+        for entry in self.album_top(album_id, album.album.fragment_head).await? {
+            for file in self.album_section(album_id, entry.fragment_id).await? {
+                file_ids.push(file.file_id);
+            }
+        }
+
+        let mut report = Report::default();
+        for file_id in file_ids {
+            report.checked += 1;
+
+            let info = match self.file_info(&file_id).await {
+                Ok(info) => info,
+                Err(error) if error.kind() == Kind::NotFound => {
+                    report.missing.push(file_id);
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let expected = match &info.hash {
+                Some(hash) => hash.to_string(),
+                None => {
+                    report.unhashed.push(file_id);
+                    continue;
+                }
+            };
+
+            // What was stored is compared, which is the ciphertext for encrypted files.
+            match self.remote_hash(&file_id, &info, false).await {
+                Ok(served) if served.eq_ignore_ascii_case(&expected) => {}
+                Ok(_) => {
+                    eprintln!("{} doesn't match what was uploaded", file_id);
+                    report.mismatched.push(file_id);
+                }
+                Err(error) if error.kind() == Kind::NotFound => report.missing.push(file_id),
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(report)
+    }
+}
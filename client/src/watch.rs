@@ -0,0 +1,197 @@
+//! Watching Directories
+//!
+//! `watch` uploads photos and videos as they show up in a directory, so that the client can back
+//! up a camera or phone sync folder by itself. Filesystem notifications name the files that were
+//! created or changed, and since most files are written in several steps, a file is only uploaded
+//! once nothing has happened to it for `SETTLE`.
+//!
+//! The SHA-256 of every file that was uploaded is kept in the `watched/<profile>` tree, so that a
+//! file that is touched, moved, or copied within the watched directories isn't uploaded again. A
+//! file that was changed is stored next to the old one under a new name.
+//!
+//! Each watched directory can have an album that the files under it are added to. Where watched
+//! directories are nested, the album of the innermost one is used. Uploads that can't reach the
+//! server are queued for `flush`, like those of `upload`.
+
+use crate::error::Result;
+use crate::queue::Operation;
+use crate::verify::hash_file;
+use crate::{retry, Client};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio::{fs, signal};
+use wire::ConflictPolicy;
+
+/// How long a file has to be left alone before it is uploaded.
+const SETTLE: Duration = Duration::from_secs(2);
+/// How often files that have settled are looked for.
+const TICK: Duration = Duration::from_millis(500);
+
+/// A directory to watch, and the album that the files under it are added to.
+#[derive(Debug)]
+pub struct WatchRule {
+    pub dir: PathBuf,
+    pub album: Option<String>,
+}
+
+impl WatchRule {
+    /// Parse `dir=album`, or a plain `dir` whose files are added to `album`, if there is one.
+    /// Directories whose names have a `=` in them can be given as they are when they exist.
+    pub fn parse(rule: &str, album: Option<&str>) -> Self {
+        match rule.rsplit_once('=') {
+            Some((dir, rule_album)) if !Path::new(rule).exists() => WatchRule {
+                dir: PathBuf::from(dir),
+                album: Some(rule_album.to_string()),
+            },
+            _ => WatchRule {
+                dir: PathBuf::from(rule),
+                album: album.map(String::from),
+            },
+        }
+    }
+}
+
+/// Whether `path` looks like a photo or a video, leaving out hidden files, which are usually
+/// written by other programs while they work.
+fn is_media(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'));
+
+    !hidden
+        && mime_guess::from_path(path)
+            .first()
+            .is_some_and(|mime| matches!(mime.type_().as_str(), "image" | "video"))
+}
+
+impl Client {
+    /// Upload the media that is created or changed under the directories of `rules` until the
+    /// process is interrupted, returning the ids of the stored files.
+    pub async fn watch(&self, rules: Vec<WatchRule>) -> Result<Vec<String>> {
+        // Log in before anything is watched, so that prompts aren't left waiting for a change.
+        self.get_prompt_key().await;
+        self.cipher()?;
+        let limits = self.limits().await?;
+        let seen = self.db.open_tree(format!("watched/{}", self.profile))?;
+
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+
+        let mut rules = rules
+            .into_iter()
+            .map(|rule| {
+                Ok(WatchRule {
+                    dir: std::fs::canonicalize(&rule.dir)?,
+                    album: rule.album,
+                })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // Innermost directories first, so that the first rule that matches a file is the one used.
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.dir.components().count()));
+
+        for rule in &rules {
+            watcher.watch(&rule.dir, RecursiveMode::Recursive)?;
+            match &rule.album {
+                Some(album) => eprintln!("Watching {:?}, adding files to {}", rule.dir, album),
+                None => eprintln!("Watching {:?}", rule.dir),
+            }
+        }
+
+        let mut changed: HashMap<PathBuf, Instant> = HashMap::new();
+        let mut ticks = time::interval(TICK);
+        let mut uploaded = vec![];
+
+        let interrupted = signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(Ok(event)) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                        for path in event.paths.into_iter().filter(|path| is_media(path)) {
+                            changed.insert(path, Instant::now());
+                        }
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(error)) => eprintln!("Couldn't watch for changes: {}", error),
+                    None => break,
+                },
+                _ = ticks.tick() => {
+                    let settled: Vec<PathBuf> = changed
+                        .iter()
+                        .filter(|(_, time)| time.elapsed() >= SETTLE)
+                        .map(|(path, _)| path.clone())
+                        .collect();
+
+                    for path in settled {
+                        changed.remove(&path);
+
+                        let album = rules
+                            .iter()
+                            .find(|rule| path.starts_with(&rule.dir))
+                            .and_then(|rule| rule.album.as_deref());
+                        match self.upload_watched(&path, album, &seen, &limits).await {
+                            Ok(Some(file_id)) => uploaded.push(file_id),
+                            Ok(None) => {}
+                            Err(error) => eprintln!("Couldn't upload {:?}: {}", path, error),
+                        }
+                    }
+                }
+                _ = &mut interrupted => break,
+            }
+        }
+
+        Ok(uploaded)
+    }
+
+    /// Upload the file at `path` and add it to `album`, unless a file with the same contents was
+    /// uploaded from a watched directory before. Returns the id of the file if it was stored.
+    async fn upload_watched(
+        &self,
+        path: &Path,
+        album: Option<&str>,
+        seen: &sled::Tree,
+        limits: &wire::Limits,
+    ) -> Result<Option<String>> {
+        // The file may have been moved away or deleted since it changed.
+        if !fs::metadata(path).await.is_ok_and(|metadata| metadata.is_file()) {
+            return Ok(None);
+        }
+        if self.too_large(path, limits).await? {
+            return Ok(None);
+        }
+
+        let hash = hash_file(path).await?;
+        if seen.contains_key(hash)? {
+            return Ok(None);
+        }
+
+        let file_id = match retry::with_backoff(|| self.upload_with(path, None, ConflictPolicy::Rename)).await {
+            Ok(new) => new.id.into_owned(),
+            Err(error) if error.is_transient() => {
+                self.queue()?.push(&Operation::Upload {
+                    path: path.to_path_buf(),
+                    album: album.map(String::from),
+                })?;
+                eprintln!("Couldn't reach the server, so {:?} will be uploaded by `flush`", path);
+                return Ok(None);
+            }
+            Err(error) => return Err(error),
+        };
+        seen.insert(hash, file_id.as_bytes())?;
+
+        if let Some(album) = album {
+            self.add_or_queue(album, std::slice::from_ref(&file_id)).await?;
+        }
+        eprintln!("Uploaded {:?}", path);
+
+        Ok(Some(file_id))
+    }
+}
@@ -0,0 +1,106 @@
+//! XMP Sidecars
+//!
+//! Photo managers like Lightroom and darktable keep the rating, title and keywords of a photo in
+//! an XMP file next to it, named after the whole file like `IMG_0001.jpg.xmp`, or after its stem
+//! like `IMG_0001.xmp`. Uploads send the sidecar of each file once the file is stored, and the
+//! server reads it into the labels of the file.
+//!
+//! `export-takeout` goes the other way, writing every original into a `.tgz` archive along with a
+//! sidecar of its labels, in a folder that `import-takeout` and `upload` both read back.
+
+use crate::error::Result;
+use crate::{decode, Client};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use wire::endpoint;
+use wire::FileLabels;
+
+/// Folder of the archive that the files are exported into.
+const EXPORT_FOLDER: &str = "Photos";
+
+pub fn is_xmp(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("xmp"))
+}
+
+/// Find the sidecar of `path` among the files in its folder, preferring the one that is named after
+/// the whole file, since the other may be shared with a file that has a different extension.
+pub fn xmp_path(path: &Path, paths: &HashSet<PathBuf>) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let dir = path.parent()?;
+
+    let mut candidates = vec![format!("{}.xmp", name), format!("{}.XMP", name)];
+    if let Some((stem, _)) = name.rsplit_once('.') {
+        candidates.push(format!("{}.xmp", stem));
+        candidates.push(format!("{}.XMP", stem));
+    }
+
+    candidates
+        .into_iter()
+        .map(|candidate| dir.join(candidate))
+        .find(|candidate| paths.contains(candidate))
+}
+
+fn tar_header(size: u64, modified: i64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(modified.max(0) as u64);
+    header
+}
+
+impl Client {
+    /// Send the sidecar at `path` for the stored file `file_id`, returning what the server read.
+    pub async fn set_xmp(&self, file_id: &str, path: &Path) -> Result<FileLabels<'static>> {
+        let xmp = tokio::fs::read(path).await?;
+        let request = self.auth_request::<endpoint::SetXmp>(&[file_id]).await.body(xmp);
+        let response = self.send_retry(request).await?;
+        decode::<endpoint::SetXmp>(response).await
+    }
+
+    /// Write the original of every file into the `.tgz` archive at `archive`, each with a sidecar
+    /// of its labels if it has any. Files keep the time that they were last modified, which is
+    /// what uploads go by when a file has no JSON sidecar. Returns the number of files.
+    pub async fn export_takeout(&self, archive: &Path) -> Result<usize> {
+        let files = self.list_files(None, None, None).await?;
+        let download_path = std::env::temp_dir().join(format!("export-{}", std::process::id()));
+
+        let mut builder = tar::Builder::new(GzEncoder::new(std::fs::File::create(archive)?, Compression::default()));
+        let bar = indicatif::ProgressBar::new(files.len() as u64);
+
+        for (name, file_id) in files.iter() {
+            let info = self.file_info(file_id).await?;
+            self.save_original(file_id, &info, &download_path).await?;
+
+            let modified = info.metadata.last_modified;
+            let path = Path::new(EXPORT_FOLDER).join(name);
+            let original = std::fs::File::open(&download_path)?;
+            let mut header = tar_header(original.metadata()?.len(), modified);
+            builder.append_data(&mut header, &path, original)?;
+
+            if info.labels != FileLabels::default() {
+                let request = self.auth_request::<endpoint::ServeFile>(&["xmp", file_id]).await;
+                let xmp = self.send_retry(request).await?.bytes().await?;
+
+                let mut header = tar_header(xmp.len() as u64, modified);
+                let xmp_path = Path::new(EXPORT_FOLDER).join(format!("{}.xmp", name));
+                builder.append_data(&mut header, xmp_path, xmp.as_ref())?;
+            }
+
+            bar.inc(1);
+        }
+
+        builder.into_inner()?.finish()?;
+        bar.finish();
+
+        if download_path.exists() {
+            std::fs::remove_file(download_path)?;
+        }
+
+        Ok(files.len())
+    }
+}
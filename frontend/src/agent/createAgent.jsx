@@ -6,6 +6,8 @@ import { createSignal } from "solid-js";
  */
 const API_ROOT = "/api";
 const BASE_URL = window.location.protocol + "//" + window.location.host;
+// Keep in step with `ApiVersion::CURRENT` in the wire crate.
+const API_VERSION = "1";
 
 export default function createAgent() {
   const navigate = useNavigate();
@@ -19,7 +21,7 @@ export default function createAgent() {
   }
 
   async function send(method, route, data=null, resKey=null, query={}) {
-    const headers = {};
+    const headers = { "api-version": API_VERSION };
     const opts = { method, headers };
 
     if (data) {
@@ -29,7 +31,7 @@ export default function createAgent() {
 
     const url = new URL(API_ROOT + route, BASE_URL);
     if (key()) {
-      query.key = key();
+      headers["Authorization"] = `Bearer ${key()}`;
     }
 
     for (const [k, v] of Object.entries(query)) {
@@ -63,8 +65,9 @@ export default function createAgent() {
       await send("DELETE", "/user");
       logout();
     },
-    async login(email, password) {
-      const key = await send("POST", "/user/auth", { email, password }, "key");
+    async login(email, password, label=null) {
+      const query = label ? { label } : {};
+      const key = await send("POST", "/user/auth", { email, password }, "key", query);
       setKey(key);
       localStorage.setItem("api_key", key);
     },
@@ -83,11 +86,12 @@ export default function createAgent() {
         logout();
       }
     },
+    logout_others: () => send("DELETE", "/user/auth/others"),
     async change_password(old_password, new_password) {
       await send("PUT", "/user/auth", { old_password, new_password });
       logout();
     },
-    sessions: () => send("GET", "/user/auth", null, "key_prefixes"),
+    sessions: () => send("GET", "/user/auth", null, "sessions"),
   };
 
   const Album = {
@@ -108,6 +112,7 @@ export default function createAgent() {
     async list(prefix=undefined, skip=undefined, length=undefined) {
       return await send("POST", "/file/list", { prefix, skip, length }, "files");
     },
+    // Images can't send headers, so their urls carry the key instead.
     resolveUrl(file_id, quality, album_id=null) {
       let route = API_ROOT + `/file/${quality}/${file_id}`;
 
@@ -136,15 +141,13 @@ export default function createAgent() {
         .replace('/', '_')
         .replace(/=+$/, '');
 
-      let route = API_ROOT + "/file";
+      const headers = { "upload-metadata": enc_metadata, "api-version": API_VERSION };
       if (key()) {
-        route += `?key=${key()}`;
+        headers["Authorization"] = `Bearer ${key()}`;
       }
 
-      const response = await fetch(route, {
-        headers: {
-          "upload-metadata": enc_metadata
-        },
+      const response = await fetch(API_ROOT + "/file", {
+        headers,
         method: "POST",
         body: file,
       });
@@ -39,6 +39,8 @@ export default function Album(props) {
         <Show when={metadata().role != "Reader"}>
           <h3>Upload Files</h3>
           <Upload album={params.id} callback={refetch} />
+        </Show>
+        <Show when={metadata().role == "Owner" || metadata().role == "Editor"}>
           <h3>Settings</h3>
           {JSON.stringify(metadata())}
           <form on:submit={update}>
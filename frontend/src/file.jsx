@@ -0,0 +1,14 @@
+import { useParams, Link } from "solid-app-router";
+import { useAgent } from "./agent";
+
+export default function FileView(props) {
+  const params = useParams();
+  const agent = useAgent();
+
+  return (
+    <>
+      <Link href="/files">Back</Link>
+      <img src={agent.File.resolveUrl(params.id, "large")} />
+    </>
+  );
+}
@@ -7,6 +7,7 @@ import Login from "./login.jsx";
 import Create from "./create.jsx";
 import Album from "./album.jsx";
 import Files from "./files.jsx";
+import FileView from "./file.jsx";
 import User from "./user.jsx";
 
 render(() => (
@@ -18,6 +19,7 @@ render(() => (
         <Route path="/create" element={<Create />} />
         <Route path="/album/:id" element={<Album />} />
         <Route path="/files" element={<Files />} />
+        <Route path="/file/:id" element={<FileView />} />
         <Route path="/user" element={<User />} />
       </Routes>
     </Provider>
@@ -17,7 +17,7 @@ export default function Share(props) {
     await agent.Album.share(
       props.album,
       el["email"].value,
-      el["edit"].checked ? "Editor" : "Reader"
+      el["role"].value
     );
     refetch();
   };
@@ -31,8 +31,11 @@ export default function Share(props) {
           placeholder="Email"
           value={searchString()}
           onInput={e => setSearchString(e.target.value)}/>
-        <label for="edit">Can edit:</label>
-        <input type="checkbox" name="edit"/>
+        <select name="role">
+          <option value="Reader">Can view</option>
+          <option value="Contributor">Can add photos</option>
+          <option value="Editor">Can edit</option>
+        </select>
         <button type="submit">Share</button>
       </form>
       <For each={search()}>
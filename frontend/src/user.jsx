@@ -34,10 +34,15 @@ export default function User(props) {
       </form>
       <h3>Sessions</h3>
       <button onClick={() => agent.User.logout()}>Logout</button>
+      <button onClick={() => agent.User.logout_others().then(() => refetch())}>
+        Logout other sessions
+      </button>
       <For each={sessions()}>
-        {(key, i) => (<div>
-          {key}
-          <button onClick={() => agent.User.logout(key).then(() => refetch())}>
+        {(session, i) => (<div>
+          {session.key_prefix} {session.label || session.user_agent}
+          {" — last used "}{new Date(session.last_used * 1000).toLocaleString()}
+          {session.current ? " (this session)" : ""}
+          <button onClick={() => agent.User.logout(session.key_prefix).then(() => refetch())}>
             Logout
           </button>
         </div>)}
@@ -538,6 +538,10 @@ export class ViewerCore {
 
         let date = new Date(this.data[i][0] * 1000)
             .toLocaleDateString();
+        // Long sections are split into parts, which end with their ordinal.
+        if (this.data[i][3]) {
+            date += " (continued)";
+        }
 
         let header = document.createElement('div');
         header.className = "header";
@@ -0,0 +1,139 @@
+//! Administration
+//!
+//! Routes under `/admin` are only open to logged in users whose verified email is listed in
+//! `PHOTOS_ADMIN_EMAILS`, and to those who registered with an invitation for an administrator.
+//! With `PHOTOS_ADMIN_NETWORKS` set, they also have to come from one of those networks.
+
+use crate::{
+    common::{require_key, respond_ok, test_logged_in, AppState, User},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+    bandwidth, forwarded, fsck, invite, jobs, regenerate, verify,
+};
+use hyper::http::request::Parts;
+use hyper::{Body, Request, Response};
+use routerify::{ext::RequestExt, Router};
+use tokio::task::block_in_place;
+use wire::endpoint;
+
+/// Fail unless the request comes from an administrator.
+pub fn require_admin(parts: &Parts) -> ApiResult<()> {
+    let key = require_key(parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let AppState {
+        ref sessions,
+        ref users,
+        ref admins,
+        ref verified,
+        ref config,
+        ..
+    } = parts.data().unwrap();
+
+    if !config.admin_networks.is_empty() && !forwarded::contains(&config.admin_networks, forwarded::client_ip(parts)) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    block_in_place(|| {
+        test_logged_in(sessions, key)?;
+
+        if admins.contains_key(user_id)? {
+            return Ok(());
+        }
+
+        // Anyone can register with an address, so it only counts once its owner has verified it.
+        if !verified.contains_key(user_id)? {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let user_bytes = users.get(user_id)?.ok_or(ApiError::Unauthorized)?;
+        let user: User = bincode::deserialize(&user_bytes).unwrap();
+
+        if !config.admin_emails.contains(&user.email.to_lowercase()) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        Ok(())
+    })
+}
+
+async fn regenerate_status(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(state.regenerator.status())
+}
+
+/// Start regenerating renditions, unless that is already underway.
+async fn regenerate_start(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(regenerate::start(state))
+}
+
+/// What the fragment collector has removed so far.
+async fn gc_status(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(state.collector.status())
+}
+
+/// Background jobs that are waiting, running, or have given up.
+async fn jobs_status(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(block_in_place(|| jobs::list(state))?)
+}
+
+pub const SCOPE: &str = "/admin";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::ListJobs, jobs_status)
+        .endpoint(SCOPE, endpoint::RegenerationStatus, regenerate_status)
+        .endpoint(SCOPE, endpoint::StartRegeneration, regenerate_start)
+        .endpoint(SCOPE, endpoint::CollectionStatus, gc_status)
+        .endpoint(SCOPE, endpoint::GetBandwidth, bandwidth::usage)
+        .endpoint(SCOPE, endpoint::Fsck, fsck::fsck)
+        .endpoint(SCOPE, endpoint::VerificationReport, verify::status)
+        .endpoint(SCOPE, endpoint::StartVerification, verify::queue)
+        .endpoint(SCOPE, endpoint::ListInvites, invite::list)
+        .endpoint(SCOPE, endpoint::CreateInvite, invite::create)
+        .endpoint(SCOPE, endpoint::DeleteInvite, invite::delete)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{TestServer, ADMIN_EMAIL, PASSWORD};
+    use crate::config::Registration;
+    use hyper::{Body, Method, StatusCode};
+    use wire::UserDetails;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unverified_admin() {
+        let server = TestServer::start().await;
+
+        // Anyone could register with the address before its owner does.
+        let key = server.signup(ADMIN_EMAIL).await;
+        let (status, _) = server
+            .request(Method::GET, &format!("/metrics?key={}", key), &[], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let closed = TestServer::start_with(|config| config.registration = Registration::Closed).await;
+        let details = UserDetails {
+            email: ADMIN_EMAIL.into(),
+            password: PASSWORD.into(),
+        };
+        assert_eq!(closed.send(Method::POST, "/user", &details).await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,164 @@
+//! Album Activity Feed
+//!
+//! Changes to an album are appended to the `activity` tree under `album_id.<time stamp><id>`, so
+//! that members can catch up on what changed since they last looked. Entries older than the
+//! configured retention are pruned when the feed is read and on startup. Readers only see member
+//! changes about themselves and the owner, as in the member list.
+
+use crate::{
+    common::{require_key, respond_ok, test_logged_in, AppState, User},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+};
+use chrono::offset::Utc;
+use hyper::{Body, Request, Response};
+use routerify::{ext::RequestExt, Router};
+use routerify_query::RequestQueryExt;
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use tokio::task::block_in_place;
+use wire::{endpoint, Activity, ActivityEvent, Role};
+
+fn get_key(album_id: &str, time_stamp: i64) -> Vec<u8> {
+    [album_id.as_bytes(), b".", &time_stamp.to_be_bytes()].concat()
+}
+
+/// Append an event to the album's feed as part of the transaction that made the change.
+pub fn record(
+    activity: &TransactionalTree,
+    album_id: &str,
+    user_id: &str,
+    event: ActivityEvent,
+) -> ConflictableTransactionResult<(), ApiError> {
+    let time_stamp = Utc::now().timestamp();
+    let id = activity.generate_id()?;
+
+    let key = [get_key(album_id, time_stamp), id.to_be_bytes().to_vec()].concat();
+    let entry = Activity {
+        time_stamp,
+        user_id: user_id.to_owned(),
+        event,
+    };
+
+    activity.insert(key, bincode::serialize(&entry).unwrap())?;
+
+    Ok(())
+}
+
+/// Remove entries of an album that are older than the retention period.
+pub fn prune(state: &AppState, album_id: &str) -> ApiResult<()> {
+    let days = state.config.activity_retention_days;
+    if days == 0 {
+        return Ok(());
+    }
+
+    let cutoff = Utc::now().timestamp() - days * 24 * 60 * 60;
+    let start = [album_id, "."].concat();
+
+    for entry in state.activity.range(start.as_bytes()..get_key(album_id, cutoff).as_slice()) {
+        let (key, _) = entry?;
+        state.activity.remove(key)?;
+    }
+
+    Ok(())
+}
+
+/// Email of the member that the event is about, for events that name one.
+fn member_email(event: &ActivityEvent) -> Option<&str> {
+    match event {
+        ActivityEvent::MemberJoined { email, .. }
+        | ActivityEvent::MemberLeft { email }
+        | ActivityEvent::OwnerChanged { email } => Some(email),
+        _ => None,
+    }
+}
+
+/// Emails of the caller and the owner of the album, which are all that a Reader may see.
+fn reader_emails(state: &AppState, user_id: &str, album_id: &str) -> ApiResult<Vec<String>> {
+    let mut member_ids = vec![user_id.to_string()];
+    for entry in state.album_to_user.scan_prefix([album_id, "."].concat()) {
+        let (key, _) = entry?;
+        let (_, member_id) = std::str::from_utf8(&key).unwrap().split_once('.').unwrap();
+        if let Some(role_bytes) = state.user_to_album.get([member_id, ".", album_id].concat())? {
+            let role: Role = bincode::deserialize(&role_bytes).unwrap();
+            if role.is_owner() {
+                member_ids.push(member_id.to_string());
+            }
+        }
+    }
+
+    let mut emails = vec![];
+    for member_id in member_ids {
+        if let Some(user_bytes) = state.users.get(&member_id)? {
+            let user: User = bincode::deserialize(&user_bytes).unwrap();
+            emails.push(user.email.to_string());
+        }
+    }
+
+    Ok(emails)
+}
+
+async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let since = req.query("since")
+        .map(|s| s.parse::<i64>().ok())
+        .unwrap_or(Some(0))
+        .ok_or(ApiError::BadRequest)?;
+
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref activity,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        let role_bytes = user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+        let role: Role = bincode::deserialize(&role_bytes).unwrap();
+
+        // Like the member list, Readers only learn about the owner and themselves.
+        let visible = if role.can_see_members() {
+            None
+        } else {
+            Some(reader_emails(state, user_id, album_id)?)
+        };
+
+        prune(state, album_id)?;
+
+        // '/' sorts right after '.', so this range covers exactly the album's entries.
+        let start = get_key(album_id, since.max(0));
+        let end = [album_id, "/"].concat();
+
+        let mut entries = vec![];
+        for entry in activity.range(start.as_slice()..end.as_bytes()) {
+            let (_, entry_bytes) = entry?;
+            let entry: Activity = bincode::deserialize(&entry_bytes).unwrap();
+            if let (Some(visible), Some(email)) = (&visible, member_email(&entry.event)) {
+                if !visible.iter().any(|visible| visible == email) {
+                    continue;
+                }
+            }
+            entries.push(entry);
+        }
+
+        respond_ok(entries)
+    })
+}
+
+pub const SCOPE: &str = "/album/:albumId/activity";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::ListActivity, list)
+        .build()
+        .unwrap()
+}
@@ -0,0 +1,219 @@
+//! Bulk Album Changes
+//!
+//! Adding thousands of files to an album in a single transaction conflicts with every other
+//! change to the album, and each conflict restarts the whole transaction. Instead, the files are
+//! split into batches of `BATCH_SIZE` that are committed one at a time. The batches are applied
+//! by a dedicated worker thread so that request handlers don't block the runtime for seconds, and
+//! progress is streamed back to the client as newline delimited `BulkProgress` objects.
+
+use super::{activity, engine::Engine, share::test_user_can_contribute};
+use crate::{
+    common::{AppState, File},
+    error::{ApiError, ApiResult},
+    events,
+};
+use bytes::Bytes;
+use sled::Transactional;
+use std::convert::Infallible;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use tokio::sync::{broadcast, mpsc::UnboundedSender};
+use futures::stream::Stream;
+use wire::{ActivityEvent, Album, AlbumEvent, BulkProgress};
+
+/// Files that are committed together.
+const BATCH_SIZE: usize = 256;
+/// Jobs that can wait for the worker before new ones are turned away.
+const QUEUE_LENGTH: usize = 64;
+/// Seconds that a client should wait when the queue is full.
+const RETRY_AFTER: u64 = 5;
+
+/// The trees that a job writes to, along with the event channel.
+pub struct Trees {
+    pub albums: sled::Tree,
+    pub files: sled::Tree,
+    pub inclusions: sled::Tree,
+    pub fragments: sled::Tree,
+    pub user_to_album: sled::Tree,
+    pub activity: sled::Tree,
+    pub events: broadcast::Sender<AlbumEvent>,
+}
+
+impl Trees {
+    pub fn new(state: &AppState) -> Self {
+        Trees {
+            albums: state.albums.clone(),
+            files: state.files.clone(),
+            inclusions: state.inclusions.clone(),
+            fragments: state.fragments.clone(),
+            user_to_album: state.user_to_album.clone(),
+            activity: state.activity.clone(),
+            events: state.events.clone(),
+        }
+    }
+}
+
+pub struct Job {
+    pub trees: Trees,
+    pub album_id: String,
+    pub user_id: String,
+    pub add: bool,
+    pub ids: Vec<String>,
+    pub progress: UnboundedSender<BulkProgress>,
+}
+
+impl Job {
+    fn run(self) {
+        let total = self.ids.len();
+        let mut done = 0;
+
+        for batch in self.ids.chunks(BATCH_SIZE) {
+            let update = match self.apply(batch) {
+                Ok(fragment_head) => {
+                    done += batch.len();
+                    BulkProgress::Committed { done, total, fragment_head }
+                }
+                Err(error) => BulkProgress::Failed { done, total, error: error.to_string() },
+            };
+
+            let failed = matches!(update, BulkProgress::Failed { .. });
+
+            // The client may have gone away, but the change is finished regardless.
+            let _ = self.progress.send(update);
+
+            if failed {
+                return;
+            }
+        }
+    }
+
+    /// Commit a single batch, returning the new fragment head.
+    fn apply(&self, batch: &[String]) -> ApiResult<u64> {
+        let Trees {
+            ref albums,
+            ref files,
+            ref inclusions,
+            ref fragments,
+            ref user_to_album,
+            ref activity,
+            ref events,
+        } = self.trees;
+
+        let album_id = self.album_id.as_str();
+        let user_id = self.user_id.as_str();
+        let add = self.add;
+
+        let fragment_head = (albums, inclusions, fragments, files, user_to_album, activity).transaction(
+            |(albums, inclusions, fragments, files, user_to_album, activity)| {
+                // Access could have been revoked since the last batch.
+                let role = test_user_can_contribute(user_to_album, user_id, album_id)?;
+
+                let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                let mut changed = vec![];
+
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
+                for file_id in batch {
+                    if add {
+                        let file_bytes = files.get(file_id)?.ok_or(ApiError::Unauthorized)?;
+                        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                        // Locked files stay out of albums.
+                        if file.owner_id != user_id || file.locked {
+                            return Err(ApiError::Unauthorized.into());
+                        }
+
+                        let inclusion = [file_id, ".", album_id].concat();
+                        if inclusions.insert(inclusion.as_bytes(), b"")?.is_none() {
+                            changed.push(file_id.to_string());
+                        }
+
+                        e.add(file_id, &file)?;
+                    } else if let Some(file_bytes) = files.get(file_id)? {
+                        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                        if !role.can_write() && file.owner_id != user_id {
+                            return Err(ApiError::Unauthorized.into());
+                        }
+
+                        let inclusion = [file_id, ".", album_id].concat();
+                        if inclusions.remove(inclusion.as_bytes())?.is_some() {
+                            changed.push(file_id.to_string());
+                        }
+
+                        e.remove(file_id, &file)?;
+                    }
+                }
+
+                e.commit()?;
+
+                let album_bytes = bincode::serialize(&album).unwrap();
+                albums.insert(album_id.as_bytes(), album_bytes)?;
+
+                if !changed.is_empty() {
+                    let event = if add {
+                        ActivityEvent::FilesAdded(changed)
+                    } else {
+                        ActivityEvent::FilesRemoved(changed)
+                    };
+                    activity::record(activity, album_id, user_id, event)?;
+                }
+
+                Ok(album.fragment_head)
+            },
+        )?;
+
+        events::publish(
+            events,
+            AlbumEvent::Updated {
+                album_id: album_id.to_string(),
+                fragment_head,
+            },
+        );
+
+        Ok(fragment_head)
+    }
+}
+
+/// Handle to the thread that applies bulk changes in the order that they were submitted.
+#[derive(Clone)]
+pub struct Worker {
+    queue: SyncSender<Job>,
+}
+
+impl Worker {
+    pub fn spawn() -> Self {
+        let (queue, jobs) = mpsc::sync_channel::<Job>(QUEUE_LENGTH);
+
+        std::thread::Builder::new()
+            .name("album-bulk".to_string())
+            .spawn(move || {
+                for job in jobs {
+                    job.run();
+                }
+            })
+            .expect("Couldn't start the bulk album worker");
+
+        Worker { queue }
+    }
+
+    pub fn submit(&self, job: Job) -> ApiResult<()> {
+        match self.queue.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(ApiError::TooManyRequests(RETRY_AFTER)),
+            Err(TrySendError::Disconnected(_)) => panic!("The bulk album worker stopped"),
+        }
+    }
+}
+
+/// Turn the progress updates of a job into a response body.
+pub fn progress_stream(
+    receiver: tokio::sync::mpsc::UnboundedReceiver<BulkProgress>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        let update = receiver.recv().await?;
+        let mut line = serde_json::to_vec(&update).unwrap();
+        line.push(b'\n');
+        Some((Ok(Bytes::from(line)), receiver))
+    })
+}
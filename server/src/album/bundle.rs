@@ -0,0 +1,412 @@
+//! Album Bundles
+//!
+//! An album is exported as a `multipart/form-data` body, so that it can be saved as a file and
+//! sent to `POST /album/import` on another server as it is. The first part is a `manifest` with
+//! the album's settings and the name, capture time and caption of every file, followed by a
+//! `file` part with each original in the order that the album shows them. Originals are streamed
+//! from storage, and count towards what the exporting user is served in a day. Files that the
+//! user is served stripped of their metadata are bundled the same way, and left out when they
+//! can't be stripped.
+//!
+//! Imports create an album owned by the user and store each file the way that an upload to the
+//! album would, one at a time, so that manually ordered albums come out in the same order and
+//! date sorted ones by the same capture times. Captions are set once every file is stored. The
+//! rules of an album aren't carried over, since they would match the files of whoever imports it.
+
+use super::{engine::Engine, insert};
+use crate::{
+    bandwidth, events, strip,
+    common::{new_id, require_key, respond_ok, test_logged_in, AppState, File},
+    error::{ApiError, ApiResult},
+    file::{self, UploadOptions},
+    storage::{self, ByteStream},
+};
+use async_stream::try_stream;
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use routerify::ext::RequestExt;
+use sled::Transactional;
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+use tokio::{fs, task::block_in_place, time};
+use wire::{
+    Album, AlbumManifest, BundledFile, ConflictPolicy, ImportedAlbum, IntoOwned, UploadResult, ALBUM_BUNDLE_VERSION,
+};
+
+/// Headers of a part called `name`, along with the boundary in front of it.
+fn part_head(boundary: &str, name: &str, mime: &str) -> Vec<u8> {
+    format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+        boundary, name, mime
+    )
+    .into_bytes()
+}
+
+/// Stream a bundle of the album to any of its members.
+pub async fn export(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+    let album_id = parts.param("albumId").unwrap();
+
+    let state: &AppState = parts.data().unwrap();
+    let (manifest, originals) = block_in_place(|| -> ApiResult<_> {
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref fragments,
+            ref files,
+            ref user_to_album,
+            ..
+        } = state;
+
+        test_logged_in(sessions, key)?;
+
+        user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+
+        let (mut settings, captions) = (albums, fragments).transaction(|(albums, fragments)| {
+            let album_bytes = albums.get(album_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            let captions = Engine::new(album_id, &mut album, fragments)?.list_captions()?;
+            Ok((album.description.into_owned(), captions))
+        })?;
+        settings.rules.clear();
+
+        let (mut bundled, mut originals) = (vec![], vec![]);
+        for (file_id, caption) in captions {
+            let file_bytes = match files.get(&file_id)? {
+                Some(file_bytes) => file_bytes,
+                None => continue,
+            };
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+            let stripped = strip::applies(state, user_id, &file_id, &file)?;
+            if stripped && !strip::strippable(file.detected_mime) {
+                continue;
+            }
+
+            let (mime, name) = (file.detected_mime.to_string(), file.metadata.name.to_string());
+            originals.push((file_id, mime, name, stripped));
+            bundled.push(BundledFile {
+                metadata: file.metadata.into_owned(),
+                caption: caption.map(Cow::from),
+                encrypted: file.encrypted,
+            });
+        }
+
+        let manifest = AlbumManifest {
+            version: ALBUM_BUNDLE_VERSION,
+            settings,
+            files: bundled,
+        };
+        Ok((manifest, originals))
+    })?;
+    bandwidth::check(state, key, user_id)?;
+
+    let boundary = new_id(16);
+    let head = [
+        part_head(&boundary, "manifest", "application/json"),
+        serde_json::to_vec(&manifest)?,
+        b"\r\n".to_vec(),
+    ]
+    .concat();
+    let content_type = format!("multipart/form-data; boundary={}", boundary);
+
+    let stripping = state.clone();
+    let stream: ByteStream = try_stream! {
+        yield Bytes::from(head);
+
+        for (file_id, mime, name, stripped) in originals {
+            let original_key = if stripped {
+                strip::original(&stripping, &file_id, &mime, &name)
+                    .await
+                    .map_err(|err| io::Error::other(err.to_string()))?
+            } else {
+                storage::key(storage::ORIGINAL, &file_id)
+            };
+            yield Bytes::from(part_head(&boundary, "file", &mime));
+
+            let mut original = stripping.storage.get_stream(&original_key).await?;
+            while let Some(chunk) = original.try_next().await? {
+                yield chunk;
+            }
+            yield Bytes::from_static(b"\r\n");
+        }
+
+        yield Bytes::from(format!("--{}--\r\n", boundary));
+    }
+    .boxed();
+    let stream = bandwidth::count(state, key, user_id, stream);
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_DISPOSITION, "attachment")
+        .status(StatusCode::OK)
+        .body(Body::wrap_stream(stream))
+        .unwrap())
+}
+
+/// Create an album from a bundle, responding with what became of each of its files.
+pub async fn import(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let state: &AppState = parts.data().unwrap();
+    let AppState {
+        ref sessions,
+        ref albums,
+        ref files,
+        ref fragments,
+        ref temp_path,
+        ref upload_limiter,
+        ref config,
+        ..
+    } = state;
+
+    test_logged_in(sessions, key)?;
+    let conflict = file::conflict_policy(&parts)?.unwrap_or(ConflictPolicy::Rename);
+    let _upload = upload_limiter.acquire(owner_id)?;
+
+    let max_bytes = config.max_upload_bytes;
+    let timeout = Duration::from_secs(config.upload_timeout_seconds);
+    let idle = Duration::from_secs(config.body_idle_seconds);
+
+    let boundary = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| multer::parse_boundary(content_type).ok())
+        .ok_or(ApiError::BadRequest)?;
+    let mut multipart = multer::Multipart::new(body, boundary);
+
+    let deadline = time::Instant::now() + timeout;
+    let wait = deadline.min(time::Instant::now() + idle);
+    let field = match time::timeout_at(wait, multipart.next_field()).await {
+        Ok(field) => field?.ok_or(ApiError::BadRequest)?,
+        Err(_) => return Err(ApiError::Timeout),
+    };
+    if field.name() != Some("manifest") {
+        return Err(ApiError::BadRequest);
+    }
+    let manifest_bytes = match time::timeout_at(deadline, field.bytes()).await {
+        Ok(bytes) => bytes?,
+        Err(_) => return Err(ApiError::Timeout),
+    };
+    let manifest: AlbumManifest = serde_json::from_slice(&manifest_bytes)?;
+    if manifest.version != ALBUM_BUNDLE_VERSION {
+        return Err(ApiError::BadRequest);
+    }
+
+    let mut settings = manifest.settings.into_owned();
+    settings.rules.clear();
+    let album_id = block_in_place(|| insert(state, owner_id, settings))?;
+
+    let mut bundled = manifest.files.into_iter();
+    let mut results = vec![];
+    let mut captions = vec![];
+
+    loop {
+        let deadline = time::Instant::now() + timeout;
+
+        let wait = deadline.min(time::Instant::now() + idle);
+        let mut field = match time::timeout_at(wait, multipart.next_field()).await {
+            Ok(field) => match field? {
+                Some(field) => field,
+                None => break,
+            },
+            Err(_) => return Err(ApiError::Timeout),
+        };
+        if field.name() != Some("file") {
+            return Err(ApiError::BadRequest);
+        }
+        let bundled_file = bundled.next().ok_or(ApiError::BadRequest)?;
+
+        let options = UploadOptions {
+            album_id: Some(album_id.clone()),
+            conflict,
+            encrypted: bundled_file.encrypted,
+            content_id: None,
+        };
+        let name = bundled_file.metadata.name.to_string();
+        let metadata = bundled_file.metadata.into_owned();
+
+        let file_id = new_id(16);
+        let upload_path = temp_path.join(&file_id);
+
+        let result = async {
+            let head = file::receive(&mut field, &upload_path, max_bytes, deadline, idle).await?;
+            file::store(state, owner_id, &file_id, &options, metadata, &upload_path, &head).await
+        }
+        .await;
+        let _ = fs::remove_file(&upload_path).await;
+
+        results.push(match result {
+            Ok(stored) => {
+                if let Some(caption) = bundled_file.caption.filter(|caption| !caption.is_empty()) {
+                    captions.push((stored.id.to_string(), caption.into_owned()));
+                }
+
+                UploadResult {
+                    name: Cow::from(name),
+                    id: Some(stored.id),
+                    error: None,
+                    stored_name: Some(stored.name),
+                    hash: stored.hash,
+                }
+            }
+            Err(err) => UploadResult {
+                name: Cow::from(name),
+                id: None,
+                error: Some(Cow::from(err.to_string())),
+                stored_name: None,
+                hash: None,
+            },
+        });
+    }
+
+    // Files that the manifest lists without a part of their own.
+    for missing in bundled {
+        results.push(UploadResult {
+            name: Cow::from(missing.metadata.name.into_owned()),
+            id: None,
+            error: Some(Cow::from(ApiError::BadRequest.to_string())),
+            stored_name: None,
+            hash: None,
+        });
+    }
+
+    if !captions.is_empty() {
+        let fragment_head = block_in_place(|| {
+            (albums, files, fragments).transaction(|(albums, files, fragments)| {
+                let album_bytes = albums.get(album_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                let mut e = Engine::new(&album_id, &mut album, fragments)?;
+                for (file_id, caption) in &captions {
+                    let file_bytes = match files.get(file_id.as_bytes())? {
+                        Some(file_bytes) => file_bytes,
+                        None => continue,
+                    };
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+                    e.set_caption(file_id, &file, Some(caption.clone()))?;
+                }
+                e.commit()?;
+
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                Ok(album.fragment_head)
+            })
+        })?;
+
+        events::album_updated(state, &album_id, fragment_head);
+    }
+
+    respond_ok(ImportedAlbum {
+        id: Cow::from(album_id),
+        files: results,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{header, Body, Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_import_album() {
+        use crate::album::engine::Engine;
+        use crate::common::File;
+        use sled::Transactional;
+
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("friend@example.com").await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC", "sort": "Manual" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let path = format!("/file?key={}&album={}", key, album_id);
+        let mut file_ids = vec![];
+        for name in ["first.png", "second.png", "third.png"].iter() {
+            let (status, body) = server.upload_to(&path, name, png(8, 8)).await;
+            assert_eq!(status, StatusCode::OK);
+            let file: Value = serde_json::from_slice(&body).unwrap();
+            file_ids.push(file["id"].as_str().unwrap().to_string());
+        }
+        let order = json!({ "ids": [file_ids[2], file_ids[0], file_ids[1]] });
+        let status = server.send(Method::POST, &format!("/album/{}/order?key={}", album_id, key), &order).await;
+        assert_eq!(status, StatusCode::OK);
+        let caption = json!({ "text": "Arrival" });
+        let path = format!("/album/{}/caption/{}?key={}", album_id, file_ids[0], key);
+        assert_eq!(server.send(Method::PUT, &path, &caption).await, StatusCode::OK);
+
+        // Only members can export.
+        let path = format!("/album/{}/export?key={}", album_id, other);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let path = format!("/album/{}/export?key={}", album_id, key);
+        let (status, bundle) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The bundle goes back in as it came out.
+        let first_line = bundle.split(|&b| b == b'\r').next().unwrap();
+        let boundary = std::str::from_utf8(&first_line[2..]).unwrap();
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let (status, body) = server
+            .request(
+                Method::POST,
+                &format!("/album/import?key={}", other),
+                &[(header::CONTENT_TYPE.as_str(), content_type)],
+                Body::from(bundle),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        let imported: Value = serde_json::from_slice(&body).unwrap();
+        let names: Vec<_> = imported["files"].as_array().unwrap().iter().map(|file| &file["name"]).collect();
+        assert_eq!(names, ["third.png", "first.png", "second.png"]);
+        assert!(imported["files"].as_array().unwrap().iter().all(|file| file["id"].is_string()));
+
+        let imported_id = imported["id"].as_str().unwrap();
+        let path = format!("/album/{}/serve/metadata?key={}", imported_id, other);
+        let info = server.json(Method::GET, &path, &()).await;
+        assert_eq!(info["description"]["name"], "Trip");
+        assert_eq!(info["description"]["sort"], "Manual");
+        assert_eq!(info["role"], "Owner");
+
+        let state = &server.state;
+        let captions = (&state.albums, &state.fragments)
+            .transaction(|(albums, fragments)| {
+                let album_bytes = albums.get(imported_id)?.unwrap();
+                let mut album: wire::Album = bincode::deserialize(&album_bytes).unwrap();
+                Engine::new(imported_id, &mut album, fragments)?.list_captions()
+            })
+            .unwrap();
+        let entries: Vec<_> = captions
+            .iter()
+            .map(|(file_id, caption)| {
+                let file_bytes = state.files.get(file_id).unwrap().unwrap();
+                let file: File = bincode::deserialize(&file_bytes).unwrap();
+                assert_eq!(file.owner_id, other.split_once('.').unwrap().0);
+                (file.metadata.name.to_string(), caption.clone())
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("third.png".to_string(), None),
+                ("first.png".to_string(), Some("Arrival".to_string())),
+                ("second.png".to_string(), None),
+            ]
+        );
+    }
+}
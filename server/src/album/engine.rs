@@ -9,12 +9,17 @@
 //! to the album that they are located in. Each album has a single `Top` fragment that lists all of
 //! its component sections and their respective `fragment_id`s. Each section then contains a list
 //! of resident files.
+//!
+//! How files are split into sections and ordered within them depends on the album's `SortMode`.
+//! Every commit also writes a delta from the previous head, and albums sorted by a date keep a
+//! summary of each month.
 
 use crate::common::File;
-use crate::error::ApiError;
-use chrono::{offset::Utc, TimeZone};
+use crate::error::{ApiError, ApiResult};
+use crate::placeholder::Placeholder;
+use chrono::{offset::Utc, Datelike, TimeZone};
 use serde::{
-    de::{Deserializer, SeqAccess, Visitor},
+    de::{self, Deserializer, SeqAccess, Visitor},
     Deserialize,
 };
 use serde::{
@@ -22,23 +27,55 @@ use serde::{
     Serialize,
 };
 use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
 use std::fmt;
-use wire::Album;
+use chrono_tz::Tz;
+use wire::{Album, IntoOwned, MonthSummary, SortMode};
+
+/// Gap between the positions of files appended to a manually ordered album, so that a file can be
+/// moved between two others by giving it a position in the gap. The album is only spaced out
+/// again once a gap runs out.
+const POSITION_GAP: i64 = 1 << 16;
+/// Number of appended files in each section of a manually ordered album.
+const MANUAL_SECTION_LENGTH: i64 = 256;
+/// Files in each part of a section, so that fragments stay small however many photos were taken
+/// on a day.
+const MAX_SECTION_LENGTH: usize = 256;
+/// Distance above the head of an album at which a rebuilt copy is laid out by `rebuild::run`,
+/// while the old layout is still served. No album is committed this many times while it is being
+/// rebuilt.
+const STAGING_OFFSET: u64 = 1 << 32;
+
+/// Sort key of a file within its section.
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Order {
+    /// A time stamp, or a position in manually ordered albums.
+    Number(i64),
+    /// A lowercase file name.
+    Name(String),
+}
 
-#[derive(PartialEq, PartialOrd, Eq, Ord, Debug)]
+#[derive(PartialEq, PartialOrd, Eq, Ord, Clone, Debug, Serialize, Deserialize)]
 struct FileKey {
-    time_stamp: i64,
+    order: Order,
     file_id: String,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 struct FileDetails {
     width: i32,
     height: i32,
+    caption: Option<String>,
+    placeholder: Option<Placeholder>,
+    /// Set for the still of a Live Photo, which has motion to show as well.
+    live: bool,
+    /// Size of the original in bytes, if it was recorded.
+    size: Option<u64>,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
 struct SectionDetails {
     fragment_id: u64,
     length: usize,
@@ -47,8 +84,53 @@ struct SectionDetails {
 #[derive(PartialEq, Eq, Debug)]
 struct Section(BTreeMap<FileKey, FileDetails>);
 
+/// Change to a single entry of a section.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Change {
+    /// Insert the entry, replacing any entry with the same key.
+    Add { section: i64, entry: Entry },
+    Remove {
+        section: i64,
+        order: Order,
+        file_id: String,
+    },
+}
+
+/// Changes that take a client from one fragment head to a later one, stored under
+/// `album_id.d<from head>`, so that clients holding an older head can fold the changes into their
+/// copy instead of downloading whole sections again.
+#[derive(PartialEq, Eq, Debug, Serialize, Deserialize)]
+struct Delta {
+    to: u64,
+    /// The changes can't be described entry by entry, so the album has to be fetched again.
+    reset: bool,
+    changes: Vec<Change>,
+}
+
+/// Number of deltas that are kept for each album.
+const MAX_DELTAS: usize = 256;
+
+/// Parts of every section, in order. Sections are keyed by the start of a day for date sorted
+/// albums, the first character of the file name for name sorted albums, and the first position in
+/// the section for manually ordered albums.
 #[derive(PartialEq, Eq, Debug)]
-struct Top(BTreeMap<i64, SectionDetails>);
+struct Top(BTreeMap<i64, Vec<SectionDetails>>);
+
+/// A row of the top, which only lists its ordinal for parts after the first.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopRow {
+    Part(i64, u64, usize, de::IgnoredAny),
+    First(i64, u64, usize),
+}
+
+/// The last file that was copied into a rebuilt album.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Cursor {
+    section: i64,
+    key: FileKey,
+}
 
 impl Serialize for Section {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -57,12 +139,106 @@ impl Serialize for Section {
     {
         let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
         for (key, details) in &self.0 {
-            seq.serialize_element(&(key.time_stamp, &key.file_id, details.width, details.height))?;
+            seq.serialize_element(&EntryRef(key, details))?;
         }
         seq.end()
     }
 }
 
+/// A single section entry, which only has a trailing caption, placeholder, live flag and size if
+/// the file has them. Fields before one that is there are `null` when the file doesn't have them,
+/// and `false` for the live flag.
+#[derive(PartialEq, Eq, Debug)]
+struct Entry(FileKey, FileDetails);
+
+struct EntryRef<'a>(&'a FileKey, &'a FileDetails);
+
+impl<'a> Serialize for EntryRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let EntryRef(key, details) = self;
+        let (order, file_id, width, height) = (&key.order, &key.file_id, details.width, details.height);
+
+        let blurhash = details.placeholder.as_ref().map(|placeholder| &placeholder.blurhash);
+        let color = details.placeholder.as_ref().map(|placeholder| &placeholder.color);
+        let (caption, live, size) = (&details.caption, details.live, details.size);
+
+        match (caption, &details.placeholder, live, size) {
+            (_, _, _, Some(_)) => {
+                (order, file_id, width, height, caption, blurhash, color, live, size).serialize(serializer)
+            }
+            (_, _, true, None) => (order, file_id, width, height, caption, blurhash, color, true).serialize(serializer),
+            (_, Some(_), false, None) => {
+                (order, file_id, width, height, caption, blurhash, color).serialize(serializer)
+            }
+            (Some(_), None, false, None) => (order, file_id, width, height, caption).serialize(serializer),
+            (None, None, false, None) => (order, file_id, width, height).serialize(serializer),
+        }
+    }
+}
+
+impl Serialize for Entry {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        EntryRef(&self.0, &self.1).serialize(serializer)
+    }
+}
+
+struct EntryVisitor;
+
+impl<'de> Visitor<'de> for EntryVisitor {
+    type Value = Entry;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a section entry")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let order = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let file_id = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        let width = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let height = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        let caption = seq.next_element::<Option<String>>()?.flatten();
+        let blurhash = seq.next_element::<Option<String>>()?.flatten();
+        let color = seq.next_element::<Option<String>>()?.flatten();
+        let live = seq.next_element()?.unwrap_or(false);
+        let size = seq.next_element::<Option<u64>>()?.flatten();
+
+        let placeholder = match (blurhash, color) {
+            (Some(blurhash), Some(color)) => Some(Placeholder { blurhash, color }),
+            _ => None,
+        };
+
+        Ok(Entry(
+            FileKey { order, file_id },
+            FileDetails {
+                width,
+                height,
+                caption,
+                placeholder,
+                live,
+                size,
+            },
+        ))
+    }
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(EntryVisitor)
+    }
+}
+
 struct SectionVisitor;
 
 impl<'de> Visitor<'de> for SectionVisitor {
@@ -78,14 +254,8 @@ impl<'de> Visitor<'de> for SectionVisitor {
     {
         let mut btree = BTreeMap::new();
 
-        while let Some((time_stamp, file_id, width, height)) = seq.next_element()? {
-            btree.insert(
-                FileKey {
-                    time_stamp,
-                    file_id,
-                },
-                FileDetails { width, height },
-            );
+        while let Some(Entry(key, details)) = seq.next_element()? {
+            btree.insert(key, details);
         }
 
         Ok(Section(btree))
@@ -106,9 +276,16 @@ impl Serialize for Top {
     where
         S: Serializer,
     {
-        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
-        for (ts, details) in &self.0 {
-            seq.serialize_element(&(ts, details.fragment_id, details.length))?;
+        let rows = self.0.values().map(Vec::len).sum();
+        let mut seq = serializer.serialize_seq(Some(rows))?;
+        for (ts, parts) in &self.0 {
+            for (part, details) in parts.iter().enumerate() {
+                if part == 0 {
+                    seq.serialize_element(&(ts, details.fragment_id, details.length))?;
+                } else {
+                    seq.serialize_element(&(ts, details.fragment_id, details.length, part))?;
+                }
+            }
         }
         seq.end()
     }
@@ -127,16 +304,19 @@ impl<'de> Visitor<'de> for TopVisitor {
     where
         A: SeqAccess<'de>,
     {
-        let mut btree = BTreeMap::new();
+        let mut btree: BTreeMap<i64, Vec<SectionDetails>> = BTreeMap::new();
 
-        while let Some((ts, fragment_id, length)) = seq.next_element()? {
-            btree.insert(
-                ts,
-                SectionDetails {
-                    fragment_id,
-                    length,
-                },
-            );
+        // Parts are listed in order, so their ordinals aren't needed to put them back together.
+        while let Some(row) = seq.next_element()? {
+            let (ts, fragment_id, length) = match row {
+                TopRow::Part(ts, fragment_id, length, _) => (ts, fragment_id, length),
+                TopRow::First(ts, fragment_id, length) => (ts, fragment_id, length),
+            };
+
+            btree.entry(ts).or_default().push(SectionDetails {
+                fragment_id,
+                length,
+            });
         }
 
         Ok(Top(btree))
@@ -156,11 +336,17 @@ pub struct Engine<'a, 'b, 'c, 'd> {
     album_id: &'a str,
     album: &'b mut Album<'c>,
     fragments: &'d TransactionalTree,
-    /// Cache of `Section`s and their original `fragment_id`s if the `Section` already existed in
-    /// the database. Amortizes serialization and `fragment_id` allocation by batching changes.
-    cache: BTreeMap<i64, (Option<u64>, Section)>,
+    /// Cache of `Section`s and the parts that they were stored in, which are empty if the
+    /// `Section` didn't exist in the database. Amortizes serialization and `fragment_id`
+    /// allocation by batching changes.
+    cache: BTreeMap<i64, (Vec<SectionDetails>, Section)>,
     top: Top,
     force_update: bool,
+    /// Set by `clear_all`, after which no delta can be described.
+    reset: bool,
+    /// Sections and positions of the files in a manually ordered album, loaded on first use.
+    positions: Option<HashMap<String, (i64, i64)>>,
+    next_position: i64,
 }
 
 type EngineResult<T> = ConflictableTransactionResult<T, ApiError>;
@@ -222,42 +408,72 @@ impl<'a, 'b, 'c, 'd> Engine<'a, 'b, 'c, 'd> {
             cache: BTreeMap::new(),
             top,
             force_update: false,
+            reset: false,
+            positions: None,
+            next_position: 0,
         })
     }
 
     pub fn commit(mut self) -> EngineResult<()> {
         // Exit if no mutations are necessary.
-        if self.cache.len() == 0 && !self.force_update {
+        if self.cache.is_empty() && !self.force_update {
             return Ok(());
         }
 
         // Otherwise delete the current top
-        self.delete(self.album.fragment_head)?;
+        let from_head = self.album.fragment_head;
+        self.delete(from_head)?;
 
-        for (ts, (maybe_id, section)) in &self.cache {
-            // If the section already exists, delete it.
-            if let Some(id) = maybe_id {
-                self.delete(*id)?;
-            };
+        let mut changes = vec![];
+
+        // Every section was dropped along with the files in it.
+        if self.reset {
+            self.album.length = 0;
+        }
+
+        for (ts, (old_parts, section)) in &self.cache {
+            let old_sections = old_parts
+                .iter()
+                .map(|details| self.read(details.fragment_id))
+                .collect::<EngineResult<Vec<_>>>()?;
+
+            if !self.reset {
+                let old = Section(old_sections.iter().flat_map(|old| old.0.clone()).collect());
+                diff(*ts, &old, section, &mut changes);
+            }
+
+            // Parts that come out the same keep their fragments. The rest are written into the
+            // next available fragment_ids.
+            let mut parts = vec![];
+            for (i, part) in split(section).into_iter().enumerate() {
+                match (old_parts.get(i), old_sections.get(i)) {
+                    (Some(details), Some(old)) if *old == part => parts.push(details.clone()),
+                    _ => {
+                        self.album.fragment_head += 1;
+                        self.write(&part)?;
+                        parts.push(SectionDetails {
+                            fragment_id: self.album.fragment_head,
+                            length: part.0.len(),
+                        });
+                    }
+                }
+            }
+
+            for details in old_parts {
+                if !parts.contains(details) {
+                    self.delete(details.fragment_id)?;
+                }
+            }
 
             let length = section.0.len();
 
-            let prev_length = if length > 0 {
-                // Write the section into the next available fragment_id, then update the top.
-                self.album.fragment_head += 1;
-                self.write(section)?;
-                self.top.0.insert(
-                    *ts,
-                    SectionDetails {
-                        fragment_id: self.album.fragment_head,
-                        length,
-                    },
-                )
-            } else {
+            let prev_length: usize = if parts.is_empty() {
                 // Delete the section since it is empty.
                 self.top.0.remove(ts)
+            } else {
+                self.top.0.insert(*ts, parts)
             }
-            .map(|v| v.length)
+            .map(|parts| parts.iter().map(|details| details.length).sum())
             .unwrap_or(0);
 
             self.album.length = self.album.length + length - prev_length;
@@ -266,238 +482,1275 @@ impl<'a, 'b, 'c, 'd> Engine<'a, 'b, 'c, 'd> {
         self.album.fragment_head += 1;
         self.write(&self.top)?;
 
+        let previous = self.fragments.remove(Self::get_months_id(self.album_id, from_head))?;
+        let previous = match previous {
+            Some(bytes) if !self.reset => Some(serde_json::from_slice(&bytes).unwrap()),
+            _ => None,
+        };
+        self.write_months(previous)?;
+
+        let delta = Delta {
+            to: self.album.fragment_head,
+            reset: self.reset,
+            changes,
+        };
+        let json = serde_json::to_string(&delta).unwrap();
+        self.fragments
+            .insert(Self::get_delta_id(self.album_id, from_head), json.as_bytes())?;
+
         self.album.last_update = Utc::now().timestamp();
 
         let min = self.top.0.iter().next();
         let max = self.top.0.iter().next_back();
-        self.album.date_range = match (min, max) {
-            (Some((min, _)), Some((max, _))) => Some((*min, *max)),
+        self.album.date_range = match (&self.album.description.sort, min, max) {
+            (SortMode::CaptureDate | SortMode::UploadDate, Some((min, _)), Some((max, _))) => {
+                Some((*min, *max))
+            }
             _ => None,
         };
 
         Ok(())
     }
 
+    /// Write a new top on commit even if no sections changed.
+    pub fn touch(&mut self) {
+        self.force_update = true;
+    }
+
+    /// Have sections that were stored in one fragment, from before sections were split into
+    /// parts, written as parts on commit.
+    pub fn split_oversized(&mut self) -> EngineResult<()> {
+        let oversized: Vec<i64> = self
+            .top
+            .0
+            .iter()
+            .filter(|(_, parts)| parts.iter().any(|details| details.length > MAX_SECTION_LENGTH))
+            .map(|(ts, _)| *ts)
+            .collect();
+
+        for ts in oversized {
+            self.modify_section(ts, |_| ())?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove every file from the album. Clients that hold an older head are told to start over
+    /// instead of being sent the removals.
     pub fn clear_all(&mut self) -> EngineResult<()> {
-        for details in self.top.0.values() {
+        for details in self.top.0.values().flatten() {
             self.delete(details.fragment_id)?;
         }
 
         self.top.0 = BTreeMap::new();
         self.cache = BTreeMap::new();
+        self.positions = Some(HashMap::new());
+        self.next_position = 0;
 
         self.force_update = true;
+        self.reset = true;
 
         Ok(())
     }
 
     pub fn add(&mut self, file_id: &str, file: &File) -> EngineResult<()> {
+        self.insert(file_id, file, None)
+    }
+
+    /// Add a file with a caption. A file that is already in the album keeps its caption.
+    fn insert(&mut self, file_id: &str, file: &File, caption: Option<String>) -> EngineResult<()> {
+        let (section, order) = if self.album.description.sort == SortMode::Manual {
+            self.positions()?;
+            let position = self.next_position;
+            let positions = self.positions.as_mut().unwrap();
+
+            if positions.contains_key(file_id) {
+                return Ok(());
+            }
+
+            let section = manual_section(position);
+            positions.insert(file_id.to_owned(), (section, position));
+            self.next_position += POSITION_GAP;
+
+            (section, Order::Number(position))
+        } else {
+            self.place(file)
+        };
+
         let key = FileKey {
-            time_stamp: file.metadata.last_modified,
+            order,
             file_id: file_id.to_owned(),
         };
 
         let details = FileDetails {
             width: file.width,
             height: file.height,
+            caption,
+            placeholder: file.placeholder.clone(),
+            live: file.motion_id.is_some(),
+            size: file.size,
         };
 
-        self.modify_section(key.time_stamp, |ref mut section| {
-            section.0.insert(key, details);
+        self.modify_section(section, |ref mut section| {
+            section.0.entry(key).or_insert(details);
         })?;
 
         Ok(())
     }
 
     pub fn remove(&mut self, file_id: &str, file: &File) -> EngineResult<()> {
+        self.take(file_id, file)?;
+        Ok(())
+    }
+
+    /// Remove a file, returning its caption if it had one.
+    pub fn take(&mut self, file_id: &str, file: &File) -> EngineResult<Option<String>> {
+        let (section, order) = if self.album.description.sort == SortMode::Manual {
+            match self.positions()?.remove(file_id) {
+                Some((section, position)) => (section, Order::Number(position)),
+                None => return Ok(None),
+            }
+        } else {
+            self.place(file)
+        };
+
         let key = FileKey {
-            time_stamp: file.metadata.last_modified,
+            order,
             file_id: file_id.to_owned(),
         };
 
-        self.modify_section(key.time_stamp, |ref mut section| {
-            section.0.remove(&key);
-        })?;
-
-        Ok(())
+        self.modify_section(section, |ref mut section| {
+            section.0.remove(&key).and_then(|details| details.caption)
+        })
     }
 
-    pub fn list_file_ids(&mut self) -> EngineResult<Vec<String>> {
-        let mut file_ids = vec![];
-
-        for details in self.top.0.values() {
-            let section = self.read(details.fragment_id)?;
-            for key in section.0.into_keys() {
-                file_ids.push(key.file_id);
-            }
+    /// Move a file to where its new metadata places it, keeping its caption.
+    pub fn replace(&mut self, file_id: &str, old: &File, new: &File) -> EngineResult<()> {
+        if self.album.description.sort == SortMode::Manual {
+            // Positions don't depend on metadata.
+            return Ok(());
         }
 
-        Ok(file_ids)
+        let caption = self.take(file_id, old)?;
+        self.insert(file_id, new, caption)
+    }
+
+    /// Set or clear the caption of a file in the album. Fails with `NotFound` if the file isn't
+    /// in the album.
+    pub fn set_caption(
+        &mut self,
+        file_id: &str,
+        file: &File,
+        caption: Option<String>,
+    ) -> EngineResult<()> {
+        self.modify_details(file_id, file, |details| details.caption = caption)
+    }
+
+    /// Copy the placeholder, dimensions, live flag and size of a file into its entry after they
+    /// have changed, as they do when it is rendered again, paired or measured. Fails with
+    /// `NotFound` if the file isn't in the album.
+    pub fn set_appearance(&mut self, file_id: &str, file: &File) -> EngineResult<()> {
+        self.modify_details(file_id, file, |details| {
+            details.width = file.width;
+            details.height = file.height;
+            details.placeholder = file.placeholder.clone();
+            details.live = file.motion_id.is_some();
+            details.size = file.size;
+        })
     }
 
-    /// Open the section on the day of timestamp `ts` and mutate by `f`.
-    fn modify_section<F>(&mut self, ts: i64, f: F) -> EngineResult<()>
+    fn modify_details<F>(&mut self, file_id: &str, file: &File, f: F) -> EngineResult<()>
     where
-        F: FnOnce(&mut Section),
+        F: FnOnce(&mut FileDetails),
     {
-        // Truncate the timestamp to the date in the album's time zone.
-        let ts = self
-            .album
-            .description
-            .time_zone
-            .timestamp(ts, 0)
-            .date()
-            .and_hms(0, 0, 0)
-            .timestamp();
-
-        if let Some((_, ref mut section)) = self.cache.get_mut(&ts) {
-            // Section is already cached.
-            f(section);
-        } else if let Some(details) = self.top.0.get(&ts) {
-            // Section exists, but needs to be deserialized.
-            let mut section = self.read(details.fragment_id)?;
-            f(&mut section);
-            self.cache.insert(ts, (Some(details.fragment_id), section));
+        let (section, order) = if self.album.description.sort == SortMode::Manual {
+            match self.positions()?.get(file_id) {
+                Some((section, position)) => (*section, Order::Number(*position)),
+                None => return Err(ApiError::NotFound.into()),
+            }
         } else {
-            // Section needs to be created.
-            let mut section = Section(BTreeMap::new());
-            f(&mut section);
-            self.cache.insert(ts, (None, section));
+            self.place(file)
+        };
+
+        let key = FileKey {
+            order,
+            file_id: file_id.to_owned(),
+        };
+
+        let found = self.modify_section(section, |ref mut section| match section.0.get_mut(&key) {
+            Some(details) => {
+                f(details);
+                true
+            }
+            None => false,
+        })?;
+
+        if !found {
+            return Err(ApiError::NotFound.into());
         }
 
         Ok(())
     }
 
-    fn read(&self, id: u64) -> EngineResult<Section> {
-        let id = Self::get_id(self.album_id, id);
-        let bytes = self.fragments.get(id)?.unwrap();
-        let section = serde_json::from_slice(&bytes).unwrap();
-        Ok(section)
-    }
+    /// Place every file again after the album's time zone or sort mode has changed. Files keep
+    /// their captions, and manually ordered albums keep the current order.
+    pub fn rebuild(&mut self, files: &TransactionalTree) -> EngineResult<()> {
+        let entries = self.entries()?;
+        self.clear_all()?;
+
+        for (_, key, details) in entries {
+            if let Some(file_bytes) = files.get(&key.file_id)? {
+                let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                // Files that were locked while the album was in the trash are dropped like deleted
+                // ones are.
+                if !file.locked {
+                    self.insert(&key.file_id, &file, details.caption)?;
+                }
+            }
+        }
 
-    fn write<T: Serialize>(&self, fragment: &T) -> EngineResult<()> {
-        let id = Self::get_id(self.album_id, self.album.fragment_head);
-        let json = serde_json::to_string(fragment).unwrap();
-        self.fragments.insert(id, json.as_bytes())?;
         Ok(())
     }
 
-    fn delete(&self, id: u64) -> EngineResult<()> {
-        let id = Self::get_id(self.album_id, id);
-        self.fragments.remove(id)?.unwrap();
-        Ok(())
-    }
+    /// Copy up to `limit` files that come after `after` in `source` into this album, keeping their
+    /// captions. Returns the last file that was copied, or `None` once there is nothing left.
+    pub fn copy_from(
+        &mut self,
+        source: &Engine,
+        after: Option<&Cursor>,
+        limit: usize,
+        files: &TransactionalTree,
+    ) -> EngineResult<Option<Cursor>> {
+        let start = after.map(|cursor| cursor.section).unwrap_or(i64::MIN);
+        let mut last = None;
+        let mut copied = 0;
+
+        for (&section, parts) in source.top.0.range(start..) {
+            for (key, details) in source.read_parts(parts)?.0 {
+                if let Some(cursor) = after {
+                    if (section, &key) <= (cursor.section, &cursor.key) {
+                        continue;
+                    }
+                }
+
+                if copied == limit {
+                    return Ok(last);
+                }
+
+                if let Some(file_bytes) = files.get(&key.file_id)? {
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+                    self.insert(&key.file_id, &file, details.caption)?;
+                }
+
+                copied += 1;
+                last = Some(Cursor { section, key });
+            }
+        }
 
-    pub fn get_id(album_id: &str, fragment_id: u64) -> Vec<u8> {
-        [album_id.as_bytes(), b".", &fragment_id.to_be_bytes()].concat()
+        Ok(last)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::wire::{AlbumSettings, FileMetadata};
-    use std::borrow::Cow;
+    /// Apply the changes that were committed to the original album from `from_head` to `to_head`
+    /// to this rebuilt copy of it. Returns false if the changes can't be described, in which case
+    /// the copy has to start over.
+    pub fn catch_up(
+        &mut self,
+        from_head: u64,
+        to_head: u64,
+        files: &TransactionalTree,
+    ) -> EngineResult<bool> {
+        // The entries that each file which was touched is left with.
+        let mut touched: HashMap<String, BTreeMap<(i64, Order), FileDetails>> = HashMap::new();
+
+        let mut head = from_head;
+        while head < to_head {
+            let delta: Delta = match self.fragments.get(Self::get_delta_id(self.album_id, head))? {
+                Some(bytes) => serde_json::from_slice(&bytes).unwrap(),
+                None => return Ok(false),
+            };
 
-    #[test]
-    fn ser_de_section() {
-        let mut s = Section(BTreeMap::new());
+            if delta.reset {
+                return Ok(false);
+            }
 
-        s.0.insert(
-            FileKey {
-                time_stamp: 0,
-                file_id: "a".to_string(),
-            },
-            FileDetails {
-                width: 1,
-                height: 2,
-            },
-        );
+            for change in delta.changes {
+                match change {
+                    Change::Add {
+                        section,
+                        entry: Entry(key, details),
+                    } => {
+                        touched
+                            .entry(key.file_id)
+                            .or_default()
+                            .insert((section, key.order), details);
+                    }
+                    Change::Remove {
+                        section,
+                        order,
+                        file_id,
+                    } => {
+                        touched.entry(file_id).or_default().remove(&(section, order));
+                    }
+                }
+            }
 
-        s.0.insert(
-            FileKey {
-                time_stamp: 3,
-                file_id: "b".to_string(),
-            },
-            FileDetails {
-                width: 4,
-                height: 5,
-            },
-        );
+            head = delta.to;
+        }
 
-        let json = serde_json::to_string(&s).unwrap();
-        assert_eq!("[[0,\"a\",1,2],[3,\"b\",4,5]]", &json);
+        if head > to_head {
+            return Ok(false);
+        }
 
-        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
-        assert_eq!(s, s_de);
+        let mut placed: HashMap<String, (i64, FileKey)> = self
+            .entries()?
+            .into_iter()
+            .filter(|(_, key, _)| touched.contains_key(&key.file_id))
+            .map(|(section, key, _)| (key.file_id.clone(), (section, key)))
+            .collect();
+
+        for (file_id, entries) in touched {
+            if let Some((section, key)) = placed.remove(&file_id) {
+                self.modify_section(section, |ref mut section| {
+                    section.0.remove(&key);
+                })?;
+                if let Some(positions) = self.positions.as_mut() {
+                    positions.remove(&file_id);
+                }
+            }
+
+            if let Some(details) = entries.into_values().next() {
+                if let Some(file_bytes) = files.get(file_id.as_bytes())? {
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+                    self.insert(&file_id, &file, details.caption)?;
+                }
+            }
+        }
+
+        Ok(true)
     }
 
-    #[test]
-    fn ser_de_top() {
-        let mut t = Top(BTreeMap::new());
+    /// Start an empty copy of `album` that is laid out by `time_zone` and `sort`, to be filled in
+    /// by `copy_from` and `catch_up`.
+    pub fn stage(
+        album_id: &str,
+        album: &Album,
+        time_zone: Tz,
+        sort: SortMode,
+        fragments: &TransactionalTree,
+    ) -> EngineResult<Album<'static>> {
+        let mut staged = album.clone().into_owned();
+        staged.description.time_zone = time_zone;
+        staged.description.sort = sort;
+        staged.fragment_head = album.fragment_head + STAGING_OFFSET;
+        staged.length = 0;
+        staged.date_range = None;
 
-        t.0.insert(
-            0,
-            SectionDetails {
-                fragment_id: 4,
-                length: 8,
-            },
-        );
-        t.0.insert(
-            1,
-            SectionDetails {
-                fragment_id: 5,
-                length: 9,
-            },
-        );
-        t.0.insert(
-            2,
-            SectionDetails {
-                fragment_id: 6,
-                length: 10,
-            },
-        );
+        let json = serde_json::to_string(&Top(BTreeMap::new())).unwrap();
+        fragments.insert(Self::get_id(album_id, staged.fragment_head), json.as_bytes())?;
 
-        let json = serde_json::to_string(&t).unwrap();
-        assert_eq!("[[0,4,8],[1,5,9],[2,6,10]]", &json);
+        Ok(staged)
+    }
 
-        let t_de: Top = serde_json::from_slice(json.as_bytes()).unwrap();
+    /// Replace the layout of `album` with a finished copy from `stage`. Clients that hold an
+    /// older head have to start over.
+    pub fn adopt(
+        album_id: &str,
+        album: &mut Album,
+        staged: &Album,
+        fragments: &TransactionalTree,
+    ) -> EngineResult<()> {
+        Self::discard(album_id, album, fragments)?;
+
+        let delta = Delta {
+            to: staged.fragment_head,
+            reset: true,
+            changes: vec![],
+        };
+        let json = serde_json::to_string(&delta).unwrap();
+        fragments.insert(Self::get_delta_id(album_id, album.fragment_head), json.as_bytes())?;
 
-        assert_eq!(t, t_de);
+        album.description.time_zone = staged.description.time_zone;
+        album.description.sort = staged.description.sort;
+        album.fragment_head = staged.fragment_head;
+        album.length = staged.length;
+        album.date_range = staged.date_range;
+        album.last_update = Utc::now().timestamp();
+
+        Ok(())
     }
 
-    fn dummy_file(num: i32, ts: i64) -> File<'static, 'static, 'static> {
-        File {
-            owner_id: "u0",
-            width: 40 + 2 * num,
-            height: 41 + 2 * num,
-            metadata: FileMetadata {
-                last_modified: ts,
-                name: Cow::from("name"),
-                mime: Cow::from("*/*"),
-            },
+    /// Delete the top at the head of `album` along with its sections and month summaries.
+    /// Fragments that are already gone, like those of an album that was purged, are skipped.
+    pub fn discard(album_id: &str, album: &Album, fragments: &TransactionalTree) -> EngineResult<()> {
+        fragments.remove(Self::get_months_id(album_id, album.fragment_head))?;
+        if let Some(top_bytes) = fragments.remove(Self::get_id(album_id, album.fragment_head))? {
+            let top: Top = serde_json::from_slice(&top_bytes).unwrap();
+            for details in top.0.values().flatten() {
+                fragments.remove(Self::get_id(album_id, details.fragment_id))?;
+            }
         }
+
+        Ok(())
     }
 
-    fn dummy_album() -> Album<'static, 'static> {
-        Album {
-            owner_id: Cow::from("u0"),
-            fragment_head: 0,
-            description: AlbumSettings {
-                name: Cow::from("album_name"),
-                time_zone: chrono_tz::Asia::Kolkata,
-            },
-            length: 0,
-            last_update: 0,
-            date_range: None,
+    /// Delete the deltas that lead from `from_head` to `to_head`, like those that were written
+    /// while a copy from `stage` was filled in.
+    pub fn discard_deltas(
+        album_id: &str,
+        from_head: u64,
+        to_head: u64,
+        fragments: &TransactionalTree,
+    ) -> EngineResult<()> {
+        let mut head = from_head;
+        while head < to_head {
+            match fragments.remove(Self::get_delta_id(album_id, head))? {
+                Some(bytes) => head = serde_json::from_slice::<Delta>(&bytes).unwrap().to,
+                None => break,
+            }
         }
+
+        Ok(())
     }
 
-    fn dummy_db() -> sled::Db {
-        let db = sled::Config::new().temporary(true).open().unwrap();
+    /// Put `file_ids` in the given order relative to each other in a manually ordered album. The
+    /// longest run of them that is already in order stays in place and the rest are moved next to
+    /// the file listed before them, so a single move only rewrites the sections that it touches.
+    /// Files that aren't listed keep their places.
+    pub fn reorder<S: AsRef<str>>(&mut self, file_ids: &[S]) -> EngineResult<()> {
+        if self.album.description.sort != SortMode::Manual {
+            return Err(ApiError::BadRequest.into());
+        }
 
-        db.transaction(|t| {
-            Engine::empty("a", t)?;
+        let mut current = HashMap::new();
+        let mut order = BTreeMap::new();
+        for (section, key, details) in self.entries()? {
+            if let Order::Number(position) = key.order {
+                order.insert(position, key.file_id.clone());
+                current.insert(key.file_id, (section, position, details));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let listed: Vec<&str> = file_ids
+            .iter()
+            .map(AsRef::as_ref)
+            .filter(|file_id| current.contains_key(*file_id) && seen.insert(*file_id))
+            .collect();
+        let listed_positions: Vec<i64> = listed.iter().map(|file_id| current[*file_id].1).collect();
+        let keep = longest_increasing(&listed_positions);
+
+        let mut placed: HashMap<String, i64> = current
+            .iter()
+            .map(|(file_id, (_, position, _))| (file_id.clone(), *position))
+            .collect();
+
+        for (i, file_id) in listed.iter().enumerate() {
+            if keep[i] {
+                continue;
+            }
+
+            order.remove(&placed[*file_id]);
+
+            let position = loop {
+                let (lower, upper) = if i > 0 {
+                    let anchor = placed[listed[i - 1]];
+                    (Some(anchor), order.range(anchor + 1..).next().map(|(p, _)| *p))
+                } else {
+                    // The first listed file goes in front of the first one that stays in place.
+                    let first_kept = keep.iter().position(|k| *k).unwrap();
+                    let anchor = placed[listed[first_kept]];
+                    (order.range(..anchor).next_back().map(|(p, _)| *p), Some(anchor))
+                };
+
+                match between(lower, upper) {
+                    Some(position) => break position,
+                    None => respace(&mut order, &mut placed),
+                }
+            };
+
+            order.insert(position, file_id.to_string());
+            placed.insert(file_id.to_string(), position);
+        }
+
+        let mut positions = HashMap::new();
+        for (file_id, (section, position, details)) in current {
+            let new_position = placed[&file_id];
+
+            if new_position == position {
+                positions.insert(file_id, (section, position));
+                continue;
+            }
+
+            let old_key = FileKey {
+                order: Order::Number(position),
+                file_id: file_id.clone(),
+            };
+            self.modify_section(section, |ref mut section| {
+                section.0.remove(&old_key);
+            })?;
+
+            let new_section = manual_section(new_position);
+            let new_key = FileKey {
+                order: Order::Number(new_position),
+                file_id: file_id.clone(),
+            };
+            self.modify_section(new_section, |ref mut section| {
+                section.0.insert(new_key, details);
+            })?;
+
+            positions.insert(file_id, (new_section, new_position));
+        }
+
+        self.next_position = positions
+            .values()
+            .map(|(_, position)| position + POSITION_GAP)
+            .max()
+            .unwrap_or(0);
+        self.positions = Some(positions);
+
+        Ok(())
+    }
+
+    /// Keys of every section, including sections that haven't been committed.
+    fn section_keys(&self) -> BTreeSet<i64> {
+        self.top.0.keys().chain(self.cache.keys()).copied().collect()
+    }
+
+    /// Every file in the album in order, including changes that haven't been committed.
+    fn entries(&self) -> EngineResult<Vec<(i64, FileKey, FileDetails)>> {
+        let mut entries = vec![];
+
+        for section in self.section_keys() {
+            if let Some((_, cached)) = self.cache.get(&section) {
+                entries.extend(cached.0.iter().map(|(k, d)| (section, k.clone(), d.clone())));
+            } else {
+                let read = self.read_parts(&self.top.0[&section])?;
+                entries.extend(read.0.into_iter().map(|(k, d)| (section, k, d)));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Ids of every file in the album in order, including changes that haven't been committed.
+    /// Sections are read one at a time as the iterator reaches them, so the engine can't be
+    /// changed until it is dropped; collect the ids that need changing first.
+    pub fn list_file_ids(&self) -> FileIds<'_, 'a, 'b, 'c, 'd> {
+        FileIds {
+            engine: self,
+            sections: self.section_keys().into_iter(),
+            section: vec![].into_iter(),
+        }
+    }
+
+    /// Ids of every file in the album in order, along with their captions.
+    pub fn list_captions(&self) -> EngineResult<Vec<(String, Option<String>)>> {
+        let entries = self.entries()?;
+        Ok(entries.into_iter().map(|(_, key, details)| (key.file_id, details.caption)).collect())
+    }
+
+    /// Entries of the sections whose keys `keep` accepts, in order, laid out the way that they are
+    /// in fragments. Sections that `keep` turns down aren't read.
+    pub fn section_entries<F>(&self, keep: F) -> EngineResult<Vec<(i64, Vec<serde_json::Value>)>>
+    where
+        F: Fn(i64) -> bool,
+    {
+        let mut sections = vec![];
+
+        for section in self.section_keys().into_iter().filter(|&section| keep(section)) {
+            let entries = if let Some((_, cached)) = self.cache.get(&section) {
+                cached.0.iter().map(|(k, d)| serde_json::to_value(EntryRef(k, d)).unwrap()).collect()
+            } else {
+                let read = self.read_parts(&self.top.0[&section])?;
+                read.0.iter().map(|(k, d)| serde_json::to_value(EntryRef(k, d)).unwrap()).collect()
+            };
+            sections.push((section, entries));
+        }
+
+        Ok(sections)
+    }
+
+    /// Ids of the files in a single section.
+    fn section_file_ids(&self, section: i64) -> EngineResult<Vec<String>> {
+        let file_ids = if let Some((_, cached)) = self.cache.get(&section) {
+            cached.0.keys().map(|key| key.file_id.clone()).collect()
+        } else {
+            let read = self.read_parts(&self.top.0[&section])?;
+            read.0.into_keys().map(|key| key.file_id).collect()
+        };
+
+        Ok(file_ids)
+    }
+
+    /// Positions of the files in a manually ordered album. Also sets `next_position` when the
+    /// positions are first loaded.
+    fn positions(&mut self) -> EngineResult<&mut HashMap<String, (i64, i64)>> {
+        if self.positions.is_none() {
+            let mut positions = HashMap::new();
+
+            for (section, key, _) in self.entries()? {
+                if let Order::Number(position) = key.order {
+                    positions.insert(key.file_id, (section, position));
+                }
+            }
+
+            self.next_position = positions
+                .values()
+                .map(|(_, position)| position + POSITION_GAP)
+                .max()
+                .unwrap_or(0);
+            self.positions = Some(positions);
+        }
+
+        Ok(self.positions.as_mut().unwrap())
+    }
+
+    /// Start of the day that `ts` is in, in `time_zone`, which is the key of its section in albums
+    /// sorted by date.
+    pub fn day(time_zone: Tz, ts: i64) -> i64 {
+        let midnight = time_zone.timestamp_opt(ts, 0).unwrap().date_naive().and_hms_opt(0, 0, 0).unwrap();
+        time_zone.from_local_datetime(&midnight).earliest().unwrap().timestamp()
+    }
+
+    /// Summarize every month of the album at its current head, for albums from before months were
+    /// summarized.
+    pub fn summarize_months(&self) -> EngineResult<()> {
+        self.write_months(None)
+    }
+
+    /// Write the month summaries at the head of the album under `album_id.m<head>`, recounting
+    /// only the months of cached sections when the `previous` summaries are given. They let a
+    /// scrubber lay out albums that span years from the metadata alone.
+    fn write_months(&self, previous: Option<Vec<MonthSummary>>) -> EngineResult<()> {
+        if !matches!(self.album.description.sort, SortMode::CaptureDate | SortMode::UploadDate) {
+            return Ok(());
+        }
+
+        let time_zone = self.album.description.time_zone;
+        let month_of = |section: i64| {
+            let day = time_zone.timestamp_opt(section, 0).unwrap();
+            (day.year(), day.month())
+        };
+
+        let recount: BTreeSet<(i32, u32)> = match previous {
+            Some(_) => self.cache.keys().map(|&section| month_of(section)).collect(),
+            None => self.top.0.keys().map(|&section| month_of(section)).collect(),
+        };
+        let mut months: BTreeMap<(i32, u32), MonthSummary> = previous
+            .into_iter()
+            .flatten()
+            .map(|summary| ((summary.year, summary.month), summary))
+            .filter(|(month, _)| !recount.contains(month))
+            .collect();
+
+        for (&section, parts) in &self.top.0 {
+            let (year, month) = month_of(section);
+            if !recount.contains(&(year, month)) {
+                continue;
+            }
+
+            let count = parts.iter().map(|details| details.length).sum();
+            let (min, max) = (self.bound(section, parts, false)?, self.bound(section, parts, true)?);
+            months
+                .entry((year, month))
+                .and_modify(|summary| {
+                    summary.count += count;
+                    summary.max = max;
+                })
+                .or_insert(MonthSummary { year, month, count, min, max });
+        }
+
+        let months: Vec<&MonthSummary> = months.values().collect();
+        let id = Self::get_months_id(self.album_id, self.album.fragment_head);
+        self.fragments.insert(id, serde_json::to_string(&months).unwrap().as_bytes())?;
+        Ok(())
+    }
+
+    /// Time stamp of the first file of a section in an album sorted by date, or of the `last` one.
+    fn bound(&self, section: i64, parts: &[SectionDetails], last: bool) -> EngineResult<i64> {
+        let key = if let Some((_, cached)) = self.cache.get(&section) {
+            if last { cached.0.keys().next_back() } else { cached.0.keys().next() }.cloned()
+        } else {
+            let details = if last { parts.last() } else { parts.first() }.unwrap();
+            let part = self.read(details.fragment_id)?;
+            if last { part.0.into_keys().next_back() } else { part.0.into_keys().next() }
+        };
+
+        match key {
+            Some(FileKey { order: Order::Number(ts), .. }) => Ok(ts),
+            _ => unreachable!("sections of albums sorted by date are ordered by time stamps"),
+        }
+    }
+
+    /// Section and sort key of a file in an album that is ordered by file metadata.
+    fn place(&self, file: &File) -> (i64, Order) {
+        let time_zone = self.album.description.time_zone;
+        let day = |ts| Self::day(time_zone, ts);
+
+        match self.album.description.sort {
+            SortMode::CaptureDate => {
+                let ts = file.metadata.last_modified;
+                (day(ts), Order::Number(ts))
+            }
+            SortMode::UploadDate => (day(file.uploaded), Order::Number(file.uploaded)),
+            SortMode::Name => {
+                let name = file.metadata.name.to_lowercase();
+                let initial = name.chars().next().map(|c| c as i64).unwrap_or(0);
+                (initial, Order::Name(name))
+            }
+            SortMode::Manual => unreachable!("manual albums are ordered by position"),
+        }
+    }
+
+    /// Open the section keyed by `ts` and mutate by `f`.
+    fn modify_section<F, T>(&mut self, ts: i64, f: F) -> EngineResult<T>
+    where
+        F: FnOnce(&mut Section) -> T,
+    {
+        let result = if let Some((_, ref mut section)) = self.cache.get_mut(&ts) {
+            // Section is already cached.
+            f(section)
+        } else if let Some(parts) = self.top.0.get(&ts) {
+            // Section exists, but needs to be deserialized.
+            let mut section = self.read_parts(parts)?;
+            let result = f(&mut section);
+            self.cache.insert(ts, (parts.clone(), section));
+            result
+        } else {
+            // Section needs to be created.
+            let mut section = Section(BTreeMap::new());
+            let result = f(&mut section);
+            self.cache.insert(ts, (vec![], section));
+            result
+        };
+
+        Ok(result)
+    }
+
+    fn read(&self, id: u64) -> EngineResult<Section> {
+        let id = Self::get_id(self.album_id, id);
+        let bytes = self.fragments.get(id)?.unwrap();
+        let section = serde_json::from_slice(&bytes).unwrap();
+        Ok(section)
+    }
+
+    /// Read every part of a section back into one.
+    fn read_parts(&self, parts: &[SectionDetails]) -> EngineResult<Section> {
+        let mut section = Section(BTreeMap::new());
+        for details in parts {
+            section.0.extend(self.read(details.fragment_id)?.0);
+        }
+        Ok(section)
+    }
+
+    fn write<T: Serialize>(&self, fragment: &T) -> EngineResult<()> {
+        let id = Self::get_id(self.album_id, self.album.fragment_head);
+        let json = serde_json::to_string(fragment).unwrap();
+        self.fragments.insert(id, json.as_bytes())?;
+        Ok(())
+    }
+
+    fn delete(&self, id: u64) -> EngineResult<()> {
+        let id = Self::get_id(self.album_id, id);
+        self.fragments.remove(id)?.unwrap();
+        Ok(())
+    }
+
+    /// Ids of the fragments that the top at `head` refers to, along with the top itself.
+    pub fn reachable(
+        album_id: &str,
+        head: u64,
+        fragments: &TransactionalTree,
+    ) -> EngineResult<HashSet<u64>> {
+        let mut reachable = HashSet::new();
+        reachable.insert(head);
+
+        if let Some(top_bytes) = fragments.get(Self::get_id(album_id, head))? {
+            let top: Top = serde_json::from_slice(&top_bytes).unwrap();
+            reachable.extend(top.0.values().flatten().map(|details| details.fragment_id));
+        }
+
+        Ok(reachable)
+    }
+
+    /// The `fragment_id` of a key in the `fragments` tree under `album_id`, unless the key is a
+    /// delta.
+    pub fn parse_id(album_id: &str, key: &[u8]) -> Option<u64> {
+        let id = key.strip_prefix(album_id.as_bytes())?.strip_prefix(b".")?;
+        Some(u64::from_be_bytes(id.try_into().ok()?))
+    }
+
+    pub fn get_id(album_id: &str, fragment_id: u64) -> Vec<u8> {
+        [album_id.as_bytes(), b".", &fragment_id.to_be_bytes()].concat()
+    }
+
+    fn get_delta_id(album_id: &str, from_head: u64) -> Vec<u8> {
+        [album_id.as_bytes(), b".d", &from_head.to_be_bytes()].concat()
+    }
+
+    fn get_months_id(album_id: &str, head: u64) -> Vec<u8> {
+        [album_id.as_bytes(), b".m", &head.to_be_bytes()].concat()
+    }
+
+    /// Month summaries of the album at `head`, which albums that are empty or not sorted by a date
+    /// don't have.
+    pub fn months(fragments: &sled::Tree, album_id: &str, head: u64) -> ApiResult<Vec<MonthSummary>> {
+        match fragments.get(Self::get_months_id(album_id, head))? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Fold the deltas from `from_head` up to `to_head` into a single delta, as json.
+    pub fn fold_deltas(
+        fragments: &sled::Tree,
+        album_id: &str,
+        from_head: u64,
+        to_head: u64,
+    ) -> ApiResult<String> {
+        let mut folded = Delta {
+            to: to_head,
+            reset: false,
+            changes: vec![],
+        };
+
+        let mut head = from_head;
+        while head < to_head {
+            let delta: Delta = match fragments.get(Self::get_delta_id(album_id, head))? {
+                Some(bytes) => serde_json::from_slice(&bytes).unwrap(),
+                // Either the delta was pruned or `from_head` was never a head of this album.
+                None => {
+                    folded.reset = true;
+                    break;
+                }
+            };
+
+            if delta.reset {
+                folded.reset = true;
+                break;
+            }
+
+            folded.changes.extend(delta.changes);
+            head = delta.to;
+        }
+
+        if head > to_head {
+            folded.reset = true;
+        }
+
+        if folded.reset {
+            folded.changes.clear();
+        }
+
+        Ok(serde_json::to_string(&folded).unwrap())
+    }
+
+    /// Only keep the most recent deltas of an album.
+    pub fn prune_deltas(fragments: &sled::Tree, album_id: &str) -> ApiResult<()> {
+        let prefix = [album_id, ".d"].concat();
+        let count = fragments.scan_prefix(&prefix).count();
+
+        for entry in fragments.scan_prefix(&prefix).take(count.saturating_sub(MAX_DELTAS)) {
+            let (key, _) = entry?;
+            fragments.remove(key)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterator over the ids of the files in an album, returned by `Engine::list_file_ids`.
+pub struct FileIds<'e, 'a, 'b, 'c, 'd> {
+    engine: &'e Engine<'a, 'b, 'c, 'd>,
+    sections: std::collections::btree_set::IntoIter<i64>,
+    section: std::vec::IntoIter<String>,
+}
+
+impl<'e, 'a, 'b, 'c, 'd> Iterator for FileIds<'e, 'a, 'b, 'c, 'd> {
+    type Item = EngineResult<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(file_id) = self.section.next() {
+                return Some(Ok(file_id));
+            }
+
+            let section = self.sections.next()?;
+            match self.engine.section_file_ids(section) {
+                Ok(file_ids) => self.section = file_ids.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Describe how `old` became `new`, for the section keyed by `ts`.
+fn diff(ts: i64, old: &Section, new: &Section, changes: &mut Vec<Change>) {
+    for key in old.0.keys() {
+        if !new.0.contains_key(key) {
+            changes.push(Change::Remove {
+                section: ts,
+                order: key.order.clone(),
+                file_id: key.file_id.clone(),
+            });
+        }
+    }
+
+    for (key, details) in &new.0 {
+        if old.0.get(key) != Some(details) {
+            changes.push(Change::Add {
+                section: ts,
+                entry: Entry(key.clone(), details.clone()),
+            });
+        }
+    }
+}
+
+/// Split a section into the parts that it is stored as, which are runs of `MAX_SECTION_LENGTH` of
+/// its ordered files.
+fn split(section: &Section) -> Vec<Section> {
+    let entries: Vec<_> = section.0.iter().collect();
+
+    entries
+        .chunks(MAX_SECTION_LENGTH)
+        .map(|chunk| Section(chunk.iter().map(|(key, details)| ((*key).clone(), (*details).clone())).collect()))
+        .collect()
+}
+
+fn manual_section(position: i64) -> i64 {
+    position - position.rem_euclid(MANUAL_SECTION_LENGTH * POSITION_GAP)
+}
+
+/// Pick a position strictly between two neighbours, if there is room.
+fn between(lower: Option<i64>, upper: Option<i64>) -> Option<i64> {
+    match (lower, upper) {
+        (Some(lower), Some(upper)) if upper - lower > 1 => Some(lower + (upper - lower) / 2),
+        (Some(_), Some(_)) => None,
+        (Some(lower), None) => Some(lower + POSITION_GAP),
+        (None, Some(upper)) => Some(upper - POSITION_GAP),
+        (None, None) => Some(0),
+    }
+}
+
+/// Spread every position back out once a gap has run out.
+fn respace(order: &mut BTreeMap<i64, String>, placed: &mut HashMap<String, i64>) {
+    *order = std::mem::take(order)
+        .into_values()
+        .enumerate()
+        .map(|(i, file_id)| {
+            let position = i as i64 * POSITION_GAP;
+            placed.insert(file_id.clone(), position);
+            (position, file_id)
+        })
+        .collect();
+}
+
+/// Mark the longest strictly increasing subsequence of `values`.
+fn longest_increasing(values: &[i64]) -> Vec<bool> {
+    // `tails[k]` is the index of the smallest value that ends an increasing run of length k + 1.
+    let mut tails: Vec<usize> = vec![];
+    let mut previous = vec![None; values.len()];
+
+    for (i, value) in values.iter().enumerate() {
+        let k = tails.partition_point(|&j| values[j] < *value);
+        if k > 0 {
+            previous[i] = Some(tails[k - 1]);
+        }
+
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+
+    let mut keep = vec![false; values.len()];
+    let mut next = tails.last().copied();
+    while let Some(i) = next {
+        keep[i] = true;
+        next = previous[i];
+    }
+
+    keep
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wire::{AlbumSettings, FileEdit, FileLabels, FileMetadata};
+    use std::borrow::Cow;
+
+    #[test]
+    fn ser_de_section() {
+        let mut s = Section(BTreeMap::new());
+
+        s.0.insert(
+            FileKey {
+                order: Order::Number(0),
+                file_id: "a".to_string(),
+            },
+            FileDetails {
+                width: 1,
+                height: 2,
+                caption: None,
+                placeholder: None,
+                live: false,
+                size: None,
+            },
+        );
+
+        s.0.insert(
+            FileKey {
+                order: Order::Number(3),
+                file_id: "b".to_string(),
+            },
+            FileDetails {
+                width: 4,
+                height: 5,
+                caption: None,
+                placeholder: None,
+                live: false,
+                size: None,
+            },
+        );
+
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!("[[0,\"a\",1,2],[3,\"b\",4,5]]", &json);
+
+        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(s, s_de);
+    }
+
+    #[test]
+    fn ser_de_caption() {
+        let mut s = Section(BTreeMap::new());
+
+        s.0.insert(
+            FileKey {
+                order: Order::Name("a".to_string()),
+                file_id: "a".to_string(),
+            },
+            FileDetails {
+                width: 1,
+                height: 2,
+                caption: Some("caption".to_string()),
+                placeholder: None,
+                live: false,
+                size: None,
+            },
+        );
+
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!("[[\"a\",\"a\",1,2,\"caption\"]]", &json);
+
+        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(s, s_de);
+    }
+
+    #[test]
+    fn ser_de_placeholder() {
+        let mut s = Section(BTreeMap::new());
+
+        s.0.insert(
+            FileKey {
+                order: Order::Number(0),
+                file_id: "a".to_string(),
+            },
+            FileDetails {
+                width: 1,
+                height: 2,
+                caption: None,
+                placeholder: Some(Placeholder {
+                    blurhash: "L00000fQfQfQfQfQfQfQfQfQfQfQ".to_string(),
+                    color: "#000000".to_string(),
+                }),
+                live: false,
+                size: None,
+            },
+        );
+
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!("[[0,\"a\",1,2,null,\"L00000fQfQfQfQfQfQfQfQfQfQfQ\",\"#000000\"]]", &json);
+
+        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(s, s_de);
+    }
+
+    #[test]
+    fn ser_de_live() {
+        let mut s = Section(BTreeMap::new());
+
+        s.0.insert(
+            FileKey {
+                order: Order::Number(0),
+                file_id: "a".to_string(),
+            },
+            FileDetails {
+                width: 1,
+                height: 2,
+                caption: None,
+                placeholder: None,
+                live: true,
+                size: None,
+            },
+        );
+
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!("[[0,\"a\",1,2,null,null,null,true]]", &json);
+
+        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(s, s_de);
+    }
+
+    #[test]
+    fn ser_de_size() {
+        let mut s = Section(BTreeMap::new());
+
+        s.0.insert(
+            FileKey {
+                order: Order::Number(0),
+                file_id: "a".to_string(),
+            },
+            FileDetails {
+                width: 1,
+                height: 2,
+                caption: Some("caption".to_string()),
+                placeholder: None,
+                live: false,
+                size: Some(1234),
+            },
+        );
+
+        let json = serde_json::to_string(&s).unwrap();
+        assert_eq!("[[0,\"a\",1,2,\"caption\",null,null,false,1234]]", &json);
+
+        let s_de = serde_json::from_slice(json.as_bytes()).unwrap();
+        assert_eq!(s, s_de);
+
+        // Clients read the same entries with the type from `wire`.
+        let entries: Vec<wire::SectionEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(entries[0].size, Some(1234));
+    }
+
+    #[test]
+    fn ser_de_top() {
+        let mut t = Top(BTreeMap::new());
+
+        t.0.insert(
+            0,
+            vec![SectionDetails {
+                fragment_id: 4,
+                length: 8,
+            }],
+        );
+        t.0.insert(
+            1,
+            vec![SectionDetails {
+                fragment_id: 5,
+                length: 9,
+            }],
+        );
+        t.0.insert(
+            2,
+            vec![SectionDetails {
+                fragment_id: 6,
+                length: 10,
+            }],
+        );
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!("[[0,4,8],[1,5,9],[2,6,10]]", &json);
+
+        let t_de: Top = serde_json::from_slice(json.as_bytes()).unwrap();
+
+        assert_eq!(t, t_de);
+    }
+
+    #[test]
+    fn ser_de_top_parts() {
+        let mut t = Top(BTreeMap::new());
+
+        t.0.insert(
+            0,
+            vec![
+                SectionDetails {
+                    fragment_id: 4,
+                    length: 256,
+                },
+                SectionDetails {
+                    fragment_id: 5,
+                    length: 1,
+                },
+            ],
+        );
+
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!("[[0,4,256],[0,5,1,1]]", &json);
+
+        let t_de: Top = serde_json::from_slice(json.as_bytes()).unwrap();
+
+        assert_eq!(t, t_de);
+
+        // Metadata requests send rows back the way that they are stored.
+        let rows: Vec<wire::TopEntry> = serde_json::from_str(&json).unwrap();
+        assert_eq!(rows[1].part, 1);
+        assert_eq!(serde_json::to_string(&rows).unwrap(), json);
+    }
+
+    fn dummy_file(num: i32, ts: i64) -> File<'static, 'static, 'static> {
+        File {
+            owner_id: "u0",
+            width: 40 + 2 * num,
+            height: 41 + 2 * num,
+            uploaded: ts,
+            detected_mime: "*/*",
+            placeholder: None,
+            phash: None,
+            location: None,
+            camera: None,
+            size: None,
+            hash: None,
+            rendition_sizes: vec![],
+            encrypted: false,
+            animation: None,
+            video: None,
+            edit: FileEdit::default(),
+            revision: 0,
+            motion_id: None,
+            still_id: None,
+            labels: FileLabels::default(),
+            hidden: false,
+            locked: false,
+            metadata: FileMetadata {
+                last_modified: ts,
+                name: Cow::from("name"),
+                mime: Cow::from("*/*"),
+            },
+        }
+    }
+
+    fn dummy_album() -> Album<'static> {
+        Album {
+            fragment_head: 0,
+            description: AlbumSettings {
+                name: Cow::from("album_name"),
+                time_zone: chrono_tz::Asia::Kolkata,
+                sort: SortMode::CaptureDate,
+                description: Cow::from(""),
+                rules: vec![],
+            },
+            length: 0,
+            last_update: 0,
+            date_range: None,
+        }
+    }
+
+    fn dummy_db() -> sled::Db {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        db.transaction(|t| {
+            Engine::empty("a", t)?;
             Ok(())
         })
         .unwrap();
@@ -505,11 +1758,19 @@ mod test {
         db
     }
 
+    /// Number of fragments in the database, not counting deltas or month summaries.
+    fn fragment_count(db: &sled::Db) -> usize {
+        db.iter()
+            .keys()
+            .filter(|key| !key.as_ref().unwrap().starts_with(b"a.d") && !key.as_ref().unwrap().starts_with(b"a.m"))
+            .count()
+    }
+
     #[test]
     fn engine_add_remove() {
         let db = dummy_db();
 
-        assert_eq!(db.len(), 1);
+        assert_eq!(fragment_count(&db), 1);
 
         let id_0 = dummy_file(0, 0);
         let id_1 = dummy_file(1, 0);
@@ -534,7 +1795,7 @@ mod test {
             })
             .unwrap();
 
-        assert_eq!(db.len(), 2);
+        assert_eq!(fragment_count(&db), 2);
         let bytes = db.get(Engine::get_id("a", 1)).unwrap().unwrap();
         assert_eq!(&bytes, b"[[0,\"id_0\",40,41],[0,\"id_1\",42,43]]");
 
@@ -550,7 +1811,7 @@ mod test {
             })
             .unwrap();
 
-        assert_eq!(db.len(), 2);
+        assert_eq!(fragment_count(&db), 2);
         let bytes = db.get(Engine::get_id("a", 3)).unwrap().unwrap();
         assert_eq!(&bytes, b"[[0,\"id_1\",42,43]]");
 
@@ -567,11 +1828,378 @@ mod test {
         })
         .unwrap();
 
-        assert_eq!(db.len(), 1);
+        assert_eq!(fragment_count(&db), 1);
         let bytes = db.get(Engine::get_id("a", 5)).unwrap().unwrap();
         assert_eq!(&bytes, b"[]");
     }
 
+    #[test]
+    fn engine_delta() {
+        let db = dummy_db();
+        let fragments: &sled::Tree = &db;
+
+        let id_0 = dummy_file(0, 0);
+        let id_1 = dummy_file(1, 0);
+        let mut album = dummy_album();
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.add("id_0", &id_0)?;
+                e.add("id_1", &id_1)?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let first_head = album.fragment_head;
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.remove("id_0", &id_0)?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let head = album.fragment_head;
+        let section = -19800;
+
+        let json = Engine::fold_deltas(fragments, "a", first_head, head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            delta,
+            Delta {
+                to: head,
+                reset: false,
+                changes: vec![Change::Remove {
+                    section,
+                    order: Order::Number(0),
+                    file_id: "id_0".to_string(),
+                }],
+            }
+        );
+
+        // Both commits fold together, so id_0 is added and then removed again.
+        let json = Engine::fold_deltas(fragments, "a", 0, head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert_eq!(delta.changes.len(), 3);
+        assert!(!delta.reset);
+
+        // Unknown heads can't be folded.
+        let json = Engine::fold_deltas(fragments, "a", head + 1, head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert!(delta.reset);
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.clear_all()?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let json = Engine::fold_deltas(fragments, "a", 0, album.fragment_head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert!(delta.reset);
+    }
+
+    #[test]
+    fn longest_increasing_run() {
+        assert_eq!(longest_increasing(&[]), Vec::<bool>::new());
+        assert_eq!(longest_increasing(&[3, 1, 2]), vec![false, true, true]);
+        assert_eq!(
+            longest_increasing(&[0, 4, 1, 2, 3]),
+            vec![true, false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn month_summaries() {
+        let db = dummy_db();
+        let fragments: &sled::Tree = &db;
+        let mut album = dummy_album();
+
+        // 20:00 on the last day of January in UTC is already February in Kolkata.
+        let days = [0, 1, 40].map(|day| day * 24 * 60 * 60);
+        let night = 30 * 24 * 60 * 60 + 20 * 60 * 60;
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                for (num, ts) in days.iter().enumerate() {
+                    e.add(&format!("id_{}", num), &dummy_file(num as i32, *ts))?;
+                }
+                e.add("id_night", &dummy_file(3, night))?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let months = Engine::months(fragments, "a", album.fragment_head).unwrap();
+        let january = MonthSummary { year: 1970, month: 1, count: 2, min: days[0], max: days[1] };
+        let february = MonthSummary { year: 1970, month: 2, count: 2, min: night, max: days[2] };
+        assert_eq!(months, vec![january, february]);
+
+        // Only February is recounted, and the summaries of the old head are gone.
+        let head = album.fragment_head;
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.remove("id_2", &dummy_file(2, days[2]))?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let months = Engine::months(fragments, "a", album.fragment_head).unwrap();
+        let february = MonthSummary { count: 1, max: night, ..february };
+        assert_eq!(months, vec![january, february]);
+        assert!(db.get(Engine::get_months_id("a", head)).unwrap().is_none());
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.clear_all()?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        assert_eq!(Engine::months(fragments, "a", album.fragment_head).unwrap(), vec![]);
+    }
+
+    /// Commit a file on each of `days` days, so that each lands in its own section.
+    fn album_over_days(db: &sled::Db, days: i64) -> Album<'static> {
+        let album = dummy_album();
+
+        db.transaction(|t| {
+            let mut local_album = album.clone();
+            let mut e = Engine::new("a", &mut local_album, t)?;
+            for day in 0..days {
+                e.add(&format!("id_{}", day), &dummy_file(day as i32, day * 24 * 60 * 60))?;
+            }
+            e.commit()?;
+            Ok(local_album)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn list_file_ids_across_sections() {
+        let db = dummy_db();
+        let album = album_over_days(&db, 3);
+        assert_eq!(album.length, 3);
+        assert_eq!(fragment_count(&db), 4);
+
+        db.transaction(|t| {
+            let mut local_album = album.clone();
+            let mut e = Engine::new("a", &mut local_album, t)?;
+
+            let file_ids: Vec<String> = e.list_file_ids().collect::<Result<_, _>>()?;
+            assert_eq!(file_ids, vec!["id_0", "id_1", "id_2"]);
+
+            // Changes that haven't been committed are listed too.
+            e.remove("id_1", &dummy_file(1, 24 * 60 * 60))?;
+            e.add("id_3", &dummy_file(3, 3 * 24 * 60 * 60))?;
+            let file_ids: Vec<String> = e.list_file_ids().collect::<Result<_, _>>()?;
+            assert_eq!(file_ids, vec!["id_0", "id_2", "id_3"]);
+
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn section_entries_of_some_days() {
+        let db = dummy_db();
+        let album = album_over_days(&db, 3);
+
+        db.transaction(|t| {
+            let mut local_album = album.clone();
+            let mut e = Engine::new("a", &mut local_album, t)?;
+
+            let sections = e.section_entries(|_| true)?;
+            assert_eq!(sections.len(), 3);
+            let second = sections[1].0;
+
+            // Changes that haven't been committed show up too.
+            e.add("id_3", &dummy_file(3, 24 * 60 * 60 + 1))?;
+            let entries = vec![
+                serde_json::json!([24 * 60 * 60, "id_1", 42, 43]),
+                serde_json::json!([24 * 60 * 60 + 1, "id_3", 46, 47]),
+            ];
+            assert_eq!(e.section_entries(|section| section == second)?, vec![(second, entries)]);
+
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn list_file_ids_empty() {
+        let db = dummy_db();
+        let album = dummy_album();
+
+        db.transaction(|t| {
+            let mut local_album = album.clone();
+            let mut e = Engine::new("a", &mut local_album, t)?;
+            assert_eq!(e.list_file_ids().count(), 0);
+
+            // A section that was emptied before the commit has no files either.
+            let file = dummy_file(0, 0);
+            e.add("id_0", &file)?;
+            e.remove("id_0", &file)?;
+            assert_eq!(e.list_file_ids().count(), 0);
+
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn clear_all_sections() {
+        let db = dummy_db();
+        let mut album = album_over_days(&db, 3);
+        let head = album.fragment_head;
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.clear_all()?;
+                assert_eq!(e.list_file_ids().count(), 0);
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        // Only the new, empty top is left.
+        assert_eq!(fragment_count(&db), 1);
+        let bytes = db.get(Engine::get_id("a", album.fragment_head)).unwrap().unwrap();
+        assert_eq!(&bytes, b"[]");
+        assert_eq!(album.length, 0);
+        assert_eq!(album.date_range, None);
+
+        let json = Engine::fold_deltas(&db, "a", head, album.fragment_head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert!(delta.reset);
+    }
+
+    #[test]
+    fn clear_all_empty() {
+        let db = dummy_db();
+        let mut album = dummy_album();
+
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.clear_all()?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        // Clearing always writes a new top, so that clients start over.
+        assert_eq!(album.fragment_head, 1);
+        assert_eq!(fragment_count(&db), 1);
+        assert_eq!(album.length, 0);
+    }
+
+    fn read_top(db: &sled::Db, album: &Album) -> Top {
+        let bytes = db.get(Engine::get_id("a", album.fragment_head)).unwrap().unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn split_and_merge_parts() {
+        let db = dummy_db();
+        let mut album = dummy_album();
+        let section = -19800;
+        let count = MAX_SECTION_LENGTH as i32 + 1;
+
+        // Every file is taken on the same day.
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                for num in 0..count {
+                    e.add(&format!("id_{:04}", num), &dummy_file(num, num as i64))?;
+                }
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let top = read_top(&db, &album);
+        let lengths: Vec<usize> = top.0[&section].iter().map(|details| details.length).collect();
+        assert_eq!(lengths, vec![MAX_SECTION_LENGTH, 1]);
+        assert_eq!(album.length, MAX_SECTION_LENGTH + 1);
+        assert_eq!(fragment_count(&db), 3);
+        let first_part = top.0[&section][0].fragment_id;
+
+        // Appending only rewrites the last part.
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.add("id_last", &dummy_file(count, count as i64))?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let top = read_top(&db, &album);
+        assert_eq!(top.0[&section][0].fragment_id, first_part);
+        assert_eq!(top.0[&section][1].length, 2);
+        assert_eq!(fragment_count(&db), 3);
+
+        // Files are listed in order across parts.
+        db.transaction(|t| {
+            let mut local_album = album.clone();
+            let e = Engine::new("a", &mut local_album, t)?;
+            let file_ids: Vec<String> = e.list_file_ids().collect::<Result<_, _>>()?;
+            assert_eq!(file_ids.len(), MAX_SECTION_LENGTH + 2);
+            assert_eq!(file_ids[0], "id_0000");
+            assert_eq!(file_ids[MAX_SECTION_LENGTH + 1], "id_last");
+            Ok(())
+        })
+        .unwrap();
+
+        // Removing from the first part shifts the rest forward until they fit in one part.
+        let before = album.fragment_head;
+        album = db
+            .transaction(|t| {
+                let mut local_album = album.clone();
+                let mut e = Engine::new("a", &mut local_album, t)?;
+                e.remove("id_0000", &dummy_file(0, 0))?;
+                e.remove("id_0001", &dummy_file(1, 1))?;
+                e.commit()?;
+                Ok(local_album)
+            })
+            .unwrap();
+
+        let top = read_top(&db, &album);
+        assert_eq!(top.0[&section].len(), 1);
+        assert_eq!(top.0[&section][0].length, MAX_SECTION_LENGTH);
+        assert_eq!(album.length, MAX_SECTION_LENGTH);
+        assert_eq!(fragment_count(&db), 2);
+
+        // The delta only describes the removals.
+        let json = Engine::fold_deltas(&db, "a", before, album.fragment_head).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert_eq!(delta.changes.len(), 2);
+    }
+
     #[test]
     fn engine_empty_transaction() {
         let db = dummy_db();
@@ -0,0 +1,154 @@
+//! Fragment Garbage Collection
+//!
+//! Every commit deletes the fragments that it replaces, but fragments can still be left behind by
+//! older versions of the server or by bugs, and nothing would ever reach them again. The
+//! collector walks the fragments of every album and removes those that neither the top at the
+//! album's head nor the top of a rebuild underway refers to. It runs when the server starts and
+//! every `COLLECT_INTERVAL` after that. Each album is collected in a transaction that reads its
+//! tops, so commits that happen in the meantime are never undone.
+//!
+//! Deltas are left to `Engine::prune_deltas`, and the fragments of trashed albums are left alone
+//! until the album is purged.
+
+use super::{engine::Engine, rebuild};
+use crate::{common::AppState, error::ApiResult};
+use chrono::offset::Utc;
+use sled::Transactional;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{task::block_in_place, time};
+use tracing::{info, warn};
+use wire::{Album, GcStatus};
+
+/// How often unreachable fragments are looked for.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Clone, Default)]
+pub struct Collector {
+    status: Arc<Mutex<GcStatus>>,
+}
+
+impl Collector {
+    pub fn status(&self) -> GcStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Remove the unreachable fragments of a single album, returning how many there were.
+fn collect_album(state: &AppState, album_id: &str) -> ApiResult<usize> {
+    let AppState {
+        ref albums,
+        ref fragments,
+        ref rebuilds,
+        ..
+    } = state;
+
+    let mut fragment_ids = vec![];
+    for key in fragments.scan_prefix([album_id, "."].concat()).keys() {
+        if let Some(fragment_id) = Engine::parse_id(album_id, &key?) {
+            fragment_ids.push(fragment_id);
+        }
+    }
+
+    let reclaimed = (albums, fragments, rebuilds).transaction(|(albums, fragments, rebuilds)| {
+        let album_bytes = match albums.get(album_id)? {
+            Some(album_bytes) => album_bytes,
+            None => return Ok(0),
+        };
+        let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        let mut reachable = Engine::reachable(album_id, album.fragment_head, fragments)?;
+        if let Some(staged_head) = rebuild::staged_head(album_id, rebuilds)? {
+            reachable.extend(Engine::reachable(album_id, staged_head, fragments)?);
+        }
+
+        let mut reclaimed = 0;
+        for fragment_id in &fragment_ids {
+            if !reachable.contains(fragment_id)
+                && fragments.remove(Engine::get_id(album_id, *fragment_id))?.is_some()
+            {
+                reclaimed += 1;
+            }
+        }
+
+        Ok(reclaimed)
+    })?;
+
+    Ok(reclaimed)
+}
+
+/// Collect every album, returning how many fragments were removed.
+pub fn collect(state: &AppState) -> ApiResult<usize> {
+    let mut albums = 0;
+    let mut reclaimed = 0;
+
+    for album_id in state.albums.iter().keys() {
+        let album_id = album_id?;
+        reclaimed += collect_album(state, std::str::from_utf8(&album_id).unwrap())?;
+        albums += 1;
+    }
+
+    let mut status = state.collector.status.lock().unwrap();
+    status.runs += 1;
+    status.last_run = Some(Utc::now().timestamp());
+    status.albums = albums;
+    status.reclaimed = reclaimed;
+    status.total_reclaimed += reclaimed as u64;
+
+    Ok(reclaimed)
+}
+
+/// Collect periodically for as long as the server runs, starting right away.
+pub async fn collector(state: AppState) {
+    let mut interval = time::interval(COLLECT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match block_in_place(|| collect(&state)) {
+            Ok(0) => {}
+            Ok(reclaimed) => info!("Removed {} unreachable album fragments", reclaimed),
+            Err(err) => warn!(error = %err.chain(), "Couldn't collect album fragments"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collect_fragments() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "photo.png", png(8, 8)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files = json!({ "ids": [file_id] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, key), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let orphan = crate::album::engine::Engine::get_id(album_id, 1 << 20);
+        server.state.fragments.insert(&orphan, b"[]".to_vec()).unwrap();
+        let live = server.state.fragments.len() - 1;
+
+        assert_eq!(crate::album::gc::collect(&server.state).unwrap(), 1);
+        assert!(server.state.fragments.get(&orphan).unwrap().is_none());
+        assert_eq!(server.state.fragments.len(), live);
+
+        // Nothing is left to collect, and the album is served as before.
+        assert_eq!(crate::album::gc::collect(&server.state).unwrap(), 0);
+        let path = format!("/album/{}/serve/metadata?key={}", album_id, key);
+        let metadata = server.json(Method::GET, &path, &()).await;
+        assert_eq!(metadata["length"], 1);
+        assert_eq!(server.state.collector.status().total_reclaimed, 1);
+    }
+}
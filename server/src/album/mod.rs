@@ -1,26 +1,69 @@
+mod activity;
+mod bundle;
 mod share;
+pub mod bulk;
 pub mod engine;
+pub mod gc;
+pub mod rebuild;
+pub mod rules;
 
 
 use crate::{
-    delete,
+    events, geo, guest, jobs, trash,
     common::{
-        join, new_id, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, File,
+        etag_matches, join, new_id, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, File,
     },
+    endpoint::EndpointExt,
     error::{ApiError, ApiResult},
 };
 use engine::Engine;
 use std::collections::HashMap;
-use chrono::offset::Utc;
+use chrono::{offset::Utc, DateTime, Datelike, NaiveDate, TimeZone};
+use hyper::http::request::Parts;
 use hyper::{header, Body, Request, Response, StatusCode};
 use routerify::{ext::RequestExt, Router};
-use share::test_user_can_write;
+use routerify_query::RequestQueryExt;
+use share::{test_user_can_contribute, test_user_can_write};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
 use sled::Transactional;
 use std::borrow::Cow;
+use tokio::sync::mpsc;
 use tokio::task::block_in_place;
-use wire::{Album, AlbumSettings, IdList, NewResource, Role};
+use wire::{
+    endpoint, ActivityEvent, Album, AlbumFilter, AlbumInfo, AlbumListing, AlbumOrder, AlbumPrivacy, AlbumSettings,
+    Caption, IdList, IntoOwned, ListedAlbum, NewResource, Role, SortMode, TopEntry,
+};
 
 const ALBUM_ID_BYTES: usize = 16;
+/// Most files that can be moved at once, since they are all moved in one transaction.
+const MAX_MOVED_FILES: usize = 1000;
+
+/// Album metadata as it is sent to a user with `role`.
+/// Entity tag for album metadata. The fragment head changes with every change to the album.
+fn album_etag(album: &Album, role: Role) -> String {
+    format!("\"{}-{:?}\"", album.fragment_head, role)
+}
+
+/// Whether the client's cached copy of the metadata is still current.
+fn not_modified(parts: &Parts, etag: &str, last_update: i64) -> bool {
+    // If-None-Match takes precedence when both are present.
+    if parts.headers.contains_key(header::IF_NONE_MATCH) {
+        return etag_matches(parts, etag);
+    }
+
+    if let Some(if_modified_since) = parts.headers.get(header::IF_MODIFIED_SINCE) {
+        let since = if_modified_since
+            .to_str()
+            .ok()
+            .and_then(|s| DateTime::parse_from_rfc2822(s).ok());
+
+        if let Some(since) = since {
+            return last_update <= since.timestamp();
+        }
+    }
+
+    false
+}
 
 async fn create(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, body) = req.into_parts();
@@ -28,54 +71,61 @@ async fn create(req: Request<Body>) -> ApiResult<Response<Body>> {
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: AlbumSettings = serde_json::from_slice(&entire_body)?;
+    rules::validate(&json.rules)?;
 
     block_in_place(|| {
-        let AppState {
-            ref sessions,
-            ref users,
-            ref albums,
-            ref fragments,
-            ref user_to_album,
-            ref album_to_user,
-            ..
-        } = parts.data().unwrap();
+        let state: &AppState = parts.data().unwrap();
+        test_logged_in(&state.sessions, key)?;
 
-        let album_id = new_id(ALBUM_ID_BYTES);
-        let album = Album {
-            description: json,
-            fragment_head: 0,
-            length: 0,
-            last_update: Utc::now().timestamp(),
-            date_range: None,
-        };
+        let album_id = insert(state, user_id, json)?;
+        respond_ok(NewResource {
+            id: Cow::from(album_id),
+        })
+    })
+}
 
-        test_logged_in(sessions, key)?;
+/// Record a new album that `user_id` owns, returning its id.
+fn insert(state: &AppState, user_id: &str, settings: AlbumSettings) -> ApiResult<String> {
+    let AppState {
+        ref users,
+        ref albums,
+        ref fragments,
+        ref user_to_album,
+        ref album_to_user,
+        ..
+    } = state;
+
+    let album_id = new_id(ALBUM_ID_BYTES);
+    let album = Album {
+        description: settings,
+        fragment_head: 0,
+        length: 0,
+        last_update: Utc::now().timestamp(),
+        date_range: None,
+    };
 
-        (users, albums, fragments, user_to_album, album_to_user).transaction(
-            |(users, albums, fragments, user_to_album, album_to_user)| {
-                users
-                    .get(user_id.as_bytes())?
-                    .ok_or(ApiError::Unauthorized)?;
+    (users, albums, fragments, user_to_album, album_to_user).transaction(
+        |(users, albums, fragments, user_to_album, album_to_user)| {
+            users
+                .get(user_id.as_bytes())?
+                .ok_or(ApiError::Unauthorized)?;
 
-                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
-                Engine::empty(&album_id, fragments)?;
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+            Engine::empty(&album_id, fragments)?;
 
-                let role = Role::Owner;
-                let role_bytes = bincode::serialize(&role).unwrap();
+            let role = Role::Owner;
+            let role_bytes = bincode::serialize(&role).unwrap();
 
-                user_to_album.insert([user_id, ".", &album_id].concat().as_bytes(), role_bytes)?;
-                album_to_user.insert([&album_id, ".", user_id].concat().as_bytes(), b"")?;
+            user_to_album.insert([user_id, ".", &album_id].concat().as_bytes(), role_bytes)?;
+            album_to_user.insert([&album_id, ".", user_id].concat().as_bytes(), b"")?;
 
-                Ok(())
-            },
-        )?;
+            Ok(())
+        },
+    )?;
 
-        respond_ok(NewResource {
-            id: Cow::from(album_id),
-        })
-    })
+    Ok(album_id)
 }
 
 async fn update(req: Request<Body>) -> ApiResult<Response<Body>> {
@@ -84,131 +134,213 @@ async fn update(req: Request<Body>) -> ApiResult<Response<Body>> {
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: AlbumSettings = serde_json::from_slice(&entire_body)?;
+    rules::validate(&json.rules)?;
 
     block_in_place(|| {
+        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref user_to_album,
             ref albums,
             ref fragments,
-            ref files,
+            ref activity,
+            ref rebuilds,
+            ref jobs,
             ..
-        } = parts.data().unwrap();
+        } = state;
 
         test_logged_in(sessions, key)?;
 
         let album_id = parts.param("albumId").unwrap();
+        let job_id = state.db.generate_id()?;
 
-        (albums, fragments, files, user_to_album).transaction(
-            |(albums, fragments, files, user_to_album)| {
+        let (fragment_head, rebuilding) = (albums, fragments, user_to_album, activity, rebuilds, jobs).transaction(
+            |(albums, fragments, user_to_album, activity, rebuilds, jobs)| {
                 test_user_can_write(user_to_album, user_id, album_id)?;
 
                 let prev_album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
                 let mut album: Album = bincode::deserialize(&prev_album_bytes).unwrap();
 
-                if album.description.time_zone != json.time_zone {
-                    let mut e = Engine::new(album_id, &mut album, fragments)?;
+                // Sections depend on both the time zone and the sort mode, so the album keeps the
+                // old ones until a job has placed every file again.
+                let rebuilding = rebuild::start(album_id, &album, json.time_zone, json.sort, fragments, rebuilds)?;
+                if rebuilding {
+                    jobs::record(jobs, job_id, &jobs::Job::Rebuild(Cow::Borrowed(album_id)))?;
+                }
 
-                    let file_ids = e.list_file_ids()?;
-                    e.clear_all()?;
+                let mut description = json.clone();
+                description.time_zone = album.description.time_zone;
+                description.sort = album.description.sort;
+                album.description = description;
 
-                    for file_id in file_ids {
-                        if let Some(file_bytes) = files.get(&file_id)? {
-                            let file: File = bincode::deserialize(&file_bytes).unwrap();
-                            e.add(&file_id, &file)?;
-                        }
-                    }
-
-                    e.commit()?;
-                }
+                // Always write a new top so that the change shows up as a new fragment head.
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
+                e.touch();
+                e.commit()?;
 
-                album.description = json.clone();
                 let album_bytes = bincode::serialize(&album).unwrap();
 
                 albums.insert(album_id.as_bytes(), album_bytes)?.unwrap();
 
-                Ok(())
+                activity::record(activity, album_id, user_id, ActivityEvent::SettingsChanged)?;
+
+                Ok((album.fragment_head, rebuilding))
             },
         )?;
 
+        if rebuilding {
+            jobs::wake(state);
+        }
+        events::album_updated(state, album_id, fragment_head);
+
         respond_ok_empty()
     })
 }
 
-async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
+async fn privacy(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, _) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
     block_in_place(|| {
-        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref user_to_album,
+            ref stripped_albums,
             ..
-        } = state;
+        } = parts.data().unwrap();
+
+        let album_id = parts.param("albumId").unwrap();
 
         test_logged_in(sessions, key)?;
-        
+        user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+
+        respond_ok(AlbumPrivacy {
+            strip_metadata: stripped_albums.contains_key(album_id)?,
+        })
+    })
+}
+
+/// Only the owner decides what the other members can see of the files in an album.
+async fn set_privacy(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: AlbumPrivacy = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref stripped_albums,
+            ..
+        } = parts.data().unwrap();
+
         let album_id = parts.param("albumId").unwrap();
 
-        // The album may actually transfer here and end up being deleted
-        // after the transfer. This is okay because it preserves the database
-        // invariants even if it may look strange to the end user.
-        let user_bytes = user_to_album
+        test_logged_in(sessions, key)?;
+        let role_bytes = user_to_album
             .get([user_id, ".", album_id].concat())?
             .ok_or(ApiError::Unauthorized)?;
-        let user_role: Role = bincode::deserialize(&user_bytes).unwrap();
-        if !user_role.is_owner() {
+        let role: Role = bincode::deserialize(&role_bytes).unwrap();
+        if !matches!(role, Role::Owner) {
             return Err(ApiError::Unauthorized);
         }
 
-        delete::Command::Album(album_id).run(state)?;
+        if json.strip_metadata {
+            stripped_albums.insert(album_id, &[])?;
+        } else {
+            stripped_albums.remove(album_id)?;
+        }
+
+        respond_ok_empty()
+    })
+}
+
+async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState { ref sessions, .. } = state;
+
+        test_logged_in(sessions, key)?;
+
+        let album_id = parts.param("albumId").unwrap();
+        trash::move_album(state, user_id, album_id)?;
 
         respond_ok_empty()
     })
 }
 
+/// Read the `role` query of a listing.
+fn album_filter(role: Option<&str>) -> ApiResult<AlbumFilter> {
+    match role {
+        None | Some("all") => Ok(AlbumFilter::All),
+        Some("owner") => Ok(AlbumFilter::Owner),
+        Some("shared") => Ok(AlbumFilter::Shared),
+        Some(_) => Err(ApiError::BadRequest),
+    }
+}
+
+/// Every album that `user_id` can see, by id.
+fn visible_albums(state: &AppState, user_id: &str) -> ApiResult<Vec<(String, AlbumInfo<'static>)>> {
+    let AppState {
+        ref user_to_album,
+        ref albums,
+        ref rebuilds,
+        ..
+    } = state;
+
+    let prefix = [user_id, "."].concat();
+    let mut visible = vec![];
+
+    for entry in user_to_album.scan_prefix(&prefix) {
+        let (key, role_bytes) = entry?;
+        let album_id = std::str::from_utf8(&key[prefix.len()..]).unwrap();
+
+        let role: Role = bincode::deserialize(&role_bytes).unwrap();
+
+        if let Some(album_bytes) = albums.get(album_id)? {
+            let album = bincode::deserialize::<Album>(&album_bytes).unwrap().into_owned();
+            let rebuilding = rebuild::is_rebuilding(rebuilds, album_id)?;
+            let info = AlbumInfo { album, role, rebuilding, sections: None, months: None };
+            visible.push((album_id.to_string(), info));
+        }
+    }
+
+    Ok(visible)
+}
+
 async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let filter = album_filter(req.query("role").map(String::as_str))?;
     let (parts, _) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
     block_in_place(|| {
-        let AppState {
-            ref sessions,
-            ref user_to_album,
-            ref albums,
-            ..
-        } = parts.data().unwrap();
+        let state = parts.data().unwrap();
+        let AppState { ref sessions, .. } = state;
 
         test_logged_in(sessions, key)?;
 
         let mut album_pairs = HashMap::new();
 
-        for entry in user_to_album.scan_prefix(&user_id) {
-            let (key, role_bytes) = entry?;
-            let (_, album_id) = std::str::from_utf8(&key)
-                .unwrap()
-                .split_once('.')
-                .unwrap();
-
-            let role: Role = bincode::deserialize(&role_bytes).unwrap();
-
-            if let Some(album_bytes) = albums.get(&album_id)? {
-                let album: Album = bincode::deserialize(&album_bytes).unwrap();
-                let mut value = serde_json::to_value(album)?;
-                if let serde_json::Value::Object(ref mut map) = value {
-                    map.insert("role".to_string(), serde_json::to_value(role)?);
-                } else {
-                    panic!("Expected album to be a json object");
-                }
-                
-                album_pairs.insert(album_id.to_string(), value);
+        for (album_id, info) in visible_albums(state, user_id)? {
+            if filter.keeps(info.role) {
+                album_pairs.insert(album_id, serde_json::to_value(info)?);
             }
         }
 
@@ -216,16 +348,143 @@ async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
     })
 }
 
+/// Like `list`, but in order and with the number of albums that the user owns and that are shared
+/// with them, so that clients can show each in a tab of its own.
+async fn browse(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let filter = album_filter(req.query("role").map(String::as_str))?;
+    let order = match req.query("sort").map(String::as_str) {
+        None | Some("last_update") => AlbumOrder::LastUpdate,
+        Some("name") => AlbumOrder::Name,
+        Some("length") => AlbumOrder::Length,
+        Some(_) => return Err(ApiError::BadRequest),
+    };
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState { ref sessions, .. } = state;
+
+        test_logged_in(sessions, key)?;
+
+        let visible = visible_albums(state, user_id)?;
+        let owned = visible.iter().filter(|(_, info)| AlbumFilter::Owner.keeps(info.role)).count();
+        let shared = visible.len() - owned;
+
+        let mut albums: Vec<_> = visible
+            .into_iter()
+            .filter(|(_, info)| filter.keeps(info.role))
+            .map(|(album_id, info)| ListedAlbum {
+                id: Cow::from(album_id),
+                info,
+            })
+            .collect();
+
+        // Ties are broken by id, so that pages of a client's view don't shuffle.
+        match order {
+            AlbumOrder::LastUpdate => {
+                albums.sort_by(|a, b| (b.info.album.last_update, &a.id).cmp(&(a.info.album.last_update, &b.id)))
+            }
+            AlbumOrder::Name => albums.sort_by_cached_key(|listed| {
+                (listed.info.album.description.name.to_lowercase(), listed.id.clone())
+            }),
+            AlbumOrder::Length => {
+                albums.sort_by(|a, b| (b.info.album.length, &a.id).cmp(&(a.info.album.length, &b.id)))
+            }
+        }
+
+        respond_ok(AlbumListing { albums, owned, shared })
+    })
+}
+
 async fn add_remove(req: Request<Body>, add: bool) -> ApiResult<Response<Body>> {
     let (parts, body) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: IdList = serde_json::from_slice(&entire_body)?;
+    let ids: Vec<String> = json.ids.iter().map(|id| id.to_string()).collect();
+
+    let progress = block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref files,
+            ref user_to_album,
+            ref bulk,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        // Reject requests that are bound to fail before anything is committed. The worker checks
+        // again for each batch.
+        (user_to_album, files).transaction(|(user_to_album, files)| {
+            let role = test_user_can_contribute(user_to_album, user_id, album_id)?;
+
+            // Files can only be added by their owners, unless they are locked, and contributors can
+            // only remove their own.
+            if add || !role.can_write() {
+                for file_id in &ids {
+                    let file_bytes = match files.get(file_id)? {
+                        Some(file_bytes) => file_bytes,
+                        None if !add => continue,
+                        None => return Err(ApiError::Unauthorized.into()),
+                    };
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                    if file.owner_id != user_id || (add && file.locked) {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+                }
+            }
+
+            Ok(())
+        })?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        bulk.submit(bulk::Job {
+            trees: bulk::Trees::new(state),
+            album_id: album_id.to_string(),
+            user_id: user_id.to_string(),
+            add,
+            ids,
+            progress: sender,
+        })?;
+
+        Ok::<_, ApiError>(receiver)
+    })?;
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .status(StatusCode::OK)
+        .body(Body::wrap_stream(bulk::progress_stream(progress)))
+        .unwrap())
+}
+
+/// Move files from one album to another, which the user has to be able to contribute to both of.
+/// Files that aren't in the first album are still added to the second, so that a move that was
+/// cut off can be sent again.
+async fn move_files(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: IdList = serde_json::from_slice(&entire_body)?;
+    if json.ids.len() > MAX_MOVED_FILES {
+        return Err(ApiError::BadRequest);
+    }
 
     block_in_place(|| {
+        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref albums,
@@ -233,58 +492,208 @@ async fn add_remove(req: Request<Body>, add: bool) -> ApiResult<Response<Body>>
             ref inclusions,
             ref fragments,
             ref user_to_album,
+            ref activity,
             ..
-        } = parts.data().unwrap();
+        } = state;
+
+        let from_id = parts.param("fromId").unwrap();
+        let to_id = parts.param("toId").unwrap();
+        if from_id == to_id {
+            return Err(ApiError::BadRequest);
+        }
+
+        test_logged_in(sessions, key)?;
+
+        let trees = (albums, files, inclusions, fragments, user_to_album, activity);
+        let (from_head, to_head) = trees.transaction(
+            |(albums, files, inclusions, fragments, user_to_album, activity)| {
+                test_user_can_contribute(user_to_album, user_id, from_id)?;
+                test_user_can_contribute(user_to_album, user_id, to_id)?;
+
+                let from_bytes = albums.get(from_id)?.ok_or(ApiError::Unauthorized)?;
+                let mut from: Album = bincode::deserialize(&from_bytes).unwrap();
+                let to_bytes = albums.get(to_id)?.ok_or(ApiError::Unauthorized)?;
+                let mut to: Album = bincode::deserialize(&to_bytes).unwrap();
+
+                let mut removed = vec![];
+                let mut added = vec![];
+
+                let mut from_engine = Engine::new(from_id, &mut from, fragments)?;
+                let mut to_engine = Engine::new(to_id, &mut to, fragments)?;
+                for file_id in json.ids.iter().map(|id| id.as_ref()) {
+                    let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::Unauthorized)?;
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                    // Only owners can add files, and locked files stay out of albums.
+                    if file.owner_id != user_id || file.locked {
+                        return Err(ApiError::Unauthorized.into());
+                    }
+
+                    let mut caption = None;
+                    if inclusions.remove([file_id, ".", from_id].concat().as_bytes())?.is_some() {
+                        caption = from_engine.take(file_id, &file)?;
+                        removed.push(file_id.to_string());
+                    }
+
+                    if inclusions.insert([file_id, ".", to_id].concat().as_bytes(), b"")?.is_none() {
+                        to_engine.add(file_id, &file)?;
+                        if caption.is_some() {
+                            to_engine.set_caption(file_id, &file, caption)?;
+                        }
+                        added.push(file_id.to_string());
+                    }
+                }
+                from_engine.commit()?;
+                to_engine.commit()?;
+
+                albums.insert(from_id.as_bytes(), bincode::serialize(&from).unwrap())?;
+                albums.insert(to_id.as_bytes(), bincode::serialize(&to).unwrap())?;
+
+                if !removed.is_empty() {
+                    activity::record(activity, from_id, user_id, ActivityEvent::FilesRemoved(removed))?;
+                }
+                if !added.is_empty() {
+                    activity::record(activity, to_id, user_id, ActivityEvent::FilesAdded(added))?;
+                }
+
+                Ok((from.fragment_head, to.fragment_head))
+            },
+        )?;
+
+        events::album_updated(state, from_id, from_head);
+        events::album_updated(state, to_id, to_head);
+
+        respond_ok_empty()
+    })
+}
+
+async fn reorder(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: IdList = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref fragments,
+            ref user_to_album,
+            ..
+        } = state;
 
         let album_id = parts.param("albumId").unwrap();
 
         test_logged_in(sessions, key)?;
 
-        (albums, inclusions, fragments, files, user_to_album).transaction(
-            |(albums, inclusions, fragments, files, user_to_album)| {
-                test_user_can_write(user_to_album, user_id, album_id)?;
+        let fragment_head = (albums, fragments, user_to_album).transaction(|(albums, fragments, user_to_album)| {
+            test_user_can_write(user_to_album, user_id, album_id)?;
 
-                let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
-                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+            let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
 
-                let mut e = Engine::new(&album_id, &mut album, fragments)?;
-                for file_id in &json.ids {
-                    if add {
-                        let file_bytes = files.get(&**file_id)?.ok_or(ApiError::Unauthorized)?;
-                        let file: File = bincode::deserialize(&file_bytes).unwrap();
+            let mut e = Engine::new(album_id, &mut album, fragments)?;
+            e.reorder(&json.ids)?;
+            e.commit()?;
 
-                        if file.owner_id != user_id {
-                            return Err(ApiError::Unauthorized.into());
-                        }
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+
+            Ok(album.fragment_head)
+        })?;
+
+        events::album_updated(state, album_id, fragment_head);
+
+        respond_ok_empty()
+    })
+}
 
-                        let inclusion = [file_id, ".", album_id].concat();
-                        inclusions.insert(inclusion.as_bytes(), b"")?;
+async fn caption(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-                        e.add(file_id, &file)?;
-                    } else if let Some(file_bytes) = files.get(&**file_id)? {
-                        let file: File = bincode::deserialize(&file_bytes).unwrap();
+    let entire_body = join(&parts, body).await?;
+    let json: Caption = serde_json::from_slice(&entire_body)?;
 
-                        let inclusion = [file_id, ".", album_id].concat();
-                        inclusions.remove(inclusion.as_bytes())?;
+    // An empty caption is the same as no caption.
+    let text = json.text.filter(|text| !text.is_empty()).map(Cow::into_owned);
 
-                        e.remove(file_id, &file)?;
-                    }
-                }
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref files,
+            ref fragments,
+            ref user_to_album,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+        let file_id = parts.param("fileId").unwrap();
+
+        test_logged_in(sessions, key)?;
 
+        let fragment_head = (albums, files, fragments, user_to_album).transaction(
+            |(albums, files, fragments, user_to_album)| {
+                test_user_can_write(user_to_album, user_id, album_id)?;
+
+                let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                let file_bytes = files.get(file_id)?.ok_or(ApiError::NotFound)?;
+                let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
+                e.set_caption(file_id, &file, text.clone())?;
                 e.commit()?;
 
-                let album_bytes = bincode::serialize(&mut album).unwrap();
-                albums.insert(album_id.as_bytes(), album_bytes)?;
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
 
-                Ok(())
+                Ok(album.fragment_head)
             },
         )?;
 
+        events::album_updated(state, album_id, fragment_head);
+
         respond_ok_empty()
     })
 }
 
+/// The `from` and `to` queries of a metadata request, either of which may be left out.
+fn date_range_query(req: &Request<Body>) -> ApiResult<(Option<i64>, Option<i64>)> {
+    let query = |name| {
+        req.query(name)
+            .map(|ts| match ts.parse() {
+                Ok(ts) if DateTime::<Utc>::from_timestamp(ts, 0).is_some() => Ok(ts),
+                _ => Err(ApiError::BadRequest),
+            })
+            .transpose()
+    };
+
+    Ok((query("from")?, query("to")?))
+}
+
+/// Sections that the top fragment of an album lists, which are only dated in albums sorted by a
+/// date.
+fn dated_sections(fragments: &sled::Tree, album_id: &str, album: &Album) -> ApiResult<Vec<TopEntry>> {
+    if !matches!(album.description.sort, SortMode::CaptureDate | SortMode::UploadDate) {
+        return Err(ApiError::BadRequest);
+    }
+
+    match fragments.get(Engine::get_id(album_id, album.fragment_head))? {
+        Some(top) => Ok(serde_json::from_slice(&top)?),
+        None => Ok(vec![]),
+    }
+}
+
 async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (from, to) = date_range_query(&req)?;
     let (parts, _) = req.into_parts();
 
     let key = require_key(&parts)?;
@@ -302,6 +711,7 @@ async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
             ref albums,
             ref fragments,
             ref user_to_album,
+            ref rebuilds,
             ..
         } = parts.data().unwrap();
 
@@ -313,11 +723,13 @@ async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
         let role: Role = bincode::deserialize(&role_bytes).unwrap();
 
         if let Some(fragment_id) = fragment_id {
-            let id = Engine::get_id(&album_id, fragment_id);
+            let id = Engine::get_id(album_id, fragment_id);
             let fragment = fragments.get(id)?.ok_or(ApiError::NotFound)?;
 
             Ok(Response::builder()
                 .header(header::CONTENT_TYPE, "application/json")
+                // Fragments never change once they are written.
+                .header(header::CACHE_CONTROL, "private, max-age=31536000, immutable")
                 .status(StatusCode::OK)
                 .body(Body::from(Vec::from(fragment.as_ref())))
                 .unwrap())
@@ -325,28 +737,450 @@ async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
             let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
             let album: Album = bincode::deserialize(&album_bytes).unwrap();
 
-            let mut value = serde_json::to_value(album)?;
-            if let serde_json::Value::Object(ref mut map) = value {
-                map.insert("role".to_string(), serde_json::to_value(role)?);
-            } else {
-                panic!("Expected album to be a json object");
+            let etag = album_etag(&album, role);
+            let last_modified = Utc
+                .timestamp_opt(album.last_update, 0)
+                .unwrap()
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string();
+
+            let response = Response::builder()
+                .header(header::ETAG, &etag)
+                .header(header::LAST_MODIFIED, last_modified)
+                .header(header::CACHE_CONTROL, "private, no-cache");
+
+            if not_modified(&parts, &etag, album.last_update) {
+                return Ok(response
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .unwrap());
             }
 
-            respond_ok(value)
+            let rebuilding = rebuild::is_rebuilding(rebuilds, album_id)?;
+            // Sections are keyed by the start of their day, so one that starts before `from` can
+            // still have files after it.
+            let sections = if from.is_some() || to.is_some() {
+                let time_zone = album.description.time_zone;
+                let within = |entry: &TopEntry| {
+                    from.is_none_or(|from| entry.section >= Engine::day(time_zone, from))
+                        && to.is_none_or(|to| entry.section <= to)
+                };
+                Some(dated_sections(fragments, album_id, &album)?.into_iter().filter(within).collect())
+            } else {
+                None
+            };
+
+            let months = match album.description.sort {
+                SortMode::CaptureDate | SortMode::UploadDate => {
+                    Some(Engine::months(fragments, album_id, album.fragment_head)?)
+                }
+                _ => None,
+            };
+
+            let json = serde_json::to_string(&AlbumInfo { album, role, rebuilding, sections, months })?;
+
+            Ok(response
+                .header(header::CONTENT_TYPE, "application/json")
+                .status(StatusCode::OK)
+                .body(Body::from(json))
+                .unwrap())
         }
     })
 }
 
+/// Keys of the sections of an album that are in a month, which lets a timeline only load the
+/// months that are visible.
+async fn month(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let album_id = parts.param("albumId").unwrap();
+    let first = NaiveDate::parse_from_str(&format!("{}-01", parts.param("month").unwrap()), "%Y-%m-%d")
+        .map_err(|_| ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref fragments,
+            ref user_to_album,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+
+        let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+        let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        let time_zone = album.description.time_zone;
+        let in_month = |section: i64| match time_zone.timestamp_opt(section, 0).single() {
+            Some(day) => (day.year(), day.month()) == (first.year(), first.month()),
+            None => false,
+        };
+
+        // Long sections are listed once for each of their parts.
+        let mut sections: Vec<i64> = dated_sections(fragments, album_id, &album)?
+            .into_iter()
+            .map(|entry| entry.section)
+            .filter(|&section| in_month(section))
+            .collect();
+        sections.dedup();
+
+        respond_ok(sections)
+    })
+}
+
+/// Changes to an album since `fromHead`, folded into a single delta.
+async fn delta(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let album_id = parts.param("albumId").unwrap();
+    let from_head: u64 = parts
+        .param("fromHead")
+        .unwrap()
+        .parse()
+        .map_err(|_| ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref fragments,
+            ref user_to_album,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+
+        let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+        let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        let json = Engine::fold_deltas(fragments, album_id, from_head, album.fragment_head)?;
+        Engine::prune_deltas(fragments, album_id)?;
+
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .status(StatusCode::OK)
+            .body(Body::from(json))
+            .unwrap())
+    })
+}
+
+/// Turn away an upload into an album that the user can't add files to before it is received.
+/// `add_upload` checks again once the file is stored.
+pub fn test_can_upload(user_to_album: &sled::Tree, user_id: &str, album_id: &str) -> ApiResult<()> {
+    user_to_album.transaction(|user_to_album| test_user_can_contribute(user_to_album, user_id, album_id))?;
+    Ok(())
+}
+
+/// Add a file to an album as part of the transaction that records the upload, so that it can't
+/// be left out of the album, or because it matches a rule of the album. Takes the `albums`,
+/// `inclusions`, `fragments`, `user_to_album` and `activity` trees, and returns the new fragment
+/// head unless the file was already in the album, which it can be when the upload stood in for
+/// an existing file.
+pub fn add_upload(
+    (albums, inclusions, fragments, user_to_album, activity): (
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+    ),
+    album_id: &str,
+    user_id: &str,
+    file_id: &str,
+    file: &File,
+) -> ConflictableTransactionResult<Option<u64>, ApiError> {
+    test_user_can_contribute(user_to_album, user_id, album_id)?;
+
+    let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+    let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+    if inclusions.insert([file_id, ".", album_id].concat().as_bytes(), b"")?.is_some() {
+        return Ok(None);
+    }
+
+    let mut e = Engine::new(album_id, &mut album, fragments)?;
+    e.add(file_id, file)?;
+    e.commit()?;
+
+    albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+
+    let event = ActivityEvent::FilesAdded(vec![file_id.to_string()]);
+    activity::record(activity, album_id, user_id, event)?;
+
+    Ok(Some(album.fragment_head))
+}
+
+/// Trim the activity feeds and deltas of every album.
+pub fn clean(state: &AppState) -> ApiResult<()> {
+    for entry in state.albums.iter() {
+        let (album_id, _) = entry?;
+        let album_id = std::str::from_utf8(&album_id).unwrap();
+
+        activity::prune(state, album_id)?;
+        Engine::prune_deltas(&state.fragments, album_id)?;
+    }
+
+    Ok(())
+}
+
+/// Takes a map from album id to the fragment head that the client knows about, and returns the
+/// metadata of the albums whose head has moved on. Albums that the user can no longer see map to
+/// `null`.
+async fn changed(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: HashMap<String, u64> = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref user_to_album,
+            ref rebuilds,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let mut changed = HashMap::new();
+
+        for (album_id, known_head) in json {
+            let role_bytes = user_to_album.get([user_id, ".", &album_id].concat())?;
+            let album_bytes = albums.get(&album_id)?;
+
+            match (role_bytes, album_bytes) {
+                (Some(role_bytes), Some(album_bytes)) => {
+                    let role: Role = bincode::deserialize(&role_bytes).unwrap();
+                    let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                    if album.fragment_head != known_head {
+                        let rebuilding = rebuild::is_rebuilding(rebuilds, &album_id)?;
+                        let info = AlbumInfo { album, role, rebuilding, sections: None, months: None };
+                        changed.insert(album_id, Some(serde_json::to_value(info)?));
+                    }
+                }
+                _ => {
+                    changed.insert(album_id, None);
+                }
+            }
+        }
+
+        respond_ok(changed)
+    })
+}
+
+pub const SCOPE: &str = "/album";
+
 pub fn router() -> Router<Body, ApiError> {
     Router::builder()
-        .post("/", create)
-        .get("/", list)
-        .delete("/:albumId", delete)
-        .patch("/:albumId", update)
-        .post("/:albumId/files", |req| add_remove(req, true))
-        .delete("/:albumId/files", |req| add_remove(req, false))
-        .get("/:albumId/serve/:fragmentId", serve)
-        .scope("/:albumId/share", share::router())
+        .endpoint(SCOPE, endpoint::CreateAlbum, create)
+        .endpoint(SCOPE, endpoint::ListAlbums, list)
+        .endpoint(SCOPE, endpoint::BrowseAlbums, browse)
+        .endpoint(SCOPE, endpoint::ChangedAlbums, changed)
+        .endpoint(SCOPE, endpoint::DeleteAlbum, delete)
+        .endpoint(SCOPE, endpoint::UpdateAlbum, update)
+        .endpoint(SCOPE, endpoint::GetAlbumPrivacy, privacy)
+        .endpoint(SCOPE, endpoint::SetAlbumPrivacy, set_privacy)
+        .endpoint(SCOPE, endpoint::ListContributionLinks, guest::list_links)
+        .endpoint(SCOPE, endpoint::CreateContributionLink, guest::create_link)
+        .endpoint(SCOPE, endpoint::DeleteContributionLink, guest::delete_link)
+        .endpoint(SCOPE, endpoint::ListPendingUploads, guest::list_pending)
+        .endpoint(SCOPE, endpoint::ApproveUploads, guest::approve)
+        .endpoint(SCOPE, endpoint::RejectUploads, guest::reject)
+        .endpoint(SCOPE, endpoint::AddFiles, |req| add_remove(req, true))
+        .endpoint(SCOPE, endpoint::RemoveFiles, |req| add_remove(req, false))
+        .endpoint(SCOPE, endpoint::MoveFiles, move_files)
+        .endpoint(SCOPE, endpoint::ReorderAlbum, reorder)
+        .endpoint(SCOPE, endpoint::SetCaption, caption)
+        .endpoint(SCOPE, endpoint::ServeAlbum, serve)
+        .endpoint(SCOPE, endpoint::AlbumDelta, delta)
+        .endpoint(SCOPE, endpoint::AlbumMonth, month)
+        .endpoint(SCOPE, endpoint::AlbumGeo, geo::album)
+        .endpoint(SCOPE, endpoint::TransferAlbum, share::transfer)
+        .endpoint(SCOPE, endpoint::ExportAlbum, bundle::export)
+        .endpoint(SCOPE, endpoint::ImportAlbum, bundle::import)
+        .scope(share::SCOPE.strip_prefix(SCOPE).unwrap(), share::router())
+        .scope(activity::SCOPE.strip_prefix(SCOPE).unwrap(), activity::router())
         .build()
         .unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn album_listing() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let mut album_ids = vec![];
+        for (key, name) in [(&key, "beach"), (&key, "Alps"), (&other, "Camping")] {
+            let settings = json!({ "name": name, "time_zone": "UTC" });
+            let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+            album_ids.push(album["id"].as_str().unwrap().to_string());
+        }
+
+        let share = json!({ "email": "owner@example.com", "role": "Reader" });
+        let path = format!("/album/{}/share?key={}", album_ids[2], other);
+        assert_eq!(server.send(Method::POST, &path, &share).await, StatusCode::OK);
+
+        let path = format!("/file?key={}&album={}", key, album_ids[0]);
+        let (status, _) = server.upload_to(&path, "sand.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let listed = |query: &str| format!("/album?key={}&{}", key, query);
+        let owned = server.json(Method::GET, &listed("role=owner"), &()).await;
+        assert_eq!(owned.as_object().unwrap().len(), 2);
+        let shared = server.json(Method::GET, &listed("role=shared"), &()).await;
+        assert_eq!(shared.as_object().unwrap().keys().collect::<Vec<_>>(), vec![&album_ids[2]]);
+        let (status, _) = server.request(Method::GET, &listed("role=friends"), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let browsed = |query: &str| format!("/album/browse?key={}&{}", key, query);
+        let names = |listing: &Value| -> Vec<String> {
+            let albums = listing["albums"].as_array().unwrap();
+            albums.iter().map(|album| album["description"]["name"].as_str().unwrap().to_string()).collect()
+        };
+
+        let listing = server.json(Method::GET, &browsed("sort=name"), &()).await;
+        assert_eq!(names(&listing), vec!["Alps", "beach", "Camping"]);
+        assert_eq!((listing["owned"].clone(), listing["shared"].clone()), (json!(2), json!(1)));
+        assert_eq!(listing["albums"][2]["id"], album_ids[2].as_str());
+        assert_eq!(listing["albums"][2]["role"], "Reader");
+
+        let listing = server.json(Method::GET, &browsed("sort=length&role=owner"), &()).await;
+        assert_eq!(names(&listing), vec!["beach", "Alps"]);
+        assert_eq!(listing["shared"], 1);
+
+        let (status, _) = server.request(Method::GET, &browsed("sort=size"), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn album_date_range() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let mut album_ids = vec![];
+        for (time_zone, sort) in [("UTC", "CaptureDate"), ("America/New_York", "CaptureDate"), ("UTC", "Name")] {
+            let settings = json!({ "name": "Range", "time_zone": time_zone, "sort": sort });
+            let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+            album_ids.push(album["id"].as_str().unwrap().to_string());
+
+            // Test uploads were all taken at the start of 1970 in UTC.
+            let path = format!("/file?key={}&album={}", key, album_ids.last().unwrap());
+            let (status, _) = server.upload_to(&path, "midnight.png", png(8, 8)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (utc, new_york, by_name) = (&album_ids[0], &album_ids[1], &album_ids[2]);
+
+        let metadata = |album_id: &str, query: &str| {
+            format!("/album/{}/serve/metadata?key={}&{}", album_id, key, query)
+        };
+        let info = server.json(Method::GET, &metadata(utc, ""), &()).await;
+        assert!(info.get("sections").is_none());
+
+        // The section of a day is in the range when any of the day is.
+        let info = server.json(Method::GET, &metadata(utc, "from=3600"), &()).await;
+        assert_eq!(info["sections"][0][0], 0);
+        assert_eq!(info["sections"][0][2], 1);
+        let info = server.json(Method::GET, &metadata(utc, "from=-86400&to=0"), &()).await;
+        assert_eq!(info["sections"].as_array().unwrap().len(), 1);
+        for query in ["from=86400", "to=-1"] {
+            let info = server.json(Method::GET, &metadata(utc, query), &()).await;
+            assert_eq!(info["sections"], json!([]));
+        }
+
+        let month = |album_id: &str, month: &str| format!("/album/{}/month/{}?key={}", album_id, month, key);
+        assert_eq!(server.json(Method::GET, &month(utc, "1970-01"), &()).await, json!([0]));
+        assert_eq!(server.json(Method::GET, &month(utc, "1969-12"), &()).await, json!([]));
+        // It was still the last day of 1969 in New York.
+        assert_eq!(server.json(Method::GET, &month(new_york, "1969-12"), &()).await, json!([-68400]));
+        assert_eq!(server.json(Method::GET, &month(new_york, "1970-01"), &()).await, json!([]));
+
+        // Months are summarized in the time zone of the album too.
+        let info = server.json(Method::GET, &metadata(new_york, ""), &()).await;
+        let december = json!({ "year": 1969, "month": 12, "count": 1, "min": 0, "max": 0 });
+        assert_eq!(info["months"], json!([december]));
+        let info = server.json(Method::GET, &metadata(by_name, ""), &()).await;
+        assert!(info.get("months").is_none());
+
+        let refused = [
+            metadata(by_name, "from=0"),
+            metadata(utc, "to=soon"),
+            month(by_name, "1970-01"),
+            month(utc, "1970-13"),
+        ];
+        for path in refused {
+            let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn move_between_albums() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let mut album_ids = vec![];
+        for name in ["Inbox", "Trip"] {
+            let settings = json!({ "name": name, "time_zone": "UTC" });
+            let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+            album_ids.push(album["id"].as_str().unwrap().to_string());
+        }
+        let (inbox, trip) = (&album_ids[0], &album_ids[1]);
+
+        let mut file_ids = vec![];
+        for name in ["a.png", "b.png"] {
+            let (status, body) = server.upload_to(&format!("/file?key={}&album={}", key, inbox), name, png(8, 8)).await;
+            assert_eq!(status, StatusCode::OK);
+            let file: Value = serde_json::from_slice(&body).unwrap();
+            file_ids.push(file["id"].as_str().unwrap().to_string());
+        }
+
+        let moved = |from: &str, to: &str, key: &str| format!("/album/{}/move/{}?key={}", from, to, key);
+        let length = |album_id: &str| format!("/album/{}/serve/metadata?key={}", album_id, key);
+        let files = json!({ "ids": file_ids });
+
+        assert_eq!(server.send(Method::POST, &moved(inbox, inbox, &key), &files).await, StatusCode::BAD_REQUEST);
+        assert_eq!(server.send(Method::POST, &moved(inbox, trip, &other), &files).await, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(server.send(Method::POST, &moved(inbox, trip, &key), &files).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &length(inbox), &()).await["length"], 0);
+        assert_eq!(server.json(Method::GET, &length(trip), &()).await["length"], 2);
+
+        // Sending the move again after it went through changes nothing.
+        assert_eq!(server.send(Method::POST, &moved(inbox, trip, &key), &files).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &length(trip), &()).await["length"], 2);
+
+        // Nothing is moved when any of the files can't be.
+        let other_file = server.upload(&other, "c.png", png(8, 8)).await;
+        let mixed = json!({ "ids": [&file_ids[0], other_file] });
+        assert_eq!(server.send(Method::POST, &moved(trip, inbox, &key), &mixed).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.json(Method::GET, &length(trip), &()).await["length"], 2);
+    }
+}
@@ -0,0 +1,238 @@
+//! Album Rebuilds
+//!
+//! Changing the time zone or sort mode of an album places every file in it again, which is too
+//! much for the transaction of a single request once an album is large. The request only stages
+//! an empty copy of the album that is laid out by the new settings and records its `Progress` in
+//! the `rebuilds` tree. A `Job::Rebuild` then copies the files over `BATCH_SIZE` at a time, each
+//! batch in its own transaction, catches up on whatever changed in the album meanwhile and swaps
+//! the copy in. Until then the album keeps its old time zone and sort mode, its old fragments are
+//! served, and its `AlbumInfo` says that it is rebuilding.
+
+use super::engine::{Cursor, Engine};
+use crate::{
+    common::AppState,
+    error::{ApiError, ApiResult},
+    events,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::Transactional;
+use wire::{Album, SortMode};
+
+/// Files that are copied together.
+const BATCH_SIZE: usize = 256;
+
+/// Stored in the `rebuilds` tree under the id of the album.
+#[derive(Serialize, Deserialize, Debug)]
+struct Progress<'a> {
+    /// Head of the album when the copy was started, from which its changes are caught up on.
+    from_head: u64,
+    /// Head of the copy when it was started, from which its own deltas are written.
+    staged_head: u64,
+    #[serde(borrow)]
+    staged: Album<'a>,
+    cursor: Option<Cursor>,
+}
+
+enum Step {
+    Copied,
+    Finished(u64),
+    Gone,
+}
+
+pub fn is_rebuilding(rebuilds: &sled::Tree, album_id: &str) -> ApiResult<bool> {
+    Ok(rebuilds.contains_key(album_id)?)
+}
+
+/// Head of the copy that is being filled in for an album, if there is one.
+pub fn staged_head(
+    album_id: &str,
+    rebuilds: &TransactionalTree,
+) -> ConflictableTransactionResult<Option<u64>, ApiError> {
+    Ok(rebuilds.get(album_id)?.map(|progress_bytes| {
+        let progress: Progress = bincode::deserialize(&progress_bytes).unwrap();
+        progress.staged.fragment_head
+    }))
+}
+
+/// Start laying out `album` by `time_zone` and `sort`, replacing any rebuild that is underway.
+/// Returns whether a rebuild was started, which then needs a `Job::Rebuild` to run it.
+pub fn start(
+    album_id: &str,
+    album: &Album,
+    time_zone: Tz,
+    sort: SortMode,
+    fragments: &TransactionalTree,
+    rebuilds: &TransactionalTree,
+) -> ConflictableTransactionResult<bool, ApiError> {
+    if let Some(progress_bytes) = rebuilds.get(album_id)? {
+        let progress: Progress = bincode::deserialize(&progress_bytes).unwrap();
+        let target = &progress.staged.description;
+        if target.time_zone == time_zone && target.sort == sort {
+            return Ok(false);
+        }
+
+        cancel(album_id, fragments, rebuilds)?;
+    }
+
+    if album.description.time_zone == time_zone && album.description.sort == sort {
+        return Ok(false);
+    }
+
+    let staged = Engine::stage(album_id, album, time_zone, sort, fragments)?;
+    let progress = Progress {
+        from_head: album.fragment_head,
+        staged_head: staged.fragment_head,
+        staged,
+        cursor: None,
+    };
+    rebuilds.insert(album_id.as_bytes(), bincode::serialize(&progress).unwrap())?;
+
+    Ok(true)
+}
+
+/// Stop the rebuild of an album if one is underway, returning the time zone and sort mode that
+/// it was laying the album out by.
+pub fn cancel(
+    album_id: &str,
+    fragments: &TransactionalTree,
+    rebuilds: &TransactionalTree,
+) -> ConflictableTransactionResult<Option<(Tz, SortMode)>, ApiError> {
+    let progress_bytes = match rebuilds.remove(album_id.as_bytes())? {
+        Some(progress_bytes) => progress_bytes,
+        None => return Ok(None),
+    };
+    let progress: Progress = bincode::deserialize(&progress_bytes).unwrap();
+
+    Engine::discard(album_id, &progress.staged, fragments)?;
+    Engine::discard_deltas(album_id, progress.staged_head, progress.staged.fragment_head, fragments)?;
+
+    let target = &progress.staged.description;
+    Ok(Some((target.time_zone, target.sort)))
+}
+
+/// Copy the next batch of files, or swap the copy in once every file has been copied.
+fn step(state: &AppState, album_id: &str) -> ApiResult<Step> {
+    let AppState {
+        ref albums,
+        ref fragments,
+        ref files,
+        ref rebuilds,
+        ..
+    } = state;
+
+    let step = (albums, fragments, files, rebuilds).transaction(
+        |(albums, fragments, files, rebuilds)| {
+            let progress_bytes = match rebuilds.get(album_id)? {
+                Some(progress_bytes) => progress_bytes,
+                None => return Ok(Step::Gone),
+            };
+            let mut progress: Progress = bincode::deserialize(&progress_bytes).unwrap();
+
+            let album_bytes = match albums.get(album_id)? {
+                Some(album_bytes) => album_bytes,
+                None => {
+                    cancel(album_id, fragments, rebuilds)?;
+                    return Ok(Step::Gone);
+                }
+            };
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            let source = Engine::new(album_id, &mut album, fragments)?;
+            let mut staged = Engine::new(album_id, &mut progress.staged, fragments)?;
+            let cursor = staged.copy_from(&source, progress.cursor.as_ref(), BATCH_SIZE, files)?;
+            drop(source);
+
+            if cursor.is_some() {
+                staged.commit()?;
+                progress.cursor = cursor;
+                rebuilds.insert(album_id.as_bytes(), bincode::serialize(&progress).unwrap())?;
+                return Ok(Step::Copied);
+            }
+
+            if !staged.catch_up(progress.from_head, album.fragment_head, files)? {
+                // The changes were pruned or the album was reset, so nothing short of copying it
+                // again will do.
+                drop(staged);
+                let target = &progress.staged.description;
+                let (time_zone, sort) = (target.time_zone, target.sort);
+                cancel(album_id, fragments, rebuilds)?;
+                start(album_id, &album, time_zone, sort, fragments, rebuilds)?;
+                return Ok(Step::Copied);
+            }
+            staged.commit()?;
+
+            Engine::discard_deltas(album_id, progress.staged_head, progress.staged.fragment_head, fragments)?;
+            Engine::adopt(album_id, &mut album, &progress.staged, fragments)?;
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+            rebuilds.remove(album_id.as_bytes())?;
+
+            Ok(Step::Finished(album.fragment_head))
+        },
+    )?;
+
+    Ok(step)
+}
+
+/// Run the rebuild of an album to the end, if it still has one.
+pub fn run(state: &AppState, album_id: &str) -> ApiResult<()> {
+    loop {
+        match step(state, album_id)? {
+            Step::Copied => continue,
+            Step::Finished(fragment_head) => {
+                events::album_updated(state, album_id, fragment_head);
+                return Ok(());
+            }
+            Step::Gone => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rebuild_album() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let first = server.upload(&key, "first.png", png(8, 8)).await;
+        let second = server.upload(&key, "second.png", png(8, 8)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files_path = format!("/album/{}/files?key={}", album_id, key);
+        let status = server.send(Method::POST, &files_path, &json!({ "ids": [first] })).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let settings = json!({ "name": "Trip", "time_zone": "Asia/Tokyo" });
+        let status = server
+            .send(Method::PATCH, &format!("/album/{}?key={}", album_id, key), &settings)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The old layout is served until the job has finished.
+        let path = format!("/album/{}/serve/metadata?key={}", album_id, key);
+        let metadata = server.json(Method::GET, &path, &()).await;
+        assert_eq!(metadata["rebuilding"], true);
+        assert_eq!(metadata["description"]["time_zone"], "UTC");
+        assert_eq!(metadata["description"]["name"], "Trip");
+
+        let status = server.send(Method::POST, &files_path, &json!({ "ids": [second] })).await;
+        assert_eq!(status, StatusCode::OK);
+
+        crate::album::rebuild::run(&server.state, album_id).unwrap();
+
+        let metadata = server.json(Method::GET, &path, &()).await;
+        assert_eq!(metadata["rebuilding"], false);
+        assert_eq!(metadata["description"]["time_zone"], "Asia/Tokyo");
+        assert_eq!(metadata["length"], 2);
+        assert!(server.state.rebuilds.is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+//! Album Rules
+//!
+//! Albums can have rules that pick out files of their owner, like every photo taken in 2024, every
+//! file tagged `hiking` or every upload from one camera. Rules are checked when a file is stored
+//! and when its tags change, and a file that matches any rule of an album is added to it as if
+//! the owner had added it by hand. Files stay when they stop matching, and files that were stored
+//! before a rule was set aren't looked at again.
+//!
+//! Only albums that the owner of a file owns are considered, so that files never end up in an
+//! album that someone else shares with their owner.
+
+use super::add_upload;
+use crate::{
+    common::{AppState, File},
+    error::{ApiError, ApiResult},
+    events,
+};
+use chrono::{Datelike, TimeZone};
+use chrono_tz::Tz;
+use exif::{In, Reader, Tag, Value};
+use sled::Transactional;
+use std::io::BufReader;
+use std::path::Path;
+use wire::{Album, AlbumRule, Role};
+
+/// Read the camera model out of the EXIF data of the file at `path`.
+pub fn read_camera(path: &Path) -> Option<String> {
+    let file = std::fs::File::open(path).ok()?;
+    let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok()?;
+
+    match exif.get_field(Tag::Model, In::PRIMARY)?.value {
+        Value::Ascii(ref values) => {
+            let model = String::from_utf8_lossy(values.first()?);
+            let model = model.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            Some(model.to_string()).filter(|model| !model.is_empty())
+        }
+        _ => None,
+    }
+}
+
+/// Refuse rules without conditions, which would match every file.
+pub fn validate(rules: &[AlbumRule]) -> ApiResult<()> {
+    if rules.iter().any(AlbumRule::is_empty) {
+        return Err(ApiError::BadRequest);
+    }
+
+    Ok(())
+}
+
+/// Whether `file` meets every condition of `rule`, dating it in `time_zone` like albums do.
+pub fn matches(rule: &AlbumRule, file: &File, time_zone: Tz) -> bool {
+    let same = |a: &str, b: &str| a.trim().to_lowercase() == b.trim().to_lowercase();
+
+    let year = rule.year.is_none_or(|year| {
+        let taken = time_zone.timestamp_opt(file.metadata.last_modified, 0).single();
+        taken.map(|taken| taken.year()) == Some(year)
+    });
+    let tag = rule
+        .tag
+        .as_ref()
+        .is_none_or(|tag| file.labels.tags.iter().any(|other| same(tag, other)));
+    let camera = rule
+        .camera
+        .as_ref()
+        .is_none_or(|camera| file.camera.as_deref().is_some_and(|other| same(camera, other)));
+
+    !rule.is_empty() && year && tag && camera
+}
+
+/// Add `file_id` to every album of its owner that it isn't in yet and that has a rule that it
+/// matches. Files that are gone by then, or that are in the trash or locked, are skipped.
+pub fn apply(state: &AppState, file_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref albums,
+        ref inclusions,
+        ref fragments,
+        ref user_to_album,
+        ref activity,
+        ..
+    } = state;
+
+    let file_bytes = match files.get(file_id)? {
+        Some(file_bytes) => file_bytes,
+        None => return Ok(()),
+    };
+    let file: File = bincode::deserialize(&file_bytes).unwrap();
+    if file.locked {
+        return Ok(());
+    }
+
+    let prefix = [file.owner_id, "."].concat();
+    let mut album_ids = vec![];
+
+    for entry in user_to_album.scan_prefix(&prefix) {
+        let (user_album, role_bytes) = entry?;
+        if !matches!(bincode::deserialize(&role_bytes).unwrap(), Role::Owner) {
+            continue;
+        }
+
+        let album_id = std::str::from_utf8(&user_album[prefix.len()..]).unwrap();
+        if inclusions.contains_key([file_id, ".", album_id].concat())? {
+            continue;
+        }
+
+        let album_bytes = match albums.get(album_id)? {
+            Some(album_bytes) => album_bytes,
+            None => continue,
+        };
+        let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        let time_zone = album.description.time_zone;
+        if album.description.rules.iter().any(|rule| matches(rule, &file, time_zone)) {
+            album_ids.push(album_id.to_string());
+        }
+    }
+
+    for album_id in album_ids {
+        let trees = (files, albums, inclusions, fragments, user_to_album, activity);
+        let album_head = trees.transaction(|(files, albums, inclusions, fragments, user_to_album, activity)| {
+            // The file may have been moved to the trash or locked since it was read.
+            let file_bytes = match files.get(file_id)? {
+                Some(file_bytes) => file_bytes,
+                None => return Ok(None),
+            };
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+            if file.locked {
+                return Ok(None);
+            }
+
+            let album_trees = (albums, inclusions, fragments, user_to_album, activity);
+            add_upload(album_trees, &album_id, file.owner_id, file_id, &file)
+        })?;
+
+        if let Some(album_head) = album_head {
+            events::album_updated(state, &album_id, album_head);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::borrow::Cow;
+    use wire::{FileEdit, FileLabels, FileMetadata};
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::json;
+
+    fn file<'a>(last_modified: i64, tags: &[&str], camera: Option<&str>) -> File<'a, 'a, 'a> {
+        File {
+            owner_id: "owner",
+            width: 1,
+            height: 1,
+            uploaded: 0,
+            detected_mime: "image/jpeg",
+            placeholder: None,
+            phash: None,
+            location: None,
+            camera: camera.map(str::to_string),
+            size: None,
+            hash: None,
+            rendition_sizes: vec![],
+            encrypted: false,
+            animation: None,
+            video: None,
+            edit: FileEdit::default(),
+            revision: 0,
+            motion_id: None,
+            still_id: None,
+            labels: FileLabels {
+                tags: tags.iter().map(|tag| Cow::from(tag.to_string())).collect(),
+                ..FileLabels::default()
+            },
+            hidden: false,
+            locked: false,
+            metadata: FileMetadata {
+                last_modified,
+                name: Cow::from("name.jpg"),
+                mime: Cow::from("image/jpeg"),
+            },
+        }
+    }
+
+    #[test]
+    fn match_rules() {
+        // 2024-01-01 00:30 in UTC, which is still 2023 in New York.
+        let new_year = 1_704_069_000;
+        let hike = file(new_year, &["Hiking", "alps"], Some("Canon EOS R5"));
+
+        let rule = |year, tag: Option<&'static str>, camera: Option<&'static str>| AlbumRule {
+            year,
+            tag: tag.map(Cow::from),
+            camera: camera.map(Cow::from),
+        };
+
+        assert!(matches(&rule(Some(2024), None, None), &hike, chrono_tz::UTC));
+        assert!(!matches(&rule(Some(2024), None, None), &hike, chrono_tz::America::New_York));
+        assert!(matches(&rule(Some(2023), None, None), &hike, chrono_tz::America::New_York));
+
+        assert!(matches(&rule(None, Some("hiking"), None), &hike, chrono_tz::UTC));
+        assert!(matches(&rule(None, None, Some("canon eos r5")), &hike, chrono_tz::UTC));
+        assert!(!matches(&rule(None, None, Some("canon")), &hike, chrono_tz::UTC));
+        assert!(!matches(&rule(None, None, Some("Canon EOS R5")), &file(new_year, &[], None), chrono_tz::UTC));
+
+        // Every condition of a rule has to hold.
+        assert!(matches(&rule(Some(2024), Some("alps"), Some("Canon EOS R5")), &hike, chrono_tz::UTC));
+        assert!(!matches(&rule(Some(2024), Some("beach"), None), &hike, chrono_tz::UTC));
+
+        assert!(!matches(&AlbumRule::default(), &hike, chrono_tz::UTC));
+        assert!(validate(&[rule(Some(2024), None, None)]).is_ok());
+        assert!(validate(&[rule(Some(2024), None, None), AlbumRule::default()]).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn album_rules() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let settings = json!({ "name": "Hikes", "time_zone": "UTC", "rules": [{}] });
+        let status = server.send(Method::POST, &format!("/album?key={}", key), &settings).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut album_ids = vec![];
+        for (key, rules) in [
+            (&key, json!([{ "tag": "Hiking" }])),
+            (&key, json!([{ "year": 1970 }, { "year": 1971 }])),
+            (&other, json!([{ "year": 1970 }])),
+        ] {
+            let settings = json!({ "name": "Rules", "time_zone": "UTC", "rules": rules });
+            let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+            album_ids.push(album["id"].as_str().unwrap().to_string());
+        }
+        let (hikes, seventies, others) = (&album_ids[0], &album_ids[1], &album_ids[2]);
+
+        let metadata = |album_id: &str, key: &str| format!("/album/{}/serve/metadata?key={}", album_id, key);
+
+        // Test uploads were all taken at the start of 1970, and have no tags until a sidecar comes.
+        let file_id = server.upload(&key, "IMG_0001.png", png(64, 48)).await;
+        assert_eq!(server.json(Method::GET, &metadata(seventies, &key), &()).await["length"], 1);
+        assert_eq!(server.json(Method::GET, &metadata(hikes, &key), &()).await["length"], 0);
+        assert_eq!(server.json(Method::GET, &metadata(others, &other), &()).await["length"], 0);
+
+        let sidecar = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF>
+            <rdf:Description><dc:subject><rdf:Bag><rdf:li>hiking</rdf:li></rdf:Bag></dc:subject>
+            </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        let path = format!("/file/{}/xmp?key={}", file_id, key);
+        let (status, _) = server.request(Method::PUT, &path, &[], Body::from(sidecar)).await;
+        assert_eq!(status, StatusCode::OK);
+        let info = server.json(Method::GET, &metadata(hikes, &key), &()).await;
+        assert_eq!(info["length"], 1);
+        assert_eq!(info["description"]["rules"], json!([{ "year": null, "tag": "Hiking", "camera": null }]));
+        assert_eq!(server.json(Method::GET, &metadata(seventies, &key), &()).await["length"], 1);
+    }
+}
@@ -1,10 +1,12 @@
 use crate::{
+    events,
     common::{
         join, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, File, User,
     },
+    endpoint::EndpointExt,
     error::{ApiError, ApiResult},
 };
-use super::engine::Engine;
+use super::{activity, engine::Engine};
 use hyper::{Body, Request, Response};
 use routerify::{ext::RequestExt, Router};
 use sled::transaction::abort;
@@ -13,7 +15,7 @@ use sled::transaction::TransactionalTree;
 use sled::Transactional;
 use std::borrow::Cow;
 use tokio::task::block_in_place;
-use wire::{Album, Key, PermissionPair, Role};
+use wire::{endpoint, ActivityEvent, Album, Key, PermissionPair, Role, TransferOwnership};
 
 pub fn test_user_can_write(
     user_to_album: &TransactionalTree,
@@ -31,13 +33,31 @@ pub fn test_user_can_write(
     Ok(())
 }
 
+/// Test that the user can at least add their own files to the album, returning their role so
+/// that callers can hold contributors to their own files.
+pub fn test_user_can_contribute(
+    user_to_album: &TransactionalTree,
+    user_id: &str,
+    album_id: &str,
+) -> ConflictableTransactionResult<Role, ApiError> {
+    let user_bytes = user_to_album
+        .get([user_id, ".", album_id].concat())?
+        .ok_or(ApiError::Unauthorized)?;
+    let user_role: Role = bincode::deserialize(&user_bytes).unwrap();
+    if !user_role.can_contribute() {
+        return abort(ApiError::Unauthorized);
+    }
+
+    Ok(user_role)
+}
+
 async fn share(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, body) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: PermissionPair = serde_json::from_slice(&entire_body)?;
 
     if let Role::Owner = json.role {
@@ -45,21 +65,23 @@ async fn share(req: Request<Body>) -> ApiResult<Response<Body>> {
     }
 
     block_in_place(|| {
+        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref emails,
             ref user_to_album,
             ref album_to_user,
             ref albums,
+            ref activity,
             ..
-        } = parts.data().unwrap();
+        } = state;
 
         let album_id = parts.param("albumId").unwrap();
 
         test_logged_in(sessions, key)?;
 
-        (emails, user_to_album, album_to_user, albums).transaction(
-            |(emails, user_to_album, album_to_user, albums)| {
+        let target_user_id = (emails, user_to_album, album_to_user, albums, activity).transaction(
+            |(emails, user_to_album, album_to_user, albums, activity)| {
                 // Test that the album exists so that albums that are being deleted
                 // can't be shared
                 albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
@@ -83,10 +105,18 @@ async fn share(req: Request<Body>) -> ApiResult<Response<Body>> {
                     }
                 }
 
-                Ok(())
+                let event = ActivityEvent::MemberJoined {
+                    email: json.email.to_string(),
+                    role: json.role,
+                };
+                activity::record(activity, album_id, user_id, event)?;
+
+                Ok(target_user_id)
             },
         )?;
 
+        events::shares_changed(state, album_id, std::str::from_utf8(&target_user_id).unwrap());
+
         respond_ok_empty()
     })
 }
@@ -97,10 +127,11 @@ async fn unshare(req: Request<Body>) -> ApiResult<Response<Body>> {
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: Key = serde_json::from_slice(&entire_body)?;
 
     block_in_place(|| {
+        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref emails,
@@ -110,19 +141,20 @@ async fn unshare(req: Request<Body>) -> ApiResult<Response<Body>> {
             ref inclusions,
             ref albums,
             ref fragments,
+            ref activity,
             ..
-        } = parts.data().unwrap();
+        } = state;
 
         let album_id = parts.param("albumId").unwrap();
 
         test_logged_in(sessions, key)?;
 
-        (emails, user_to_album, album_to_user, inclusions, files, albums, fragments).transaction(
-            |(emails, user_to_album, album_to_user, inclusions, files, albums, fragments)| {
+        let removed = (emails, user_to_album, album_to_user, inclusions, files, albums, fragments, activity).transaction(
+            |(emails, user_to_album, album_to_user, inclusions, files, albums, fragments, activity)| {
                 let target_user_id = emails.get(&*json.key)?.ok_or(ApiError::NotFound)?;
 
                 // Users can remove themselves from an album if they want to
-                if &target_user_id != user_id.as_bytes() {
+                if target_user_id != user_id.as_bytes() {
                     test_user_can_write(user_to_album, user_id, album_id)?;
                 }
 
@@ -130,7 +162,7 @@ async fn unshare(req: Request<Body>) -> ApiResult<Response<Body>> {
                 let role_bytes =
                     match user_to_album.remove([target_user_id.as_ref(), b".", album_id.as_bytes()].concat())? {
                         Some(x) => x,
-                        None => return Ok(()),
+                        None => return Ok(None),
                     };
                 album_to_user.remove([album_id.as_bytes(), b".", target_user_id.as_ref()].concat())?;
 
@@ -143,33 +175,122 @@ async fn unshare(req: Request<Body>) -> ApiResult<Response<Body>> {
                 let album_bytes = albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
                 let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
 
-                let mut e = Engine::new(&album_id, &mut album, fragments)?;
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
 
                 // Remove all files that the target has added to the album. A user must be
                 // able to see all of albums that their photos are in.
-                for file_id in e.list_file_ids()? {
+                let mut owned = vec![];
+                for file_id in e.list_file_ids() {
+                    let file_id = file_id?;
                     if let Some(file_bytes) = files.get(&file_id)? {
                         let file: File = bincode::deserialize(&file_bytes).unwrap();
 
                         if file.owner_id.as_bytes() == target_user_id.as_ref() {
-                            let inclusion = [&file_id, ".", album_id].concat();
-                            inclusions.remove(inclusion.as_bytes())?;
-
-                            e.remove(&file_id, &file)?;
+                            owned.push((file_id, file_bytes));
                         }
                     }
                 }
 
+                for (file_id, file_bytes) in owned {
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                    let inclusion = [&file_id, ".", album_id].concat();
+                    inclusions.remove(inclusion.as_bytes())?;
+
+                    e.remove(&file_id, &file)?;
+                }
+
                 e.commit()?;
 
-                Ok(())
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+
+                let event = ActivityEvent::MemberLeft {
+                    email: json.key.to_string(),
+                };
+                activity::record(activity, album_id, user_id, event)?;
+
+                Ok(Some((target_user_id, album.fragment_head)))
+            },
+        )?;
+
+        if let Some((target_user_id, fragment_head)) = removed {
+            events::shares_changed(state, album_id, std::str::from_utf8(&target_user_id).unwrap());
+            events::album_updated(state, album_id, fragment_head);
+        }
+
+        respond_ok_empty()
+    })
+}
+
+/// Make another member the owner of the album, leaving the previous owner as an editor.
+pub async fn transfer(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: TransferOwnership = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref emails,
+            ref user_to_album,
+            ref albums,
+            ref activity,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let target_user_id = (emails, user_to_album, albums, activity).transaction(
+            |(emails, user_to_album, albums, activity)| {
+                albums.get(album_id)?.ok_or(ApiError::Unauthorized)?;
+
+                let role_bytes = user_to_album
+                    .get([user_id, ".", album_id].concat())?
+                    .ok_or(ApiError::Unauthorized)?;
+                let role: Role = bincode::deserialize(&role_bytes).unwrap();
+                if !role.is_owner() {
+                    return abort(ApiError::Unauthorized);
+                }
+
+                let target_user_id = emails.get(&*json.email)?.ok_or(ApiError::NotFound)?;
+                if target_user_id == user_id.as_bytes() {
+                    return abort(ApiError::BadRequest);
+                }
+
+                // Only members can be given the album, so that it never lands on someone unawares.
+                let target_key = [target_user_id.as_ref(), b".", album_id.as_bytes()].concat();
+                user_to_album.get(&target_key)?.ok_or(ApiError::NotFound)?;
+
+                user_to_album.insert(target_key, bincode::serialize(&Role::Owner).unwrap())?;
+                user_to_album.insert(
+                    [user_id, ".", album_id].concat().as_bytes(),
+                    bincode::serialize(&Role::Editor).unwrap(),
+                )?;
+
+                let event = ActivityEvent::OwnerChanged {
+                    email: json.email.to_string(),
+                };
+                activity::record(activity, album_id, user_id, event)?;
+
+                Ok(target_user_id)
             },
         )?;
 
+        events::shares_changed(state, album_id, user_id);
+        events::shares_changed(state, album_id, std::str::from_utf8(&target_user_id).unwrap());
+
         respond_ok_empty()
     })
 }
 
+/// Members of the album and their roles. Readers only see the owner and themselves.
 async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, _) = req.into_parts();
 
@@ -188,9 +309,10 @@ async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
         let album_id = parts.param("albumId").unwrap();
 
         test_logged_in(sessions, key)?;
-        user_to_album
+        let role_bytes = user_to_album
             .get([user_id, ".", album_id].concat())?
             .ok_or(ApiError::Unauthorized)?;
+        let own_role: Role = bincode::deserialize(&role_bytes).unwrap();
 
         let mut user_ids = vec![];
         for entry in album_to_user.scan_prefix([album_id, "."].concat()) {
@@ -203,17 +325,21 @@ async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
         }
 
         let mut pairs: Vec<PermissionPair<'static, '_>> = vec![];
-        for user_id in user_ids {
-            let key = [user_id.as_str(), ".", album_id].concat();
+        for member_id in user_ids {
+            let key = [member_id.as_str(), ".", album_id].concat();
             if let Some(role_bytes) = user_to_album.get(key)? {
-                if let Some(user_bytes) = users.get(&user_id)? {
+                if let Some(user_bytes) = users.get(&member_id)? {
                     let role: Role = bincode::deserialize(&role_bytes).unwrap();
+                    if !own_role.can_see_members() && !role.is_owner() && member_id != user_id {
+                        continue;
+                    }
+
                     let user: User = bincode::deserialize(&user_bytes).unwrap();
 
                     pairs.push(PermissionPair {
                         email: Cow::Owned(user.email.to_string()),
-                        user_id: Some(Cow::from(user_id)),
-                        role: role,
+                        user_id: Some(Cow::from(member_id)),
+                        role,
                     });
                 }
             }
@@ -223,11 +349,167 @@ async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
     })
 }
 
+pub const SCOPE: &str = "/album/:albumId/share";
+
 pub fn router() -> Router<Body, ApiError> {
     Router::builder()
-        .post("/", share)
-        .delete("/", unshare)
-        .get("/", list)
+        .endpoint(SCOPE, endpoint::ShareAlbum, share)
+        .endpoint(SCOPE, endpoint::UnshareAlbum, unshare)
+        .endpoint(SCOPE, endpoint::ListMembers, list)
         .build()
         .unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn share_album() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let file_id = server.upload(&owner, "black.png", png(64, 48)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files = json!({ "ids": [file_id] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, owner), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Not shared yet.
+        let path = format!("/album/{}/serve/metadata?key={}", album_id, reader);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let share = json!({ "email": "reader@example.com", "role": "Reader" });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let metadata: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metadata["length"], 1);
+        assert_eq!(metadata["role"], "Reader");
+
+        // Readers see the file through the album.
+        let path = format!("/file/small/{}?key={}&album={}", file_id, reader, album_id);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn contributors_and_readers() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let contributor = server.signup("contributor@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let settings = json!({ "name": "Party", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        for (email, role) in [("contributor@example.com", "Contributor"), ("reader@example.com", "Reader")] {
+            let share = json!({ "email": email, "role": role });
+            let status = server
+                .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let owner_file = server.upload(&owner, "owner.png", png(8, 8)).await;
+        let files = json!({ "ids": [owner_file] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, owner), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Contributors add and remove their own files, but not anyone else's.
+        let own_file = server.upload(&contributor, "mine.png", png(8, 8)).await;
+        let own = json!({ "ids": [own_file] });
+        let path = format!("/album/{}/files?key={}", album_id, contributor);
+        assert_eq!(server.send(Method::POST, &path, &own).await, StatusCode::OK);
+        assert_eq!(server.send(Method::DELETE, &path, &files).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.send(Method::DELETE, &path, &own).await, StatusCode::OK);
+
+        let path = format!("/album/{}/share?key={}", album_id, contributor);
+        let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        let members: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(members.as_array().unwrap().len(), 3);
+
+        // Readers only see the owner and themselves.
+        let path = format!("/album/{}/share?key={}", album_id, reader);
+        let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        let members: Value = serde_json::from_slice(&body).unwrap();
+        let mut emails: Vec<_> = members
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|member| member["email"].as_str().unwrap())
+            .collect();
+        emails.sort_unstable();
+        assert_eq!(emails, ["owner@example.com", "reader@example.com"]);
+
+        // The activity feed doesn't give the other members away either.
+        for (member, expected) in [
+            (&contributor, vec!["contributor@example.com", "reader@example.com"]),
+            (&reader, vec!["reader@example.com"]),
+        ] {
+            let path = format!("/album/{}/activity?key={}", album_id, member);
+            let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+            let feed: Value = serde_json::from_slice(&body).unwrap();
+            let joined: Vec<_> = feed
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|entry| entry["event"]["MemberJoined"]["email"].as_str())
+                .collect();
+            assert_eq!(joined, expected);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transfer_ownership() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let editor = server.signup("editor@example.com").await;
+        server.signup("stranger@example.com").await;
+
+        let settings = json!({ "name": "Shared", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let share = json!({ "email": "editor@example.com", "role": "Editor" });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let path = format!("/album/{}/transfer?key={}", album_id, owner);
+        let stranger = json!({ "email": "stranger@example.com" });
+        assert_eq!(server.send(Method::POST, &path, &stranger).await, StatusCode::NOT_FOUND);
+
+        let target = json!({ "email": "editor@example.com" });
+        assert_eq!(server.send(Method::POST, &path, &target).await, StatusCode::OK);
+
+        // The previous owner can no longer hand the album on.
+        assert_eq!(server.send(Method::POST, &path, &target).await, StatusCode::UNAUTHORIZED);
+
+        let path = format!("/album/{}/share?key={}", album_id, editor);
+        let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        let members: Value = serde_json::from_slice(&body).unwrap();
+        for member in members.as_array().unwrap() {
+            let expected = if member["email"] == "editor@example.com" { "Owner" } else { "Editor" };
+            assert_eq!(member["role"], expected);
+        }
+    }
+}
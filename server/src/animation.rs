@@ -0,0 +1,221 @@
+//! Animated Images
+//!
+//! GIFs and WebPs can hold several frames, of which libvips only loads the first unless it is
+//! asked for more. That first frame is what the usual renditions are made from, so they work as a
+//! poster, and animated files get a WebP copy of their tallest rendition with the frames as well,
+//! which is stored under `animated_kind` and served in its place. Only the first
+//! `max_animation_frames` frames are kept in the copy, since every one of them is decoded at once.
+//!
+//! The frames and their delays are read from the file itself, which is simpler than getting them
+//! out of libvips. Like browsers do, GIF delays of 0 or 1 hundredths of a second count as 10.
+
+use crate::config::{Encoding, Rendition};
+use crate::error::ApiResult;
+use crate::format;
+use libvips::ops;
+use std::convert::TryInto;
+use std::path::Path;
+use wire::Animation;
+
+/// Largest width that libvips accepts, so that only the height limits the animated copy.
+const MAX_COORD: i32 = 10_000_000;
+/// GIF delays shorter than this, in hundredths of a second, are played at `DEFAULT_GIF_DELAY`.
+const MIN_GIF_DELAY: u64 = 2;
+const DEFAULT_GIF_DELAY: u64 = 10;
+
+/// Write the animated copy of the tallest of `renditions` to `output` if the image at `path`, with
+/// type `mime`, is animated, returning its frames.
+pub fn make(
+    mime: &str,
+    path: &Path,
+    renditions: &[Rendition],
+    output: &Path,
+    max_frames: u32,
+) -> ApiResult<Option<Animation>> {
+    let (animation, rendition) = match (read(mime, path)?, renditions.first()) {
+        (Some(animation), Some(rendition)) => (animation, rendition),
+        _ => return Ok(None),
+    };
+
+    render(path, rendition, output, max_frames)?;
+    Ok(Some(animation))
+}
+
+/// Frames of the image at `path` with type `mime`, if it is animated.
+fn read(mime: &str, path: &Path) -> ApiResult<Option<Animation>> {
+    let frames = match mime {
+        "image/gif" => gif_frames(&std::fs::read(path)?),
+        "image/webp" => webp_frames(&std::fs::read(path)?),
+        _ => return Ok(None),
+    };
+
+    Ok(frames.filter(|animation| animation.frames > 1))
+}
+
+/// Write the animated copy of `rendition` for the image at `path` to `output`.
+fn render(path: &Path, rendition: &Rendition, output: &Path, max_frames: u32) -> ApiResult<()> {
+    // Thumbnails keep the height of each frame, which a plain resize of the stacked frames
+    // wouldn't.
+    let options = ops::ThumbnailOptions {
+        height: rendition.height as i32,
+        size: ops::Size::Down,
+        ..ops::ThumbnailOptions::default()
+    };
+    let frames = format!("{}[n={}]", path.to_str().unwrap(), max_frames.max(1));
+    let image = ops::thumbnail_with_opts(&frames, MAX_COORD, &options)?;
+
+    let animated = Rendition {
+        encoding: Encoding::Webp,
+        ..rendition.clone()
+    };
+    format::save(&image, &animated, output)
+}
+
+/// Walk the blocks of a GIF, counting its images and adding up the delays of their graphic
+/// control extensions. Returns `None` for files that end before their trailer.
+fn gif_frames(bytes: &[u8]) -> Option<Animation> {
+    fn color_table(packed: u8) -> usize {
+        if packed & 0x80 != 0 {
+            3 << ((packed & 0x07) + 1)
+        } else {
+            0
+        }
+    }
+
+    /// Skip a run of data sub-blocks, returning the position after its terminator.
+    fn skip_sub_blocks(bytes: &[u8], mut pos: usize) -> Option<usize> {
+        loop {
+            let size = *bytes.get(pos)? as usize;
+            pos += 1 + size;
+            if size == 0 {
+                return Some(pos);
+            }
+        }
+    }
+
+    if !bytes.starts_with(b"GIF8") || bytes.len() < 13 {
+        return None;
+    }
+    let mut pos = 13 + color_table(bytes[10]);
+
+    let mut frames = 0;
+    let mut duration_ms = 0;
+    let mut delay = None;
+
+    loop {
+        match *bytes.get(pos)? {
+            // Extension, of which only graphic control matters.
+            0x21 => {
+                if *bytes.get(pos + 1)? == 0xF9 {
+                    let centiseconds = u16::from_le_bytes(bytes.get(pos + 4..pos + 6)?.try_into().unwrap()) as u64;
+                    delay = Some(centiseconds);
+                }
+                pos = skip_sub_blocks(bytes, pos + 2)?;
+            }
+            // Image descriptor, followed by an optional local color table and the image data.
+            0x2C => {
+                let packed = *bytes.get(pos + 9)?;
+                pos = skip_sub_blocks(bytes, pos + 10 + color_table(packed) + 1)?;
+
+                let centiseconds = match delay.take() {
+                    Some(centiseconds) if centiseconds >= MIN_GIF_DELAY => centiseconds,
+                    _ => DEFAULT_GIF_DELAY,
+                };
+                frames += 1;
+                duration_ms += centiseconds * 10;
+            }
+            0x3B => break,
+            _ => return None,
+        }
+    }
+
+    Some(Animation { frames, duration_ms })
+}
+
+/// Walk the chunks of a WebP, counting its animation frames and adding up their durations.
+/// Still images have no frames.
+fn webp_frames(bytes: &[u8]) -> Option<Animation> {
+    if bytes.get(..4)? != b"RIFF" || bytes.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut pos = 12;
+
+    let mut frames = 0;
+    let mut duration_ms = 0;
+
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+
+        if &header[..4] == b"ANMF" {
+            let duration = bytes.get(pos + 8 + 12..pos + 8 + 15)?;
+            frames += 1;
+            duration_ms += u32::from_le_bytes([duration[0], duration[1], duration[2], 0]) as u64;
+        }
+
+        // Chunks are padded to an even size.
+        pos += 8 + size + (size & 1);
+    }
+
+    Some(Animation { frames, duration_ms })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gif() {
+        let mut gif = b"GIF89a".to_vec();
+        // 1x1 screen with a global color table of 2 entries.
+        gif.extend_from_slice(&[1, 0, 1, 0, 0x80, 0, 0]);
+        gif.extend_from_slice(&[0; 6]);
+        // Looping, which is an application extension that has nothing to do with frames.
+        gif.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+        for delay in [50u16, 0, 20] {
+            gif.extend_from_slice(&[0x21, 0xF9, 4, 0]);
+            gif.extend_from_slice(&delay.to_le_bytes());
+            gif.extend_from_slice(&[0, 0]);
+            gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, 1, 0, 1, 0, 0]);
+            gif.extend_from_slice(&[2, 2, 0x4C, 0x01, 0]);
+        }
+        gif.push(0x3B);
+
+        let animation = gif_frames(&gif).unwrap();
+        assert_eq!(animation.frames, 3);
+        assert_eq!(animation.duration_ms, 500 + 100 + 200);
+
+        // Files that are cut short aren't guessed at.
+        assert!(gif_frames(&gif[..gif.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn webp() {
+        fn chunk(fourcc: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut chunk = fourcc.to_vec();
+            chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        }
+
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &[0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+        body.extend(chunk(b"ANIM", &[0, 0, 0, 0, 0, 0]));
+        for duration in [100u32, 70_000] {
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&duration.to_le_bytes()[..3]);
+            frame.extend_from_slice(&[0, 1, 2, 3]);
+            body.extend(chunk(b"ANMF", &frame));
+        }
+        let webp = chunk(b"RIFF", &body);
+
+        let animation = webp_frames(&webp).unwrap();
+        assert_eq!(animation.frames, 2);
+        assert_eq!(animation.duration_ms, 70_100);
+
+        let still = chunk(b"RIFF", &[&b"WEBP"[..], &chunk(b"VP8 ", &[0; 10])].concat());
+        assert_eq!(webp_frames(&still).unwrap().frames, 0);
+    }
+}
@@ -0,0 +1,198 @@
+//! Serving Bandwidth
+//!
+//! Links to renditions can carry the session key, so a link that ends up on a busy page is
+//! fetched by everyone who sees it. The bytes that `GET /file/:quality/:fileId` sends are counted
+//! for each day in UTC, per session key and per user, in the `bandwidth` tree under
+//! `<day>.token.<key>` and `<day>.user.<user_id>`. Once either is over its limit, serving is
+//! turned away with `TooManyRequests` until the next day.
+//!
+//! The limits are soft: they are checked before a response starts, and the response is then sent
+//! in full, so a day can go over by about one file. Counters of earlier days are removed on
+//! startup, and those of the current day are shown by `GET /admin/bandwidth`.
+
+use crate::{
+    admin::require_admin,
+    common::{respond_ok, AppState},
+    error::{ApiError, ApiResult},
+    storage::ByteStream,
+};
+use chrono::offset::Utc;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use tokio::task::block_in_place;
+use tracing::warn;
+use wire::{BandwidthCounter, BandwidthUsage};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+/// Characters of a session key that are shown, like sessions are listed by.
+const KEY_PREFIX_LENGTH: usize = 20;
+
+/// The current day in UTC, which sorts like the days that came before it.
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn token_key(day: &str, key: &str) -> String {
+    [day, ".token.", key].concat()
+}
+
+fn user_key(day: &str, user_id: &str) -> String {
+    [day, ".user.", user_id].concat()
+}
+
+fn read(bandwidth: &sled::Tree, counter: &str) -> ApiResult<u64> {
+    Ok(bandwidth
+        .get(counter)?
+        .map(|bytes| u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+        .unwrap_or(0))
+}
+
+fn add(bandwidth: &sled::Tree, counter: &str, bytes: u64) -> sled::Result<()> {
+    bandwidth.update_and_fetch(counter, |old| {
+        let old = old.map_or(0, |old| u64::from_be_bytes(old.try_into().unwrap()));
+        Some(old.saturating_add(bytes).to_be_bytes().to_vec())
+    })?;
+
+    Ok(())
+}
+
+/// Fail with `TooManyRequests` if the session `key` or its user has been sent more than their
+/// limit today.
+pub fn check(state: &AppState, key: &str, user_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref bandwidth,
+        ref config,
+        ..
+    } = state;
+
+    let day = today();
+    let counters = [
+        (token_key(&day, key), config.token_bytes_per_day),
+        (user_key(&day, user_id), config.user_bytes_per_day),
+    ];
+
+    for (counter, limit) in &counters {
+        if *limit > 0 && read(bandwidth, counter)? >= *limit {
+            let retry_after = SECONDS_PER_DAY - Utc::now().timestamp().rem_euclid(SECONDS_PER_DAY);
+            return Err(ApiError::TooManyRequests(retry_after as u64));
+        }
+    }
+
+    Ok(())
+}
+
+/// Count the bytes of `stream` against the session `key` and its user as they are sent.
+pub fn count(state: &AppState, key: &str, user_id: &str, stream: ByteStream) -> ByteStream {
+    let bandwidth = state.bandwidth.clone();
+    let day = today();
+    let counters = [token_key(&day, key), user_key(&day, user_id)];
+
+    stream
+        .inspect_ok(move |chunk| {
+            for counter in &counters {
+                if let Err(err) = add(&bandwidth, counter, chunk.len() as u64) {
+                    warn!(error = %err, "Couldn't count served bytes");
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Remove the counters of earlier days.
+pub fn clean(state: &AppState) -> ApiResult<usize> {
+    let mut removed = 0;
+
+    for entry in state.bandwidth.range(..today().as_bytes()) {
+        let (counter, _) = entry?;
+        state.bandwidth.remove(counter)?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// What each user and session has been sent today, most first.
+pub async fn usage(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let AppState {
+        ref bandwidth,
+        ref config,
+        ..
+    } = parts.data().unwrap();
+
+    block_in_place(|| {
+        let day = today();
+        let prefix = [&day, "."].concat();
+
+        let mut users = vec![];
+        let mut tokens = vec![];
+        for entry in bandwidth.scan_prefix(&prefix) {
+            let (counter, bytes) = entry?;
+            let counter = std::str::from_utf8(&counter[prefix.len()..]).unwrap();
+            let bytes = u64::from_be_bytes(bytes.as_ref().try_into().unwrap());
+
+            match counter.split_once('.') {
+                Some(("user", user_id)) => users.push(BandwidthCounter {
+                    id: Cow::from(user_id.to_string()),
+                    bytes,
+                }),
+                Some(("token", key)) => tokens.push(BandwidthCounter {
+                    id: Cow::from(key.chars().take(KEY_PREFIX_LENGTH).collect::<String>()),
+                    bytes,
+                }),
+                _ => {}
+            }
+        }
+
+        users.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.id.cmp(&b.id)));
+        tokens.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.id.cmp(&b.id)));
+
+        respond_ok(BandwidthUsage {
+            day: Cow::from(day),
+            token_bytes_per_day: config.token_bytes_per_day,
+            user_bytes_per_day: config.user_bytes_per_day,
+            users,
+            tokens,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bandwidth_limits() {
+        let server = TestServer::start_with(|config| config.token_bytes_per_day = 1).await;
+        let admin_key = server.signup_admin().await;
+        let key = server.signup("owner@example.com").await;
+        let file_id = server.upload(&key, "photo.png", png(8, 8)).await;
+
+        let served = |key: &str| format!("/file/large/{}?key={}", file_id, key);
+        let (status, body) = server.request(Method::GET, &served(&key), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The first response goes over the limit, which turns away the next one.
+        let (status, _) = server.request(Method::GET, &served(&key), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Other sessions have their own counter, while the user has no limit.
+        let other_session = server.login("owner@example.com").await;
+        let (status, _) = server.request(Method::GET, &served(&other_session), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let usage = server.json(Method::GET, &format!("/admin/bandwidth?key={}", admin_key), &()).await;
+        assert_eq!(usage["token_bytes_per_day"], 1);
+        assert_eq!(usage["user_bytes_per_day"], 0);
+        assert_eq!(usage["users"].as_array().unwrap().len(), 1);
+        assert_eq!(usage["users"][0]["bytes"], 2 * body.len() as u64);
+        assert_eq!(usage["tokens"].as_array().unwrap().len(), 2);
+        assert_eq!(usage["tokens"][0]["bytes"], body.len() as u64);
+    }
+}
@@ -0,0 +1,196 @@
+//! Capabilities
+//!
+//! What the server can decode and encode depends on how libvips was built and on the programs
+//! that are installed next to it. Rather than finding out halfway through an upload, the server
+//! probes for them once when it starts: libvips is asked to write a tiny WebP and AVIF and to read
+//! the AVIF back, which only works with libheif, and `ffmpeg` and `dcraw` (or `dcraw_emu`) are run
+//! to see whether they are there.
+//!
+//! The report is logged on startup. The server refuses to start when a rendition is encoded in a
+//! way that libvips can't write, since no image could be stored, but uploads that need something
+//! that is missing, like videos without `ffmpeg`, are only turned away with `UnsupportedFormat`.
+//! `GET /limits` lists those formats, so that clients can leave them out before uploading.
+//!
+//! HEIC photos from phones are encoded with HEVC, which libheif reads through a decoder of its
+//! own. Whether that decoder is there only shows once such a photo is uploaded.
+
+use crate::config::{Encoding, Rendition};
+use crate::format::Format;
+use libvips::ops;
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Capabilities {
+    pub webp: bool,
+    pub avif: bool,
+    /// libvips can read HEIF files, which it does through libheif.
+    pub heif: bool,
+    /// Videos are represented by a frame that `ffmpeg` takes out of them.
+    pub ffmpeg: bool,
+    /// RAW files are developed by `dcraw` or `dcraw_emu`.
+    pub raw: bool,
+}
+
+impl Capabilities {
+    /// Find out what this server can do. libvips has to be set up first.
+    pub fn probe() -> Self {
+        let image = ops::black(1, 1).ok();
+
+        let webp = image.as_ref().is_some_and(|image| ops::webpsave_buffer(image).is_ok());
+        let avif_bytes = image.as_ref().and_then(|image| {
+            let options = ops::HeifsaveBufferOptions {
+                compression: ops::ForeignHeifCompression::Av1,
+                ..ops::HeifsaveBufferOptions::default()
+            };
+            ops::heifsave_buffer_with_opts(image, &options).ok()
+        });
+        let heif = avif_bytes.as_ref().is_some_and(|bytes| ops::heifload_buffer(bytes).is_ok());
+
+        Capabilities {
+            webp,
+            avif: avif_bytes.is_some(),
+            heif,
+            ffmpeg: runs("ffmpeg", &["-version"]),
+            raw: installed("dcraw") || installed("dcraw_emu"),
+        }
+    }
+
+    /// Whether uploads of `format` can be decoded into renditions.
+    pub fn decodes(&self, format: Format) -> bool {
+        match format {
+            Format::Image => true,
+            Format::Heif => self.heif,
+            Format::Raw => self.raw,
+            Format::Video => self.ffmpeg,
+        }
+    }
+
+    pub fn encodes(&self, encoding: Encoding) -> bool {
+        match encoding {
+            Encoding::Webp => self.webp,
+            Encoding::Avif => self.avif,
+            Encoding::Jpeg => true,
+        }
+    }
+
+    /// Kinds of uploads that are turned away, as `GET /limits` lists them.
+    pub fn unsupported(&self) -> Vec<String> {
+        let formats = [(Format::Heif, "heif"), (Format::Raw, "raw"), (Format::Video, "video")];
+
+        formats
+            .iter()
+            .filter(|(format, _)| !self.decodes(*format))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    /// The first of `renditions` that can't be encoded, if any.
+    pub fn missing_encoding<'a>(&self, renditions: &'a [Rendition]) -> Option<&'a Rendition> {
+        renditions.iter().find(|rendition| !self.encodes(rendition.encoding))
+    }
+
+    /// Log what the server can do, and warn about what it can't.
+    pub fn report(&self) {
+        let features = [
+            (self.webp, "WebP", "animated copies can't be made"),
+            (self.avif, "AVIF", "renditions aren't converted for browsers that prefer AVIF"),
+            (self.heif, "HEIF", "HEIC and HEIF uploads are turned away"),
+            (self.ffmpeg, "ffmpeg", "video uploads are turned away"),
+            (self.raw, "dcraw", "camera RAW uploads are turned away"),
+        ];
+
+        for (available, name, consequence) in features.iter() {
+            if *available {
+                info!("{} is available", name);
+            } else {
+                warn!("{} isn't available, so {}", name, consequence);
+            }
+        }
+    }
+}
+
+/// Whether `program` runs and succeeds with `args`.
+fn runs(program: &str, args: &[&str]) -> bool {
+    Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Whether `program` can be started at all, for programs without a flag that succeeds.
+fn installed(program: &str) -> bool {
+    Command::new(program).stdin(Stdio::null()).output().is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TestServer;
+    use hyper::{header, Body, Method, StatusCode};
+    use serde_json::{json, Value};
+    use wire::FileMetadata;
+
+    #[test]
+    fn unsupported_formats() {
+        let everything = Capabilities {
+            webp: true,
+            avif: true,
+            heif: true,
+            ffmpeg: true,
+            raw: true,
+        };
+        assert!(everything.unsupported().is_empty());
+
+        let bare = Capabilities {
+            webp: false,
+            avif: false,
+            heif: false,
+            ffmpeg: false,
+            raw: false,
+        };
+        assert!(bare.decodes(Format::Image));
+        assert!(!bare.decodes(Format::Video));
+        assert_eq!(bare.unsupported(), vec!["heif", "raw", "video"]);
+
+        let jpeg = Rendition {
+            name: "small".to_string(),
+            height: 100,
+            encoding: Encoding::Jpeg,
+            quality: 80,
+        };
+        let webp = Rendition {
+            encoding: Encoding::Webp,
+            ..jpeg.clone()
+        };
+        let renditions = [jpeg, webp];
+        assert_eq!(bare.missing_encoding(&renditions).map(|rendition| rendition.encoding), Some(Encoding::Webp));
+        assert!(everything.missing_encoding(&renditions).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn capabilities() {
+        let server = TestServer::start().await;
+        let key = server.signup("user@example.com").await;
+
+        let limits = server.json(Method::GET, "/limits", &()).await;
+        assert_eq!(limits["unsupported_formats"], json!(server.state.capabilities.unsupported()));
+
+        // Whether `ffmpeg` is missing or can't read it, a broken video is turned away cleanly.
+        let metadata = FileMetadata {
+            last_modified: 0,
+            name: "clip.mp4".into(),
+            mime: "video/mp4".into(),
+        };
+        let metadata = base64::encode_config(serde_json::to_vec(&metadata).unwrap(), base64::URL_SAFE);
+        let headers = [("upload-metadata", metadata), (header::CONTENT_TYPE.as_str(), "video/mp4".to_string())];
+        let video = b"\0\0\0\x18ftypmp42\0\0\0\0".to_vec();
+
+        let path = format!("/file?key={}", key);
+        let (status, body) = server.request(Method::POST, &path, &headers, Body::from(video)).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let error: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["code"], "unsupported_format");
+    }
+}
@@ -0,0 +1,183 @@
+//! Stored File Cleanup
+//!
+//! Objects in storage can outlive their file record when the server stops between deleting the
+//! record and deleting the objects. Partial uploads in the temp directory are removed before the
+//! server starts, but looking through storage takes as long as the library is big, so it runs in
+//! the background while requests are served. It looks at `CLEAN_BATCH` objects at a time, pausing
+//! for `clean_delay_ms` in between, and records how far it got under `CLEAN_PROGRESS` in the
+//! default tree, so that a server that stops part way through carries on from there.
+
+use crate::{common::AppState, error::ApiResult, storage, trash};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::{fs, time};
+use tracing::{info, warn};
+
+/// Objects that are looked at between pauses.
+const CLEAN_BATCH: usize = 500;
+
+const CLEAN_PROGRESS: &[u8] = b"clean_progress";
+
+/// The last object that was looked at.
+#[derive(Serialize, Deserialize, Debug)]
+struct Progress {
+    kind: String,
+    key: String,
+}
+
+/// Ids of the files that have an object of `kind` in storage.
+pub async fn stored_ids(state: &AppState, kind: &str) -> ApiResult<Vec<String>> {
+    let prefix = [kind, "/"].concat();
+    let keys = state
+        .storage
+        .list(&prefix, None)
+        .try_collect::<Vec<String>>()
+        .await?;
+
+    Ok(keys.into_iter().map(|key| key[prefix.len()..].to_string()).collect())
+}
+
+/// Whether the stored objects of `file_id` belong to neither a file record nor the trash. An
+/// upload keeps its file in the temp directory until its record exists, so that is looked at
+/// first.
+pub fn is_orphan(state: &AppState, trashed: &HashSet<String>, file_id: &str) -> ApiResult<bool> {
+    if state.temp_path.join(file_id).exists() {
+        return Ok(false);
+    }
+
+    Ok(state.files.get(file_id.as_bytes())?.is_none() && !trashed.contains(file_id))
+}
+
+/// Remove the partial uploads that were left in the temp directory, which must happen before
+/// any upload starts.
+pub async fn clean_temp(state: &AppState) -> ApiResult<usize> {
+    let mut removed = 0;
+
+    let mut iter = fs::read_dir(&state.temp_path).await?;
+    while let Some(entry) = iter.next_entry().await? {
+        if fs::remove_file(entry.path()).await.is_ok() {
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Record that every object of `kind` up to `key` has been looked at.
+pub fn save_progress(state: &AppState, kind: &str, key: &str) -> ApiResult<()> {
+    let progress = Progress {
+        kind: kind.to_string(),
+        key: key.to_string(),
+    };
+    state.db.insert(CLEAN_PROGRESS, bincode::serialize(&progress).unwrap())?;
+    Ok(())
+}
+
+async fn clean_kind(state: &AppState, kind: &str, start_after: Option<&str>) -> ApiResult<usize> {
+    let prefix = [kind, "/"].concat();
+    let delay = Duration::from_millis(state.config.clean_delay_ms);
+
+    let mut trashed = trash::file_ids(state)?;
+    let mut keys = state.storage.list(&prefix, start_after);
+    let mut removed = 0;
+    let mut looked_at = 0;
+
+    while let Some(key) = keys.try_next().await? {
+        let file_id = &key[prefix.len()..];
+
+        if is_orphan(state, &trashed, file_id)? {
+            // The file may have been moved to the trash since the trash was read.
+            trashed = trash::file_ids(state)?;
+            if is_orphan(state, &trashed, file_id)? && state.storage.delete(&key).await.is_ok() {
+                removed += 1;
+            }
+        }
+
+        looked_at += 1;
+        if looked_at % CLEAN_BATCH == 0 {
+            save_progress(state, kind, &key)?;
+            time::sleep(delay).await;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Remove stored files that don't belong to any file record, starting where the last run
+/// stopped if it didn't finish.
+pub async fn clean_files(state: &AppState) -> ApiResult<usize> {
+    let kinds = storage::kinds(&state.config);
+
+    // Renditions that are no longer configured aren't looked at, so their progress is dropped.
+    let (first, mut start_after) = match state.db.get(CLEAN_PROGRESS)? {
+        Some(progress_bytes) => {
+            let progress: Progress = bincode::deserialize(&progress_bytes).unwrap();
+            match kinds.iter().position(|kind| *kind == progress.kind) {
+                Some(first) => (first, Some(progress.key)),
+                None => (0, None),
+            }
+        }
+        None => (0, None),
+    };
+
+    let mut removed = 0;
+    for kind in &kinds[first..] {
+        removed += clean_kind(state, kind, start_after.take().as_deref()).await?;
+    }
+
+    state.db.remove(CLEAN_PROGRESS)?;
+    Ok(removed)
+}
+
+/// Clean up stored files in the background after the server has started.
+pub async fn cleaner(state: AppState) {
+    match clean_files(&state).await {
+        Ok(removed) => info!("Removed {} files", removed),
+        Err(err) => warn!(error = %err.chain(), "Couldn't clean up stored files"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn clean_stored_files() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let file_id = server.upload(&key, "photo.png", png(8, 8)).await;
+
+        let state = &server.state;
+        let kinds = crate::storage::kinds(&state.config);
+        let put_orphan = |kind: &str, orphan_id: &str| {
+            let path = state.temp_path.join(["orphan.", kind, ".", orphan_id].concat());
+            std::fs::write(&path, b"orphan").unwrap();
+            let key = crate::storage::key(kind, orphan_id);
+            async move { state.storage.put(&key, &path).await.unwrap() }
+        };
+        put_orphan(&kinds[0], "0000").await;
+        put_orphan(&kinds[0], "zzzz").await;
+        put_orphan(&kinds[1], "0000").await;
+
+        // A run that stopped part way through the first kind carries on from there.
+        let stopped_at = crate::storage::key(&kinds[0], "0001");
+        crate::clean::save_progress(state, &kinds[0], &stopped_at).unwrap();
+        assert_eq!(crate::clean::clean_files(state).await.unwrap(), 2);
+
+        let exists = |kind: &str, id: &str| {
+            let key = crate::storage::key(kind, id);
+            async move { state.storage.exists(&key).await }
+        };
+        assert!(exists(&kinds[0], "0000").await.unwrap());
+        assert!(!exists(&kinds[0], "zzzz").await.unwrap());
+        assert!(!exists(&kinds[1], "0000").await.unwrap());
+        assert!(exists(&kinds[0], &file_id).await.unwrap());
+
+        // The next run starts over.
+        assert_eq!(crate::clean::clean_files(state).await.unwrap(), 1);
+        assert!(!exists(&kinds[0], "0000").await.unwrap());
+        assert!(exists(&kinds[0], &file_id).await.unwrap());
+    }
+}
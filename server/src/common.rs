@@ -1,10 +1,35 @@
+use crate::album::{bulk, gc};
+use crate::capabilities::Capabilities;
+use crate::config::Config;
+use crate::detect::{self, Detector};
 use crate::error::{ApiError, ApiResult};
+use crate::events;
+use crate::forwarded;
+use crate::jobs;
+use crate::limit::{ConcurrencyLimiter, RateLimiter};
+use crate::mail::Mailer;
+use crate::metrics::Metrics;
+use crate::oidc;
+use crate::placeholder::Placeholder;
+use crate::pool::Pool;
+use crate::regenerate::Regenerator;
+use crate::resize;
+use crate::storage::Storage;
+use crate::trace;
+use bytes::Bytes;
+use chrono::offset::Utc;
+use futures::{Stream, TryStreamExt};
 use hyper::http::request::Parts;
-use hyper::{header, Body, Response, StatusCode};
+use hyper::{header, Body, Method, Response, StatusCode};
 use rand::{thread_rng, Rng};
+use routerify::ext::RequestExt;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use wire::FileMetadata;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant};
+use wire::{AlbumEvent, Animation, FileEdit, FileLabels, FileMetadata, Location, Video};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct User<'a> {
@@ -12,22 +37,143 @@ pub struct User<'a> {
     pub password: &'a str,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct File<'a, 'b, 'c> {
     pub owner_id: &'a str,
 
     pub width: i32,
     pub height: i32,
 
+    pub uploaded: i64,
+
+    /// Type that the contents were checked to have, while `metadata.mime` is what the client
+    /// declared.
+    pub detected_mime: &'a str,
+
+    /// Missing for files that were uploaded before placeholders were made.
+    pub placeholder: Option<Placeholder>,
+
+    /// Perceptual hash, which is indexed in `phashes`. Missing for files that can't be read and
+    /// for those that were rendered before hashes were made.
+    pub phash: Option<u64>,
+
+    /// Where the photo was taken, if its EXIF data says.
+    pub location: Option<Location>,
+
+    /// Model of the camera that took the photo, if its EXIF data says.
+    pub camera: Option<String>,
+
+    /// Size of the original in bytes. Missing for files that were uploaded before sizes were
+    /// recorded, until the `Measure` job reaches them.
+    pub size: Option<u64>,
+
+    /// SHA-256 of the original, which is missing like `size`.
+    pub hash: Option<[u8; 32]>,
+
+    /// Size in bytes of each rendition by name, which is empty like `size` is missing.
+    pub rendition_sizes: Vec<(String, u64)>,
+
+    /// Set for files that their client encrypted before uploading, which are stored without
+    /// renditions since the server can't read them.
+    pub encrypted: bool,
+
+    /// Set for animated images, which have an animated copy of their tallest rendition.
+    pub animation: Option<Animation>,
+
+    /// Set for videos, with what `ffprobe` read from them. Missing for videos that it couldn't
+    /// read, and for those that were rendered before videos were probed.
+    pub video: Option<Video<'static>>,
+
+    /// How the file is turned for display, which its renditions were rendered with.
+    pub edit: FileEdit,
+    /// Goes up with every edit, so that the renditions of each have their own entity tags.
+    pub revision: u32,
+
+    /// The motion of a Live Photo, on its still.
+    pub motion_id: Option<String>,
+    /// The still of a Live Photo, on its motion.
+    pub still_id: Option<String>,
+
+    /// Rating, title and tags, which are read from XMP sidecars.
+    pub labels: FileLabels<'static>,
+
+    /// Set for files that are left out of their owner's library, while they stay in albums and
+    /// listings.
+    pub hidden: bool,
+
+    /// Set for files in their owner's locked folder, which are left out of the library, albums
+    /// and searches, and which can only be seen with a token from `POST /user/auth/elevate`.
+    pub locked: bool,
+
     #[serde(borrow)]
     pub metadata: FileMetadata<'b, 'c>,
 }
 
+impl<'a, 'b, 'c> File<'a, 'b, 'c> {
+    /// Whether the file is shown in its owner's library.
+    pub fn in_library(&self) -> bool {
+        !self.hidden && !self.locked
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LoginFailures {
+    pub count: u32,
+    pub locked_until: i64,
+}
+
+/// Stored in `sessions` under the full session key.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Session {
+    /// Set for sessions that are held in a cookie, which can't be trusted on their own for
+    /// requests that change anything.
+    pub csrf_token: Option<String>,
+    /// Name that the user gave the session when logging in.
+    pub label: Option<String>,
+    pub user_agent: Option<String>,
+    pub created: i64,
+    /// Only kept to within `LAST_USED_RESOLUTION`, so that most requests don't write.
+    pub last_used: i64,
+}
+
+/// Seconds that may pass before a session's `last_used` time is brought up to date.
+const LAST_USED_RESOLUTION: i64 = 60;
+
+/// Name of the cookie that holds the session key in cookie mode.
+pub const SESSION_COOKIE: &str = "photos_session";
+/// Header that repeats the CSRF token of a cookie session.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Single use token that is emailed to a user.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EmailToken<'a> {
+    pub user_id: &'a str,
+    pub expires: i64,
+}
+
+/// Stored in `elevations` under the token, which only counts for the session that it was given to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Elevation<'a> {
+    pub session_key: &'a str,
+    pub expires: i64,
+}
+
+/// Header that carries a token from `POST /user/auth/elevate`.
+pub const ELEVATION_HEADER: &str = "x-elevation-token";
+
+/// Shared by every request. Cloning it is cheap, which lets background tasks keep their own copy.
+#[derive(Clone)]
 pub struct AppState {
     pub db: sled::Db,
     pub users: sled::Tree,
     pub emails: sled::Tree,
     pub sessions: sled::Tree,
+    pub login_failures: sled::Tree,
+    pub login_audit: sled::Tree,
+    pub verified: sled::Tree,
+    pub verify_tokens: sled::Tree,
+    pub reset_tokens: sled::Tree,
+    pub elevations: sled::Tree,
     pub files: sled::Tree,
     pub file_names: sled::Tree,
     pub albums: sled::Tree,
@@ -35,23 +181,83 @@ pub struct AppState {
     pub fragments: sled::Tree,
     pub user_to_album: sled::Tree,
     pub album_to_user: sled::Tree,
-    pub delete: sled::Tree,
+    pub activity: sled::Tree,
+    pub jobs: sled::Tree,
+    pub geo: sled::Tree,
+    pub phashes: sled::Tree,
+    /// What the detection service found in each file.
+    pub detections: sled::Tree,
+    pub detection_labels: sled::Tree,
+    pub libraries: sled::Tree,
+    pub library_fragments: sled::Tree,
+    pub trash: sled::Tree,
+    pub rebuilds: sled::Tree,
+    pub stats: sled::Tree,
+    pub oidc_states: sled::Tree,
+    pub invites: sled::Tree,
+    /// Bytes of originals that a user may keep, by user id, for users who were given a quota.
+    pub quotas: sled::Tree,
+    /// Users who were invited as administrators.
+    pub admins: sled::Tree,
+    /// Bytes served today, by session key and by user.
+    pub bandwidth: sled::Tree,
+    /// The last file that each Live Photo content identifier came with, by owner.
+    pub content_ids: sled::Tree,
+    /// Originals that failed verification, by file id.
+    pub damaged: sled::Tree,
+    /// Albums whose members other than the owner of a file are served its original stripped of
+    /// metadata.
+    pub stripped_albums: sled::Tree,
+    /// Links that guests upload to albums through, by token.
+    pub contribution_links: sled::Tree,
+    /// Uploads through moderated links that wait for the owner of the album, by album and file.
+    pub pending_uploads: sled::Tree,
+    /// Files that were resized on demand, by storage key.
+    pub custom_cache: sled::Tree,
 
+    pub config: Config,
+    pub storage: Arc<dyn Storage>,
+    pub detector: Option<Arc<dyn Detector>>,
+    pub mailer: Mailer,
+    pub events: broadcast::Sender<AlbumEvent>,
+    pub bulk: bulk::Worker,
+    pub regenerator: Regenerator,
+    pub collector: gc::Collector,
+    pub job_queue: jobs::Queue,
+    pub metrics: Arc<Metrics>,
+    pub oidc: Arc<oidc::Provider>,
+    pub auth_ip_limiter: Arc<RateLimiter>,
+    pub auth_email_limiter: Arc<RateLimiter>,
+    pub upload_limiter: Arc<ConcurrencyLimiter>,
+    pub resize_limiter: Arc<ConcurrencyLimiter>,
+    pub custom_cache_size: Arc<resize::CacheSize>,
+    pub image_pool: Arc<Pool>,
     pub argon_config: argon2::Config<'static>,
-    pub upload_path: PathBuf,
-    pub medium_path: PathBuf,
-    pub small_path: PathBuf,
+    /// What libvips and the installed programs can decode and encode.
+    pub capabilities: Capabilities,
+    /// Local scratch space for uploads that are still being processed.
     pub temp_path: PathBuf,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        let db = sled::Config::new().temporary(true).open().unwrap();
+    pub fn new(config: Config, storage: Arc<dyn Storage>) -> Self {
+        let db = match config.db_path {
+            Some(ref path) => sled::open(path).expect("Couldn't open database"),
+            None => sled::Config::new().temporary(true).open().unwrap(),
+        };
+        let custom_cache = db.open_tree(b"custom_cache").unwrap();
+        let custom_cache_size = Arc::new(resize::CacheSize::count(&custom_cache));
 
         AppState {
             users: db.open_tree(b"users").unwrap(),
             emails: db.open_tree(b"emails").unwrap(),
             sessions: db.open_tree(b"sessions").unwrap(),
+            login_failures: db.open_tree(b"login_failures").unwrap(),
+            login_audit: db.open_tree(b"login_audit").unwrap(),
+            verified: db.open_tree(b"verified").unwrap(),
+            verify_tokens: db.open_tree(b"verify_tokens").unwrap(),
+            reset_tokens: db.open_tree(b"reset_tokens").unwrap(),
+            elevations: db.open_tree(b"elevations").unwrap(),
             files: db.open_tree(b"files").unwrap(),
             file_names: db.open_tree(b"file_names").unwrap(),
             albums: db.open_tree(b"albums").unwrap(),
@@ -59,41 +265,156 @@ impl AppState {
             fragments: db.open_tree(b"fragments").unwrap(),
             user_to_album: db.open_tree(b"user_to_album").unwrap(),
             album_to_user: db.open_tree(b"album_to_user").unwrap(),
-            delete: db.open_tree(b"delete").unwrap(),
-            db: db,
+            activity: db.open_tree(b"activity").unwrap(),
+            jobs: db.open_tree(b"jobs").unwrap(),
+            geo: db.open_tree(b"geo").unwrap(),
+            phashes: db.open_tree(b"phashes").unwrap(),
+            detections: db.open_tree(b"detections").unwrap(),
+            detection_labels: db.open_tree(b"detection_labels").unwrap(),
+            libraries: db.open_tree(b"libraries").unwrap(),
+            library_fragments: db.open_tree(b"library_fragments").unwrap(),
+            trash: db.open_tree(b"trash").unwrap(),
+            rebuilds: db.open_tree(b"rebuilds").unwrap(),
+            stats: db.open_tree(b"stats").unwrap(),
+            oidc_states: db.open_tree(b"oidc_states").unwrap(),
+            invites: db.open_tree(b"invites").unwrap(),
+            quotas: db.open_tree(b"quotas").unwrap(),
+            admins: db.open_tree(b"admins").unwrap(),
+            bandwidth: db.open_tree(b"bandwidth").unwrap(),
+            content_ids: db.open_tree(b"content_ids").unwrap(),
+            damaged: db.open_tree(b"damaged").unwrap(),
+            stripped_albums: db.open_tree(b"stripped_albums").unwrap(),
+            contribution_links: db.open_tree(b"contribution_links").unwrap(),
+            pending_uploads: db.open_tree(b"pending_uploads").unwrap(),
+            custom_cache,
+            db,
 
+            detector: detect::open(config.detection.as_ref()),
+            mailer: Mailer::new(config.smtp.as_ref()),
+            events: broadcast::channel(events::CHANNEL_CAPACITY).0,
+            bulk: bulk::Worker::spawn(),
+            regenerator: Regenerator::default(),
+            collector: gc::Collector::default(),
+            job_queue: jobs::Queue::default(),
+            metrics: Arc::new(Metrics::default()),
+            oidc: Arc::new(oidc::Provider::default()),
+            auth_ip_limiter: Arc::new(RateLimiter::new(config.auth_ip_limit)),
+            auth_email_limiter: Arc::new(RateLimiter::new(config.auth_email_limit)),
+            upload_limiter: Arc::new(ConcurrencyLimiter::new(config.max_uploads_per_user)),
+            resize_limiter: Arc::new(ConcurrencyLimiter::new(config.max_resizes_per_user)),
+            custom_cache_size,
+            image_pool: Arc::new(Pool::new(config.vips_threads, config.vips_queue)),
             argon_config: argon2::Config::default(),
+            capabilities: Capabilities::probe(),
+
+            temp_path: config.data_path.join("temp"),
 
-            upload_path: PathBuf::from("data/uploads"),
-            medium_path: PathBuf::from("data/medium"),
-            small_path: PathBuf::from("data/small"),
-            temp_path: PathBuf::from("data/temp"),
+            config,
+            storage,
         }
     }
 
     pub fn create_dirs(&self) -> std::io::Result<()> {
-        std::fs::create_dir_all(&self.upload_path)?;
-        std::fs::create_dir_all(&self.medium_path)?;
-        std::fs::create_dir_all(&self.small_path)?;
         std::fs::create_dir_all(&self.temp_path)?;
         Ok(())
     }
 }
 
-pub async fn join(body: Body) -> ApiResult<Vec<u8>> {
-    use futures::TryStreamExt;
+/// Read the whole body of a request, failing with `Timeout` if it takes longer than
+/// `body_timeout_seconds` or stalls, and with `PayloadTooLarge` past `max_body_bytes`.
+pub async fn join(parts: &Parts, body: Body) -> ApiResult<Vec<u8>> {
+    let AppState { ref config, .. } = parts.data().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(config.body_timeout_seconds);
+    let idle = Duration::from_secs(config.body_idle_seconds);
 
     let mut data = vec![];
     let mut stream = body.into_stream();
 
-    while let Some(chunk) = stream.try_next().await? {
+    while let Some(chunk) = next_chunk(&mut stream, deadline, idle).await? {
+        if data.len() + chunk.len() > config.max_body_bytes {
+            return Err(ApiError::PayloadTooLarge);
+        }
         data.extend_from_slice(&chunk);
     }
 
     Ok(data)
 }
 
+/// Wait for the next chunk of a body, failing with `Timeout` at `deadline` or once nothing has
+/// arrived for `idle`.
+pub async fn next_chunk<S, E>(stream: &mut S, deadline: Instant, idle: Duration) -> ApiResult<Option<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    ApiError: From<E>,
+{
+    match time::timeout_at(deadline.min(Instant::now() + idle), stream.try_next()).await {
+        Ok(chunk) => Ok(chunk?),
+        Err(_) => Err(ApiError::Timeout),
+    }
+}
+
+/// The value of the cookie called `name` that a request carries, if any.
+pub fn cookie<'a>(parts: &'a Parts, name: &str) -> Option<&'a str> {
+    parts
+        .headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// The session key that a request carries in its cookies, if any.
+pub fn session_cookie(parts: &Parts) -> Option<&str> {
+    cookie(parts, SESSION_COOKIE)
+}
+
+/// Session key from an `Authorization: Bearer` header, from the session cookie in cookie mode, or
+/// from the `key` query parameter when `allow_query_key` is set. Cookie sessions also have to
+/// send their CSRF token with anything but `GET` and `HEAD` requests, since browsers attach
+/// cookies to requests that other sites make.
 pub fn require_key(parts: &Parts) -> ApiResult<&str> {
+    if let Some(authorization) = parts.headers.get(header::AUTHORIZATION) {
+        let authorization = authorization.to_str().map_err(|_| ApiError::Unauthorized)?;
+        return authorization
+            .strip_prefix("Bearer ")
+            .map(str::trim)
+            .ok_or(ApiError::Unauthorized);
+    }
+
+    let AppState {
+        ref config,
+        ref sessions,
+        ..
+    } = parts.data().unwrap();
+
+    if config.cookie_sessions {
+        if let Some(key) = session_cookie(parts) {
+            if parts.method != Method::GET && parts.method != Method::HEAD {
+                let token = parts
+                    .headers
+                    .get(CSRF_HEADER)
+                    .and_then(|token| token.to_str().ok())
+                    .ok_or(ApiError::Unauthorized)?;
+
+                let session_bytes = sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
+                let session: Session = bincode::deserialize(&session_bytes).unwrap();
+                if session.csrf_token.as_deref() != Some(token) {
+                    return Err(ApiError::Unauthorized);
+                }
+            }
+
+            return Ok(key);
+        }
+    }
+
+    if !config.allow_query_key {
+        return Err(ApiError::Unauthorized);
+    }
+
     let query_str = parts.uri.query().ok_or(ApiError::Unauthorized)?;
     let queries = querystring::querify(query_str);
     let (_, key) = queries
@@ -104,12 +425,61 @@ pub fn require_key(parts: &Parts) -> ApiResult<&str> {
 }
 
 pub fn test_logged_in(sessions: &sled::Tree, key: &str) -> ApiResult<()> {
-    sessions
+    let session_bytes = sessions
         .get(key.as_bytes())?
         .ok_or(ApiError::Unauthorized)?;
+
+    let mut session: Session = bincode::deserialize(&session_bytes).unwrap();
+    let now = Utc::now().timestamp();
+    if now - session.last_used >= LAST_USED_RESOLUTION {
+        session.last_used = now;
+
+        // Swapping instead of inserting keeps a session that was just logged out from coming back.
+        let new_bytes = bincode::serialize(&session).unwrap();
+        let _ = sessions.compare_and_swap(key.as_bytes(), Some(session_bytes), Some(new_bytes))?;
+    }
+
+    if let Some((user_id, _)) = key.split_once('.') {
+        trace::record_user(user_id);
+    }
+
+    Ok(())
+}
+
+/// Fail with `ElevationRequired` unless the request carries a token from `POST /user/auth/elevate`
+/// that was given to the session `key` and hasn't expired. The token is sent in the
+/// `x-elevation-token` header, or in the `elevation` query parameter for links like those of
+/// images.
+pub fn require_elevation(parts: &Parts, key: &str) -> ApiResult<()> {
+    let AppState { ref elevations, .. } = parts.data().unwrap();
+
+    let token = match parts.headers.get(ELEVATION_HEADER) {
+        Some(token) => token.to_str().ok(),
+        None => parts.uri.query().and_then(|query_str| {
+            let queries = querystring::querify(query_str);
+            queries.into_iter().find(|(k, _)| k == &"elevation").map(|(_, token)| token)
+        }),
+    };
+    let token = token.ok_or(ApiError::ElevationRequired)?;
+
+    let elevation_bytes = elevations.get(token.as_bytes())?.ok_or(ApiError::ElevationRequired)?;
+    let elevation: Elevation = bincode::deserialize(&elevation_bytes).unwrap();
+    if elevation.session_key != key || elevation.expires < Utc::now().timestamp() {
+        return Err(ApiError::ElevationRequired);
+    }
+
     Ok(())
 }
 
+/// Whether `file_id` is stored and can be found by searches, which leave out files that are in the
+/// trash or locked.
+pub fn searchable(files: &sled::Tree, file_id: &str) -> ApiResult<bool> {
+    match files.get(file_id.as_bytes())? {
+        Some(file_bytes) => Ok(!bincode::deserialize::<File>(&file_bytes).unwrap().locked),
+        None => Ok(false),
+    }
+}
+
 pub fn auth_album(parts: &Parts) -> Option<&str> {
     let query_str = parts.uri.query()?;
     let queries = querystring::querify(query_str);
@@ -117,6 +487,33 @@ pub fn auth_album(parts: &Parts) -> Option<&str> {
     Some(album)
 }
 
+/// Throttle credential guessing against login and registration.
+pub fn limit_auth(parts: &Parts, email: &str) -> ApiResult<()> {
+    let AppState {
+        ref auth_ip_limiter,
+        ref auth_email_limiter,
+        ..
+    } = parts.data().unwrap();
+
+    auth_ip_limiter.check(&forwarded::client_ip(parts).to_string())?;
+    auth_email_limiter.check(&email.to_lowercase())?;
+
+    Ok(())
+}
+
+/// Whether `If-None-Match` names `etag`, so that the client's cached copy is still current.
+pub fn etag_matches(parts: &Parts, etag: &str) -> bool {
+    let if_none_match = match parts.headers.get(header::IF_NONE_MATCH) {
+        Some(if_none_match) => if_none_match.to_str().unwrap_or(""),
+        None => return false,
+    };
+
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    })
+}
+
 pub fn new_id(size: usize) -> String {
     let bytes: Vec<u8> = (0..size).map(|_| thread_rng().gen()).collect();
     base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
@@ -137,3 +534,24 @@ pub fn respond_ok_empty() -> ApiResult<Response<Body>> {
         .body(Body::empty())
         .unwrap())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::TestServer;
+    use hyper::{Body, Method, StatusCode};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bearer_key() {
+        let server = TestServer::start_with(|config| config.allow_query_key = false).await;
+        let key = server.signup("owner@example.com").await;
+
+        let (status, _) = server
+            .request(Method::GET, &format!("/user/auth?key={}", key), &[], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let bearer = [("authorization", format!("Bearer {}", key))];
+        let (status, _) = server.request(Method::GET, "/user/auth", &bearer, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
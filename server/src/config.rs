@@ -0,0 +1,497 @@
+//! Server Configuration
+//!
+//! All settings are read from `PHOTOS_*` environment variables when the server starts, falling
+//! back to defaults that are suitable for local development.
+
+use crate::limit::Limit;
+use ipnet::IpNet;
+use std::convert::TryInto;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Plain HTTP address that redirects every request to the HTTPS listener.
+    pub redirect_addr: Option<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+/// An OpenID Connect provider that users can log in through.
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    /// Serves the provider's configuration at `/.well-known/openid-configuration`.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends the browser back to, which has to reach `GET /user/oidc/callback`.
+    pub redirect_url: String,
+    /// Whether a user is created for a verified email that isn't registered yet, while
+    /// registration is open.
+    pub auto_provision: bool,
+}
+
+/// A service that finds what is in photos, which every image is sent to once it is stored.
+#[derive(Clone, Debug)]
+pub struct DetectionConfig {
+    /// Receives a rendition as the body of a `POST` and answers with the JSON `Detections` in it.
+    pub url: String,
+    /// Sent as a bearer token, for services that want one.
+    pub token: Option<String>,
+    /// Name of the rendition that is sent.
+    pub rendition: String,
+    pub timeout_seconds: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum StorageConfig {
+    /// Keep files below the data directory.
+    Local,
+    /// Keep files in an S3 compatible bucket.
+    S3 {
+        bucket: String,
+        endpoint: Option<String>,
+        region: Option<String>,
+    },
+}
+
+/// Key that stored files are encrypted with, which is left out of `Debug` so that it can't end up
+/// in the log.
+#[derive(Clone)]
+pub struct StorageKey(pub [u8; 32]);
+
+impl std::fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+/// Who may register with `POST /user`. Administrators have to register while it's open, and
+/// verify their email, before they can make invitations.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Registration {
+    Open,
+    /// Only with a code from `POST /admin/invites`.
+    Invite,
+    Closed,
+}
+
+impl FromStr for Registration {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "open" => Ok(Registration::Open),
+            "invite" => Ok(Registration::Invite),
+            "closed" => Ok(Registration::Closed),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Webp,
+    Avif,
+    Jpeg,
+}
+
+impl Encoding {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Encoding::Webp => "image/webp",
+            Encoding::Avif => "image/avif",
+            Encoding::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "webp" => Ok(Encoding::Webp),
+            "avif" => Ok(Encoding::Avif),
+            "jpeg" => Ok(Encoding::Jpeg),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A downscaled copy of every upload.
+#[derive(Clone, Debug)]
+pub struct Rendition {
+    /// Quality name that `file::serve` accepts, which is also the storage kind.
+    pub name: String,
+    pub height: u32,
+    pub encoding: Encoding,
+    /// Encoder quality from 1 to 100.
+    pub quality: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsConfig>,
+    pub data_path: PathBuf,
+    /// Location of the sled database, or `None` to use a temporary one.
+    pub db_path: Option<PathBuf>,
+    pub storage: StorageConfig,
+    /// Keys that stored files are encrypted with, or none to store them as they are. The first
+    /// one encrypts new files, and the others are only kept to read files from before a rotation.
+    pub storage_keys: Vec<StorageKey>,
+    /// `tracing` filter directive, e.g. `info` or `server=debug,hyper=warn`.
+    pub log_level: String,
+    pub log_json: bool,
+    /// Login and registration attempts allowed per client IP.
+    pub auth_ip_limit: Limit,
+    /// Login and registration attempts allowed per email address.
+    pub auth_email_limit: Limit,
+    /// Consecutive failed logins after which an account is temporarily locked.
+    pub lockout_threshold: u32,
+    pub lockout_seconds: i64,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are believed about the
+    /// address of the client.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Base URL of the web frontend, used to build links in emails.
+    pub public_url: String,
+    pub smtp: Option<SmtpConfig>,
+    pub oidc: Option<OidcConfig>,
+    pub detection: Option<DetectionConfig>,
+    pub verify_token_seconds: i64,
+    pub reset_token_seconds: i64,
+    /// How long a password that was entered again opens the locked folder for.
+    pub elevation_seconds: i64,
+    /// How long album activity is kept for, or 0 to keep it forever.
+    pub activity_retention_days: i64,
+    /// How long deleted files and albums can be restored before they are removed for good.
+    pub trash_retention_days: i64,
+    /// How often every original is hashed again to find damage, or 0 to only do it when asked.
+    pub verify_interval_days: i64,
+    /// Largest file that can be uploaded.
+    pub max_upload_bytes: u64,
+    /// Bytes that a single session may be served in a day, or 0 for no limit.
+    pub token_bytes_per_day: u64,
+    /// Bytes that all sessions of a user may be served in a day, or 0 for no limit.
+    pub user_bytes_per_day: u64,
+    /// Time allowed for the body of an upload to arrive.
+    pub upload_timeout_seconds: u64,
+    /// Time allowed for the body of any other request to arrive.
+    pub body_timeout_seconds: u64,
+    /// Largest body of a request that isn't an upload, which is read into memory whole.
+    pub max_body_bytes: usize,
+    /// Time allowed for the headers of a request to arrive.
+    pub header_timeout_seconds: u64,
+    /// Time that the body of a request may go without sending anything, so that clients can't
+    /// hold on to an upload by trickling it in.
+    pub body_idle_seconds: u64,
+    /// Uploads that a user may have underway at the same time.
+    pub max_uploads_per_user: usize,
+    /// Files that a single batch upload may hold.
+    pub max_batch_files: usize,
+    /// Files of a batch upload that are processed at the same time.
+    pub batch_upload_parallelism: usize,
+    /// Threads that decode and encode images.
+    pub vips_threads: usize,
+    /// Images that may wait for a thread before uploads are turned away.
+    pub vips_queue: usize,
+    /// Largest image that is decoded, in pixels, since decoding takes memory in proportion.
+    pub max_image_pixels: u64,
+    /// Frames that are kept in the animated copy of an animated image.
+    pub max_animation_frames: u32,
+    /// How far into a video its poster is taken from, as a fraction of its length.
+    pub poster_position: f64,
+    /// Frames of a video that are put side by side in its preview strip, or 0 for no strip.
+    pub preview_frames: u32,
+    /// Renditions that are made on upload, from tallest to shortest. Changing them only affects
+    /// files that are uploaded afterwards.
+    pub renditions: Vec<Rendition>,
+    /// Widths and heights that files may be resized to on demand, which are kept few so that
+    /// results can be cached.
+    pub custom_sizes: Vec<u32>,
+    /// Bytes of resized files that are kept on disk before the oldest are removed.
+    pub custom_cache_bytes: u64,
+    /// Files that a user may have resized on demand at the same time.
+    pub max_resizes_per_user: usize,
+    /// Whether session keys are still accepted in the `key` query parameter as well as in the
+    /// `Authorization` header. Keys in URLs end up in logs and referrers, but browsers can't send
+    /// headers for image sources or `EventSource`.
+    pub allow_query_key: bool,
+    /// Whether browsers may log in with an HttpOnly session cookie instead of handling the key.
+    pub cookie_sessions: bool,
+    /// Users that may use the `/admin` routes, along with those who were invited as
+    /// administrators.
+    pub admin_emails: Vec<String>,
+    /// Networks that the `/admin` routes may be used from, or none to allow any.
+    pub admin_networks: Vec<IpNet>,
+    pub registration: Registration,
+    /// Pause between files while renditions are regenerated, to leave room for other requests.
+    pub regenerate_delay_ms: u64,
+    /// Background jobs that can run at the same time.
+    pub job_workers: usize,
+    /// Pause between batches of stored files while leftovers are cleaned up after startup.
+    pub clean_delay_ms: u64,
+    /// Where metrics are served without authentication, which should only be reachable by the
+    /// scraper.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let data_path = var("PHOTOS_DATA").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("data"));
+
+        let db_path = if parse_var("PHOTOS_DB_TEMPORARY").unwrap_or(false) {
+            None
+        } else {
+            Some(var("PHOTOS_DB").map(PathBuf::from).unwrap_or_else(|| data_path.join("db")))
+        };
+
+        let tls = match (var("PHOTOS_TLS_CERT"), var("PHOTOS_TLS_KEY")) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert_path: PathBuf::from(cert),
+                key_path: PathBuf::from(key),
+                redirect_addr: parse_var("PHOTOS_TLS_REDIRECT_ADDR"),
+            }),
+            (None, None) => None,
+            _ => panic!("PHOTOS_TLS_CERT and PHOTOS_TLS_KEY must be set together"),
+        };
+
+        let storage = match var("PHOTOS_STORAGE").as_deref() {
+            None | Some("local") => StorageConfig::Local,
+            Some("s3") => StorageConfig::S3 {
+                bucket: var("PHOTOS_S3_BUCKET").expect("PHOTOS_S3_BUCKET must be set for s3 storage"),
+                endpoint: var("PHOTOS_S3_ENDPOINT"),
+                region: var("PHOTOS_S3_REGION"),
+            },
+            Some(other) => panic!("Unknown PHOTOS_STORAGE={:?}", other),
+        };
+
+        let storage_keys = match (var("PHOTOS_STORAGE_KEYS"), var("PHOTOS_STORAGE_KEYFILE")) {
+            (Some(keys), None) => parse_storage_keys(keys.split(',')),
+            (None, Some(path)) => {
+                let keys = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|err| panic!("Couldn't read PHOTOS_STORAGE_KEYFILE {:?}: {}", path, err));
+                parse_storage_keys(keys.lines())
+            }
+            (None, None) => vec![],
+            _ => panic!("Only one of PHOTOS_STORAGE_KEYS and PHOTOS_STORAGE_KEYFILE may be set"),
+        };
+
+        let smtp = var("PHOTOS_SMTP_HOST").map(|host| SmtpConfig {
+            host,
+            port: parse_var("PHOTOS_SMTP_PORT").unwrap_or(587),
+            username: var("PHOTOS_SMTP_USERNAME"),
+            password: var("PHOTOS_SMTP_PASSWORD"),
+            from: var("PHOTOS_SMTP_FROM").expect("PHOTOS_SMTP_FROM must be set with PHOTOS_SMTP_HOST"),
+        });
+
+        let public_url = var("PHOTOS_PUBLIC_URL").unwrap_or_else(|| "http://localhost:3000".to_string());
+
+        let oidc = var("PHOTOS_OIDC_ISSUER").map(|issuer| OidcConfig {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id: var("PHOTOS_OIDC_CLIENT_ID").expect("PHOTOS_OIDC_CLIENT_ID must be set with PHOTOS_OIDC_ISSUER"),
+            client_secret: var("PHOTOS_OIDC_CLIENT_SECRET")
+                .expect("PHOTOS_OIDC_CLIENT_SECRET must be set with PHOTOS_OIDC_ISSUER"),
+            redirect_url: var("PHOTOS_OIDC_REDIRECT_URL")
+                .unwrap_or_else(|| format!("{}/user/oidc/callback", public_url.trim_end_matches('/'))),
+            auto_provision: parse_var("PHOTOS_OIDC_AUTO_PROVISION").unwrap_or(false),
+        });
+
+        let renditions = parse_renditions(
+            &var("PHOTOS_RENDITIONS").unwrap_or_else(|| "medium:400:webp:75,small:10:webp:75".to_string()),
+        );
+
+        let custom_sizes =
+            parse_sizes(&var("PHOTOS_CUSTOM_SIZES").unwrap_or_else(|| "128,256,512,1024,2048".to_string()));
+
+        let detection = var("PHOTOS_DETECTION_URL").map(|url| DetectionConfig {
+            url,
+            token: var("PHOTOS_DETECTION_TOKEN"),
+            rendition: var("PHOTOS_DETECTION_RENDITION").unwrap_or_else(|| "medium".to_string()),
+            timeout_seconds: parse_var("PHOTOS_DETECTION_TIMEOUT_SECONDS").unwrap_or(30),
+        });
+        if let Some(ref detection) = detection {
+            if !renditions.iter().any(|rendition| rendition.name == detection.rendition) {
+                panic!("PHOTOS_DETECTION_RENDITION={:?} isn't one of PHOTOS_RENDITIONS", detection.rendition);
+            }
+        }
+
+        Config {
+            addr: parse_var("PHOTOS_ADDR").unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+            tls,
+            data_path,
+            db_path,
+            storage,
+            storage_keys,
+            log_level: var("PHOTOS_LOG").unwrap_or_else(|| "info".to_string()),
+            log_json: parse_var("PHOTOS_LOG_JSON").unwrap_or(false),
+            auth_ip_limit: Limit {
+                burst: parse_var("PHOTOS_AUTH_IP_BURST").unwrap_or(20),
+                per_minute: parse_var("PHOTOS_AUTH_IP_PER_MINUTE").unwrap_or(10),
+            },
+            auth_email_limit: Limit {
+                burst: parse_var("PHOTOS_AUTH_EMAIL_BURST").unwrap_or(5),
+                per_minute: parse_var("PHOTOS_AUTH_EMAIL_PER_MINUTE").unwrap_or(2),
+            },
+            lockout_threshold: parse_var("PHOTOS_LOCKOUT_THRESHOLD").unwrap_or(10),
+            lockout_seconds: parse_var("PHOTOS_LOCKOUT_SECONDS").unwrap_or(15 * 60),
+            trusted_proxies: parse_networks("PHOTOS_TRUSTED_PROXIES"),
+            public_url,
+            smtp,
+            oidc,
+            detection,
+            verify_token_seconds: parse_var("PHOTOS_VERIFY_TOKEN_SECONDS").unwrap_or(7 * 24 * 60 * 60),
+            reset_token_seconds: parse_var("PHOTOS_RESET_TOKEN_SECONDS").unwrap_or(60 * 60),
+            elevation_seconds: parse_var("PHOTOS_ELEVATION_SECONDS").unwrap_or(5 * 60),
+            activity_retention_days: parse_var("PHOTOS_ACTIVITY_RETENTION_DAYS").unwrap_or(90),
+            trash_retention_days: parse_var("PHOTOS_TRASH_RETENTION_DAYS").unwrap_or(30),
+            verify_interval_days: parse_var("PHOTOS_VERIFY_INTERVAL_DAYS").unwrap_or(30),
+            max_upload_bytes: parse_var("PHOTOS_MAX_UPLOAD_BYTES").unwrap_or(1 << 30),
+            token_bytes_per_day: parse_var("PHOTOS_TOKEN_BYTES_PER_DAY").unwrap_or(0),
+            user_bytes_per_day: parse_var("PHOTOS_USER_BYTES_PER_DAY").unwrap_or(0),
+            upload_timeout_seconds: parse_var("PHOTOS_UPLOAD_TIMEOUT_SECONDS").unwrap_or(30 * 60),
+            body_timeout_seconds: parse_var("PHOTOS_BODY_TIMEOUT_SECONDS").unwrap_or(60),
+            max_body_bytes: parse_var("PHOTOS_MAX_BODY_BYTES").unwrap_or(1 << 20),
+            header_timeout_seconds: parse_var("PHOTOS_HEADER_TIMEOUT_SECONDS").unwrap_or(30),
+            body_idle_seconds: parse_var("PHOTOS_BODY_IDLE_SECONDS").unwrap_or(60),
+            max_uploads_per_user: parse_var("PHOTOS_MAX_UPLOADS_PER_USER").unwrap_or(4),
+            max_batch_files: parse_var("PHOTOS_MAX_BATCH_FILES").unwrap_or(100),
+            batch_upload_parallelism: parse_var("PHOTOS_BATCH_UPLOAD_PARALLELISM").unwrap_or(4),
+            vips_threads: parse_var("PHOTOS_VIPS_THREADS").unwrap_or(2),
+            vips_queue: parse_var("PHOTOS_VIPS_QUEUE").unwrap_or(16),
+            max_image_pixels: parse_var("PHOTOS_MAX_IMAGE_PIXELS").unwrap_or(200_000_000),
+            max_animation_frames: parse_var("PHOTOS_MAX_ANIMATION_FRAMES").unwrap_or(200),
+            poster_position: parse_var::<f64>("PHOTOS_POSTER_POSITION").unwrap_or(0.1).clamp(0.0, 1.0),
+            preview_frames: parse_var("PHOTOS_PREVIEW_FRAMES").unwrap_or(10),
+            renditions,
+            custom_sizes,
+            custom_cache_bytes: parse_var("PHOTOS_CUSTOM_CACHE_BYTES").unwrap_or(1 << 30),
+            max_resizes_per_user: parse_var("PHOTOS_MAX_RESIZES_PER_USER").unwrap_or(2),
+            allow_query_key: parse_var("PHOTOS_ALLOW_QUERY_KEY").unwrap_or(true),
+            cookie_sessions: parse_var("PHOTOS_COOKIE_SESSIONS").unwrap_or(false),
+            admin_emails: var("PHOTOS_ADMIN_EMAILS")
+                .map(|emails| emails.split(',').map(|email| email.trim().to_lowercase()).collect())
+                .unwrap_or_default(),
+            admin_networks: parse_networks("PHOTOS_ADMIN_NETWORKS"),
+            registration: parse_var("PHOTOS_REGISTRATION").unwrap_or(Registration::Open),
+            regenerate_delay_ms: parse_var("PHOTOS_REGENERATE_DELAY_MS").unwrap_or(100),
+            job_workers: parse_var("PHOTOS_JOB_WORKERS").unwrap_or(2),
+            clean_delay_ms: parse_var("PHOTOS_CLEAN_DELAY_MS").unwrap_or(100),
+            metrics_addr: parse_var("PHOTOS_METRICS_ADDR"),
+        }
+    }
+}
+
+/// Parse a comma separated list of `name:height:encoding:quality` renditions.
+fn parse_renditions(value: &str) -> Vec<Rendition> {
+    let mut renditions: Vec<Rendition> = value
+        .split(',')
+        .map(|rendition| {
+            let fields: Vec<&str> = rendition.trim().split(':').collect();
+            if fields.len() != 4 {
+                invalid_rendition(rendition)
+            }
+
+            let name = fields[0].to_string();
+            // `large` is how the original is requested, `uploads` is where it is stored,
+            // `preview` is the strip of a video, and `stripped` is the original without metadata.
+            let reserved = ["large", crate::storage::ORIGINAL, crate::storage::PREVIEW, crate::storage::STRIPPED];
+            if name.is_empty() || reserved.contains(&name.as_str()) || name.contains('/') {
+                invalid_rendition(rendition)
+            }
+
+            Rendition {
+                name,
+                height: fields[1].parse().unwrap_or_else(|_| invalid_rendition(rendition)),
+                encoding: fields[2].parse().unwrap_or_else(|_| invalid_rendition(rendition)),
+                quality: fields[3]
+                    .parse()
+                    .ok()
+                    .filter(|quality| (1..=100).contains(quality))
+                    .unwrap_or_else(|| invalid_rendition(rendition)),
+            }
+        })
+        .collect();
+
+    renditions.sort_by_key(|rendition| std::cmp::Reverse(rendition.height));
+    renditions
+}
+
+/// Parse a comma separated list of dimensions in pixels.
+fn parse_sizes(value: &str) -> Vec<u32> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|size| !size.is_empty())
+        .map(|size| {
+            size.parse()
+                .ok()
+                .filter(|&size| size > 0)
+                .unwrap_or_else(|| panic!("Couldn't parse PHOTOS_CUSTOM_SIZES entry {:?}", size))
+        })
+        .collect()
+}
+
+/// Parse base64 encoded 32 byte keys, skipping blank lines.
+fn parse_storage_keys<'a>(keys: impl Iterator<Item = &'a str>) -> Vec<StorageKey> {
+    keys.map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            base64::decode(key)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(StorageKey)
+                .unwrap_or_else(|| panic!("Storage keys must be 32 bytes encoded in base64"))
+        })
+        .collect()
+}
+
+/// Parse a comma separated list of networks in CIDR notation, where single addresses stand for
+/// themselves.
+fn parse_networks(name: &str) -> Vec<IpNet> {
+    let value = match var(name) {
+        Some(value) => value,
+        None => return vec![],
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|network| !network.is_empty())
+        .map(|network| {
+            network
+                .parse()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .unwrap_or_else(|_| panic!("Couldn't parse {} entry {:?}", name, network))
+        })
+        .collect()
+}
+
+fn invalid_rendition<T>(rendition: &str) -> T {
+    panic!("Couldn't parse PHOTOS_RENDITIONS entry {:?}", rendition)
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Parse an optional environment variable, panicking on malformed values so that a typo in the
+/// configuration is caught at startup rather than silently ignored.
+fn parse_var<T: FromStr>(name: &str) -> Option<T> {
+    var(name).map(|value| {
+        value
+            .parse()
+            .unwrap_or_else(|_| panic!("Couldn't parse {}={:?}", name, value))
+    })
+}
@@ -3,6 +3,8 @@ use crate::{
     error::{ApiResult},
     common::{File, AppState, User},
     album::engine::Engine,
+    detect, events, guest, library, live, resize, similar, stats, storage,
+    jobs::{self, Job},
 };
 use wire::Album;
 use sled::Transactional;
@@ -10,43 +12,31 @@ use sled::Transactional;
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command<'a> {
     Album(&'a str),
-    File(&'a str, File<'a, 'a, 'a>),
+    /// Boxed, since files are much bigger than the ids of the other commands.
+    File(&'a str, Box<File<'a, 'a, 'a>>),
     User(&'a str),
 }
 
 impl<'a> Command<'a> {
-    pub fn run(&self, state: &AppState) -> ApiResult<()> {
-        let cmd_id = state.db.generate_id()?.to_be_bytes();
-        let cmd_bytes = bincode::serialize(self).unwrap();
-
-        state.delete.insert(&cmd_id, cmd_bytes)?;
+    /// Delete right away. The command is kept as a job until it succeeds, so that it is finished
+    /// after a crash or a failure.
+    pub fn run(self, state: &AppState) -> ApiResult<()> {
+        jobs::run(state, &Job::Delete(self))
+    }
 
-        self.finish(state, &cmd_id)
+    /// Leave the deletion to the job workers.
+    pub fn enqueue(self, state: &AppState) -> ApiResult<()> {
+        jobs::enqueue(state, &Job::Delete(self))
     }
 
-    fn finish(&self, state: &AppState, cmd_id: &[u8]) -> ApiResult<()> {
+    pub fn execute(&self, state: &AppState) -> ApiResult<()> {
         use Command::*;
 
         match self {
             Album(album_id) => delete_album(state, album_id),
             File(file_id, file) => delete_file(state, file_id, file),
             User(user_id) => delete_user(state, user_id),
-        }?;
-
-        state.delete.remove(cmd_id)?;
-
-        Ok(())
-    }
-
-    pub fn restore(state: &AppState) -> ApiResult<()> {
-        for entry in state.delete.iter() {
-            let (key, value) = entry?;
-
-            let cmd: Command = bincode::deserialize(&value).unwrap();
-            cmd.finish(state, &key)?;
         }
-
-        Ok(())
     }
 }
 
@@ -57,10 +47,16 @@ fn delete_album(state: &AppState, album_id: &str) -> ApiResult<()> {
         ref user_to_album,
         ref inclusions,
         ref fragments,
+        ref activity,
+        ref rebuilds,
+        ref stripped_albums,
         ..
     } = state;
 
     albums.remove(album_id)?;
+    rebuilds.remove(album_id)?;
+    stripped_albums.remove(album_id)?;
+    guest::forget_album(state, album_id)?;
 
     let prefix = [album_id, "."].concat();
 
@@ -92,6 +88,11 @@ fn delete_album(state: &AppState, album_id: &str) -> ApiResult<()> {
         inclusions.remove(key)?;
     }
 
+    for entry in activity.scan_prefix(&prefix) {
+        let (key, _) = entry?;
+        activity.remove(key)?;
+    }
+
     Ok(())
 }
 
@@ -99,21 +100,65 @@ fn delete_file(state: &AppState, file_id: &str, file: &File) -> ApiResult<()> {
     let AppState {
         ref files,
         ref file_names,
+        ref geo,
+        ref phashes,
+        ref detections,
+        ref detection_labels,
+        ref libraries,
+        ref library_fragments,
         ref albums,
         ref fragments,
         ref inclusions,
-        ref upload_path,
-        ref medium_path,
-        ref small_path,
+        ref stats,
+        ref storage,
+        ref config,
         ..
     } = state;
 
-    (files, file_names).transaction(|(files, file_names)| {
-        files.remove(file_id)?;
-        file_names.remove([file.owner_id, ".", &file.metadata.name].concat().as_bytes())?;
+    let trees = (
+        files,
+        file_names,
+        geo,
+        phashes,
+        detections,
+        detection_labels,
+        libraries,
+        library_fragments,
+        stats,
+    );
+    let library_head = trees.transaction(
+        |(files, file_names, geo, phashes, detections, detection_labels, libraries, library_fragments, stats)| {
+            // Files that are purged from the trash were counted out when they were trashed.
+            if files.remove(file_id)?.is_some() {
+                stats::count(stats, file, false)?;
+            }
 
-        Ok(())
-    })?;
+            // The name may belong to another file by now if this one was in the trash.
+            let file_name = [file.owner_id, ".", &file.metadata.name].concat();
+            if file_names.get(file_name.as_bytes())?.as_deref() == Some(file_id.as_bytes()) {
+                file_names.remove(file_name.as_bytes())?;
+            }
+
+            if let Some(location) = file.location {
+                geo.remove(crate::geo::key(file.owner_id, location, file_id).as_bytes())?;
+            }
+            if let Some(phash) = file.phash {
+                phashes.remove(similar::key(file.owner_id, phash, file_id).as_bytes())?;
+            }
+            detect::remove(detections, detection_labels, file.owner_id, file_id)?;
+
+            library::modify(libraries, library_fragments, file.owner_id, |e| {
+                if !file.in_library() {
+                    Ok(())
+                } else {
+                    e.remove(file_id, file)
+                }
+            })
+        },
+    )?;
+
+    library::updated(state, file.owner_id, library_head);
+    live::unpair(state, file_id, file)?;
 
     for entry in inclusions.scan_prefix([file_id, "."].concat()) {
         let (key, _) = entry?;
@@ -122,7 +167,7 @@ fn delete_file(state: &AppState, file_id: &str, file: &File) -> ApiResult<()> {
             .split_once(".")
             .unwrap();
 
-        (albums, fragments, inclusions).transaction(|(albums, fragments, inclusions)| {
+        let updated = (albums, fragments, inclusions).transaction(|(albums, fragments, inclusions)| {
             if let Some(album_bytes) = albums.get(album_id)? {
                 let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
 
@@ -136,20 +181,21 @@ fn delete_file(state: &AppState, file_id: &str, file: &File) -> ApiResult<()> {
                 // Ok that we didn't check to see if the file was still in the album
                 // because the remove operation is idempotent.
                 inclusions.remove(key.clone())?;
+
+                return Ok(Some(album.fragment_head));
             }
 
-            Ok(())
+            Ok(None)
         })?;
+
+        if let Some(fragment_head) = updated {
+            events::album_updated(state, album_id, fragment_head);
+        }
     }
 
-    let upload_path = upload_path.join(file_id);
-    let medium_path = medium_path.join(file_id);
-    let small_path = small_path.join(file_id);
+    storage::delete_blocking(storage.as_ref(), &storage::file_keys(config, file_id));
+    resize::forget(state, file_id)?;
 
-    let _ = std::fs::remove_file(upload_path);
-    let _ = std::fs::remove_file(medium_path);
-    let _ = std::fs::remove_file(small_path);
-    
     Ok(())
 }
 
@@ -158,9 +204,19 @@ fn delete_user(state: &AppState, user_id: &str) -> ApiResult<()> {
         ref users,
         ref emails,
         ref sessions,
+        ref login_failures,
+        ref login_audit,
+        ref verified,
         ref inclusions,
         ref files,
         ref user_to_album,
+        ref libraries,
+        ref library_fragments,
+        ref trash,
+        ref stats,
+        ref quotas,
+        ref admins,
+        ref content_ids,
         ..
     } = state;
 
@@ -178,6 +234,19 @@ fn delete_user(state: &AppState, user_id: &str) -> ApiResult<()> {
         sessions.remove(key)?;
     }
 
+    login_failures.remove(user_id)?;
+    verified.remove(user_id)?;
+    quotas.remove(user_id)?;
+    admins.remove(user_id)?;
+    for entry in login_audit.scan_prefix([user_id, "."].concat()) {
+        let (key, _) = entry?;
+        login_audit.remove(key)?;
+    }
+    for entry in content_ids.scan_prefix([user_id, "."].concat()) {
+        let (key, _) = entry?;
+        content_ids.remove(key)?;
+    }
+
     // Delete albums first because this will reduce the number of recalculations
     // that individual file removals will cause.
     for entry in user_to_album.scan_prefix([user_id, "."].concat()) {
@@ -200,9 +269,21 @@ fn delete_user(state: &AppState, user_id: &str) -> ApiResult<()> {
 
             // Need to preserve the file so that it can be tracked down in
             // the album using the timestamp.
-            Command::File(file_id, file).run(state)?;
+            Command::File(file_id, Box::new(file)).run(state)?;
         }
     }
 
+    for entry in trash.scan_prefix([user_id, "."].concat()) {
+        let (key, _) = entry?;
+        crate::trash::purge(state, &key)?;
+    }
+
+    libraries.remove(user_id)?;
+    for entry in library_fragments.scan_prefix([user_id, "."].concat()) {
+        let (key, _) = entry?;
+        library_fragments.remove(key)?;
+    }
+    stats.remove(user_id)?;
+
     Ok(())
 }
@@ -0,0 +1,355 @@
+//! Object and Face Detection
+//!
+//! When a detection service is configured, every image that is stored is handed to a `Detect`
+//! job, which sends one of its renditions to the service and keeps what comes back: labels for
+//! what is in the photo, and the bounds and embedding of every face. Detection happens after the
+//! upload succeeded, so a service that is down or slow only delays it, and the job retries until
+//! it gives up, which `GET /admin/jobs` shows.
+//!
+//! What was found is kept in the `detections` tree under the file, and each label is indexed in
+//! the `detection_labels` tree under `<owner_id>.<label>.<file_id>` along with its confidence, so
+//! that `GET /file/search?label=dog` only has to scan one prefix. Files in the trash or in the
+//! locked folder keep their entries and are left out of searches, so restoring or unlocking them
+//! brings their labels back.
+//!
+//! Services are reached through the `Detector` trait, which `HttpDetector` implements for any
+//! service that speaks JSON over HTTP.
+
+use crate::{
+    common::{require_key, respond_ok, searchable, test_logged_in, AppState, File},
+    config::DetectionConfig,
+    error::{ApiError, ApiResult},
+    jobs::{self, Job},
+    storage,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::{header, Body, Request, Response};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::Transactional;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::block_in_place;
+use wire::LabeledFile;
+
+/// Something that the service found in a photo, with how sure it was from 0 to 1.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DetectedLabel {
+    pub name: String,
+    pub confidence: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Face {
+    /// Left, top, width and height, as fractions of the width and height of the image.
+    pub bounds: [f64; 4],
+    /// Faces of the same person have embeddings that are close to each other.
+    pub embedding: Vec<f32>,
+}
+
+/// What the service answers with, which is also what is stored for each file.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct Detections {
+    #[serde(default)]
+    pub labels: Vec<DetectedLabel>,
+    #[serde(default)]
+    pub faces: Vec<Face>,
+}
+
+impl Detections {
+    /// Lowercase and trim the labels, keeping the surest of those that are the same and dropping
+    /// those that are empty.
+    fn normalize(mut self) -> Self {
+        let mut labels: Vec<DetectedLabel> = vec![];
+
+        for label in self.labels {
+            let name = label.name.trim().to_lowercase();
+            if name.is_empty() {
+                continue;
+            }
+
+            match labels.iter_mut().find(|existing| existing.name == name) {
+                Some(existing) => existing.confidence = existing.confidence.max(label.confidence),
+                None => labels.push(DetectedLabel {
+                    name,
+                    confidence: label.confidence,
+                }),
+            }
+        }
+
+        self.labels = labels;
+        self
+    }
+}
+
+#[async_trait]
+pub trait Detector: Send + Sync {
+    /// Find what is in `image`, which is encoded as `mime`.
+    async fn detect(&self, image: Bytes, mime: &str) -> ApiResult<Detections>;
+}
+
+/// Sends images as the body of a `POST` to a URL, which answers with JSON `Detections`.
+pub struct HttpDetector {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl HttpDetector {
+    pub fn new(config: &DetectionConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .build()
+            .expect("Couldn't create the detection client");
+
+        HttpDetector {
+            client,
+            url: config.url.clone(),
+            token: config.token.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Detector for HttpDetector {
+    async fn detect(&self, image: Bytes, mime: &str) -> ApiResult<Detections> {
+        let mut request = self.client.post(&self.url).header(header::CONTENT_TYPE, mime).body(image);
+        if let Some(ref token) = self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await.and_then(|response| response.error_for_status());
+        let response = response.map_err(ApiError::Detector)?;
+        response.json().await.map_err(ApiError::Detector)
+    }
+}
+
+/// The detector for `config`, if there is one.
+pub fn open(config: Option<&DetectionConfig>) -> Option<Arc<dyn Detector>> {
+    config.map(|config| Arc::new(HttpDetector::new(config)) as Arc<dyn Detector>)
+}
+
+fn label_key(owner_id: &str, label: &str, file_id: &str) -> String {
+    [owner_id, ".", label, ".", file_id].concat()
+}
+
+/// Leave the newly stored `file_id` for detection, if a service is configured.
+pub fn enqueue(state: &AppState, file_id: &str) -> ApiResult<()> {
+    if state.detector.is_none() {
+        return Ok(());
+    }
+
+    jobs::enqueue(state, &Job::Detect(Cow::from(file_id)))
+}
+
+/// Send a rendition of `file_id` to the detection service and record what it found. Files that
+/// are gone by then, or that are in the trash, are skipped.
+pub fn run(state: &AppState, file_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref detections,
+        ref detection_labels,
+        ref config,
+        ..
+    } = state;
+
+    let (detector, detection_config) = match (&state.detector, &config.detection) {
+        (Some(detector), Some(detection_config)) => (detector, detection_config),
+        _ => return Ok(()),
+    };
+    let rendition = config
+        .renditions
+        .iter()
+        .find(|rendition| rendition.name == detection_config.rendition)
+        .unwrap();
+
+    match files.get(file_id)? {
+        Some(file_bytes) if !bincode::deserialize::<File>(&file_bytes).unwrap().encrypted => {}
+        _ => return Ok(()),
+    }
+
+    let handle = tokio::runtime::Handle::current();
+    let found = handle.block_on(async {
+        let stream = state.storage.get_stream(&storage::key(&rendition.name, file_id)).await?;
+        let image = stream
+            .try_fold(vec![], |mut image, chunk| async move {
+                image.extend_from_slice(&chunk);
+                Ok(image)
+            })
+            .await?;
+
+        detector.detect(Bytes::from(image), rendition.encoding.mime()).await
+    })?;
+    let found = found.normalize();
+
+    (files, detections, detection_labels).transaction(|(files, detections, detection_labels)| {
+        let file_bytes = match files.get(file_id)? {
+            Some(file_bytes) => file_bytes,
+            None => return Ok(()),
+        };
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+        // Detection may run again, for instance when the job is retried after it was recorded.
+        if let Some(previous_bytes) = detections.get(file_id)? {
+            let previous: Detections = bincode::deserialize(&previous_bytes).unwrap();
+            for label in previous.labels {
+                detection_labels.remove(label_key(file.owner_id, &label.name, file_id).as_bytes())?;
+            }
+        }
+
+        for label in &found.labels {
+            let key = label_key(file.owner_id, &label.name, file_id);
+            detection_labels.insert(key.as_bytes(), &label.confidence.to_be_bytes())?;
+        }
+        detections.insert(file_id, bincode::serialize(&found).unwrap())?;
+
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Forget what was found in `file_id`, which belongs to `owner_id`, as part of deleting it.
+pub fn remove(
+    detections: &TransactionalTree,
+    detection_labels: &TransactionalTree,
+    owner_id: &str,
+    file_id: &str,
+) -> ConflictableTransactionResult<(), ApiError> {
+    if let Some(found_bytes) = detections.remove(file_id)? {
+        let found: Detections = bincode::deserialize(&found_bytes).unwrap();
+        for label in found.labels {
+            detection_labels.remove(label_key(owner_id, &label.name, file_id).as_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Files of the user that the `label` query was found in, surest first.
+pub async fn search(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let label = req
+        .query("label")
+        .map(|label| label.trim().to_lowercase())
+        .filter(|label| !label.is_empty())
+        .ok_or(ApiError::BadRequest)?;
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref detection_labels,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let prefix = [user_id, ".", &label, "."].concat();
+        let mut found = vec![];
+
+        for entry in detection_labels.scan_prefix(&prefix) {
+            let (label_key, confidence) = entry?;
+            let file_id = std::str::from_utf8(&label_key[prefix.len()..]).unwrap();
+            // Only the file id may follow, so `dog` doesn't find files labeled `dog.bed`.
+            if file_id.contains('.') || !searchable(files, file_id)? {
+                continue;
+            }
+
+            found.push(LabeledFile {
+                id: Cow::from(file_id.to_string()),
+                confidence: f64::from_be_bytes(confidence.as_ref().try_into().unwrap()),
+            });
+        }
+
+        found.sort_by(|a, b| b.confidence.total_cmp(&a.confidence).then_with(|| a.id.cmp(&b.id)));
+        respond_ok(found)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, start_detector, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::json;
+
+    #[test]
+    fn labels_are_normalized() {
+        let label = |name: &str, confidence| DetectedLabel {
+            name: name.to_string(),
+            confidence,
+        };
+
+        let found = Detections {
+            labels: vec![label(" Dog", 0.5), label("beach", 0.75), label("dog ", 0.9), label(" ", 1.0)],
+            faces: vec![],
+        };
+        assert_eq!(found.normalize().labels, vec![label("dog", 0.9), label("beach", 0.75)]);
+
+        let answer = r#"{ "labels": [{ "name": "cat", "confidence": 0.8 }] }"#;
+        let parsed: Detections = serde_json::from_str(answer).unwrap();
+        assert_eq!(parsed.labels, vec![label("cat", 0.8)]);
+        assert!(parsed.faces.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn detection() {
+        let url = start_detector().await;
+        let server = TestServer::start_with(|config| {
+            config.detection = Some(DetectionConfig {
+                url,
+                token: None,
+                rendition: "medium".to_string(),
+                timeout_seconds: 5,
+            })
+        })
+        .await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+        let file_id = server.upload(&key, "dog.png", png(64, 48)).await;
+
+        // Uploads leave a job for the workers, which aren't running here.
+        let detect = |file_id: &str| tokio::task::block_in_place(|| crate::detect::run(&server.state, file_id));
+        detect(&file_id).unwrap();
+        detect(&file_id).unwrap();
+
+        let search = |key: &str, label: &str| format!("/file/search?label={}&key={}", label, key);
+        let found = server.json(Method::GET, &search(&key, "dog"), &()).await;
+        assert_eq!(found, json!([{ "id": file_id, "confidence": 0.9 }]));
+        assert_eq!(server.json(Method::GET, &search(&key, "do"), &()).await, json!([]));
+        assert_eq!(server.json(Method::GET, &search(&other, "dog"), &()).await, json!([]));
+        let (status, _) = server.request(Method::GET, &search(&key, ""), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Files in the trash are left out until they are restored.
+        let path = format!("/file/{}?key={}", file_id, key);
+        let (status, _) = server.request(Method::DELETE, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &search(&key, "grass"), &()).await, json!([]));
+
+        // A service that can't be reached fails the job without touching the file.
+        let unreachable = TestServer::start_with(|config| {
+            config.detection = Some(DetectionConfig {
+                url: "http://127.0.0.1:1/detect".to_string(),
+                token: None,
+                rendition: "medium".to_string(),
+                timeout_seconds: 5,
+            })
+        })
+        .await;
+        let key = unreachable.signup("owner@example.com").await;
+        let file_id = unreachable.upload(&key, "dog.png", png(64, 48)).await;
+        assert!(tokio::task::block_in_place(|| crate::detect::run(&unreachable.state, &file_id)).is_err());
+        assert!(unreachable.state.files.contains_key(&file_id).unwrap());
+    }
+}
@@ -0,0 +1,242 @@
+//! Edits
+//!
+//! A `FileEdit` turns an image for display without touching its original, which is rendered again
+//! with the edit applied, so reverting only takes rendering it without. Every way to turn and
+//! mirror an image is a `Turn`, which mirrors and then rotates, and the EXIF orientations map onto
+//! them one to one. Decoding already applies the orientation that the camera recorded, so one that
+//! is put in its place is applied after undoing the recorded one.
+//!
+//! Animated images are shown by their still poster once they are edited, since the frames of the
+//! animated copy aren't turned.
+
+use crate::error::{ApiError, ApiResult};
+use exif::{In, Reader, Tag};
+use libvips::{ops, VipsImage};
+use std::convert::TryFrom;
+use std::io::BufReader;
+use std::path::Path;
+use wire::FileEdit;
+
+/// Mirroring left to right if `flip` is set, and then rotating clockwise by `quarters` turns.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Turn {
+    flip: bool,
+    quarters: u8,
+}
+
+impl Turn {
+    const NONE: Turn = Turn {
+        flip: false,
+        quarters: 0,
+    };
+
+    /// The turn that shows an image with EXIF `orientation` upright.
+    fn from_orientation(orientation: u8) -> Option<Self> {
+        let (flip, quarters) = match orientation {
+            1 => (false, 0),
+            2 => (true, 0),
+            3 => (false, 2),
+            4 => (true, 2),
+            5 => (true, 3),
+            6 => (false, 1),
+            7 => (true, 1),
+            8 => (false, 3),
+            _ => return None,
+        };
+        Some(Turn { flip, quarters })
+    }
+
+    /// This turn followed by `next`. Mirroring reverses the direction of any rotation before it.
+    fn then(self, next: Turn) -> Turn {
+        let quarters = if next.flip { 4 - self.quarters } else { self.quarters };
+        Turn {
+            flip: self.flip != next.flip,
+            quarters: (quarters + next.quarters) % 4,
+        }
+    }
+
+    fn inverse(self) -> Turn {
+        if self.flip {
+            // Mirroring around any axis undoes itself.
+            self
+        } else {
+            Turn {
+                flip: false,
+                quarters: (4 - self.quarters) % 4,
+            }
+        }
+    }
+
+    fn apply(self, image: VipsImage) -> ApiResult<VipsImage> {
+        let image = if self.flip {
+            ops::flip(&image, ops::Direction::Horizontal)?
+        } else {
+            image
+        };
+
+        let angle = match self.quarters {
+            0 => return Ok(image),
+            1 => ops::Angle::D90,
+            2 => ops::Angle::D180,
+            _ => ops::Angle::D270,
+        };
+        Ok(ops::rot(&image, angle)?)
+    }
+}
+
+/// Turn away edits that don't describe a turn.
+pub fn check(edit: &FileEdit) -> ApiResult<()> {
+    let orientation_valid = edit
+        .orientation
+        .is_none_or(|orientation| Turn::from_orientation(orientation).is_some());
+    if !orientation_valid || !edit.rotate.is_multiple_of(90) || edit.rotate >= 360 {
+        return Err(ApiError::BadRequest);
+    }
+
+    Ok(())
+}
+
+/// Orientation that the camera recorded in the EXIF data of the file at `path`.
+fn recorded_orientation(path: &Path) -> u8 {
+    let read = || -> Option<u8> {
+        let file = std::fs::File::open(path).ok()?;
+        let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok()?;
+        let orientation = exif.get_field(Tag::Orientation, In::PRIMARY)?.value.get_uint(0)?;
+        u8::try_from(orientation).ok()
+    };
+
+    read().unwrap_or(1)
+}
+
+/// Apply `edit` to the upright `image` that was decoded from the file at `path`.
+pub fn apply(image: VipsImage, edit: &FileEdit, path: &Path) -> ApiResult<VipsImage> {
+    let mut turn = Turn::NONE;
+
+    if let Some(orientation) = edit.orientation.and_then(Turn::from_orientation) {
+        let recorded = Turn::from_orientation(recorded_orientation(path)).unwrap_or(Turn::NONE);
+        turn = recorded.inverse().then(orientation);
+    }
+    turn = turn.then(Turn {
+        flip: edit.flip,
+        quarters: (edit.rotate / 90 % 4) as u8,
+    });
+
+    if turn == Turn::NONE {
+        return Ok(image);
+    }
+    turn.apply(image)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, TestServer};
+    use hyper::{header, Body, Method, Request, StatusCode};
+    use serde_json::json;
+
+    /// Where the corners of a 2x1 image, numbered clockwise from the top left, end up after
+    /// `turn`, for checking turns against each other without pixels.
+    fn corners(turn: Turn) -> [u8; 4] {
+        let mut corners = [0, 1, 2, 3];
+        if turn.flip {
+            corners = [corners[1], corners[0], corners[3], corners[2]];
+        }
+        for _ in 0..turn.quarters {
+            corners = [corners[3], corners[0], corners[1], corners[2]];
+        }
+        corners
+    }
+
+    #[test]
+    fn turns_compose() {
+        let turns: Vec<Turn> = (1..=8)
+            .map(|orientation| Turn::from_orientation(orientation).unwrap())
+            .collect();
+
+        for &first in &turns {
+            assert_eq!(first.then(first.inverse()), Turn::NONE);
+            for &second in &turns {
+                let (first_corners, second_corners) = (corners(first), corners(second));
+                let expected = second_corners.map(|corner| first_corners[corner as usize]);
+                assert_eq!(corners(first.then(second)), expected, "{:?} then {:?}", first, second);
+            }
+        }
+    }
+
+    #[test]
+    fn edits_are_checked() {
+        assert!(check(&FileEdit::default()).is_ok());
+        let turned = FileEdit {
+            orientation: Some(6),
+            flip: true,
+            rotate: 270,
+        };
+        assert!(check(&turned).is_ok());
+
+        for bad in [
+            FileEdit {
+                orientation: Some(9),
+                ..FileEdit::default()
+            },
+            FileEdit {
+                rotate: 45,
+                ..FileEdit::default()
+            },
+            FileEdit {
+                rotate: 360,
+                ..FileEdit::default()
+            },
+        ] {
+            assert!(check(&bad).is_err());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn edit_and_revert() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let file_id = server.upload(&key, "black.png", png(64, 48)).await;
+        let serve_path = format!("/file/medium/{}?key={}", file_id, key);
+
+        /// Fetch `path`, returning the status and the entity tag.
+        async fn served(server: &TestServer, path: &str, if_none_match: Option<&str>) -> (StatusCode, String) {
+            let mut request = Request::get(format!("http://{}{}", server.addr, path));
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            let response = server.client.request(request.body(Body::empty()).unwrap()).await.unwrap();
+            (response.status(), response.headers()[header::ETAG].to_str().unwrap().to_string())
+        }
+
+        let (status, unedited) = served(&server, &serve_path, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(served(&server, &serve_path, Some(&unedited)).await.0, StatusCode::NOT_MODIFIED);
+
+        let edit_path = format!("/file/{}/edit?key={}", file_id, key);
+        let turn = json!({ "rotate": 90 });
+        assert_eq!(server.send(Method::POST, &edit_path, &turn).await, StatusCode::OK);
+
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(info["width"], 48);
+        assert_eq!(info["height"], 64);
+        assert_eq!(info["edit"]["rotate"], 90);
+        assert_eq!(info["revision"], 1);
+
+        // The cached rendition is stale now.
+        let (status, edited) = served(&server, &serve_path, Some(&unedited)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(edited, unedited);
+
+        assert_eq!(server.send(Method::POST, &edit_path, &json!({})).await, StatusCode::OK);
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(info["width"], 64);
+        assert_eq!(info["revision"], 2);
+
+        let crooked = json!({ "rotate": 45 });
+        assert_eq!(server.send(Method::POST, &edit_path, &crooked).await, StatusCode::BAD_REQUEST);
+        let path = format!("/file/{}/edit?key={}", file_id, other);
+        assert_eq!(server.send(Method::POST, &path, &turn).await, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,42 @@
+//! Endpoints
+//!
+//! Routers register their handlers by the endpoints in `wire::endpoint`, so that a route is
+//! served where the client expects it.
+
+use crate::error::{ApiError, ApiResult};
+use hyper::{Body, Request, Response};
+use routerify::RouterBuilder;
+use std::future::Future;
+use wire::endpoint::{Endpoint, Method};
+
+pub trait EndpointExt {
+    /// Serve `E` with `handler`, where the router is mounted at `scope`.
+    fn endpoint<E, H, R>(self, scope: &str, endpoint: E, handler: H) -> Self
+    where
+        E: Endpoint,
+        H: Fn(Request<Body>) -> R + Send + Sync + 'static,
+        R: Future<Output = ApiResult<Response<Body>>> + Send + 'static;
+}
+
+impl EndpointExt for RouterBuilder<Body, ApiError> {
+    fn endpoint<E, H, R>(self, scope: &str, _: E, handler: H) -> Self
+    where
+        E: Endpoint,
+        H: Fn(Request<Body>) -> R + Send + Sync + 'static,
+        R: Future<Output = ApiResult<Response<Body>>> + Send + 'static,
+    {
+        let path = match E::PATH.strip_prefix(scope) {
+            Some("") => "/",
+            Some(path) => path,
+            None => panic!("{} isn't under {}", E::PATH, scope),
+        };
+
+        match E::METHOD {
+            Method::Get => self.get(path, handler),
+            Method::Post => self.post(path, handler),
+            Method::Put => self.put(path, handler),
+            Method::Patch => self.patch(path, handler),
+            Method::Delete => self.delete(path, handler),
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use serde_json::json;
 use sled::transaction::{ConflictableTransactionError, TransactionError};
 use std::fmt;
+use wire::{ApiVersion, ErrorCode, ErrorResponse};
 
 #[derive(Debug)]
 pub enum ApiError {
@@ -8,15 +10,129 @@ pub enum ApiError {
     BadRequest,
     EmailTaken,
     FileExists,
+    /// The upload can't be decoded into renditions.
+    UnsupportedFormat,
+    /// The upload is larger than the configured maximum.
+    PayloadTooLarge,
+    /// The body of the request didn't arrive in time.
+    Timeout,
+    /// Carries the number of seconds until the client may try again.
+    TooManyRequests(u64),
+    /// Carries the number of seconds until the account unlocks.
+    AccountLocked(u64),
+    /// Carries the version that the client sent, which is older than the server supports.
+    UnsupportedVersion(wire::ApiVersion),
+    /// The upload would take its owner over their quota.
+    QuotaExceeded,
+    /// The file is locked, and the request doesn't carry a current elevation token.
+    ElevationRequired,
+    /// Too many images are waiting to be processed. Carries the number of seconds until the client
+    /// may try again.
+    Busy(u64),
     Hyper(hyper::Error),
     Json(serde_json::Error),
     Sled(sled::Error),
     Argon(argon2::Error),
     IO(std::io::Error),
     Vips(libvips::error::Error),
+    /// The body of a batch upload isn't valid multipart data.
+    Multipart(multer::Error),
+    /// The OpenID Connect provider couldn't be reached or turned the request down.
+    Provider(reqwest::Error),
+    /// The detection service couldn't be reached or turned the request down.
+    Detector(reqwest::Error),
 }
 
-impl std::error::Error for ApiError {}
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use ApiError::*;
+
+        match self {
+            Hyper(error) => Some(error),
+            Json(error) => Some(error),
+            Sled(error) => Some(error),
+            Argon(error) => Some(error),
+            IO(error) => Some(error),
+            Vips(error) => Some(error),
+            Multipart(error) => Some(error),
+            Provider(error) => Some(error),
+            Detector(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl ApiError {
+    /// Render the error along with every underlying cause.
+    pub fn chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+
+        while let Some(error) = source {
+            message.push_str(": ");
+            message.push_str(&error.to_string());
+            source = error.source();
+        }
+
+        message
+    }
+}
+
+impl ApiError {
+    /// The body that the client gets for the error. Internal failures are only described in the
+    /// log.
+    pub fn response(&self) -> ErrorResponse<'static> {
+        use ApiError::*;
+
+        let (code, message, details) = match self {
+            Unauthorized => (ErrorCode::Unauthorized, "Not authorized".into(), None),
+            NotFound => (ErrorCode::NotFound, "Not found".into(), None),
+            BadRequest => (ErrorCode::BadRequest, "Bad request".into(), None),
+            Json(error) => (ErrorCode::BadRequest, format!("Invalid JSON: {}", error).into(), None),
+            Multipart(error) => (ErrorCode::BadRequest, format!("Invalid multipart body: {}", error).into(), None),
+            EmailTaken => (ErrorCode::EmailTaken, "The email is already taken".into(), None),
+            FileExists => (ErrorCode::FileExists, "A file with that name already exists".into(), None),
+            UnsupportedFormat => (ErrorCode::UnsupportedFormat, "The file format isn't supported".into(), None),
+            PayloadTooLarge => (ErrorCode::PayloadTooLarge, "The upload is too large".into(), None),
+            QuotaExceeded => (ErrorCode::QuotaExceeded, "The upload would go over the storage quota".into(), None),
+            ElevationRequired => (
+                ErrorCode::ElevationRequired,
+                "The password has to be entered again to see locked files".into(),
+                None,
+            ),
+            Timeout => (ErrorCode::Timeout, "The request body took too long to arrive".into(), None),
+            TooManyRequests(retry_after) => (
+                ErrorCode::TooManyRequests,
+                "Too many requests".into(),
+                Some(json!({ "retry_after": retry_after })),
+            ),
+            Busy(retry_after) => (
+                ErrorCode::Busy,
+                "The server is busy processing other images".into(),
+                Some(json!({ "retry_after": retry_after })),
+            ),
+            AccountLocked(retry_after) => (
+                ErrorCode::AccountLocked,
+                "The account is locked after too many failed logins".into(),
+                Some(json!({ "retry_after": retry_after })),
+            ),
+            UnsupportedVersion(client) => (
+                ErrorCode::UnsupportedVersion,
+                "The client is too old for this server".into(),
+                Some(json!(wire::UnsupportedVersion {
+                    client: *client,
+                    minimum: ApiVersion::MIN_SUPPORTED,
+                    current: ApiVersion::CURRENT,
+                })),
+            ),
+            Hyper(_) | Sled(_) | Argon(_) | IO(_) | Vips(_) | Provider(_) | Detector(_) => {
+                (ErrorCode::Internal, "Internal server error".into(), None)
+            }
+        };
+
+        ErrorResponse { code, message, details }
+    }
+}
 
 impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -60,6 +176,18 @@ impl From<libvips::error::Error> for ApiError {
     }
 }
 
+impl From<multer::Error> for ApiError {
+    fn from(error: multer::Error) -> Self {
+        ApiError::Multipart(error)
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        ApiError::Provider(error)
+    }
+}
+
 impl From<TransactionError<ApiError>> for ApiError {
     fn from(error: TransactionError<ApiError>) -> Self {
         use TransactionError::*;
@@ -0,0 +1,154 @@
+//! Album Change Notifications
+//!
+//! Handlers publish an `AlbumEvent` on a broadcast channel once a change to an album has been
+//! committed. `GET /events` streams the events for albums that the user can see as server-sent
+//! events, so that web clients don't need to poll album metadata.
+
+use crate::{
+    common::{require_key, test_logged_in, AppState},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+};
+use bytes::Bytes;
+use hyper::{header, Body, Request, Response, StatusCode};
+use routerify::{ext::RequestExt, Router};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::block_in_place;
+use tokio::time::{self, Interval};
+use wire::{endpoint, AlbumEvent};
+
+/// Events that a slow client can fall behind by before it is told that it lagged.
+pub const CHANNEL_CAPACITY: usize = 1024;
+/// Proxies tend to close connections that are idle for too long.
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+pub fn album_updated(state: &AppState, album_id: &str, fragment_head: u64) {
+    publish(
+        &state.events,
+        AlbumEvent::Updated {
+            album_id: album_id.to_string(),
+            fragment_head,
+        },
+    );
+}
+
+pub fn shares_changed(state: &AppState, album_id: &str, user_id: &str) {
+    publish(
+        &state.events,
+        AlbumEvent::SharesChanged {
+            album_id: album_id.to_string(),
+            user_id: user_id.to_string(),
+        },
+    );
+}
+
+pub fn publish(events: &broadcast::Sender<AlbumEvent>, event: AlbumEvent) {
+    // Sending only fails when nobody is listening.
+    let _ = events.send(event);
+}
+
+struct Listener {
+    receiver: broadcast::Receiver<AlbumEvent>,
+    sessions: sled::Tree,
+    user_to_album: sled::Tree,
+    key: String,
+    keep_alive: Interval,
+}
+
+impl Listener {
+    /// Wait for the next chunk of the event stream, or `None` once the stream should end.
+    async fn next(&mut self) -> Option<Bytes> {
+        loop {
+            tokio::select! {
+                _ = self.keep_alive.tick() => {
+                    return Some(Bytes::from_static(b": keep-alive\n\n"));
+                }
+                received = self.receiver.recv() => {
+                    let event = match received {
+                        Ok(event) => event,
+                        // Events were dropped, so the client needs to refetch anything it shows.
+                        Err(RecvError::Lagged(_)) => {
+                            return Some(Bytes::from_static(b"event: lagged\ndata: {}\n\n"));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    };
+
+                    match block_in_place(|| self.can_see(&event)) {
+                        Ok(true) => {
+                            let json = serde_json::to_string(&event).unwrap();
+                            return Some(Bytes::from(format!("data: {}\n\n", json)));
+                        }
+                        Ok(false) => continue,
+                        // The session was revoked, or the database failed.
+                        Err(_) => return None,
+                    }
+                }
+            }
+        }
+    }
+
+    fn can_see(&self, event: &AlbumEvent) -> ApiResult<bool> {
+        test_logged_in(&self.sessions, &self.key)?;
+
+        let (user_id, _) = self.key.split_once('.').unwrap();
+
+        match event {
+            // Members that were just removed still need to hear about it.
+            AlbumEvent::SharesChanged { user_id: ref member, .. } if member == user_id => {
+                return Ok(true);
+            }
+            AlbumEvent::LibraryUpdated { user_id: ref owner, .. } => return Ok(owner == user_id),
+            _ => {}
+        }
+
+        let role_key = [user_id, ".", event.album_id().unwrap()].concat();
+        Ok(self.user_to_album.contains_key(role_key)?)
+    }
+}
+
+async fn stream(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let AppState {
+        ref sessions,
+        ref user_to_album,
+        ref events,
+        ..
+    } = parts.data().unwrap();
+
+    block_in_place(|| test_logged_in(sessions, key))?;
+
+    let listener = Listener {
+        receiver: events.subscribe(),
+        sessions: sessions.clone(),
+        user_to_album: user_to_album.clone(),
+        key: key.to_string(),
+        keep_alive: time::interval(KEEP_ALIVE),
+    };
+
+    let chunks = futures::stream::unfold(listener, |mut listener| async move {
+        let chunk = listener.next().await?;
+        Some((Ok::<_, Infallible>(chunk), listener))
+    });
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .status(StatusCode::OK)
+        .body(Body::wrap_stream(chunks))
+        .unwrap())
+}
+
+pub const SCOPE: &str = "/events";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::Events, stream)
+        .build()
+        .unwrap()
+}
@@ -1,30 +1,49 @@
 use crate::{
-    delete,
-    common::{auth_album, join, new_id, require_key, respond_ok, test_logged_in, AppState, File, respond_ok_empty},
+    album, animation, bandwidth, detect, edit, events, geo, library, live, regenerate, resize, similar, stats, storage,
+    strip, trash, video, xmp,
+    common::{
+        auth_album, etag_matches, join, new_id, next_chunk, require_elevation, require_key, respond_ok,
+        test_logged_in, AppState, File, respond_ok_empty,
+    },
+    endpoint::EndpointExt,
     error::{ApiError, ApiResult},
 };
-use async_stream::try_stream;
-use bytes::{Bytes, BytesMut};
-use futures::stream::Stream;
-use futures::{join, TryStreamExt};
+use crate::album::{engine::Engine, rules};
+use crate::config::{Encoding, Rendition};
+use crate::format::{self, Format};
+use bytes::Bytes;
+use chrono::offset::Utc;
+use futures::{future, join, Stream};
+use hyper::http::request::Parts;
 use hyper::{header, Body, Request, Response, StatusCode};
-use libvips::{ops, VipsImage};
+use libvips::VipsImage;
 use routerify::ext::RequestExt;
 use routerify::Router;
+use routerify_query::RequestQueryExt;
+use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
 use sled::Transactional;
 use std::borrow::Cow;
-use std::os::unix::ffi::OsStrExt;
+use std::io;
+use std::ops::Bound;
 use std::path::Path;
-use tokio::{
-    fs,
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    task::block_in_place,
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::{fs, io::AsyncWriteExt, task::block_in_place, time};
+use tracing::warn;
+use wire::{
+    endpoint,
+    Album, ConflictPolicy, FileAlbum, FileEdit, FileEntry, FileEntryList, FileInfo, FileLabels, FileList, FileMetadata,
+    hash_hex, Hidden, IntoOwned, Limits, ListRequest, Locked, Rename, SortMode, StoredFile, UploadResult,
 };
-use wire::{FileList, FileMetadata, ListRequest, NewResource};
 
-const UPLOAD_METADATA: &'static str = "upload-metadata";
-const MEDIUM_HEIGHT: f64 = 400.;
-const SMALL_HEIGHT: f64 = 10.;
+const UPLOAD_METADATA: &str = "upload-metadata";
+/// Numbers that are tried before a file that would be renamed is turned away instead.
+const MAX_RENAMES: usize = 1000;
+/// Type that encrypted files are recorded and served as, since their contents can't be checked.
+const ENCRYPTED_MIME: &str = "application/octet-stream";
 
 async fn upload(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, mut body) = req.into_parts();
@@ -32,22 +51,49 @@ async fn upload(req: Request<Body>) -> ApiResult<Response<Body>> {
     let key = require_key(&parts)?;
     let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
+    let state = parts.data().unwrap();
     let AppState {
-        ref users,
         ref sessions,
-        ref files,
-        ref file_names,
-        ref upload_path,
-        ref medium_path,
-        ref small_path,
         ref temp_path,
+        ref upload_limiter,
+        ref config,
         ..
-    } = parts.data().unwrap();
+    } = state;
 
     // Don't start uploading until we have verified that the user may be able
     // to save the file
     test_logged_in(sessions, key)?;
+    let options = UploadOptions::parse(&parts, owner_id)?;
+    let _upload = upload_limiter.acquire(owner_id)?;
+
+    let max_bytes = config.max_upload_bytes;
+    let deadline = time::Instant::now() + Duration::from_secs(config.upload_timeout_seconds);
+    let idle = Duration::from_secs(config.body_idle_seconds);
+
+    // Turn away uploads that announce their size up front, but still count the bytes since the
+    // header can't be trusted.
+    if content_length(&parts).is_some_and(|length| length > max_bytes) {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let metadata = upload_metadata(&parts)?;
+
+    let file_id = new_id(16);
+    let upload_path = temp_path.join(&file_id);
+
+    let result = async {
+        let head = receive(&mut body, &upload_path, max_bytes, deadline, idle).await?;
+        store(state, owner_id, &file_id, &options, metadata, &upload_path, &head).await
+    }
+    .await;
 
+    let _ = fs::remove_file(&upload_path).await;
+
+    respond_ok(result?)
+}
+
+/// The `FileMetadata` of a single upload, which is sent in a header since the body is the file.
+pub fn upload_metadata(parts: &Parts) -> ApiResult<FileMetadata<'static, 'static>> {
     let metadata_header = parts
         .headers
         .get(UPLOAD_METADATA)
@@ -56,173 +102,1300 @@ async fn upload(req: Request<Body>) -> ApiResult<Response<Body>> {
         .map_err(|_| ApiError::BadRequest)?;
     let metadata: FileMetadata = serde_json::from_slice(&metadata_bytes)?;
 
-    let file_id = new_id(16);
-    let owner_file_name = [&owner_id, ".", &metadata.name].concat();
+    Ok(metadata.into_owned())
+}
+
+/// Queries that apply to every file of an upload.
+pub struct UploadOptions {
+    /// Album that the files are added to.
+    pub album_id: Option<String>,
+    pub conflict: ConflictPolicy,
+    /// The client encrypted the files, so they are stored as they are.
+    pub encrypted: bool,
+    /// Identifier that the camera gave both halves of a Live Photo, which only single uploads take.
+    pub content_id: Option<String>,
+}
+
+/// The `conflict` query of an upload, if it was given.
+pub fn conflict_policy(parts: &Parts) -> ApiResult<Option<ConflictPolicy>> {
+    let queries = querystring::querify(parts.uri.query().unwrap_or(""));
+    match queries.iter().find(|(k, _)| k == &"conflict").map(|(_, v)| *v) {
+        None => Ok(None),
+        Some("error") => Ok(Some(ConflictPolicy::Error)),
+        Some("rename") => Ok(Some(ConflictPolicy::Rename)),
+        Some("replace_if_same") => Ok(Some(ConflictPolicy::ReplaceIfSame)),
+        Some(_) => Err(ApiError::BadRequest),
+    }
+}
+
+impl UploadOptions {
+    /// Read the queries of an upload, turning it away before anything is received if `user_id`
+    /// can't add files to the album.
+    fn parse(parts: &Parts, user_id: &str) -> ApiResult<Self> {
+        let AppState { ref user_to_album, .. } = parts.data().unwrap();
 
-    let upload_path = upload_path.join(&file_id);
-    let medium_path = medium_path.join(&file_id);
-    let small_path = small_path.join(&file_id);
+        let queries = querystring::querify(parts.uri.query().unwrap_or(""));
+        let conflict = conflict_policy(parts)?.unwrap_or_default();
+        let encrypted = match queries.iter().find(|(k, _)| k == &"encrypted").map(|(_, v)| *v) {
+            None => false,
+            Some(encrypted) => encrypted.parse::<bool>().map_err(|_| ApiError::BadRequest)?,
+        };
+
+        let content_id = queries.iter().find(|(k, _)| k == &"content_id").map(|(_, v)| v.to_string());
 
-    let temp_id = [&file_id, ".png"].concat();
-    let temp_path = temp_path.join(&temp_id);
+        let album_id = auth_album(parts);
+        if let Some(album_id) = album_id {
+            block_in_place(|| album::test_can_upload(user_to_album, user_id, album_id))?;
+        }
 
+        Ok(UploadOptions {
+            album_id: album_id.map(str::to_string),
+            conflict,
+            encrypted,
+            content_id,
+        })
+    }
+}
+
+pub fn content_length(parts: &Parts) -> Option<u64> {
+    parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok())
+}
+
+/// Write the contents of an upload to `path`, returning the first bytes of the file so that its
+/// type can be checked. Gives up at `deadline`, or once nothing has arrived for `idle`.
+pub async fn receive<S, E>(
+    mut stream: S,
+    path: &Path,
+    max_bytes: u64,
+    deadline: time::Instant,
+    idle: Duration,
+) -> ApiResult<Vec<u8>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    ApiError: From<E>,
+{
     let mut buffer = fs::OpenOptions::new()
         .create_new(true)
         .write(true)
-        .open(&upload_path)
+        .open(path)
         .await?;
 
-    while let Some(chunk) = body.try_next().await? {
-        buffer.write_all(&chunk).await.unwrap();
-    }
+    let mut head = Vec::with_capacity(format::HEAD_LENGTH);
+    let mut received = 0;
+
+    while let Some(chunk) = next_chunk(&mut stream, deadline, idle).await? {
+        received += chunk.len() as u64;
+        if received > max_bytes {
+            return Err(ApiError::PayloadTooLarge);
+        }
+
+        if head.len() < format::HEAD_LENGTH {
+            let needed = (format::HEAD_LENGTH - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..needed]);
+        }
+
+        buffer.write_all(&chunk).await?;
+    }
+
+    Ok(head)
+}
+
+/// SHA-256 of the file at `path`.
+pub fn hash_file(path: &Path) -> ApiResult<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// `name` with ` (n)` before its extension.
+fn numbered(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{} ({}).{}", stem, n, extension),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Where an upload goes under its `ConflictPolicy`.
+enum Resolution {
+    /// Store it under this name.
+    Name(String),
+    /// It has the same contents as the existing file with this id.
+    Duplicate(String),
+}
+
+/// Find the name that a file called `name` is stored as, or the file that it stands in for.
+fn resolve(
+    files: &TransactionalTree,
+    file_names: &TransactionalTree,
+    owner_id: &str,
+    name: &str,
+    hash: &[u8; 32],
+    conflict: ConflictPolicy,
+) -> ConflictableTransactionResult<Resolution, ApiError> {
+    let existing_id = match file_names.get([owner_id, ".", name].concat())? {
+        Some(existing_id) => existing_id,
+        None => return Ok(Resolution::Name(name.to_string())),
+    };
+
+    match conflict {
+        ConflictPolicy::Error => Err(ApiError::FileExists.into()),
+        ConflictPolicy::Rename => {
+            for n in 1..=MAX_RENAMES {
+                let candidate = numbered(name, n);
+                if file_names.get([owner_id, ".", &candidate].concat())?.is_none() {
+                    return Ok(Resolution::Name(candidate));
+                }
+            }
+
+            Err(ApiError::FileExists.into())
+        }
+        ConflictPolicy::ReplaceIfSame => {
+            let file_bytes = files.get(&existing_id)?.ok_or(ApiError::FileExists)?;
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+            // Files from before hashes were recorded never match.
+            if file.hash.as_ref() != Some(hash) {
+                return Err(ApiError::FileExists.into());
+            }
+
+            Ok(Resolution::Duplicate(std::str::from_utf8(&existing_id).unwrap().to_string()))
+        }
+    }
+}
+
+/// Add the existing file that an upload stands in for to the upload's album, returning the new
+/// fragment head if it wasn't there yet.
+fn include_duplicate(
+    files: &TransactionalTree,
+    album_trees: (
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+        &TransactionalTree,
+    ),
+    owner_id: &str,
+    file_id: &str,
+    album_id: Option<&str>,
+) -> ConflictableTransactionResult<Option<u64>, ApiError> {
+    let album_id = match album_id {
+        Some(album_id) => album_id,
+        None => return Ok(None),
+    };
+
+    let file_bytes = files.get(file_id)?.ok_or(ApiError::FileExists)?;
+    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+    // Locked files stay out of albums, so an upload to one can't stand in for them.
+    if file.locked {
+        return Err(ApiError::FileExists.into());
+    }
+
+    album::add_upload(album_trees, album_id, owner_id, file_id, &file)
+}
+
+/// Look for a file that an upload can stand in for before it is rendered, so that uploading the
+/// same files again is cheap. The upload is stored as usual if none is found, which checks again.
+fn find_duplicate(
+    state: &AppState,
+    owner_id: &str,
+    options: &UploadOptions,
+    name: &str,
+    hash: &[u8; 32],
+) -> ApiResult<Option<String>> {
+    let AppState {
+        ref files,
+        ref file_names,
+        ref albums,
+        ref inclusions,
+        ref fragments,
+        ref user_to_album,
+        ref activity,
+        ..
+    } = state;
+
+    let album_id = options.album_id.as_deref();
+
+    let trees = (files, file_names, albums, inclusions, fragments, user_to_album, activity);
+    let found = trees.transaction(|(files, file_names, albums, inclusions, fragments, user_to_album, activity)| {
+        match resolve(files, file_names, owner_id, name, hash, options.conflict)? {
+            Resolution::Duplicate(existing_id) => {
+                let album_trees = (albums, inclusions, fragments, user_to_album, activity);
+                let album_head = include_duplicate(files, album_trees, owner_id, &existing_id, album_id)?;
+                Ok(Some((existing_id, album_head)))
+            }
+            Resolution::Name(_) => Ok(None),
+        }
+    })?;
+
+    Ok(found.map(|(existing_id, album_head)| {
+        if let (Some(album_id), Some(album_head)) = (album_id, album_head) {
+            events::album_updated(state, album_id, album_head);
+        }
+        existing_id
+    }))
+}
+
+/// Render and store a file that was received into `upload_path`, and add it to the library of
+/// its owner, as well as to the album of the upload if there is one. Everything that was stored
+/// is removed again if this fails, or if the file stands in for an existing one.
+pub async fn store(
+    state: &AppState,
+    owner_id: &str,
+    file_id: &str,
+    options: &UploadOptions,
+    metadata: FileMetadata<'_, '_>,
+    upload_path: &Path,
+    head: &[u8],
+) -> ApiResult<StoredFile<'static>> {
+    let AppState {
+        ref users,
+        ref files,
+        ref file_names,
+        ref geo,
+        ref phashes,
+        ref libraries,
+        ref library_fragments,
+        ref albums,
+        ref inclusions,
+        ref fragments,
+        ref user_to_album,
+        ref activity,
+        ref stats,
+        ref storage,
+        ref temp_path,
+        ref image_pool,
+        ref config,
+        ref metrics,
+        ref capabilities,
+        ..
+    } = state;
+
+    let album_id = options.album_id.as_deref();
+
+    let hash = block_in_place(|| hash_file(upload_path))?;
+    if options.conflict == ConflictPolicy::ReplaceIfSame {
+        let duplicate = block_in_place(|| find_duplicate(state, owner_id, options, &metadata.name, &hash))?;
+        if let Some(existing_id) = duplicate {
+            return Ok(StoredFile {
+                id: Cow::from(existing_id),
+                name: Cow::from(metadata.name.into_owned()),
+                hash: Some(Cow::from(hash_hex(&hash))),
+            });
+        }
+    }
+
+    // Encrypted files can't be read, so only the original is stored.
+    let renditions: &[Rendition] = if options.encrypted { &[] } else { &config.renditions };
+
+    let scratch_path = temp_path.join([file_id, ".decoded"].concat());
+    let rendition_paths: Vec<_> = renditions
+        .iter()
+        .map(|rendition| temp_path.join([file_id, ".", &rendition.name].concat()))
+        .collect();
+    let animated_path = temp_path.join([file_id, ".animated"].concat());
+    let preview_path = temp_path.join([file_id, ".preview"].concat());
+
+    let keys = storage::file_keys(config, file_id);
+    let mut duplicate = false;
+
+    let result = async {
+        let size = fs::metadata(upload_path).await?.len();
+        block_in_place(|| stats::check_quota(state, owner_id, size))?;
+
+        let rendered = if options.encrypted {
+            (ENCRYPTED_MIME.to_string(), 0, 0, None, None, None, None, None, None)
+        } else {
+            let detected_mime = format::check_mime(&metadata.mime, format::sniff(head))?.to_string();
+
+            let format = Format::detect(&detected_mime, &metadata.name);
+            // Rather than failing halfway through, like a video would without `ffmpeg`.
+            if !capabilities.decodes(format) {
+                return Err(ApiError::UnsupportedFormat);
+            }
+            let (path, scratch, ladder, paths, animated, preview) = (
+                upload_path.to_owned(),
+                scratch_path.clone(),
+                renditions.to_vec(),
+                rendition_paths.clone(),
+                animated_path.clone(),
+                preview_path.clone(),
+            );
+            let (metrics, decoding, max_frames, preview_frames) = (
+                metrics.clone(),
+                format::Decoding::new(config),
+                config.max_animation_frames,
+                config.preview_frames,
+            );
+            let mime = detected_mime.clone();
+
+            let (width, height, placeholder, phash, location, camera, animation, video) = image_pool
+                .run(move || {
+                    let started = Instant::now();
+                    let (width, height, placeholder, phash) =
+                        format::render(format, &path, &scratch, &ladder, &paths, decoding, &FileEdit::default())?;
+                    let animation = animation::make(&mime, &path, &ladder, &animated, max_frames)?;
+                    let video = video::make(format, &path, &ladder, &scratch, &preview, preview_frames)?;
+                    metrics.record_processing(started.elapsed());
+
+                    let (location, camera) = (geo::read_location(&path), rules::read_camera(&path));
+                    Ok((width, height, placeholder, phash, location, camera, animation, video))
+                })
+                .await?;
+            (detected_mime, width, height, Some(placeholder), Some(phash), location, camera, animation, video)
+        };
+        let (detected_mime, width, height, placeholder, phash, location, camera, animation, video) = rendered;
+
+        // Files must be in storage before the database can refer to them.
+        storage.put(&storage::key(storage::ORIGINAL, file_id), upload_path).await?;
+        let mut rendition_sizes = vec![];
+        for (rendition, path) in renditions.iter().zip(rendition_paths.iter()) {
+            rendition_sizes.push((rendition.name.clone(), fs::metadata(path).await?.len()));
+            storage.put(&storage::key(&rendition.name, file_id), path).await?;
+        }
+        if let (Some(_), Some(tallest)) = (animation, renditions.first()) {
+            let kind = storage::animated_kind(&tallest.name);
+            rendition_sizes.push((kind.clone(), fs::metadata(&animated_path).await?.len()));
+            storage.put(&storage::key(&kind, file_id), &animated_path).await?;
+        }
+        if video.as_ref().is_some_and(|video| video.preview_frames > 0) {
+            let kind = storage::PREVIEW.to_string();
+            rendition_sizes.push((kind.clone(), fs::metadata(&preview_path).await?.len()));
+            storage.put(&storage::key(&kind, file_id), &preview_path).await?;
+        }
+
+        let file = File {
+            owner_id,
+            width,
+            height,
+            uploaded: Utc::now().timestamp(),
+            detected_mime: &detected_mime,
+            placeholder,
+            phash,
+            location,
+            camera,
+            size: Some(size),
+            hash: Some(hash),
+            rendition_sizes,
+            encrypted: options.encrypted,
+            animation,
+            video,
+            edit: FileEdit::default(),
+            revision: 0,
+            motion_id: None,
+            still_id: None,
+            labels: FileLabels::default(),
+            hidden: false,
+            locked: false,
+            metadata,
+        };
+
+        let stored = block_in_place(|| -> ApiResult<_> {
+            let trees = (
+                users,
+                files,
+                file_names,
+                geo,
+                phashes,
+                libraries,
+                library_fragments,
+                albums,
+                inclusions,
+                fragments,
+                user_to_album,
+                activity,
+                stats,
+            );
+            let (stored, library_head, album_head) = trees.transaction(
+                |(
+                    users,
+                    files,
+                    file_names,
+                    geo,
+                    phashes,
+                    libraries,
+                    library_fragments,
+                    albums,
+                    inclusions,
+                    fragments,
+                    user_to_album,
+                    activity,
+                    stats,
+                )| {
+                    users.get(owner_id)?.ok_or(ApiError::Unauthorized)?;
+
+                    let album_trees = (albums, inclusions, fragments, user_to_album, activity);
+
+                    let name = &file.metadata.name;
+                    let name = match resolve(files, file_names, owner_id, name, &hash, options.conflict)? {
+                        Resolution::Name(name) => name,
+                        Resolution::Duplicate(existing_id) => {
+                            let album_head = include_duplicate(files, album_trees, owner_id, &existing_id, album_id)?;
+                            return Ok((Resolution::Duplicate(existing_id), None, album_head));
+                        }
+                    };
+
+                    let mut file = file.clone();
+                    file.metadata.name = Cow::from(name.clone());
+
+                    file_names.insert([owner_id, ".", &name].concat().as_bytes(), file_id.as_bytes())?;
+                    files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+                    stats::count(stats, &file, true)?;
+
+                    if let Some(location) = location {
+                        geo.insert(geo::key(owner_id, location, file_id).as_bytes(), b"")?;
+                    }
+                    if let Some(phash) = phash {
+                        phashes.insert(similar::key(owner_id, phash, file_id).as_bytes(), b"")?;
+                    }
+
+                    let album_head = match album_id {
+                        Some(album_id) => album::add_upload(album_trees, album_id, owner_id, file_id, &file)?,
+                        None => None,
+                    };
+
+                    let library_head =
+                        library::modify(libraries, library_fragments, owner_id, |e| e.add(file_id, &file))?;
+                    Ok((Resolution::Name(name), Some(library_head), album_head))
+                },
+            )?;
+
+            if let Some(library_head) = library_head {
+                library::updated(state, owner_id, library_head);
+            }
+            if let (Some(album_id), Some(album_head)) = (album_id, album_head) {
+                events::album_updated(state, album_id, album_head);
+            }
+
+            Ok(stored)
+        })?;
+
+        match stored {
+            Resolution::Name(name) => {
+                metrics.record_upload(size);
+
+                // The file is stored either way, so a pairing that fails only loses the badge.
+                if let Err(err) = block_in_place(|| live::pair(state, file_id, options.content_id.as_deref())) {
+                    warn!(error = %err.chain(), "Couldn't pair {}", file_id);
+                }
+                if let Err(err) = block_in_place(|| rules::apply(state, file_id)) {
+                    warn!(error = %err.chain(), "Couldn't apply album rules to {}", file_id);
+                }
+                if !options.encrypted {
+                    if let Err(err) = block_in_place(|| detect::enqueue(state, file_id)) {
+                        warn!(error = %err.chain(), "Couldn't leave {} for detection", file_id);
+                    }
+                }
+
+                Ok(StoredFile {
+                    id: Cow::from(file_id.to_string()),
+                    name: Cow::from(name),
+                    hash: Some(Cow::from(hash_hex(&hash))),
+                })
+            }
+            // Another upload of the same file got there first.
+            Resolution::Duplicate(existing_id) => {
+                duplicate = true;
+                Ok(StoredFile {
+                    id: Cow::from(existing_id),
+                    name: Cow::from(file.metadata.name.into_owned()),
+                    hash: Some(Cow::from(hash_hex(&hash))),
+                })
+            }
+        }
+    }
+    .await;
+
+    let _ = join!(
+        fs::remove_file(&scratch_path),
+        fs::remove_file(&animated_path),
+        fs::remove_file(&preview_path),
+        future::join_all(rendition_paths.iter().map(fs::remove_file))
+    );
+
+    if result.is_err() || duplicate {
+        for key in &keys {
+            let _ = storage.delete(key).await;
+        }
+    }
+
+    result
+}
+
+/// A file of a batch upload that is being stored, or that was already turned away.
+enum Pending {
+    Running(JoinHandle<ApiResult<StoredFile<'static>>>),
+    Failed(ApiError),
+}
+
+/// Upload several files in one `multipart/form-data` request, which saves small files the cost
+/// of a request each. Every file is sent as a `metadata` part, holding its JSON `FileMetadata`,
+/// followed by a `file` part with its contents. Files are stored while the rest of the body
+/// arrives, and the response holds an `UploadResult` for each of them in the order that they
+/// were sent, so that one bad file doesn't fail the others.
+async fn upload_batch(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let state: &AppState = parts.data().unwrap();
+    let AppState {
+        ref sessions,
+        ref temp_path,
+        ref upload_limiter,
+        ref config,
+        ..
+    } = state;
+
+    test_logged_in(sessions, key)?;
+    let options = Arc::new(UploadOptions::parse(&parts, owner_id)?);
+    if options.content_id.is_some() {
+        return Err(ApiError::BadRequest);
+    }
+    let _upload = upload_limiter.acquire(owner_id)?;
+
+    let max_bytes = config.max_upload_bytes;
+    let timeout = Duration::from_secs(config.upload_timeout_seconds);
+    let idle = Duration::from_secs(config.body_idle_seconds);
+
+    let max_batch_bytes = max_bytes.saturating_mul(config.max_batch_files as u64);
+    if content_length(&parts).is_some_and(|length| length > max_batch_bytes) {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let boundary = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| multer::parse_boundary(content_type).ok())
+        .ok_or(ApiError::BadRequest)?;
+    let mut multipart = multer::Multipart::new(body, boundary);
+
+    // Bounds the files that are rendered at once, and so the files that wait on disk.
+    let permits = Arc::new(Semaphore::new(config.batch_upload_parallelism.max(1)));
+
+    let mut pending = vec![];
+    let mut metadata: Option<FileMetadata<'static, 'static>> = None;
+
+    loop {
+        let deadline = time::Instant::now() + timeout;
+
+        let wait = deadline.min(time::Instant::now() + idle);
+        let mut field = match time::timeout_at(wait, multipart.next_field()).await {
+            Ok(field) => match field? {
+                Some(field) => field,
+                None => break,
+            },
+            Err(_) => return Err(ApiError::Timeout),
+        };
+
+        match field.name() {
+            Some("metadata") => {
+                // Metadata is read into memory, so it is capped like any other small body.
+                let mut bytes = vec![];
+                while let Some(chunk) = next_chunk(&mut field, deadline, idle).await? {
+                    if bytes.len() + chunk.len() > config.max_body_bytes {
+                        return Err(ApiError::PayloadTooLarge);
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                let parsed: FileMetadata = serde_json::from_slice(&bytes)?;
+                metadata = Some(parsed.into_owned());
+            }
+            Some("file") => {
+                let metadata = metadata.take().ok_or(ApiError::BadRequest)?;
+                if pending.len() >= config.max_batch_files {
+                    return Err(ApiError::PayloadTooLarge);
+                }
+
+                let file_id = new_id(16);
+                let upload_path = temp_path.join(&file_id);
+
+                let head = match receive(&mut field, &upload_path, max_bytes, deadline, idle).await {
+                    Ok(head) => head,
+                    Err(err) => {
+                        let _ = fs::remove_file(&upload_path).await;
+                        pending.push((metadata.name.to_string(), Pending::Failed(err)));
+                        continue;
+                    }
+                };
+
+                let permit = permits.clone().acquire_owned().await.unwrap();
+                let name = metadata.name.to_string();
+
+                let state = state.clone();
+                let owner_id = owner_id.to_string();
+                let options = options.clone();
+                let handle = tokio::spawn(async move {
+                    // Held until the file is stored.
+                    let _permit = permit;
+
+                    let result = store(&state, &owner_id, &file_id, &options, metadata, &upload_path, &head).await;
+                    let _ = fs::remove_file(&upload_path).await;
+                    result
+                });
+
+                pending.push((name, Pending::Running(handle)));
+            }
+            _ => return Err(ApiError::BadRequest),
+        }
+    }
+
+    let mut results = vec![];
+    for (name, task) in pending {
+        let result = match task {
+            Pending::Running(handle) => handle.await.unwrap(),
+            Pending::Failed(err) => Err(err),
+        };
+
+        results.push(match result {
+            Ok(stored) => UploadResult {
+                name: Cow::from(name),
+                id: Some(stored.id),
+                error: None,
+                stored_name: Some(stored.name),
+                hash: stored.hash,
+            },
+            Err(err) => UploadResult {
+                name: Cow::from(name),
+                id: None,
+                error: Some(Cow::from(err.to_string())),
+                stored_name: None,
+                hash: None,
+            },
+        });
+    }
+
+    respond_ok(results)
+}
+
+/// Names and ids of files, along with the token for the next page.
+struct Page {
+    files: Vec<(String, String)>,
+    next: Option<String>,
+}
+
+/// Whether a file stays out of the locked folder, which files that were deleted in the meantime do.
+fn unlocked(files: &sled::Tree, file_id: &[u8]) -> sled::Result<bool> {
+    Ok(match files.get(file_id)? {
+        Some(file_bytes) => !bincode::deserialize::<File>(&file_bytes).unwrap().locked,
+        None => true,
+    })
+}
+
+fn list_page(files: &sled::Tree, file_names: &sled::Tree, owner_id: &str, json: &ListRequest) -> ApiResult<Page> {
+    let name_prefix = json.prefix.as_deref().unwrap_or("");
+    let prefix = [owner_id, ".", name_prefix].concat();
+
+    let start = match json.token {
+        Some(ref token) => {
+            let last_name = decode_token(token)?;
+            if !last_name.starts_with(name_prefix) {
+                return Err(ApiError::BadRequest);
+            }
+            Bound::Excluded([owner_id, ".", &last_name].concat().into_bytes())
+        }
+        None => Bound::Included(prefix.clone().into_bytes()),
+    };
+
+    // One more file than was asked for tells whether there is another page.
+    let length = json.length.unwrap_or(usize::MAX);
+    let mut kv_pairs = file_names
+        .range((start, Bound::Unbounded))
+        .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key.starts_with(prefix.as_bytes())))
+        // Locked files are only listed in the locked folder, which needs elevation.
+        .filter_map(|entry| match entry {
+            Ok((name, file_id)) => match unlocked(files, &file_id) {
+                Ok(true) => Some(Ok((name, file_id))),
+                Ok(false) => None,
+                Err(err) => Some(Err(err)),
+            },
+            Err(err) => Some(Err(err)),
+        })
+        .skip(json.skip.unwrap_or(0))
+        .take(length.saturating_add(1))
+        .collect::<sled::Result<Vec<(sled::IVec, sled::IVec)>>>()?;
+
+    let more = kv_pairs.len() > length;
+    kv_pairs.truncate(length);
+
+    let file_pairs: Vec<_> = kv_pairs
+        .iter()
+        .map(|(key, file_id)| {
+            let (_, file_name) = std::str::from_utf8(key).unwrap().split_once('.').unwrap();
+            let file_id = std::str::from_utf8(file_id).unwrap();
+            (file_name.to_string(), file_id.to_string())
+        })
+        .collect();
+
+    let next = match file_pairs.last() {
+        Some((file_name, _)) if more => Some(encode_token(file_name)),
+        _ => None,
+    };
+
+    Ok(Page { files: file_pairs, next })
+}
+
+async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: ListRequest = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref file_names,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let page = list_page(files, file_names, owner_id, &json)?;
+
+        respond_ok(FileList {
+            files: page
+                .files
+                .into_iter()
+                .map(|(file_name, file_id)| (Cow::from(file_name), Cow::from(file_id)))
+                .collect(),
+            next: page.next.map(Cow::from),
+        })
+    })
+}
+
+/// List files along with their records, so that galleries don't have to ask about each one.
+async fn list_entries(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: ListRequest = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref file_names,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let page = list_page(files, file_names, owner_id, &json)?;
+
+        let mut entries = Vec::with_capacity(page.files.len());
+        for (file_name, file_id) in page.files {
+            // Files that were deleted since their names were read are left out.
+            let file_bytes = match files.get(file_id.as_bytes())? {
+                Some(file_bytes) => file_bytes,
+                None => continue,
+            };
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+            if file.locked {
+                continue;
+            }
+
+            entries.push(FileEntry {
+                name: Cow::from(file_name),
+                id: Cow::from(file_id),
+                width: file.width,
+                height: file.height,
+                last_modified: file.metadata.last_modified,
+                mime: Cow::from(file.detected_mime.to_string()),
+                size: file.size,
+                encrypted: file.encrypted,
+            });
+        }
+
+        respond_ok(FileEntryList {
+            files: entries,
+            next: page.next.map(Cow::from),
+        })
+    })
+}
+
+/// The files in the user's locked folder, by name. Files aren't indexed by whether they are
+/// locked, so every file of the user is read.
+async fn list_locked(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref file_names,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+        require_elevation(&parts, key)?;
+
+        let prefix = [owner_id, "."].concat();
+        let mut entries = vec![];
+
+        for entry in file_names.scan_prefix(&prefix) {
+            let (file_name, file_id) = entry?;
+            let file_bytes = match files.get(&file_id)? {
+                Some(file_bytes) => file_bytes,
+                None => continue,
+            };
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+            if !file.locked {
+                continue;
+            }
+
+            entries.push(FileEntry {
+                name: Cow::from(std::str::from_utf8(&file_name[prefix.len()..]).unwrap().to_string()),
+                id: Cow::from(std::str::from_utf8(&file_id).unwrap().to_string()),
+                width: file.width,
+                height: file.height,
+                last_modified: file.metadata.last_modified,
+                mime: Cow::from(file.detected_mime.to_string()),
+                size: file.size,
+                encrypted: file.encrypted,
+            });
+        }
+
+        respond_ok(entries)
+    })
+}
+
+/// Tokens hold the name of the last file of a page, but clients shouldn't rely on that.
+fn encode_token(file_name: &str) -> String {
+    base64::encode_config(file_name, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_token(token: &str) -> ApiResult<String> {
+    let name_bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| ApiError::BadRequest)?;
+    String::from_utf8(name_bytes).map_err(|_| ApiError::BadRequest)
+}
+
+async fn info(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref inclusions,
+            ref albums,
+            ref user_to_album,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let file_id = parts.param("fileId").unwrap();
+        let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+        let mut file_albums = vec![];
+        for inclusion in inclusions.scan_prefix([file_id, "."].concat()).keys() {
+            let inclusion = inclusion?;
+            let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+
+            let role_bytes = match user_to_album.get([user_id, ".", album_id].concat())? {
+                Some(role_bytes) => role_bytes,
+                None => continue,
+            };
+
+            // Trashed albums keep their inclusions until they are purged.
+            let album_bytes = match albums.get(album_id.as_bytes())? {
+                Some(album_bytes) => album_bytes,
+                None => continue,
+            };
+            let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            file_albums.push(FileAlbum {
+                id: Cow::from(album_id.to_string()),
+                name: Cow::from(album.description.name.to_string()),
+                role: bincode::deserialize(&role_bytes).unwrap(),
+            });
+        }
+
+        if file.owner_id != user_id && file_albums.is_empty() {
+            return Err(ApiError::NotFound);
+        }
+        if file.locked {
+            require_elevation(&parts, key)?;
+        }
+
+        respond_ok(FileInfo {
+            owner_id: Cow::from(file.owner_id),
+            width: file.width,
+            height: file.height,
+            uploaded: file.uploaded,
+            detected_mime: Cow::from(file.detected_mime),
+            size: file.size,
+            hash: file.hash.as_ref().map(|hash| Cow::from(hash_hex(hash))),
+            location: file.location,
+            camera: file.camera.map(Cow::from),
+            encrypted: file.encrypted,
+            animation: file.animation,
+            video: file.video,
+            edit: file.edit,
+            revision: file.revision,
+            motion_id: file.motion_id.map(Cow::from),
+            still_id: file.still_id.map(Cow::from),
+            labels: file.labels,
+            hidden: file.hidden,
+            locked: file.locked,
+            metadata: file.metadata,
+            albums: file_albums,
+        })
+    })
+}
+
+async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState { ref sessions, .. } = state;
+
+        test_logged_in(sessions, key)?;
+
+        let file_id = parts.param("fileId").unwrap();
+        trash::move_file(state, owner_id, file_id)?;
+
+        respond_ok_empty()
+    })
+}
+
+async fn rename(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: Rename = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref files,
+            ref file_names,
+            ref inclusions,
+            ref albums,
+            ref fragments,
+            ..
+        } = state;
+
+        test_logged_in(sessions, key)?;
+
+        let file_id = parts.param("fileId").unwrap();
+        let new_file_name = [owner_id, ".", &json.name].concat();
+
+        let mut album_ids = vec![];
+        for entry in inclusions.scan_prefix([file_id, "."].concat()) {
+            let (inclusion, _) = entry?;
+            let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+            album_ids.push(album_id.to_string());
+        }
+
+        let updated = (files, file_names, albums, fragments).transaction(|(files, file_names, albums, fragments)| {
+            let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
 
-    let result = block_in_place(|| {
-        let original = if metadata.mime.starts_with("video/") {
-            std::process::Command::new("ffmpeg")
-                .arg("-i")
-                .arg(upload_path.as_os_str())
-                .arg("-vframes")
-                .arg("1")
-                .arg(&temp_path.to_str().unwrap())
-                .output()?;
-            VipsImage::new_from_file(&temp_path.to_str().unwrap())?
-        } else {
-            VipsImage::new_from_file(&upload_path.to_str().unwrap())?
-        };
+            if file.owner_id != owner_id {
+                return Err(ApiError::NotFound.into());
+            }
 
-        let rotated = ops::autorot(&original).unwrap();
+            let old_file_name = [owner_id, ".", &file.metadata.name].concat();
+            if old_file_name == new_file_name {
+                return Ok(vec![]);
+            }
 
-        let height = rotated.get_height();
-        let width = rotated.get_width();
+            if file_names.insert(new_file_name.as_bytes(), file_id.as_bytes())?.is_some() {
+                return Err(ApiError::FileExists.into());
+            }
+            file_names.remove(old_file_name.as_bytes())?;
 
-        let medium_factor = MEDIUM_HEIGHT / height as f64;
-        let medium = ops::resize(&rotated, medium_factor)?;
-        ops::webpsave(&medium, medium_path.to_str().unwrap())?;
+            let mut renamed = file.clone();
+            renamed.metadata.name = json.name.clone();
+            files.insert(file_id.as_bytes(), bincode::serialize(&renamed).unwrap())?;
 
-        let small_factor = SMALL_HEIGHT / MEDIUM_HEIGHT;
-        let small = ops::resize(&medium, small_factor)?;
-        ops::webpsave(&small, small_path.to_str().unwrap())?;
+            // Albums that are sorted by name need to move the file to its new place.
+            let mut updated = vec![];
+            for album_id in &album_ids {
+                let album_bytes = match albums.get(album_id.as_bytes())? {
+                    Some(album_bytes) => album_bytes,
+                    None => continue,
+                };
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
 
-        let file = File {
-            owner_id,
-            width,
-            height,
-            metadata,
-        };
+                if album.description.sort != SortMode::Name {
+                    continue;
+                }
 
-        (users, files, file_names).transaction(|(users, files, file_names)| {
-            users.get(owner_id)?.ok_or(ApiError::Unauthorized)?;
-            files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
+                e.replace(file_id, &file, &renamed)?;
+                e.commit()?;
 
-            match file_names.insert(owner_file_name.as_bytes(), file_id.as_bytes())? {
-                Some(_) => Err(ApiError::FileExists.into()),
-                None => Ok(()),
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                updated.push((album_id, album.fragment_head));
             }
+
+            Ok(updated)
         })?;
 
-        respond_ok(NewResource {
-            id: Cow::from(file_id),
-        })
-    });
-
-    if result.is_err() {
-        let _ = join!(
-            fs::remove_file(&upload_path),
-            fs::remove_file(&medium_path),
-            fs::remove_file(&small_path),
-            fs::remove_file(&temp_path)
-        );
-    } else {
-        let _ = fs::remove_file(&temp_path).await;
-    }
+        for (album_id, fragment_head) in updated {
+            events::album_updated(state, album_id, fragment_head);
+        }
 
-    result
+        respond_ok_empty()
+    })
 }
 
-async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+/// Leave a file out of its owner's library, or put it back in. Albums and listings keep it either
+/// way.
+async fn hide(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, body) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
-    let json: ListRequest = serde_json::from_slice(&entire_body)?;
+    let entire_body = join(&parts, body).await?;
+    let json: Hidden = serde_json::from_slice(&entire_body)?;
 
     block_in_place(|| {
+        let state = parts.data().unwrap();
         let AppState {
             ref sessions,
-            ref file_names,
+            ref files,
+            ref libraries,
+            ref library_fragments,
             ..
-        } = parts.data().unwrap();
+        } = state;
 
-        sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
-
-        let prefix = [owner_id, ".", &json.prefix.unwrap_or(Cow::from(""))].concat();
-        let kv_pairs = file_names
-            .scan_prefix(prefix.as_bytes())
-            .skip(json.skip.unwrap_or(0))
-            .take(json.length.unwrap_or(usize::MAX))
-            .collect::<sled::Result<Vec<(sled::IVec, sled::IVec)>>>()?;
-
-        let file_pairs = kv_pairs
-            .iter()
-            .map(|(key, file_id)| {
-                let (_, file_name) = std::str::from_utf8(&key).unwrap().split_once('.').unwrap();
-                let file_id = std::str::from_utf8(&file_id).unwrap();
-                (Cow::from(file_name), Cow::from(file_id))
-            })
-            .collect();
+        test_logged_in(sessions, key)?;
+
+        let file_id = parts.param("fileId").unwrap();
+
+        let library_head = (files, libraries, library_fragments).transaction(|(files, libraries, library_fragments)| {
+            let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+
+            if file.owner_id != owner_id {
+                return Err(ApiError::NotFound.into());
+            }
+            if file.hidden == json.hidden {
+                return Ok(None);
+            }
+
+            file.hidden = json.hidden;
+            files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+
+            let library_head = library::modify(libraries, library_fragments, owner_id, |e| {
+                if file.hidden {
+                    e.remove(file_id, &file)
+                } else {
+                    e.add(file_id, &file)
+                }
+            })?;
+            Ok(Some(library_head))
+        })?;
+
+        if let Some(library_head) = library_head {
+            library::updated(state, owner_id, library_head);
+        }
 
-        respond_ok(FileList { files: file_pairs })
+        respond_ok_empty()
     })
 }
 
-async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
-    let (parts, _) = req.into_parts();
+/// Move a file into its owner's locked folder, which takes it out of the library and out of every
+/// album that it is in, or move it back out into the library. Albums don't get it back.
+async fn lock(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
 
     let key = require_key(&parts)?;
     let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
+    let entire_body = join(&parts, body).await?;
+    let json: Locked = serde_json::from_slice(&entire_body)?;
+
     block_in_place(|| {
         let state = parts.data().unwrap();
         let AppState {
             ref sessions,
             ref files,
+            ref libraries,
+            ref library_fragments,
+            ref albums,
+            ref fragments,
+            ref inclusions,
             ..
         } = state;
 
-        sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
+        test_logged_in(sessions, key)?;
+        require_elevation(&parts, key)?;
 
         let file_id = parts.param("fileId").unwrap();
-        let file_bytes = files.get(file_id)?.ok_or(ApiError::NotFound)?;
-        let file: File = bincode::deserialize(&file_bytes).unwrap();
 
-        if file.owner_id != owner_id {
-            return Err(ApiError::NotFound);
+        let mut album_ids = vec![];
+        for entry in inclusions.scan_prefix([file_id, "."].concat()) {
+            let (inclusion, _) = entry?;
+            let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+            album_ids.push(album_id.to_string());
         }
 
-        delete::Command::File(file_id, file).run(state)?;
+        let trees = (files, libraries, library_fragments, albums, fragments, inclusions);
+        let (library_head, updated) = trees.transaction(
+            |(files, libraries, library_fragments, albums, fragments, inclusions)| {
+                let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+                let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                if file.owner_id != owner_id {
+                    return Err(ApiError::NotFound.into());
+                }
+                if file.locked == json.locked {
+                    return Ok((None, vec![]));
+                }
+
+                // Hidden files stay out of the library either way.
+                let was_in_library = file.in_library();
+                file.locked = json.locked;
+                files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+
+                let mut library_head = None;
+                if was_in_library != file.in_library() {
+                    let head = library::modify(libraries, library_fragments, owner_id, |e| {
+                        if file.locked {
+                            e.remove(file_id, &file)
+                        } else {
+                            e.add(file_id, &file)
+                        }
+                    })?;
+                    library_head = Some(head);
+                }
+
+                let mut updated = vec![];
+                if file.locked {
+                    for album_id in &album_ids {
+                        inclusions.remove([file_id, ".", album_id].concat().as_bytes())?;
+
+                        // Albums that are in the trash drop the file when they are restored.
+                        if let Some(album_bytes) = albums.get(album_id.as_bytes())? {
+                            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                            let mut e = Engine::new(album_id, &mut album, fragments)?;
+                            e.remove(file_id, &file)?;
+                            e.commit()?;
+
+                            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                            updated.push((album_id.clone(), album.fragment_head));
+                        }
+                    }
+                }
+
+                Ok((library_head, updated))
+            },
+        )?;
+
+        if let Some(library_head) = library_head {
+            library::updated(state, owner_id, library_head);
+        }
+        for (album_id, fragment_head) in updated {
+            events::album_updated(state, &album_id, fragment_head);
+        }
 
         respond_ok_empty()
     })
 }
 
-fn file_stream(mut file: fs::File, chunk_size: usize) -> impl Stream<Item = io::Result<Bytes>> {
-    try_stream! {
-        loop {
-            let mut buffer = BytesMut::with_capacity(chunk_size);
-            file.read_buf(&mut buffer).await?;
+/// Turn a file for display, rendering it again with the edit. The original is left as it is, so
+/// the edit can be replaced or reverted later.
+async fn edit(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-            if buffer.is_empty() {
-                break;
-            }
+    let entire_body = join(&parts, body).await?;
+    let edit: FileEdit = serde_json::from_slice(&entire_body)?;
+    edit::check(&edit)?;
+
+    let file_id = parts.param("fileId").unwrap();
+    let state: &AppState = parts.data().unwrap();
+    let AppState {
+        ref sessions,
+        ref files,
+        ..
+    } = state;
+
+    block_in_place(|| -> ApiResult<()> {
+        test_logged_in(sessions, key)?;
+
+        let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+        if file.owner_id != owner_id {
+            return Err(ApiError::NotFound);
+        }
+
+        Ok(())
+    })?;
+
+    regenerate::edit(state, file_id, edit).await?;
+
+    respond_ok_empty()
+}
 
-            yield buffer.into();
+/// Check that a user may be served `file`, which they may if they own it or are a member of the
+/// album in the `album` query. Locked files also need an elevation token.
+pub fn check_access(parts: &Parts, key: &str, user_id: &str, file: &File) -> ApiResult<()> {
+    let AppState { ref user_to_album, .. } = parts.data().unwrap();
+
+    match auth_album(parts) {
+        None => {
+            if file.owner_id != user_id {
+                return Err(ApiError::NotFound);
+            }
+        }
+        Some(album_id) => {
+            user_to_album
+                .get([user_id, ".", album_id].concat())?
+                .ok_or(ApiError::Unauthorized)?;
         }
     }
+    if file.locked {
+        require_elevation(parts, key)?;
+    }
+
+    Ok(())
 }
 
 async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    // Animated images are served animated in their tallest rendition unless the poster is asked for.
+    let still = req
+        .query("still")
+        .map(|s| s.parse::<bool>().ok())
+        .unwrap_or(Some(false))
+        .ok_or(ApiError::BadRequest)?;
+
     let (parts, _) = req.into_parts();
 
     let key = require_key(&parts)?;
@@ -231,86 +1404,682 @@ async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
     let quality = parts.param("quality").unwrap();
     let file_id = parts.param("fileId").unwrap();
 
+    let state: &AppState = parts.data().unwrap();
     let AppState {
         ref sessions,
         ref files,
-        ref user_to_album,
-        ref upload_path,
-        ref medium_path,
-        ref small_path,
+        ref storage,
+        ref config,
         ..
-    } = parts.data().unwrap();
+    } = state;
 
-    sessions
-        .get(key.as_bytes())?
-        .ok_or(ApiError::Unauthorized)?;
+    test_logged_in(sessions, key)?;
 
     let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
     let file: File = bincode::deserialize(&file_bytes).unwrap();
 
-    match auth_album(&parts) {
-        None => {
-            if file.owner_id != user_id {
-                return Err(ApiError::NotFound);
+    check_access(&parts, key, user_id, &file)?;
+    bandwidth::check(state, key, user_id)?;
+    // Members of albums that strip metadata are served originals without it.
+    let stripped = block_in_place(|| strip::applies(state, user_id, file_id, &file))?;
+
+    // The motion of a Live Photo is served in full, as its own original is.
+    if quality == "motion" {
+        let motion_id = file.motion_id.as_deref().ok_or(ApiError::NotFound)?;
+        let motion_bytes = files.get(motion_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+        let motion: File = bincode::deserialize(&motion_bytes).unwrap();
+        let original = if stripped {
+            strip::original(state, motion_id, motion.detected_mime, &motion.metadata.name).await?
+        } else {
+            storage::key(storage::ORIGINAL, motion_id)
+        };
+        let stream = storage.get_stream(&original).await?;
+        let stream = bandwidth::count(state, key, user_id, stream);
+
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, motion.detected_mime)
+            .status(StatusCode::OK)
+            .body(Body::wrap_stream(stream))
+            .unwrap());
+    }
+
+    // Labels are written out as a sidecar, which is made from the record every time.
+    if quality == "xmp" {
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, xmp::MIME)
+            .status(StatusCode::OK)
+            .body(Body::from(xmp::write(&file.labels)))
+            .unwrap());
+    }
+
+    if quality == "large" {
+        let original = if stripped {
+            strip::original(state, file_id, file.detected_mime, &file.metadata.name).await?
+        } else {
+            storage::key(storage::ORIGINAL, file_id)
+        };
+        let stream = storage.get_stream(&original).await?;
+        let stream = bandwidth::count(state, key, user_id, stream);
+
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, file.detected_mime)
+            .status(StatusCode::OK)
+            .body(Body::wrap_stream(stream))
+            .unwrap());
+    }
+
+    // Encrypted files only have their original.
+    if file.encrypted {
+        return Err(ApiError::NotFound);
+    }
+
+    // The preview strip of a video is encoded like the shortest rendition.
+    if quality == storage::PREVIEW {
+        let has_strip = file.video.as_ref().is_some_and(|video| video.preview_frames > 0);
+        let shortest = config.renditions.last().filter(|_| has_strip).ok_or(ApiError::NotFound)?;
+        let stream = storage.get_stream(&storage::key(storage::PREVIEW, file_id)).await?;
+        let stream = bandwidth::count(state, key, user_id, stream);
+
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, shortest.encoding.mime())
+            .status(StatusCode::OK)
+            .body(Body::wrap_stream(stream))
+            .unwrap());
+    }
+
+    let rendition = config
+        .renditions
+        .iter()
+        .find(|rendition| &rendition.name == quality)
+        .ok_or(ApiError::BadRequest)?;
+
+    // The frames of the animated copy aren't turned by edits, so edited files show their poster.
+    let tallest = config.renditions.first().map(|tallest| &tallest.name);
+    let animated = file.animation.is_some()
+        && file.edit == FileEdit::default()
+        && !still
+        && tallest == Some(&rendition.name);
+
+    let mut kind = rendition.name.clone();
+    let mut mime = rendition.encoding.mime();
+
+    if animated {
+        kind = storage::animated_kind(&rendition.name);
+        mime = Encoding::Webp.mime();
+    } else if rendition.encoding != Encoding::Avif && state.capabilities.avif && accepts_avif(&parts) {
+        let key = storage::key(&rendition.name, file_id);
+        let avif_kind = storage::avif_kind(&rendition.name);
+
+        match avif_rendition(state, rendition, &key, &storage::key(&avif_kind, file_id)).await {
+            Ok(()) => {
+                kind = avif_kind;
+                mime = Encoding::Avif.mime();
             }
-        }
-        Some(album_id) => {
-            user_to_album
-                .get([user_id, ".", album_id].concat())?
-                .ok_or(ApiError::Unauthorized)?;
+            Err(err) => warn!(error = %err.chain(), "Couldn't make AVIF rendition of {}", file_id),
         }
     }
 
-    let (path, mime): (_, &str) = match quality.as_str() {
-        "large" => (upload_path.join(file_id), &file.metadata.mime),
-        "medium" => (medium_path.join(file_id), "image/webp"),
-        "small" => (small_path.join(file_id), "image/webp"),
-        _ => return Err(ApiError::BadRequest),
-    };
+    // Renditions only change when the file is edited, which changes the revision.
+    let etag = format!("\"{}-{}-{}\"", file_id, file.revision, kind);
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "private, no-cache")
+        .header(header::VARY, "Accept");
 
-    let stream = file_stream(fs::File::open(path).await?, 1024 * 8);
+    if etag_matches(&parts, &etag) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
+    }
+
+    let stream = storage.get_stream(&storage::key(&kind, file_id)).await?;
+    let stream = bandwidth::count(state, key, user_id, stream);
 
-    Ok(Response::builder()
+    Ok(response
         .header(header::CONTENT_TYPE, mime)
         .status(StatusCode::OK)
         .body(Body::wrap_stream(stream))
         .unwrap())
 }
 
+/// Whether the `Accept` header asks for AVIF by name. A bare `*/*` doesn't count, since that is
+/// what clients without AVIF support send too.
+fn accepts_avif(parts: &Parts) -> bool {
+    let accept = match parts.headers.get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+
+    accept.split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        let mime = params.next().unwrap_or("");
+
+        let quality = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        mime.eq_ignore_ascii_case("image/avif") && quality > 0.0
+    })
+}
+
+/// Make sure that the AVIF copy of a rendition is stored at `avif_key`, encoding it from the
+/// rendition at `key` the first time that it is asked for.
+async fn avif_rendition(app_state: &AppState, rendition: &Rendition, key: &str, avif_key: &str) -> ApiResult<()> {
+    let AppState {
+        ref storage,
+        ref temp_path,
+        ref image_pool,
+        ..
+    } = app_state;
+
+    if storage.exists(avif_key).await? {
+        return Ok(());
+    }
+
+    let id = new_id(16);
+    let source_path = temp_path.join([&id, ".", &rendition.name].concat());
+    let avif_path = temp_path.join([&id, ".avif"].concat());
+
+    let result = async {
+        storage::download(storage.as_ref(), key, &source_path).await?;
+
+        let avif = Rendition {
+            encoding: Encoding::Avif,
+            ..rendition.clone()
+        };
+        let (source, destination) = (source_path.clone(), avif_path.clone());
+        image_pool
+            .run(move || {
+                let source = VipsImage::new_from_file(source.to_str().unwrap())?;
+                format::save(&source, &avif, &destination)
+            })
+            .await?;
+
+        storage.put(avif_key, &avif_path).await?;
+        Ok(())
+    }
+    .await;
+
+    let _ = join!(fs::remove_file(&source_path), fs::remove_file(&avif_path));
+
+    result
+}
+
+/// Upload restrictions, so that clients can skip files that would be rejected.
+pub async fn limits(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let AppState {
+        ref config,
+        ref capabilities,
+        ..
+    } = req.data().unwrap();
+
+    respond_ok(Limits {
+        max_upload_bytes: config.max_upload_bytes,
+        upload_timeout_seconds: config.upload_timeout_seconds,
+        max_batch_files: config.max_batch_files,
+        unsupported_formats: capabilities.unsupported(),
+    })
+}
+
+pub const SCOPE: &str = "/file";
+
 pub fn router() -> Router<Body, ApiError> {
     Router::builder()
-        .post("/", upload)
-        .post("/list", list)
-        .delete("/:fileId", delete)
-        .get("/:quality/:fileId", serve)
+        .endpoint(SCOPE, endpoint::Upload, upload)
+        .endpoint(SCOPE, endpoint::UploadBatch, upload_batch)
+        .endpoint(SCOPE, endpoint::ListFiles, list)
+        .endpoint(SCOPE, endpoint::ListFileEntries, list_entries)
+        .endpoint(SCOPE, endpoint::SearchGeo, geo::search)
+        .endpoint(SCOPE, endpoint::SearchLabels, detect::search)
+        .endpoint(SCOPE, endpoint::ListLocked, list_locked)
+        .endpoint(SCOPE, endpoint::GetFile, info)
+        .endpoint(SCOPE, endpoint::DeleteFile, delete)
+        .endpoint(SCOPE, endpoint::RenameFile, rename)
+        .endpoint(SCOPE, endpoint::HideFile, hide)
+        .endpoint(SCOPE, endpoint::LockFile, lock)
+        .endpoint(SCOPE, endpoint::EditFile, edit)
+        .endpoint(SCOPE, endpoint::SetXmp, xmp::set)
+        // Before `ServeFile`, whose path also matches.
+        .endpoint(SCOPE, endpoint::FindSimilar, similar::search)
+        .endpoint(SCOPE, endpoint::ServeCustom, resize::serve)
+        .endpoint(SCOPE, endpoint::ServeFile, serve)
         .build()
         .unwrap()
 }
 
-async fn clean_path(app_state: &AppState, path: &Path) -> ApiResult<usize> {
-    let mut removed = 0;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, TestServer, PASSWORD};
+    use crate::common::ELEVATION_HEADER;
+    use hyper::{header, Body, Method, StatusCode};
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
 
-    let mut iter = fs::read_dir(path).await?;
-    while let Some(entry) = iter.next_entry().await? {
-        let path = entry.path();
-        if let Some(file_name) = path.file_name() {
-            if app_state.files.get(file_name.as_bytes())?.is_none() {
-                if fs::remove_file(path).await.is_ok() {
-                    removed += 1;
-                }
-            }
+    #[test]
+    fn number_names() {
+        assert_eq!(numbered("IMG_0001.jpg", 1), "IMG_0001 (1).jpg");
+        assert_eq!(numbered("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(numbered("notes", 3), "notes (3)");
+        assert_eq!(numbered(".hidden", 1), ".hidden (1)");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upload_and_serve() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "black.png", png(64, 48)).await;
+        assert!(server.state.files.contains_key(&file_id).unwrap());
+
+        for quality in &["large", "medium", "small"] {
+            let path = format!("/file/{}/{}?key={}", quality, file_id, key);
+            let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+
+            assert_eq!(status, StatusCode::OK, "{}", quality);
+            assert!(!body.is_empty());
         }
+
+        // Only videos have a preview strip.
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert!(info["video"].is_null());
+        let path = format!("/file/preview/{}?key={}", file_id, key);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = server
+            .request(Method::GET, &format!("/file/small/{}", file_id), &[], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
     }
 
-    Ok(removed)
-}
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_upload() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
 
-pub async fn clean_files(app_state: &AppState) -> ApiResult<usize> {
-    let (a, b, c) = join!(
-        clean_path(app_state, &app_state.upload_path),
-        clean_path(app_state, &app_state.medium_path),
-        clean_path(app_state, &app_state.small_path)
-    );
+        let boundary = "photos-test-boundary";
+        let mut body = vec![];
+        let files = [("first.png", png(8, 8)), ("broken.png", b"not a png".to_vec()), ("second.png", png(8, 8))];
+        for (name, bytes) in files {
+            let metadata = json!({ "last_modified": 0, "name": name, "mime": "image/png" });
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{}\r\n",
+                    boundary, metadata
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+                    boundary, name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&bytes);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let (status, response) = server
+            .request(
+                Method::POST,
+                &format!("/file/batch?key={}", key),
+                &[(header::CONTENT_TYPE.as_str(), content_type)],
+                Body::from(body),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&response));
+
+        let results: Value = serde_json::from_slice(&response).unwrap();
+        let results = results.as_array().unwrap();
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[0]["name"], "first.png");
+        assert!(results[0]["id"].is_string());
+        assert_eq!(results[0]["hash"], wire::hash_hex(&Sha256::digest(png(8, 8)).into()));
+        assert!(results[1]["id"].is_null());
+        assert!(results[1]["error"].is_string());
+        assert!(results[2]["id"].is_string());
+
+        assert_eq!(server.state.files.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_upload_large_metadata() {
+        let server = TestServer::start_with(|config| config.max_body_bytes = 1024).await;
+        let key = server.signup("owner@example.com").await;
+
+        let boundary = "photos-test-boundary";
+        let metadata = json!({ "last_modified": 0, "name": "x".repeat(2048), "mime": "image/png" });
+        let body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{}\r\n--{}--\r\n",
+            boundary, metadata, boundary
+        );
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        let (status, _) = server
+            .request(
+                Method::POST,
+                &format!("/file/batch?key={}", key),
+                &[(header::CONTENT_TYPE.as_str(), content_type)],
+                Body::from(body),
+            )
+            .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_pages() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
 
-    Ok(a? + b? + c?)
+        for name in ["a.png", "b.png", "c.png", "other.png"] {
+            server.upload(&key, name, png(8, 8)).await;
+        }
+
+        let path = format!("/file/list?key={}", key);
+        let first = server.json(Method::POST, &path, &json!({ "length": 2 })).await;
+        assert_eq!(first["files"][0][0], "a.png");
+        assert_eq!(first["files"][1][0], "b.png");
+
+        let token = first["next"].as_str().unwrap();
+        let second = server.json(Method::POST, &path, &json!({ "length": 2, "token": token })).await;
+        assert_eq!(second["files"][0][0], "c.png");
+        assert_eq!(second["files"][1][0], "other.png");
+        assert_eq!(second["next"], Value::Null);
+
+        // Tokens stay within the prefix that they were made for.
+        let prefixed = server.json(Method::POST, &path, &json!({ "prefix": "o", "length": 1 })).await;
+        assert_eq!(prefixed["files"][0][0], "other.png");
+        assert_eq!(prefixed["next"], Value::Null);
+
+        let status = server
+            .send(Method::POST, &path, &json!({ "prefix": "o", "token": token }))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_entries() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let image = png(12, 8);
+        let size = image.len();
+        server.upload(&key, "a.png", image).await;
+        server.upload(&key, "b.png", png(8, 8)).await;
+
+        let path = format!("/file/entries?key={}", key);
+        let page = server.json(Method::POST, &path, &json!({ "length": 1 })).await;
+        let entry = &page["files"][0];
+        assert_eq!(entry["name"], "a.png");
+        assert_eq!(entry["width"], 12);
+        assert_eq!(entry["height"], 8);
+        assert_eq!(entry["mime"], "image/png");
+        assert_eq!(entry["size"], size);
+
+        let token = page["next"].as_str().unwrap();
+        let rest = server.json(Method::POST, &path, &json!({ "token": token })).await;
+        assert_eq!(rest["files"][0]["name"], "b.png");
+        assert_eq!(rest["next"], Value::Null);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_info() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let bytes = png(64, 48);
+        let file_id = server.upload(&owner, "black.png", bytes.clone()).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files = json!({ "ids": [file_id] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, owner), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let path = format!("/file/{}?key={}", file_id, owner);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["width"], 64);
+        assert_eq!(info["metadata"]["name"], "black.png");
+        assert_eq!(info["hash"], wire::hash_hex(&Sha256::digest(&bytes).into()));
+        assert_eq!(info["albums"][0]["id"], album_id);
+        assert_eq!(info["albums"][0]["name"], "Trip");
+        assert_eq!(info["albums"][0]["role"], "Owner");
+
+        // Others can only see the file once it is in an album that is shared with them.
+        let path = format!("/file/{}?key={}", file_id, reader);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let share = json!({ "email": "reader@example.com", "role": "Reader" });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["albums"][0]["role"], "Reader");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upload_into_album() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let contributor = server.signup("contributor@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        for (email, role) in [("contributor@example.com", "Contributor"), ("reader@example.com", "Reader")] {
+            let share = json!({ "email": email, "role": role });
+            let status = server
+                .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+                .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        // Readers are turned away before anything is stored.
+        let path = format!("/file?key={}&album={}", reader, album_id);
+        let (status, _) = server.upload_to(&path, "read.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(server.state.file_names.is_empty());
+
+        let path = format!("/file?key={}&album={}", contributor, album_id);
+        let (status, body) = server.upload_to(&path, "added.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        let file_id = file["id"].as_str().unwrap();
+
+        let path = format!("/album/{}/serve/metadata?key={}", album_id, owner);
+        let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        let metadata: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metadata["length"], 1);
+
+        let path = format!("/file/{}?key={}", file_id, contributor);
+        let (_, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["albums"][0]["id"], album_id);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn upload_conflicts() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "a.png", png(8, 8)).await;
+
+        let (status, _) = server.upload_to(&format!("/file?key={}", key), "a.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let path = format!("/file?key={}&conflict=rename", key);
+        let (status, body) = server.upload_to(&path, "a.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        let renamed: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(renamed["name"], "a (1).png");
+
+        // The same contents stand in for the existing file, while different contents don't.
+        let path = format!("/file?key={}&conflict=replace_if_same", key);
+        let (status, body) = server.upload_to(&path, "a.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        let stored: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(stored["id"], file_id.as_str());
+        assert_eq!(stored["name"], "a.png");
+
+        let (status, _) = server.upload_to(&path, "a.png", png(9, 9)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        assert_eq!(server.state.files.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn encrypted_upload() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        // Anything goes, since the server can't look inside.
+        let sealed: Vec<u8> = (0..=255).collect();
+        let path = format!("/file?key={}&encrypted=true", key);
+        let (status, body) = server.upload_to(&path, "secret.png", sealed.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        let file_id = file["id"].as_str().unwrap();
+
+        let path = format!("/file/large/{}?key={}", file_id, key);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, sealed);
+
+        let path = format!("/file/small/{}?key={}", file_id, key);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(info["encrypted"], true);
+        assert_eq!(info["detected_mime"], "application/octet-stream");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stalled_upload() {
+        let server = TestServer::start_with(|config| config.body_idle_seconds = 1).await;
+        let key = server.signup("owner@example.com").await;
+
+        // Send the start of a file and then nothing, while keeping the connection open.
+        let (mut sender, body) = Body::channel();
+        sender.send_data(png(8, 8)[..16].to_vec().into()).await.unwrap();
+
+        let (status, _) = server.upload_to(&format!("/file?key={}", key), "slow.png", body).await;
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        drop(sender);
+
+        let temp_files = std::fs::read_dir(&server.state.temp_path).unwrap().count();
+        assert_eq!(temp_files, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn locked_folder() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let path = format!("/file?key={}&album={}", key, album_id);
+        let (status, body) = server.upload_to(&path, "private.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        let file_id = file["id"].as_str().unwrap();
+
+        let elevate = format!("/user/auth/elevate?key={}", key);
+        let wrong = json!({ "password": "wrong" });
+        assert_eq!(server.send(Method::POST, &elevate, &wrong).await, StatusCode::UNAUTHORIZED);
+        let elevation = server.json(Method::POST, &elevate, &json!({ "password": PASSWORD })).await;
+        let token = elevation["token"].as_str().unwrap();
+
+        let locked = |query: &str| format!("/file/{}/locked?key={}{}", file_id, key, query);
+        let elevated = format!("&elevation={}", token);
+        let lock = json!({ "locked": true });
+        assert_eq!(server.send(Method::PUT, &locked(""), &lock).await, StatusCode::FORBIDDEN);
+        assert_eq!(server.send(Method::PUT, &locked(&elevated), &lock).await, StatusCode::OK);
+
+        // Locked files leave the library and their albums, and aren't listed as entries.
+        let library = format!("/library/serve/metadata?key={}", key);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 0);
+        let album_path = format!("/album/{}/serve/metadata?key={}", album_id, key);
+        assert_eq!(server.json(Method::GET, &album_path, &()).await["length"], 0);
+        let entries = server.json(Method::POST, &format!("/file/entries?key={}", key), &json!({})).await;
+        assert_eq!(entries["files"], json!([]));
+        let files = json!({ "ids": [file_id] });
+        let status = server.send(Method::POST, &format!("/album/{}/files?key={}", album_id, key), &files).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // The record, the renditions and the folder itself need the token.
+        let info = format!("/file/{}?key={}", file_id, key);
+        let served = format!("/file/large/{}?key={}", file_id, key);
+        let folder = format!("/file/locked?key={}", key);
+        for path in [&info, &served, &folder] {
+            let (status, _) = server.request(Method::GET, path, &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            let (status, _) = server.request(Method::GET, &format!("{}{}", path, elevated), &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let headers = [(ELEVATION_HEADER, token.to_string())];
+        let (status, body) = server.request(Method::GET, &info, &headers, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let info: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["locked"], true);
+        assert_eq!(info["albums"], json!([]));
+        let listed = server.json(Method::GET, &format!("{}{}", folder, elevated), &()).await;
+        assert_eq!(listed[0]["name"], "private.png");
+
+        // Tokens only count for the session that they were given to.
+        let other_session = server.login("owner@example.com").await;
+        let path = format!("/file/{}?key={}{}", file_id, other_session, elevated);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let unlock = json!({ "locked": false });
+        assert_eq!(server.send(Method::PUT, &locked(&elevated), &unlock).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 1);
+        assert_eq!(server.json(Method::GET, &album_path, &()).await["length"], 0);
+        let (status, _) = server.request(Method::GET, &served, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_leaves_out_locked() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let private_id = server.upload(&key, "a-private.png", png(8, 8)).await;
+        server.upload(&key, "b-public.png", png(8, 8)).await;
+
+        let elevate = format!("/user/auth/elevate?key={}", key);
+        let elevation = server.json(Method::POST, &elevate, &json!({ "password": PASSWORD })).await;
+        let token = elevation["token"].as_str().unwrap();
+        let path = format!("/file/{}/locked?key={}&elevation={}", private_id, key, token);
+        assert_eq!(server.send(Method::PUT, &path, &json!({ "locked": true })).await, StatusCode::OK);
+
+        // Pages are still filled up to their length with the files that are left.
+        let list = format!("/file/list?key={}", key);
+        let page = server.json(Method::POST, &list, &json!({ "length": 1 })).await;
+        assert_eq!(page["files"], json!([["b-public.png", page["files"][0][1]]]));
+        assert_eq!(page["next"], Value::Null);
+        let page = server.json(Method::POST, &list, &json!({ "prefix": "a-" })).await;
+        assert_eq!(page["files"], json!([]));
+    }
 }
@@ -0,0 +1,310 @@
+//! Upload Formats
+//!
+//! libvips loads most image formats directly. HEIC/HEIF photos need libvips to be built with
+//! libheif, camera RAW files are developed by `dcraw` (or `dcraw_emu` from LibRaw) before libvips
+//! sees them, and videos are represented by a poster frame that `video` takes out of them. These
+//! conversions only feed the renditions; the original file is always stored untouched.
+//!
+//! The MIME type that a client declares isn't trusted. The first bytes of every upload are
+//! sniffed, and the sniffed type decides both the pipeline and the `Content-Type` that the
+//! original is served with.
+
+use crate::config::{Config, Encoding, Rendition};
+use crate::edit;
+use crate::error::{ApiError, ApiResult};
+use crate::placeholder::{self, Placeholder};
+use crate::similar;
+use crate::video;
+use libvips::{ops, VipsImage};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use wire::FileEdit;
+
+/// Bytes at the start of a file that are enough to recognize it.
+pub const HEAD_LENGTH: usize = 16;
+/// Stands in for contents that couldn't be recognized.
+pub const UNKNOWN_MIME: &str = "application/octet-stream";
+
+const RAW_EXTENSIONS: &[&str] = &[
+    "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "dng", "raf", "orf", "rw2", "pef",
+    "srw",
+];
+const HEIF_EXTENSIONS: &[&str] = &["heic", "heif", "hif"];
+const HEIF_MIMES: &[&str] = &["image/heic", "image/heif", "image/avif"];
+
+/// Recognize the type of a file from its first bytes.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| head.starts_with(magic);
+
+    if starts(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if starts(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some("image/gif")
+    } else if starts(b"RIFF") && head.len() >= 12 {
+        match &head[8..12] {
+            b"WEBP" => Some("image/webp"),
+            b"AVI " => Some("video/x-msvideo"),
+            _ => None,
+        }
+    } else if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        // ISO media files say what they hold with the brand of their `ftyp` box.
+        match &head[8..12] {
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" => Some("image/heic"),
+            b"mif1" | b"msf1" => Some("image/heif"),
+            b"avif" | b"avis" => Some("image/avif"),
+            b"crx " => Some("image/x-canon-cr3"),
+            b"qt  " => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        }
+    } else if starts(b"\x1a\x45\xdf\xa3") {
+        Some("video/webm")
+    } else if starts(b"FUJIFILMCCD-RAW") {
+        Some("image/x-fuji-raf")
+    } else if starts(b"IIRO") || starts(b"IIRS") {
+        Some("image/x-olympus-orf")
+    } else if starts(b"IIU\0") {
+        Some("image/x-panasonic-rw2")
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        if head.len() >= 10 && &head[8..10] == b"CR" {
+            Some("image/x-canon-cr2")
+        } else {
+            Some("image/tiff")
+        }
+    } else if starts(b"BM") {
+        Some("image/bmp")
+    } else {
+        None
+    }
+}
+
+/// Pick the MIME type to trust for an upload that declared `declared` and was sniffed as
+/// `sniffed`. Claiming to be an image when the contents are a video, or the other way around,
+/// is rejected.
+pub fn check_mime<'a>(declared: &'a str, sniffed: Option<&'static str>) -> ApiResult<&'a str> {
+    let sniffed = match sniffed {
+        Some(sniffed) => sniffed,
+        None => return Ok(UNKNOWN_MIME),
+    };
+
+    let kind = |mime: &'a str| mime.split('/').next().unwrap_or("");
+    let declared_kind = kind(declared);
+
+    if (declared_kind == "image" || declared_kind == "video") && declared_kind != kind(sniffed) {
+        return Err(ApiError::BadRequest);
+    }
+
+    // Most RAW formats are TIFF files underneath, so a more specific image type is kept.
+    if sniffed == "image/tiff" && declared_kind == "image" {
+        return Ok(declared);
+    }
+
+    Ok(sniffed)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Image,
+    Heif,
+    Raw,
+    Video,
+}
+
+impl Format {
+    /// Work out how to decode an upload from its checked MIME type and its name.
+    pub fn detect(mime: &str, name: &str) -> Format {
+        let extension = name
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_lowercase())
+            .unwrap_or_default();
+
+        if HEIF_MIMES.contains(&mime) {
+            Format::Heif
+        } else if mime.starts_with("video/") {
+            Format::Video
+        } else if HEIF_EXTENSIONS.contains(&extension.as_str()) {
+            Format::Heif
+        } else if RAW_EXTENSIONS.contains(&extension.as_str()) {
+            Format::Raw
+        } else {
+            Format::Image
+        }
+    }
+}
+
+/// How uploads are decoded, which the configuration decides.
+#[derive(Clone, Copy, Debug)]
+pub struct Decoding {
+    /// Images of more pixels are turned away before they are decoded.
+    pub max_pixels: u64,
+    /// How far into a video its poster is taken from, as a fraction of its length.
+    pub poster_position: f64,
+}
+
+impl Decoding {
+    pub fn new(config: &Config) -> Self {
+        Decoding {
+            max_pixels: config.max_image_pixels,
+            poster_position: config.poster_position,
+        }
+    }
+}
+
+/// Load the image that the renditions of the file at `path` are made from. Intermediate files
+/// are written to `scratch`, which the caller removes.
+pub fn load(format: Format, path: &Path, scratch: &Path, decoding: Decoding) -> ApiResult<VipsImage> {
+    let path_str = path.to_str().unwrap();
+    let scratch_str = scratch.to_str().unwrap();
+
+    let image = match format {
+        Format::Image => VipsImage::new_from_file(path_str),
+        // Fails when libvips was built without libheif.
+        Format::Heif => ops::heifload(path_str),
+        Format::Raw => {
+            develop(path, scratch)?;
+            VipsImage::new_from_file(scratch_str)
+        }
+        Format::Video => {
+            video::poster(path, scratch, decoding.poster_position)?;
+            VipsImage::new_from_file(scratch_str)
+        }
+    };
+
+    image.map_err(|_| ApiError::UnsupportedFormat)
+}
+
+/// Decode the file at `path` and write every rendition to the matching entry of `paths`,
+/// returning the dimensions of the upright original, with `edit` applied, its placeholder and its
+/// perceptual hash.
+pub fn render(
+    format: Format,
+    path: &Path,
+    scratch: &Path,
+    renditions: &[Rendition],
+    paths: &[PathBuf],
+    decoding: Decoding,
+    edit: &FileEdit,
+) -> ApiResult<(i32, i32, Placeholder, u64)> {
+    let original = load(format, path, scratch, decoding)?;
+    // Loading only reads the header, so the size is known before any pixels are.
+    if original.get_width() as u64 * original.get_height() as u64 > decoding.max_pixels {
+        return Err(ApiError::PayloadTooLarge);
+    }
+    let rotated = edit::apply(ops::autorot(&original)?, edit, path)?;
+
+    let height = rotated.get_height();
+    let width = rotated.get_width();
+
+    // Renditions go from tallest to shortest, so each one is scaled down from the last.
+    let mut source = rotated;
+    for (rendition, path) in renditions.iter().zip(paths.iter()) {
+        let factor = rendition.height as f64 / source.get_height() as f64;
+        let resized = ops::resize(&source, factor)?;
+        save(&resized, rendition, path)?;
+        source = resized;
+    }
+
+    // The smallest rendition is plenty for a placeholder, and for a hash.
+    let placeholder = placeholder::compute(&source)?;
+    let phash = similar::compute(&source)?;
+
+    Ok((width, height, placeholder, phash))
+}
+
+/// Encode `image` as `rendition` at `path`. Renditions are upright already, so they are saved
+/// without the metadata of the original, like where it was taken, which anyone who can see the
+/// file would otherwise get along with every thumbnail.
+pub fn save(image: &VipsImage, rendition: &Rendition, path: &Path) -> ApiResult<()> {
+    let path = path.to_str().unwrap();
+
+    match rendition.encoding {
+        Encoding::Webp => {
+            let options = ops::WebpsaveOptions {
+                q: rendition.quality,
+                strip: true,
+                ..ops::WebpsaveOptions::default()
+            };
+            ops::webpsave_with_opts(image, path, &options)?;
+        }
+        Encoding::Avif => {
+            let options = ops::HeifsaveOptions {
+                q: rendition.quality,
+                compression: ops::ForeignHeifCompression::Av1,
+                strip: true,
+                ..ops::HeifsaveOptions::default()
+            };
+            ops::heifsave_with_opts(image, path, &options)?;
+        }
+        Encoding::Jpeg => {
+            let options = ops::JpegsaveOptions {
+                q: rendition.quality,
+                strip: true,
+                ..ops::JpegsaveOptions::default()
+            };
+            ops::jpegsave_with_opts(image, path, &options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Develop a RAW file into a TIFF at `output`.
+fn develop(path: &Path, output: &Path) -> ApiResult<()> {
+    let developers: [(&str, &[&str]); 2] = [
+        // Camera white balance, TIFF output, written to stdout.
+        ("dcraw", &["-w", "-T", "-c"]),
+        ("dcraw_emu", &["-w", "-T", "-Z", "-"]),
+    ];
+
+    for (program, args) in developers.iter() {
+        let developed = Command::new(program).args(args.iter()).arg(path).output();
+
+        match developed {
+            Ok(developed) if developed.status.success() && !developed.stdout.is_empty() => {
+                std::fs::write(output, developed.stdout)?;
+                return Ok(());
+            }
+            // Not installed, or it couldn't read this camera's files.
+            _ => continue,
+        }
+    }
+
+    Err(ApiError::UnsupportedFormat)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_formats() {
+        assert_eq!(Format::detect("image/heic", "IMG_0001"), Format::Heif);
+        assert_eq!(Format::detect(UNKNOWN_MIME, "IMG_0001.HEIC"), Format::Heif);
+        assert_eq!(Format::detect("video/mp4", "clip.mp4"), Format::Video);
+        assert_eq!(Format::detect("image/x-nikon-nef", "DSC_0001.NEF"), Format::Raw);
+        assert_eq!(Format::detect("image/jpeg", "photo.jpg"), Format::Image);
+    }
+
+    #[test]
+    fn sniff_and_check() {
+        let iphone = b"\0\0\0\x18ftypheic\0\0\0\0";
+        assert_eq!(sniff(iphone), Some("image/heic"));
+
+        let mp4 = b"\0\0\0\x18ftypmp42\0\0\0\0";
+        assert_eq!(sniff(mp4), Some("video/mp4"));
+        assert_eq!(sniff(b"II*\0\x10\0\0\0CR\x02\0"), Some("image/x-canon-cr2"));
+
+        // A video can't sneak into the image pipeline, or the other way around.
+        assert!(check_mime("image/jpeg", sniff(mp4)).is_err());
+        assert!(check_mime("video/mp4", sniff(b"\xff\xd8\xff\xe0")).is_err());
+
+        // Mislabelled images are corrected.
+        assert_eq!(check_mime("image/png", sniff(b"\xff\xd8\xff\xe0")).unwrap(), "image/jpeg");
+        assert_eq!(check_mime(UNKNOWN_MIME, sniff(iphone)).unwrap(), "image/heic");
+
+        let nef = b"MM\0*\0\0\0\x08";
+        assert_eq!(check_mime("image/x-nikon-nef", sniff(nef)).unwrap(), "image/x-nikon-nef");
+        assert_eq!(check_mime("video/mp4", sniff(b"what is this")).unwrap(), UNKNOWN_MIME);
+    }
+}
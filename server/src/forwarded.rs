@@ -0,0 +1,158 @@
+//! Client Addresses
+//!
+//! Behind a reverse proxy every connection comes from the proxy, so the address of the client has
+//! to be read from the `Forwarded` or `X-Forwarded-For` header that the proxy adds. Those headers
+//! can be sent by anyone, so they are only believed when the connection comes from one of the
+//! `trusted_proxies`. The hops are read from the nearest one back, and the first that isn't a
+//! trusted proxy is the client, which keeps clients from choosing their own address by sending the
+//! header themselves. `Forwarded` is preferred when both are there.
+//!
+//! The address is kept with the request as a `ClientAddr`, where rate limits, the login audit and
+//! the `admin_networks` check find it, and recorded on the request span.
+
+use crate::{
+    common::AppState,
+    error::{ApiError, ApiResult},
+};
+use hyper::header::{HeaderMap, HeaderName, FORWARDED};
+use hyper::http::request::Parts;
+use hyper::{Body, Request};
+use ipnet::IpNet;
+use routerify::{ext::RequestExt, Middleware};
+use std::net::{IpAddr, SocketAddr};
+use tracing::Span;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The address that a request came from, after any trusted proxies.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub IpAddr);
+
+/// The address that `parts` came from.
+pub fn client_ip(parts: &Parts) -> IpAddr {
+    match parts.extensions.get::<ClientAddr>() {
+        Some(ClientAddr(ip)) => *ip,
+        None => parts.remote_addr().ip(),
+    }
+}
+
+/// Whether `ip` is in any of `networks`.
+pub fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(&ip))
+}
+
+/// Parse an address from a hop of either header, which may be quoted, carry a port, or put an
+/// IPv6 address in brackets. Obfuscated identifiers like `unknown` give `None`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // A bracketed IPv6 address without a port.
+    hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+/// The hops that the headers list, from the client to the nearest proxy. Each header may be sent
+/// several times, in which case the values are read in order.
+fn hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers.get_all(FORWARDED).iter().filter_map(|value| value.to_str().ok()).collect();
+
+    if !forwarded.is_empty() {
+        forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("for").then(|| parse_hop(value))
+                })
+            })
+            .collect()
+    } else {
+        headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(parse_hop)
+            .collect()
+    }
+}
+
+/// Find the client behind the proxies in `trusted` that a connection from `remote` went through.
+pub fn resolve(remote: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let mut client = remote;
+
+    for hop in hops(headers).into_iter().rev() {
+        if !contains(trusted, client) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = ip,
+            // Nothing more can be said about a hop that hides its address, so the proxy in front
+            // of it is the best guess.
+            None => break,
+        }
+    }
+
+    client
+}
+
+async fn record(mut req: Request<Body>) -> ApiResult<Request<Body>> {
+    let state = req.data::<AppState>().unwrap();
+    let ip = resolve(req.remote_addr().ip(), req.headers(), &state.config.trusted_proxies);
+
+    Span::current().record("client_addr", tracing::field::display(ip));
+    req.extensions_mut().insert(ClientAddr(ip));
+
+    Ok(req)
+}
+
+/// Work out the address of the client of every request.
+pub fn resolver() -> Middleware<Body, ApiError> {
+    Middleware::pre(record)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn headers(name: HeaderName, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn trusted_hops() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()];
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let stranger: IpAddr = "198.51.100.7".parse().unwrap();
+
+        let forwarded_for = headers(X_FORWARDED_FOR, &["203.0.113.9, 10.0.0.2"]);
+        assert_eq!(resolve(proxy, &forwarded_for, &trusted), "203.0.113.9".parse::<IpAddr>().unwrap());
+        // Anyone else could have made the header up.
+        assert_eq!(resolve(stranger, &forwarded_for, &trusted), stranger);
+
+        // Hops that the client sent itself are ignored.
+        let spoofed = headers(X_FORWARDED_FOR, &["127.0.0.1", "203.0.113.9"]);
+        assert_eq!(resolve(proxy, &spoofed, &trusted), "203.0.113.9".parse::<IpAddr>().unwrap());
+
+        let forwarded = headers(
+            FORWARDED,
+            &[r#"for="[2001:db8::1]:4711";proto=https, for=unknown"#, "For=[::1]"],
+        );
+        assert_eq!(resolve(proxy, &forwarded, &trusted), "::1".parse::<IpAddr>().unwrap());
+        let forwarded = headers(FORWARDED, &[r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.3:80"#]);
+        assert_eq!(resolve(proxy, &forwarded, &trusted), "2001:db8::1".parse::<IpAddr>().unwrap());
+
+        assert_eq!(resolve(proxy, &HeaderMap::new(), &trusted), proxy);
+    }
+}
@@ -0,0 +1,432 @@
+//! Consistency Checks
+//!
+//! Most relations are recorded twice: a file is found by its name through `file_names`, the
+//! albums of a file are kept in `inclusions` as well as in the fragments of each album, a share
+//! is kept in both `user_to_album` and `album_to_user`, and the contents of a file are in storage.
+//! Each pair is changed in one transaction, but older versions of the server, crashes around
+//! storage and bugs can still leave them apart. `POST /admin/fsck` walks every pair and returns a
+//! `Finding` for each entry that is out of place.
+//!
+//! With `?repair=true`, whatever can be put right is. Every repair looks at its entries again in
+//! a transaction of its own, so that changes made while the check was running aren't undone.
+//! Files that an album holds without a record and objects missing from storage are only
+//! reported, since there is nothing left to repair them from. Orphaned objects are found the way
+//! that `clean::clean_files` finds them.
+
+use crate::{
+    admin::require_admin,
+    album::engine::Engine,
+    common::{respond_ok, AppState, File},
+    error::{ApiError, ApiResult},
+    clean, storage, trash,
+};
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use sled::Transactional;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use tokio::task::block_in_place;
+use tracing::info;
+use wire::{Album, Finding, Inconsistency};
+
+fn finding(kind: Inconsistency, key: &[u8], repaired: bool) -> Finding<'static> {
+    Finding {
+        kind,
+        key: Cow::Owned(String::from_utf8_lossy(key).into_owned()),
+        repaired,
+    }
+}
+
+/// Whether `file_bytes` is a file that is called by `name_key`.
+fn is_named(name_key: &[u8], file_bytes: Option<&[u8]>) -> bool {
+    file_bytes.is_some_and(|file_bytes| {
+        let file: File = bincode::deserialize(file_bytes).unwrap();
+        [file.owner_id, ".", &file.metadata.name].concat().as_bytes() == name_key
+    })
+}
+
+fn check_file_names(state: &AppState, repair: bool, findings: &mut Vec<Finding<'static>>) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref file_names,
+        ..
+    } = state;
+
+    for entry in file_names.iter() {
+        let (name_key, file_id) = entry?;
+        if is_named(&name_key, files.get(&file_id)?.as_deref()) {
+            continue;
+        }
+
+        let repaired = repair
+            && (files, file_names).transaction(|(files, file_names)| {
+                if file_names.get(&name_key)?.as_deref() != Some(&file_id[..])
+                    || is_named(&name_key, files.get(&file_id)?.as_deref())
+                {
+                    return Ok(false);
+                }
+
+                file_names.remove(name_key.clone())?;
+                Ok(true)
+            })?;
+        findings.push(finding(Inconsistency::DanglingFileName, &name_key, repaired));
+    }
+
+    for entry in files.iter() {
+        let (file_id, file_bytes) = entry?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+        let name_key = [file.owner_id, ".", &file.metadata.name].concat();
+        if file_names.get(&name_key)?.as_deref() == Some(&file_id[..]) {
+            continue;
+        }
+
+        // The name can only be given back while no other file has taken it.
+        let repaired = repair
+            && (files, file_names).transaction(|(files, file_names)| {
+                let file_bytes = match files.get(&file_id)? {
+                    Some(file_bytes) => file_bytes,
+                    None => return Ok(false),
+                };
+                let file: File = bincode::deserialize(&file_bytes).unwrap();
+                let name_key = [file.owner_id, ".", &file.metadata.name].concat();
+                if file_names.get(name_key.as_bytes())?.is_some() {
+                    return Ok(false);
+                }
+
+                file_names.insert(name_key.as_bytes(), file_id.clone())?;
+                Ok(true)
+            })?;
+        findings.push(finding(Inconsistency::MissingFileName, name_key.as_bytes(), repaired));
+    }
+
+    Ok(())
+}
+
+/// Compare the files that an album holds with its inclusions, given the ids of the files that
+/// were included in it when the check started.
+fn check_album(
+    state: &AppState,
+    album_id: &str,
+    included: &[String],
+    repair: bool,
+) -> ApiResult<Vec<Finding<'static>>> {
+    let AppState {
+        ref albums,
+        ref fragments,
+        ref files,
+        ref inclusions,
+        ..
+    } = state;
+
+    let findings = (albums, fragments, files, inclusions).transaction(|(albums, fragments, files, inclusions)| {
+        let album_bytes = match albums.get(album_id)? {
+            Some(album_bytes) => album_bytes,
+            None => return Ok(vec![]),
+        };
+        let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        let e = Engine::new(album_id, &mut album, fragments)?;
+        let held = e.list_file_ids().collect::<Result<HashSet<_>, _>>()?;
+
+        let mut findings = vec![];
+        for file_id in &held {
+            let inclusion = [file_id, ".", album_id].concat();
+            if files.get(file_id.as_bytes())?.is_none() {
+                findings.push(finding(Inconsistency::MissingFile, inclusion.as_bytes(), false));
+            } else if inclusions.get(inclusion.as_bytes())?.is_none() {
+                if repair {
+                    inclusions.insert(inclusion.as_bytes(), b"")?;
+                }
+                findings.push(finding(Inconsistency::MissingInclusion, inclusion.as_bytes(), repair));
+            }
+        }
+
+        for file_id in included.iter().filter(|file_id| !held.contains(*file_id)) {
+            let inclusion = [file_id, ".", album_id].concat();
+            if inclusions.get(inclusion.as_bytes())?.is_some() {
+                if repair {
+                    inclusions.remove(inclusion.as_bytes())?;
+                }
+                findings.push(finding(Inconsistency::UnlistedInclusion, inclusion.as_bytes(), repair));
+            }
+        }
+
+        Ok(findings)
+    })?;
+
+    Ok(findings)
+}
+
+fn check_inclusions(state: &AppState, repair: bool, findings: &mut Vec<Finding<'static>>) -> ApiResult<()> {
+    let AppState {
+        ref albums,
+        ref files,
+        ref inclusions,
+        ..
+    } = state;
+
+    let mut included: HashMap<String, Vec<String>> = HashMap::new();
+    for album_id in albums.iter().keys() {
+        included.insert(String::from_utf8(album_id?.to_vec()).unwrap(), vec![]);
+    }
+
+    let mut dangling = vec![];
+    for inclusion in inclusions.iter().keys() {
+        let inclusion = inclusion?;
+        let (file_id, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+
+        match included.get_mut(album_id) {
+            Some(file_ids) => file_ids.push(file_id.to_string()),
+            None => dangling.push(inclusion.clone()),
+        }
+    }
+
+    for (album_id, file_ids) in &included {
+        findings.extend(check_album(state, album_id, file_ids, repair)?);
+    }
+
+    // Trashed albums keep their inclusions. Albums that were trashed while the inclusions were
+    // being read are in the trash by now, unless they were restored or purged since.
+    let trashed = trash::album_ids(state)?;
+    for inclusion in dangling {
+        let (file_id, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+        if trashed.contains(album_id) && files.get(file_id)?.is_some() {
+            continue;
+        }
+
+        let repaired = repair
+            && (albums, files, inclusions).transaction(|(albums, files, inclusions)| {
+                if albums.get(album_id)?.is_some() && files.get(file_id)?.is_some() {
+                    return Ok(false);
+                }
+
+                Ok(inclusions.remove(inclusion.clone())?.is_some())
+            })?;
+        findings.push(finding(Inconsistency::DanglingInclusion, &inclusion, repaired));
+    }
+
+    Ok(())
+}
+
+fn check_shares(state: &AppState, repair: bool, findings: &mut Vec<Finding<'static>>) -> ApiResult<()> {
+    let AppState {
+        ref users,
+        ref albums,
+        ref user_to_album,
+        ref album_to_user,
+        ..
+    } = state;
+
+    for share in user_to_album.iter().keys() {
+        let share = share?;
+        let (user_id, album_id) = std::str::from_utf8(&share).unwrap().split_once('.').unwrap();
+        let mirror = [album_id, ".", user_id].concat();
+
+        let kind = if users.get(user_id)?.is_none() || albums.get(album_id)?.is_none() {
+            Inconsistency::DanglingShare
+        } else if album_to_user.get(mirror.as_bytes())?.is_none() {
+            Inconsistency::OneSidedShare
+        } else {
+            continue;
+        };
+
+        let trees = (users, albums, user_to_album, album_to_user);
+        let repaired = repair
+            && trees.transaction(|(users, albums, user_to_album, album_to_user)| {
+                if user_to_album.get(&share)?.is_none() {
+                    return Ok(false);
+                }
+
+                if users.get(user_id)?.is_none() || albums.get(album_id)?.is_none() {
+                    user_to_album.remove(share.clone())?;
+                    album_to_user.remove(mirror.as_bytes())?;
+                } else {
+                    album_to_user.insert(mirror.as_bytes(), b"")?;
+                }
+
+                Ok(true)
+            })?;
+        findings.push(finding(kind, &share, repaired));
+    }
+
+    // Without the other side there is no role to give the member, so these are only removed.
+    for mirror in album_to_user.iter().keys() {
+        let mirror = mirror?;
+        let (album_id, user_id) = std::str::from_utf8(&mirror).unwrap().split_once('.').unwrap();
+        let share = [user_id, ".", album_id].concat();
+        if user_to_album.get(share.as_bytes())?.is_some() {
+            continue;
+        }
+
+        let repaired = repair
+            && (user_to_album, album_to_user).transaction(|(user_to_album, album_to_user)| {
+                if user_to_album.get(share.as_bytes())?.is_some() {
+                    return Ok(false);
+                }
+
+                Ok(album_to_user.remove(mirror.clone())?.is_some())
+            })?;
+        findings.push(finding(Inconsistency::OneSidedShare, &mirror, repaired));
+    }
+
+    Ok(())
+}
+
+async fn check_storage(state: &AppState, repair: bool, findings: &mut Vec<Finding<'static>>) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref storage,
+        ref config,
+        ..
+    } = state;
+
+    // AVIF copies are only made when they are first requested, so they can't be missing.
+    let mut required = vec![storage::ORIGINAL.to_string()];
+    required.extend(config.renditions.iter().map(|rendition| rendition.name.clone()));
+
+    for kind in storage::kinds(config) {
+        // Files are listed before their objects, which uploads store before writing the record.
+        let mut expected = vec![];
+        if required.contains(&kind) {
+            block_in_place(|| -> ApiResult<()> {
+                // Encrypted files only have their original.
+                let rendition = kind != storage::ORIGINAL;
+
+                for entry in files.iter() {
+                    let (file_id, file_bytes) = entry?;
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+                    if !(rendition && file.encrypted) {
+                        expected.push(String::from_utf8(file_id.to_vec()).unwrap());
+                    }
+                }
+
+                let mut trashed = trash::file_ids(state)?;
+                if rendition {
+                    let encrypted = trash::encrypted_file_ids(state)?;
+                    trashed.retain(|file_id| !encrypted.contains(file_id));
+                }
+                expected.extend(trashed);
+                Ok(())
+            })?;
+        }
+
+        let stored = clean::stored_ids(state, &kind).await?;
+
+        let trashed = block_in_place(|| trash::file_ids(state))?;
+        let mut orphans = vec![];
+        for file_id in &stored {
+            if block_in_place(|| clean::is_orphan(state, &trashed, file_id))? {
+                orphans.push(file_id);
+            }
+        }
+
+        // A file may have been moved to the trash since, so everything is looked at again.
+        let trashed = block_in_place(|| trash::file_ids(state))?;
+        for file_id in orphans {
+            let key = storage::key(&kind, file_id);
+            let repaired = repair
+                && block_in_place(|| clean::is_orphan(state, &trashed, file_id))?
+                && storage.delete(&key).await.is_ok();
+            findings.push(finding(Inconsistency::OrphanObject, key.as_bytes(), repaired));
+        }
+
+        // Files that were deleted since they were listed are in neither place anymore.
+        let stored: HashSet<&String> = stored.iter().collect();
+        for file_id in expected.iter().filter(|file_id| !stored.contains(file_id)) {
+            if files.get(file_id.as_bytes())?.is_some() || trashed.contains(file_id) {
+                let key = storage::key(&kind, file_id);
+                findings.push(finding(Inconsistency::MissingObject, key.as_bytes(), false));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check every relation, repairing what can be repaired when `repair` is set.
+pub async fn run(state: &AppState, repair: bool) -> ApiResult<Vec<Finding<'static>>> {
+    let mut findings = vec![];
+
+    block_in_place(|| -> ApiResult<()> {
+        check_file_names(state, repair, &mut findings)?;
+        check_inclusions(state, repair, &mut findings)?;
+        check_shares(state, repair, &mut findings)
+    })?;
+    check_storage(state, repair, &mut findings).await?;
+
+    let repaired = findings.iter().filter(|finding| finding.repaired).count();
+    info!("Found {} inconsistencies and repaired {}", findings.len(), repaired);
+
+    Ok(findings)
+}
+
+pub async fn fsck(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let repair = req
+        .query("repair")
+        .map(|s| s.parse::<bool>().ok())
+        .unwrap_or(Some(false))
+        .ok_or(ApiError::BadRequest)?;
+
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(run(state, repair).await?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fsck() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let admin_key = server.signup_admin().await;
+
+        let file_id = server.upload(&key, "photo.png", png(8, 8)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files = json!({ "ids": [file_id] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, key), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let path = format!("/admin/fsck?key={}", admin_key);
+        assert_eq!(server.json(Method::POST, &path, &()).await, json!([]));
+
+        let state = &server.state;
+        let (user_id, _) = key.split_once('.').unwrap();
+        state.file_names.insert("nobody.ghost.png", b"ghost".to_vec()).unwrap();
+        state.inclusions.remove([file_id.as_str(), ".", album_id].concat()).unwrap();
+        state.album_to_user.remove([album_id, ".", user_id].concat()).unwrap();
+
+        let findings = server.json(Method::POST, &path, &()).await;
+        let mut kinds: Vec<_> = findings
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|finding| {
+                assert_eq!(finding["repaired"], false);
+                finding["kind"].as_str().unwrap().to_string()
+            })
+            .collect();
+        kinds.sort();
+        assert_eq!(kinds, ["dangling_file_name", "missing_inclusion", "one_sided_share"]);
+
+        let repaired = server.json(Method::POST, &format!("{}&repair=true", path), &()).await;
+        assert!(repaired.as_array().unwrap().iter().all(|finding| finding["repaired"] == true));
+        assert_eq!(server.json(Method::POST, &path, &()).await, json!([]));
+
+        // Only administrators may check.
+        let status = server.send(Method::POST, &format!("/admin/fsck?key={}", key), &()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
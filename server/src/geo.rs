@@ -0,0 +1,434 @@
+//! Photo Locations
+//!
+//! The GPS position in the EXIF data of an upload is stored on the `File` and indexed in the
+//! `geo` tree under `<owner_id>.<geohash>.<file_id>`. Geohashes of nearby points share a prefix,
+//! so the files in a bounding box are found by scanning the few prefixes that cover it.
+//!
+//! Points are grouped into clusters by a shorter geohash before they are returned, so that a map
+//! of a dense area doesn't need one entry per photo. The geohash length is picked from the size of
+//! the area, so zooming in splits clusters up. Locked files stay indexed, but are left out of
+//! searches.
+
+use crate::{
+    album::engine::Engine,
+    common::{require_key, respond_ok, searchable, test_logged_in, AppState, File},
+    error::{ApiError, ApiResult},
+};
+use exif::{In, Reader, Tag, Value};
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use sled::Transactional;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::Path;
+use tokio::task::block_in_place;
+use wire::{Album, GeoCluster, Location};
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+/// Length of the indexed geohashes, which is precise to a few centimeters.
+const GEOHASH_LENGTH: usize = 12;
+/// Most prefixes that are scanned to answer a bounding box query.
+const MAX_COVER_CELLS: usize = 16;
+/// Roughly the most clusters that are returned.
+const MAX_CLUSTERS: usize = 256;
+
+/// Area on a map, in degrees. Areas that cross the antimeridian aren't supported.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl BBox {
+    const WORLD: BBox = BBox {
+        west: -180.0,
+        south: -90.0,
+        east: 180.0,
+        north: 90.0,
+    };
+
+    /// Parse `west,south,east,north`.
+    pub fn parse(value: &str) -> ApiResult<BBox> {
+        let values = value
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ApiError::BadRequest)?;
+
+        let bbox = match values.as_slice() {
+            [west, south, east, north] => BBox {
+                west: *west,
+                south: *south,
+                east: *east,
+                north: *north,
+            },
+            _ => return Err(ApiError::BadRequest),
+        };
+
+        let valid = (-180.0..=180.0).contains(&bbox.west)
+            && (-180.0..=180.0).contains(&bbox.east)
+            && (-90.0..=90.0).contains(&bbox.south)
+            && (-90.0..=90.0).contains(&bbox.north)
+            && bbox.west <= bbox.east
+            && bbox.south <= bbox.north;
+
+        if !valid {
+            return Err(ApiError::BadRequest);
+        }
+
+        Ok(bbox)
+    }
+
+    fn contains(&self, location: Location) -> bool {
+        (self.west..=self.east).contains(&location.longitude)
+            && (self.south..=self.north).contains(&location.latitude)
+    }
+
+    /// Smallest area around every location.
+    fn around(locations: &[(String, Location)]) -> BBox {
+        locations.iter().fold(
+            BBox {
+                west: 180.0,
+                south: 90.0,
+                east: -180.0,
+                north: -90.0,
+            },
+            |bbox, (_, location)| BBox {
+                west: bbox.west.min(location.longitude),
+                south: bbox.south.min(location.latitude),
+                east: bbox.east.max(location.longitude),
+                north: bbox.north.max(location.latitude),
+            },
+        )
+    }
+}
+
+/// Read the GPS position out of the EXIF data of the file at `path`.
+pub fn read_location(path: &Path) -> Option<Location> {
+    let file = std::fs::File::open(path).ok()?;
+    let exif = Reader::new().read_from_container(&mut BufReader::new(file)).ok()?;
+
+    let coordinate = |tag, reference_tag, negative: &[u8]| -> Option<f64> {
+        let degrees = match exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(ref parts) if parts.len() >= 3 => {
+                parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+
+        match exif.get_field(reference_tag, In::PRIMARY)?.value {
+            Value::Ascii(ref values) if values.first().map(Vec::as_slice) == Some(negative) => Some(-degrees),
+            _ => Some(degrees),
+        }
+    };
+
+    let location = Location {
+        latitude: coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b"S")?,
+        longitude: coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b"W")?,
+    };
+
+    if BBox::WORLD.contains(location) && location.latitude.is_finite() && location.longitude.is_finite() {
+        Some(location)
+    } else {
+        None
+    }
+}
+
+/// Geohash of `location` with `length` characters.
+pub fn encode(location: Location, length: usize) -> String {
+    let mut bbox = BBox::WORLD;
+    let mut hash = String::with_capacity(length);
+    let mut value = 0;
+
+    // Bits alternate between longitude and latitude, starting with longitude.
+    for bit in 0..5 * length {
+        value <<= 1;
+
+        if bit % 2 == 0 {
+            let middle = (bbox.west + bbox.east) / 2.0;
+            if location.longitude >= middle {
+                value |= 1;
+                bbox.west = middle;
+            } else {
+                bbox.east = middle;
+            }
+        } else {
+            let middle = (bbox.south + bbox.north) / 2.0;
+            if location.latitude >= middle {
+                value |= 1;
+                bbox.south = middle;
+            } else {
+                bbox.north = middle;
+            }
+        }
+
+        if bit % 5 == 4 {
+            hash.push(BASE32[value] as char);
+            value = 0;
+        }
+    }
+
+    hash
+}
+
+/// Area of the cell that `hash` names.
+pub fn decode(hash: &str) -> Option<BBox> {
+    let mut bbox = BBox::WORLD;
+    let mut bit = 0;
+
+    for c in hash.bytes() {
+        let value = BASE32.iter().position(|&b| b == c)?;
+
+        for shift in (0..5).rev() {
+            let high = value >> shift & 1 == 1;
+
+            if bit % 2 == 0 {
+                let middle = (bbox.west + bbox.east) / 2.0;
+                if high {
+                    bbox.west = middle;
+                } else {
+                    bbox.east = middle;
+                }
+            } else {
+                let middle = (bbox.south + bbox.north) / 2.0;
+                if high {
+                    bbox.south = middle;
+                } else {
+                    bbox.north = middle;
+                }
+            }
+
+            bit += 1;
+        }
+    }
+
+    Some(bbox)
+}
+
+/// Width and height in degrees of the cells of geohashes with `length` characters.
+fn cell_size(length: usize) -> (f64, f64) {
+    let bits = 5 * length as i32;
+    let longitude_bits = (bits + 1) / 2;
+    let latitude_bits = bits / 2;
+
+    (360.0 / 2f64.powi(longitude_bits), 180.0 / 2f64.powi(latitude_bits))
+}
+
+/// Columns and rows of geohash cells of `length` characters that overlap `bbox`.
+fn cell_span(bbox: &BBox, length: usize) -> (i64, i64, i64, i64) {
+    let (width, height) = cell_size(length);
+    let columns = (360.0 / width) as i64;
+    let rows = (180.0 / height) as i64;
+
+    let column = |longitude: f64| (((longitude + 180.0) / width) as i64).min(columns - 1);
+    let row = |latitude: f64| (((latitude + 90.0) / height) as i64).min(rows - 1);
+
+    (column(bbox.west), column(bbox.east), row(bbox.south), row(bbox.north))
+}
+
+/// Longest geohash length whose cells cover `bbox` with at most `max_cells` of them.
+fn precision(bbox: &BBox, max_cells: usize) -> usize {
+    (1..=GEOHASH_LENGTH)
+        .rev()
+        .find(|&length| {
+            let (west, east, south, north) = cell_span(bbox, length);
+            ((east - west + 1) * (north - south + 1)) as usize <= max_cells
+        })
+        .unwrap_or(0)
+}
+
+/// Geohash prefixes that together cover `bbox`.
+fn covering(bbox: &BBox) -> Vec<String> {
+    let length = precision(bbox, MAX_COVER_CELLS);
+    if length == 0 {
+        return vec![String::new()];
+    }
+
+    let (width, height) = cell_size(length);
+    let (west, east, south, north) = cell_span(bbox, length);
+
+    let mut prefixes = vec![];
+    for row in south..=north {
+        for column in west..=east {
+            let center = Location {
+                latitude: (row as f64 + 0.5) * height - 90.0,
+                longitude: (column as f64 + 0.5) * width - 180.0,
+            };
+            prefixes.push(encode(center, length));
+        }
+    }
+
+    prefixes
+}
+
+/// Group `locations` by geohash cells sized so that about `MAX_CLUSTERS` of them span `bbox`.
+fn cluster(locations: Vec<(String, Location)>, bbox: &BBox) -> Vec<GeoCluster<'static>> {
+    let length = precision(bbox, MAX_CLUSTERS);
+    let mut clusters: BTreeMap<String, GeoCluster> = BTreeMap::new();
+
+    for (file_id, location) in locations {
+        let cluster = clusters
+            .entry(encode(location, length))
+            .or_insert_with(|| GeoCluster {
+                latitude: 0.0,
+                longitude: 0.0,
+                count: 0,
+                file_id: Cow::Owned(file_id),
+            });
+
+        // Running mean, so the cluster sits in the middle of its files.
+        cluster.count += 1;
+        cluster.latitude += (location.latitude - cluster.latitude) / cluster.count as f64;
+        cluster.longitude += (location.longitude - cluster.longitude) / cluster.count as f64;
+    }
+
+    clusters.into_values().collect()
+}
+
+pub fn key(owner_id: &str, location: Location, file_id: &str) -> String {
+    [owner_id, ".", &encode(location, GEOHASH_LENGTH), ".", file_id].concat()
+}
+
+fn bbox_query(req: &Request<Body>) -> ApiResult<Option<BBox>> {
+    req.query("bbox").map(|bbox| BBox::parse(bbox)).transpose()
+}
+
+/// Clusters of the user's own files within `?bbox=west,south,east,north`.
+pub async fn search(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let bbox = bbox_query(&req)?.ok_or(ApiError::BadRequest)?;
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref files,
+            ref geo,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let mut locations = vec![];
+        for prefix in covering(&bbox) {
+            for entry in geo.scan_prefix([user_id, ".", &prefix].concat()) {
+                let (geo_key, _) = entry?;
+                let geo_key = std::str::from_utf8(&geo_key).unwrap();
+
+                let mut fields = geo_key.splitn(3, '.').skip(1);
+                let cell = decode(fields.next().unwrap()).unwrap();
+                let file_id = fields.next().unwrap();
+
+                let location = Location {
+                    latitude: (cell.south + cell.north) / 2.0,
+                    longitude: (cell.west + cell.east) / 2.0,
+                };
+
+                if bbox.contains(location) && searchable(files, file_id)? {
+                    locations.push((file_id.to_string(), location));
+                }
+            }
+        }
+
+        respond_ok(cluster(locations, &bbox))
+    })
+}
+
+/// Clusters of the files in an album, optionally limited to `?bbox=west,south,east,north`.
+pub async fn album(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let bbox = bbox_query(&req)?;
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+    let album_id = parts.param("albumId").unwrap();
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref albums,
+            ref fragments,
+            ref files,
+            ref user_to_album,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        user_to_album
+            .get([user_id, ".", album_id].concat())?
+            .ok_or(ApiError::Unauthorized)?;
+
+        let file_ids = (albums, fragments).transaction(|(albums, fragments)| {
+            let album_bytes = albums.get(album_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            let e = Engine::new(album_id, &mut album, fragments)?;
+            e.list_file_ids().collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let mut locations = vec![];
+        for file_id in file_ids {
+            if let Some(file_bytes) = files.get(&file_id)? {
+                let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                match file.location {
+                    Some(location) if bbox.is_none_or(|bbox| bbox.contains(location)) => {
+                        locations.push((file_id, location))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let bbox = bbox.unwrap_or_else(|| BBox::around(&locations));
+        respond_ok(cluster(locations, &bbox))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn geohash() {
+        let location = Location {
+            latitude: 57.64911,
+            longitude: 10.40744,
+        };
+        assert_eq!(encode(location, 11), "u4pruydqqvj");
+
+        let cell = decode("u4pruydqqvj").unwrap();
+        assert!(cell.contains(location));
+        assert!(cell.east - cell.west < 1e-5);
+    }
+
+    #[test]
+    fn covering_and_clusters() {
+        let bbox = BBox::parse("10.3,57.6,10.5,57.7").unwrap();
+        let prefixes = covering(&bbox);
+        assert!(prefixes.len() <= MAX_COVER_CELLS);
+        assert!(prefixes.iter().any(|prefix| "u4pruydqqvj".starts_with(prefix.as_str())));
+
+        let near = |file_id: &str, latitude| {
+            (
+                file_id.to_string(),
+                Location {
+                    latitude,
+                    longitude: 10.4,
+                },
+            )
+        };
+        let clusters = cluster(vec![near("a", 57.65), near("b", 57.65001), near("c", 57.69)], &bbox);
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters.iter().map(|cluster| cluster.count).sum::<usize>(), 3);
+        assert!(BBox::parse("10,60,11,50").is_err());
+    }
+}
@@ -0,0 +1,575 @@
+//! Contribution Links
+//!
+//! The owner of an album can make links that let guests without an account upload to it, like
+//! everyone at a wedding sending in what they took. The token of a link stands in for a key on
+//! the `/guest` routes, and what comes in through it is stored as files of the owner, so it counts
+//! towards their quota and is in their library. Each link can limit how many files and bytes it
+//! takes, which are counted in the link's record in `contribution_links` before a file is stored.
+//!
+//! Uploads through a moderated link are kept out of the album until the owner approves them, and
+//! wait in `pending_uploads` by album and file. Rejected uploads go to the owner's trash. Links
+//! stop working while the album is in the trash, and are removed along with it.
+
+use crate::{
+    album, events, trash,
+    common::{join, new_id, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, File},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+    file::{self, UploadOptions},
+};
+use chrono::offset::Utc;
+use hyper::{Body, Request, Response};
+use routerify::{ext::RequestExt, Router};
+use sled::Transactional;
+use std::borrow::Cow;
+use std::time::Duration;
+use tokio::{fs, task::block_in_place, time};
+use wire::{
+    endpoint, Album, ConflictPolicy, ContributionLink, ContributionOptions, GuestAlbum, IdList, IntoOwned,
+    PendingUpload, Role,
+};
+
+const LINK_TOKEN_BYTES: usize = 16;
+
+/// Check that `user_id` owns the album, since only the owner manages its links.
+fn test_owner(user_to_album: &sled::Tree, user_id: &str, album_id: &str) -> ApiResult<()> {
+    let role_bytes = user_to_album
+        .get([user_id, ".", album_id].concat())?
+        .ok_or(ApiError::Unauthorized)?;
+    let role: Role = bincode::deserialize(&role_bytes).unwrap();
+
+    match role {
+        Role::Owner => Ok(()),
+        _ => Err(ApiError::Unauthorized),
+    }
+}
+
+/// The owner of an album, who uploads through its links are stored for. Albums in the trash have
+/// no members, so they have no owner either.
+fn album_owner(state: &AppState, album_id: &str) -> ApiResult<String> {
+    let prefix = [album_id, "."].concat();
+    for member in state.album_to_user.scan_prefix(&prefix).keys() {
+        let member = member?;
+        let user_id = std::str::from_utf8(&member[prefix.len()..]).unwrap();
+
+        if test_owner(&state.user_to_album, user_id, album_id).is_ok() {
+            return Ok(user_id.to_string());
+        }
+    }
+
+    Err(ApiError::NotFound)
+}
+
+/// The link with `token`, which fails if it doesn't exist or has expired.
+fn open(links: &sled::Tree, token: &str) -> ApiResult<ContributionLink<'static>> {
+    let link_bytes = links.get(token)?.ok_or(ApiError::Unauthorized)?;
+    let link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+
+    if link.expires.is_some_and(|expires| expires < Utc::now().timestamp()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(link.into_owned())
+}
+
+/// Count a file of `size` bytes towards the limits of a link, failing with `QuotaExceeded` if it
+/// would go over them.
+fn reserve(links: &sled::Tree, token: &str, size: u64) -> ApiResult<()> {
+    links.transaction(|links| {
+        let link_bytes = links.get(token)?.ok_or(ApiError::Unauthorized)?;
+        let mut link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+
+        if link.max_files.is_some_and(|max| link.files >= max)
+            || link.max_bytes.is_some_and(|max| link.bytes + size > max)
+        {
+            return Err(ApiError::QuotaExceeded.into());
+        }
+
+        link.files += 1;
+        link.bytes += size;
+        links.insert(token, bincode::serialize(&link).unwrap())?;
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+/// Give back what `reserve` counted for a file that wasn't stored after all.
+fn release(links: &sled::Tree, token: &str, size: u64) -> ApiResult<()> {
+    links.transaction(|links| {
+        // The link may have been deleted in the meantime.
+        if let Some(link_bytes) = links.get(token)? {
+            let mut link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+            link.files = link.files.saturating_sub(1);
+            link.bytes = link.bytes.saturating_sub(size);
+            links.insert(token, bincode::serialize(&link).unwrap())?;
+        }
+
+        Ok::<_, sled::transaction::ConflictableTransactionError<ApiError>>(())
+    })?;
+
+    Ok(())
+}
+
+/// Remove the links and pending uploads of an album that is being deleted. The uploads stay with
+/// the owner.
+pub fn forget_album(state: &AppState, album_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref contribution_links,
+        ref pending_uploads,
+        ..
+    } = state;
+
+    for entry in contribution_links.iter() {
+        let (token, link_bytes) = entry?;
+        let link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+
+        if link.album_id == album_id {
+            contribution_links.remove(token)?;
+        }
+    }
+
+    for entry in pending_uploads.scan_prefix([album_id, "."].concat()).keys() {
+        pending_uploads.remove(entry?)?;
+    }
+
+    Ok(())
+}
+
+pub async fn list_links(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref contribution_links,
+            ..
+        } = parts.data().unwrap();
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        let mut links = vec![];
+        for entry in contribution_links.iter() {
+            let (_, link_bytes) = entry?;
+            let link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+
+            if link.album_id == *album_id {
+                links.push(link.into_owned());
+            }
+        }
+        links.sort_by_key(|link| link.created);
+
+        respond_ok(links)
+    })
+}
+
+pub async fn create_link(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let options: ContributionOptions = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref contribution_links,
+            ..
+        } = parts.data().unwrap();
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        let now = Utc::now().timestamp();
+        let link = ContributionLink {
+            token: Cow::from(new_id(LINK_TOKEN_BYTES)),
+            album_id: Cow::from(album_id),
+            created: now,
+            expires: options.expires_in.map(|expires_in| now + expires_in),
+            max_files: options.max_files,
+            max_bytes: options.max_bytes,
+            moderated: options.moderated,
+            files: 0,
+            bytes: 0,
+        };
+        contribution_links.insert(link.token.as_bytes(), bincode::serialize(&link).unwrap())?;
+
+        respond_ok(link)
+    })
+}
+
+pub async fn delete_link(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref contribution_links,
+            ..
+        } = parts.data().unwrap();
+
+        let album_id = parts.param("albumId").unwrap();
+        let token = parts.param("token").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        // Links of other albums look the same as links that don't exist.
+        let link_bytes = contribution_links.get(token)?.ok_or(ApiError::NotFound)?;
+        let link: ContributionLink = bincode::deserialize(&link_bytes).unwrap();
+        if link.album_id != *album_id {
+            return Err(ApiError::NotFound);
+        }
+        contribution_links.remove(token)?;
+
+        respond_ok_empty()
+    })
+}
+
+pub async fn list_pending(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref pending_uploads,
+            ..
+        } = parts.data().unwrap();
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        let mut pending = vec![];
+        for entry in pending_uploads.scan_prefix([album_id, "."].concat()).values() {
+            let upload_bytes = entry?;
+            let upload: PendingUpload = bincode::deserialize(&upload_bytes).unwrap();
+            pending.push(upload.into_owned());
+        }
+        pending.sort_by_key(|upload| upload.uploaded);
+
+        respond_ok(pending)
+    })
+}
+
+pub async fn approve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: IdList = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref files,
+            ref albums,
+            ref inclusions,
+            ref fragments,
+            ref user_to_album,
+            ref activity,
+            ref pending_uploads,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        for file_id in &json.ids {
+            let pending_key = [album_id, ".", file_id].concat();
+
+            let trees = (pending_uploads, files, albums, inclusions, fragments, user_to_album, activity);
+            let album_head = trees.transaction(
+                |(pending_uploads, files, albums, inclusions, fragments, user_to_album, activity)| {
+                    if pending_uploads.remove(pending_key.as_bytes())?.is_none() {
+                        return Ok(None);
+                    }
+
+                    // Files that were deleted or locked while they waited stay out of the album.
+                    let file_bytes = match files.get(file_id.as_bytes())? {
+                        Some(file_bytes) => file_bytes,
+                        None => return Ok(None),
+                    };
+                    let file: File = bincode::deserialize(&file_bytes).unwrap();
+                    if file.locked {
+                        return Ok(None);
+                    }
+
+                    let album_trees = (albums, inclusions, fragments, user_to_album, activity);
+                    album::add_upload(album_trees, album_id, user_id, file_id, &file)
+                },
+            )?;
+
+            if let Some(album_head) = album_head {
+                events::album_updated(state, album_id, album_head);
+            }
+        }
+
+        respond_ok_empty()
+    })
+}
+
+pub async fn reject(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: IdList = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref user_to_album,
+            ref pending_uploads,
+            ..
+        } = state;
+
+        let album_id = parts.param("albumId").unwrap();
+
+        test_logged_in(sessions, key)?;
+        test_owner(user_to_album, user_id, album_id)?;
+
+        for file_id in &json.ids {
+            if pending_uploads.remove([album_id, ".", file_id].concat())?.is_none() {
+                continue;
+            }
+
+            // The file may be gone already, or belong to whoever owned the album before.
+            match trash::move_file(state, user_id, file_id) {
+                Err(ApiError::NotFound) => {}
+                result => result?,
+            }
+        }
+
+        respond_ok_empty()
+    })
+}
+
+async fn guest_album(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let token = parts.param("token").unwrap();
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref albums,
+            ref contribution_links,
+            ..
+        } = state;
+
+        let link = open(contribution_links, token)?;
+        album_owner(state, &link.album_id)?;
+
+        let album_bytes = albums.get(link.album_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+        let album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+        respond_ok(GuestAlbum {
+            name: album.description.name,
+            moderated: link.moderated,
+            files_left: link.max_files.map(|max| max.saturating_sub(link.files)),
+            bytes_left: link.max_bytes.map(|max| max.saturating_sub(link.bytes)),
+        })
+    })
+}
+
+async fn upload(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, mut body) = req.into_parts();
+
+    let token = parts.param("token").unwrap();
+
+    let state = parts.data().unwrap();
+    let AppState {
+        ref contribution_links,
+        ref pending_uploads,
+        ref temp_path,
+        ref upload_limiter,
+        ref config,
+        ..
+    } = state;
+
+    // Turn the upload away before anything is received if the link can't take it.
+    let (link, owner_id) = block_in_place(|| -> ApiResult<_> {
+        let link = open(contribution_links, token)?;
+        if link.max_files.is_some_and(|max| link.files >= max) {
+            return Err(ApiError::QuotaExceeded);
+        }
+
+        let owner_id = album_owner(state, &link.album_id)?;
+        Ok((link, owner_id))
+    })?;
+    // Every guest of a link shares its places.
+    let _upload = upload_limiter.acquire(&["guest.", token].concat())?;
+
+    let max_bytes = config.max_upload_bytes;
+    let deadline = time::Instant::now() + Duration::from_secs(config.upload_timeout_seconds);
+    let idle = Duration::from_secs(config.body_idle_seconds);
+
+    if file::content_length(&parts).is_some_and(|length| length > max_bytes) {
+        return Err(ApiError::PayloadTooLarge);
+    }
+
+    let metadata = file::upload_metadata(&parts)?;
+    let options = UploadOptions {
+        album_id: (!link.moderated).then(|| link.album_id.to_string()),
+        conflict: ConflictPolicy::Rename,
+        encrypted: false,
+        content_id: None,
+    };
+
+    let file_id = new_id(16);
+    let upload_path = temp_path.join(&file_id);
+
+    let result = async {
+        let head = file::receive(&mut body, &upload_path, max_bytes, deadline, idle).await?;
+
+        let size = fs::metadata(&upload_path).await?.len();
+        block_in_place(|| reserve(contribution_links, token, size))?;
+
+        let stored = match file::store(state, &owner_id, &file_id, &options, metadata, &upload_path, &head).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                block_in_place(|| release(contribution_links, token, size))?;
+                return Err(err);
+            }
+        };
+
+        if link.moderated {
+            let pending = PendingUpload {
+                file_id: stored.id.clone(),
+                token: Cow::from(token),
+                uploaded: Utc::now().timestamp(),
+            };
+            let pending_key = [&link.album_id, ".", &stored.id].concat();
+            block_in_place(|| pending_uploads.insert(pending_key, bincode::serialize(&pending).unwrap()))?;
+        }
+
+        Ok(stored)
+    }
+    .await;
+
+    let _ = fs::remove_file(&upload_path).await;
+
+    respond_ok(result?)
+}
+
+pub const SCOPE: &str = "/guest";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::GetGuestAlbum, guest_album)
+        .endpoint(SCOPE, endpoint::GuestUpload, upload)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn guest_uploads() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let settings = json!({ "name": "Wedding", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+        let share = json!({ "email": "reader@example.com", "role": "Reader" });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Only the owner makes links.
+        let links = |key: &str| format!("/album/{}/links?key={}", album_id, key);
+        let options = json!({ "max_files": 2, "moderated": true });
+        assert_eq!(server.send(Method::POST, &links(&reader), &options).await, StatusCode::UNAUTHORIZED);
+        let link = server.json(Method::POST, &links(&owner), &options).await;
+        let token = link["token"].as_str().unwrap();
+        let guest = format!("/guest/{}", token);
+
+        let info = server.json(Method::GET, &guest, &()).await;
+        assert_eq!(info["name"], "Wedding");
+        assert_eq!(info["files_left"], 2);
+
+        let mut uploaded = vec![];
+        for name in ["one.png", "two.png"] {
+            let (status, body) = server.upload_to(&guest, name, png(8, 8)).await;
+            assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+            let file: Value = serde_json::from_slice(&body).unwrap();
+            uploaded.push(file["id"].as_str().unwrap().to_string());
+        }
+        let (status, _) = server.upload_to(&guest, "three.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Uploads wait for the owner, and are stored as their files.
+        let metadata = format!("/album/{}/serve/metadata?key={}", album_id, owner);
+        assert_eq!(server.json(Method::GET, &metadata, &()).await["length"], 0);
+        let pending = |key: &str| format!("/album/{}/pending?key={}", album_id, key);
+        let waiting = server.json(Method::GET, &pending(&owner), &()).await;
+        assert_eq!(waiting.as_array().unwrap().len(), 2);
+        assert_eq!(waiting[0]["token"], token);
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", uploaded[0], owner), &()).await;
+        assert_eq!(info["metadata"]["name"], "one.png");
+
+        let (approved, rejected) = (json!({ "ids": [uploaded[0]] }), json!({ "ids": [uploaded[1]] }));
+        assert_eq!(server.send(Method::POST, &pending(&reader), &approved).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.send(Method::POST, &pending(&owner), &approved).await, StatusCode::OK);
+        assert_eq!(server.send(Method::DELETE, &pending(&owner), &rejected).await, StatusCode::OK);
+
+        assert_eq!(server.json(Method::GET, &metadata, &()).await["length"], 1);
+        assert!(server.json(Method::GET, &pending(&owner), &()).await.as_array().unwrap().is_empty());
+        let trash = server.json(Method::GET, &format!("/trash?key={}", owner), &()).await;
+        assert_eq!(trash[0]["id"], uploaded[1].as_str());
+
+        // Links without moderation add uploads right away, until they are deleted.
+        let link = server.json(Method::POST, &links(&owner), &json!({})).await;
+        let guest = format!("/guest/{}", link["token"].as_str().unwrap());
+        let (status, _) = server.upload_to(&guest, "four.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &metadata, &()).await["length"], 2);
+
+        let listed = server.json(Method::GET, &links(&owner), &()).await;
+        let counted: Vec<_> = listed.as_array().unwrap().iter().map(|link| link["files"].clone()).collect();
+        assert_eq!(counted.len(), 2);
+        assert!(counted.contains(&json!(2)) && counted.contains(&json!(1)));
+
+        let path = format!("/album/{}/links/{}?key={}", album_id, link["token"].as_str().unwrap(), owner);
+        assert_eq!(server.send(Method::DELETE, &path, &()).await, StatusCode::OK);
+        let (status, _) = server.upload_to(&guest, "five.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,162 @@
+//! Invitations
+//!
+//! With `registration` set to `invite`, `POST /user` needs a code that an administrator made with
+//! `POST /admin/invites`. Codes are kept in the `invites` tree until they are used, expire, or are
+//! deleted, and whatever quota or administrator role one carries is given to the user who
+//! registers with it. Codes can be used while registration is open too, to give those presets.
+
+use crate::{
+    admin::require_admin,
+    common::{join, new_id, require_key, respond_ok, respond_ok_empty, AppState},
+    error::{ApiError, ApiResult},
+};
+use chrono::offset::Utc;
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use std::borrow::Cow;
+use tokio::task::block_in_place;
+use wire::{IntoOwned, Invite, InviteOptions};
+
+const INVITE_CODE_BYTES: usize = 16;
+
+/// Use up the invitation `code`, which fails if it doesn't exist or has expired.
+pub fn redeem(invites: &sled::Tree, code: &str) -> ApiResult<Invite<'static>> {
+    let invite_bytes = invites.remove(code)?.ok_or(ApiError::Unauthorized)?;
+    let invite: Invite = bincode::deserialize(&invite_bytes).unwrap();
+
+    if invite.expires.is_some_and(|expires| expires < Utc::now().timestamp()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(invite.into_owned())
+}
+
+/// Put back an invitation that was redeemed by a registration that failed.
+pub fn restore(invites: &sled::Tree, invite: &Invite) -> ApiResult<()> {
+    invites.insert(invite.code.as_bytes(), bincode::serialize(invite).unwrap())?;
+    Ok(())
+}
+
+/// Give the presets of `invite` to the user who registered with it.
+pub fn apply(state: &AppState, user_id: &str, invite: &Invite) -> ApiResult<()> {
+    if let Some(quota) = invite.quota {
+        state.quotas.insert(user_id, &quota.to_be_bytes())?;
+    }
+    if invite.admin {
+        state.admins.insert(user_id, b"")?;
+    }
+
+    Ok(())
+}
+
+pub async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let AppState { ref invites, .. } = parts.data().unwrap();
+
+    block_in_place(|| {
+        let now = Utc::now().timestamp();
+
+        let mut list = vec![];
+        for entry in invites.iter() {
+            let (_, invite_bytes) = entry?;
+            let invite: Invite = bincode::deserialize(&invite_bytes).unwrap();
+
+            if invite.expires.is_none_or(|expires| expires >= now) {
+                list.push(invite.into_owned());
+            }
+        }
+
+        respond_ok(list)
+    })
+}
+
+pub async fn create(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    require_admin(&parts)?;
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let options: InviteOptions = serde_json::from_slice(&entire_body)?;
+
+    let AppState { ref invites, .. } = parts.data().unwrap();
+
+    let now = Utc::now().timestamp();
+    let invite = Invite {
+        code: Cow::from(new_id(INVITE_CODE_BYTES)),
+        created_by: Cow::from(user_id),
+        created: now,
+        expires: options.expires_in.map(|expires_in| now + expires_in),
+        quota: options.quota,
+        admin: options.admin,
+    };
+
+    block_in_place(|| restore(invites, &invite))?;
+
+    respond_ok(invite)
+}
+
+pub async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let code = parts.param("code").unwrap();
+    let AppState { ref invites, .. } = parts.data().unwrap();
+
+    block_in_place(|| invites.remove(code.as_bytes()))?.ok_or(ApiError::NotFound)?;
+
+    respond_ok_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer, ADMIN_EMAIL, PASSWORD};
+    use crate::config::Registration;
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+    use wire::UserDetails;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invite_only() {
+        let server = TestServer::start_with(|config| config.registration = Registration::Invite).await;
+
+        let details = UserDetails {
+            email: "guest@example.com".into(),
+            password: PASSWORD.into(),
+        };
+        assert_eq!(server.send(Method::POST, "/user", &details).await, StatusCode::UNAUTHORIZED);
+
+        // Administrators can't register while it's by invitation either.
+        let admin = UserDetails {
+            email: ADMIN_EMAIL.into(),
+            password: PASSWORD.into(),
+        };
+        assert_eq!(server.send(Method::POST, "/user", &admin).await, StatusCode::UNAUTHORIZED);
+
+        let admin_key = server.signup_admin().await;
+        let options = json!({ "quota": 100 });
+        let invite = server.json(Method::POST, &format!("/admin/invites?key={}", admin_key), &options).await;
+        let code = invite["code"].as_str().unwrap();
+
+        let invites = server.json(Method::GET, &format!("/admin/invites?key={}", admin_key), &()).await;
+        assert_eq!(invites.as_array().unwrap().len(), 1);
+
+        let path = format!("/user?invite={}", code);
+        assert_eq!(server.send(Method::POST, &path, &details).await, StatusCode::OK);
+        let details = UserDetails {
+            email: "other@example.com".into(),
+            password: PASSWORD.into(),
+        };
+        assert_eq!(server.send(Method::POST, &path, &details).await, StatusCode::UNAUTHORIZED);
+
+        let key = server.login("guest@example.com").await;
+        let stats = server.json(Method::GET, &format!("/user/stats?key={}", key), &()).await;
+        assert_eq!(stats["quota"], 100);
+
+        let (status, _) = server.upload_to(&format!("/file?key={}", key), "big.png", png(64, 64)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
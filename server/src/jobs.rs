@@ -0,0 +1,266 @@
+//! Background Jobs
+//!
+//! Work that has to be finished even if the server stops part way through is recorded in the
+//! `jobs` tree before it starts and removed once it succeeds. A pool of workers picks up whatever
+//! is left over: jobs from before a restart, jobs that are due for a retry after failing, and jobs
+//! that were only enqueued. Jobs may therefore run more than once and must be idempotent.
+//!
+//! Each failure pushes the next attempt further back, and a job that keeps failing is left in the
+//! tree, where it shows up in `GET /admin/jobs`, instead of being retried forever.
+
+use crate::{
+    album::rebuild,
+    common::AppState,
+    delete, detect,
+    error::{ApiError, ApiResult},
+    measure, rekey, verify,
+};
+use chrono::offset::Utc;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::{task::block_in_place, time};
+use tracing::{info, warn};
+use wire::JobStatus;
+
+/// Longest that an idle worker waits before looking for due retries.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Delay before the first retry, which doubles with every attempt.
+const RETRY_BASE_SECONDS: i64 = 30;
+const RETRY_MAX_SECONDS: i64 = 6 * 60 * 60;
+/// Attempts after which a job is given up on.
+const MAX_ATTEMPTS: u32 = 10;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Job<'a> {
+    Delete(#[serde(borrow)] delete::Command<'a>),
+    /// Lay out an album by a new time zone or sort mode.
+    Rebuild(#[serde(borrow)] Cow<'a, str>),
+    /// Store files that aren't encrypted with the current storage key again.
+    Reencrypt,
+    /// Send a stored image to the detection service.
+    Detect(#[serde(borrow)] Cow<'a, str>),
+    /// Record the sizes and hashes of files from before they were recorded on upload.
+    Measure,
+    /// Hash every original again to find the ones that were damaged in storage.
+    Verify,
+}
+
+impl<'a> Job<'a> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::Delete(_) => "delete",
+            Job::Rebuild(_) => "rebuild",
+            Job::Reencrypt => "reencrypt",
+            Job::Detect(_) => "detect",
+            Job::Measure => "measure",
+            Job::Verify => "verify",
+        }
+    }
+
+    fn execute(&self, state: &AppState) -> ApiResult<()> {
+        match self {
+            Job::Delete(command) => command.execute(state),
+            Job::Rebuild(album_id) => rebuild::run(state, album_id),
+            Job::Reencrypt => rekey::run(state),
+            Job::Detect(file_id) => detect::run(state, file_id),
+            Job::Measure => measure::run(state),
+            Job::Verify => verify::run(state),
+        }
+    }
+}
+
+/// Stored along with each job as `(Schedule, Job)`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Schedule {
+    attempts: u32,
+    /// Unix time before which the job isn't picked up.
+    run_after: i64,
+    last_error: Option<String>,
+}
+
+impl Schedule {
+    fn failed(&self) -> bool {
+        self.attempts >= MAX_ATTEMPTS
+    }
+}
+
+/// Jobs that are running in this process, which lets workers skip them.
+#[derive(Clone, Default)]
+pub struct Queue {
+    running: Arc<Mutex<HashSet<u64>>>,
+    wake: Arc<Notify>,
+}
+
+impl Queue {
+    /// How many jobs this process is running.
+    pub fn running(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+}
+
+fn job_id(key: &[u8]) -> u64 {
+    u64::from_be_bytes(key.try_into().unwrap())
+}
+
+fn due_now() -> Schedule {
+    Schedule {
+        attempts: 0,
+        run_after: Utc::now().timestamp(),
+        last_error: None,
+    }
+}
+
+/// Record a job under `id` so that it is picked up by the workers.
+pub fn insert(jobs: &sled::Tree, id: &[u8], job: &Job) -> ApiResult<()> {
+    jobs.insert(id, bincode::serialize(&(due_now(), job)).unwrap())?;
+    Ok(())
+}
+
+/// Record a job as part of a transaction, so that it only exists if the changes that it finishes
+/// were committed. The workers should be woken with `wake` afterwards.
+pub fn record(
+    jobs: &TransactionalTree,
+    id: u64,
+    job: &Job,
+) -> ConflictableTransactionResult<(), ApiError> {
+    jobs.insert(&id.to_be_bytes(), bincode::serialize(&(due_now(), job)).unwrap())?;
+    Ok(())
+}
+
+/// Have a worker look for jobs that were recorded.
+pub fn wake(state: &AppState) {
+    state.job_queue.wake.notify_one();
+}
+
+/// Leave a job for the workers.
+pub fn enqueue(state: &AppState, job: &Job) -> ApiResult<()> {
+    let id = state.db.generate_id()?;
+    insert(&state.jobs, &id.to_be_bytes(), job)?;
+
+    wake(state);
+    Ok(())
+}
+
+/// Run a job right away, keeping it recorded until it succeeds. If it fails, the error is
+/// returned and the workers retry it later.
+pub fn run(state: &AppState, job: &Job) -> ApiResult<()> {
+    let id = state.db.generate_id()?;
+    state.job_queue.running.lock().unwrap().insert(id);
+
+    let result = insert(&state.jobs, &id.to_be_bytes(), job).and_then(|_| finish(state, id, job, 0));
+
+    state.job_queue.running.lock().unwrap().remove(&id);
+    result
+}
+
+/// Remove a job after it ran, or schedule its next attempt if it failed.
+fn finish(state: &AppState, id: u64, job: &Job, attempts: u32) -> ApiResult<()> {
+    let key = id.to_be_bytes();
+
+    match job.execute(state) {
+        Ok(()) => {
+            state.jobs.remove(key)?;
+            Ok(())
+        }
+        Err(err) => {
+            let attempts = attempts + 1;
+            let delay = (RETRY_BASE_SECONDS << (attempts - 1).min(16)).min(RETRY_MAX_SECONDS);
+
+            let schedule = Schedule {
+                attempts,
+                run_after: Utc::now().timestamp() + delay,
+                last_error: Some(err.chain().to_string()),
+            };
+            state.jobs.insert(key, bincode::serialize(&(&schedule, job)).unwrap())?;
+
+            if schedule.failed() {
+                warn!(job = id, kind = job.kind(), error = %err.chain(), "job gave up");
+            } else {
+                info!(job = id, kind = job.kind(), attempts, "job will be retried");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Claim and run the next job that is due, returning whether there was one.
+fn run_next(state: &AppState) -> ApiResult<bool> {
+    let now = Utc::now().timestamp();
+
+    for entry in state.jobs.iter() {
+        let (key, job_bytes) = entry?;
+        let id = job_id(&key);
+
+        let (schedule, job): (Schedule, Job) = bincode::deserialize(&job_bytes).unwrap();
+        if schedule.failed() || schedule.run_after > now {
+            continue;
+        }
+
+        if !state.job_queue.running.lock().unwrap().insert(id) {
+            continue;
+        }
+
+        // The job may have finished between reading it and claiming it.
+        let claimed = state.jobs.get(&key)?.as_ref() == Some(&job_bytes);
+        if claimed {
+            // Failures are recorded with the job itself.
+            let _ = finish(state, id, &job, schedule.attempts);
+        }
+
+        state.job_queue.running.lock().unwrap().remove(&id);
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+async fn work(state: AppState) {
+    loop {
+        match block_in_place(|| run_next(&state)) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(err) => warn!(error = %err.chain(), "Couldn't read the job queue"),
+        }
+
+        let _ = time::timeout(POLL_INTERVAL, state.job_queue.wake.notified()).await;
+    }
+}
+
+/// Start the workers, which begin with any jobs that were left over from a previous run.
+pub fn spawn_workers(state: &AppState) {
+    for _ in 0..state.config.job_workers.max(1) {
+        tokio::spawn(work(state.clone()));
+    }
+}
+
+/// Every job that hasn't succeeded yet, oldest first.
+pub fn list(state: &AppState) -> ApiResult<Vec<JobStatus>> {
+    let running = state.job_queue.running.lock().unwrap().clone();
+
+    state
+        .jobs
+        .iter()
+        .map(|entry| {
+            let (key, job_bytes) = entry?;
+            let id = job_id(&key);
+            let (schedule, job): (Schedule, Job) = bincode::deserialize(&job_bytes).unwrap();
+
+            Ok(JobStatus {
+                id,
+                kind: job.kind().to_string(),
+                attempts: schedule.attempts,
+                run_after: schedule.run_after,
+                running: running.contains(&id),
+                failed: schedule.failed(),
+                last_error: schedule.last_error,
+            })
+        })
+        .collect::<Result<_, ApiError>>()
+}
@@ -0,0 +1,259 @@
+//! Photo Library
+//!
+//! Every user has an implicit library that holds all of their own files, split into days like an
+//! album that is sorted by capture date. It is driven by the same `Engine` as albums, so clients
+//! can page through it with the same fragments, but it is kept in its own `libraries` and
+//! `library_fragments` trees under the user id. That way it never shows up among albums and can't
+//! be shared or changed directly. Uploads add files to it and deletes remove them, and files that
+//! their owner hid with `PUT /file/:fileId/hidden` or locked with `PUT /file/:fileId/locked` are
+//! left out.
+//!
+//! Libraries are dated in UTC, since users don't have a time zone of their own.
+
+use crate::{
+    album::engine::Engine,
+    common::{require_key, respond_ok, test_logged_in, AppState, File},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+    events,
+};
+use chrono::offset::Utc;
+use hyper::{header, Body, Request, Response, StatusCode};
+use routerify::{ext::RequestExt, Router};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::Transactional;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use tokio::task::block_in_place;
+use wire::{endpoint, Album, AlbumEvent, AlbumSettings, SortMode};
+
+/// Files that the migration adds to a library in a single transaction.
+const BUILD_BATCH_SIZE: usize = 256;
+
+fn new_library() -> Album<'static> {
+    Album {
+        description: AlbumSettings {
+            name: Cow::from("Library"),
+            time_zone: chrono_tz::UTC,
+            sort: SortMode::CaptureDate,
+            description: Cow::from(""),
+            rules: vec![],
+        },
+        fragment_head: 0,
+        length: 0,
+        last_update: Utc::now().timestamp(),
+        date_range: None,
+    }
+}
+
+/// Apply `f` to the library of `user_id`, creating the library first if it doesn't exist, and
+/// return its new fragment head.
+pub fn modify<F>(
+    libraries: &TransactionalTree,
+    fragments: &TransactionalTree,
+    user_id: &str,
+    f: F,
+) -> ConflictableTransactionResult<u64, ApiError>
+where
+    F: FnOnce(&mut Engine) -> ConflictableTransactionResult<(), ApiError>,
+{
+    let library_bytes = libraries.get(user_id)?;
+    let mut library: Album = match library_bytes {
+        Some(ref library_bytes) => bincode::deserialize(library_bytes).unwrap(),
+        None => {
+            Engine::empty(user_id, fragments)?;
+            new_library()
+        }
+    };
+
+    let mut e = Engine::new(user_id, &mut library, fragments)?;
+    f(&mut e)?;
+    e.commit()?;
+
+    libraries.insert(user_id, bincode::serialize(&library).unwrap())?;
+    Ok(library.fragment_head)
+}
+
+pub fn updated(state: &AppState, user_id: &str, fragment_head: u64) {
+    events::publish(
+        &state.events,
+        AlbumEvent::LibraryUpdated {
+            user_id: user_id.to_string(),
+            fragment_head,
+        },
+    );
+}
+
+/// Fill the libraries from the files that are already stored.
+pub fn build(state: &AppState) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref libraries,
+        ref library_fragments,
+        ..
+    } = state;
+
+    let mut owned: HashMap<String, Vec<sled::IVec>> = HashMap::new();
+    for entry in files.iter() {
+        let (file_id, file_bytes) = entry?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+        owned.entry(file.owner_id.to_string()).or_default().push(file_id);
+    }
+
+    for (user_id, file_ids) in owned {
+        for batch in file_ids.chunks(BUILD_BATCH_SIZE) {
+            // Adding a file that is already in the library does nothing, so an interrupted build
+            // can start over.
+            (files, libraries, library_fragments).transaction(|(files, libraries, library_fragments)| {
+                modify(libraries, library_fragments, &user_id, |e| {
+                    for file_id in batch {
+                        if let Some(file_bytes) = files.get(file_id)? {
+                            let file: File = bincode::deserialize(&file_bytes).unwrap();
+                            if file.in_library() {
+                                e.add(std::str::from_utf8(file_id).unwrap(), &file)?;
+                            }
+                        }
+                    }
+
+                    Ok(())
+                })?;
+
+                Ok(())
+            })?;
+        }
+
+        Engine::prune_deltas(library_fragments, &user_id)?;
+    }
+
+    Ok(())
+}
+
+pub fn clean(state: &AppState) -> ApiResult<()> {
+    for entry in state.libraries.iter() {
+        let (user_id, _) = entry?;
+        let user_id = std::str::from_utf8(&user_id).unwrap();
+
+        Engine::prune_deltas(&state.library_fragments, user_id)?;
+    }
+
+    Ok(())
+}
+
+/// Serves the library like `album::serve` serves an album: `metadata` gives the fragment head,
+/// and numbered fragments never change once they are written.
+async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let fragment_id = match parts.param("fragmentId").unwrap().as_str() {
+        "metadata" => None,
+        string => Some(string.parse().map_err(|_| ApiError::BadRequest)?),
+    };
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref libraries,
+            ref library_fragments,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        if let Some(fragment_id) = fragment_id {
+            let id = Engine::get_id(user_id, fragment_id);
+            let fragment = library_fragments.get(id)?.ok_or(ApiError::NotFound)?;
+
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(header::CACHE_CONTROL, "private, max-age=31536000, immutable")
+                .status(StatusCode::OK)
+                .body(Body::from(Vec::from(fragment.as_ref())))
+                .unwrap());
+        }
+
+        match libraries.get(user_id)? {
+            Some(library_bytes) => {
+                let library: Album = bincode::deserialize(&library_bytes).unwrap();
+                respond_ok(library)
+            }
+            // Users that haven't uploaded anything get an empty library.
+            None => {
+                (libraries, library_fragments).transaction(|(libraries, library_fragments)| {
+                    modify(libraries, library_fragments, user_id, |_| Ok(()))
+                })?;
+
+                let library_bytes = libraries.get(user_id)?.unwrap();
+                let library: Album = bincode::deserialize(&library_bytes).unwrap();
+                respond_ok(library)
+            }
+        }
+    })
+}
+
+pub const SCOPE: &str = "/library";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::ServeLibrary, serve)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hide_from_library() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let settings = json!({ "name": "Receipts", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let path = format!("/file?key={}&album={}", key, album_id);
+        let (status, body) = server.upload_to(&path, "receipt.png", png(8, 8)).await;
+        assert_eq!(status, StatusCode::OK);
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        let file_id = file["id"].as_str().unwrap();
+
+        let library = format!("/library/serve/metadata?key={}", key);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 1);
+
+        let hidden = |key: &str| format!("/file/{}/hidden?key={}", file_id, key);
+        let hide = json!({ "hidden": true });
+        assert_eq!(server.send(Method::PUT, &hidden(&other), &hide).await, StatusCode::NOT_FOUND);
+        assert_eq!(server.send(Method::PUT, &hidden(&key), &hide).await, StatusCode::OK);
+        // Hiding it again changes nothing.
+        assert_eq!(server.send(Method::PUT, &hidden(&key), &hide).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 0);
+
+        // The file is still listed, and still in its album.
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(info["hidden"], true);
+        assert_eq!(info["albums"][0]["id"], album_id);
+        let list = server.json(Method::POST, &format!("/file/list?key={}", key), &json!({})).await;
+        assert_eq!(list["files"][0][0], "receipt.png");
+        let path = format!("/album/{}/serve/metadata?key={}", album_id, key);
+        assert_eq!(server.json(Method::GET, &path, &()).await["length"], 1);
+
+        // Hidden files stay out of the library through the trash.
+        let status = server.send(Method::DELETE, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(status, StatusCode::OK);
+        let restore = json!({ "kind": "file", "id": file_id });
+        let status = server.send(Method::POST, &format!("/trash/restore?key={}", key), &restore).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 0);
+
+        let show = json!({ "hidden": false });
+        assert_eq!(server.send(Method::PUT, &hidden(&key), &show).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &library, &()).await["length"], 1);
+    }
+}
@@ -0,0 +1,176 @@
+//! Token Bucket Rate Limiting
+//!
+//! Each key (an IP address or an email) owns a bucket that holds up to `burst` tokens and refills
+//! continuously at `per_minute` tokens per minute. A request spends one token, and is rejected with
+//! the time until the next token arrives when the bucket is empty.
+//!
+//! Requests that take long, like uploads, are limited by how many each key has underway instead,
+//! with a `ConcurrencyLimiter`.
+
+use crate::error::{ApiError, ApiResult};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of tracked keys after which full buckets are forgotten.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: Limit) -> Self {
+        RateLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spend a token for `key`, failing with `TooManyRequests` if none are left.
+    pub fn check(&self, key: &str) -> ApiResult<()> {
+        self.check_at(key, Instant::now())
+            .map_err(|retry_after| ApiError::TooManyRequests(retry_after.as_secs().max(1)))
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let burst = self.limit.burst as f64;
+        let per_second = self.limit.per_minute as f64 / 60.;
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            Ok(())
+        } else if per_second > 0. {
+            Err(Duration::from_secs_f64((1. - bucket.tokens) / per_second))
+        } else {
+            Err(Duration::from_secs(u64::MAX))
+        }
+    }
+}
+
+/// Counts the requests that each key has underway, turning away those over `max`.
+pub struct ConcurrencyLimiter {
+    max: usize,
+    active: Mutex<HashMap<String, usize>>,
+}
+
+/// Holds a place with a `ConcurrencyLimiter` until it is dropped.
+pub struct ConcurrencyGuard {
+    limiter: Arc<ConcurrencyLimiter>,
+    key: String,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max: usize) -> Self {
+        ConcurrencyLimiter {
+            max,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a place for `key`, failing with `TooManyRequests` if it has `max` underway already.
+    pub fn acquire(self: &Arc<Self>, key: &str) -> ApiResult<ConcurrencyGuard> {
+        let mut active = self.active.lock().unwrap();
+
+        let count = active.entry(key.to_owned()).or_insert(0);
+        if *count >= self.max {
+            return Err(ApiError::TooManyRequests(1));
+        }
+        *count += 1;
+
+        Ok(ConcurrencyGuard {
+            limiter: self.clone(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_drains_and_refills() {
+        let limiter = RateLimiter::new(Limit {
+            burst: 2,
+            per_minute: 60,
+        });
+        let start = Instant::now();
+
+        assert!(limiter.check_at("a", start).is_ok());
+        assert!(limiter.check_at("a", start).is_ok());
+
+        let retry_after = limiter.check_at("a", start).unwrap_err();
+        assert_eq!(retry_after.as_secs(), 1);
+
+        // Other keys have their own bucket
+        assert!(limiter.check_at("b", start).is_ok());
+
+        // A token comes back every second
+        assert!(limiter.check_at("a", start + Duration::from_secs(1)).is_ok());
+        assert!(limiter.check_at("a", start + Duration::from_secs(1)).is_err());
+
+        // Buckets never hold more than the burst
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check_at("a", later).is_ok());
+        assert!(limiter.check_at("a", later).is_ok());
+        assert!(limiter.check_at("a", later).is_err());
+    }
+
+    #[test]
+    fn concurrency_is_released() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(2));
+
+        let first = limiter.acquire("a").unwrap();
+        let _second = limiter.acquire("a").unwrap();
+        assert!(limiter.acquire("a").is_err());
+        assert!(limiter.acquire("b").is_ok());
+
+        drop(first);
+        let _third = limiter.acquire("a").unwrap();
+        assert!(limiter.acquire("a").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+//! Live Photos
+//!
+//! iPhones keep a Live Photo as a still HEIC or JPEG and a short MOV, which are uploaded as two
+//! files. Once both are in they are paired, by the content identifier that the camera gives both
+//! halves if the client sent it as `content_id` with each upload, and otherwise by their names,
+//! which are the same apart from the extension. The still records its motion in `motion_id` and
+//! the motion its still in `still_id`. Entries of the still in the library and in albums are
+//! flagged as live, so that clients can show a badge, and the `motion` quality of the still serves
+//! the original of its motion.
+//!
+//! Content identifiers are kept in the `content_ids` tree under the owner, pointing at the last
+//! file that was uploaded with them. Entries of files that were deleted since are only replaced
+//! when the identifier comes up again.
+
+use crate::{
+    album::engine::Engine,
+    common::{AppState, File},
+    error::{ApiError, ApiResult},
+    events, library,
+};
+use sled::Transactional;
+use wire::{Album, AlbumEvent};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Half {
+    Still,
+    Motion,
+}
+
+/// Which half of a Live Photo `file` could be.
+fn half_of(file: &File) -> Option<Half> {
+    if file.encrypted {
+        None
+    } else if file.detected_mime.starts_with("image/") {
+        Some(Half::Still)
+    } else if file.detected_mime.starts_with("video/") {
+        Some(Half::Motion)
+    } else {
+        None
+    }
+}
+
+fn is_paired(file: &File) -> bool {
+    file.motion_id.is_some() || file.still_id.is_some()
+}
+
+/// Another file of `owner_id` that is the other half of `file`, which is `half`, and isn't paired
+/// yet.
+fn find_partner(
+    state: &AppState,
+    file_id: &str,
+    file: &File,
+    half: Half,
+    content_id: Option<&str>,
+) -> ApiResult<Option<String>> {
+    let AppState {
+        ref files,
+        ref file_names,
+        ref content_ids,
+        ..
+    } = state;
+
+    let owner_id = file.owner_id;
+    let is_partner = |candidate_id: &[u8]| -> ApiResult<bool> {
+        if candidate_id == file_id.as_bytes() {
+            return Ok(false);
+        }
+        Ok(match files.get(candidate_id)? {
+            Some(candidate_bytes) => {
+                let candidate: File = bincode::deserialize(&candidate_bytes).unwrap();
+                let other = half_of(&candidate);
+                other.is_some() && other != Some(half) && !is_paired(&candidate)
+            }
+            None => false,
+        })
+    };
+
+    if let Some(content_id) = content_id {
+        let previous = content_ids.insert([owner_id, ".", content_id].concat(), file_id.as_bytes())?;
+        return Ok(match previous {
+            Some(candidate_id) if is_partner(&candidate_id)? => {
+                Some(std::str::from_utf8(&candidate_id).unwrap().to_string())
+            }
+            _ => None,
+        });
+    }
+
+    let stem = match file.metadata.name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => return Ok(None),
+    };
+    let prefix = [owner_id, ".", stem, "."].concat();
+
+    for entry in file_names.scan_prefix(&prefix) {
+        let (name, candidate_id) = entry?;
+        // Only the extension may differ, so `IMG_0001.edited.jpg` isn't a partner of `IMG_0001.mov`.
+        if name[prefix.len()..].contains(&b'.') {
+            continue;
+        }
+        if is_partner(&candidate_id)? {
+            return Ok(Some(std::str::from_utf8(&candidate_id).unwrap().to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Link the still `still_id` and the motion `motion_id` to each other if `linked` is set and
+/// neither is paired yet, or undo their link otherwise, copying the live flag of the still into
+/// its entries. Files that are gone are left out.
+fn link(state: &AppState, still_id: &str, motion_id: &str, linked: bool) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref libraries,
+        ref library_fragments,
+        ref albums,
+        ref inclusions,
+        ref fragments,
+        ref events,
+        ..
+    } = state;
+
+    let mut album_ids = vec![];
+    for entry in inclusions.scan_prefix([still_id, "."].concat()) {
+        let (inclusion, _) = entry?;
+        let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+        album_ids.push(album_id.to_string());
+    }
+
+    let trees = (files, libraries, library_fragments, albums, fragments);
+    let published = trees.transaction(|(files, libraries, library_fragments, albums, fragments)| {
+        let still_bytes = files.get(still_id)?;
+        let motion_bytes = files.get(motion_id)?;
+        let mut still: Option<File> = still_bytes.as_ref().map(|bytes| bincode::deserialize(bytes).unwrap());
+        let mut motion: Option<File> = motion_bytes.as_ref().map(|bytes| bincode::deserialize(bytes).unwrap());
+
+        if linked {
+            match (&mut still, &mut motion) {
+                (Some(still), Some(motion)) if !is_paired(still) && !is_paired(motion) => {
+                    still.motion_id = Some(motion_id.to_string());
+                    motion.still_id = Some(still_id.to_string());
+                }
+                _ => return Ok(vec![]),
+            }
+        } else {
+            if let Some(ref mut motion) = motion {
+                if motion.still_id.as_deref() == Some(still_id) {
+                    motion.still_id = None;
+                }
+            }
+            match still {
+                Some(ref mut still) if still.motion_id.as_deref() == Some(motion_id) => still.motion_id = None,
+                // Nothing about the still changes, so neither do its entries.
+                _ => still = None,
+            }
+        }
+
+        if let Some(ref motion) = motion {
+            files.insert(motion_id, bincode::serialize(motion).unwrap())?;
+        }
+        let still = match still {
+            Some(still) => still,
+            None => return Ok(vec![]),
+        };
+        files.insert(still_id, bincode::serialize(&still).unwrap())?;
+
+        let mut published = vec![];
+        if still.in_library() {
+            let library_head = library::modify(libraries, library_fragments, still.owner_id, |e| {
+                e.set_appearance(still_id, &still)
+            })?;
+            published.push(AlbumEvent::LibraryUpdated {
+                user_id: still.owner_id.to_string(),
+                fragment_head: library_head,
+            });
+        }
+        for album_id in &album_ids {
+            let album_bytes = match albums.get(album_id.as_bytes())? {
+                Some(album_bytes) => album_bytes,
+                None => continue,
+            };
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            let mut e = Engine::new(album_id, &mut album, fragments)?;
+            e.set_appearance(still_id, &still)?;
+            e.commit()?;
+
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+            published.push(AlbumEvent::Updated {
+                album_id: album_id.to_string(),
+                fragment_head: album.fragment_head,
+            });
+        }
+
+        Ok(published)
+    })?;
+
+    for event in published {
+        events::publish(events, event);
+    }
+
+    Ok(())
+}
+
+/// Pair the newly stored `file_id` with the other half of its Live Photo, if that is there.
+pub fn pair(state: &AppState, file_id: &str, content_id: Option<&str>) -> ApiResult<()> {
+    let file_bytes = state.files.get(file_id)?.ok_or(ApiError::NotFound)?;
+    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+    let half = match half_of(&file) {
+        Some(half) if !is_paired(&file) => half,
+        _ => return Ok(()),
+    };
+    let partner_id = match find_partner(state, file_id, &file, half, content_id)? {
+        Some(partner_id) => partner_id,
+        None => return Ok(()),
+    };
+
+    match half {
+        Half::Still => link(state, file_id, &partner_id, true),
+        Half::Motion => link(state, &partner_id, file_id, true),
+    }
+}
+
+/// Undo the pairing of `file`, which is being deleted, so that its other half stands alone again.
+pub fn unpair(state: &AppState, file_id: &str, file: &File) -> ApiResult<()> {
+    if let Some(ref motion_id) = file.motion_id {
+        link(state, file_id, motion_id, false)?;
+    }
+    if let Some(ref still_id) = file.still_id {
+        link(state, still_id, file_id, false)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::AppState, storage};
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::Value;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn live_photo() {
+        use crate::common::File;
+
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let still_id = server.upload(&key, "IMG_0001.png", png(64, 48)).await;
+
+        // Videos need ffmpeg to be stored, so the motion is put in place by hand, as if the
+        // upload of `IMG_0001.mov` had just stored it.
+        let AppState {
+            ref files,
+            ref file_names,
+            ref storage,
+            ref temp_path,
+            ..
+        } = server.state;
+        let motion_id = "motion";
+        let still_bytes = files.get(&still_id).unwrap().unwrap();
+        let mut motion: File = bincode::deserialize(&still_bytes).unwrap();
+        motion.detected_mime = "video/quicktime";
+        motion.metadata.name = "IMG_0001.mov".into();
+        files.insert(motion_id, bincode::serialize(&motion).unwrap()).unwrap();
+        file_names.insert([motion.owner_id, ".IMG_0001.mov"].concat(), motion_id).unwrap();
+        let motion_path = temp_path.join("motion.mov");
+        std::fs::write(&motion_path, b"moving").unwrap();
+        storage.put(&storage::key(storage::ORIGINAL, motion_id), &motion_path).await.unwrap();
+
+        crate::live::pair(&server.state, motion_id, None).unwrap();
+
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", still_id, key), &()).await;
+        assert_eq!(info["motion_id"], motion_id);
+        let path = format!("/file/motion/{}?key={}", still_id, key);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"moving");
+
+        // The motion has no motion of its own.
+        let path = format!("/file/motion/{}?key={}", motion_id, key);
+        assert_eq!(server.request(Method::GET, &path, &[], Body::empty()).await.0, StatusCode::NOT_FOUND);
+
+        let motion_bytes = files.get(motion_id).unwrap().unwrap();
+        let motion: File = bincode::deserialize(&motion_bytes).unwrap();
+        assert_eq!(motion.still_id.as_deref(), Some(still_id.as_str()));
+        crate::live::unpair(&server.state, motion_id, &motion).unwrap();
+
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", still_id, key), &()).await;
+        assert_eq!(info["motion_id"], Value::Null);
+    }
+}
@@ -0,0 +1,67 @@
+//! Outgoing Email
+//!
+//! Messages are sent in the background so that requests never wait on the mail server. Without an
+//! SMTP configuration the messages are logged instead, which is enough for local development.
+
+use crate::config::SmtpConfig;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub struct Mailer {
+    transport: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+}
+
+impl Mailer {
+    pub fn new(config: Option<&SmtpConfig>) -> Self {
+        let transport = config.map(|config| {
+            let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+                .expect("Invalid SMTP host")
+                .port(config.port);
+
+            if let (Some(username), Some(password)) = (&config.username, &config.password) {
+                builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+            }
+
+            let from = config.from.parse().expect("Invalid SMTP from address");
+            (builder.build(), from)
+        });
+
+        Mailer { transport }
+    }
+
+    pub fn send(&self, to: &str, subject: &str, body: String) {
+        let (transport, from) = match &self.transport {
+            Some(transport) => transport,
+            None => {
+                info!(to, subject, "Mail is not configured, not sending:\n{}", body);
+                return;
+            }
+        };
+
+        let to_mailbox = match to.parse() {
+            Ok(mailbox) => mailbox,
+            Err(err) => {
+                warn!(to, "Couldn't send mail to invalid address: {}", err);
+                return;
+            }
+        };
+
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to_mailbox)
+            .subject(subject)
+            .body(body)
+            .unwrap();
+
+        let transport = transport.clone();
+        let to = to.to_owned();
+        tokio::spawn(async move {
+            if let Err(err) = transport.send(message).await {
+                warn!(to = %to, "Couldn't send mail: {}", err);
+            }
+        });
+    }
+}
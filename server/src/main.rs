@@ -1,42 +1,155 @@
+mod admin;
 mod album;
+mod animation;
+mod bandwidth;
+mod capabilities;
+mod clean;
 mod common;
+mod config;
+mod detect;
+mod edit;
 mod error;
+mod events;
 mod file;
+mod format;
+mod forwarded;
+mod fsck;
+mod geo;
+mod guest;
+mod invite;
+mod jobs;
+mod library;
+mod limit;
+mod live;
+mod mail;
+mod measure;
+mod memories;
+mod metrics;
+mod migrate;
+mod oidc;
+mod placeholder;
+mod pool;
+mod regenerate;
+mod rekey;
+mod resize;
+mod similar;
+mod stats;
+mod storage;
+mod strip;
 mod user;
+mod verify;
+mod version;
+mod video;
 mod delete;
+mod endpoint;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod trace;
+mod trash;
+mod xmp;
 
 use common::AppState;
-use error::{ApiError, ApiResult};
-use hyper::{Body, Response, Server, StatusCode, Request};
-use routerify::{Router, RouterService, Middleware};
-use routerify::ext::RequestExt;
+use config::Config;
+use endpoint::EndpointExt;
+use error::ApiError;
+use futures::future;
+use hyper::server::conn::AddrStream;
+use hyper::service::make_service_fn;
+use hyper::{header, Body, Response, Server, StatusCode};
+use routerify::{RequestServiceBuilder, Router};
 use routerify_query::query_parser;
-use std::net::SocketAddr;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+use wire::API_VERSION_HEADER;
 
 async fn handle_error(error: routerify::RouteError) -> Response<Body> {
     let api_error = error.downcast::<ApiError>().unwrap();
 
-    println!("{}", api_error);
+    match api_error.as_ref() {
+        ApiError::Unauthorized
+        | ApiError::NotFound
+        | ApiError::BadRequest
+        | ApiError::UnsupportedFormat
+        | ApiError::PayloadTooLarge
+        | ApiError::QuotaExceeded
+        | ApiError::ElevationRequired
+        | ApiError::Timeout
+        | ApiError::Multipart(_)
+        | ApiError::TooManyRequests(_)
+        | ApiError::Busy(_)
+        | ApiError::AccountLocked(_)
+        | ApiError::UnsupportedVersion(_) => {
+            info!(error = %api_error.chain(), "request rejected")
+        }
+        _ => error!(error = %api_error.chain(), "request failed"),
+    }
 
     match api_error.as_ref() {
         ApiError::Unauthorized => Response::builder().status(StatusCode::UNAUTHORIZED),
         ApiError::NotFound => Response::builder().status(StatusCode::NOT_FOUND),
+        ApiError::ElevationRequired => Response::builder().status(StatusCode::FORBIDDEN),
         ApiError::Hyper(_)
         | ApiError::Sled(_)
         | ApiError::Argon(_)
         | ApiError::IO(_)
         | ApiError::Vips(_) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR),
-        ApiError::BadRequest | ApiError::Json(_) | ApiError::EmailTaken | ApiError::FileExists => {
-            Response::builder().status(StatusCode::BAD_REQUEST)
+        ApiError::Provider(_) | ApiError::Detector(_) => Response::builder().status(StatusCode::BAD_GATEWAY),
+        ApiError::BadRequest
+        | ApiError::Json(_)
+        | ApiError::Multipart(_)
+        | ApiError::EmailTaken
+        | ApiError::FileExists => Response::builder().status(StatusCode::BAD_REQUEST),
+        ApiError::UnsupportedFormat => Response::builder().status(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        ApiError::PayloadTooLarge | ApiError::QuotaExceeded => {
+            Response::builder().status(StatusCode::PAYLOAD_TOO_LARGE)
         }
+        ApiError::Timeout => Response::builder().status(StatusCode::REQUEST_TIMEOUT),
+        ApiError::TooManyRequests(retry_after) => Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, *retry_after),
+        ApiError::Busy(retry_after) => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, *retry_after),
+        ApiError::AccountLocked(retry_after) => Response::builder()
+            .status(StatusCode::LOCKED)
+            .header(header::RETRY_AFTER, *retry_after),
+        ApiError::UnsupportedVersion(_) => Response::builder().status(StatusCode::UPGRADE_REQUIRED),
     }
-    .body(Body::from(api_error.to_string()))
+    .header(header::CONTENT_TYPE, "application/json")
+    .header(API_VERSION_HEADER, version::header_value())
+    .body(Body::from(serde_json::to_string(&api_error.response()).unwrap()))
     .unwrap()
 }
 
-async fn logger(req: Request<Body>) -> ApiResult<Request<Body>> {
-    println!("{} {} {}", req.remote_addr(), req.method(), req.uri().path());
-    Ok(req)
+/// Every route, with `state` available to each of them.
+fn build_router(state: AppState) -> Router<Body, ApiError> {
+    Router::builder()
+        .middleware(forwarded::resolver())
+        .middleware(query_parser())
+        .middleware(version::checker())
+        .middleware(version::stamper())
+        // Provide app state to routes
+        .data(state)
+        // Routes
+        .scope(user::SCOPE, user::router())
+        .scope(file::SCOPE, file::router())
+        .scope(album::SCOPE, album::router())
+        .scope(library::SCOPE, library::router())
+        .scope(memories::SCOPE, memories::router())
+        .scope(trash::SCOPE, trash::router())
+        .scope(events::SCOPE, events::router())
+        .scope(guest::SCOPE, guest::router())
+        .scope(admin::SCOPE, admin::router())
+        .endpoint("", wire::endpoint::GetLimits, file::limits)
+        .endpoint("", wire::endpoint::GetMetrics, metrics::serve)
+        // Not found for invalid paths
+        .any(|_| async { Err(ApiError::NotFound) })
+        .err_handler(handle_error)
+        .build()
+        .unwrap()
 }
 
 async fn shutdown_signal() {
@@ -51,42 +164,95 @@ async fn main() {
     let vips = libvips::VipsApp::new("vips", true).unwrap();
     vips.concurrency_set(2);
 
-    let state = AppState::new();
+    let config = Config::from_env();
+    trace::init(&config);
+
+    let addr = config.addr;
+    let tls_config = config.tls.clone();
+
+    let storage = storage::open(&config).await;
+    let state = AppState::new(config, storage);
     state.create_dirs().expect("Couldn't set up directories");
 
-    let removed = file::clean_files(&state).await.unwrap();
-    println!("Removed {} files", removed);
+    state.capabilities.report();
+    if let Some(rendition) = state.capabilities.missing_encoding(&state.config.renditions) {
+        panic!("libvips can't encode the {} rendition as {}", rendition.name, rendition.encoding.mime());
+    }
 
-    delete::Command::restore(&state)
-        .expect("Failed to restore pending deletions");
+    migrate::run(&state).expect("Failed to migrate the database");
+    rekey::check(&state).expect("Failed to check the storage key");
 
-    let router = Router::builder()
-        .middleware(query_parser())
-        .middleware(Middleware::pre(logger))
-        // Provide app state to routes
-        .data(state)
-        // Routes
-        .scope("/user", user::router())
-        .scope("/file", file::router())
-        .scope("/album", album::router())
-        // Not found for invalid paths
-        .any(|_| async { Err(ApiError::NotFound) })
-        .err_handler(handle_error)
-        .build()
-        .unwrap();
+    let removed = clean::clean_temp(&state).await.unwrap();
+    info!("Removed {} partial uploads", removed);
+
+    album::clean(&state).expect("Failed to prune album history");
+    library::clean(&state).expect("Failed to prune library history");
+
+    let expired = user::clean_tokens(&state).expect("Failed to clean tokens");
+    info!("Removed {} expired tokens", expired);
+
+    let removed = bandwidth::clean(&state).expect("Failed to clean bandwidth counters");
+    info!("Removed {} bandwidth counters of earlier days", removed);
 
-    let service = RouterService::new(router).unwrap();
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    jobs::spawn_workers(&state);
 
-    let server = Server::bind(&addr)
-        .serve(service)
-        .with_graceful_shutdown(shutdown_signal());
+    tokio::spawn(trash::sweeper(state.clone()));
+    tokio::spawn(album::gc::collector(state.clone()));
+    tokio::spawn(clean::cleaner(state.clone()));
+    tokio::spawn(verify::scheduler(state.clone()));
 
-    println!("Running on: {}", addr);
-    if let Err(err) = server.await {
-        eprintln!("Server error: {}", err);
+    if let Some(metrics_addr) = state.config.metrics_addr {
+        let listener = metrics::listen(state.clone(), metrics_addr, shutdown_signal());
+        tokio::spawn(async move {
+            if let Err(err) = listener.await {
+                error!("Metrics server error: {}", err);
+            }
+        });
+        info!("Serving metrics: http://{}/metrics", metrics_addr);
     }
-    println!("\rShutting down...");
 
-    drop(vips);
+    let metrics = state.metrics.clone();
+    let header_timeout = Duration::from_secs(state.config.header_timeout_seconds);
+    let router = build_router(state);
+
+    match tls_config {
+        None => {
+            let builder = Mutex::new(RequestServiceBuilder::new(router).unwrap());
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let remote_addr = conn.remote_addr();
+                let service = builder.lock().unwrap().build(remote_addr);
+                future::ok::<_, Infallible>(trace::Traced::new(service, remote_addr, metrics.clone()))
+            });
+
+            let server = Server::bind(&addr)
+                .http1_header_read_timeout(header_timeout)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown_signal());
+
+            info!("Running on: http://{}", addr);
+            if let Err(err) = server.await {
+                error!("Server error: {}", err);
+            }
+        }
+        Some(tls_config) => {
+            let acceptor = tls::load_acceptor(&tls_config).expect("Couldn't load TLS certificate");
+
+            if let Some(redirect_addr) = tls_config.redirect_addr {
+                tokio::spawn(async move {
+                    let redirect = tls::serve_redirect(redirect_addr, addr.port(), header_timeout, shutdown_signal());
+                    if let Err(err) = redirect.await {
+                        error!("Redirect server error: {}", err);
+                    }
+                });
+                info!("Redirecting: http://{}", redirect_addr);
+            }
+
+            info!("Running on: https://{}", addr);
+            if let Err(err) = tls::serve(router, metrics, addr, acceptor, header_timeout, shutdown_signal()).await {
+                error!("Server error: {}", err);
+            }
+        }
+    }
+    // libvips shuts down when `vips` goes out of scope.
+    warn!("Shutting down...");
 }
@@ -0,0 +1,230 @@
+//! Measuring Stored Files
+//!
+//! Uploads record the size and hash of their original and the size of every rendition, which
+//! quotas, statistics and clients go by. Files from before those were recorded are measured by a
+//! `Measure` job, which a migration leaves, and which reads their stored objects instead of
+//! rendering them again like regeneration would. What it finds is counted into the statistics of
+//! the owner and copied into the entries of their library and of the albums that hold the file.
+//!
+//! Only files that are missing something are read, so the job can pick up where it stopped.
+//! Files in the trash are left as they are.
+
+use crate::{
+    album::engine::Engine,
+    common::{AppState, File},
+    config::Config,
+    error::ApiResult,
+    events, library, stats,
+    storage::{self, Storage},
+};
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use sled::Transactional;
+use std::io;
+use tracing::{info, warn};
+use wire::{Album, AlbumEvent};
+
+/// Whether `file` is missing something that its stored objects tell.
+fn unmeasured(file: &File) -> bool {
+    file.size.is_none() || file.hash.is_none() || (file.rendition_sizes.is_empty() && !file.encrypted)
+}
+
+/// Kinds that uploads record the size of, in the order that they do.
+fn rendition_kinds(config: &Config) -> Vec<String> {
+    let mut kinds: Vec<_> = config.renditions.iter().map(|rendition| rendition.name.clone()).collect();
+    if let Some(tallest) = config.renditions.first() {
+        kinds.push(storage::animated_kind(&tallest.name));
+    }
+    kinds.push(storage::PREVIEW.to_string());
+
+    kinds
+}
+
+/// Size and hash of the object under `key`, or `None` if it isn't stored.
+pub async fn measure(storage: &dyn Storage, key: &str) -> io::Result<Option<(u64, [u8; 32])>> {
+    let mut stream = match storage.get_stream(key).await {
+        Ok(stream) => stream,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let (mut size, mut hasher) = (0, Sha256::new());
+    while let Some(chunk) = stream.try_next().await? {
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+
+    Ok(Some((size, hasher.finalize().into())))
+}
+
+/// Measure every file that is missing its size, hash or rendition sizes.
+pub fn run(state: &AppState) -> ApiResult<()> {
+    // Collect the ids first so that no database iterator is held across awaits.
+    let mut file_ids = vec![];
+    for entry in state.files.iter() {
+        let (file_id, file_bytes) = entry?;
+        if unmeasured(&bincode::deserialize(&file_bytes).unwrap()) {
+            file_ids.push(String::from_utf8(file_id.to_vec()).unwrap());
+        }
+    }
+
+    let handle = tokio::runtime::Handle::current();
+    let storage = state.storage.as_ref();
+    let mut measured = 0;
+
+    for file_id in &file_ids {
+        let (size, hash) = match handle.block_on(measure(storage, &storage::key(storage::ORIGINAL, file_id)))? {
+            Some(original) => original,
+            None => {
+                warn!("The original of {} is missing, so it can't be measured", file_id);
+                continue;
+            }
+        };
+
+        let mut rendition_sizes = vec![];
+        for kind in rendition_kinds(&state.config) {
+            if let Some((size, _)) = handle.block_on(measure(storage, &storage::key(&kind, file_id)))? {
+                rendition_sizes.push((kind, size));
+            }
+        }
+
+        if record(state, file_id, size, hash, rendition_sizes)? {
+            measured += 1;
+        }
+    }
+
+    info!("Measured {} files", measured);
+    Ok(())
+}
+
+/// Fill in what `file_id` is missing, returning whether it still was.
+fn record(
+    state: &AppState,
+    file_id: &str,
+    size: u64,
+    hash: [u8; 32],
+    rendition_sizes: Vec<(String, u64)>,
+) -> ApiResult<bool> {
+    let AppState {
+        ref files,
+        ref inclusions,
+        ref albums,
+        ref fragments,
+        ref libraries,
+        ref library_fragments,
+        ref stats,
+        ..
+    } = state;
+
+    let mut album_ids = vec![];
+    for entry in inclusions.scan_prefix([file_id, "."].concat()) {
+        let (inclusion, _) = entry?;
+        let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+        album_ids.push(album_id.to_string());
+    }
+
+    let trees = (files, albums, fragments, libraries, library_fragments, stats);
+    let published = trees.transaction(|(files, albums, fragments, libraries, library_fragments, stats_tree)| {
+        let file_bytes = match files.get(file_id)? {
+            Some(file_bytes) => file_bytes,
+            None => return Ok(None),
+        };
+        let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+        if !unmeasured(&file) {
+            return Ok(None);
+        }
+
+        stats::count(stats_tree, &file, false)?;
+        file.size.get_or_insert(size);
+        file.hash.get_or_insert(hash);
+        if file.rendition_sizes.is_empty() && !file.encrypted {
+            file.rendition_sizes = rendition_sizes.clone();
+        }
+        files.insert(file_id, bincode::serialize(&file).unwrap())?;
+        stats::count(stats_tree, &file, true)?;
+
+        let mut published = vec![];
+        if file.in_library() {
+            let library_head = library::modify(libraries, library_fragments, file.owner_id, |e| {
+                e.set_appearance(file_id, &file)
+            })?;
+            published.push(AlbumEvent::LibraryUpdated {
+                user_id: file.owner_id.to_string(),
+                fragment_head: library_head,
+            });
+        }
+        for album_id in &album_ids {
+            let album_bytes = match albums.get(album_id.as_bytes())? {
+                Some(album_bytes) => album_bytes,
+                None => continue,
+            };
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            let mut e = Engine::new(album_id, &mut album, fragments)?;
+            e.set_appearance(file_id, &file)?;
+            e.commit()?;
+
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+            published.push(AlbumEvent::Updated {
+                album_id: album_id.to_string(),
+                fragment_head: album.fragment_head,
+            });
+        }
+
+        Ok(Some(published))
+    })?;
+
+    let found = published.is_some();
+    for event in published.into_iter().flatten() {
+        events::publish(&state.events, event);
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::Method;
+    use serde_json::Value;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn measure_old_files() {
+        use crate::common::File;
+        use sled::Transactional;
+
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let file_id = server.upload(&key, "old.png", png(8, 8)).await;
+
+        let info_path = format!("/file/{}?key={}", file_id, key);
+        let stats_path = format!("/user/stats?key={}", key);
+        let info = server.json(Method::GET, &info_path, &()).await;
+        let stats = server.json(Method::GET, &stats_path, &()).await;
+        assert!(info["size"].as_u64().unwrap() > 0);
+
+        // Forget what was recorded on upload, like for files from before it was.
+        let state = &server.state;
+        (&state.files, &state.stats)
+            .transaction(|(files, stats)| {
+                let file_bytes = files.get(&file_id)?.unwrap();
+                let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+                crate::stats::count(stats, &file, false)?;
+                file.size = None;
+                file.hash = None;
+                file.rendition_sizes.clear();
+                files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+                crate::stats::count(stats, &file, true)
+            })
+            .unwrap();
+        assert_eq!(server.json(Method::GET, &info_path, &()).await["size"], Value::Null);
+
+        tokio::task::block_in_place(|| crate::measure::run(state)).unwrap();
+        assert_eq!(server.json(Method::GET, &info_path, &()).await, info);
+        assert_eq!(server.json(Method::GET, &stats_path, &()).await, stats);
+
+        // Measuring again finds nothing left to do.
+        tokio::task::block_in_place(|| crate::measure::run(state)).unwrap();
+        assert_eq!(server.json(Method::GET, &stats_path, &()).await, stats);
+    }
+}
@@ -0,0 +1,106 @@
+//! Memories
+//!
+//! `GET /memories` looks back at what a user took on this day in earlier years. Libraries are
+//! split into a section for every day, so the sections that fall on the same month and day are
+//! picked out of the top of the library, and only those are read. Their entries are sent the way
+//! that they are laid out in fragments, so that clients can draw them with the same code.
+
+use crate::{
+    album::engine::Engine,
+    common::{require_key, respond_ok, test_logged_in, AppState},
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+};
+use chrono::{offset::Utc, DateTime, Datelike, NaiveDate};
+use hyper::{Body, Request, Response};
+use routerify::{ext::RequestExt, Router};
+use routerify_query::RequestQueryExt;
+use tokio::task::block_in_place;
+use wire::{endpoint, Album};
+
+async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let today = match req.query("date") {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| ApiError::BadRequest)?,
+        None => Utc::now().date_naive(),
+    };
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref libraries,
+            ref library_fragments,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let library_bytes = match libraries.get(user_id)? {
+            Some(library_bytes) => library_bytes,
+            None => return respond_ok(Vec::<()>::new()),
+        };
+
+        let on_this_day = |section: i64| match DateTime::<Utc>::from_timestamp(section, 0) {
+            Some(day) => day.year() < today.year() && (day.month(), day.day()) == (today.month(), today.day()),
+            None => false,
+        };
+        let sections = library_fragments.transaction(|fragments| {
+            let mut library: Album = bincode::deserialize(&library_bytes).unwrap();
+            let e = Engine::new(user_id, &mut library, fragments)?;
+            e.section_entries(on_this_day)
+        })?;
+
+        // Each day is a section of its own, so there is one section for every year.
+        let memories: Vec<_> = sections
+            .into_iter()
+            .rev()
+            .map(|(section, entries)| (DateTime::<Utc>::from_timestamp(section, 0).unwrap().year(), entries))
+            .collect();
+
+        respond_ok(memories)
+    })
+}
+
+pub const SCOPE: &str = "/memories";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::Memories, serve)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn memories() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let path = |key: &str, date: &str| format!("/memories?date={}&key={}", date, key);
+        assert_eq!(server.json(Method::GET, &path(&key, "1971-01-01"), &()).await, json!([]));
+
+        // Test uploads were all taken at the start of 1970.
+        let file_id = server.upload(&key, "new-year.png", png(64, 48)).await;
+        let memories = server.json(Method::GET, &path(&key, "1971-01-01"), &()).await;
+        assert_eq!(memories[0][0], 1970);
+        assert_eq!(memories[0][1][0][1], file_id.as_str());
+        assert_eq!(memories.as_array().unwrap().len(), 1);
+
+        // Only earlier years on the same day count, and only the user's own files.
+        assert_eq!(server.json(Method::GET, &path(&key, "1970-01-01"), &()).await, json!([]));
+        assert_eq!(server.json(Method::GET, &path(&key, "1971-01-02"), &()).await, json!([]));
+        assert_eq!(server.json(Method::GET, &path(&other, "1971-01-01"), &()).await, json!([]));
+
+        let (status, _) = server.request(Method::GET, &path(&key, "January"), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,289 @@
+//! Metrics
+//!
+//! Counters that are kept in memory and rendered in the Prometheus text format by `GET /metrics`,
+//! which only administrators may read. Scrapers that can't log in can instead be pointed at
+//! `PHOTOS_METRICS_ADDR`, a listener of its own that serves the same text without authentication
+//! and is meant to be bound to an address that only they can reach.
+//!
+//! Requests are counted by the `wire::endpoint` route that they match rather than by their path,
+//! so that ids don't make a series each, and anything else is counted as `other`. Their latency
+//! runs until the response head is ready, which leaves out the body of streamed responses. The
+//! sizes of the trees, the queued jobs and the sessions are read from the database when scraped.
+
+use crate::{
+    admin::require_admin,
+    common::AppState,
+    error::{ApiError, ApiResult},
+};
+use futures::future;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use routerify::ext::RequestExt;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::block_in_place;
+use wire::endpoint::ROUTES;
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations at or below each of `BUCKETS`.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter_mut()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, bucket);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Route {
+    statuses: BTreeMap<u16, u64>,
+    latency: Histogram,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Keyed by method and route.
+    routes: Mutex<BTreeMap<(&'static str, &'static str), Route>>,
+    uploads: AtomicU64,
+    upload_bytes: AtomicU64,
+    processing: Mutex<Histogram>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, method: &hyper::Method, path: &str, status: u16, latency: Duration) {
+        let key = route(method, path).unwrap_or(("other", "other"));
+
+        let mut routes = self.routes.lock().unwrap();
+        let route = routes.entry(key).or_default();
+        *route.statuses.entry(status).or_default() += 1;
+        route.latency.observe(latency);
+    }
+
+    /// Count a file that was stored.
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        self.upload_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Time that libvips took to render a file.
+    pub fn record_processing(&self, duration: Duration) {
+        self.processing.lock().unwrap().observe(duration);
+    }
+
+    fn render(&self, out: &mut String) {
+        let routes = self.routes.lock().unwrap();
+
+        header(out, "photos_http_requests_total", "counter", "Requests by route and status.");
+        for ((method, path), route) in routes.iter() {
+            for (status, count) in &route.statuses {
+                let _ = writeln!(
+                    out,
+                    "photos_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method, path, status, count
+                );
+            }
+        }
+
+        let name = "photos_http_request_duration_seconds";
+        header(out, name, "histogram", "Time until the response head was ready.");
+        for ((method, path), route) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, path);
+            route.latency.render(out, name, &labels);
+        }
+        drop(routes);
+
+        header(out, "photos_uploads_total", "counter", "Files that were stored.");
+        let _ = writeln!(out, "photos_uploads_total {}", self.uploads.load(Ordering::Relaxed));
+        header(out, "photos_upload_bytes_total", "counter", "Size of the files that were stored.");
+        let _ = writeln!(out, "photos_upload_bytes_total {}", self.upload_bytes.load(Ordering::Relaxed));
+
+        let name = "photos_processing_duration_seconds";
+        header(out, name, "histogram", "Time that libvips took to render a file.");
+        self.processing.lock().unwrap().render(out, name, "");
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// The method and path of the endpoint that a request is for. Literal segments win over
+/// parameters, so that `/file/list` isn't taken for a file id.
+fn route(method: &hyper::Method, path: &str) -> Option<(&'static str, &'static str)> {
+    let segments: Vec<&str> = path.split('/').collect();
+
+    ROUTES
+        .iter()
+        .filter(|(endpoint_method, _)| endpoint_method.as_str() == method.as_str())
+        .filter_map(|(endpoint_method, pattern)| {
+            let pattern_segments: Vec<&str> = pattern.split('/').collect();
+            if pattern_segments.len() != segments.len() {
+                return None;
+            }
+
+            let mut params = 0;
+            for (pattern_segment, segment) in pattern_segments.iter().zip(segments.iter()) {
+                if pattern_segment.starts_with(':') {
+                    params += 1;
+                } else if pattern_segment != segment {
+                    return None;
+                }
+            }
+
+            Some((params, endpoint_method.as_str(), *pattern))
+        })
+        .min_by_key(|(params, _, _)| *params)
+        .map(|(_, method, pattern)| (method, pattern))
+}
+
+/// Every metric, including those that are read from the database.
+pub fn render(state: &AppState) -> ApiResult<String> {
+    let mut out = String::new();
+    state.metrics.render(&mut out);
+
+    header(&mut out, "photos_tree_entries", "gauge", "Entries in each database tree.");
+    for name in state.db.tree_names() {
+        let tree = state.db.open_tree(&name)?;
+        let name = String::from_utf8_lossy(&name);
+        let _ = writeln!(out, "photos_tree_entries{{tree=\"{}\"}} {}", name, tree.len());
+    }
+
+    header(&mut out, "photos_db_size_bytes", "gauge", "Size of the database on disk.");
+    let _ = writeln!(out, "photos_db_size_bytes {}", state.db.size_on_disk()?);
+
+    header(&mut out, "photos_jobs_queued", "gauge", "Background jobs that are recorded, including failed ones.");
+    let _ = writeln!(out, "photos_jobs_queued {}", state.jobs.len());
+    header(&mut out, "photos_jobs_running", "gauge", "Background jobs that are running.");
+    let _ = writeln!(out, "photos_jobs_running {}", state.job_queue.running());
+
+    header(&mut out, "photos_sessions", "gauge", "Sessions that are logged in.");
+    let _ = writeln!(out, "photos_sessions {}", state.sessions.len());
+
+    Ok(out)
+}
+
+fn respond(text: String) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, CONTENT_TYPE)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+pub async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    Ok(respond(block_in_place(|| render(state))?))
+}
+
+async fn serve_open(state: &AppState, req: Request<Body>) -> ApiResult<Response<Body>> {
+    if req.method() != hyper::Method::GET || req.uri().path() != "/metrics" {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(respond(block_in_place(|| render(state))?))
+}
+
+/// Serve `GET /metrics` without authentication on `addr` until `shutdown` resolves.
+pub async fn listen(state: AppState, addr: SocketAddr, shutdown: impl Future<Output = ()>) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        future::ok::<_, Infallible>(service_fn(move |req| {
+            let state = state.clone();
+            async move {
+                let response = serve_open(&state, req).await.unwrap_or_else(|err| {
+                    let status = match err {
+                        ApiError::NotFound => StatusCode::NOT_FOUND,
+                        _ => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    Response::builder().status(status).body(Body::empty()).unwrap()
+                });
+                Ok::<_, Infallible>(response)
+            }
+        }))
+    });
+
+    Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::Method;
+    use crate::testing::TestServer;
+    use hyper::{Body, StatusCode};
+
+    #[test]
+    fn match_routes() {
+        assert_eq!(route(&Method::POST, "/file/list"), Some(("POST", "/file/list")));
+        assert_eq!(route(&Method::DELETE, "/file/abc"), Some(("DELETE", "/file/:fileId")));
+        assert_eq!(route(&Method::GET, "/file/geo"), Some(("GET", "/file/geo")));
+        assert_eq!(route(&Method::GET, "/file/medium/abc"), Some(("GET", "/file/:quality/:fileId")));
+        assert_eq!(route(&Method::GET, "/nowhere"), None);
+        assert_eq!(route(&Method::PUT, "/file/list"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metrics() {
+        let server = TestServer::start().await;
+        let key = server.signup("user@example.com").await;
+        let admin_key = server.signup_admin().await;
+
+        let (status, _) = server.request(Method::GET, "/limits", &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = server
+            .request(Method::GET, &format!("/metrics?key={}", key), &[], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = server
+            .request(Method::GET, &format!("/metrics?key={}", admin_key), &[], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("photos_http_requests_total{method=\"GET\",route=\"/limits\",status=\"200\"} 1"));
+        assert!(text.contains("photos_http_requests_total{method=\"GET\",route=\"/metrics\",status=\"401\"} 1"));
+        assert!(text.contains("photos_sessions 2"));
+    }
+}
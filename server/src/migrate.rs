@@ -0,0 +1,1486 @@
+//! Database Migrations
+//!
+//! The layout version of the database is stored under `schema_version` in the default tree.
+//! `MIGRATIONS[n]` upgrades a database from version `n` to `n + 1`, so the current version is the
+//! number of migrations. A migration may be interrupted part way through, so migrations that
+//! rewrite records in place keep track of their progress in a tree that is dropped once the
+//! migration completes.
+
+use crate::album::engine::Engine;
+use crate::common::{AppState, File, Session};
+use crate::delete;
+use crate::jobs::{Job, Schedule};
+use crate::error::ApiResult;
+use crate::placeholder::Placeholder;
+use chrono::offset::Utc;
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+use std::borrow::Cow;
+use tracing::info;
+use wire::{
+    Album, AlbumSettings, Animation, FileEdit, FileLabels, FileMetadata, Location, LoginAttempt, Role, SortMode,
+};
+
+const SCHEMA_VERSION: &[u8] = b"schema_version";
+const PROGRESS: &[u8] = b"migration_progress";
+
+/// A migration receives the state and its own progress tree.
+type Migration = fn(&AppState, &sled::Tree) -> ApiResult<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    owner_to_roles,
+    upload_time,
+    sort_mode,
+    album_description,
+    detected_mime,
+    placeholder,
+    location,
+    library,
+    jobs,
+    session_records,
+    session_metadata,
+    section_parts,
+    file_size,
+    file_hash,
+    rendition_sizes,
+    stats,
+    encrypted_flag,
+    animation,
+    edits,
+    live_pairs,
+    labels,
+    phashes,
+    album_rules,
+    hidden_flag,
+    locked_flag,
+    video_details,
+    measure_files,
+    month_summaries,
+    lock_flag,
+];
+
+fn get_version(state: &AppState) -> ApiResult<u32> {
+    match state.db.get(SCHEMA_VERSION)? {
+        Some(bytes) => Ok(bincode::deserialize(&bytes).unwrap()),
+        // Databases from before versioning existed always have users, while a new database
+        // starts out at the current layout.
+        None if state.users.is_empty() => Ok(MIGRATIONS.len() as u32),
+        None => Ok(0),
+    }
+}
+
+fn set_version(state: &AppState, version: u32) -> ApiResult<()> {
+    state
+        .db
+        .insert(SCHEMA_VERSION, bincode::serialize(&version).unwrap())?;
+    state.db.flush()?;
+    Ok(())
+}
+
+/// Bring the database up to the layout that this server expects.
+pub fn run(state: &AppState) -> ApiResult<()> {
+    let current = MIGRATIONS.len() as u32;
+    let mut version = get_version(state)?;
+
+    if version > current {
+        panic!(
+            "Database schema version {} is newer than this server supports ({})",
+            version, current
+        );
+    }
+
+    while version < current {
+        info!("Migrating database from version {} to {}", version, version + 1);
+        let progress_name = [PROGRESS, b".", &version.to_be_bytes()].concat();
+        let progress = state.db.open_tree(&progress_name)?;
+
+        MIGRATIONS[version as usize](state, &progress)?;
+        version += 1;
+        set_version(state, version)?;
+
+        state.db.drop_tree(&progress_name)?;
+    }
+
+    set_version(state, version)
+}
+
+/// Album settings from before albums had a sort mode.
+#[derive(Serialize, Deserialize)]
+struct UnsortedSettings<'a> {
+    #[serde(borrow)]
+    name: Cow<'a, str>,
+    time_zone: chrono_tz::Tz,
+}
+
+/// Album layout from before sharing, when every album had exactly one owner.
+#[derive(Serialize, Deserialize)]
+struct OwnedAlbum<'a> {
+    owner_id: Cow<'a, str>,
+    #[serde(borrow)]
+    description: UnsortedSettings<'a>,
+    fragment_head: u64,
+    length: usize,
+    last_update: i64,
+    date_range: Option<(i64, i64)>,
+}
+
+/// Move album ownership out of the album record and into the `user_to_album` role model.
+fn owner_to_roles(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    let AppState {
+        ref albums,
+        ref user_to_album,
+        ref album_to_user,
+        ..
+    } = state;
+
+    for entry in albums.iter() {
+        let (album_id, album_bytes) = entry?;
+
+        if progress.contains_key(&album_id)? {
+            continue;
+        }
+
+        let old: OwnedAlbum = bincode::deserialize(&album_bytes).unwrap();
+
+        let album = UnsortedAlbum {
+            description: old.description,
+            fragment_head: old.fragment_head,
+            length: old.length,
+            last_update: old.last_update,
+            date_range: old.date_range,
+        };
+
+        let album_id = std::str::from_utf8(&album_id).unwrap();
+        let owner_id: &str = &old.owner_id;
+        let role_bytes = bincode::serialize(&Role::Owner).unwrap();
+
+        (albums, user_to_album, album_to_user, progress).transaction(
+            |(albums, user_to_album, album_to_user, progress)| {
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                progress.insert(album_id.as_bytes(), b"")?;
+                user_to_album.insert([owner_id, ".", album_id].concat().as_bytes(), role_bytes.clone())?;
+                album_to_user.insert([album_id, ".", owner_id].concat().as_bytes(), b"")?;
+
+                Ok(())
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Album layout from before albums had a sort mode.
+#[derive(Serialize, Deserialize)]
+struct UnsortedAlbum<'a> {
+    #[serde(borrow)]
+    description: UnsortedSettings<'a>,
+    fragment_head: u64,
+    length: usize,
+    last_update: i64,
+    date_range: Option<(i64, i64)>,
+}
+
+/// File layout from before the upload time was recorded.
+#[derive(Serialize, Deserialize)]
+struct UntimedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+/// File layout from before the contents of uploads were sniffed.
+#[derive(Serialize, Deserialize)]
+struct UnsniffedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+/// File layout from before placeholders were made for uploads.
+#[derive(Serialize, Deserialize)]
+struct UnhashedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+/// File layout from before the locations of photos were read.
+#[derive(Serialize, Deserialize)]
+struct UnlocatedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+/// File layout from before the sizes of uploads were recorded.
+#[derive(Serialize, Deserialize)]
+struct UnsizedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnsizedFile<'a, 'b, 'c> {
+    fn sized(self, size: Option<u64>) -> UndigestedFile<'a, 'b, 'c> {
+        UndigestedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before the contents of uploads were hashed.
+#[derive(Serialize, Deserialize)]
+struct UndigestedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UndigestedFile<'a, 'b, 'c> {
+    fn digested(self, hash: Option<[u8; 32]>) -> UnmeasuredFile<'a, 'b, 'c> {
+        UnmeasuredFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before the sizes of renditions were recorded.
+#[derive(Serialize, Deserialize)]
+struct UnmeasuredFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnmeasuredFile<'a, 'b, 'c> {
+    fn measured(self, rendition_sizes: Vec<(String, u64)>) -> UnflaggedFile<'a, 'b, 'c> {
+        UnflaggedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before clients could encrypt files.
+#[derive(Serialize, Deserialize)]
+struct UnflaggedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnflaggedFile<'a, 'b, 'c> {
+    fn flagged(self) -> UnanimatedFile<'a, 'b, 'c> {
+        UnanimatedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: false,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before animated images were recognized.
+#[derive(Serialize, Deserialize)]
+struct UnanimatedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnanimatedFile<'a, 'b, 'c> {
+    fn still(self) -> UneditedFile<'a, 'b, 'c> {
+        UneditedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: None,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before files could be edited.
+#[derive(Serialize, Deserialize)]
+struct UneditedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UneditedFile<'a, 'b, 'c> {
+    fn unedited(self) -> UnpairedFile<'a, 'b, 'c> {
+        UnpairedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: FileEdit::default(),
+            revision: 0,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before Live Photos were paired.
+#[derive(Serialize, Deserialize)]
+struct UnpairedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnpairedFile<'a, 'b, 'c> {
+    fn unpaired(self) -> UnlabeledFile<'a, 'b, 'c> {
+        UnlabeledFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: None,
+            still_id: None,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before files were labeled from XMP sidecars.
+#[derive(Serialize, Deserialize)]
+struct UnlabeledFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnlabeledFile<'a, 'b, 'c> {
+    fn unlabeled(self) -> UnfingerprintedFile<'a, 'b, 'c> {
+        UnfingerprintedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: FileLabels::default(),
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before images were hashed perceptually.
+#[derive(Serialize, Deserialize)]
+struct UnfingerprintedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    labels: FileLabels<'static>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnfingerprintedFile<'a, 'b, 'c> {
+    fn unfingerprinted(self) -> CameralessFile<'a, 'b, 'c> {
+        CameralessFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            phash: None,
+            location: self.location,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: self.labels,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before files recorded the camera that took them.
+#[derive(Serialize, Deserialize)]
+struct CameralessFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    phash: Option<u64>,
+    location: Option<Location>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    labels: FileLabels<'static>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> CameralessFile<'a, 'b, 'c> {
+    fn cameraless(self) -> UnhiddenFile<'a, 'b, 'c> {
+        UnhiddenFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            phash: self.phash,
+            location: self.location,
+            camera: None,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: self.labels,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before files could be hidden from the library.
+#[derive(Serialize, Deserialize)]
+struct UnhiddenFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    phash: Option<u64>,
+    location: Option<Location>,
+    camera: Option<String>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    labels: FileLabels<'static>,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnhiddenFile<'a, 'b, 'c> {
+    fn unhidden(self) -> UnlockedFile<'a, 'b, 'c> {
+        UnlockedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            phash: self.phash,
+            location: self.location,
+            camera: self.camera,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: self.labels,
+            hidden: false,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before files could be locked.
+#[derive(Serialize, Deserialize)]
+struct UnlockedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    phash: Option<u64>,
+    location: Option<Location>,
+    camera: Option<String>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    labels: FileLabels<'static>,
+    hidden: bool,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnlockedFile<'a, 'b, 'c> {
+    fn unlocked(self) -> UnprobedFile<'a, 'b, 'c> {
+        UnprobedFile {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            phash: self.phash,
+            location: self.location,
+            camera: self.camera,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: self.labels,
+            hidden: self.hidden,
+            locked: false,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// File layout from before videos were probed.
+#[derive(Serialize, Deserialize)]
+struct UnprobedFile<'a, 'b, 'c> {
+    owner_id: &'a str,
+    width: i32,
+    height: i32,
+    uploaded: i64,
+    detected_mime: &'a str,
+    placeholder: Option<Placeholder>,
+    phash: Option<u64>,
+    location: Option<Location>,
+    camera: Option<String>,
+    size: Option<u64>,
+    hash: Option<[u8; 32]>,
+    rendition_sizes: Vec<(String, u64)>,
+    encrypted: bool,
+    animation: Option<Animation>,
+    edit: FileEdit,
+    revision: u32,
+    motion_id: Option<String>,
+    still_id: Option<String>,
+    labels: FileLabels<'static>,
+    hidden: bool,
+    locked: bool,
+    #[serde(borrow)]
+    metadata: FileMetadata<'b, 'c>,
+}
+
+impl<'a, 'b, 'c> UnprobedFile<'a, 'b, 'c> {
+    fn unprobed(self) -> File<'a, 'b, 'c> {
+        File {
+            owner_id: self.owner_id,
+            width: self.width,
+            height: self.height,
+            uploaded: self.uploaded,
+            detected_mime: self.detected_mime,
+            placeholder: self.placeholder,
+            phash: self.phash,
+            location: self.location,
+            camera: self.camera,
+            size: self.size,
+            hash: self.hash,
+            rendition_sizes: self.rendition_sizes,
+            encrypted: self.encrypted,
+            animation: self.animation,
+            video: None,
+            edit: self.edit,
+            revision: self.revision,
+            motion_id: self.motion_id,
+            still_id: self.still_id,
+            labels: self.labels,
+            hidden: self.hidden,
+            locked: self.locked,
+            metadata: self.metadata,
+        }
+    }
+}
+
+/// Album settings from before albums had rules.
+#[derive(Serialize, Deserialize)]
+struct UnruledSettings<'a> {
+    #[serde(borrow)]
+    name: Cow<'a, str>,
+    time_zone: chrono_tz::Tz,
+    sort: SortMode,
+    #[serde(borrow)]
+    description: Cow<'a, str>,
+}
+
+/// Album layout from before albums had rules.
+#[derive(Serialize, Deserialize)]
+struct UnruledAlbum<'a> {
+    #[serde(borrow)]
+    description: UnruledSettings<'a>,
+    fragment_head: u64,
+    length: usize,
+    last_update: i64,
+    date_range: Option<(i64, i64)>,
+}
+
+impl<'a> UnruledAlbum<'a> {
+    fn unruled(self) -> Album<'a> {
+        Album {
+            description: AlbumSettings {
+                name: self.description.name,
+                time_zone: self.description.time_zone,
+                sort: self.description.sort,
+                description: self.description.description,
+                rules: vec![],
+            },
+            fragment_head: self.fragment_head,
+            length: self.length,
+            last_update: self.last_update,
+            date_range: self.date_range,
+        }
+    }
+}
+
+/// Trash entry layout, with its file in the layout `F` and its album in the layout `A` of the
+/// time.
+#[derive(Serialize, Deserialize)]
+struct TrashedLayout<F, A> {
+    deleted: i64,
+    item: ItemLayout<F, A>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum ItemLayout<F, A> {
+    File {
+        file: F,
+        albums: Vec<(String, Option<String>)>,
+    },
+    Album {
+        album: A,
+        members: Vec<(String, Role)>,
+    },
+}
+
+impl<F, A> TrashedLayout<F, A> {
+    fn map<G, B>(self, f: impl FnOnce(F) -> G, g: impl FnOnce(A) -> B) -> TrashedLayout<G, B> {
+        let item = match self.item {
+            ItemLayout::File { file, albums } => ItemLayout::File { file: f(file), albums },
+            ItemLayout::Album { album, members } => ItemLayout::Album { album: g(album), members },
+        };
+        TrashedLayout {
+            deleted: self.deleted,
+            item,
+        }
+    }
+
+    fn map_file<G>(self, f: impl FnOnce(F) -> G) -> TrashedLayout<G, A> {
+        self.map(f, |album| album)
+    }
+}
+
+/// Job layout, with the file of a deletion in the layout `F` of the time.
+#[derive(Serialize, Deserialize)]
+enum JobLayout<'a, F> {
+    Delete(#[serde(borrow)] CommandLayout<'a, F>),
+    Rebuild(#[serde(borrow)] Cow<'a, str>),
+    Reencrypt,
+    Detect(#[serde(borrow)] Cow<'a, str>),
+    Measure,
+    Verify,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CommandLayout<'a, F> {
+    Album(&'a str),
+    File(&'a str, F),
+    User(&'a str),
+}
+
+impl<'a, F> JobLayout<'a, F> {
+    fn map_file<G>(self, f: impl FnOnce(F) -> G) -> JobLayout<'a, G> {
+        match self {
+            JobLayout::Delete(CommandLayout::Album(album_id)) => JobLayout::Delete(CommandLayout::Album(album_id)),
+            JobLayout::Delete(CommandLayout::File(file_id, file)) => {
+                JobLayout::Delete(CommandLayout::File(file_id, f(file)))
+            }
+            JobLayout::Delete(CommandLayout::User(user_id)) => JobLayout::Delete(CommandLayout::User(user_id)),
+            JobLayout::Rebuild(album_id) => JobLayout::Rebuild(album_id),
+            JobLayout::Reencrypt => JobLayout::Reencrypt,
+            JobLayout::Detect(file_id) => JobLayout::Detect(file_id),
+            JobLayout::Measure => JobLayout::Measure,
+            JobLayout::Verify => JobLayout::Verify,
+        }
+    }
+}
+
+/// Rewrite every record in `tree` with `f`, skipping records that an interrupted run already
+/// rewrote.
+fn rewrite<F>(tree: &sled::Tree, progress: &sled::Tree, f: F) -> ApiResult<()>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    rewrite_tagged(tree, progress, b"", f)
+}
+
+/// Like `rewrite`, but with the keys in `progress` prefixed by `tag`, so that a migration can
+/// rewrite several trees whose keys might overlap.
+fn rewrite_tagged<F>(tree: &sled::Tree, progress: &sled::Tree, tag: &[u8], f: F) -> ApiResult<()>
+where
+    F: Fn(&[u8]) -> Vec<u8>,
+{
+    for entry in tree.iter() {
+        let (key, bytes) = entry?;
+        let progress_key = [tag, &key].concat();
+
+        if progress.contains_key(&progress_key)? {
+            continue;
+        }
+
+        let new_bytes = f(&bytes);
+
+        (tree, progress).transaction(|(tree, progress)| {
+            tree.insert(&key, new_bytes.as_slice())?;
+            progress.insert(progress_key.as_slice(), b"")?;
+
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Record an upload time for every file. The real upload time was never stored, so the capture
+/// time stands in for it.
+fn upload_time(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.files, progress, |bytes| {
+        let old: UntimedFile = bincode::deserialize(bytes).unwrap();
+
+        let file = UnsniffedFile {
+            owner_id: old.owner_id,
+            width: old.width,
+            height: old.height,
+            uploaded: old.metadata.last_modified,
+            metadata: old.metadata,
+        };
+
+        bincode::serialize(&file).unwrap()
+    })
+}
+
+/// Album settings from before albums had a description.
+#[derive(Serialize, Deserialize)]
+struct SortedSettings<'a> {
+    #[serde(borrow)]
+    name: Cow<'a, str>,
+    time_zone: chrono_tz::Tz,
+    sort: SortMode,
+}
+
+/// Album layout from before albums had a description.
+#[derive(Serialize, Deserialize)]
+struct SortedAlbum<'a> {
+    #[serde(borrow)]
+    description: SortedSettings<'a>,
+    fragment_head: u64,
+    length: usize,
+    last_update: i64,
+    date_range: Option<(i64, i64)>,
+}
+
+/// Give every album a sort mode. Existing fragments are already sorted by capture date.
+fn sort_mode(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.albums, progress, |bytes| {
+        let old: UnsortedAlbum = bincode::deserialize(bytes).unwrap();
+
+        let album = SortedAlbum {
+            description: SortedSettings {
+                name: old.description.name,
+                time_zone: old.description.time_zone,
+                sort: SortMode::CaptureDate,
+            },
+            fragment_head: old.fragment_head,
+            length: old.length,
+            last_update: old.last_update,
+            date_range: old.date_range,
+        };
+
+        bincode::serialize(&album).unwrap()
+    })
+}
+
+/// Give every album an empty description.
+fn album_description(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.albums, progress, |bytes| {
+        let old: SortedAlbum = bincode::deserialize(bytes).unwrap();
+
+        let album = UnruledAlbum {
+            description: UnruledSettings {
+                name: old.description.name,
+                time_zone: old.description.time_zone,
+                sort: old.description.sort,
+                description: Cow::from(""),
+            },
+            fragment_head: old.fragment_head,
+            length: old.length,
+            last_update: old.last_update,
+            date_range: old.date_range,
+        };
+
+        bincode::serialize(&album).unwrap()
+    })
+}
+
+/// Record a detected type for every file. Sniffing the stored originals would mean reading all
+/// of them back out of storage, so existing files keep the type that was declared for them.
+fn detected_mime(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.files, progress, |bytes| {
+        let old: UnsniffedFile = bincode::deserialize(bytes).unwrap();
+
+        let file = UnhashedFile {
+            owner_id: old.owner_id,
+            width: old.width,
+            height: old.height,
+            uploaded: old.uploaded,
+            detected_mime: &old.metadata.mime,
+            metadata: old.metadata.clone(),
+        };
+
+        bincode::serialize(&file).unwrap()
+    })
+}
+
+/// Leave existing files without a placeholder. Regenerating renditions fills them in.
+fn placeholder(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.files, progress, |bytes| {
+        let old: UnhashedFile = bincode::deserialize(bytes).unwrap();
+
+        let file = UnlocatedFile {
+            owner_id: old.owner_id,
+            width: old.width,
+            height: old.height,
+            uploaded: old.uploaded,
+            detected_mime: old.detected_mime,
+            placeholder: None,
+            metadata: old.metadata,
+        };
+
+        bincode::serialize(&file).unwrap()
+    })
+}
+
+/// Leave existing files without a location. Regenerating renditions reads it from the originals.
+fn location(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.files, progress, |bytes| {
+        let old: UnlocatedFile = bincode::deserialize(bytes).unwrap();
+
+        let file = UnsizedFile {
+            owner_id: old.owner_id,
+            width: old.width,
+            height: old.height,
+            uploaded: old.uploaded,
+            detected_mime: old.detected_mime,
+            placeholder: old.placeholder,
+            location: None,
+            metadata: old.metadata,
+        };
+
+        bincode::serialize(&file).unwrap()
+    })
+}
+
+/// Give every user a library that holds the files they already uploaded.
+fn library(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    crate::library::build(state)
+}
+
+/// Move pending deletions from the `delete` tree, which predates background jobs, into `jobs`.
+fn jobs(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    let delete = state.db.open_tree(b"delete")?;
+
+    // Jobs keep the ids of the commands, so moving one twice just records it again.
+    for entry in delete.iter() {
+        let (id, command_bytes) = entry?;
+        let command: delete::Command = bincode::deserialize(&command_bytes).unwrap();
+        crate::jobs::insert(&state.jobs, &id, &Job::Delete(command))?;
+    }
+
+    drop(delete);
+    state.db.drop_tree(b"delete")?;
+    Ok(())
+}
+
+/// Sessions used to be stored with an empty value.
+fn session_records(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.sessions, progress, |_| {
+        bincode::serialize(&UnlabeledSession { csrf_token: None }).unwrap()
+    })
+}
+
+/// Session layout from before sessions recorded where and when they were used.
+#[derive(Serialize, Deserialize)]
+struct UnlabeledSession {
+    csrf_token: Option<String>,
+}
+
+/// When a session was made isn't known, so the time of the migration stands in for it.
+fn session_metadata(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    let now = Utc::now().timestamp();
+
+    rewrite(&state.sessions, progress, |bytes| {
+        let old: UnlabeledSession = bincode::deserialize(bytes).unwrap();
+
+        let session = Session {
+            csrf_token: old.csrf_token,
+            label: None,
+            user_agent: None,
+            created: now,
+            last_used: now,
+        };
+        bincode::serialize(&session).unwrap()
+    })
+}
+
+/// Sections used to be stored in a single fragment however many files they held.
+fn section_parts(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    let layouts = [
+        (&state.albums, &state.fragments),
+        (&state.libraries, &state.library_fragments),
+    ];
+
+    for (albums, fragments) in layouts {
+        for entry in albums.iter() {
+            let (album_id, _) = entry?;
+            let album_id = std::str::from_utf8(&album_id).unwrap();
+
+            // Sections that are already split are left alone, so this can start over.
+            (albums, fragments).transaction(|(albums, fragments)| {
+                let album_bytes = match albums.get(album_id)? {
+                    Some(album_bytes) => album_bytes,
+                    None => return Ok(()),
+                };
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                let mut e = Engine::new(album_id, &mut album, fragments)?;
+                e.split_oversized()?;
+                e.commit()?;
+
+                albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Leave existing files without a size, which would take reading every original back out of
+/// storage. Regenerating renditions fills it in. Files are also kept in trash entries and in
+/// pending deletions, which are rewritten too.
+fn file_size(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnsizedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.sized(None)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnsizedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(|file| file.sized(None))).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnsizedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(|file| file.sized(None)))).unwrap()
+    })
+}
+
+/// Leave existing files without a hash, like their size. Regenerating renditions fills it in.
+fn file_hash(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UndigestedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.digested(None)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UndigestedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(|file| file.digested(None))).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UndigestedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(|file| file.digested(None)))).unwrap()
+    })
+}
+
+/// Leave existing files without rendition sizes, like their size. Regenerating renditions fills
+/// them in.
+fn rendition_sizes(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnmeasuredFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.measured(vec![])).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnmeasuredFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(|file| file.measured(vec![]))).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnmeasuredFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(|file| file.measured(vec![])))).unwrap()
+    })
+}
+
+/// Count the files that every user already has.
+fn stats(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    crate::stats::build(state)
+}
+
+/// Every file that exists already was uploaded in the clear.
+fn encrypted_flag(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnflaggedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.flagged()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnflaggedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnflaggedFile::flagged)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnflaggedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnflaggedFile::flagged))).unwrap()
+    })
+}
+
+/// Files that exist already are treated as still images until their renditions are regenerated,
+/// which finds their frames.
+fn animation(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnanimatedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.still()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnanimatedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnanimatedFile::still)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnanimatedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnanimatedFile::still))).unwrap()
+    })
+}
+
+fn edits(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UneditedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unedited()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UneditedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UneditedFile::unedited)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UneditedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UneditedFile::unedited))).unwrap()
+    })
+}
+
+/// Files that exist already stay unpaired, since their halves could have been uploaded long apart.
+fn live_pairs(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnpairedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unpaired()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnpairedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnpairedFile::unpaired)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnpairedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnpairedFile::unpaired))).unwrap()
+    })
+}
+
+fn labels(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnlabeledFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unlabeled()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnlabeledFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnlabeledFile::unlabeled)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnlabeledFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnlabeledFile::unlabeled))).unwrap()
+    })
+}
+
+/// Files are hashed as regeneration renders them again, so until then they aren't found similar.
+fn phashes(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnfingerprintedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unfingerprinted()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnfingerprintedFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnfingerprintedFile::unfingerprinted)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnfingerprintedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnfingerprintedFile::unfingerprinted))).unwrap()
+    })
+}
+
+/// Leave existing files without a camera, which regenerating renditions reads from the originals,
+/// and existing albums without rules.
+fn album_rules(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: CameralessFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.cameraless()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<CameralessFile, UnruledAlbum> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map(CameralessFile::cameraless, UnruledAlbum::unruled)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<CameralessFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(CameralessFile::cameraless))).unwrap()
+    })?;
+
+    rewrite_tagged(&state.albums, progress, b"album.", |bytes| {
+        let old: UnruledAlbum = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unruled()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.libraries, progress, b"library.", |bytes| {
+        let old: UnruledAlbum = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unruled()).unwrap()
+    })
+}
+
+/// No file starts out hidden.
+fn hidden_flag(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnhiddenFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unhidden()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnhiddenFile, Album> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnhiddenFile::unhidden)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnhiddenFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnhiddenFile::unhidden))).unwrap()
+    })
+}
+
+/// No file starts out locked.
+fn locked_flag(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnlockedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unlocked()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnlockedFile, Album> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnlockedFile::unlocked)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnlockedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnlockedFile::unlocked))).unwrap()
+    })
+}
+
+/// Videos that are already stored are probed when their renditions are next regenerated.
+fn video_details(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite_tagged(&state.files, progress, b"file.", |bytes| {
+        let old: UnprobedFile = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.unprobed()).unwrap()
+    })?;
+
+    rewrite_tagged(&state.trash, progress, b"trash.", |bytes| {
+        let old: TrashedLayout<UnprobedFile, Album> = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&old.map_file(UnprobedFile::unprobed)).unwrap()
+    })?;
+
+    rewrite_tagged(&state.jobs, progress, b"job.", |bytes| {
+        let (schedule, old): (Schedule, JobLayout<UnprobedFile>) = bincode::deserialize(bytes).unwrap();
+        bincode::serialize(&(schedule, old.map_file(UnprobedFile::unprobed))).unwrap()
+    })
+}
+
+/// Files from before sizes and hashes were recorded on upload are measured in the background.
+fn measure_files(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    let id = state.db.generate_id()?;
+    crate::jobs::insert(&state.jobs, &id.to_be_bytes(), &Job::Measure)
+}
+
+/// Summarize the months of albums that are sorted by a date, which commits keep up to date from
+/// then on.
+fn month_summaries(state: &AppState, _progress: &sled::Tree) -> ApiResult<()> {
+    let layouts = [
+        (&state.albums, &state.fragments),
+        (&state.libraries, &state.library_fragments),
+    ];
+
+    for (albums, fragments) in layouts {
+        for entry in albums.iter() {
+            let (album_id, _) = entry?;
+            let album_id = std::str::from_utf8(&album_id).unwrap();
+
+            // Summaries are written over, so this can start over.
+            (albums, fragments).transaction(|(albums, fragments)| {
+                let album_bytes = match albums.get(album_id)? {
+                    Some(album_bytes) => album_bytes,
+                    None => return Ok(()),
+                };
+                let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                Engine::new(album_id, &mut album, fragments)?.summarize_months()?;
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Login attempt layout from before locked accounts were told apart from wrong passwords.
+#[derive(Serialize, Deserialize)]
+struct UnflaggedAttempt {
+    time_stamp: i64,
+    ip: String,
+    user_agent: Option<String>,
+    success: bool,
+}
+
+/// Earlier attempts against a locked account can't be told apart, so they are all left unflagged.
+fn lock_flag(state: &AppState, progress: &sled::Tree) -> ApiResult<()> {
+    rewrite(&state.login_audit, progress, |bytes| {
+        let old: UnflaggedAttempt = bincode::deserialize(bytes).unwrap();
+
+        let attempt = LoginAttempt {
+            time_stamp: old.time_stamp,
+            ip: Cow::from(old.ip),
+            user_agent: old.user_agent.map(Cow::from),
+            success: old.success,
+            locked: false,
+        };
+        bincode::serialize(&attempt).unwrap()
+    })
+}
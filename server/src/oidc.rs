@@ -0,0 +1,353 @@
+//! OpenID Connect Login
+//!
+//! With `oidc` configured, users can log in through a shared identity provider with the
+//! authorization code flow. `GET /user/oidc/login` sends the browser to the provider with a random
+//! `state`, which is kept in the `oidc_states` tree along with the PKCE verifier and the options of
+//! the login, and in a short-lived cookie of the browser. `GET /user/oidc/callback` only redeems
+//! the state that the browser's cookie holds, so a callback can't be passed on to someone else. It
+//! exchanges the code for an access token, and asks the provider's userinfo endpoint for the user's
+//! email. Both come straight from the provider over TLS, so the ID token isn't checked on its own.
+//!
+//! Only emails that the provider verified are accepted. They are looked up in `emails` like those
+//! of a password login, and when `auto_provision` is set and registration is open, users that don't
+//! exist yet are created with a random password that nobody knows. The session is the same as one
+//! from `POST /user/auth`.
+
+use crate::{
+    common::{cookie, new_id, respond_ok, AppState},
+    config::{Config, Registration},
+    error::{ApiError, ApiResult},
+    user,
+};
+use chrono::offset::Utc;
+use hyper::{header, Body, Request, Response, StatusCode};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use tokio::sync::OnceCell;
+use tokio::task::block_in_place;
+use wire::Key;
+
+const STATE_BYTES: usize = 32;
+const VERIFIER_BYTES: usize = 32;
+/// Time that the user has to log in at the provider.
+const STATE_SECONDS: i64 = 10 * 60;
+const STATE_COOKIE: &str = "photos_oidc_state";
+
+/// The part of the provider's configuration that the flow needs.
+#[derive(Deserialize, Debug)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+/// A login that went to the provider, stored under its `state`.
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingLogin {
+    expires: i64,
+    verifier: String,
+    cookie: bool,
+    label: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct UserInfo {
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+/// Talks to the provider, whose configuration is fetched once, when it is first needed.
+#[derive(Default)]
+pub struct Provider {
+    client: reqwest::Client,
+    discovery: OnceCell<Discovery>,
+}
+
+impl Provider {
+    async fn discovery(&self, issuer: &str) -> ApiResult<&Discovery> {
+        self.discovery
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", issuer);
+                let response = self.client.get(url).send().await?.error_for_status()?;
+                Ok(response.json().await?)
+            })
+            .await
+    }
+}
+
+/// The `Set-Cookie` value that binds `state` to the browser, or clears it when `state` is empty.
+/// It has to be `Lax`, since the browser comes back from the provider's site.
+fn state_cookie(config: &Config, state: &str) -> String {
+    let secure = if config.public_url.starts_with("https://") { "; Secure" } else { "" };
+    let max_age = if state.is_empty() { 0 } else { STATE_SECONDS };
+    format!(
+        "{}={}; Path=/user/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE, state, max_age, secure
+    )
+}
+
+/// Send the browser to the provider.
+pub async fn login(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (cookie, label) = user::login_options(&req)?;
+
+    let (parts, _) = req.into_parts();
+    let AppState {
+        ref oidc_states,
+        ref oidc,
+        ref config,
+        ..
+    } = parts.data().unwrap();
+
+    let oidc_config = config.oidc.as_ref().ok_or(ApiError::NotFound)?;
+    if cookie && !config.cookie_sessions {
+        return Err(ApiError::BadRequest);
+    }
+
+    let discovery = oidc.discovery(&oidc_config.issuer).await?;
+
+    let state = new_id(STATE_BYTES);
+    let verifier = new_id(VERIFIER_BYTES);
+    let challenge = base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+    let now = Utc::now().timestamp();
+    let pending = PendingLogin {
+        expires: now + STATE_SECONDS,
+        verifier,
+        cookie,
+        label,
+    };
+
+    block_in_place(|| -> ApiResult<()> {
+        // Logins that were given up on are dropped here, since there are only ever a few.
+        for entry in oidc_states.iter() {
+            let (key, pending_bytes) = entry?;
+            if bincode::deserialize::<PendingLogin>(&pending_bytes).unwrap().expires < now {
+                oidc_states.remove(key)?;
+            }
+        }
+
+        oidc_states.insert(state.as_bytes(), bincode::serialize(&pending).unwrap())?;
+        Ok(())
+    })?;
+
+    let location = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &oidc_config.client_id),
+            ("redirect_uri", &oidc_config.redirect_url),
+            ("scope", "openid email"),
+            ("state", &state),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|_| ApiError::BadRequest)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, location.as_str())
+        .header(header::SET_COOKIE, state_cookie(config, &state))
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Finish a login that the provider sent back.
+pub async fn callback(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let code = req.query("code").cloned().ok_or(ApiError::Unauthorized)?;
+    let state = req.query("state").cloned().ok_or(ApiError::Unauthorized)?;
+
+    let (parts, _) = req.into_parts();
+    let app_state = parts.data::<AppState>().unwrap();
+    let AppState {
+        ref users,
+        ref emails,
+        ref sessions,
+        ref verified,
+        ref oidc_states,
+        ref oidc,
+        ref config,
+        ref argon_config,
+        ..
+    } = app_state;
+
+    let oidc_config = config.oidc.as_ref().ok_or(ApiError::NotFound)?;
+
+    // Only the browser that started the login may finish it.
+    if cookie(&parts, STATE_COOKIE) != Some(state.as_str()) {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let pending_bytes = block_in_place(|| oidc_states.remove(state.as_bytes()))?.ok_or(ApiError::Unauthorized)?;
+    let pending: PendingLogin = bincode::deserialize(&pending_bytes).unwrap();
+    let now = Utc::now().timestamp();
+    if pending.expires < now {
+        return Err(ApiError::Unauthorized);
+    }
+
+    let discovery = oidc.discovery(&oidc_config.issuer).await?;
+
+    let token: TokenResponse = oidc
+        .client
+        .post(&discovery.token_endpoint)
+        .basic_auth(&oidc_config.client_id, Some(&oidc_config.client_secret))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", &oidc_config.redirect_url),
+            ("code_verifier", &pending.verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let info: UserInfo = oidc
+        .client
+        .get(&discovery.userinfo_endpoint)
+        .bearer_auth(&token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let email = match info.email {
+        Some(email) if info.email_verified => email,
+        _ => return Err(ApiError::Unauthorized),
+    };
+
+    block_in_place(|| {
+        let user_id = match emails.get(&email)? {
+            Some(user_id) => std::str::from_utf8(&user_id).unwrap().to_string(),
+            None if oidc_config.auto_provision && config.registration == Registration::Open => {
+                let password = user::hash_password(new_id(STATE_BYTES).as_bytes(), argon_config)?;
+                user::register(users, emails, &email, &password)?
+            }
+            None => return Err(ApiError::Unauthorized),
+        };
+
+        // The provider checked that the user owns the address.
+        verified.insert(user_id.as_bytes(), &now.to_be_bytes())?;
+
+        let extended_key = [&user_id, ".", &user::new_session_key()].concat();
+        let session = user::new_session(&parts, pending.cookie, pending.label, now);
+        sessions.insert(extended_key.as_bytes(), bincode::serialize(&session).unwrap())?;
+        user::audit_attempt(app_state, &parts, user_id.as_bytes(), true)?;
+
+        // The browser came here from the provider, so it goes on to the web frontend, which can
+        // ask for the CSRF token of the session.
+        if session.csrf_token.is_some() {
+            return Ok(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, &config.public_url)
+                .header(header::SET_COOKIE, user::cookie_header(config, &extended_key))
+                .header(header::SET_COOKIE, state_cookie(config, ""))
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        let mut response = respond_ok(Key {
+            key: Cow::from(extended_key),
+        })?;
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, state_cookie(config, "").parse().unwrap());
+        Ok(response)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{start_provider, TestServer};
+    use crate::config::Registration;
+    use hyper::{header, Body, Method, Request, StatusCode};
+    use serde_json::Value;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn oidc_login() {
+        let issuer = start_provider("sso@example.com").await;
+        let oidc = |auto_provision| crate::config::OidcConfig {
+            issuer: issuer.clone(),
+            client_id: "photos".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "http://localhost/user/oidc/callback".to_string(),
+            auto_provision,
+        };
+
+        // Go to the provider and come back with the state that the server handed out, along with
+        // the cookie that holds it.
+        async fn log_in(server: &TestServer) -> (StatusCode, Vec<u8>, String, String) {
+            let response = server
+                .client
+                .request(
+                    Request::get(format!("http://{}/user/oidc/login", server.addr))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FOUND);
+
+            let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+            let cookie = set_cookie.split(';').next().unwrap().to_string();
+            let state = cookie.split_once('=').unwrap().1;
+            assert!(server.state.oidc_states.contains_key(state).unwrap());
+
+            let path = format!("/user/oidc/callback?code=code&state={}", state);
+            let (status, body) = server
+                .request(Method::GET, &path, &[("cookie", cookie.clone())], Body::empty())
+                .await;
+            (status, body, path, cookie)
+        }
+
+        let server = TestServer::start_with(|config| config.oidc = Some(oidc(false))).await;
+        let (status, _, _, _) = log_in(&server).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        server.create_user("sso@example.com").await;
+        let (status, body, path, cookie) = log_in(&server).await;
+        assert_eq!(status, StatusCode::OK);
+        let key: Value = serde_json::from_slice(&body).unwrap();
+        let sessions_path = format!("/user/auth?key={}", key["key"].as_str().unwrap());
+        assert_eq!(server.json(Method::GET, &sessions_path, &()).await["sessions"].as_array().unwrap().len(), 1);
+
+        // States only work once.
+        let (status, _) = server.request(Method::GET, &path, &[("cookie", cookie)], Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // A callback only works in the browser that started the login.
+        let (status, _) = server.request(Method::GET, "/user/oidc/login", &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::FOUND);
+        let (state, _) = server.state.oidc_states.iter().next().unwrap().unwrap();
+        let path = format!("/user/oidc/callback?code=code&state={}", std::str::from_utf8(&state).unwrap());
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(server.state.oidc_states.contains_key(&state).unwrap());
+
+        let server = TestServer::start_with(|config| config.oidc = Some(oidc(true))).await;
+        let (status, _, _, _) = log_in(&server).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(server.state.emails.contains_key("sso@example.com").unwrap());
+
+        // Users aren't created while registration is closed.
+        let server = TestServer::start_with(|config| {
+            config.oidc = Some(oidc(true));
+            config.registration = Registration::Closed;
+        })
+        .await;
+        let (status, _, _, _) = log_in(&server).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!server.state.emails.contains_key("sso@example.com").unwrap());
+    }
+}
@@ -0,0 +1,158 @@
+//! Image Placeholders
+//!
+//! Galleries paint something in place of a photo until its rendition arrives. Every upload gets
+//! a [blurhash](https://blurha.sh), a short string that decodes to a blurry version of the
+//! photo, along with its average color for clients that don't decode blurhashes. Both are small
+//! enough to embed in section fragments, so placeholders don't cost any extra requests.
+
+use crate::error::ApiResult;
+use libvips::{ops, VipsImage};
+use serde::{Deserialize, Serialize};
+
+/// Width that photos are shrunk to before they are hashed.
+const SAMPLE_WIDTH: i32 = 32;
+/// Number of horizontal and vertical blurhash components.
+const COMPONENTS: (usize, usize) = (4, 3);
+
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Placeholder {
+    pub blurhash: String,
+    /// Average color as `#rrggbb`.
+    pub color: String,
+}
+
+/// Make the placeholder of an upright image.
+pub fn compute(image: &VipsImage) -> ApiResult<Placeholder> {
+    let sample = if image.get_width() > SAMPLE_WIDTH {
+        ops::thumbnail_image(image, SAMPLE_WIDTH)?
+    } else {
+        image.clone()
+    };
+
+    let srgb = ops::colourspace(&sample, ops::Interpretation::Srgb)?;
+    let flat = if srgb.get_bands() > 3 {
+        ops::flatten(&srgb)?
+    } else {
+        srgb
+    };
+
+    let width = flat.get_width() as usize;
+    let height = flat.get_height() as usize;
+    let pixels = flat.image_write_to_memory();
+
+    Ok(from_pixels(&pixels, width, height))
+}
+
+/// Make the placeholder of `width` by `height` packed RGB pixels.
+pub fn from_pixels(pixels: &[u8], width: usize, height: usize) -> Placeholder {
+    let (x_components, y_components) = COMPONENTS;
+    let linear: Vec<f64> = pixels.iter().map(|&value| to_linear(value)).collect();
+
+    let mut factors = Vec::with_capacity(x_components * y_components);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+
+            for y in 0..height {
+                for x in 0..width {
+                    let basis = (std::f64::consts::PI * i as f64 * x as f64 / width as f64).cos()
+                        * (std::f64::consts::PI * j as f64 * y as f64 / height as f64).cos();
+                    let pixel = &linear[3 * (y * width + x)..][..3];
+
+                    for (channel, value) in factor.iter_mut().zip(pixel) {
+                        *channel += basis * value;
+                    }
+                }
+            }
+
+            let scale = normalisation / (width * height) as f64;
+            factors.push(factor.map(|channel| channel * scale));
+        }
+    }
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+
+    let mut blurhash = String::new();
+    encode83((x_components - 1) + (y_components - 1) * 9, 1, &mut blurhash);
+
+    let maximum = if ac.is_empty() {
+        encode83(0, 1, &mut blurhash);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0.0f64, |max, value| max.max(value.abs()));
+        let quantised = ((actual * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as usize;
+        encode83(quantised, 1, &mut blurhash);
+        (quantised + 1) as f64 / 166.0
+    };
+
+    let [r, g, b] = dc.map(to_srgb);
+    encode83(((r as usize) << 16) + ((g as usize) << 8) + b as usize, 4, &mut blurhash);
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|value| {
+            let scaled = (value / maximum).signum() * (value / maximum).abs().sqrt();
+            (scaled * 9.0 + 9.5).floor().clamp(0.0, 18.0) as usize
+        });
+        encode83(r * 19 * 19 + g * 19 + b, 2, &mut blurhash);
+    }
+
+    Placeholder {
+        blurhash,
+        color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+    }
+}
+
+fn encode83(value: usize, length: u32, output: &mut String) {
+    for i in (0..length).rev() {
+        let digit = value / 83usize.pow(i) % 83;
+        output.push(BASE83[digit] as char);
+    }
+}
+
+fn to_linear(value: u8) -> f64 {
+    let value = value as f64 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_srgb(value: f64) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        (value * 12.92 * 255.0 + 0.5) as u8
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn solid_color() {
+        let pixels = [255, 0, 0].repeat(4 * 4);
+        let placeholder = from_pixels(&pixels, 4, 4);
+
+        // 4x3 components, then the average color.
+        assert_eq!(placeholder.blurhash.len(), 6 + 2 * 11);
+        assert_eq!(&placeholder.blurhash[..1], "L");
+        assert_eq!(&placeholder.blurhash[2..6], "TI:j");
+        assert_eq!(placeholder.color, "#ff0000");
+    }
+
+    #[test]
+    fn average_color() {
+        let pixels = [[0, 0, 0], [255, 255, 255]].concat();
+        let placeholder = from_pixels(&pixels, 2, 1);
+
+        // Colors are averaged in linear light, which is lighter than averaging the bytes.
+        assert_eq!(placeholder.color, "#bcbcbc");
+    }
+}
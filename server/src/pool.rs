@@ -0,0 +1,139 @@
+//! Image Processing Pool
+//!
+//! Decoding and encoding an image can keep a thread busy for seconds, and under `block_in_place`
+//! that thread is one of the runtime's, so a few large uploads at once could stall every other
+//! request. Image work runs on threads of its own instead, `vips_threads` of them, which take tasks
+//! from a queue that holds up to `vips_queue`. Requests are turned away with `Busy` while the queue
+//! is full, so that clients come back later rather than piling up, and background work like
+//! regeneration waits for room instead.
+
+use crate::error::{ApiError, ApiResult};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+/// Seconds that clients are asked to wait while the queue is full.
+const BUSY_RETRY_SECONDS: u64 = 5;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+pub struct Pool {
+    sender: mpsc::Sender<Task>,
+}
+
+impl Pool {
+    /// Start `threads` threads, which stop once the pool is dropped.
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Task>(queue.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("vips-{}", i))
+                .spawn(move || loop {
+                    let task = receiver.lock().unwrap().blocking_recv();
+                    match task {
+                        Some(task) => task(),
+                        None => break,
+                    }
+                })
+                .expect("Couldn't start image processing thread");
+        }
+
+        Pool { sender }
+    }
+
+    /// Run `work` on the pool, failing with `Busy` if too much is waiting already.
+    pub async fn run<T, F>(&self, work: F) -> ApiResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> ApiResult<T> + Send + 'static,
+    {
+        let (task, result) = task(work);
+        self.sender
+            .try_send(task)
+            .map_err(|_| ApiError::Busy(BUSY_RETRY_SECONDS))?;
+
+        finish(result).await
+    }
+
+    /// Run `work` on the pool once there is room for it.
+    pub async fn run_waiting<T, F>(&self, work: F) -> ApiResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> ApiResult<T> + Send + 'static,
+    {
+        let (task, result) = task(work);
+        if self.sender.send(task).await.is_err() {
+            unreachable!("image processing threads stopped");
+        }
+
+        finish(result).await
+    }
+}
+
+/// Wrap `work` so that its result, or its panic, is sent back to the caller.
+fn task<T, F>(work: F) -> (Task, oneshot::Receiver<thread::Result<ApiResult<T>>>)
+where
+    T: Send + 'static,
+    F: FnOnce() -> ApiResult<T> + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let task = Box::new(move || {
+        let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(work)));
+    });
+
+    (task, receiver)
+}
+
+/// Wait for a task, passing a panic on to the caller as if the work had run there.
+async fn finish<T>(result: oneshot::Receiver<thread::Result<ApiResult<T>>>) -> ApiResult<T> {
+    match result.await.expect("image processing task was dropped") {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc as std_mpsc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn full_queue_is_busy() {
+        let pool = Arc::new(Pool::new(1, 1));
+        let (started, wait_started) = std_mpsc::channel();
+        let (release, wait_release) = std_mpsc::channel::<()>();
+
+        // Hold the only thread, and then fill the queue behind it.
+        let first = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(move || {
+                    started.send(()).unwrap();
+                    wait_release.recv().unwrap();
+                    Ok(1)
+                })
+                .await
+            }
+        });
+        tokio::task::spawn_blocking(move || wait_started.recv().unwrap()).await.unwrap();
+
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| Ok(2)).await }
+        });
+        while pool.sender.capacity() > 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(pool.run(|| Ok(3)).await, Err(ApiError::Busy(_))));
+
+        release.send(()).unwrap();
+        assert_eq!(first.await.unwrap().unwrap(), 1);
+        assert_eq!(second.await.unwrap().unwrap(), 2);
+        assert_eq!(pool.run_waiting(|| Ok(4)).await.unwrap(), 4);
+    }
+}
@@ -0,0 +1,426 @@
+//! Rendition Regeneration
+//!
+//! Renditions are made once, on upload, so they go stale when the configured ladder or libvips
+//! changes. Regeneration walks every file in the background, downloads its original, and renders
+//! and stores the configured renditions again, along with its placeholder, location, camera,
+//! animated copy and, for videos, what `ffprobe` reads and the preview strip. The size, hash and
+//! rendition sizes of files from before those were recorded are filled in along the way, and the
+//! owner's library statistics follow. Files are processed one at a time with a pause in between so
+//! that the server stays responsive.
+//!
+//! Edits render a single file the same way, with the edit that replaces its own.
+
+use crate::{
+    album::{bulk::Trees, engine::Engine, rules},
+    animation,
+    common::{AppState, File},
+    config::Rendition,
+    error::{ApiError, ApiResult},
+    events,
+    file::hash_file,
+    format::{self, Format},
+    geo, library,
+    metrics::Metrics,
+    placeholder::Placeholder,
+    pool::Pool,
+    similar, stats,
+    storage::{self, Storage},
+    video,
+};
+use futures::future;
+use sled::Transactional;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{fs, task::block_in_place, time};
+use tracing::{info, warn};
+use wire::{Album, AlbumEvent, Animation, FileEdit, Location, RegenerateStatus, Video};
+
+#[derive(Clone, Default)]
+pub struct Regenerator {
+    status: Arc<Mutex<RegenerateStatus>>,
+}
+
+impl Regenerator {
+    pub fn status(&self) -> RegenerateStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// Everything that the background task needs, since it outlives the request that started it.
+struct Task {
+    trees: Trees,
+    geo: sled::Tree,
+    phashes: sled::Tree,
+    libraries: sled::Tree,
+    library_fragments: sled::Tree,
+    stats: sled::Tree,
+    storage: Arc<dyn Storage>,
+    renditions: Vec<Rendition>,
+    decoding: format::Decoding,
+    max_frames: u32,
+    preview_frames: u32,
+    pool: Arc<Pool>,
+    temp_path: PathBuf,
+    delay: Duration,
+    status: Arc<Mutex<RegenerateStatus>>,
+    metrics: Arc<Metrics>,
+}
+
+/// What rendering a file again found out about it.
+struct Rendered {
+    width: i32,
+    height: i32,
+    placeholder: Placeholder,
+    phash: u64,
+    location: Option<Location>,
+    camera: Option<String>,
+    animation: Option<Animation>,
+    video: Option<Video<'static>>,
+    /// The edit that replaced the file's own, if it was rendered for one.
+    edit: Option<FileEdit>,
+}
+
+/// Start regenerating every file unless a run is already underway, returning the status.
+pub fn start(state: &AppState) -> RegenerateStatus {
+    let task = Task::new(state);
+
+    let started = {
+        let mut current = task.status.lock().unwrap();
+        if current.running {
+            return current.clone();
+        }
+
+        *current = RegenerateStatus {
+            running: true,
+            total: state.files.len(),
+            done: 0,
+            failed: 0,
+        };
+        current.clone()
+    };
+
+    tokio::spawn(async move {
+        info!("Regenerating renditions");
+        task.run().await;
+        info!("Finished regenerating renditions");
+    });
+
+    started
+}
+
+impl Task {
+    fn new(state: &AppState) -> Self {
+        Task {
+            trees: Trees::new(state),
+            geo: state.geo.clone(),
+            phashes: state.phashes.clone(),
+            libraries: state.libraries.clone(),
+            library_fragments: state.library_fragments.clone(),
+            stats: state.stats.clone(),
+            storage: state.storage.clone(),
+            renditions: state.config.renditions.clone(),
+            decoding: format::Decoding::new(&state.config),
+            max_frames: state.config.max_animation_frames,
+            preview_frames: state.config.preview_frames,
+            pool: state.image_pool.clone(),
+            temp_path: state.temp_path.clone(),
+            delay: Duration::from_millis(state.config.regenerate_delay_ms),
+            status: state.regenerator.status.clone(),
+            metrics: state.metrics.clone(),
+        }
+    }
+
+    async fn run(&self) {
+        // Collect the ids first so that no database iterator is held across awaits.
+        let file_ids: Vec<sled::IVec> = self.trees.files.iter().keys().filter_map(Result::ok).collect();
+
+        for file_id in file_ids {
+            let file_id = std::str::from_utf8(&file_id).unwrap();
+
+            match self.regenerate(file_id, None).await {
+                Ok(()) => self.status.lock().unwrap().done += 1,
+                Err(err) => {
+                    warn!(error = %err.chain(), "Couldn't regenerate {}", file_id);
+                    self.status.lock().unwrap().failed += 1;
+                }
+            }
+
+            time::sleep(self.delay).await;
+        }
+
+        self.status.lock().unwrap().running = false;
+    }
+
+    /// Render a file again, with `edit` in place of its own edit if it is given.
+    async fn regenerate(&self, file_id: &str, edit: Option<FileEdit>) -> ApiResult<()> {
+        // The file may have been deleted since the run started.
+        let file_bytes = match self.trees.files.get(file_id)? {
+            Some(file_bytes) => file_bytes,
+            None if edit.is_some() => return Err(ApiError::NotFound),
+            None => return Ok(()),
+        };
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+        // The server can't read encrypted files, so they have nothing to render.
+        if file.encrypted {
+            return match edit {
+                Some(_) => Err(ApiError::BadRequest),
+                None => Ok(()),
+            };
+        }
+
+        // Edits can happen while a run is at the same file, so they keep their own files.
+        let prefix = [file_id, if edit.is_some() { ".edit" } else { ".regenerate" }].concat();
+        let original_path = self.temp_path.join(&prefix);
+        let scratch_path = self.temp_path.join([&prefix, ".decoded"].concat());
+        let rendition_paths: Vec<_> = self
+            .renditions
+            .iter()
+            .map(|rendition| self.temp_path.join([&prefix, ".", &rendition.name].concat()))
+            .collect();
+        let animated_path = self.temp_path.join([&prefix, ".animated"].concat());
+        let preview_path = self.temp_path.join([&prefix, ".preview"].concat());
+
+        let result = async {
+            let original_key = storage::key(storage::ORIGINAL, file_id);
+            storage::download(self.storage.as_ref(), &original_key, &original_path).await?;
+
+            let format = Format::detect(file.detected_mime, &file.metadata.name);
+            let (path, scratch, ladder, paths, animated, preview) = (
+                original_path.clone(),
+                scratch_path.clone(),
+                self.renditions.clone(),
+                rendition_paths.clone(),
+                animated_path.clone(),
+                preview_path.clone(),
+            );
+            let (metrics, decoding, max_frames, preview_frames) =
+                (self.metrics.clone(), self.decoding, self.max_frames, self.preview_frames);
+            let mime = file.detected_mime.to_string();
+            let applied = edit.unwrap_or(file.edit);
+
+            let work = move || {
+                let started = Instant::now();
+                let (width, height, placeholder, phash) =
+                    format::render(format, &path, &scratch, &ladder, &paths, decoding, &applied)?;
+                let animation = animation::make(&mime, &path, &ladder, &animated, max_frames)?;
+                let video = video::make(format, &path, &ladder, &scratch, &preview, preview_frames)?;
+                metrics.record_processing(started.elapsed());
+
+                Ok(Rendered {
+                    width,
+                    height,
+                    placeholder,
+                    phash,
+                    location: geo::read_location(&path),
+                    camera: rules::read_camera(&path),
+                    animation,
+                    video,
+                    edit,
+                })
+            };
+            // Edits are asked for by a client, which can retry, while nobody is waiting on a run,
+            // so it waits for room rather than failing.
+            let rendered = match edit {
+                Some(_) => self.pool.run(work).await?,
+                None => self.pool.run_waiting(work).await?,
+            };
+            let size = fs::metadata(&original_path).await?.len();
+            let hash = block_in_place(|| hash_file(&original_path))?;
+
+            let mut rendition_sizes = vec![];
+            for (rendition, path) in self.renditions.iter().zip(rendition_paths.iter()) {
+                rendition_sizes.push((rendition.name.clone(), fs::metadata(path).await?.len()));
+                self.storage.put(&storage::key(&rendition.name, file_id), path).await?;
+
+                // The AVIF copy is made again from the new rendition when it is next requested.
+                let avif_key = storage::key(&storage::avif_kind(&rendition.name), file_id);
+                self.storage.delete(&avif_key).await?;
+            }
+            if let Some(tallest) = self.renditions.first() {
+                let animated_key = storage::key(&storage::animated_kind(&tallest.name), file_id);
+                match rendered.animation {
+                    Some(_) => {
+                        let kind = storage::animated_kind(&tallest.name);
+                        rendition_sizes.push((kind, fs::metadata(&animated_path).await?.len()));
+                        self.storage.put(&animated_key, &animated_path).await?;
+                    }
+                    None => self.storage.delete(&animated_key).await?,
+                }
+            }
+            let preview_key = storage::key(storage::PREVIEW, file_id);
+            match rendered.video {
+                Some(ref video) if video.preview_frames > 0 => {
+                    let kind = storage::PREVIEW.to_string();
+                    rendition_sizes.push((kind, fs::metadata(&preview_path).await?.len()));
+                    self.storage.put(&preview_key, &preview_path).await?;
+                }
+                _ => self.storage.delete(&preview_key).await?,
+            }
+
+            block_in_place(|| self.update(file_id, rendered, size, hash, rendition_sizes))
+        }
+        .await;
+
+        let _ = futures::join!(
+            fs::remove_file(&original_path),
+            fs::remove_file(&scratch_path),
+            fs::remove_file(&animated_path),
+            fs::remove_file(&preview_path),
+            future::join_all(rendition_paths.iter().map(fs::remove_file))
+        );
+
+        result
+    }
+
+    /// Store what was rendered, and the size, hash and rendition sizes of a file, copying its
+    /// placeholder and dimensions into its owner's library and every album that the file is in.
+    fn update(
+        &self,
+        file_id: &str,
+        rendered: Rendered,
+        size: u64,
+        hash: [u8; 32],
+        rendition_sizes: Vec<(String, u64)>,
+    ) -> ApiResult<()> {
+        let Trees {
+            ref files,
+            ref inclusions,
+            ref albums,
+            ref fragments,
+            ref events,
+            ..
+        } = self.trees;
+        let Rendered {
+            width,
+            height,
+            placeholder,
+            phash,
+            location,
+            camera,
+            animation,
+            video,
+            edit,
+        } = rendered;
+
+        let mut album_ids = vec![];
+        for entry in inclusions.scan_prefix([file_id, "."].concat()) {
+            let (inclusion, _) = entry?;
+            let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+            album_ids.push(album_id.to_string());
+        }
+
+        let trees = (
+            files,
+            albums,
+            fragments,
+            &self.geo,
+            &self.phashes,
+            &self.libraries,
+            &self.library_fragments,
+            &self.stats,
+        );
+        let published = trees.transaction(
+            |(files, albums, fragments, geo_tree, phashes, libraries, library_fragments, stats_tree)| {
+                let file_bytes = files.get(file_id)?.ok_or(ApiError::NotFound)?;
+                let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+
+                if edit.is_none()
+                    && file.width == width
+                    && file.height == height
+                    && file.placeholder.as_ref() == Some(&placeholder)
+                    && file.phash == Some(phash)
+                    && file.location == location
+                    && file.camera == camera
+                    && file.size == Some(size)
+                    && file.hash == Some(hash)
+                    && file.rendition_sizes == rendition_sizes
+                    && file.animation == animation
+                    && file.video == video
+                {
+                    return Ok(vec![]);
+                }
+
+                if let Some(old) = file.location {
+                    geo_tree.remove(geo::key(file.owner_id, old, file_id).as_bytes())?;
+                }
+                if let Some(new) = location {
+                    geo_tree.insert(geo::key(file.owner_id, new, file_id).as_bytes(), b"")?;
+                }
+                if let Some(old) = file.phash {
+                    phashes.remove(similar::key(file.owner_id, old, file_id).as_bytes())?;
+                }
+                phashes.insert(similar::key(file.owner_id, phash, file_id).as_bytes(), b"")?;
+
+                let appearance_changed = file.placeholder.as_ref() != Some(&placeholder)
+                    || file.width != width
+                    || file.height != height
+                    || file.size != Some(size);
+                file.width = width;
+                file.height = height;
+                file.placeholder = Some(placeholder.clone());
+                file.phash = Some(phash);
+                file.location = location;
+                file.camera = camera.clone();
+                stats::count(stats_tree, &file, false)?;
+                file.size = Some(size);
+                file.hash = Some(hash);
+                file.rendition_sizes = rendition_sizes.clone();
+                file.animation = animation;
+                file.video = video.clone();
+                if let Some(edit) = edit {
+                    file.edit = edit;
+                    file.revision += 1;
+                }
+                files.insert(file_id, bincode::serialize(&file).unwrap())?;
+                stats::count(stats_tree, &file, true)?;
+
+                if !appearance_changed {
+                    return Ok(vec![]);
+                }
+
+                let mut published = vec![];
+                if file.in_library() {
+                    let library_head = library::modify(libraries, library_fragments, file.owner_id, |e| {
+                        e.set_appearance(file_id, &file)
+                    })?;
+                    published.push(AlbumEvent::LibraryUpdated {
+                        user_id: file.owner_id.to_string(),
+                        fragment_head: library_head,
+                    });
+                }
+                for album_id in &album_ids {
+                    let album_bytes = match albums.get(album_id.as_bytes())? {
+                        Some(album_bytes) => album_bytes,
+                        None => continue,
+                    };
+                    let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                    let mut e = Engine::new(album_id, &mut album, fragments)?;
+                    e.set_appearance(file_id, &file)?;
+                    e.commit()?;
+
+                    albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                    published.push(AlbumEvent::Updated {
+                        album_id: album_id.to_string(),
+                        fragment_head: album.fragment_head,
+                    });
+                }
+
+                Ok(published)
+            },
+        )?;
+
+        for event in published {
+            events::publish(events, event);
+        }
+
+        Ok(())
+    }
+}
+
+/// Render `file_id` with `edit` in place of its own edit, and store the edit once it is done.
+pub async fn edit(state: &AppState, file_id: &str, edit: FileEdit) -> ApiResult<()> {
+    Task::new(state).regenerate(file_id, Some(edit)).await
+}
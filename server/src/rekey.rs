@@ -0,0 +1,67 @@
+//! Storage Key Rotation
+//!
+//! Stored files are encrypted with the first of the configured storage keys, and each one records
+//! which key that was. The id of the first key is kept under `STORAGE_KEY_ID` in the default tree,
+//! and when the server starts with a different one, a `Reencrypt` job stores every file again
+//! under the new key. Files that were stored before encryption was turned on are encrypted along
+//! the way. Old keys have to stay configured until the job is done, which `GET /admin/jobs`
+//! shows.
+
+use crate::{
+    common::AppState,
+    error::ApiResult,
+    jobs::{self, Job},
+    storage,
+};
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+const STORAGE_KEY_ID: &[u8] = b"storage_key_id";
+
+/// Start re-encrypting stored files if the current storage key changed since the last start.
+pub fn check(state: &AppState) -> ApiResult<()> {
+    let current = match state.config.storage_keys.first() {
+        Some(key) => Sha256::digest(key.0).to_vec(),
+        None => {
+            if state.db.contains_key(STORAGE_KEY_ID)? {
+                warn!("Stored files were encrypted, but no storage keys are configured to read them with");
+            }
+            return Ok(());
+        }
+    };
+
+    if state.db.get(STORAGE_KEY_ID)?.as_deref() != Some(&current) {
+        jobs::enqueue(state, &Job::Reencrypt)?;
+        state.db.insert(STORAGE_KEY_ID, current)?;
+    }
+
+    Ok(())
+}
+
+/// Store every file that isn't encrypted with the current key again. Files that are already are
+/// skipped, so the job can pick up where it stopped.
+pub fn run(state: &AppState) -> ApiResult<()> {
+    let handle = tokio::runtime::Handle::current();
+
+    handle.block_on(async {
+        let mut refreshed = 0;
+
+        // Resized files are cached under keys of their own, which are stored again along with the
+        // rest.
+        for kind in storage::kinds(&state.config).into_iter().chain([storage::CUSTOM.to_string()]) {
+            let prefix = [&kind, "/"].concat();
+            let mut keys = state.storage.list(&prefix, None);
+
+            while let Some(key) = keys.try_next().await? {
+                let scratch = state.temp_path.join(["reencrypt.", &key.replace('/', ".")].concat());
+                if state.storage.refresh(&key, &scratch).await? {
+                    refreshed += 1;
+                }
+            }
+        }
+
+        info!("Re-encrypted {} stored files", refreshed);
+        Ok(())
+    })
+}
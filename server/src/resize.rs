@@ -0,0 +1,443 @@
+//! Custom Sizes
+//!
+//! Frontends that lay photos out at sizes of their own can ask for a file scaled to fit in, or to
+//! cover, a box of exactly that size instead of picking the closest rendition. Only the widths and
+//! heights in `custom_sizes` can be asked for, so that few results are made for each file and they
+//! can be kept. Results are made from the original with the edit of the file applied, encoded like
+//! the tallest rendition, and cached in storage under the `CUSTOM` kind by file and revision, so
+//! they are encrypted like everything else that is stored. Each result is listed in `custom_cache`
+//! along with its size, and once the cache holds more than `custom_cache_bytes`, a background task
+//! removes the oldest.
+//!
+//! Resizing decodes the whole original, so it runs on the image pool and each user may only have
+//! `max_resizes_per_user` resizes underway. Results that are cached are served without either.
+
+use crate::{
+    bandwidth, edit, file,
+    common::{new_id, require_key, test_logged_in, etag_matches, AppState, File},
+    config::Rendition,
+    error::{ApiError, ApiResult},
+    format::{self, Decoding, Format},
+    storage,
+};
+use bytes::Bytes;
+use chrono::offset::Utc;
+use futures::{join, stream, StreamExt};
+use hyper::{header, Body, Request, Response, StatusCode};
+use libvips::{ops, VipsImage};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::{fs, task::block_in_place};
+use tracing::warn;
+
+/// Stored in `custom_cache` under the storage key of a result.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cached {
+    created: i64,
+    bytes: u64,
+}
+
+/// Bytes that the cached results take up, which is counted once at startup and kept up to date
+/// after, so that the cache only has to be walked when it is trimmed.
+#[derive(Default, Debug)]
+pub struct CacheSize {
+    bytes: AtomicU64,
+    trimming: AtomicBool,
+}
+
+impl CacheSize {
+    pub fn count(custom_cache: &sled::Tree) -> Self {
+        let bytes = custom_cache
+            .iter()
+            .values()
+            .filter_map(Result::ok)
+            .map(|cached_bytes| bincode::deserialize::<Cached>(&cached_bytes).unwrap().bytes)
+            .sum();
+
+        CacheSize {
+            bytes: AtomicU64::new(bytes),
+            trimming: AtomicBool::new(false),
+        }
+    }
+}
+
+/// How a file is scaled into the box that was asked for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Fit {
+    /// Scale the whole file down to fit in the box.
+    Contain,
+    /// Scale the file down to cover the box, and crop what is left over around the center.
+    Cover,
+}
+
+impl FromStr for Fit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "contain" => Ok(Fit::Contain),
+            "cover" => Ok(Fit::Cover),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A box that a file is scaled into, where a side that is left out doesn't limit the scale.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Size {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+}
+
+impl Size {
+    /// Check a size against the `allowed` dimensions. Covering a box needs both of its sides.
+    fn new(width: Option<u32>, height: Option<u32>, fit: Fit, allowed: &[u32]) -> ApiResult<Self> {
+        let complete = match fit {
+            Fit::Contain => width.is_some() || height.is_some(),
+            Fit::Cover => width.is_some() && height.is_some(),
+        };
+        let allowed = |side: Option<u32>| side.is_none_or(|side| allowed.contains(&side));
+
+        if complete && allowed(width) && allowed(height) {
+            Ok(Size { width, height, fit })
+        } else {
+            Err(ApiError::BadRequest)
+        }
+    }
+
+    /// Name of the result of a revision of a file at this size, among the others of the file.
+    fn name(&self, revision: u32) -> String {
+        let fit = match self.fit {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+        };
+        let (width, height) = (self.width.unwrap_or(0), self.height.unwrap_or(0));
+
+        format!("{}-{}x{}-{}", revision, width, height, fit)
+    }
+
+    /// Scale `image` down into the box.
+    fn apply(&self, image: &VipsImage) -> ApiResult<VipsImage> {
+        match self.fit {
+            Fit::Contain => {
+                let factor = [
+                    self.width.map(|width| width as f64 / image.get_width() as f64),
+                    self.height.map(|height| height as f64 / image.get_height() as f64),
+                ]
+                .iter()
+                .flatten()
+                .fold(1.0, |factor: f64, side| factor.min(*side));
+
+                Ok(ops::resize(image, factor)?)
+            }
+            Fit::Cover => {
+                let options = ops::ThumbnailImageOptions {
+                    height: self.height.unwrap() as i32,
+                    size: ops::Size::Down,
+                    crop: ops::Interesting::Centre,
+                    ..ops::ThumbnailImageOptions::default()
+                };
+
+                Ok(ops::thumbnail_image_with_opts(image, self.width.unwrap() as i32, &options)?)
+            }
+        }
+    }
+}
+
+pub async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let side = |name| {
+        req.query(name)
+            .map(|side| side.parse::<u32>().map_err(|_| ApiError::BadRequest))
+            .transpose()
+    };
+    let (width, height) = (side("w")?, side("h")?);
+    let fit = match req.query("fit") {
+        Some(fit) => fit.parse().map_err(|_| ApiError::BadRequest)?,
+        None => Fit::Contain,
+    };
+
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+    let file_id = parts.param("fileId").unwrap();
+
+    let state: &AppState = parts.data().unwrap();
+    let size = Size::new(width, height, fit, &state.config.custom_sizes)?;
+
+    test_logged_in(&state.sessions, key)?;
+
+    let file_bytes = state.files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+    let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+    file::check_access(&parts, key, user_id, &file)?;
+    bandwidth::check(state, key, user_id)?;
+
+    // Encrypted files only have their original.
+    if file.encrypted {
+        return Err(ApiError::NotFound);
+    }
+    let tallest = state.config.renditions.first().ok_or(ApiError::NotFound)?;
+
+    // Results only change when the file is edited, which changes the revision.
+    let name = size.name(file.revision);
+    let etag = format!("\"{}-{}\"", file_id, name);
+    let response = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, "private, no-cache");
+
+    if etag_matches(&parts, &etag) {
+        return Ok(response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap());
+    }
+
+    let cache_key = cache_key(file_id, &name);
+    let stream = if block_in_place(|| state.custom_cache.contains_key(&cache_key))? {
+        state.storage.get_stream(&cache_key).await?
+    } else {
+        let _resize = state.resize_limiter.acquire(user_id)?;
+        let bytes = resize(state, file_id, &file, size, tallest, &cache_key).await?;
+        stream::once(async { Ok(Bytes::from(bytes)) }).boxed()
+    };
+    let stream = bandwidth::count(state, key, user_id, stream);
+
+    Ok(response
+        .header(header::CONTENT_TYPE, tallest.encoding.mime())
+        .status(StatusCode::OK)
+        .body(Body::wrap_stream(stream))
+        .unwrap())
+}
+
+/// Storage key of the result called `name` of a file, which is the key of the file for `CUSTOM`
+/// followed by a dot and the name.
+fn cache_key(file_id: &str, name: &str) -> String {
+    [&storage::key(storage::CUSTOM, file_id), ".", name].concat()
+}
+
+/// Scale a file to `size` and encode it like `rendition`, returning the result once it is cached
+/// under `cache_key`.
+async fn resize(
+    state: &AppState,
+    file_id: &str,
+    file: &File<'_, '_, '_>,
+    size: Size,
+    rendition: &Rendition,
+    cache_key: &str,
+) -> ApiResult<Vec<u8>> {
+    let AppState {
+        ref storage,
+        ref temp_path,
+        ref image_pool,
+        ..
+    } = state;
+    let config = &state.config;
+
+    let id = new_id(16);
+    let original_path = temp_path.join([&id, ".original"].concat());
+    let scratch_path = temp_path.join([&id, ".scratch"].concat());
+    let resized_path = temp_path.join([&id, ".custom"].concat());
+
+    let result = async {
+        storage::download(storage.as_ref(), &storage::key(storage::ORIGINAL, file_id), &original_path).await?;
+
+        let format = Format::detect(file.detected_mime, &file.metadata.name);
+        let (source, scratch, destination) = (original_path.clone(), scratch_path.clone(), resized_path.clone());
+        let (decoding, applied, rendition) = (Decoding::new(config), file.edit, rendition.clone());
+        image_pool
+            .run(move || {
+                let original = format::load(format, &source, &scratch, decoding)?;
+                if original.get_width() as u64 * original.get_height() as u64 > decoding.max_pixels {
+                    return Err(ApiError::PayloadTooLarge);
+                }
+                let image = edit::apply(ops::autorot(&original)?, &applied, &source)?;
+                format::save(&size.apply(&image)?, &rendition, &destination)
+            })
+            .await?;
+
+        let bytes = fs::read(&resized_path).await?;
+        storage.put(cache_key, &resized_path).await?;
+        block_in_place(|| record(state, cache_key, bytes.len() as u64))?;
+
+        Ok(bytes)
+    }
+    .await;
+
+    let _ = join!(
+        fs::remove_file(&original_path),
+        fs::remove_file(&scratch_path),
+        fs::remove_file(&resized_path)
+    );
+
+    result
+}
+
+/// List a result that was just stored, and start trimming the cache if it got too big.
+fn record(state: &AppState, cache_key: &str, bytes: u64) -> ApiResult<()> {
+    let cached = Cached {
+        created: Utc::now().timestamp(),
+        bytes,
+    };
+
+    let size = &state.custom_cache_size;
+    // Another request may have made the same result in the meantime.
+    if let Some(replaced) = state.custom_cache.insert(cache_key, bincode::serialize(&cached).unwrap())? {
+        size.bytes.fetch_sub(bincode::deserialize::<Cached>(&replaced).unwrap().bytes, Ordering::SeqCst);
+    }
+    let total = size.bytes.fetch_add(bytes, Ordering::SeqCst) + bytes;
+
+    if total > state.config.custom_cache_bytes && !size.trimming.swap(true, Ordering::SeqCst) {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = trim(&state).await {
+                warn!(error = %err, "Couldn't trim the cache of resized files");
+            }
+            state.custom_cache_size.trimming.store(false, Ordering::SeqCst);
+        });
+    }
+
+    Ok(())
+}
+
+/// Remove the oldest results until the cache is down to three quarters of `custom_cache_bytes`,
+/// so that it isn't walked again for every result that is added.
+async fn trim(state: &AppState) -> ApiResult<()> {
+    let AppState {
+        ref storage,
+        ref custom_cache,
+        ref custom_cache_size,
+        ref config,
+        ..
+    } = state;
+
+    let mut results = block_in_place(|| {
+        custom_cache
+            .iter()
+            .map(|entry| {
+                let (cache_key, cached_bytes) = entry?;
+                let cached: Cached = bincode::deserialize(&cached_bytes).unwrap();
+                Ok((cached.created, cache_key))
+            })
+            .collect::<sled::Result<Vec<_>>>()
+    })?;
+    results.sort();
+
+    let target = config.custom_cache_bytes / 4 * 3;
+    for (_, cache_key) in results {
+        if custom_cache_size.bytes.load(Ordering::SeqCst) <= target {
+            break;
+        }
+
+        // The file may have been deleted along with its results in the meantime.
+        if let Some(cached_bytes) = block_in_place(|| custom_cache.remove(&cache_key))? {
+            let cached: Cached = bincode::deserialize(&cached_bytes).unwrap();
+            custom_cache_size.bytes.fetch_sub(cached.bytes, Ordering::SeqCst);
+            storage.delete(std::str::from_utf8(&cache_key).unwrap()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove every cached result of a file that is being deleted.
+pub fn forget(state: &AppState, file_id: &str) -> ApiResult<()> {
+    let prefix = cache_key(file_id, "");
+
+    let mut cache_keys = vec![];
+    for entry in state.custom_cache.scan_prefix(&prefix) {
+        let (cache_key, _) = entry?;
+        if let Some(cached_bytes) = state.custom_cache.remove(&cache_key)? {
+            let cached: Cached = bincode::deserialize(&cached_bytes).unwrap();
+            state.custom_cache_size.bytes.fetch_sub(cached.bytes, Ordering::SeqCst);
+            cache_keys.push(std::str::from_utf8(&cache_key).unwrap().to_string());
+        }
+    }
+    storage::delete_blocking(state.storage.as_ref(), &cache_keys);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, TestServer};
+    use futures::TryStreamExt;
+    use hyper::{Body, Method, StatusCode};
+
+    #[test]
+    fn allowed_sizes() {
+        let allowed = [128, 256];
+
+        let size = Size::new(Some(128), None, Fit::Contain, &allowed).unwrap();
+        assert_eq!(size.name(2), "2-128x0-contain");
+        let size = Size::new(Some(256), Some(128), Fit::Cover, &allowed).unwrap();
+        assert_eq!(size.name(0), "0-256x128-cover");
+
+        assert!(Size::new(None, None, Fit::Contain, &allowed).is_err());
+        assert!(Size::new(Some(128), None, Fit::Cover, &allowed).is_err());
+        assert!(Size::new(Some(128), Some(100), Fit::Contain, &allowed).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn serve_custom_size() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+
+        let file_id = server.upload(&key, "black.png", png(64, 48)).await;
+        let custom = |query: &str| format!("/file/serve/custom/{}?key={}&{}", file_id, key, query);
+
+        for query in ["w=32", "h=16&w=32", "w=16&h=16&fit=cover"] {
+            let (status, body) = server.request(Method::GET, &custom(query), &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::OK, "{}", query);
+            assert!(!body.is_empty());
+        }
+        let prefix = format!("custom/{}.", file_id);
+        let cached = || server.state.custom_cache.scan_prefix(&prefix).count();
+        assert_eq!(cached(), 3);
+        let stored = server.state.storage.list(&prefix, None).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(stored.len(), 3);
+
+        // Results are served again from the cache.
+        let (status, _) = server.request(Method::GET, &custom("w=32"), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cached(), 3);
+
+        for query in ["w=33", "", "w=16&fit=cover", "w=16&fit=stretch", "w=-16"] {
+            let (status, _) = server.request(Method::GET, &custom(query), &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", query);
+        }
+
+        let path = format!("/file/serve/custom/{}?key={}&w=16", file_id, other);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn trim_custom_sizes() {
+        let server = TestServer::start_with(|config| config.custom_cache_bytes = 1).await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "black.png", png(64, 48)).await;
+        let path = format!("/file/serve/custom/{}?key={}&w=32", file_id, key);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The cache is trimmed in the background.
+        for _ in 0..50 {
+            if server.state.custom_cache.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert!(server.state.custom_cache.is_empty());
+        let prefix = format!("custom/{}.", file_id);
+        let stored = server.state.storage.list(&prefix, None).try_collect::<Vec<_>>().await.unwrap();
+        assert!(stored.is_empty());
+
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
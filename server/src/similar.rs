@@ -0,0 +1,191 @@
+//! Similar Photos
+//!
+//! Every image gets a perceptual hash when it is rendered, a difference hash of its smallest
+//! rendition: the image is shrunk to 9 by 8 pixels, and each of the 64 bits says whether a pixel
+//! is brighter than the one to its right. Retakes, crops and screenshots of the same scene differ
+//! in few bits, so photos count as similar when the Hamming distance between their hashes is
+//! small. Hashes of edited files are taken as they are shown.
+//!
+//! Hashes are indexed in the `phashes` tree under their owner, and `GET /file/:fileId/similar`
+//! compares the hash of a file with every other one of its owner's, which is quick even for large
+//! libraries since each key only needs a few bit operations. Locked files keep their hashes, but
+//! are left out of the results.
+
+use crate::{
+    common::{require_elevation, require_key, respond_ok, searchable, test_logged_in, AppState, File},
+    error::{ApiError, ApiResult},
+};
+use hyper::{Body, Request, Response};
+use libvips::{ops, VipsImage};
+use routerify::ext::RequestExt;
+use routerify_query::RequestQueryExt;
+use std::borrow::Cow;
+use tokio::task::block_in_place;
+use wire::SimilarFile;
+
+const WIDTH: usize = 9;
+const HEIGHT: usize = 8;
+/// Distance that files are searched within unless the request says otherwise.
+const DEFAULT_DISTANCE: u32 = 10;
+/// Beyond this, unrelated photos match about as often as similar ones do.
+const MAX_DISTANCE: u32 = 24;
+
+/// Hash an upright image.
+pub fn compute(image: &VipsImage) -> ApiResult<u64> {
+    let options = ops::ThumbnailImageOptions {
+        height: HEIGHT as i32,
+        size: ops::Size::Force,
+        ..ops::ThumbnailImageOptions::default()
+    };
+    let sample = ops::thumbnail_image_with_opts(image, WIDTH as i32, &options)?;
+
+    let srgb = ops::colourspace(&sample, ops::Interpretation::Srgb)?;
+    let flat = if srgb.get_bands() > 3 {
+        ops::flatten(&srgb)?
+    } else {
+        srgb
+    };
+
+    Ok(from_pixels(&flat.image_write_to_memory()))
+}
+
+/// Hash 9 by 8 packed RGB pixels.
+fn from_pixels(pixels: &[u8]) -> u64 {
+    let luma: Vec<u32> = pixels
+        .chunks_exact(3)
+        .map(|pixel| 299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32)
+        .collect();
+
+    let mut hash = 0;
+    for row in luma.chunks_exact(WIDTH).take(HEIGHT) {
+        for pair in row.windows(2) {
+            hash = hash << 1 | (pair[0] > pair[1]) as u64;
+        }
+    }
+    hash
+}
+
+pub fn key(owner_id: &str, phash: u64, file_id: &str) -> String {
+    format!("{}.{:016x}.{}", owner_id, phash, file_id)
+}
+
+/// Files of the user that look like the one asked about, closest first.
+pub async fn search(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let max_distance = req
+        .query("distance")
+        .map(|distance| distance.parse::<u32>().ok())
+        .unwrap_or(Some(DEFAULT_DISTANCE))
+        .filter(|&distance| distance <= MAX_DISTANCE)
+        .ok_or(ApiError::BadRequest)?;
+
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let file_id = parts.param("fileId").unwrap();
+    let AppState {
+        ref sessions,
+        ref files,
+        ref phashes,
+        ..
+    } = parts.data().unwrap();
+
+    block_in_place(|| {
+        test_logged_in(sessions, key)?;
+
+        let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+        if file.owner_id != owner_id {
+            return Err(ApiError::NotFound);
+        }
+        if file.locked {
+            require_elevation(&parts, key)?;
+        }
+
+        let mut similar = vec![];
+        let prefix = [owner_id, "."].concat();
+
+        if let Some(phash) = file.phash {
+            for entry in phashes.scan_prefix(&prefix).keys() {
+                let entry = entry?;
+                let (other, other_id) = std::str::from_utf8(&entry[prefix.len()..])
+                    .unwrap()
+                    .split_once('.')
+                    .unwrap();
+
+                let distance = (phash ^ u64::from_str_radix(other, 16).unwrap()).count_ones();
+                if distance <= max_distance && other_id != file_id && searchable(files, other_id)? {
+                    similar.push(SimilarFile {
+                        id: Cow::from(other_id.to_string()),
+                        distance,
+                    });
+                }
+            }
+        }
+
+        similar.sort_by(|a, b| (a.distance, &a.id).cmp(&(b.distance, &b.id)));
+        respond_ok(similar)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::json;
+
+    fn pixels(brightness: impl Fn(usize, usize) -> u8) -> Vec<u8> {
+        let mut pixels = vec![];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let value = brightness(x, y);
+                pixels.extend_from_slice(&[value, value, value]);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn hashes() {
+        let darkening = from_pixels(&pixels(|x, y| 200 - 10 * x as u8 - y as u8));
+        assert_eq!(darkening, u64::MAX);
+        assert_eq!(from_pixels(&pixels(|x, _| 10 * x as u8)), 0);
+
+        // Changing the exposure doesn't change the hash, while inverting changes every bit.
+        let scene = |x: usize, y: usize| ((x * 37 + y * 11) % 23 * 8) as u8;
+        let hash = from_pixels(&pixels(scene));
+        assert_eq!(from_pixels(&pixels(|x, y| scene(x, y) / 2 + 40)), hash);
+        let inverted = from_pixels(&pixels(|x, y| 255 - scene(x, y)));
+        assert_eq!((hash ^ inverted).count_ones(), 64);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn similar_photos() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+        let file_id = server.upload(&key, "IMG_0001.png", png(64, 48)).await;
+        let retake_id = server.upload(&key, "IMG_0002.png", png(32, 24)).await;
+        server.upload(&other, "IMG_0001.png", png(64, 48)).await;
+
+        // Files of other users are neither searched from nor found.
+        let similar_path = |key: &str, query: &str| format!("/file/{}/similar?key={}{}", file_id, key, query);
+        let (status, _) = server.request(Method::GET, &similar_path(&other, ""), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = server.request(Method::GET, &similar_path(&key, "&distance=65"), &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let similar = server.json(Method::GET, &similar_path(&key, ""), &()).await;
+        assert_eq!(similar, json!([{ "id": retake_id, "distance": 0 }]));
+
+        // Neither are files in the trash.
+        let path = format!("/file/{}?key={}", retake_id, key);
+        let (status, _) = server.request(Method::DELETE, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let similar = server.json(Method::GET, &similar_path(&key, "&distance=0"), &()).await;
+        assert_eq!(similar, json!([]));
+    }
+}
@@ -0,0 +1,212 @@
+//! Library Statistics
+//!
+//! Counting a library on every request would take as long as the library is big, so each user's
+//! totals are kept under their id in the `stats` tree. They are changed in the same transaction
+//! that adds a file to the library or takes it out again, by uploads, the trash, regeneration
+//! and measuring, so they never drift from the files. Purging a trashed file doesn't change
+//! them, since it was counted out when it was trashed.
+//!
+//! The date range is read from the user's library, which already tracks it, and albums are
+//! counted from the user's roles, of which there are few.
+//!
+//! Users who registered with an invitation that carries a quota have it under their id in the
+//! `quotas` tree, and uploads that would take their originals over it are turned away. Only
+//! originals count, since renditions are up to the server. Uploads that run at the same time are
+//! each checked against the totals from before them, so they can go over by a little.
+
+use crate::{
+    common::{require_key, respond_ok, test_logged_in, AppState, File},
+    error::{ApiError, ApiResult},
+};
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use tokio::task::block_in_place;
+use wire::{Album, LibraryStats, Role};
+
+/// Name that originals are counted under, rather than their storage kind.
+const ORIGINAL: &str = "original";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Counters {
+    files: u64,
+    bytes: BTreeMap<String, u64>,
+    mimes: BTreeMap<String, u64>,
+}
+
+impl Counters {
+    /// Count `file` in, or out of the counters when `added` isn't set.
+    fn count(&mut self, file: &File, added: bool) {
+        fn change(counter: &mut u64, by: u64, added: bool) {
+            if added {
+                *counter += by;
+            } else {
+                *counter = counter.saturating_sub(by);
+            }
+        }
+
+        change(&mut self.files, 1, added);
+        change(self.mimes.entry(file.detected_mime.to_string()).or_default(), 1, added);
+        if let Some(size) = file.size {
+            change(self.bytes.entry(ORIGINAL.to_string()).or_default(), size, added);
+        }
+        for (name, size) in &file.rendition_sizes {
+            change(self.bytes.entry(name.clone()).or_default(), *size, added);
+        }
+
+        self.mimes.retain(|_, count| *count > 0);
+        self.bytes.retain(|_, count| *count > 0);
+    }
+}
+
+/// Count `file` into the totals of its owner, or out of them when `added` isn't set.
+pub fn count(stats: &TransactionalTree, file: &File, added: bool) -> ConflictableTransactionResult<(), ApiError> {
+    let mut counters: Counters = match stats.get(file.owner_id)? {
+        Some(counters_bytes) => bincode::deserialize(&counters_bytes).unwrap(),
+        None => Counters::default(),
+    };
+
+    counters.count(file, added);
+    stats.insert(file.owner_id.as_bytes(), bincode::serialize(&counters).unwrap())?;
+
+    Ok(())
+}
+
+/// Fail if storing an original of `size` bytes would take `user_id` over their quota.
+pub fn check_quota(state: &AppState, user_id: &str, size: u64) -> ApiResult<()> {
+    let quota = match quota(&state.quotas, user_id)? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let used = match state.stats.get(user_id)? {
+        Some(counters_bytes) => {
+            let counters: Counters = bincode::deserialize(&counters_bytes).unwrap();
+            counters.bytes.get(ORIGINAL).copied().unwrap_or(0)
+        }
+        None => 0,
+    };
+
+    if used.saturating_add(size) > quota {
+        return Err(ApiError::QuotaExceeded);
+    }
+
+    Ok(())
+}
+
+fn quota(quotas: &sled::Tree, user_id: &str) -> ApiResult<Option<u64>> {
+    Ok(quotas
+        .get(user_id)?
+        .map(|quota_bytes| u64::from_be_bytes(quota_bytes.as_ref().try_into().unwrap())))
+}
+
+/// Count every file from scratch, replacing whatever totals there were.
+pub fn build(state: &AppState) -> ApiResult<()> {
+    let mut totals: HashMap<String, Counters> = HashMap::new();
+    for entry in state.files.iter() {
+        let (_, file_bytes) = entry?;
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+        totals.entry(file.owner_id.to_string()).or_default().count(&file, true);
+    }
+
+    state.stats.clear()?;
+    for (user_id, counters) in totals {
+        state.stats.insert(user_id.as_bytes(), bincode::serialize(&counters).unwrap())?;
+    }
+
+    Ok(())
+}
+
+pub async fn serve(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let AppState {
+        ref sessions,
+        ref stats,
+        ref libraries,
+        ref user_to_album,
+        ref quotas,
+        ..
+    } = parts.data().unwrap();
+
+    block_in_place(|| {
+        test_logged_in(sessions, key)?;
+
+        let counters: Counters = match stats.get(user_id)? {
+            Some(counters_bytes) => bincode::deserialize(&counters_bytes).unwrap(),
+            None => Counters::default(),
+        };
+
+        let date_range = match libraries.get(user_id)? {
+            Some(library_bytes) => bincode::deserialize::<Album>(&library_bytes).unwrap().date_range,
+            None => None,
+        };
+
+        let mut owned_albums = 0;
+        let mut shared_albums = 0;
+        for entry in user_to_album.scan_prefix([user_id, "."].concat()) {
+            let (_, role_bytes) = entry?;
+            if bincode::deserialize::<Role>(&role_bytes).unwrap().is_owner() {
+                owned_albums += 1;
+            } else {
+                shared_albums += 1;
+            }
+        }
+
+        respond_ok(LibraryStats {
+            files: counters.files,
+            bytes: counters.bytes.into_iter().collect(),
+            mimes: counters.mimes.into_iter().collect(),
+            date_range,
+            owned_albums,
+            shared_albums,
+            quota: quota(quotas, user_id)?,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn library_stats() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let first = server.upload(&key, "first.png", png(8, 8)).await;
+        server.upload(&key, "second.png", png(16, 16)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        server.json(Method::POST, &format!("/album?key={}", key), &settings).await;
+
+        let stats_path = format!("/user/stats?key={}", key);
+        let stats = server.json(Method::GET, &stats_path, &()).await;
+        assert_eq!(stats["files"], 2);
+        assert_eq!(stats["mimes"]["image/png"], 2);
+        assert!(stats["bytes"]["original"].as_u64().unwrap() > 0);
+        assert!(stats["date_range"].is_array());
+        assert_eq!(stats["owned_albums"], 1);
+        assert_eq!(stats["shared_albums"], 0);
+
+        // Trashed files aren't counted until they are restored.
+        let status = server.send(Method::DELETE, &format!("/file/{}?key={}", first, key), &()).await;
+        assert_eq!(status, StatusCode::OK);
+        let stats = server.json(Method::GET, &stats_path, &()).await;
+        assert_eq!(stats["files"], 1);
+
+        let restore = json!({ "kind": "file", "id": first });
+        let status = server.send(Method::POST, &format!("/trash/restore?key={}", key), &restore).await;
+        assert_eq!(status, StatusCode::OK);
+        let stats = server.json(Method::GET, &stats_path, &()).await;
+        assert_eq!(stats["files"], 2);
+        assert_eq!(stats["mimes"]["image/png"], 2);
+    }
+}
@@ -0,0 +1,257 @@
+use super::{download, ByteStream, Storage};
+use crate::config::StorageKey;
+use async_stream::try_stream;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Marks objects that were encrypted, so that ones from before encryption was turned on are still
+/// read as they are.
+const MAGIC: &[u8; 8] = b"photos\x00\x01";
+const KEY_ID_BYTES: usize = 8;
+/// The STREAM construction takes 5 bytes of the XChaCha20 nonce for its counter.
+const NONCE_BYTES: usize = 19;
+const HEADER_BYTES: usize = MAGIC.len() + KEY_ID_BYTES + NONCE_BYTES;
+
+/// Plaintext that is sealed at a time, each of which gains a 16 byte tag.
+const CHUNK_SIZE: usize = 64 * 1024;
+const SEALED_CHUNK_SIZE: usize = CHUNK_SIZE + 16;
+
+fn corrupt<E: std::fmt::Display>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Identifies a key in the objects that it sealed without giving it away.
+fn key_id(key: &StorageKey) -> [u8; KEY_ID_BYTES] {
+    Sha256::digest(key.0)[..KEY_ID_BYTES].try_into().unwrap()
+}
+
+fn cipher(key: &StorageKey) -> XChaCha20Poly1305 {
+    XChaCha20Poly1305::new(&key.0.into())
+}
+
+/// What the start of an object says about how it was stored.
+enum Header {
+    Plain,
+    Sealed {
+        key_id: [u8; KEY_ID_BYTES],
+        nonce: [u8; NONCE_BYTES],
+    },
+}
+
+/// Read enough of `stream` to tell whether it was sealed, returning what was read along with the
+/// header.
+async fn read_header(stream: &mut ByteStream) -> io::Result<(Header, BytesMut)> {
+    let mut buffer = BytesMut::new();
+    while buffer.len() < HEADER_BYTES {
+        match stream.try_next().await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+
+    if !buffer.starts_with(MAGIC) {
+        return Ok((Header::Plain, buffer));
+    }
+    if buffer.len() < HEADER_BYTES {
+        return Err(corrupt("encrypted object is truncated"));
+    }
+
+    let header = buffer.split_to(HEADER_BYTES);
+    let (key_id, nonce) = header[MAGIC.len()..].split_at(KEY_ID_BYTES);
+
+    Ok((
+        Header::Sealed {
+            key_id: key_id.try_into().unwrap(),
+            nonce: nonce.try_into().unwrap(),
+        },
+        buffer,
+    ))
+}
+
+/// Encrypts everything that is put into another backend, and decrypts it again as it is read.
+/// Objects are sealed in chunks with the STREAM construction, so that large originals can be
+/// streamed without holding them in memory, and start with the id of the key that sealed them so
+/// that keys can be rotated.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    /// The first key seals new objects.
+    keys: Vec<StorageKey>,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, keys: Vec<StorageKey>) -> Self {
+        assert!(!keys.is_empty());
+        EncryptedStorage { inner, keys }
+    }
+
+    /// Seal the local file at `path` into `sealed_path`.
+    async fn seal(&self, path: &Path, sealed_path: &Path) -> io::Result<()> {
+        let key = &self.keys[0];
+        let nonce: [u8; NONCE_BYTES] = thread_rng().gen();
+        let mut encryptor = EncryptorBE32::from_aead(cipher(key), &nonce.into());
+
+        let mut plain = fs::File::open(path).await?;
+        let mut sealed = fs::File::create(sealed_path).await?;
+        sealed.write_all(MAGIC).await?;
+        sealed.write_all(&key_id(key)).await?;
+        sealed.write_all(&nonce).await?;
+
+        // The last chunk is sealed differently, so a full chunk waits until more follows it.
+        let mut buffer = BytesMut::with_capacity(2 * CHUNK_SIZE);
+        while plain.read_buf(&mut buffer).await? > 0 {
+            while buffer.len() > CHUNK_SIZE {
+                let chunk = buffer.split_to(CHUNK_SIZE);
+                sealed.write_all(&encryptor.encrypt_next(&chunk[..]).map_err(corrupt)?).await?;
+            }
+            buffer.reserve(CHUNK_SIZE);
+        }
+        sealed.write_all(&encryptor.encrypt_last(&buffer[..]).map_err(corrupt)?).await?;
+
+        sealed.flush().await
+    }
+}
+
+#[async_trait]
+impl Storage for EncryptedStorage {
+    async fn put(&self, key: &str, path: &Path) -> io::Result<()> {
+        let mut sealed_path = path.as_os_str().to_owned();
+        sealed_path.push(".sealed");
+        let sealed_path = PathBuf::from(sealed_path);
+
+        let result = match self.seal(path, &sealed_path).await {
+            Ok(()) => self.inner.put(key, &sealed_path).await,
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&sealed_path).await;
+        }
+        result?;
+
+        fs::remove_file(path).await
+    }
+
+    async fn get_stream(&self, key: &str) -> io::Result<ByteStream> {
+        let mut inner = self.inner.get_stream(key).await?;
+
+        let (key_id, nonce, mut buffer) = match read_header(&mut inner).await? {
+            (Header::Plain, buffer) => return Ok(stream::once(async { Ok(buffer.freeze()) }).chain(inner).boxed()),
+            (Header::Sealed { key_id, nonce }, buffer) => (key_id, nonce, buffer),
+        };
+        let storage_key = self
+            .keys
+            .iter()
+            .find(|storage_key| self::key_id(storage_key) == key_id)
+            .ok_or_else(|| corrupt(format!("{} was encrypted with a key that isn't configured", key)))?;
+        let mut decryptor = DecryptorBE32::from_aead(cipher(storage_key), &nonce.into());
+
+        Ok(try_stream! {
+            while let Some(chunk) = inner.try_next().await? {
+                buffer.extend_from_slice(&chunk);
+
+                while buffer.len() > SEALED_CHUNK_SIZE {
+                    let sealed = buffer.split_to(SEALED_CHUNK_SIZE);
+                    yield Bytes::from(decryptor.decrypt_next(&sealed[..]).map_err(corrupt)?);
+                }
+            }
+
+            yield Bytes::from(decryptor.decrypt_last(&buffer[..]).map_err(corrupt)?);
+        }
+        .boxed())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        self.inner.exists(key).await
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str, start_after: Option<&'a str>) -> BoxStream<'a, io::Result<String>> {
+        self.inner.list(prefix, start_after)
+    }
+
+    async fn refresh(&self, key: &str, scratch: &Path) -> io::Result<bool> {
+        let mut inner = self.inner.get_stream(key).await?;
+        if let (Header::Sealed { key_id, .. }, _) = read_header(&mut inner).await? {
+            if key_id == self::key_id(&self.keys[0]) {
+                return Ok(false);
+            }
+        }
+        drop(inner);
+
+        download(self, key, scratch).await?;
+        self.put(key, scratch).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    async fn read(storage: &dyn Storage, key: &str) -> io::Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = storage.get_stream(key).await?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    #[tokio::test]
+    async fn seal_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let local: Arc<dyn Storage> = Arc::new(LocalStorage::new(&dir.path().join("data")));
+
+        let old = StorageKey([1; 32]);
+        let new = StorageKey([2; 32]);
+        let before = EncryptedStorage::new(local.clone(), vec![old.clone()]);
+
+        // Sizes around the chunk boundaries, and a file from before encryption was turned on.
+        let contents: Vec<Vec<u8>> = [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE]
+            .iter()
+            .map(|size| (0..*size).map(|i| i as u8).collect())
+            .collect();
+        for (i, contents) in contents.iter().enumerate() {
+            let path = dir.path().join(i.to_string());
+            std::fs::write(&path, contents).unwrap();
+            before.put(&format!("uploads/{}", i), &path).await.unwrap();
+            assert!(!path.exists());
+        }
+        let path = dir.path().join("plain");
+        std::fs::write(&path, b"plain").unwrap();
+        local.put("uploads/plain", &path).await.unwrap();
+
+        for (i, contents) in contents.iter().enumerate() {
+            let key = format!("uploads/{}", i);
+            assert_ne!(&read(local.as_ref(), &key).await.unwrap(), contents);
+            assert_eq!(&read(&before, &key).await.unwrap(), contents);
+        }
+        assert_eq!(read(&before, "uploads/plain").await.unwrap(), b"plain");
+
+        // After a rotation the old key is only needed until everything is sealed with the new one.
+        let rotating = EncryptedStorage::new(local.clone(), vec![new.clone(), old]);
+        let scratch = dir.path().join("scratch");
+        for i in 0..contents.len() {
+            assert!(rotating.refresh(&format!("uploads/{}", i), &scratch).await.unwrap());
+        }
+        assert!(rotating.refresh("uploads/plain", &scratch).await.unwrap());
+        assert!(!rotating.refresh("uploads/0", &scratch).await.unwrap());
+
+        let after = EncryptedStorage::new(local.clone(), vec![new]);
+        for (i, contents) in contents.iter().enumerate() {
+            assert_eq!(&read(&after, &format!("uploads/{}", i)).await.unwrap(), contents);
+        }
+        assert_eq!(read(&after, "uploads/plain").await.unwrap(), b"plain");
+        assert!(read(&before, "uploads/0").await.is_err());
+    }
+}
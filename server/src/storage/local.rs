@@ -0,0 +1,114 @@
+use super::{ByteStream, Storage};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use futures::stream::{BoxStream, StreamExt};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+const CHUNK_SIZE: usize = 1024 * 8;
+
+/// Stores each key as a file below `root`, using the key's kind as the directory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path) -> Self {
+        LocalStorage {
+            root: root.to_owned(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+fn file_stream(mut file: fs::File) -> ByteStream {
+    try_stream! {
+        loop {
+            let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+            file.read_buf(&mut buffer).await?;
+
+            if buffer.is_empty() {
+                break;
+            }
+
+            yield buffer.into();
+        }
+    }
+    .boxed()
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, path: &Path) -> io::Result<()> {
+        let target = self.path(key);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Renaming fails across file systems, in which case fall back to copying.
+        if fs::rename(path, &target).await.is_err() {
+            fs::copy(path, &target).await?;
+            fs::remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_stream(&self, key: &str) -> io::Result<ByteStream> {
+        let file = fs::File::open(self.path(key)).await?;
+        Ok(file_stream(file))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match fs::metadata(self.path(key)).await {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str, start_after: Option<&'a str>) -> BoxStream<'a, io::Result<String>> {
+        // Keys are only ever `<kind>/<file_id>`, so the directory is the part before the slash.
+        let (dir, file_prefix) = prefix.split_once('/').unwrap_or((prefix, ""));
+        let path = self.root.join(dir);
+
+        try_stream! {
+            let mut iter = match fs::read_dir(&path).await {
+                Ok(iter) => iter,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+                Err(err) => Err(err)?,
+            };
+
+            // Directories aren't read in any particular order, so the keys are sorted first.
+            let mut keys = vec![];
+            while let Some(entry) = iter.next_entry().await? {
+                if let Some(name) = entry.file_name().to_str() {
+                    if name.starts_with(file_prefix) {
+                        keys.push([dir, "/", name].concat());
+                    }
+                }
+            }
+            keys.sort();
+
+            for key in keys {
+                if start_after.is_none_or(|start_after| key.as_str() > start_after) {
+                    yield key;
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,143 @@
+//! File Storage Backends
+//!
+//! Originals and renditions are stored as opaque blobs addressed by keys of the form
+//! `<kind>/<file_id>`, where the kind is `ORIGINAL`, the name of a configured rendition, the
+//! `avif_kind` of a rendition that is cached in AVIF for clients that accept it, or the
+//! `animated_kind` of the tallest rendition of an animated image, `PREVIEW` for the preview strip
+//! of a video, or `STRIPPED` for a copy of the original without its metadata. Files that were
+//! resized on demand are cached under the key of the file for `CUSTOM`, a dot and their size.
+//! Uploads are staged and processed in the local temp directory, and only the finished files are
+//! handed to `put`.
+//!
+//! When storage keys are configured, the backend is wrapped in `EncryptedStorage`, which encrypts
+//! objects on their way in and decrypts them on their way out, so that nothing else has to know.
+
+mod encrypted;
+mod local;
+mod s3;
+
+use crate::config::{Config, Encoding, StorageConfig};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::{fs, io::AsyncWriteExt};
+
+pub use encrypted::EncryptedStorage;
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+pub const ORIGINAL: &str = "uploads";
+/// Kind of the preview strip of a video, which is also the quality that it is served as.
+pub const PREVIEW: &str = "preview";
+/// Kind of the copy of an original that members of albums which strip metadata are served.
+pub const STRIPPED: &str = "stripped";
+/// Kind of the files that were resized on demand, which are listed in `custom_cache`.
+pub const CUSTOM: &str = "custom";
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Store the local file at `path` under `key`. The file may be moved rather than copied.
+    async fn put(&self, key: &str, path: &Path) -> io::Result<()>;
+
+    async fn get_stream(&self, key: &str) -> io::Result<ByteStream>;
+
+    /// Remove `key`, succeeding if it doesn't exist.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    async fn exists(&self, key: &str) -> io::Result<bool>;
+
+    /// List every key that starts with `prefix` in order, beginning after `start_after` if it is
+    /// given.
+    fn list<'a>(&'a self, prefix: &'a str, start_after: Option<&'a str>) -> BoxStream<'a, io::Result<String>>;
+
+    /// Store `key` again if it isn't stored the way that new objects are, such as under a storage
+    /// key that has been rotated out, returning whether it was. `scratch` is a local path that may
+    /// be used along the way.
+    async fn refresh(&self, _key: &str, _scratch: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+pub fn key(kind: &str, file_id: &str) -> String {
+    [kind, "/", file_id].concat()
+}
+
+/// Kind of the AVIF copy of a rendition that is made when it is first requested.
+pub fn avif_kind(rendition: &str) -> String {
+    [rendition, "-avif"].concat()
+}
+
+/// Kind of the animated copy of a rendition, which only animated images have.
+pub fn animated_kind(rendition: &str) -> String {
+    [rendition, "-animated"].concat()
+}
+
+/// Every kind of file that can be stored for an upload.
+pub fn kinds(config: &Config) -> Vec<String> {
+    let mut kinds = vec![ORIGINAL.to_string()];
+
+    for rendition in config.renditions.iter() {
+        kinds.push(rendition.name.clone());
+
+        if rendition.encoding != Encoding::Avif {
+            kinds.push(avif_kind(&rendition.name));
+        }
+    }
+    if let Some(tallest) = config.renditions.first() {
+        kinds.push(animated_kind(&tallest.name));
+    }
+    kinds.push(PREVIEW.to_string());
+    kinds.push(STRIPPED.to_string());
+
+    kinds
+}
+
+/// Keys of the original and every rendition of a file.
+pub fn file_keys(config: &Config, file_id: &str) -> Vec<String> {
+    kinds(config).iter().map(|kind| key(kind, file_id)).collect()
+}
+
+/// Copy `key` into the local file at `path`.
+pub async fn download(storage: &dyn Storage, key: &str, path: &Path) -> io::Result<()> {
+    let mut stream = storage.get_stream(key).await?;
+    let mut file = fs::File::create(path).await?;
+
+    while let Some(chunk) = stream.try_next().await? {
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await
+}
+
+pub async fn open(config: &Config) -> Arc<dyn Storage> {
+    let storage: Arc<dyn Storage> = match config.storage {
+        StorageConfig::Local => Arc::new(LocalStorage::new(&config.data_path)),
+        StorageConfig::S3 {
+            ref bucket,
+            ref endpoint,
+            ref region,
+        } => Arc::new(S3Storage::new(bucket, endpoint.as_deref(), region.as_deref()).await),
+    };
+
+    if config.storage_keys.is_empty() {
+        storage
+    } else {
+        Arc::new(EncryptedStorage::new(storage, config.storage_keys.clone()))
+    }
+}
+
+/// Delete keys from synchronous code that is running under `block_in_place`.
+pub fn delete_blocking(storage: &dyn Storage, keys: &[String]) {
+    let handle = tokio::runtime::Handle::current();
+    for key in keys {
+        if let Err(err) = handle.block_on(storage.delete(key)) {
+            tracing::warn!("Couldn't delete {}: {}", key, err);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use super::{ByteStream, Storage};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use aws_sdk_s3::config::{BehaviorVersion, Region};
+use aws_sdk_s3::Client;
+use futures::stream::{BoxStream, StreamExt};
+use std::io;
+use std::path::Path;
+
+fn other<E: std::fmt::Display>(error: E) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+/// Stores each key as an object in an S3 compatible bucket. Credentials are taken from the
+/// standard AWS environment variables and configuration files.
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub async fn new(bucket: &str, endpoint: Option<&str>, region: Option<&str>) -> Self {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(endpoint) = endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        if let Some(region) = region {
+            loader = loader.region(Region::new(region.to_owned()));
+        }
+        let sdk_config = loader.load().await;
+
+        // Self hosted S3 implementations usually don't support virtual hosted buckets.
+        let s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(endpoint.is_some())
+            .build();
+
+        S3Storage {
+            client: Client::from_conf(s3_config),
+            bucket: bucket.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, path: &Path) -> io::Result<()> {
+        let body = aws_sdk_s3::primitives::ByteStream::from_path(path)
+            .await
+            .map_err(other)?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(body)
+            .send()
+            .await
+            .map_err(other)?;
+
+        tokio::fs::remove_file(path).await
+    }
+
+    async fn get_stream(&self, key: &str) -> io::Result<ByteStream> {
+        let output = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|err| match err.as_service_error() {
+                Some(service_err) if service_err.is_no_such_key() => {
+                    io::Error::new(io::ErrorKind::NotFound, key.to_owned())
+                }
+                _ => other(err),
+            })?;
+
+        let mut body = output.body;
+        let stream = try_stream! {
+            while let Some(chunk) = body.try_next().await.map_err(other)? {
+                yield chunk;
+            }
+        };
+
+        Ok(stream.boxed())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(other)?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> io::Result<bool> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(err) => match err.as_service_error() {
+                Some(service_err) if service_err.is_not_found() => Ok(false),
+                _ => Err(other(err)),
+            },
+        }
+    }
+
+    fn list<'a>(&'a self, prefix: &'a str, start_after: Option<&'a str>) -> BoxStream<'a, io::Result<String>> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .set_start_after(start_after.map(str::to_string))
+            .into_paginator()
+            .send();
+
+        try_stream! {
+            while let Some(page) = pages.next().await {
+                let page = page.map_err(other)?;
+                for object in page.contents() {
+                    if let Some(key) = object.key() {
+                        yield key.to_owned();
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,193 @@
+//! Stripped Originals
+//!
+//! Cameras write where a photo was taken and the serial number of the camera into the original,
+//! which every member of an album that it is shared in can download. The owner of an album can
+//! have it strip that metadata, and then members other than the owner of a file are served a copy
+//! that libvips wrote again without any, turned upright first since the orientation goes along
+//! with the rest. Copies are made when they are first asked for and stored as `STRIPPED`, so
+//! only the first download waits for one. Renditions are always saved without metadata, so only
+//! originals need this.
+//!
+//! A file is stripped when any album that it is in strips metadata, since members can ask for a
+//! file through any album that they are in. Originals of a type that libvips can't write, like
+//! videos, RAW files and files that were encrypted by the client, aren't served to those members
+//! at all.
+
+use crate::{
+    common::{new_id, AppState, File},
+    error::{ApiError, ApiResult},
+    format::{self, Decoding, Format},
+    storage,
+};
+use futures::join;
+use libvips::ops;
+use tokio::fs;
+
+/// Types that can be stripped, with the extension that libvips picks the saver by and the options
+/// that leave the metadata out. Lossy types are written at a high quality, since the copy stands
+/// in for the original.
+const STRIPPABLE: &[(&str, &str, &str)] = &[
+    ("image/jpeg", "jpg", "[Q=95,strip]"),
+    ("image/png", "png", "[strip]"),
+    ("image/webp", "webp", "[Q=95,strip]"),
+    ("image/tiff", "tif", "[strip]"),
+    ("image/heic", "heic", "[Q=95,strip]"),
+    ("image/heif", "heif", "[Q=95,strip]"),
+    ("image/avif", "avif", "[Q=95,strip]"),
+];
+
+pub fn strippable(mime: &str) -> bool {
+    STRIPPABLE.iter().any(|(strippable, _, _)| *strippable == mime)
+}
+
+/// Whether `user_id` is served the original of a file stripped of its metadata, which they are if
+/// they don't own it and any album that it is in strips metadata.
+pub fn applies(state: &AppState, user_id: &str, file_id: &str, file: &File) -> ApiResult<bool> {
+    if file.owner_id == user_id {
+        return Ok(false);
+    }
+
+    let prefix = [file_id, "."].concat();
+    for inclusion in state.inclusions.scan_prefix(&prefix).keys() {
+        if state.stripped_albums.contains_key(&inclusion?[prefix.len()..])? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Key of the copy of an original without its metadata, which is made the first time that it is
+/// asked for. `mime` and `name` are those of the file.
+pub async fn original(state: &AppState, file_id: &str, mime: &str, name: &str) -> ApiResult<String> {
+    let AppState {
+        ref storage,
+        ref temp_path,
+        ref image_pool,
+        ref config,
+        ..
+    } = state;
+
+    let (_, extension, options) = STRIPPABLE
+        .iter()
+        .find(|(strippable, _, _)| *strippable == mime)
+        .ok_or(ApiError::Unauthorized)?;
+
+    let key = storage::key(storage::STRIPPED, file_id);
+    if storage.exists(&key).await? {
+        return Ok(key);
+    }
+
+    let id = new_id(16);
+    let original_path = temp_path.join([&id, ".original"].concat());
+    let scratch_path = temp_path.join([&id, ".scratch"].concat());
+    let stripped_path = temp_path.join([&id, ".", extension].concat());
+
+    let result = async {
+        storage::download(storage.as_ref(), &storage::key(storage::ORIGINAL, file_id), &original_path).await?;
+
+        let format = Format::detect(mime, name);
+        let (source, scratch, destination) = (original_path.clone(), scratch_path.clone(), stripped_path.clone());
+        let decoding = Decoding::new(config);
+        image_pool
+            .run(move || {
+                let original = format::load(format, &source, &scratch, decoding)?;
+                if original.get_width() as u64 * original.get_height() as u64 > decoding.max_pixels {
+                    return Err(ApiError::PayloadTooLarge);
+                }
+                let upright = ops::autorot(&original)?;
+                upright.image_write_to_file(&[destination.to_str().unwrap(), options].concat())?;
+                Ok(())
+            })
+            .await?;
+
+        storage.put(&key, &stripped_path).await?;
+        Ok(key)
+    }
+    .await;
+
+    let _ = join!(
+        fs::remove_file(&original_path),
+        fs::remove_file(&scratch_path),
+        fs::remove_file(&stripped_path)
+    );
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage;
+    use crate::testing::{jpeg_with_exif, png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::json;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn renditions_without_metadata() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "camera.jpg", jpeg_with_exif(64, 48, "LeakyCam")).await;
+        let has_make = |bytes: &[u8]| bytes.windows(8).any(|window| window == b"LeakyCam");
+
+        // The original is kept as it was sent.
+        let path = format!("/file/large/{}?key={}", file_id, key);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(has_make(&body));
+
+        for path in [
+            format!("/file/medium/{}?key={}", file_id, key),
+            format!("/file/small/{}?key={}", file_id, key),
+            format!("/file/serve/custom/{}?key={}&w=16", file_id, key),
+        ] {
+            let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert!(!has_make(&body), "{}", path);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn strip_metadata() {
+        let server = TestServer::start().await;
+        let owner = server.signup("owner@example.com").await;
+        let reader = server.signup("reader@example.com").await;
+
+        let file_id = server.upload(&owner, "black.png", png(64, 48)).await;
+
+        let settings = json!({ "name": "Trip", "time_zone": "UTC" });
+        let album = server.json(Method::POST, &format!("/album?key={}", owner), &settings).await;
+        let album_id = album["id"].as_str().unwrap();
+
+        let files = json!({ "ids": [file_id] });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/files?key={}", album_id, owner), &files)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let share = json!({ "email": "reader@example.com", "role": "Reader" });
+        let status = server
+            .send(Method::POST, &format!("/album/{}/share?key={}", album_id, owner), &share)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Only the owner can change it.
+        let privacy = |key: &str| format!("/album/{}/privacy?key={}", album_id, key);
+        let strip = json!({ "strip_metadata": true });
+        assert_eq!(server.send(Method::PUT, &privacy(&reader), &strip).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(server.json(Method::GET, &privacy(&reader), &()).await["strip_metadata"], false);
+        assert_eq!(server.send(Method::PUT, &privacy(&owner), &strip).await, StatusCode::OK);
+        assert_eq!(server.json(Method::GET, &privacy(&reader), &()).await["strip_metadata"], true);
+
+        let stripped = storage::key(storage::STRIPPED, &file_id);
+        let path = format!("/file/large/{}?key={}", file_id, owner);
+        let (status, _) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!server.state.storage.exists(&stripped).await.unwrap());
+
+        let path = format!("/file/large/{}?key={}&album={}", file_id, reader, album_id);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.is_empty());
+        assert!(server.state.storage.exists(&stripped).await.unwrap());
+    }
+}
@@ -0,0 +1,349 @@
+//! Test Harness
+//!
+//! `TestServer` runs the whole router in-process on an ephemeral port, backed by a temporary
+//! database and data directory, so that tests can go through the same requests that clients
+//! make. Tests that use it need a multi-threaded runtime because handlers call `block_in_place`:
+//!
+//! ```ignore
+//! #[tokio::test(flavor = "multi_thread")]
+//! async fn example() {
+//!     let server = TestServer::start().await;
+//!     let key = server.signup("a@example.com").await;
+//! }
+//! ```
+
+use crate::{
+    common::AppState,
+    config::{Config, Encoding, Registration, Rendition, StorageConfig},
+    limit::Limit,
+    migrate, storage, trace,
+};
+use hyper::client::HttpConnector;
+use hyper::server::conn::AddrStream;
+use hyper::service::make_service_fn;
+use hyper::{header, Body, Client, Method, Request, Server, StatusCode};
+use routerify::RequestServiceBuilder;
+use serde::Serialize;
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use tempfile::TempDir;
+use wire::{FileMetadata, UserDetails};
+
+pub const PASSWORD: &str = "correct horse battery staple";
+pub const ADMIN_EMAIL: &str = "admin@example.com";
+
+/// libvips may only be set up once per process, and has to stay up for every test, so it is kept
+/// in a static that is never dropped.
+static VIPS: OnceLock<libvips::VipsApp> = OnceLock::new();
+
+fn init_vips() {
+    VIPS.get_or_init(|| libvips::VipsApp::new("test", false).unwrap());
+}
+
+fn config(data_path: &std::path::Path) -> Config {
+    // Tests log in far more often than any person would.
+    let unlimited = Limit {
+        burst: 1_000_000,
+        per_minute: 1_000_000,
+    };
+
+    Config {
+        addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        tls: None,
+        data_path: data_path.to_path_buf(),
+        db_path: None,
+        storage: StorageConfig::Local,
+        storage_keys: vec![],
+        log_level: "warn".to_string(),
+        log_json: false,
+        auth_ip_limit: unlimited,
+        auth_email_limit: unlimited,
+        lockout_threshold: 10,
+        lockout_seconds: 15 * 60,
+        trusted_proxies: vec![],
+        public_url: "http://localhost".to_string(),
+        smtp: None,
+        oidc: None,
+        detection: None,
+        verify_token_seconds: 60 * 60,
+        reset_token_seconds: 60 * 60,
+        elevation_seconds: 5 * 60,
+        activity_retention_days: 90,
+        trash_retention_days: 30,
+        verify_interval_days: 0,
+        max_upload_bytes: 1 << 24,
+        token_bytes_per_day: 0,
+        user_bytes_per_day: 0,
+        upload_timeout_seconds: 60,
+        body_timeout_seconds: 60,
+        max_body_bytes: 1 << 20,
+        header_timeout_seconds: 30,
+        body_idle_seconds: 60,
+        max_uploads_per_user: 4,
+        max_batch_files: 10,
+        batch_upload_parallelism: 2,
+        vips_threads: 2,
+        vips_queue: 16,
+        max_image_pixels: 200_000_000,
+        max_animation_frames: 200,
+        poster_position: 0.1,
+        preview_frames: 10,
+        renditions: vec![
+            Rendition {
+                name: "medium".to_string(),
+                height: 400,
+                encoding: Encoding::Webp,
+                quality: 75,
+            },
+            Rendition {
+                name: "small".to_string(),
+                height: 10,
+                encoding: Encoding::Webp,
+                quality: 75,
+            },
+        ],
+        custom_sizes: vec![16, 32],
+        custom_cache_bytes: 1 << 20,
+        max_resizes_per_user: 2,
+        allow_query_key: true,
+        cookie_sessions: true,
+        admin_emails: vec![ADMIN_EMAIL.to_string()],
+        admin_networks: vec![],
+        registration: Registration::Open,
+        regenerate_delay_ms: 0,
+        job_workers: 1,
+        clean_delay_ms: 0,
+        metrics_addr: None,
+    }
+}
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    /// Shares its trees with the running server, so tests can check the database directly.
+    pub state: AppState,
+    /// For requests that need more of the response than `request` returns.
+    pub client: Client<HttpConnector>,
+    _data: TempDir,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start a server with a configuration that `configure` has adjusted.
+    pub async fn start_with<F: FnOnce(&mut Config)>(configure: F) -> Self {
+        init_vips();
+
+        let data = tempfile::tempdir().unwrap();
+        let mut config = config(data.path());
+        configure(&mut config);
+
+        let storage = storage::open(&config).await;
+        let state = AppState::new(config, storage);
+        state.create_dirs().unwrap();
+        migrate::run(&state).unwrap();
+
+        let builder = Mutex::new(RequestServiceBuilder::new(crate::build_router(state.clone())).unwrap());
+        let metrics = state.metrics.clone();
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let remote_addr = conn.remote_addr();
+            let service = builder.lock().unwrap().build(remote_addr);
+            futures::future::ok::<_, Infallible>(trace::Traced::new(service, remote_addr, metrics.clone()))
+        });
+
+        let server = Server::bind(&state.config.addr).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        TestServer {
+            addr,
+            state,
+            client: Client::new(),
+            _data: data,
+        }
+    }
+
+    /// Send a request to `path`, which already includes any query, and return the status and body.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Body,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+        for (name, value) in headers {
+            request = request.header(*name, value.as_str());
+        }
+
+        let response = self.client.request(request.body(body).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, body.to_vec())
+    }
+
+    /// Send `json` and parse the response as JSON, panicking unless it succeeded.
+    pub async fn json<T: Serialize>(&self, method: Method, path: &str, json: &T) -> Value {
+        let body = Body::from(serde_json::to_vec(json).unwrap());
+        let (status, body) = self.request(method, path, &[], body).await;
+
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Send `json` and return the status, for requests that respond with an empty body.
+    pub async fn send<T: Serialize>(&self, method: Method, path: &str, json: &T) -> StatusCode {
+        let body = Body::from(serde_json::to_vec(json).unwrap());
+        self.request(method, path, &[], body).await.0
+    }
+
+    pub async fn create_user(&self, email: &str) {
+        let details = UserDetails {
+            email: email.into(),
+            password: PASSWORD.into(),
+        };
+        assert_eq!(self.send(Method::POST, "/user", &details).await, StatusCode::OK);
+    }
+
+    /// Log in and return the session key.
+    pub async fn login(&self, email: &str) -> String {
+        let details = UserDetails {
+            email: email.into(),
+            password: PASSWORD.into(),
+        };
+        let key = self.json(Method::POST, "/user/auth", &details).await;
+        key["key"].as_str().unwrap().to_string()
+    }
+
+    /// Create a user and log them in.
+    pub async fn signup(&self, email: &str) -> String {
+        self.create_user(email).await;
+        self.login(email).await
+    }
+
+    /// Create the user of `ADMIN_EMAIL` with a verified email, without going through registration,
+    /// and log them in.
+    pub async fn signup_admin(&self) -> String {
+        let hash = crate::user::hash_password(PASSWORD.as_bytes(), &self.state.argon_config).unwrap();
+        let user_id = crate::user::register(&self.state.users, &self.state.emails, ADMIN_EMAIL, &hash).unwrap();
+        let now = chrono::Utc::now().timestamp();
+        self.state.verified.insert(user_id.as_bytes(), &now.to_be_bytes()).unwrap();
+        self.login(ADMIN_EMAIL).await
+    }
+
+    /// Upload `bytes` as a file called `name` to `path`, which holds the query.
+    pub async fn upload_to(&self, path: &str, name: &str, bytes: impl Into<Body>) -> (StatusCode, Vec<u8>) {
+        let metadata = FileMetadata {
+            last_modified: 0,
+            name: name.into(),
+            mime: "image/png".into(),
+        };
+        let metadata = base64::encode_config(serde_json::to_vec(&metadata).unwrap(), base64::URL_SAFE);
+
+        self.request(
+            Method::POST,
+            path,
+            &[("upload-metadata", metadata), (header::CONTENT_TYPE.as_str(), "image/png".to_string())],
+            bytes.into(),
+        )
+        .await
+    }
+
+    /// Upload `bytes` as a file called `name`, returning the file id.
+    pub async fn upload(&self, key: &str, name: &str, bytes: Vec<u8>) -> String {
+        let (status, body) = self.upload_to(&format!("/file?key={}", key), name, bytes).await;
+
+        assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+        let file: Value = serde_json::from_slice(&body).unwrap();
+        file["id"].as_str().unwrap().to_string()
+    }
+}
+
+/// A black PNG that is big enough for every rendition.
+pub fn png(width: i32, height: i32) -> Vec<u8> {
+    init_vips();
+
+    libvips::ops::black(width, height)
+        .unwrap()
+        .image_write_to_buffer(".png")
+        .unwrap()
+}
+
+/// A black JPEG whose EXIF names `make` as the camera that took it.
+pub fn jpeg_with_exif(width: i32, height: i32, make: &str) -> Vec<u8> {
+    init_vips();
+
+    let jpeg = libvips::ops::black(width, height)
+        .unwrap()
+        .image_write_to_buffer(".jpg")
+        .unwrap();
+
+    // A little endian TIFF header and a single IFD with the make, whose value follows the IFD.
+    let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x0f\x01\x02\0".to_vec();
+    exif.extend_from_slice(&(make.len() as u32 + 1).to_le_bytes());
+    exif.extend_from_slice(&26u32.to_le_bytes());
+    exif.extend_from_slice(&[0; 4]);
+    exif.extend_from_slice(make.as_bytes());
+    exif.push(0);
+
+    // The APP1 segment goes right after the start of image marker.
+    let mut bytes = jpeg[..2].to_vec();
+    bytes.extend_from_slice(&[0xff, 0xe1]);
+    bytes.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    bytes.extend_from_slice(&exif);
+    bytes.extend_from_slice(&jpeg[2..]);
+    bytes
+}
+
+/// An OpenID Connect provider that logs everyone in as `email`, returning its issuer URL.
+pub async fn start_provider(email: &'static str) -> String {
+    let addr = std::sync::Arc::new(std::sync::OnceLock::<SocketAddr>::new());
+    let issuer = addr.clone();
+
+    let make_service = make_service_fn(move |_| {
+        let addr = *issuer.get().unwrap();
+        futures::future::ok::<_, Infallible>(hyper::service::service_fn(move |req: Request<Body>| {
+            let body = match req.uri().path() {
+                "/.well-known/openid-configuration" => serde_json::json!({
+                    "authorization_endpoint": format!("http://{}/authorize", addr),
+                    "token_endpoint": format!("http://{}/token", addr),
+                    "userinfo_endpoint": format!("http://{}/userinfo", addr),
+                }),
+                "/token" => serde_json::json!({ "access_token": "token", "token_type": "Bearer" }),
+                _ => serde_json::json!({ "email": email, "email_verified": true }),
+            };
+            futures::future::ok::<_, Infallible>(hyper::Response::new(Body::from(body.to_string())))
+        }))
+    });
+
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    addr.set(server.local_addr()).unwrap();
+    tokio::spawn(server);
+
+    format!("http://{}", addr.get().unwrap())
+}
+
+/// A detection service that finds a dog and a face in everything, returning its URL.
+pub async fn start_detector() -> String {
+    let make_service = make_service_fn(|_| {
+        futures::future::ok::<_, Infallible>(hyper::service::service_fn(|_: Request<Body>| {
+            let body = serde_json::json!({
+                "labels": [{ "name": "Dog", "confidence": 0.9 }, { "name": "grass", "confidence": 0.4 }],
+                "faces": [{ "bounds": [0.25, 0.25, 0.5, 0.5], "embedding": [0.5, -0.5] }],
+            });
+            futures::future::ok::<_, Infallible>(hyper::Response::new(Body::from(body.to_string())))
+        }))
+    });
+
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    format!("http://{}/detect", addr)
+}
@@ -0,0 +1,189 @@
+//! HTTPS Termination
+//!
+//! Hyper's `Server` only understands plain TCP, so TLS connections are accepted by hand and each
+//! one is handed a `RequestService` built from the shared router.
+
+use crate::config::TlsConfig;
+use crate::error::ApiError;
+use crate::metrics::Metrics;
+use crate::trace::Traced;
+use futures::future::{self, Future};
+use hyper::http::uri::Authority;
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use routerify::{RequestServiceBuilder, Router};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+/// How long a client gets to finish the TLS handshake before the connection is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed accept, which is usually from running out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+fn invalid_data<E: std::fmt::Display>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+pub fn load_acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let mut cert_reader = BufReader::new(File::open(&config.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let mut key_reader = BufReader::new(File::open(&config.key_path)?);
+    let key = rustls_pemfile::read_all(&mut key_reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data("no private key found"))?;
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Serve `router` over TLS on `addr` until `shutdown` resolves.
+pub async fn serve(
+    router: Router<Body, ApiError>,
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    header_timeout: Duration,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let mut builder = RequestServiceBuilder::new(router).map_err(invalid_data)?;
+    let listener = TcpListener::bind(addr).await?;
+
+    futures::pin_mut!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return Ok(()),
+        };
+
+        let (stream, remote_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Couldn't accept a connection: {}", err);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let service = Traced::new(builder.build(remote_addr), remote_addr, metrics.clone());
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            // Handshake failures are the client's problem, so they are only logged.
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let connection = Http::new()
+                        .http1_header_read_timeout(header_timeout)
+                        .serve_connection(stream, service);
+                    if let Err(err) = connection.await {
+                        warn!("Connection error from {}: {}", remote_addr, err);
+                    }
+                }
+                Ok(Err(err)) => warn!("TLS handshake failed for {}: {}", remote_addr, err),
+                Err(_) => warn!("TLS handshake timed out for {}", remote_addr),
+            }
+        });
+    }
+}
+
+fn redirect(req: Request<Body>, https_port: u16) -> Response<Body> {
+    // Parsing the header as an authority drops the port without cutting into IPv6 addresses.
+    let authority = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+
+    let host = match &authority {
+        Some(authority) => authority.host(),
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path)
+    };
+
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Plain HTTP listener that sends every request to the HTTPS port.
+pub async fn serve_redirect(
+    addr: SocketAddr,
+    https_port: u16,
+    header_timeout: Duration,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        future::ok::<_, Infallible>(service_fn(move |req| {
+            future::ok::<_, Infallible>(redirect(req, https_port))
+        }))
+    });
+
+    Server::bind(&addr)
+        .http1_header_read_timeout(header_timeout)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::redirect;
+    use hyper::{header, Body, Request};
+
+    fn location(host: &str, https_port: u16) -> String {
+        let req = Request::get("/album?key=1").header(header::HOST, host).body(Body::empty()).unwrap();
+        let response = redirect(req, https_port);
+        response.headers()[header::LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn redirect_hosts() {
+        assert_eq!(location("example.com", 443), "https://example.com/album?key=1");
+        assert_eq!(location("example.com:80", 8443), "https://example.com:8443/album?key=1");
+        assert_eq!(location("[::1]", 443), "https://[::1]/album?key=1");
+        assert_eq!(location("[::1]:80", 8443), "https://[::1]:8443/album?key=1");
+    }
+}
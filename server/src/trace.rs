@@ -0,0 +1,104 @@
+//! Request Tracing
+//!
+//! Every request runs inside a `request` span carrying its method, path and remote address. The
+//! address of the client behind any trusted proxies is recorded on the span once the router has
+//! worked it out, and the authenticated user id once a session key has been checked. The status
+//! and latency are logged when the response is ready, and counted in `Metrics`.
+
+use crate::config::Config;
+use crate::metrics::Metrics;
+use futures::future::{BoxFuture, FutureExt};
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{field, info, info_span, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_level).expect("Invalid log level");
+
+    if config.log_json {
+        tracing_subscriber::fmt()
+            .json()
+            .with_current_span(true)
+            .with_env_filter(filter)
+            .init();
+    } else {
+        tracing_subscriber::fmt().with_env_filter(filter).init();
+    }
+}
+
+/// Attach the authenticated user to the current request span.
+pub fn record_user(user_id: &str) {
+    Span::current().record("user_id", user_id);
+}
+
+/// Wraps a per-connection service so that each request is handled inside its own span.
+pub struct Traced<S> {
+    inner: S,
+    remote_addr: SocketAddr,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Traced<S> {
+    pub fn new(inner: S, remote_addr: SocketAddr, metrics: Arc<Metrics>) -> Self {
+        Traced {
+            inner,
+            remote_addr,
+            metrics,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for Traced<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let span = info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            remote_addr = %self.remote_addr,
+            client_addr = field::Empty,
+            user_id = field::Empty,
+        );
+
+        let start = Instant::now();
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let metrics = self.metrics.clone();
+        let future = span.in_scope(|| self.inner.call(req));
+
+        async move {
+            let result = future.await;
+
+            if let Ok(response) = &result {
+                let latency = start.elapsed();
+                info!(
+                    status = response.status().as_u16(),
+                    latency_ms = latency.as_millis() as u64,
+                    "finished"
+                );
+                metrics.record_request(&method, &path, response.status().as_u16(), latency);
+            }
+
+            result
+        }
+        .instrument(span)
+        .boxed()
+    }
+}
@@ -0,0 +1,654 @@
+//! Trash
+//!
+//! Deleting a file or an album only moves its record into the `trash` tree, keyed by
+//! `<user_id>.<kind>.<id>`, so that it can be restored for `trash_retention_days`. A trashed file
+//! is taken out of the owner's library and of every album, remembering its captions, and a
+//! trashed album loses all of its members, remembering their roles. Everything else, including
+//! the stored renditions and the album fragments, is left in place until the sweeper purges the
+//! entry with a `delete::Command`. Moving blobs somewhere else would mean copying them when the
+//! storage is remote, and nothing can reach them without a record anyway.
+
+use crate::{
+    album::{engine::Engine, rebuild},
+    common::{join, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, File},
+    delete,
+    endpoint::EndpointExt,
+    error::{ApiError, ApiResult},
+    events, geo, library, similar, stats,
+};
+use chrono::offset::Utc;
+use hyper::{Body, Request, Response};
+use routerify::{ext::RequestExt, Router};
+use serde::{Deserialize, Serialize};
+use sled::Transactional;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::{task::block_in_place, time};
+use tracing::{info, warn};
+use wire::{endpoint, Album, Role, TrashEntry, TrashKind, TrashRestore};
+
+/// How often the sweeper looks for entries that have outlived the retention period.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Trashed<'a> {
+    deleted: i64,
+    #[serde(borrow)]
+    item: Item<'a>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Item<'a> {
+    File {
+        /// Boxed, since files are much bigger than albums.
+        #[serde(borrow)]
+        file: Box<File<'a, 'a, 'a>>,
+        /// Albums that the file was in, along with its caption in each.
+        albums: Vec<(String, Option<String>)>,
+    },
+    Album {
+        #[serde(borrow)]
+        album: Album<'a>,
+        members: Vec<(String, Role)>,
+    },
+}
+
+fn kind_name(kind: TrashKind) -> &'static str {
+    match kind {
+        TrashKind::File => "file",
+        TrashKind::Album => "album",
+    }
+}
+
+fn key(user_id: &str, kind: TrashKind, id: &str) -> String {
+    [user_id, ".", kind_name(kind), ".", id].concat()
+}
+
+/// Move a file that `owner_id` owns into the trash.
+pub fn move_file(state: &AppState, owner_id: &str, file_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref file_names,
+        ref geo,
+        ref phashes,
+        ref libraries,
+        ref library_fragments,
+        ref albums,
+        ref fragments,
+        ref inclusions,
+        ref trash,
+        ref stats,
+        ..
+    } = state;
+
+    let mut album_ids = vec![];
+    for entry in inclusions.scan_prefix([file_id, "."].concat()) {
+        let (inclusion, _) = entry?;
+        let (_, album_id) = std::str::from_utf8(&inclusion).unwrap().split_once('.').unwrap();
+        album_ids.push(album_id.to_string());
+    }
+
+    let trees = (
+        files,
+        file_names,
+        geo,
+        phashes,
+        libraries,
+        library_fragments,
+        albums,
+        fragments,
+        inclusions,
+        trash,
+        stats,
+    );
+    let (library_head, updated) = trees.transaction(
+        |(files, file_names, geo, phashes, libraries, library_fragments, albums, fragments, inclusions, trash, stats)| {
+            let file_bytes = files.remove(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let file: File = bincode::deserialize(&file_bytes).unwrap();
+
+            if file.owner_id != owner_id {
+                return Err(ApiError::NotFound.into());
+            }
+
+            file_names.remove([owner_id, ".", &file.metadata.name].concat().as_bytes())?;
+            stats::count(stats, &file, false)?;
+
+            if let Some(location) = file.location {
+                geo.remove(geo::key(owner_id, location, file_id).as_bytes())?;
+            }
+            if let Some(phash) = file.phash {
+                phashes.remove(similar::key(owner_id, phash, file_id).as_bytes())?;
+            }
+
+            // Hidden and locked files aren't in the library to begin with.
+            let library_head = library::modify(libraries, library_fragments, owner_id, |e| {
+                if !file.in_library() {
+                    Ok(())
+                } else {
+                    e.remove(file_id, &file)
+                }
+            })?;
+
+            let mut captions = vec![];
+            let mut updated = vec![];
+            for album_id in &album_ids {
+                inclusions.remove([file_id, ".", album_id].concat().as_bytes())?;
+
+                // Albums that are in the trash themselves drop the file when they are restored.
+                if let Some(album_bytes) = albums.get(album_id.as_bytes())? {
+                    let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                    let mut e = Engine::new(album_id, &mut album, fragments)?;
+                    let caption = e.take(file_id, &file)?;
+                    e.commit()?;
+
+                    albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+
+                    captions.push((album_id.clone(), caption));
+                    updated.push((album_id.clone(), album.fragment_head));
+                }
+            }
+
+            let trashed = Trashed {
+                deleted: Utc::now().timestamp(),
+                item: Item::File {
+                    file: Box::new(file),
+                    albums: captions,
+                },
+            };
+            trash.insert(
+                key(owner_id, TrashKind::File, file_id).as_bytes(),
+                bincode::serialize(&trashed).unwrap(),
+            )?;
+
+            Ok((library_head, updated))
+        },
+    )?;
+
+    library::updated(state, owner_id, library_head);
+    for (album_id, fragment_head) in updated {
+        events::album_updated(state, &album_id, fragment_head);
+    }
+
+    Ok(())
+}
+
+/// Move an album that `user_id` owns into the trash, taking it away from every member. A rebuild
+/// that is underway is dropped, and its settings are applied when the album is restored.
+pub fn move_album(state: &AppState, user_id: &str, album_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref albums,
+        ref fragments,
+        ref user_to_album,
+        ref album_to_user,
+        ref trash,
+        ref rebuilds,
+        ..
+    } = state;
+
+    let mut member_ids = vec![];
+    for entry in album_to_user.scan_prefix([album_id, "."].concat()) {
+        let (key, _) = entry?;
+        let (_, member_id) = std::str::from_utf8(&key).unwrap().split_once('.').unwrap();
+        member_ids.push(member_id.to_string());
+    }
+
+    let members = (albums, fragments, user_to_album, album_to_user, trash, rebuilds).transaction(
+        |(albums, fragments, user_to_album, album_to_user, trash, rebuilds)| {
+            let role_bytes = user_to_album
+                .get([user_id, ".", album_id].concat())?
+                .ok_or(ApiError::Unauthorized)?;
+            let role: Role = bincode::deserialize(&role_bytes).unwrap();
+            if !role.is_owner() {
+                return Err(ApiError::Unauthorized.into());
+            }
+
+            let album_bytes = albums.remove(album_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+            // Restoring places every file again, so a rebuild that is underway can wait until
+            // then.
+            if let Some((time_zone, sort)) = rebuild::cancel(album_id, fragments, rebuilds)? {
+                album.description.time_zone = time_zone;
+                album.description.sort = sort;
+            }
+
+            let mut members = vec![];
+            for member_id in &member_ids {
+                album_to_user.remove([album_id, ".", member_id].concat().as_bytes())?;
+                if let Some(role_bytes) = user_to_album.remove([member_id, ".", album_id].concat().as_bytes())? {
+                    members.push((member_id.clone(), bincode::deserialize(&role_bytes).unwrap()));
+                }
+            }
+
+            let trashed = Trashed {
+                deleted: Utc::now().timestamp(),
+                item: Item::Album {
+                    album,
+                    members: members.clone(),
+                },
+            };
+            trash.insert(
+                key(user_id, TrashKind::Album, album_id).as_bytes(),
+                bincode::serialize(&trashed).unwrap(),
+            )?;
+
+            Ok(members)
+        },
+    )?;
+
+    for (member_id, _) in members {
+        events::shares_changed(state, album_id, &member_id);
+    }
+
+    Ok(())
+}
+
+/// Put a trashed file back into the library and into the albums that it was in, as long as the
+/// owner can still write to them. Fails with `FileExists` if its name has been taken since.
+fn restore_file(state: &AppState, owner_id: &str, file_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref files,
+        ref file_names,
+        ref geo,
+        ref phashes,
+        ref libraries,
+        ref library_fragments,
+        ref albums,
+        ref fragments,
+        ref inclusions,
+        ref user_to_album,
+        ref trash,
+        ref stats,
+        ..
+    } = state;
+
+    let trees = (
+        files,
+        file_names,
+        geo,
+        phashes,
+        libraries,
+        library_fragments,
+        albums,
+        fragments,
+        inclusions,
+        user_to_album,
+        trash,
+        stats,
+    );
+    let (library_head, updated) = trees.transaction(
+        |(
+            files,
+            file_names,
+            geo,
+            phashes,
+            libraries,
+            library_fragments,
+            albums,
+            fragments,
+            inclusions,
+            user_to_album,
+            trash,
+            stats,
+        )| {
+            let trashed_bytes = trash
+                .remove(key(owner_id, TrashKind::File, file_id).as_bytes())?
+                .ok_or(ApiError::NotFound)?;
+            let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+            let (file, captions) = match trashed.item {
+                Item::File { file, albums } => (*file, albums),
+                Item::Album { .. } => unreachable!(),
+            };
+
+            let file_name = [owner_id, ".", &file.metadata.name].concat();
+            if file_names.get(file_name.as_bytes())?.is_some() {
+                return Err(ApiError::FileExists.into());
+            }
+
+            file_names.insert(file_name.as_bytes(), file_id.as_bytes())?;
+            files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+            stats::count(stats, &file, true)?;
+
+            if let Some(location) = file.location {
+                geo.insert(geo::key(owner_id, location, file_id).as_bytes(), b"")?;
+            }
+            if let Some(phash) = file.phash {
+                phashes.insert(similar::key(owner_id, phash, file_id).as_bytes(), b"")?;
+            }
+
+            let library_head = library::modify(libraries, library_fragments, owner_id, |e| {
+                if !file.in_library() {
+                    Ok(())
+                } else {
+                    e.add(file_id, &file)
+                }
+            })?;
+
+            let mut updated = vec![];
+            for (album_id, caption) in captions {
+                let can_add = match user_to_album.get([owner_id, ".", &album_id].concat())? {
+                    Some(role_bytes) => bincode::deserialize::<Role>(&role_bytes).unwrap().can_contribute(),
+                    None => false,
+                };
+                if !can_add {
+                    continue;
+                }
+
+                if let Some(album_bytes) = albums.get(album_id.as_bytes())? {
+                    let mut album: Album = bincode::deserialize(&album_bytes).unwrap();
+
+                    let mut e = Engine::new(&album_id, &mut album, fragments)?;
+                    e.add(file_id, &file)?;
+                    if caption.is_some() {
+                        e.set_caption(file_id, &file, caption)?;
+                    }
+                    e.commit()?;
+
+                    albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+                    inclusions.insert([file_id, ".", &album_id].concat().as_bytes(), b"")?;
+
+                    updated.push((album_id, album.fragment_head));
+                }
+            }
+
+            Ok((library_head, updated))
+        },
+    )?;
+
+    library::updated(state, owner_id, library_head);
+    for (album_id, fragment_head) in updated {
+        events::album_updated(state, &album_id, fragment_head);
+    }
+
+    Ok(())
+}
+
+/// Give a trashed album back to its members, leaving out anyone whose account is gone and any
+/// files that were deleted in the meantime.
+fn restore_album(state: &AppState, user_id: &str, album_id: &str) -> ApiResult<()> {
+    let AppState {
+        ref users,
+        ref files,
+        ref albums,
+        ref fragments,
+        ref user_to_album,
+        ref album_to_user,
+        ref trash,
+        ..
+    } = state;
+
+    let trees = (users, files, albums, fragments, user_to_album, album_to_user, trash);
+    let (fragment_head, member_ids) = trees.transaction(
+        |(users, files, albums, fragments, user_to_album, album_to_user, trash)| {
+            let trashed_bytes = trash
+                .remove(key(user_id, TrashKind::Album, album_id).as_bytes())?
+                .ok_or(ApiError::NotFound)?;
+            let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+            let (mut album, members) = match trashed.item {
+                Item::Album { album, members } => (album, members),
+                Item::File { .. } => unreachable!(),
+            };
+
+            let mut member_ids = vec![];
+            for (member_id, role) in members {
+                if users.get(member_id.as_bytes())?.is_none() {
+                    continue;
+                }
+
+                user_to_album.insert(
+                    [&member_id, ".", album_id].concat().as_bytes(),
+                    bincode::serialize(&role).unwrap(),
+                )?;
+                album_to_user.insert([album_id, ".", &member_id].concat().as_bytes(), b"")?;
+                member_ids.push(member_id);
+            }
+
+            let mut e = Engine::new(album_id, &mut album, fragments)?;
+            e.rebuild(files)?;
+            e.commit()?;
+
+            albums.insert(album_id.as_bytes(), bincode::serialize(&album).unwrap())?;
+
+            Ok((album.fragment_head, member_ids))
+        },
+    )?;
+
+    events::album_updated(state, album_id, fragment_head);
+    for member_id in member_ids {
+        events::shares_changed(state, album_id, &member_id);
+    }
+
+    Ok(())
+}
+
+/// Remove a trash entry for good, along with everything that still belongs to it.
+pub fn purge(state: &AppState, trash_key: &[u8]) -> ApiResult<()> {
+    // Taking the entry out first keeps it from being restored while it is half deleted. If the
+    // server stops before the job is recorded, `clean::clean_files` finds the leftover files.
+    let trashed_bytes = match state.trash.remove(trash_key)? {
+        Some(trashed_bytes) => trashed_bytes,
+        None => return Ok(()),
+    };
+    let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+
+    let trash_key = std::str::from_utf8(trash_key).unwrap();
+    let (_, id) = trash_key.rsplit_once('.').unwrap();
+
+    match trashed.item {
+        Item::File { file, .. } => delete::Command::File(id, file).enqueue(state),
+        Item::Album { .. } => delete::Command::Album(id).enqueue(state),
+    }
+}
+
+/// Purge every entry that is older than the retention period, returning how many there were.
+pub fn sweep(state: &AppState) -> ApiResult<usize> {
+    let cutoff = Utc::now().timestamp() - state.config.trash_retention_days * 24 * 60 * 60;
+
+    let mut purged = 0;
+    for entry in state.trash.iter() {
+        let (trash_key, trashed_bytes) = entry?;
+        let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+
+        if trashed.deleted <= cutoff {
+            purge(state, &trash_key)?;
+            purged += 1;
+        }
+    }
+
+    Ok(purged)
+}
+
+/// Sweep the trash periodically for as long as the server runs.
+pub async fn sweeper(state: AppState) {
+    let mut interval = time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        match block_in_place(|| sweep(&state)) {
+            Ok(0) => {}
+            Ok(purged) => info!("Purged {} items from the trash", purged),
+            Err(err) => warn!(error = %err.chain(), "Couldn't sweep the trash"),
+        }
+    }
+}
+
+/// Ids of the files in the trash, whose stored files must be kept.
+pub fn file_ids(state: &AppState) -> ApiResult<HashSet<String>> {
+    ids(state, TrashKind::File)
+}
+
+/// Ids of the files in the trash that their clients encrypted, which have no renditions.
+pub fn encrypted_file_ids(state: &AppState) -> ApiResult<HashSet<String>> {
+    let mut ids = HashSet::new();
+
+    for entry in state.trash.iter() {
+        let (trash_key, trashed_bytes) = entry?;
+        let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+
+        if let Item::File { file, .. } = trashed.item {
+            if file.encrypted {
+                let (_, file_id) = std::str::from_utf8(&trash_key).unwrap().rsplit_once('.').unwrap();
+                ids.insert(file_id.to_string());
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Ids of the albums in the trash, which keep their fragments and inclusions.
+pub fn album_ids(state: &AppState) -> ApiResult<HashSet<String>> {
+    ids(state, TrashKind::Album)
+}
+
+fn ids(state: &AppState, kind: TrashKind) -> ApiResult<HashSet<String>> {
+    let mut ids = HashSet::new();
+    let prefix = [kind_name(kind), "."].concat();
+
+    for entry in state.trash.iter() {
+        let (trash_key, _) = entry?;
+        let trash_key = std::str::from_utf8(&trash_key).unwrap();
+
+        let (_, rest) = trash_key.split_once('.').unwrap();
+        if let Some(id) = rest.strip_prefix(prefix.as_str()) {
+            ids.insert(id.to_string());
+        }
+    }
+
+    Ok(ids)
+}
+
+async fn list(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref trash,
+            ref config,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let retention = config.trash_retention_days * 24 * 60 * 60;
+
+        let mut entries = vec![];
+        for entry in trash.scan_prefix([user_id, "."].concat()) {
+            let (trash_key, trashed_bytes) = entry?;
+            let trashed: Trashed = bincode::deserialize(&trashed_bytes).unwrap();
+
+            let trash_key = std::str::from_utf8(&trash_key).unwrap();
+            let (_, id) = trash_key.rsplit_once('.').unwrap();
+
+            let (kind, name) = match trashed.item {
+                Item::File { ref file, .. } => (TrashKind::File, file.metadata.name.to_string()),
+                Item::Album { ref album, .. } => (TrashKind::Album, album.description.name.to_string()),
+            };
+
+            entries.push(TrashEntry {
+                kind,
+                id: Cow::from(id.to_string()),
+                name: Cow::from(name),
+                deleted: trashed.deleted,
+                purge_after: trashed.deleted + retention,
+            });
+        }
+
+        respond_ok(entries)
+    })
+}
+
+async fn restore(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: TrashRestore = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState { ref sessions, .. } = state;
+
+        test_logged_in(sessions, key)?;
+
+        match json.kind {
+            TrashKind::File => restore_file(state, user_id, &json.id)?,
+            TrashKind::Album => restore_album(state, user_id, &json.id)?,
+        }
+
+        respond_ok_empty()
+    })
+}
+
+/// Purge every entry in the user's trash without waiting for the retention period.
+async fn empty(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref sessions,
+            ref trash,
+            ..
+        } = state;
+
+        test_logged_in(sessions, key)?;
+
+        for entry in trash.scan_prefix([user_id, "."].concat()) {
+            let (trash_key, _) = entry?;
+            purge(state, &trash_key)?;
+        }
+
+        respond_ok_empty()
+    })
+}
+
+pub const SCOPE: &str = "/trash";
+
+pub fn router() -> Router<Body, ApiError> {
+    Router::builder()
+        .endpoint(SCOPE, endpoint::ListTrash, list)
+        .endpoint(SCOPE, endpoint::EmptyTrash, empty)
+        .endpoint(SCOPE, endpoint::RestoreTrash, restore)
+        .build()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_trash() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let file_id = server.upload(&key, "photo.png", png(8, 8)).await;
+        let status = server.send(Method::DELETE, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let trash = server.json(Method::GET, &format!("/trash?key={}", key), &()).await;
+        assert_eq!(trash.as_array().unwrap().len(), 1);
+        assert_eq!(trash[0]["id"], file_id.as_str());
+
+        let status = server.send(Method::DELETE, &format!("/trash?key={}", key), &()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let trash = server.json(Method::GET, &format!("/trash?key={}", key), &()).await;
+        assert!(trash.as_array().unwrap().is_empty());
+        assert!(server.state.trash.is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 use crate::{
     delete,
     common::{
-        join, new_id, require_key, respond_ok, respond_ok_empty, test_logged_in, AppState, User,
+        join, limit_auth, new_id, require_key, respond_ok, respond_ok_empty, session_cookie,
+        test_logged_in, AppState, Elevation, EmailToken, LoginFailures, Session, User, SESSION_COOKIE,
     },
+    config::{Config, Registration},
+    endpoint::EndpointExt,
     error::{ApiError, ApiResult},
+    forwarded, invite, oidc, stats,
 };
-use hyper::{Body, Request, Response};
+use chrono::offset::{TimeZone, Utc};
+use hyper::http::request::Parts;
+use hyper::{header, Body, Request, Response, StatusCode};
 use rand::{thread_rng, Rng};
 use routerify::ext::RequestExt;
 use routerify::Router;
@@ -13,58 +19,276 @@ use routerify_query::RequestQueryExt;
 use sled::Transactional;
 use std::borrow::Cow;
 use tokio::task::block_in_place;
-use wire::{Key, SessionList, UserDetails, ChangePassword};
+use wire::{
+    endpoint,
+    ChangePassword, CsrfToken, Elevate, ElevationToken, IntoOwned, Key, LoginAttempt, ResetConfirm,
+    ResetRequest, SessionInfo, SessionList, UserDetails,
+};
 
 const USER_ID_BYTES: usize = 8;
 const SESSION_KEY_BYTES: usize = 32;
-
-fn hash_password(password: &[u8], config: &argon2::Config) -> ApiResult<String> {
+const EMAIL_TOKEN_BYTES: usize = 32;
+const CSRF_TOKEN_BYTES: usize = 32;
+const ELEVATION_TOKEN_BYTES: usize = 32;
+/// Browsers cap cookie lifetimes at 400 days.
+const COOKIE_MAX_AGE: i64 = 400 * 24 * 60 * 60;
+/// Number of login attempts remembered per user.
+const AUDIT_LENGTH: usize = 100;
+/// Longest name, in characters, that a session may be given.
+const MAX_LABEL_LENGTH: usize = 100;
+
+pub fn hash_password(password: &[u8], config: &argon2::Config) -> ApiResult<String> {
     let salt: [u8; 32] = thread_rng().gen();
     let hash = argon2::hash_encoded(password, &salt, config)?;
 
     Ok(hash)
 }
 
+fn issue_token(tokens: &sled::Tree, user_id: &str, lifetime: i64) -> ApiResult<String> {
+    let token = new_id(EMAIL_TOKEN_BYTES);
+    let record = EmailToken {
+        user_id,
+        expires: Utc::now().timestamp() + lifetime,
+    };
+
+    tokens.insert(token.as_bytes(), bincode::serialize(&record).unwrap())?;
+
+    Ok(token)
+}
+
+/// Consume a single use token, returning the user that it was issued to.
+fn redeem_token(tokens: &sled::Tree, token: &str) -> ApiResult<String> {
+    let record_bytes = tokens.remove(token.as_bytes())?.ok_or(ApiError::Unauthorized)?;
+    let record: EmailToken = bincode::deserialize(&record_bytes).unwrap();
+
+    if record.expires < Utc::now().timestamp() {
+        return Err(ApiError::Unauthorized);
+    }
+
+    Ok(record.user_id.to_owned())
+}
+
+/// Remove tokens that expired without being used, and elevations that expired.
+pub fn clean_tokens(state: &AppState) -> ApiResult<usize> {
+    let now = Utc::now().timestamp();
+    let mut removed = 0;
+
+    for tokens in &[&state.verify_tokens, &state.reset_tokens] {
+        for entry in tokens.iter() {
+            let (token, record_bytes) = entry?;
+            let record: EmailToken = bincode::deserialize(&record_bytes).unwrap();
+
+            if record.expires < now {
+                tokens.remove(token)?;
+                removed += 1;
+            }
+        }
+    }
+
+    for entry in state.elevations.iter() {
+        let (token, elevation_bytes) = entry?;
+        let elevation: Elevation = bincode::deserialize(&elevation_bytes).unwrap();
+
+        if elevation.expires < now {
+            state.elevations.remove(token)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
 fn verify_password(hash: &str, password: &str) -> ApiResult<()> {
     if !argon2::verify_encoded(hash, password.as_bytes())? {
-        return Err(ApiError::Unauthorized.into());
+        return Err(ApiError::Unauthorized);
     }
 
     Ok(())
 }
 
+/// Add a user with the password `hash`, returning their new id.
+pub fn register(users: &sled::Tree, emails: &sled::Tree, email: &str, hash: &str) -> ApiResult<String> {
+    let user_id = new_id(USER_ID_BYTES);
+    let user = User { email, password: hash };
+
+    (users, emails).transaction(|(users, emails)| {
+        if emails.insert(email, user_id.as_bytes())?.is_some() {
+            return Err(ApiError::EmailTaken.into());
+        }
+
+        users.insert(user_id.as_bytes(), bincode::serialize(&user).unwrap())?;
+
+        Ok(())
+    })?;
+
+    Ok(user_id)
+}
+
+/// Register, which takes an `invite` query with a code from an administrator while registration
+/// is by invitation.
 async fn create(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let code = req.query("invite").cloned();
     let (parts, body) = req.into_parts();
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: UserDetails = serde_json::from_slice(&entire_body)?;
 
+    limit_auth(&parts, &json.email)?;
+
     block_in_place(move || {
+        let state = parts.data().unwrap();
         let AppState {
             ref users,
             ref emails,
+            ref invites,
+            ref verify_tokens,
+            ref mailer,
+            ref config,
             ref argon_config,
             ..
-        } = parts.data().unwrap();
-
-        let user_id = new_id(USER_ID_BYTES);
-        let hash = hash_password(json.password.as_bytes(), argon_config)?;
+        } = state;
 
-        let user = User {
-            email: &json.email,
-            password: &hash,
+        let invite = match (config.registration, code) {
+            (Registration::Closed, _) => return Err(ApiError::Unauthorized),
+            (_, Some(code)) => Some(invite::redeem(invites, &code)?),
+            (Registration::Invite, None) => return Err(ApiError::Unauthorized),
+            (Registration::Open, None) => None,
         };
 
-        (users, emails).transaction(|(users, emails)| {
-            if emails.insert(&*json.email, user_id.as_bytes())?.is_some() {
-                return Err(ApiError::EmailTaken.into());
+        let hash = hash_password(json.password.as_bytes(), argon_config)?;
+        let user_id = match register(users, emails, &json.email, &hash) {
+            Ok(user_id) => user_id,
+            Err(err) => {
+                if let Some(invite) = &invite {
+                    invite::restore(invites, invite)?;
+                }
+                return Err(err);
             }
+        };
+        if let Some(invite) = &invite {
+            invite::apply(state, &user_id, invite)?;
+        }
+
+        let token = issue_token(verify_tokens, &user_id, config.verify_token_seconds)?;
+        mailer.send(
+            &json.email,
+            "Verify your email address",
+            format!(
+                "Open this link to verify your email address:\n\n{}/verify?token={}\n",
+                config.public_url, token
+            ),
+        );
+
+        respond_ok_empty()
+    })
+}
+
+async fn verify(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let entire_body = join(&parts, body).await?;
+    let json: Key = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref users,
+            ref verified,
+            ref verify_tokens,
+            ..
+        } = parts.data().unwrap();
+
+        let user_id = redeem_token(verify_tokens, &json.key)?;
+        let now = Utc::now().timestamp();
+
+        (users, verified).transaction(|(users, verified)| {
+            users.get(&user_id)?.ok_or(ApiError::Unauthorized)?;
+            verified.insert(user_id.as_bytes(), &now.to_be_bytes())?;
+
+            Ok(())
+        })?;
+
+        respond_ok_empty()
+    })
+}
+
+async fn reset_request(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
 
+    let entire_body = join(&parts, body).await?;
+    let json: ResetRequest = serde_json::from_slice(&entire_body)?;
+
+    limit_auth(&parts, &json.email)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref emails,
+            ref reset_tokens,
+            ref mailer,
+            ref config,
+            ..
+        } = parts.data().unwrap();
+
+        if let Some(user_id) = emails.get(&*json.email)? {
+            let user_id = std::str::from_utf8(&user_id).unwrap();
+            let token = issue_token(reset_tokens, user_id, config.reset_token_seconds)?;
+
+            mailer.send(
+                &json.email,
+                "Reset your password",
+                format!(
+                    "Open this link to choose a new password:\n\n{}/reset?token={}\n\n\
+                     If you didn't ask to reset your password you can ignore this email.\n",
+                    config.public_url, token
+                ),
+            );
+        }
+
+        // Respond the same way whether or not the account exists so that this can't be used to
+        // find out which emails are registered.
+        respond_ok_empty()
+    })
+}
+
+async fn reset_confirm(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let entire_body = join(&parts, body).await?;
+    let json: ResetConfirm = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref users,
+            ref sessions,
+            ref login_failures,
+            ref verified,
+            ref reset_tokens,
+            ref argon_config,
+            ..
+        } = parts.data().unwrap();
+
+        let user_id = redeem_token(reset_tokens, &json.token)?;
+        let hash = hash_password(json.new_password.as_bytes(), argon_config)?;
+        let now = Utc::now().timestamp();
+
+        (users, verified).transaction(|(users, verified)| {
+            let user_bytes = users.get(&user_id)?.ok_or(ApiError::Unauthorized)?;
+            let mut user: User = bincode::deserialize(&user_bytes).unwrap();
+
+            user.password = &hash;
             users.insert(user_id.as_bytes(), bincode::serialize(&user).unwrap())?;
 
+            // Receiving the reset email proves that the user owns the address.
+            verified.insert(user_id.as_bytes(), &now.to_be_bytes())?;
+
             Ok(())
         })?;
 
+        for entry in sessions.scan_prefix([&user_id, "."].concat()) {
+            let (key, _) = entry?;
+            sessions.remove(key)?;
+        }
+        login_failures.remove(&user_id)?;
+
         respond_ok_empty()
     })
 }
@@ -82,7 +306,7 @@ async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
             ..
         } = state;
 
-        sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
+        test_logged_in(sessions, key)?;
 
         delete::Command::User(user_id).run(state)?;
 
@@ -90,43 +314,259 @@ async fn delete(req: Request<Body>) -> ApiResult<Response<Body>> {
     })
 }
 
+/// Read the `cookie` and `label` queries that logins take.
+pub fn login_options(req: &Request<Body>) -> ApiResult<(bool, Option<String>)> {
+    let cookie = req
+        .query("cookie")
+        .map(|s| s.parse::<bool>().ok())
+        .unwrap_or(Some(false))
+        .ok_or(ApiError::BadRequest)?;
+    let label = req.query("label").cloned();
+
+    if label.as_ref().map_or(0, |label| label.chars().count()) > MAX_LABEL_LENGTH {
+        return Err(ApiError::BadRequest);
+    }
+
+    Ok((cookie, label))
+}
+
+/// A session for the login that `parts` makes, with a CSRF token if it is held in a cookie.
+pub fn new_session(parts: &Parts, cookie: bool, label: Option<String>, now: i64) -> Session {
+    Session {
+        csrf_token: if cookie { Some(new_id(CSRF_TOKEN_BYTES)) } else { None },
+        label,
+        user_agent: parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from),
+        created: now,
+        last_used: now,
+    }
+}
+
+/// The secret part of a new session key, which follows the user id and a dot.
+pub fn new_session_key() -> String {
+    new_id(SESSION_KEY_BYTES)
+}
+
+/// The `Set-Cookie` value that hands a session key to the browser in cookie mode.
+pub fn cookie_header(config: &Config, extended_key: &str) -> String {
+    let secure = if config.public_url.starts_with("https://") { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        SESSION_COOKIE, extended_key, COOKIE_MAX_AGE, secure
+    )
+}
+
+/// Log in with an email and password. With `?cookie=true`, and `cookie_sessions` configured, the
+/// key is set as an HttpOnly cookie instead of being returned, and the response holds the CSRF
+/// token of the session. `?label=` names the session so that the user can tell it apart later.
 async fn login(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (cookie, label) = login_options(&req)?;
+
     let (parts, body) = req.into_parts();
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: UserDetails = serde_json::from_slice(&entire_body)?;
 
+    limit_auth(&parts, &json.email)?;
+
     block_in_place(move || {
+        let state = parts.data().unwrap();
         let AppState {
             ref users,
             ref emails,
             ref sessions,
+            ref login_failures,
+            ref config,
             ..
-        } = parts.data().unwrap();
+        } = state;
 
-        let key = new_id(SESSION_KEY_BYTES);
+        if cookie && !config.cookie_sessions {
+            return Err(ApiError::BadRequest);
+        }
+
+        let user_id = emails.get(&*json.email)?.ok_or(ApiError::Unauthorized)?;
 
-        let extended_key = (users, emails, sessions).transaction(|(users, emails, sessions)| {
-            let user_id = emails.get(&*json.email)?.ok_or(ApiError::Unauthorized)?;
+        let now = Utc::now().timestamp();
+        let failures: LoginFailures = login_failures
+            .get(&user_id)?
+            .map(|bytes| bincode::deserialize(&bytes).unwrap())
+            .unwrap_or_default();
 
-            let user_bytes = users.get(&user_id)?.unwrap();
-            let user: User = bincode::deserialize(&user_bytes).unwrap();
+        // Locked accounts answer like a wrong password, so that locking one can't be used to find
+        // out that it exists. The owner learns when it opens again from the lockout notice.
+        if failures.locked_until > now {
+            append_attempt(state, &parts, &user_id, false, true)?;
+            return Err(ApiError::Unauthorized);
+        }
 
-            verify_password(&user.password, &json.password)?;
+        let key = new_session_key();
+        let session = new_session(&parts, cookie, label, now);
+        let session_bytes = bincode::serialize(&session).unwrap();
 
-            let extended_key = [user_id.as_ref(), b".", key.as_bytes()].concat();
+        let result = (users, sessions)
+            .transaction(|(users, sessions)| {
+                let user_bytes = users.get(&user_id)?.ok_or(ApiError::Unauthorized)?;
+                let user: User = bincode::deserialize(&user_bytes).unwrap();
 
-            sessions.insert(extended_key.clone(), b"")?;
+                verify_password(user.password, &json.password)?;
 
-            Ok(extended_key)
-        })?;
+                let extended_key = [user_id.as_ref(), b".", key.as_bytes()].concat();
+
+                sessions.insert(extended_key.clone(), session_bytes.as_slice())?;
+
+                Ok(extended_key)
+            })
+            .map_err(ApiError::from);
+
+        match result {
+            Ok(_) => {
+                login_failures.remove(&user_id)?;
+                audit_attempt(state, &parts, &user_id, true)?;
+            }
+            Err(ApiError::Unauthorized) => {
+                record_failure(state, &user_id, now)?;
+                audit_attempt(state, &parts, &user_id, false)?;
+            }
+            Err(_) => (),
+        }
+
+        let extended_key = result?;
+        let extended_key = std::str::from_utf8(&extended_key).unwrap();
+
+        if let Some(token) = session.csrf_token {
+            let cookie = cookie_header(config, extended_key);
+
+            let mut response = respond_ok(CsrfToken {
+                token: Cow::from(token),
+            })?;
+            response.headers_mut().insert(header::SET_COOKIE, cookie.parse().unwrap());
+            return Ok(response);
+        }
 
         respond_ok(Key {
-            key: Cow::from(std::str::from_utf8(&extended_key).unwrap()),
+            key: Cow::from(extended_key),
         })
     })
 }
 
+/// Count a failed login, locking the account once too many have happened in a row and telling the
+/// owner when they can log in again.
+fn record_failure(state: &AppState, user_id: &[u8], now: i64) -> ApiResult<()> {
+    let AppState {
+        ref users,
+        ref login_failures,
+        ref mailer,
+        ref config,
+        ..
+    } = state;
+
+    let previous = login_failures.fetch_and_update(user_id, |bytes| {
+        let mut failures: LoginFailures = bytes
+            .map(|bytes| bincode::deserialize(bytes).unwrap())
+            .unwrap_or_default();
+
+        failures.count += 1;
+        if failures.count >= config.lockout_threshold {
+            failures.count = 0;
+            failures.locked_until = now + config.lockout_seconds;
+        }
+
+        Some(bincode::serialize(&failures).unwrap())
+    })?;
+
+    let previous: LoginFailures = previous
+        .map(|bytes| bincode::deserialize(&bytes).unwrap())
+        .unwrap_or_default();
+    if previous.count + 1 < config.lockout_threshold {
+        return Ok(());
+    }
+
+    if let Some(user_bytes) = users.get(user_id)? {
+        let user: User = bincode::deserialize(&user_bytes).unwrap();
+        let unlocked = Utc.timestamp_opt(now + config.lockout_seconds, 0).unwrap();
+
+        mailer.send(
+            user.email,
+            "Your account was locked",
+            format!(
+                "Your account was locked after {} failed logins in a row. You can log in again \
+                 after {}.\n\nIf these weren't you, consider choosing a new password.\n",
+                config.lockout_threshold,
+                unlocked.format("%Y-%m-%d %H:%M UTC"),
+            ),
+        );
+    }
+
+    Ok(())
+}
+
+/// Append a login attempt to the user's audit log, dropping the oldest entries.
+pub fn audit_attempt(state: &AppState, parts: &Parts, user_id: &[u8], success: bool) -> ApiResult<()> {
+    append_attempt(state, parts, user_id, success, false)
+}
+
+fn append_attempt(state: &AppState, parts: &Parts, user_id: &[u8], success: bool, locked: bool) -> ApiResult<()> {
+    let AppState {
+        ref db,
+        ref login_audit,
+        ..
+    } = state;
+
+    let attempt = LoginAttempt {
+        time_stamp: Utc::now().timestamp(),
+        ip: Cow::from(forwarded::client_ip(parts).to_string()),
+        user_agent: parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(Cow::from),
+        success,
+        locked,
+    };
+
+    // Ids from the database are monotonic, so entries are stored in chronological order.
+    let prefix = [user_id, b"."].concat();
+    let audit_key = [&prefix[..], &db.generate_id()?.to_be_bytes()].concat();
+    login_audit.insert(audit_key, bincode::serialize(&attempt).unwrap())?;
+
+    let excess = login_audit.scan_prefix(&prefix).count().saturating_sub(AUDIT_LENGTH);
+    for entry in login_audit.scan_prefix(&prefix).take(excess) {
+        let (key, _) = entry?;
+        login_audit.remove(key)?;
+    }
+
+    Ok(())
+}
+
+async fn audit(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref login_audit,
+            ..
+        } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let mut attempts = vec![];
+        for entry in login_audit.scan_prefix([user_id, "."].concat()).rev() {
+            let (_, attempt_bytes) = entry?;
+            let attempt: LoginAttempt = bincode::deserialize(&attempt_bytes).unwrap();
+            attempts.push(attempt.into_owned());
+        }
+
+        respond_ok(attempts)
+    })
+}
+
 async fn sessions(req: Request<Body>) -> ApiResult<Response<Body>> {
     let (parts, _) = req.into_parts();
 
@@ -136,19 +576,26 @@ async fn sessions(req: Request<Body>) -> ApiResult<Response<Body>> {
     block_in_place(|| {
         let AppState { ref sessions, .. } = parts.data().unwrap();
 
-        let mut prefixes = vec![];
+        let mut list = vec![];
 
         test_logged_in(sessions, key)?;
 
-        for maybe_pair in sessions.scan_prefix(&user_id) {
-            let (key, _) = maybe_pair?;
-            let string = String::from(&std::str::from_utf8(key.as_ref()).unwrap()[..20]);
-            prefixes.push(Cow::from(string));
+        for maybe_pair in sessions.scan_prefix([user_id, "."].concat()) {
+            let (session_key, session_bytes) = maybe_pair?;
+            let session_key = std::str::from_utf8(session_key.as_ref()).unwrap();
+            let session: Session = bincode::deserialize(&session_bytes).unwrap();
+
+            list.push(SessionInfo {
+                key_prefix: Cow::from(session_key[..20].to_string()),
+                label: session.label.map(Cow::from),
+                user_agent: session.user_agent.map(Cow::from),
+                created: session.created,
+                last_used: session.last_used,
+                current: session_key == key,
+            });
         }
 
-        respond_ok(SessionList {
-            key_prefixes: prefixes,
-        })
+        respond_ok(SessionList { sessions: list })
     })
 }
 
@@ -157,7 +604,7 @@ async fn logout(req: Request<Body>) -> ApiResult<Response<Body>> {
 
     let key = require_key(&parts)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: Key = serde_json::from_slice(&entire_body)?;
 
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
@@ -174,10 +621,80 @@ async fn logout(req: Request<Body>) -> ApiResult<Response<Body>> {
             sessions.remove(key)?;
         }
 
+        // Let the browser forget a cookie session that was just ended.
+        if session_cookie(&parts).is_some_and(|cookie| cookie.starts_with(&to_remove)) {
+            let cookie = format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict", SESSION_COOKIE);
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::SET_COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        respond_ok_empty()
+    })
+}
+
+/// End every session of the user except the one that made the request.
+async fn logout_others(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    block_in_place(|| {
+        let AppState { ref sessions, .. } = parts.data().unwrap();
+
+        test_logged_in(sessions, key)?;
+
+        let mut others = vec![];
+        for maybe_pair in sessions.scan_prefix([user_id, "."].concat()) {
+            let (session_key, _) = maybe_pair?;
+            if session_key != key.as_bytes() {
+                others.push(session_key);
+            }
+        }
+
+        sessions.transaction(|sessions| {
+            for session_key in &others {
+                sessions.remove(session_key)?;
+            }
+            Ok(())
+        })?;
+
         respond_ok_empty()
     })
 }
 
+/// The CSRF token of the cookie session that the request comes from.
+async fn csrf(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    let key = session_cookie(&parts).ok_or(ApiError::Unauthorized)?;
+
+    block_in_place(|| {
+        let AppState {
+            ref sessions,
+            ref config,
+            ..
+        } = parts.data().unwrap();
+
+        if !config.cookie_sessions {
+            return Err(ApiError::NotFound);
+        }
+
+        test_logged_in(sessions, key)?;
+
+        let session_bytes = sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
+        let session: Session = bincode::deserialize(&session_bytes).unwrap();
+        let token = session.csrf_token.ok_or(ApiError::Unauthorized)?;
+
+        respond_ok(CsrfToken {
+            token: Cow::from(token),
+        })
+    })
+}
+
 async fn list_emails(req: Request<Body>) -> ApiResult<Response<Body>> {
     let prefix = req.query("prefix")
         .map(|s| s.as_str())
@@ -210,7 +727,6 @@ async fn list_emails(req: Request<Body>) -> ApiResult<Response<Body>> {
             let (key, _) = entry?;
             let email = std::str::from_utf8(&key).unwrap();
             email_list.push(email.to_owned());
-            println!("email={}", email);
         }
 
         respond_ok(email_list)
@@ -223,7 +739,7 @@ async fn change_password(req: Request<Body>) -> ApiResult<Response<Body>> {
     let key = require_key(&parts)?;
     let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
 
-    let entire_body = join(body).await?;
+    let entire_body = join(&parts, body).await?;
     let json: ChangePassword = serde_json::from_slice(&entire_body)?;
 
     block_in_place(|| {
@@ -234,13 +750,13 @@ async fn change_password(req: Request<Body>) -> ApiResult<Response<Body>> {
             ..
         } = parts.data().unwrap();
 
-        sessions.get(key)?.ok_or(ApiError::Unauthorized)?;
+        test_logged_in(sessions, key)?;
 
         users.transaction(|users| {
             let user_bytes = users.get(user_id)?.unwrap();
             let mut user: User = bincode::deserialize(&user_bytes).unwrap();
             
-            verify_password(&user.password, &json.old_password)?;
+            verify_password(user.password, &json.old_password)?;
 
             let hash = hash_password(json.new_password.as_bytes(), argon_config)?;
             user.password = &hash;
@@ -260,15 +776,234 @@ async fn change_password(req: Request<Body>) -> ApiResult<Response<Body>> {
     })
 }
 
+/// Check the password of a logged in user again, for a token that opens their locked folder from
+/// the same session until `elevation_seconds` have passed. Wrong passwords count towards locking
+/// the account, like failed logins do.
+async fn elevate(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (user_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let json: Elevate = serde_json::from_slice(&entire_body)?;
+
+    block_in_place(|| {
+        let state = parts.data().unwrap();
+        let AppState {
+            ref users,
+            ref sessions,
+            ref login_failures,
+            ref elevations,
+            ref config,
+            ..
+        } = state;
+
+        test_logged_in(sessions, key)?;
+
+        let user_bytes = users.get(user_id)?.ok_or(ApiError::Unauthorized)?;
+        let user: User = bincode::deserialize(&user_bytes).unwrap();
+
+        limit_auth(&parts, user.email)?;
+
+        let now = Utc::now().timestamp();
+        let failures: LoginFailures = login_failures
+            .get(user_id)?
+            .map(|bytes| bincode::deserialize(&bytes).unwrap())
+            .unwrap_or_default();
+
+        if failures.locked_until > now {
+            return Err(ApiError::AccountLocked((failures.locked_until - now) as u64));
+        }
+
+        if let Err(err) = verify_password(user.password, &json.password) {
+            record_failure(state, user_id.as_bytes(), now)?;
+            return Err(err);
+        }
+        login_failures.remove(user_id)?;
+
+        let token = new_id(ELEVATION_TOKEN_BYTES);
+        let elevation = Elevation {
+            session_key: key,
+            expires: now + config.elevation_seconds,
+        };
+        elevations.insert(token.as_bytes(), bincode::serialize(&elevation).unwrap())?;
+
+        respond_ok(ElevationToken {
+            token: Cow::from(token),
+            expires: elevation.expires,
+        })
+    })
+}
+
+pub const SCOPE: &str = "/user";
+
 pub fn router() -> Router<Body, ApiError> {
     Router::builder()
-        .post("/", create)
-        .delete("/", delete)
-        .get("/emails", list_emails)
-        .post("/auth", login)
-        .put("/auth", change_password)
-        .get("/auth", sessions)
-        .delete("/auth", logout)
+        .endpoint(SCOPE, endpoint::CreateUser, create)
+        .endpoint(SCOPE, endpoint::DeleteUser, delete)
+        .endpoint(SCOPE, endpoint::ListEmails, list_emails)
+        .endpoint(SCOPE, endpoint::Login, login)
+        .endpoint(SCOPE, endpoint::UpdatePassword, change_password)
+        .endpoint(SCOPE, endpoint::ListSessions, sessions)
+        .endpoint(SCOPE, endpoint::Logout, logout)
+        .endpoint(SCOPE, endpoint::LogoutOthers, logout_others)
+        .endpoint(SCOPE, endpoint::Csrf, csrf)
+        .endpoint(SCOPE, endpoint::Audit, audit)
+        .endpoint(SCOPE, endpoint::Elevate, elevate)
+        .endpoint(SCOPE, endpoint::GetStats, stats::serve)
+        .endpoint(SCOPE, endpoint::OidcLogin, oidc::login)
+        .endpoint(SCOPE, endpoint::OidcCallback, oidc::callback)
+        .endpoint(SCOPE, endpoint::Verify, verify)
+        .endpoint(SCOPE, endpoint::RequestReset, reset_request)
+        .endpoint(SCOPE, endpoint::ConfirmReset, reset_confirm)
         .build()
         .unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{TestServer, PASSWORD};
+    use hyper::{header, Body, Method, Request, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lockout() {
+        let server = TestServer::start_with(|config| config.lockout_threshold = 2).await;
+        let key = server.signup("owner@example.com").await;
+
+        let wrong = json!({ "email": "owner@example.com", "password": "wrong password" });
+        for _ in 0..2 {
+            let (status, _) = server
+                .request(Method::POST, "/user/auth", &[], Body::from(wrong.to_string()))
+                .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        // A locked account looks just like a wrong password or an unknown email.
+        for email in ["owner@example.com", "stranger@example.com"] {
+            let details = json!({ "email": email, "password": PASSWORD });
+            let (status, _) = server
+                .request(Method::POST, "/user/auth", &[], Body::from(details.to_string()))
+                .await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        let (_, body) = server
+            .request(Method::GET, &format!("/user/auth/audit?key={}", key), &[], Body::empty())
+            .await;
+        let attempts: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(attempts[0]["locked"], true);
+        assert_eq!(attempts[1]["locked"], false);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn oversized_body() {
+        let server = TestServer::start_with(|config| config.max_body_bytes = 1024).await;
+
+        let padding = "x".repeat(2048);
+        let details = json!({ "email": "owner@example.com", "password": PASSWORD, "padding": padding });
+        let (status, _) = server
+            .request(Method::POST, "/user/auth", &[], Body::from(details.to_string()))
+            .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn session_labels() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+
+        let details = json!({ "email": "owner@example.com", "password": PASSWORD });
+        let headers = [("user-agent", "photos-test".to_string())];
+        let (status, _) = server
+            .request(Method::POST, "/user/auth?label=Laptop", &headers, Body::from(details.to_string()))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let bearer = [("authorization", format!("Bearer {}", key))];
+        let (status, body) = server.request(Method::GET, "/user/auth", &bearer, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let list: Value = serde_json::from_slice(&body).unwrap();
+        let sessions = list["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+
+        let current: Vec<_> = sessions.iter().filter(|s| s["current"] == true).collect();
+        assert_eq!(current.len(), 1);
+        assert!(key.starts_with(current[0]["key_prefix"].as_str().unwrap()));
+
+        let labeled = sessions.iter().find(|s| s["label"] == "Laptop").unwrap();
+        assert_eq!(labeled["user_agent"], "photos-test");
+        assert_eq!(labeled["current"], false);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn logout_others() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.login("owner@example.com").await;
+
+        let bearer = [("authorization", format!("Bearer {}", key))];
+        let (status, _) = server
+            .request(Method::DELETE, "/user/auth/others", &bearer, Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = server.request(Method::GET, "/user/auth", &bearer, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let bearer = [("authorization", format!("Bearer {}", other))];
+        let (status, _) = server.request(Method::GET, "/user/auth", &bearer, Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cookie_session() {
+        let server = TestServer::start().await;
+        server.create_user("owner@example.com").await;
+
+        let details = json!({ "email": "owner@example.com", "password": PASSWORD });
+        let body = Body::from(details.to_string());
+        let response = server
+            .client
+            .request(
+                Request::post(format!("http://{}/user/auth?cookie=true", server.addr))
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().to_string();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let key = cookie.split_once('=').unwrap().1.to_string();
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let csrf: Value = serde_json::from_slice(&body).unwrap();
+        let token = csrf["token"].as_str().unwrap().to_string();
+
+        let (status, _) = server
+            .request(Method::GET, "/user/auth", &[("cookie", cookie.clone())], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Changes need the CSRF token as well as the cookie.
+        let logout = || Body::from(json!({ "key": key }).to_string());
+        let (status, _) = server
+            .request(Method::DELETE, "/user/auth", &[("cookie", cookie.clone())], logout())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let headers = [("cookie", cookie.clone()), ("x-csrf-token", token)];
+        let (status, _) = server.request(Method::DELETE, "/user/auth", &headers, logout()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = server
+            .request(Method::GET, "/user/auth", &[("cookie", cookie)], Body::empty())
+            .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
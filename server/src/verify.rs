@@ -0,0 +1,272 @@
+//! Verifying Originals
+//!
+//! The SHA-256 of every original is recorded on upload, and nothing ever writes an original
+//! again, so one that no longer hashes to what it did has been damaged in storage. A `Verify`
+//! job reads every original back, and keeps those that are missing or corrupted in the `damaged`
+//! tree along with when they were first found, so that they can be restored from elsewhere while
+//! that is still possible. Originals that verify again are taken off, and so are files that have
+//! since been deleted.
+//!
+//! `POST /admin/verify` queues a run, and one is queued by itself every `verify_interval_days`.
+//! What the last run found is served by `GET /admin/verify`, and how far it got is kept under
+//! `LAST_VERIFIED` so that the schedule survives restarts. Files from before hashes were recorded
+//! are counted but not checked until they have been measured. Files in the trash aren't checked.
+
+use crate::{
+    admin::require_admin,
+    common::{respond_ok, AppState, File},
+    error::{ApiError, ApiResult},
+    jobs::{self, Job},
+    measure, storage,
+};
+use chrono::Utc;
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::Duration;
+use tokio::{task::block_in_place, time};
+use tracing::{info, warn};
+use wire::{Damage, DamagedFile, VerifyReport};
+
+const LAST_VERIFIED: &[u8] = b"last_verified";
+/// How often the scheduler looks at whether a run is due.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How the last run went, stored under `LAST_VERIFIED`.
+#[derive(Serialize, Deserialize)]
+struct LastRun {
+    finished: i64,
+    checked: u64,
+    unhashed: u64,
+}
+
+/// Hash every original again and record the ones that don't match.
+pub fn run(state: &AppState) -> ApiResult<()> {
+    // Collect the ids first so that no database iterator is held across awaits.
+    let file_ids: Vec<sled::IVec> = state.files.iter().keys().collect::<Result<_, _>>()?;
+
+    let handle = tokio::runtime::Handle::current();
+    let now = Utc::now().timestamp();
+    let (mut checked, mut unhashed, mut damaged) = (0, 0, 0);
+
+    for file_id in &file_ids {
+        let expected = match state.files.get(file_id)? {
+            Some(file_bytes) => bincode::deserialize::<File>(&file_bytes).unwrap().hash,
+            None => continue,
+        };
+        let expected = match expected {
+            Some(expected) => expected,
+            None => {
+                unhashed += 1;
+                continue;
+            }
+        };
+
+        let file_id = std::str::from_utf8(file_id).unwrap();
+        let original_key = storage::key(storage::ORIGINAL, file_id);
+        let damage = match handle.block_on(measure::measure(state.storage.as_ref(), &original_key))? {
+            None => Some(Damage::Missing),
+            Some((_, hash)) if hash != expected => Some(Damage::Corrupted),
+            Some(_) => None,
+        };
+        checked += 1;
+
+        match damage {
+            // The file may have been deleted, along with its original, while it was being read.
+            Some(damage) if state.files.contains_key(file_id)? => {
+                warn!("The original of {} is {:?}", file_id, damage);
+                damaged += 1;
+
+                let found = match state.damaged.get(file_id)? {
+                    Some(ref entry_bytes) => match bincode::deserialize::<(Damage, i64)>(entry_bytes).unwrap() {
+                        (previous, found) if previous == damage => found,
+                        _ => now,
+                    },
+                    None => now,
+                };
+                state.damaged.insert(file_id, bincode::serialize(&(damage, found)).unwrap())?;
+            }
+            _ => {
+                state.damaged.remove(file_id)?;
+            }
+        }
+    }
+
+    for entry in state.damaged.iter() {
+        let (file_id, _) = entry?;
+        if !state.files.contains_key(&file_id)? {
+            state.damaged.remove(&file_id)?;
+        }
+    }
+
+    let last_run = LastRun {
+        finished: Utc::now().timestamp(),
+        checked,
+        unhashed,
+    };
+    state.db.insert(LAST_VERIFIED, bincode::serialize(&last_run).unwrap())?;
+
+    info!("Verified {} originals, of which {} are damaged", checked, damaged);
+    Ok(())
+}
+
+/// Queue a run unless one is waiting or underway already.
+pub fn start(state: &AppState) -> ApiResult<()> {
+    let queued = jobs::list(state)?
+        .iter()
+        .any(|job| job.kind == "verify" && !job.failed);
+    if !queued {
+        jobs::enqueue(state, &Job::Verify)?;
+    }
+
+    Ok(())
+}
+
+/// What the last run found, with the files that are damaged by when they were found.
+pub fn report(state: &AppState) -> ApiResult<VerifyReport<'static>> {
+    let mut report = match state.db.get(LAST_VERIFIED)? {
+        Some(last_bytes) => {
+            let last_run: LastRun = bincode::deserialize(&last_bytes).unwrap();
+            VerifyReport {
+                last_run: Some(last_run.finished),
+                checked: last_run.checked,
+                unhashed: last_run.unhashed,
+                damaged: vec![],
+            }
+        }
+        None => VerifyReport::default(),
+    };
+
+    for entry in state.damaged.iter() {
+        let (file_id, entry_bytes) = entry?;
+        let file_bytes = match state.files.get(&file_id)? {
+            Some(file_bytes) => file_bytes,
+            None => continue,
+        };
+        let file: File = bincode::deserialize(&file_bytes).unwrap();
+        let (damage, found) = bincode::deserialize(&entry_bytes).unwrap();
+
+        report.damaged.push(DamagedFile {
+            file_id: Cow::Owned(String::from_utf8(file_id.to_vec()).unwrap()),
+            owner_id: Cow::Owned(file.owner_id.to_string()),
+            name: Cow::Owned(file.metadata.name.to_string()),
+            damage,
+            found,
+        });
+    }
+    report.damaged.sort_by_key(|damaged| damaged.found);
+
+    Ok(report)
+}
+
+/// Queue a run every `verify_interval_days`, counting from the last one that finished.
+pub async fn scheduler(state: AppState) {
+    if state.config.verify_interval_days <= 0 {
+        return;
+    }
+    let mut interval = time::interval(SCHEDULE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let due = block_in_place(|| {
+            let last_run = match state.db.get(LAST_VERIFIED)? {
+                Some(last_bytes) => bincode::deserialize::<LastRun>(&last_bytes).unwrap().finished,
+                None => 0,
+            };
+            let due = Utc::now().timestamp() - last_run >= state.config.verify_interval_days * 24 * 60 * 60;
+            if due {
+                start(&state)?;
+            }
+            Ok::<_, ApiError>(due)
+        });
+
+        match due {
+            Ok(true) => info!("Queued verification of every original"),
+            Ok(false) => {}
+            Err(err) => warn!(error = %err.chain(), "Couldn't queue verification"),
+        }
+    }
+}
+
+/// What the last run found.
+pub async fn status(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(block_in_place(|| report(state))?)
+}
+
+/// Queue a run unless one is waiting already.
+pub async fn queue(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, _) = req.into_parts();
+    require_admin(&parts)?;
+
+    let state: &AppState = parts.data().unwrap();
+    respond_ok(block_in_place(|| start(state))?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::{png, TestServer};
+    use hyper::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verify_originals() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let admin_key = server.signup_admin().await;
+
+        let kept = server.upload(&key, "kept.png", png(8, 8)).await;
+        let corrupted = server.upload(&key, "corrupted.png", png(16, 16)).await;
+        let missing = server.upload(&key, "missing.png", png(24, 24)).await;
+
+        let path = format!("/admin/verify?key={}", admin_key);
+        let report = server.json(Method::GET, &path, &()).await;
+        assert_eq!(report["last_run"], Value::Null);
+
+        // Asking twice queues a single run.
+        assert_eq!(server.send(Method::POST, &path, &()).await, StatusCode::OK);
+        assert_eq!(server.send(Method::POST, &path, &()).await, StatusCode::OK);
+        let jobs = server.json(Method::GET, &format!("/admin/jobs?key={}", admin_key), &()).await;
+        let verifications = jobs.as_array().unwrap().iter().filter(|job| job["kind"] == "verify");
+        assert_eq!(verifications.count(), 1);
+
+        let state = &server.state;
+        tokio::task::block_in_place(|| crate::verify::run(state)).unwrap();
+        let report = server.json(Method::GET, &path, &()).await;
+        assert_eq!(report["checked"], 3);
+        assert_eq!(report["damaged"], json!([]));
+
+        let scratch = state.temp_path.join("corrupted");
+        std::fs::write(&scratch, b"not the original").unwrap();
+        let original_key = |file_id: &str| crate::storage::key(crate::storage::ORIGINAL, file_id);
+        state.storage.put(&original_key(&corrupted), &scratch).await.unwrap();
+        state.storage.delete(&original_key(&missing)).await.unwrap();
+
+        tokio::task::block_in_place(|| crate::verify::run(state)).unwrap();
+        let report = server.json(Method::GET, &path, &()).await;
+        let mut damaged: Vec<_> = report["damaged"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|damaged| (damaged["name"].as_str().unwrap(), damaged["damage"].as_str().unwrap()))
+            .collect();
+        damaged.sort();
+        assert_eq!(damaged, [("corrupted.png", "corrupted"), ("missing.png", "missing")]);
+        assert!(report["damaged"].as_array().unwrap().iter().all(|damaged| damaged["file_id"] != kept));
+
+        // Deleted files aren't damaged anymore.
+        let status = server.send(Method::DELETE, &format!("/file/{}?key={}", missing, key), &()).await;
+        assert_eq!(status, StatusCode::OK);
+        let report = server.json(Method::GET, &path, &()).await;
+        assert_eq!(report["damaged"].as_array().unwrap().len(), 1);
+
+        // Only administrators may look.
+        let status = server.send(Method::GET, &format!("/admin/verify?key={}", key), &()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
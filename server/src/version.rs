@@ -0,0 +1,75 @@
+//! API Versions
+//!
+//! Clients send the `ApiVersion` that they were built against in the `api-version` header, and
+//! are turned away when it is older than `ApiVersion::MIN_SUPPORTED`. Requests without the header
+//! are served as before, so that tools like curl keep working. Every response carries the version
+//! of the server.
+
+use crate::error::{ApiError, ApiResult};
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response};
+use routerify::Middleware;
+use wire::{ApiVersion, API_VERSION_HEADER};
+
+async fn check(req: Request<Body>) -> ApiResult<Request<Body>> {
+    if let Some(version) = req.headers().get(API_VERSION_HEADER) {
+        let version = version
+            .to_str()
+            .ok()
+            .and_then(ApiVersion::parse)
+            .ok_or(ApiError::BadRequest)?;
+
+        if version < ApiVersion::MIN_SUPPORTED {
+            return Err(ApiError::UnsupportedVersion(version));
+        }
+    }
+
+    Ok(req)
+}
+
+async fn stamp(mut res: Response<Body>) -> ApiResult<Response<Body>> {
+    res.headers_mut().insert(API_VERSION_HEADER, header_value());
+    Ok(res)
+}
+
+pub fn header_value() -> HeaderValue {
+    HeaderValue::from(ApiVersion::CURRENT.0)
+}
+
+/// Reject requests from clients that are too old.
+pub fn checker() -> Middleware<Body, ApiError> {
+    Middleware::pre(check)
+}
+
+/// Add the version of the server to every response.
+pub fn stamper() -> Middleware<Body, ApiError> {
+    Middleware::post(stamp)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::testing::TestServer;
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::Value;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn api_version() {
+        let server = TestServer::start().await;
+
+        let current = [("api-version", wire::ApiVersion::CURRENT.to_string())];
+        let (status, _) = server.request(Method::GET, "/limits", &current, Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let old = [("api-version", "0".to_string())];
+        let (status, body) = server.request(Method::GET, "/limits", &old, Body::empty()).await;
+        assert_eq!(status, StatusCode::UPGRADE_REQUIRED);
+
+        let error: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["code"], "unsupported_version");
+        assert_eq!(error["details"]["client"], 0);
+        assert_eq!(error["details"]["minimum"], wire::ApiVersion::MIN_SUPPORTED.0);
+
+        let (status, _) = server.request(Method::GET, "/limits", &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,173 @@
+//! Videos
+//!
+//! Videos are represented by a poster, which their renditions are made from like they would be
+//! from a photo. The first frame is often black, so the poster is taken `poster_position` of the
+//! way in, falling back to the first frame for videos that can't be seeked.
+//!
+//! `ffprobe` reads the length and codec of each video, which are kept on the file for clients to
+//! show. Videos also get a preview strip of `preview_frames` frames, evenly spaced and side by
+//! side at the height of the shortest rendition, which is stored and served as `PREVIEW`. Clients
+//! scrub through a video by showing the frame of the strip under the pointer.
+
+use crate::config::Rendition;
+use crate::error::{ApiError, ApiResult};
+use crate::format::{self, Format};
+use libvips::VipsImage;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::path::Path;
+use std::process::{Command, Output};
+use wire::Video;
+
+/// What `ffprobe -of json` answers with, for the entries that are asked for.
+#[derive(Deserialize)]
+struct Probed {
+    #[serde(default)]
+    streams: Vec<ProbedStream>,
+    format: Option<ProbedFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbedStream {
+    codec_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbedFormat {
+    /// Seconds, as a decimal string.
+    duration: Option<String>,
+}
+
+impl Probed {
+    fn video(self) -> Option<Video<'static>> {
+        let codec = self.streams.into_iter().next()?.codec_name?;
+        let duration_ms = self
+            .format
+            .and_then(|format| format.duration)
+            .and_then(|duration| duration.parse::<f64>().ok())
+            .filter(|duration| duration.is_finite() && *duration > 0.0)
+            .map(|duration| (duration * 1000.0).round() as u64);
+
+        Some(Video {
+            duration_ms,
+            codec: Cow::from(codec),
+            preview_frames: 0,
+        })
+    }
+}
+
+/// Read the length and codec of the video at `path`, if `ffprobe` can.
+pub fn probe(path: &Path) -> Option<Video<'static>> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=codec_name:format=duration", "-of", "json"])
+        .arg(path)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    serde_json::from_slice::<Probed>(&output.stdout).ok()?.video()
+}
+
+fn succeeded(output: std::io::Result<Output>) -> bool {
+    output.is_ok_and(|output| output.status.success())
+}
+
+/// Write the frame at `position` of the way into the video at `path` to `output` as a PNG.
+pub fn poster(path: &Path, output: &Path, position: f64) -> ApiResult<()> {
+    let seconds = probe(path)
+        .and_then(|video| video.duration_ms)
+        .map_or(0.0, |duration_ms| duration_ms as f64 / 1000.0 * position);
+
+    for seconds in [seconds, 0.0].iter() {
+        let extracted = Command::new("ffmpeg")
+            .args(["-v", "error", "-y", "-ss", &format!("{:.3}", seconds), "-i"])
+            .arg(path)
+            .args(["-vframes", "1", "-f", "image2", "-c:v", "png"])
+            .arg(output)
+            .output();
+
+        // Seeking to the end, or past what a broken file holds, writes nothing.
+        let written = std::fs::metadata(output).is_ok_and(|metadata| metadata.len() > 0);
+        if succeeded(extracted) && written {
+            return Ok(());
+        }
+    }
+
+    Err(ApiError::UnsupportedFormat)
+}
+
+/// Probe the video at `path` if it is one, and write its preview strip of `frames` frames,
+/// encoded like the shortest of `renditions`, to `output`. `scratch` is used along the way.
+pub fn make(
+    format: Format,
+    path: &Path,
+    renditions: &[Rendition],
+    scratch: &Path,
+    output: &Path,
+    frames: u32,
+) -> ApiResult<Option<Video<'static>>> {
+    if format != Format::Video {
+        return Ok(None);
+    }
+    let mut video = match probe(path) {
+        Some(video) => video,
+        None => return Ok(None),
+    };
+
+    // Frames can only be spread over a video whose length is known.
+    let (duration_ms, shortest) = match (video.duration_ms, renditions.last()) {
+        (Some(duration_ms), Some(shortest)) if frames > 0 => (duration_ms, shortest),
+        _ => return Ok(Some(video)),
+    };
+
+    let filter = format!(
+        "fps={}/{:.3},scale=-2:{},tile={}x1",
+        frames,
+        duration_ms as f64 / 1000.0,
+        shortest.height,
+        frames
+    );
+    let tiled = Command::new("ffmpeg")
+        .args(["-v", "error", "-y", "-i"])
+        .arg(path)
+        .args(["-vf", &filter, "-frames:v", "1", "-f", "image2", "-c:v", "png"])
+        .arg(scratch)
+        .output();
+    if !succeeded(tiled) {
+        return Err(ApiError::UnsupportedFormat);
+    }
+
+    let strip = VipsImage::new_from_file(scratch.to_str().unwrap()).map_err(|_| ApiError::UnsupportedFormat)?;
+    format::save(&strip, shortest, output)?;
+
+    video.preview_frames = frames;
+    Ok(Some(video))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_probes() {
+        let phone = r#"{
+            "programs": [],
+            "streams": [{ "codec_name": "hevc" }],
+            "format": { "duration": "12.345600" }
+        }"#;
+        let video = serde_json::from_str::<Probed>(phone).unwrap().video().unwrap();
+        assert_eq!(video.codec, "hevc");
+        assert_eq!(video.duration_ms, Some(12_346));
+        assert_eq!(video.preview_frames, 0);
+
+        // Live streams and some WebM files don't say how long they are.
+        let stream = r#"{ "streams": [{ "codec_name": "vp9" }], "format": { "duration": "N/A" } }"#;
+        let video = serde_json::from_str::<Probed>(stream).unwrap().video().unwrap();
+        assert_eq!(video.duration_ms, None);
+
+        // Files without a video stream, like audio, aren't videos.
+        let audio = r#"{ "streams": [], "format": { "duration": "3.0" } }"#;
+        assert!(serde_json::from_str::<Probed>(audio).unwrap().video().is_none());
+    }
+}
@@ -0,0 +1,312 @@
+//! XMP Sidecars
+//!
+//! Photo managers like Lightroom and darktable keep what the user said about a photo in an XMP
+//! file next to it. Clients send the sidecar of each upload to `PUT /file/:fileId/xmp`, which
+//! reads the rating, title and keywords into the labels of the file, and the `xmp` quality writes
+//! the labels back out as a sidecar of their own, so that exports can carry them along.
+//!
+//! Only those three properties are read, and they are recognized by the prefixes that every
+//! writer uses for them, `xmp:Rating`, `dc:title` and `dc:subject`, rather than by resolving their
+//! namespaces. Ratings can be given as an attribute of their description or as an element of
+//! their own.
+
+use crate::{
+    album::rules,
+    common::{join, require_key, respond_ok, test_logged_in, AppState, File},
+    error::{ApiError, ApiResult},
+};
+use hyper::{Body, Request, Response};
+use routerify::ext::RequestExt;
+use std::borrow::Cow;
+use std::fmt::Write;
+use tokio::task::block_in_place;
+use wire::FileLabels;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+pub const MIME: &str = "application/rdf+xml";
+
+const RDF_DESCRIPTION: (&str, &str) = ("rdf", "Description");
+const RDF_LI: (&str, &str) = ("rdf", "li");
+const RATING: (&str, &str) = ("xmp", "Rating");
+const TITLE: (&str, &str) = ("dc", "title");
+const SUBJECT: (&str, &str) = ("dc", "subject");
+
+/// Read the labels out of the XMP packet `xmp`.
+pub fn read(xmp: &str) -> ApiResult<FileLabels<'static>> {
+    let mut labels = FileLabels::default();
+    // Elements that are open, by prefix and local name.
+    let mut open: Vec<(&str, &str)> = vec![];
+
+    for token in Tokenizer::from(xmp.trim_start_matches('\u{feff}')) {
+        match token.map_err(|_| ApiError::BadRequest)? {
+            Token::ElementStart { prefix, local, .. } => open.push((prefix.as_str(), local.as_str())),
+            Token::Attribute { prefix, local, value, .. }
+                if open.last() == Some(&RDF_DESCRIPTION) && (prefix.as_str(), local.as_str()) == RATING =>
+            {
+                labels.rating = parse_rating(&unescape(value.as_str()));
+            }
+            Token::ElementEnd {
+                end: ElementEnd::Close(..) | ElementEnd::Empty,
+                ..
+            } => {
+                open.pop();
+            }
+            Token::Text { text } | Token::Cdata { text, .. } => {
+                let text = unescape(text.as_str());
+                let text = text.trim();
+                if text.is_empty() {
+                    continue;
+                }
+
+                if open.last() == Some(&RATING) {
+                    labels.rating = parse_rating(text);
+                } else if open.last() == Some(&RDF_LI) && open.contains(&TITLE) && labels.title.is_none() {
+                    // Titles come in one language or more, the first of which is the default.
+                    labels.title = Some(Cow::from(text.to_string()));
+                } else if open.last() == Some(&RDF_LI)
+                    && open.contains(&SUBJECT)
+                    && !labels.tags.iter().any(|tag| tag == text)
+                {
+                    labels.tags.push(Cow::from(text.to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Ratings outside of what `xmp:Rating` allows are left out.
+fn parse_rating(text: &str) -> Option<i8> {
+    text.trim().parse().ok().filter(|rating| (-1..=5).contains(rating))
+}
+
+/// Replace the entity and character references in `text`. References that aren't known are kept
+/// as they are.
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let reference = rest.find(';').map(|end| (&rest[1..end], end));
+        let c = reference.and_then(|(name, _)| match name {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                let code = if let Some(hex) = name.strip_prefix("#x") {
+                    u32::from_str_radix(hex, 16).ok()
+                } else {
+                    name.strip_prefix('#')?.parse().ok()
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+
+        match (c, reference) {
+            (Some(c), Some((_, end))) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Write `labels` as an XMP packet that `read` gives back.
+pub fn write(labels: &FileLabels) -> String {
+    let mut xmp = String::new();
+
+    xmp.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    xmp.push_str("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n");
+    xmp.push_str(" <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+    xmp.push_str("  <rdf:Description rdf:about=\"\"\n");
+    xmp.push_str("    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n");
+    xmp.push_str("    xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n");
+
+    if let Some(rating) = labels.rating {
+        writeln!(xmp, "   <xmp:Rating>{}</xmp:Rating>", rating).unwrap();
+    }
+    if let Some(ref title) = labels.title {
+        xmp.push_str("   <dc:title>\n    <rdf:Alt>\n");
+        writeln!(xmp, "     <rdf:li xml:lang=\"x-default\">{}</rdf:li>", escape(title)).unwrap();
+        xmp.push_str("    </rdf:Alt>\n   </dc:title>\n");
+    }
+    if !labels.tags.is_empty() {
+        xmp.push_str("   <dc:subject>\n    <rdf:Bag>\n");
+        for tag in &labels.tags {
+            writeln!(xmp, "     <rdf:li>{}</rdf:li>", escape(tag)).unwrap();
+        }
+        xmp.push_str("    </rdf:Bag>\n   </dc:subject>\n");
+    }
+
+    xmp.push_str("  </rdf:Description>\n </rdf:RDF>\n</x:xmpmeta>\n");
+    xmp.push_str("<?xpacket end=\"w\"?>\n");
+    xmp
+}
+
+/// Replace the labels of a file with those of the XMP sidecar in the body.
+pub async fn set(req: Request<Body>) -> ApiResult<Response<Body>> {
+    let (parts, body) = req.into_parts();
+
+    let key = require_key(&parts)?;
+    let (owner_id, _) = key.split_once('.').ok_or(ApiError::BadRequest)?;
+
+    let entire_body = join(&parts, body).await?;
+    let xmp = std::str::from_utf8(&entire_body).map_err(|_| ApiError::BadRequest)?;
+    let labels = read(xmp)?;
+
+    let file_id = parts.param("fileId").unwrap();
+    let state = parts.data().unwrap();
+    let AppState {
+        ref sessions,
+        ref files,
+        ..
+    } = state;
+
+    block_in_place(|| {
+        test_logged_in(sessions, key)?;
+
+        files.transaction(|files| {
+            let file_bytes = files.get(file_id.as_bytes())?.ok_or(ApiError::NotFound)?;
+            let mut file: File = bincode::deserialize(&file_bytes).unwrap();
+
+            if file.owner_id != owner_id {
+                return Err(ApiError::NotFound.into());
+            }
+
+            file.labels = labels.clone();
+            files.insert(file_id.as_bytes(), bincode::serialize(&file).unwrap())?;
+            Ok(())
+        })?;
+
+        // New tags may match rules that the file didn't before.
+        rules::apply(state, file_id)?;
+
+        respond_ok(labels)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{png, TestServer};
+    use hyper::{Body, Method, StatusCode};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn read_attributes() {
+        // As Lightroom writes it.
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="Adobe XMP Core 5.6-c140">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmp:Rating="4">
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Fish &amp; chips</rdf:li>
+     <rdf:li xml:lang="fr">Poisson-frites</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>beach</rdf:li>
+     <rdf:li> food </rdf:li>
+     <rdf:li>beach</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+        let labels = read(xmp).unwrap();
+        assert_eq!(labels.rating, Some(4));
+        assert_eq!(labels.title.as_deref(), Some("Fish & chips"));
+        assert_eq!(labels.tags, vec!["beach", "food"]);
+    }
+
+    #[test]
+    fn read_elements() {
+        // As darktable writes it, with a rating that is out of range.
+        let xmp = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/"><xmp:Rating>7</xmp:Rating>
+<xmp:Label>Red</xmp:Label><dc:subject><rdf:Seq><rdf:li>caf&#xE9;</rdf:li></rdf:Seq></dc:subject>
+</rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+        let labels = read(xmp).unwrap();
+        assert_eq!(labels.rating, None);
+        assert_eq!(labels.title, None);
+        assert_eq!(labels.tags, vec!["café"]);
+
+        assert!(read("<x:xmpmeta><rdf:RDF>").is_ok());
+        assert!(read("<x:xmpmeta <rdf:RDF>").is_err());
+    }
+
+    #[test]
+    fn write_and_read() {
+        let labels = FileLabels {
+            rating: Some(-1),
+            title: Some(Cow::from("<Untitled> & \"unused\"")),
+            tags: vec![Cow::from("a&b"), Cow::from("c")],
+        };
+        assert_eq!(read(&write(&labels)).unwrap(), labels);
+        assert_eq!(read(&write(&FileLabels::default())).unwrap(), FileLabels::default());
+    }
+
+    #[test]
+    fn unknown_references() {
+        assert_eq!(unescape("&unknown; &#xZZ; & &amp;"), "&unknown; &#xZZ; & &");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn xmp_sidecar() {
+        let server = TestServer::start().await;
+        let key = server.signup("owner@example.com").await;
+        let other = server.signup("other@example.com").await;
+        let file_id = server.upload(&key, "IMG_0001.png", png(64, 48)).await;
+
+        let sidecar = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF>
+            <rdf:Description xmp:Rating="5"><dc:subject><rdf:Bag>
+            <rdf:li>hiking</rdf:li><rdf:li>alps</rdf:li>
+            </rdf:Bag></dc:subject></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        let xmp_path = |key: &str| format!("/file/{}/xmp?key={}", file_id, key);
+
+        let (status, _) = server.request(Method::PUT, &xmp_path(&other), &[], Body::from(sidecar)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = server.request(Method::PUT, &xmp_path(&key), &[], Body::from("<rdf:RDF <")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = server.request(Method::PUT, &xmp_path(&key), &[], Body::from(sidecar)).await;
+        assert_eq!(status, StatusCode::OK);
+        let labels: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(labels, json!({ "rating": 5, "title": null, "tags": ["hiking", "alps"] }));
+
+        let info = server.json(Method::GET, &format!("/file/{}?key={}", file_id, key), &()).await;
+        assert_eq!(info["labels"], labels);
+
+        // The sidecar that is served reads back the same.
+        let path = format!("/file/xmp/{}?key={}", file_id, key);
+        let (status, body) = server.request(Method::GET, &path, &[], Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        let served = crate::xmp::read(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(serde_json::to_value(served).unwrap(), labels);
+    }
+}
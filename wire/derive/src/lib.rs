@@ -0,0 +1,88 @@
+//! Derive for `wire::IntoOwned`. Every field is turned into its owned form, and every lifetime
+//! of the type is replaced by `'static`, so the type may only be generic over lifetimes.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+#[proc_macro_derive(IntoOwned)]
+pub fn derive_into_owned(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    let name = &input.ident;
+
+    if input.generics.type_params().next().is_some() || input.generics.const_params().next().is_some() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "IntoOwned can only be derived for types that are generic over lifetimes",
+        ));
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let statics = input.generics.lifetimes().map(|_| quote!('static));
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, construct) = convert(quote!(#name), &data.fields);
+            quote! {
+                let #pattern = self;
+                #construct
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let (pattern, construct) = convert(quote!(#name::#ident), &variant.fields);
+                quote!(#pattern => #construct,)
+            });
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(input, "IntoOwned can't be derived for unions"));
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::wire::IntoOwned for #name #ty_generics #where_clause {
+            type Owned = #name<#(#statics),*>;
+
+            fn into_owned(self) -> Self::Owned {
+                #body
+            }
+        }
+    })
+}
+
+/// A pattern that binds every field of `path`, and an expression that builds it again out of
+/// the owned fields.
+fn convert(path: TokenStream2, fields: &Fields) -> (TokenStream2, TokenStream2) {
+    match fields {
+        Fields::Named(fields) => {
+            let names: Vec<_> = fields.named.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+            (
+                quote!(#path { #(#names),* }),
+                quote!(#path { #(#names: ::wire::IntoOwned::into_owned(#names)),* }),
+            )
+        }
+        Fields::Unnamed(fields) => {
+            let bindings: Vec<_> = (0..fields.unnamed.len()).map(|i| format_ident!("field{}", i)).collect();
+            (
+                quote!(#path(#(#bindings),*)),
+                quote!(#path(#(::wire::IntoOwned::into_owned(#bindings)),*)),
+            )
+        }
+        Fields::Unit => (quote!(#path), quote!(#path)),
+    }
+}
@@ -0,0 +1,259 @@
+//! Endpoints
+//!
+//! Every route of the API as a type that names its method, its path and the bodies that it takes
+//! and returns. The server routes by these and the client builds its requests from them, so a
+//! route can't move on one side without the other following.
+
+use crate::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+        }
+    }
+}
+
+pub trait Endpoint {
+    const METHOD: Method;
+    /// Path from the root of the API. Segments that start with `:` are parameters.
+    const PATH: &'static str;
+    /// Body of the request, which is `()` for requests without one.
+    type Request<'a>;
+    /// Body of a successful response, which is `()` when it is empty.
+    type Response<'a>;
+
+    /// The path with the parameters filled in by `params`, in order.
+    fn path(params: &[&str]) -> String {
+        let mut params = params.iter();
+        let path = Self::PATH
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => *params.next().unwrap_or_else(|| panic!("Missing the {} parameter", name)),
+                None => segment,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+
+        assert!(params.next().is_none(), "Too many parameters for {}", Self::PATH);
+        path
+    }
+}
+
+/// A body that isn't JSON, like an image or a fragment that the server stored as it is sent.
+pub struct Bytes;
+
+/// A `multipart/form-data` body.
+pub struct Multipart;
+
+/// A body of JSON values that are streamed one per line.
+pub struct Lines<T>(std::marker::PhantomData<T>);
+
+/// A stream of server-sent events.
+pub struct EventStream;
+
+macro_rules! endpoints {
+    ($($(#[$meta:meta])* $name:ident: $method:ident $path:literal, $request:ty => $response:ty;)*) => {
+        $(
+            $(#[$meta])*
+            pub struct $name;
+
+            impl Endpoint for $name {
+                const METHOD: Method = Method::$method;
+                const PATH: &'static str = $path;
+                type Request<'a> = $request;
+                type Response<'a> = $response;
+            }
+        )*
+
+        /// The method and path of every endpoint.
+        pub const ROUTES: &[(Method, &str)] = &[$((Method::$method, $path)),*];
+    };
+}
+
+endpoints! {
+    /// Register. While registration is by invitation, the code is sent in the `invite` query.
+    CreateUser: Post "/user", UserDetails<'a, 'a> => ();
+    /// Delete the user along with everything that they own.
+    DeleteUser: Delete "/user", () => ();
+    ListEmails: Get "/user/emails", () => Vec<String>;
+    /// Log in. With `?cookie=true` the key is set as a cookie instead, and the response is a
+    /// `CsrfToken`.
+    Login: Post "/user/auth", UserDetails<'a, 'a> => Key<'a>;
+    UpdatePassword: Put "/user/auth", ChangePassword => ();
+    ListSessions: Get "/user/auth", () => SessionList<'a, 'a, 'a>;
+    Logout: Delete "/user/auth", Key<'a> => ();
+    LogoutOthers: Delete "/user/auth/others", () => ();
+    Csrf: Get "/user/auth/csrf", () => CsrfToken<'a>;
+    Audit: Get "/user/auth/audit", () => Vec<LoginAttempt<'a, 'a>>;
+    /// Check the password again for a token that opens the locked folder for a few minutes.
+    Elevate: Post "/user/auth/elevate", Elevate => ElevationToken<'a>;
+    /// Totals over the user's library, which are kept as files come and go.
+    GetStats: Get "/user/stats", () => LibraryStats;
+    /// Log in through the configured OpenID Connect provider, which the browser is redirected to.
+    /// Takes the same queries as `Login`.
+    OidcLogin: Get "/user/oidc/login", () => ();
+    /// Where the provider sends the browser back to. Responds like `Login`, except that a cookie
+    /// login redirects to the web frontend once the cookie is set.
+    OidcCallback: Get "/user/oidc/callback", () => Key<'a>;
+    Verify: Post "/user/verify", Key<'a> => ();
+    RequestReset: Post "/user/reset/request", ResetRequest<'a> => ();
+    ConfirmReset: Post "/user/reset/confirm", ResetConfirm => ();
+
+    /// Upload a file. Its `FileMetadata` is sent in the `upload-metadata` header. Uploads with an
+    /// `album` query are also added to that album, which the user has to be able to contribute to,
+    /// and the `conflict` query takes a `ConflictPolicy`. With `?encrypted=true` the file was
+    /// encrypted by the client, so it is stored as it is and only served as `large`.
+    Upload: Post "/file", Bytes => StoredFile<'a>;
+    /// Takes the same queries as `Upload`.
+    UploadBatch: Post "/file/batch", Multipart => Vec<UploadResult<'a, 'a, 'a>>;
+    /// Locked files are left out, since they are listed by `ListLocked`.
+    ListFiles: Post "/file/list", ListRequest<'a> => FileList<'a, 'a>;
+    /// Pages the same way as `ListFiles`, but with the dimensions, type and size of every file.
+    /// Locked files are left out here too.
+    ListFileEntries: Post "/file/entries", ListRequest<'a> => FileEntryList<'a>;
+    SearchGeo: Get "/file/geo", () => Vec<GeoCluster<'a>>;
+    /// Files of the user that the detection service found the `label` query in, surest first.
+    SearchLabels: Get "/file/search", () => Vec<LabeledFile<'a>>;
+    /// The files in the user's locked folder, by name, which needs an elevation token.
+    ListLocked: Get "/file/locked", () => Vec<FileEntry<'a>>;
+    /// The file record and the albums that the user can see it in. Files can be seen by their
+    /// owner and by the members of an album that they are in.
+    GetFile: Get "/file/:fileId", () => FileInfo<'a>;
+    DeleteFile: Delete "/file/:fileId", () => ();
+    RenameFile: Patch "/file/:fileId", Rename<'a> => ();
+    /// Leave a file out of the library, or put it back in.
+    HideFile: Put "/file/:fileId/hidden", Hidden => ();
+    /// Move a file into the locked folder, which takes it out of the library, its albums and
+    /// searches, or move it back out. Both need an elevation token, as do the record and the
+    /// renditions of locked files.
+    LockFile: Put "/file/:fileId/locked", Locked => ();
+    /// Replace the edit of a file and render it again.
+    EditFile: Post "/file/:fileId/edit", FileEdit => ();
+    /// Replace the labels of a file with the rating, title and keywords of an XMP sidecar. The
+    /// `xmp` quality of `ServeFile` writes them back out as one.
+    SetXmp: Put "/file/:fileId/xmp", Bytes => FileLabels<'a>;
+    /// Files of the user that look like this one, closest first, whose perceptual hashes are
+    /// within the `distance` query of its own, which defaults to 10 bits.
+    FindSimilar: Get "/file/:fileId/similar", () => Vec<SimilarFile<'a>>;
+    ServeFile: Get "/file/:quality/:fileId", () => Bytes;
+    /// A file scaled to fit in a box of the `w` and `h` queries, either of which may be left out,
+    /// or to cover it when the `fit` query is `cover` rather than `contain`. Only the sizes that
+    /// the server allows can be asked for, and files are never scaled up.
+    ServeCustom: Get "/file/serve/custom/:fileId", () => Bytes;
+    GetLimits: Get "/limits", () => Limits;
+    /// Counters in the Prometheus text format.
+    GetMetrics: Get "/metrics", () => Bytes;
+
+    CreateAlbum: Post "/album", AlbumSettings<'a> => NewResource<'a>;
+    /// Takes a `role` query of `owner` or `shared` to only list some of the albums.
+    ListAlbums: Get "/album", () => HashMap<String, AlbumInfo<'a>>;
+    /// Lists albums in the order of the `sort` query, and takes `role` like `ListAlbums` does.
+    BrowseAlbums: Get "/album/browse", () => AlbumListing<'a>;
+    /// Takes the fragment head that the client knows for each album, and returns the albums that
+    /// have moved on, or `None` for albums that the user can't see anymore.
+    ChangedAlbums: Post "/album/changed", HashMap<String, u64> => HashMap<String, Option<AlbumInfo<'a>>>;
+    DeleteAlbum: Delete "/album/:albumId", () => ();
+    UpdateAlbum: Patch "/album/:albumId", AlbumSettings<'a> => ();
+    AddFiles: Post "/album/:albumId/files", IdList<'a> => Lines<BulkProgress>;
+    RemoveFiles: Delete "/album/:albumId/files", IdList<'a> => Lines<BulkProgress>;
+    /// Take files out of the first album and add them to the second in one transaction, so that
+    /// they are never missing from both. Files keep their captions, unless they were already in
+    /// the second album, and only the user's own files can be moved.
+    MoveFiles: Post "/album/:fromId/move/:toId", IdList<'a> => ();
+    ReorderAlbum: Post "/album/:albumId/order", IdList<'a> => ();
+    SetCaption: Put "/album/:albumId/caption/:fileId", Caption<'a> => ();
+    /// Serve the `AlbumInfo` when the fragment is `metadata`, or a fragment as it was stored. For
+    /// albums sorted by date, the metadata takes `from` and `to` queries of unix times, and then
+    /// lists the sections of days within them, so that the top fragment doesn't have to be read.
+    ServeAlbum: Get "/album/:albumId/serve/:fragmentId", () => Bytes;
+    /// Keys of the sections of an album sorted by date whose days are in the month, `YYYY-MM` in
+    /// the time zone of the album.
+    AlbumMonth: Get "/album/:albumId/month/:month", () => Vec<i64>;
+    AlbumDelta: Get "/album/:albumId/delta/:fromHead", () => Bytes;
+    AlbumGeo: Get "/album/:albumId/geo", () => Vec<GeoCluster<'a>>;
+    TransferAlbum: Post "/album/:albumId/transfer", TransferOwnership<'a> => ();
+    ShareAlbum: Post "/album/:albumId/share", PermissionPair<'a, 'a> => ();
+    UnshareAlbum: Delete "/album/:albumId/share", Key<'a> => ();
+    ListMembers: Get "/album/:albumId/share", () => Vec<PermissionPair<'a, 'a>>;
+    ListActivity: Get "/album/:albumId/activity", () => Vec<Activity>;
+    GetAlbumPrivacy: Get "/album/:albumId/privacy", () => AlbumPrivacy;
+    /// Only the owner of the album can change it. Originals that can't be stripped of their
+    /// metadata, like videos, aren't served to the members that it applies to.
+    SetAlbumPrivacy: Put "/album/:albumId/privacy", AlbumPrivacy => ();
+    /// Links that guests upload to the album through. Only the owner can see or change them.
+    ListContributionLinks: Get "/album/:albumId/links", () => Vec<ContributionLink<'a>>;
+    CreateContributionLink: Post "/album/:albumId/links", ContributionOptions => ContributionLink<'a>;
+    /// Files that were already uploaded through the link are kept.
+    DeleteContributionLink: Delete "/album/:albumId/links/:token", () => ();
+    /// Uploads through moderated links that wait for the owner, oldest first.
+    ListPendingUploads: Get "/album/:albumId/pending", () => Vec<PendingUpload<'a>>;
+    /// Add uploads that were waiting to the album.
+    ApproveUploads: Post "/album/:albumId/pending", IdList<'a> => ();
+    /// Move uploads that were waiting to the owner's trash instead.
+    RejectUploads: Delete "/album/:albumId/pending", IdList<'a> => ();
+    /// Bundle the originals of an album with an `AlbumManifest`, as a `multipart/form-data` body
+    /// that `ImportAlbum` takes as it is.
+    ExportAlbum: Get "/album/:albumId/export", () => Multipart;
+    /// Create an album owned by the user from a bundle, storing its files as uploads do. Takes the
+    /// `conflict` query of `Upload`, which defaults to `rename` here.
+    ImportAlbum: Post "/album/import", Multipart => ImportedAlbum<'a>;
+
+    /// The album that a contribution link uploads to. Guest routes are authorized by the token of
+    /// the link instead of a key.
+    GetGuestAlbum: Get "/guest/:token", () => GuestAlbum<'a>;
+    /// Upload a file through a contribution link, the way `Upload` does without its queries. Files
+    /// are renamed when their name is taken, and turned away with `QuotaExceeded` once the link
+    /// has taken as many files or bytes as it may.
+    GuestUpload: Post "/guest/:token", Bytes => StoredFile<'a>;
+
+    ServeLibrary: Get "/library/serve/:fragmentId", () => Bytes;
+    /// Files of the library that were taken on this day in earlier years, as `[year, entries]`
+    /// with the latest year first. The day is the `date` query, `YYYY-MM-DD`, or else today in
+    /// UTC, which the library is dated in.
+    Memories: Get "/memories", () => Vec<(i32, Vec<SectionEntry>)>;
+
+    ListTrash: Get "/trash", () => Vec<TrashEntry<'a, 'a>>;
+    EmptyTrash: Delete "/trash", () => ();
+    RestoreTrash: Post "/trash/restore", TrashRestore<'a> => ();
+
+    Events: Get "/events", () => EventStream;
+
+    ListJobs: Get "/admin/jobs", () => Vec<JobStatus>;
+    RegenerationStatus: Get "/admin/regenerate", () => RegenerateStatus;
+    StartRegeneration: Post "/admin/regenerate", () => RegenerateStatus;
+    CollectionStatus: Get "/admin/gc", () => GcStatus;
+    /// Bytes that each user and session has been served today, most first.
+    GetBandwidth: Get "/admin/bandwidth", () => BandwidthUsage<'a>;
+    /// Check that the trees and storage agree with each other, repairing what can be repaired
+    /// with `?repair=true`.
+    Fsck: Post "/admin/fsck", () => Vec<Finding<'a>>;
+    /// Originals that didn't hash to what they did on upload when they were last verified.
+    VerificationReport: Get "/admin/verify", () => VerifyReport<'a>;
+    /// Queue a job that hashes every original again, unless one is waiting already.
+    StartVerification: Post "/admin/verify", () => ();
+    /// Invitations that haven't been used yet.
+    ListInvites: Get "/admin/invites", () => Vec<Invite<'a>>;
+    CreateInvite: Post "/admin/invites", InviteOptions => Invite<'a>;
+    DeleteInvite: Delete "/admin/invites/:code", () => ();
+}
+
+#[test]
+fn fill_path() {
+    assert_eq!(SetCaption::path(&["album", "file"]), "/album/album/caption/file");
+    assert_eq!(ListAlbums::path(&[]), "/album");
+}
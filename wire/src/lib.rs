@@ -1,72 +1,254 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+// Lets the derive name the trait as `::wire::IntoOwned` inside this crate too.
+extern crate self as wire;
+
+pub mod endpoint;
+
+pub use wire_derive::IntoOwned;
 
 pub trait IntoOwned {
     type Owned;
     fn into_owned(self) -> Self::Owned;
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct UserDetails<'a, 'b> {
-    #[serde(borrow)]
-    pub email: Cow<'a, str>,
-    #[serde(borrow)]
-    pub password: Cow<'b, str>,
+impl<'a, B: ToOwned + ?Sized + 'static> IntoOwned for Cow<'a, B> {
+    type Owned = Cow<'static, B>;
+
+    fn into_owned(self) -> Cow<'static, B> {
+        Cow::Owned(Cow::into_owned(self))
+    }
 }
 
-impl<'a, 'b> IntoOwned for UserDetails<'a, 'b> {
-    type Owned = UserDetails<'static, 'static>;
+impl<T: IntoOwned> IntoOwned for Option<T> {
+    type Owned = Option<T::Owned>;
 
     fn into_owned(self) -> Self::Owned {
-        UserDetails {
-            email: Cow::Owned(self.email.into_owned()),
-            password: Cow::Owned(self.password.into_owned()),
-        }
+        self.map(IntoOwned::into_owned)
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Vec<T> {
+    type Owned = Vec<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.into_iter().map(IntoOwned::into_owned).collect()
+    }
+}
+
+impl<K: IntoOwned, V: IntoOwned> IntoOwned for HashMap<K, V>
+where
+    K::Owned: Eq + Hash,
+{
+    type Owned = HashMap<K::Owned, V::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.into_iter().map(IntoOwned::into_owned).collect()
+    }
+}
+
+impl<A: IntoOwned, B: IntoOwned> IntoOwned for (A, B) {
+    type Owned = (A::Owned, B::Owned);
+
+    fn into_owned(self) -> Self::Owned {
+        (self.0.into_owned(), self.1.into_owned())
+    }
+}
+
+/// Types that never borrow, so they are their own owned form.
+macro_rules! already_owned {
+    ($($ty:ty),*) => {
+        $(
+            impl IntoOwned for $ty {
+                type Owned = $ty;
+
+                fn into_owned(self) -> Self::Owned {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+already_owned!((), bool, i8, u8, u16, i32, i64, u32, u64, usize, f64, String, chrono_tz::Tz, serde_json::Value);
+
+/// Header that carries the `ApiVersion` of a request, and of every response.
+pub const API_VERSION_HEADER: &str = "api-version";
+
+/// Version of the wire format. It goes up whenever a change would break clients that read the
+/// old format, like a new fragment schema.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, IntoOwned)]
+#[serde(transparent)]
+pub struct ApiVersion(pub u32);
+
+impl ApiVersion {
+    /// The version that this crate describes.
+    pub const CURRENT: ApiVersion = ApiVersion(1);
+    /// The oldest version that the server still answers.
+    pub const MIN_SUPPORTED: ApiVersion = ApiVersion(1);
+
+    pub fn parse(version: &str) -> Option<ApiVersion> {
+        version.trim().parse().ok().map(ApiVersion)
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Details of the error that a client gets for sending a version that is too old.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, IntoOwned)]
+pub struct UnsupportedVersion {
+    pub client: ApiVersion,
+    pub minimum: ApiVersion,
+    pub current: ApiVersion,
+}
+
+/// What went wrong with a request. The codes are stable, so clients can branch on them.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Unauthorized,
+    NotFound,
+    BadRequest,
+    EmailTaken,
+    FileExists,
+    UnsupportedFormat,
+    PayloadTooLarge,
+    Timeout,
+    /// Details carry `retry_after`, in seconds.
+    TooManyRequests,
+    /// Details carry `retry_after`, in seconds.
+    AccountLocked,
+    /// Details are an `UnsupportedVersion`.
+    UnsupportedVersion,
+    /// The upload would take the user over their quota.
+    QuotaExceeded,
+    /// The server has too many images to process already. Details carry `retry_after`, in seconds.
+    Busy,
+    /// The file is locked, and a token from `Elevate` has to be sent along to see it.
+    ElevationRequired,
+    Internal,
+    /// A code from a newer server, or a body that isn't an `ErrorResponse` at all.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Body of every error response.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ErrorResponse<'a> {
+    pub code: ErrorCode,
+    #[serde(borrow)]
+    pub message: Cow<'a, str>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl<'a> ErrorResponse<'a> {
+    /// Seconds until the request may be tried again.
+    pub fn retry_after(&self) -> Option<u64> {
+        self.details.as_ref()?.get("retry_after")?.as_u64()
+    }
+
+    pub fn unsupported_version(&self) -> Option<UnsupportedVersion> {
+        serde_json::from_value(self.details.clone()?).ok()
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct UserDetails<'a, 'b> {
+    #[serde(borrow)]
+    pub email: Cow<'a, str>,
+    #[serde(borrow)]
+    pub password: Cow<'b, str>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChangePassword {
     pub old_password: String,
     pub new_password: String,
 }
 
+/// The password of the user, entered again to open their locked folder.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Elevate {
+    pub password: String,
+}
+
+/// Sent along with requests for locked files until it `expires`, in the `x-elevation-token` header
+/// or the `elevation` query. Only the session that asked for it can use it.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ElevationToken<'a> {
+    #[serde(borrow)]
+    pub token: Cow<'a, str>,
+    pub expires: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ResetRequest<'a> {
+    #[serde(borrow)]
+    pub email: Cow<'a, str>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ResetConfirm {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct Key<'a> {
     #[serde(borrow)]
     pub key: Cow<'a, str>,
 }
 
-impl<'a> IntoOwned for Key<'a> {
-    type Owned = Key<'static>;
-
-    fn into_owned(self) -> Self::Owned {
-        Key {
-            key: Cow::Owned(self.key.into_owned()),
-        }
-    }
+/// Token that requests authenticated by a session cookie must repeat in the `x-csrf-token`
+/// header, unless they only read.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct CsrfToken<'a> {
+    #[serde(borrow)]
+    pub token: Cow<'a, str>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct SessionList<'a> {
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct SessionInfo<'a, 'b, 'c> {
+    #[serde(borrow)]
+    pub key_prefix: Cow<'a, str>,
     #[serde(borrow)]
-    pub key_prefixes: Vec<Cow<'a, str>>,
+    pub label: Option<Cow<'b, str>>,
+    #[serde(borrow)]
+    pub user_agent: Option<Cow<'c, str>>,
+    pub created: i64,
+    pub last_used: i64,
+    /// Whether this is the session that made the request.
+    pub current: bool,
 }
 
-impl<'a> IntoOwned for SessionList<'a> {
-    type Owned = SessionList<'static>;
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct SessionList<'a, 'b, 'c> {
+    #[serde(borrow)]
+    pub sessions: Vec<SessionInfo<'a, 'b, 'c>>,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        SessionList {
-            key_prefixes: self.key_prefixes
-                .iter()
-                .map(|e| Cow::Owned(e.to_string()))
-                .collect(),
-        }
-    }
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct LoginAttempt<'a, 'b> {
+    pub time_stamp: i64,
+    #[serde(borrow)]
+    pub ip: Cow<'a, str>,
+    #[serde(borrow)]
+    pub user_agent: Option<Cow<'b, str>>,
+    pub success: bool,
+    /// The account was locked, so the password wasn't checked.
+    pub locked: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct FileMetadata<'a, 'b> {
     pub last_modified: i64,
 
@@ -77,147 +259,525 @@ pub struct FileMetadata<'a, 'b> {
     pub mime: Cow<'b, str>,
 }
 
-impl<'a, 'b> IntoOwned for FileMetadata<'a, 'b> {
-    type Owned = FileMetadata<'static, 'static>;
+/// Whether a file is left out of its owner's library, which it stays in albums and listings either
+/// way.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, IntoOwned)]
+pub struct Hidden {
+    pub hidden: bool,
+}
+
+/// Whether members of an album other than the owner of a file are served its original without the
+/// metadata that the camera wrote into it, like where it was taken.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, IntoOwned)]
+pub struct AlbumPrivacy {
+    pub strip_metadata: bool,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        FileMetadata {
-            last_modified: self.last_modified,
-            name: Cow::Owned(self.name.into_owned()),
-            mime: Cow::Owned(self.mime.into_owned()),
-        }
-    }
+/// Limits of a contribution link, which lets guests without an account upload to an album.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct ContributionOptions {
+    /// Files that can be uploaded through the link, or no limit if missing.
+    pub max_files: Option<u64>,
+    /// Bytes of originals that can be uploaded through the link, or no limit if missing.
+    pub max_bytes: Option<u64>,
+    /// Seconds until the link can't be used anymore, or never if missing.
+    pub expires_in: Option<i64>,
+    /// Hold uploads until the owner approves them, instead of adding them to the album.
+    #[serde(default)]
+    pub moderated: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A link that guests upload to an album through. What they upload is stored as files of the
+/// owner of the album.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ContributionLink<'a> {
+    #[serde(borrow)]
+    pub token: Cow<'a, str>,
+    #[serde(borrow)]
+    pub album_id: Cow<'a, str>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub max_files: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub moderated: bool,
+    /// Files that were uploaded through the link so far.
+    pub files: u64,
+    /// Bytes of originals that were uploaded through the link so far.
+    pub bytes: u64,
+}
+
+/// What a guest is shown of the album that a contribution link uploads to.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct GuestAlbum<'a> {
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    pub moderated: bool,
+    /// Files that can still be uploaded, or no limit if missing.
+    pub files_left: Option<u64>,
+    /// Bytes that can still be uploaded, or no limit if missing.
+    pub bytes_left: Option<u64>,
+}
+
+/// An upload through a moderated contribution link that the owner hasn't approved or rejected.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct PendingUpload<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+    /// Token of the link that it was uploaded through.
+    #[serde(borrow)]
+    pub token: Cow<'a, str>,
+    pub uploaded: i64,
+}
+
+/// Whether a file is in its owner's locked folder.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, IntoOwned)]
+pub struct Locked {
+    pub locked: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct Rename<'a> {
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+}
+
+/// Lists the files of a user by name. Long listings are read a page of `length` files at a
+/// time, passing the `next` token of each page on to the request for the following one.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct ListRequest<'a> {
     pub prefix: Option<Cow<'a, str>>,
+    /// Files to leave out from the start, which still have to be read past. Tokens don't.
     pub skip: Option<usize>,
     pub length: Option<usize>,
+    /// The `next` token of the previous page.
+    #[serde(default, borrow)]
+    pub token: Option<Cow<'a, str>>,
 }
 
-impl<'a> IntoOwned for ListRequest<'a> {
-    type Owned = ListRequest<'static>;
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct FileList<'a, 'b> {
+    #[serde(borrow)]
+    pub files: Vec<(Cow<'a, str>, Cow<'b, str>)>,
+    /// Continues the listing where this page ended, unless it was the last page.
+    #[serde(default, borrow)]
+    pub next: Option<Cow<'a, str>>,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        let Self { skip, length, prefix } = self;
-        
-        let prefix = match prefix {
-            Some(e) => Some(Cow::Owned(e.into_owned())),
-            None => None,
-        };
-
-        ListRequest {
-            skip,
-            length,
-            prefix,
-        }
-    }
+/// A file listed along with what galleries need to lay it out.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct FileEntry<'a> {
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    pub width: i32,
+    pub height: i32,
+    pub last_modified: i64,
+    /// Type that the contents were checked to have.
+    #[serde(borrow)]
+    pub mime: Cow<'a, str>,
+    /// Size of the original in bytes, which isn't known for files from before it was recorded
+    /// until they have been measured.
+    pub size: Option<u64>,
+    /// The original was encrypted by the client that uploaded it, and has no renditions.
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct FileList<'a, 'b> {
+/// Like `FileList`, but with an entry for every file.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct FileEntryList<'a> {
     #[serde(borrow)]
-    pub files: Vec<(Cow<'a, str>, Cow<'b, str>)>,
+    pub files: Vec<FileEntry<'a>>,
+    #[serde(default, borrow)]
+    pub next: Option<Cow<'a, str>>,
 }
 
-impl<'a, 'b> IntoOwned for FileList<'a, 'b> {
-    type Owned = FileList<'static, 'static>;
+/// An album that a file is in, along with the role of the user that asked for the file.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct FileAlbum<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    pub role: Role,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        FileList {
-            files: self.files
-                .iter()
-                .map(|(a, b)| (
-                        Cow::Owned(a.to_string()),
-                        Cow::Owned(b.to_string())
-                    ))
-                .collect(),
-        }
-    }
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct FileInfo<'a> {
+    #[serde(borrow)]
+    pub owner_id: Cow<'a, str>,
+    pub width: i32,
+    pub height: i32,
+    pub uploaded: i64,
+    /// Type that the contents were checked to have, while `metadata.mime` is what the client
+    /// declared.
+    #[serde(borrow)]
+    pub detected_mime: Cow<'a, str>,
+    pub size: Option<u64>,
+    /// SHA-256 of the original, in hex like `StoredFile::hash`. Files from before hashes were
+    /// recorded don't have one until they have been measured.
+    #[serde(default, borrow)]
+    pub hash: Option<Cow<'a, str>>,
+    pub location: Option<Location>,
+    /// Model of the camera that took the photo, which album rules can go by.
+    #[serde(default, borrow)]
+    pub camera: Option<Cow<'a, str>>,
+    /// Like `FileEntry::encrypted`.
+    #[serde(default)]
+    pub encrypted: bool,
+    /// Set for animated images, whose tallest rendition is served animated.
+    #[serde(default)]
+    pub animation: Option<Animation>,
+    #[serde(default)]
+    pub edit: FileEdit,
+    /// Counts the edits of the file, and is part of the entity tags of its renditions.
+    #[serde(default)]
+    pub revision: u32,
+    /// The motion of a Live Photo, on its still, which is served as its `motion` quality.
+    #[serde(default, borrow)]
+    pub motion_id: Option<Cow<'a, str>>,
+    /// The still of a Live Photo, on its motion.
+    #[serde(default, borrow)]
+    pub still_id: Option<Cow<'a, str>>,
+    #[serde(default)]
+    pub labels: FileLabels<'a>,
+    /// Left out of the owner's library.
+    #[serde(default)]
+    pub hidden: bool,
+    /// In the owner's locked folder.
+    #[serde(default)]
+    pub locked: bool,
+    /// Set for videos, once `ffprobe` has read them.
+    #[serde(default)]
+    pub video: Option<Video<'a>>,
+    #[serde(borrow)]
+    pub metadata: FileMetadata<'a, 'a>,
+    /// Albums that contain the file and that the user who asked is a member of.
+    #[serde(borrow)]
+    pub albums: Vec<FileAlbum<'a>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// How the files of an album are grouped into sections and ordered within them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, IntoOwned)]
+pub enum SortMode {
+    /// Sections are days of the capture time, which is the default.
+    #[default]
+    CaptureDate,
+    /// Sections are days of the upload time.
+    UploadDate,
+    /// Sections are the first character of the lowercase file name.
+    Name,
+    /// Files keep the order that they were added in unless the album is reordered. The sort key
+    /// of each fragment entry is the file's position, and sections are fixed size runs of
+    /// positions.
+    Manual,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct AlbumSettings<'a> {
     pub name: Cow<'a, str>,
     pub time_zone: chrono_tz::Tz,
+    #[serde(default)]
+    pub sort: SortMode,
+    #[serde(default)]
+    pub description: Cow<'a, str>,
+    /// Files of the album's owner that match any of these are added to the album as they are
+    /// uploaded or tagged.
+    #[serde(default)]
+    pub rules: Vec<AlbumRule<'a>>,
 }
 
-impl<'a> IntoOwned for AlbumSettings<'a> {
-    type Owned = AlbumSettings<'static>;
+/// Conditions that a file has to meet all of to be added to an album. Rules without any are
+/// refused, since they would match every file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, IntoOwned)]
+pub struct AlbumRule<'a> {
+    /// Taken in the year, in the time zone of the album.
+    #[serde(default)]
+    pub year: Option<i32>,
+    /// Tagged with the keyword, which is compared without regard to case.
+    #[serde(default)]
+    pub tag: Option<Cow<'a, str>>,
+    /// Taken with the camera model that the EXIF data of the file names, which is compared
+    /// without regard to case.
+    #[serde(default)]
+    pub camera: Option<Cow<'a, str>>,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        AlbumSettings {
-            time_zone: self.time_zone,
-            name: Cow::Owned(self.name.into_owned()),
-        }
+impl<'a> AlbumRule<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.year.is_none() && self.tag.is_none() && self.camera.is_none()
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Caption of a file in an album. `None` or an empty string removes the caption.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct Caption<'a> {
+    #[serde(borrow)]
+    pub text: Option<Cow<'a, str>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct NewResource<'a> {
     #[serde(borrow)]
     pub id: Cow<'a, str>,
 }
 
-impl<'a> IntoOwned for NewResource<'a> {
-    type Owned = NewResource<'static>;
+/// What an upload does when its owner already has a file with the same name, which is given by
+/// the `conflict` query of an upload.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Fail with `FileExists`, which is the default.
+    #[default]
+    Error,
+    /// Store the file as `name (1).ext`, or the first number after that which is free.
+    Rename,
+    /// Stand in for the existing file, whose id is returned, if the contents are the same, and
+    /// fail otherwise. Uploading a directory again then skips the files that were already stored.
+    ReplaceIfSame,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        NewResource {
-            id: Cow::Owned(self.id.into_owned()),
-        }
-    }
+/// The file that an upload was stored as. Its name differs from the one that was sent when it was
+/// renamed to avoid a conflict.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct StoredFile<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    /// SHA-256 of what the server received, which clients check against what they sent.
+    #[serde(default, borrow)]
+    pub hash: Option<Cow<'a, str>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct IdList<'a> {
+/// Hashes are sent as lowercase hex.
+pub fn hash_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Outcome of one file of a batch upload, which has either an `id` or an `error`.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct UploadResult<'a, 'b, 'c> {
     #[serde(borrow)]
-    pub ids: Vec<Cow<'a, str>>,
+    pub name: Cow<'a, str>,
+    #[serde(borrow)]
+    pub id: Option<Cow<'b, str>>,
+    #[serde(borrow)]
+    pub error: Option<Cow<'c, str>>,
+    /// Name that the file was stored as, when it has an `id`.
+    #[serde(default, borrow)]
+    pub stored_name: Option<Cow<'a, str>>,
+    /// Like `StoredFile::hash`, when the file has an `id`.
+    #[serde(default, borrow)]
+    pub hash: Option<Cow<'b, str>>,
 }
 
-impl<'a> IntoOwned for IdList<'a> {
-    type Owned = IdList<'static>;
+/// Version of the bundles that `ExportAlbum` writes. `ImportAlbum` turns away bundles with a
+/// version that it doesn't know.
+pub const ALBUM_BUNDLE_VERSION: u32 = 1;
 
-    fn into_owned(self) -> Self::Owned {
-        IdList {
-            ids: self.ids
-                .iter()
-                .map(|e| Cow::Owned(e.to_string()))
-                .collect(),
-        }
-    }
+/// First part of an album bundle, which is followed by a `file` part with the original of each
+/// of `files`, in the same order.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct AlbumManifest<'a> {
+    pub version: u32,
+    /// Settings of the album, without its rules, which would match the files of whoever imports
+    /// it.
+    #[serde(borrow)]
+    pub settings: AlbumSettings<'a>,
+    /// Files in the order that the album shows them.
+    #[serde(borrow)]
+    pub files: Vec<BundledFile<'a>>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A file of an album bundle.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct BundledFile<'a> {
+    /// Name, type and capture time that the file was uploaded with.
+    #[serde(borrow)]
+    pub metadata: FileMetadata<'a, 'a>,
+    #[serde(default, borrow)]
+    pub caption: Option<Cow<'a, str>>,
+    /// The original was encrypted by the client that uploaded it, and is bundled as it is.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// The album that a bundle was imported as, along with what became of each of its files.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ImportedAlbum<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    /// Outcome of each file, in the order of the bundle.
+    #[serde(borrow)]
+    pub files: Vec<UploadResult<'a, 'a, 'a>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct IdList<'a> {
+    #[serde(borrow)]
+    pub ids: Vec<Cow<'a, str>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
 pub struct Album<'a> {
     #[serde(borrow)]
     pub description: AlbumSettings<'a>,
     pub fragment_head: u64,
     pub length: usize,
     pub last_update: i64,
+    /// Only tracked for albums that are sorted by a date.
     pub date_range: Option<(i64, i64)>,
 }
 
-impl<'a> IntoOwned for Album<'a> {
-    type Owned = Album<'static>;
+/// Album metadata along with the role of the user that asked for it.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct AlbumInfo<'a> {
+    #[serde(flatten, borrow)]
+    pub album: Album<'a>,
+    pub role: Role,
+    /// The album is being laid out by a new time zone or sort mode, and is served with the old
+    /// ones until that is done.
+    #[serde(default)]
+    pub rebuilding: bool,
+    /// Sections of the top fragment that are within the `from` and `to` of a metadata request,
+    /// when it had them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sections: Option<Vec<TopEntry>>,
+    /// Files of the album by month, for albums sorted by a date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub months: Option<Vec<MonthSummary>>,
+}
+
+/// Which albums a listing keeps, by the role of the user that asks, which is given by the `role`
+/// query.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum AlbumFilter {
+    #[default]
+    All,
+    /// Albums that the user owns.
+    Owner,
+    /// Albums that others share with the user, in any role.
+    Shared,
+}
 
-    fn into_owned(self) -> Self::Owned {
-        Album {
-            fragment_head: self.fragment_head,
-            length: self.length,
-            last_update: self.last_update,
-            date_range: self.date_range,
-            description: self.description.into_owned(),
+impl AlbumFilter {
+    pub fn keeps(&self, role: Role) -> bool {
+        match self {
+            AlbumFilter::All => true,
+            AlbumFilter::Owner => matches!(role, Role::Owner),
+            AlbumFilter::Shared => !matches!(role, Role::Owner),
+        }
+    }
+}
+
+/// How `BrowseAlbums` orders albums, which is given by the `sort` query.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum AlbumOrder {
+    /// Most recently changed first.
+    #[default]
+    LastUpdate,
+    /// By name, without regard to case.
+    Name,
+    /// Most files first.
+    Length,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct ListedAlbum<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    #[serde(flatten, borrow)]
+    pub info: AlbumInfo<'a>,
+}
+
+/// Albums that the user can see, in order, along with how many of them there are by role.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct AlbumListing<'a> {
+    #[serde(borrow)]
+    pub albums: Vec<ListedAlbum<'a>>,
+    /// Albums that the user owns, including those that the filter left out.
+    pub owned: usize,
+    /// Albums that are shared with the user, like `owned`.
+    pub shared: usize,
+}
+
+/// A section listed by the top fragment of an album, which is sent as an array of
+/// `[section, fragment_id, length]`. Sections are keyed by the start of a day for albums sorted by
+/// date, by the first character of the name for albums sorted by name, and by the first position
+/// for manually ordered albums. Long sections are split into parts that are listed one after
+/// another, and the array of every part after the first ends with its ordinal. Deriving
+/// `Serialize` would write an object, so the array is written by hand.
+#[derive(Deserialize, Clone, Copy, Debug, IntoOwned)]
+pub struct TopEntry {
+    pub section: i64,
+    pub fragment_id: u64,
+    pub length: usize,
+    #[serde(default)]
+    pub part: usize,
+}
+
+impl Serialize for TopEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.part == 0 {
+            (self.section, self.fragment_id, self.length).serialize(serializer)
+        } else {
+            (self.section, self.fragment_id, self.length, self.part).serialize(serializer)
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Files of an album whose time stamps fall in a month of the album's time zone, which lets a
+/// scrubber lay out albums that span years without reading every section.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, IntoOwned)]
+pub struct MonthSummary {
+    pub year: i32,
+    /// From 1 for January.
+    pub month: u32,
+    pub count: usize,
+    /// Earliest time stamp of a file in the month.
+    pub min: i64,
+    /// Latest time stamp of a file in the month.
+    pub max: i64,
+}
+
+/// A file in a section fragment, which is sent as an array of `[order, file_id, width, height]`
+/// followed by the caption, blurhash, average color, live flag and size of the file. Those are left
+/// off the end when the file doesn't have them, and are `null` when one after them is there. The
+/// type is only deserialized.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SectionEntry {
+    /// Time stamp of the file in albums sorted by date, its lowercase name in albums sorted by
+    /// name, and its position in manually ordered albums.
+    pub order: serde_json::Value,
+    pub file_id: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(default)]
+    pub caption: Option<String>,
+    #[serde(default)]
+    pub blurhash: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub live: bool,
+    /// Size of the original in bytes, if it is known.
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+already_owned!(SectionEntry);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, IntoOwned)]
 pub enum Role {
     Owner,
     Editor,
     Reader,
+    /// Can add their own files to an album and remove them again, but can't change anything else.
+    Contributor,
 }
 
 impl Role {
@@ -228,18 +788,33 @@ impl Role {
             Owner => true,
             Editor => true,
             Reader => false,
+            Contributor => false,
         }
     }
 
-    pub fn is_owner(&self) -> bool {
+    /// Whether the role can add files to the album.
+    pub fn can_contribute(&self) -> bool {
+        use Role::*;
+
         match self {
-            Role::Owner => true,
-            _ => false,
+            Owner => true,
+            Editor => true,
+            Reader => false,
+            Contributor => true,
         }
     }
+
+    /// Readers are only shown the owner of an album, and not the emails of every other member.
+    pub fn can_see_members(&self) -> bool {
+        !matches!(self, Role::Reader)
+    }
+
+    pub fn is_owner(&self) -> bool {
+        matches!(self, Role::Owner)
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, IntoOwned)]
 pub struct PermissionPair<'a, 'b> {
     #[serde(borrow)]
     pub email: Cow<'a, str>,
@@ -248,18 +823,364 @@ pub struct PermissionPair<'a, 'b> {
     pub role: Role,
 }
 
-impl<'a, 'b> IntoOwned for PermissionPair<'a, 'b> {
-    type Owned = PermissionPair<'static, 'static>;
+/// Hands the ownership of an album to another member, who must already be shared on it.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct TransferOwnership<'a> {
+    #[serde(borrow)]
+    pub email: Cow<'a, str>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ActivityEvent {
+    FilesAdded(Vec<String>),
+    FilesRemoved(Vec<String>),
+    MemberJoined { email: String, role: Role },
+    MemberLeft { email: String },
+    SettingsChanged,
+    OwnerChanged { email: String },
+}
 
-    fn into_owned(self) -> Self::Owned {
-        PermissionPair {
-            role: self.role,
-            user_id: self.user_id.map(|s| Cow::Owned(s.into_owned())),
-            email: Cow::Owned(self.email.into_owned()),
+/// Entry in an album's activity feed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Activity {
+    pub time_stamp: i64,
+    /// User that made the change.
+    pub user_id: String,
+    pub event: ActivityEvent,
+}
+
+/// Change notification for an album, sent over the event stream.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum AlbumEvent {
+    /// The album has a new fragment head, e.g. because files were added or its settings changed.
+    Updated { album_id: String, fragment_head: u64 },
+    /// `user_id` was added to the album, removed from it, or had their role changed.
+    SharesChanged { album_id: String, user_id: String },
+    /// The library of `user_id` has a new fragment head after an upload or a delete.
+    LibraryUpdated { user_id: String, fragment_head: u64 },
+}
+
+impl AlbumEvent {
+    /// The album that the event is about, or `None` for library events.
+    pub fn album_id(&self) -> Option<&str> {
+        match self {
+            AlbumEvent::Updated { album_id, .. } => Some(album_id),
+            AlbumEvent::SharesChanged { album_id, .. } => Some(album_id),
+            AlbumEvent::LibraryUpdated { .. } => None,
         }
     }
 }
 
+/// Restrictions on uploads that clients can check before sending a file.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct Limits {
+    pub max_upload_bytes: u64,
+    pub upload_timeout_seconds: u64,
+    /// Files that a batch upload may hold, which is zero for servers without batch uploads.
+    #[serde(default)]
+    pub max_batch_files: usize,
+    /// Kinds of uploads that the server can't decode, out of `heif`, `raw` and `video`.
+    #[serde(default)]
+    pub unsupported_formats: Vec<String>,
+}
+
+/// What a user keeps in their library, leaving out the trash.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct LibraryStats {
+    pub files: u64,
+    /// Bytes in storage by kind, which is `original` or the name of a rendition. Files from
+    /// before sizes were recorded aren't counted until they have been measured.
+    pub bytes: HashMap<String, u64>,
+    /// Files by the type that their contents were checked to have.
+    pub mimes: HashMap<String, u64>,
+    /// Earliest and latest capture time in the library.
+    pub date_range: Option<(i64, i64)>,
+    /// Albums that the user owns.
+    pub owned_albums: u64,
+    /// Albums that have been shared with the user.
+    pub shared_albums: u64,
+    /// Bytes of originals that the user may keep, if they were given a quota.
+    #[serde(default)]
+    pub quota: Option<u64>,
+}
+
+/// Progress of regenerating the renditions of every file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct RegenerateStatus {
+    pub running: bool,
+    pub total: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Runs of the collector that removes album fragments which nothing refers to anymore.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct GcStatus {
+    pub runs: u64,
+    /// Unix time at which the last run finished.
+    pub last_run: Option<i64>,
+    /// Albums that the last run went through.
+    pub albums: usize,
+    /// Fragments that the last run removed.
+    pub reclaimed: usize,
+    /// Fragments removed since the server started.
+    pub total_reclaimed: u64,
+}
+
+/// Bytes served to a user, or to a session that is named by the start of its key.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct BandwidthCounter<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    pub bytes: u64,
+}
+
+/// What has been served on the current day in UTC, along with the limits, which are 0 when there
+/// is none.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct BandwidthUsage<'a> {
+    #[serde(borrow)]
+    pub day: Cow<'a, str>,
+    pub token_bytes_per_day: u64,
+    pub user_bytes_per_day: u64,
+    #[serde(borrow)]
+    pub users: Vec<BandwidthCounter<'a>>,
+    #[serde(borrow)]
+    pub tokens: Vec<BandwidthCounter<'a>>,
+}
+
+/// Something that `POST /admin/fsck` found out of place.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum Inconsistency {
+    /// A name in `file_names` whose file doesn't exist or is called something else.
+    DanglingFileName,
+    /// A file that can't be found by its name.
+    MissingFileName,
+    /// An inclusion of a file or in an album that doesn't exist.
+    DanglingInclusion,
+    /// A file in an album without an inclusion.
+    MissingInclusion,
+    /// An inclusion of a file that its album doesn't hold.
+    UnlistedInclusion,
+    /// A file in an album that doesn't exist.
+    MissingFile,
+    /// A share of an album or with a user that doesn't exist.
+    DanglingShare,
+    /// A share that is only recorded in one of `user_to_album` and `album_to_user`.
+    OneSidedShare,
+    /// An object in storage that no file refers to.
+    OrphanObject,
+    /// The original or a rendition of a file that isn't in storage.
+    MissingObject,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct Finding<'a> {
+    pub kind: Inconsistency,
+    /// Key of the entry or the object that is out of place.
+    #[serde(borrow)]
+    pub key: Cow<'a, str>,
+    pub repaired: bool,
+}
+
+/// What `POST /admin/verify` found wrong with an original.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, IntoOwned)]
+#[serde(rename_all = "snake_case")]
+pub enum Damage {
+    /// The original isn't in storage anymore.
+    Missing,
+    /// The original doesn't hash to what it did when it was uploaded.
+    Corrupted,
+}
+
+/// An original that failed verification.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct DamagedFile<'a> {
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub owner_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub name: Cow<'a, str>,
+    pub damage: Damage,
+    /// Unix time of the run that first found it.
+    pub found: i64,
+}
+
+/// What the last verification of every original found.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct VerifyReport<'a> {
+    /// Unix time at which the last run finished, if one has.
+    pub last_run: Option<i64>,
+    /// Originals that the last run hashed again.
+    pub checked: u64,
+    /// Files from before hashes were recorded, which couldn't be checked.
+    pub unhashed: u64,
+    #[serde(borrow)]
+    pub damaged: Vec<DamagedFile<'a>>,
+}
+
+/// What an invitation gives to the user who registers with it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoOwned)]
+pub struct InviteOptions {
+    /// Bytes of originals that the user may keep, or no limit if missing.
+    pub quota: Option<u64>,
+    /// Whether the user may use the `/admin` routes.
+    #[serde(default)]
+    pub admin: bool,
+    /// Seconds until the code can't be used anymore, or never if missing.
+    pub expires_in: Option<i64>,
+}
+
+/// A single use code that lets someone register while registration is by invitation.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct Invite<'a> {
+    #[serde(borrow)]
+    pub code: Cow<'a, str>,
+    /// Id of the administrator who made it.
+    #[serde(borrow)]
+    pub created_by: Cow<'a, str>,
+    pub created: i64,
+    pub expires: Option<i64>,
+    pub quota: Option<u64>,
+    pub admin: bool,
+}
+
+/// A background job that hasn't succeeded yet.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct JobStatus {
+    pub id: u64,
+    pub kind: String,
+    pub attempts: u32,
+    /// Unix time of the next attempt.
+    pub run_after: i64,
+    pub running: bool,
+    /// Whether the job has failed too many times to be retried.
+    pub failed: bool,
+    pub last_error: Option<String>,
+}
+
+/// Progress of a bulk album change, streamed back as one JSON object per line.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BulkProgress {
+    /// `done` of the `total` files have been committed, leaving the album at `fragment_head`.
+    Committed { done: usize, total: usize, fragment_head: u64 },
+    /// The change stopped after the first `done` files were committed.
+    Failed { done: usize, total: usize, error: String },
+}
+
+/// Frames of an animated image.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, IntoOwned)]
+pub struct Animation {
+    pub frames: u32,
+    /// Time that one loop through the frames takes.
+    pub duration_ms: u64,
+}
+
+/// What `ffprobe` read from a video.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, IntoOwned)]
+pub struct Video<'a> {
+    /// Missing for videos whose container doesn't say.
+    pub duration_ms: Option<u64>,
+    /// Codec of the first video stream, like `h264` or `hevc`.
+    pub codec: Cow<'a, str>,
+    /// Frames side by side in the strip that is served as the `preview` quality, for scrubbing
+    /// through the video, or 0 if there is no strip.
+    pub preview_frames: u32,
+}
+
+/// A file that looks like another, by how many bits their perceptual hashes differ in.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct SimilarFile<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    pub distance: u32,
+}
+
+/// A file that the detection service found something in, by how sure it was from 0 to 1.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct LabeledFile<'a> {
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    pub confidence: f64,
+}
+
+/// How a file is turned for display, which leaves its original as it was uploaded. The default
+/// shows it as the camera recorded it, so sending it reverts every edit.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default, IntoOwned)]
+pub struct FileEdit {
+    /// EXIF orientation, from 1 to 8, to use in place of the one that the camera recorded.
+    #[serde(default)]
+    pub orientation: Option<u8>,
+    /// Mirror the image left to right, before turning it.
+    #[serde(default)]
+    pub flip: bool,
+    /// Degrees to turn the image clockwise, in quarter turns.
+    #[serde(default)]
+    pub rotate: u16,
+}
+
+/// What the user said about a file in an XMP sidecar, which is also written back out as one.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default, IntoOwned)]
+pub struct FileLabels<'a> {
+    /// Stars from 1 to 5, 0 for none, or -1 for rejected, like `xmp:Rating`.
+    #[serde(default)]
+    pub rating: Option<i8>,
+    #[serde(default)]
+    pub title: Option<Cow<'a, str>>,
+    /// Keywords, in the order that they were given and without repeats.
+    #[serde(default)]
+    pub tags: Vec<Cow<'a, str>>,
+}
+
+/// Where a photo was taken, in degrees.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, IntoOwned)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Files that are close together on a map. `file_id` is one of them, to be shown as a preview.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct GeoCluster<'a> {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub count: usize,
+    #[serde(borrow)]
+    pub file_id: Cow<'a, str>,
+}
+
+/// What a trash entry holds.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, IntoOwned)]
+#[serde(rename_all = "lowercase")]
+pub enum TrashKind {
+    File,
+    Album,
+}
+
+/// A deleted file or album that can still be restored until `purge_after`.
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct TrashEntry<'a, 'b> {
+    pub kind: TrashKind,
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+    /// File name or album name at the time it was deleted.
+    #[serde(borrow)]
+    pub name: Cow<'b, str>,
+    pub deleted: i64,
+    pub purge_after: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, IntoOwned)]
+pub struct TrashRestore<'a> {
+    pub kind: TrashKind,
+    #[serde(borrow)]
+    pub id: Cow<'a, str>,
+}
+
 #[test]
 fn return_cow() {
     fn helper() -> UserDetails<'static, 'static> {